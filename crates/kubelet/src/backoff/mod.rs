@@ -1,7 +1,10 @@
 //! Provides backoff timing control for Kubernetes pod states
 //! such as ImagePullBackoff and CrashLoopBackoff.
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::environment::{Clock, SystemClock};
+
 /// Determines how long to back off before performing a retry.
 #[async_trait::async_trait]
 pub trait BackoffStrategy: Send {
@@ -11,7 +14,8 @@ pub trait BackoffStrategy: Send {
     fn next_duration(&mut self) -> Duration;
     /// Waits the prescribed amount of time (as per `next_duration`).
     async fn wait(&mut self) {
-        tokio::time::sleep(self.next_duration()).await
+        let duration = self.next_duration();
+        tokio::time::sleep(duration).await
     }
 }
 
@@ -21,6 +25,7 @@ pub struct ExponentialBackoffStrategy {
     base_duration: Duration,
     cap: Duration,
     last_duration: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl Default for ExponentialBackoffStrategy {
@@ -30,11 +35,18 @@ impl Default for ExponentialBackoffStrategy {
             base_duration: Duration::from_secs(10),
             cap: Duration::from_secs(300),
             last_duration: Duration::from_secs(0),
+            clock: Arc::new(SystemClock),
         }
     }
 }
 
 impl ExponentialBackoffStrategy {
+    /// Waits on the given clock instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     fn capped_next_duration(&self) -> Duration {
         let next_duration = if self.last_duration == Duration::from_secs(0) {
             self.base_duration
@@ -50,6 +62,7 @@ impl ExponentialBackoffStrategy {
     }
 }
 
+#[async_trait::async_trait]
 impl BackoffStrategy for ExponentialBackoffStrategy {
     fn reset(&mut self) {
         self.last_duration = Duration::from_secs(0);
@@ -60,6 +73,11 @@ impl BackoffStrategy for ExponentialBackoffStrategy {
         self.last_duration = next_duration;
         next_duration
     }
+
+    async fn wait(&mut self) {
+        let duration = self.next_duration();
+        self.clock.sleep(duration).await
+    }
 }
 
 #[cfg(test)]
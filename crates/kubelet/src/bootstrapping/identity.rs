@@ -0,0 +1,168 @@
+//! Sources of the credentials a node authenticates with while bootstrapping.
+//!
+//! While bootstrapping, the node uses a bootstrap kubeconfig to request a client certificate
+//! for itself. By default that kubeconfig is read from the bootstrap file, which usually holds a
+//! bootstrap token. A [`BootstrapIdentity`] can instead produce the kubeconfig from something
+//! the node already has, such as a cloud instance identity document or a SPIFFE SVID.
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use kube::config::Kubeconfig;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::config::Config as KubeletConfig;
+
+/// Produces the kubeconfig a node uses to authenticate while it requests its client
+/// certificate.
+#[async_trait::async_trait]
+pub trait BootstrapIdentity: Send + Sync {
+    /// Returns a kubeconfig whose first cluster is the cluster to join and whose current context
+    /// is allowed to create the node's CertificateSigningRequest.
+    async fn bootstrap_kubeconfig(&self, config: &KubeletConfig) -> anyhow::Result<Kubeconfig>;
+}
+
+/// Reads the bootstrap kubeconfig from a file, which is how bootstrap tokens are usually
+/// provided.
+pub struct BootstrapFile {
+    path: PathBuf,
+}
+
+impl BootstrapFile {
+    /// Creates an identity that reads the bootstrap kubeconfig at the given path.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        BootstrapFile {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BootstrapIdentity for BootstrapFile {
+    async fn bootstrap_kubeconfig(&self, _config: &KubeletConfig) -> anyhow::Result<Kubeconfig> {
+        debug!(bootstrap_file = %self.path.display(), "Loading bootstrap config");
+        // Serde yaml doesn't have async support so we have to read the whole file in
+        let raw = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| anyhow::anyhow!(format!("Error loading bootstrap file: {}", e)))?;
+        serde_yaml::from_slice(&raw)
+            .map_err(|e| anyhow::anyhow!(format!("Error parsing bootstrap file: {}", e)))
+    }
+}
+
+/// Runs an executable plugin that prints the bootstrap kubeconfig to stdout.
+///
+/// This allows nodes to join with whatever identity their environment provides: a plugin might,
+/// for example, exchange a cloud instance identity document for a token, or fetch a SPIFFE SVID
+/// and use it as the client certificate. The plugin is run with `KRUSTLET_NODE_NAME`,
+/// `KRUSTLET_HOSTNAME` and `KRUSTLET_NODE_IP` set, and must exit successfully after printing a
+/// kubeconfig in YAML or JSON.
+pub struct ExecIdentityPlugin {
+    command: PathBuf,
+}
+
+impl ExecIdentityPlugin {
+    /// Creates an identity that runs the plugin at the given path.
+    pub fn new(command: impl AsRef<Path>) -> Self {
+        ExecIdentityPlugin {
+            command: command.as_ref().to_owned(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BootstrapIdentity for ExecIdentityPlugin {
+    async fn bootstrap_kubeconfig(&self, config: &KubeletConfig) -> anyhow::Result<Kubeconfig> {
+        debug!(plugin = %self.command.display(), "Running bootstrap identity plugin");
+        let output = Command::new(&self.command)
+            .env("KRUSTLET_NODE_NAME", &config.node_name)
+            .env("KRUSTLET_HOSTNAME", &config.hostname)
+            .env("KRUSTLET_NODE_IP", config.node_ip.to_string())
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Unable to run bootstrap identity plugin {}: {}",
+                    self.command.display(),
+                    e
+                )
+            })?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Bootstrap identity plugin {} failed with {}: {}",
+                self.command.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        serde_yaml::from_slice(&output.stdout).map_err(|e| {
+            anyhow::anyhow!(
+                "Bootstrap identity plugin {} did not print a valid kubeconfig: {}",
+                self.command.display(),
+                e
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_exec_identity_plugin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = dir.path().join("identity-plugin");
+        std::fs::write(
+            &plugin,
+            r#"#!/bin/sh
+cat <<EOF
+apiVersion: v1
+kind: Config
+clusters:
+- name: cloud
+  cluster:
+    server: https://cloud.example.com
+    certificate-authority-data: Y2E=
+users:
+- name: $KRUSTLET_NODE_NAME
+  user:
+    token: instance-identity-token
+contexts:
+- name: cloud
+  context:
+    cluster: cloud
+    user: $KRUSTLET_NODE_NAME
+current-context: cloud
+EOF
+"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut config = KubeletConfig::default();
+        config.node_name = "krusty".to_owned();
+        let kubeconfig = ExecIdentityPlugin::new(&plugin)
+            .bootstrap_kubeconfig(&config)
+            .await
+            .unwrap();
+        assert_eq!(
+            kubeconfig.clusters[0].cluster.server,
+            "https://cloud.example.com"
+        );
+        assert_eq!(kubeconfig.auth_infos[0].name, "krusty");
+
+        let failing = dir.path().join("failing-plugin");
+        std::fs::write(&failing, "#!/bin/sh\necho denied >&2\nexit 1\n").unwrap();
+        std::fs::set_permissions(&failing, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = ExecIdentityPlugin::new(&failing)
+            .bootstrap_kubeconfig(&config)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("denied"));
+    }
+}
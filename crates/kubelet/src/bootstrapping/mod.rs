@@ -3,7 +3,7 @@ use std::{convert::TryFrom, env, io, path::Path, str};
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::certificates::v1::CertificateSigningRequest;
 use kube::api::{Api, ListParams, PostParams};
-use kube::config::Kubeconfig;
+use kube::config::KubeConfigOptions;
 use kube::Config;
 use kube_runtime::watcher::{watcher, Event};
 use rcgen::{
@@ -18,45 +18,63 @@ use crate::config::{self, Config as KubeletConfig};
 use crate::kubeconfig::exists as kubeconfig_exists;
 use crate::kubeconfig::KUBECONFIG;
 
+pub mod identity;
+
+use identity::{BootstrapFile, BootstrapIdentity, ExecIdentityPlugin};
+
 const APPROVED_TYPE: &str = "Approved";
 
 /// Bootstrap the cluster with TLS certificates but only if no existing kubeconfig can be found.
+///
+/// The node authenticates with the kubeconfig printed by the configured bootstrap plugin, or
+/// with the bootstrap file if there is no plugin.
 pub async fn bootstrap<K: AsRef<Path>>(
     config: &KubeletConfig,
     bootstrap_file: K,
     notify: impl Fn(String),
+) -> anyhow::Result<Config> {
+    match &config.bootstrap_plugin {
+        Some(plugin) => {
+            bootstrap_with_identity(config, &ExecIdentityPlugin::new(plugin), notify).await
+        }
+        None => bootstrap_with_identity(config, &BootstrapFile::new(bootstrap_file), notify).await,
+    }
+}
+
+/// Bootstrap the cluster with TLS certificates, authenticating with the kubeconfig produced by
+/// the given identity, but only if no existing kubeconfig can be found.
+pub async fn bootstrap_with_identity(
+    config: &KubeletConfig,
+    identity: &dyn BootstrapIdentity,
+    notify: impl Fn(String),
 ) -> anyhow::Result<Config> {
     debug!(%config.node_name, "Starting bootstrap");
-    let kubeconfig = bootstrap_auth(config, bootstrap_file).await?;
+    let kubeconfig = bootstrap_auth(config, identity).await?;
     bootstrap_tls(config, kubeconfig.clone(), notify).await?;
     Ok(kubeconfig)
 }
 
-#[instrument(level = "info", skip(config, bootstrap_file))]
-async fn bootstrap_auth<K: AsRef<Path>>(
+#[instrument(level = "info", skip(config, identity))]
+async fn bootstrap_auth(
     config: &KubeletConfig,
-    bootstrap_file: K,
+    identity: &dyn BootstrapIdentity,
 ) -> anyhow::Result<Config> {
     if kubeconfig_exists() {
         debug!("Found existing kubeconfig, loading...");
-        Config::infer()
-            .await
-            .map_err(|e| anyhow::anyhow!("Unable to load config from host: {}", e))
+        crate::kubeconfig::load(config.kube_context.as_deref()).await
     } else {
         // TODO: if configured, kubelet automatically requests renewal of the certificate when it is close to expiry
         let original_kubeconfig = std::path::PathBuf::from(env::var(KUBECONFIG)?);
-        debug!(
-            bootstrap_file = %bootstrap_file.as_ref().display(),
-            "No existing kubeconfig found, loading bootstrap config"
-        );
-        env::set_var(KUBECONFIG, bootstrap_file.as_ref().as_os_str());
-        let conf = kube::Config::infer().await?;
+        debug!("No existing kubeconfig found, loading bootstrap config");
+        let bootstrap_config = identity.bootstrap_kubeconfig(config).await?;
+        let conf =
+            Config::from_custom_kubeconfig(bootstrap_config.clone(), &KubeConfigOptions::default())
+                .await?;
         let client = kube::Client::try_from(conf)?;
 
         trace!("Generating auth certificate");
         let cert_bundle = gen_auth_cert(config)?;
         trace!("Getting cluster information from bootstrap config");
-        let bootstrap_config = read_from(&bootstrap_file).await?;
         let named_cluster = bootstrap_config
             .clusters
             .into_iter()
@@ -172,9 +190,6 @@ async fn bootstrap_auth<K: AsRef<Path>>(
 
         debug!(path = %original_kubeconfig.display(), "Writing generated kubeconfig to file");
         write(&original_kubeconfig, &generated_kubeconfig).await?;
-        // Set environment variable back to original value
-        // so that infer will now pick up the file we generated
-        env::set_var(KUBECONFIG, original_kubeconfig.as_os_str());
 
         Config::infer()
             .await
@@ -398,17 +413,6 @@ fn gen_kubeconfig(
         .map_err(|e| anyhow::anyhow!("Unable to serialize generated kubeconfig: {}", e))
 }
 
-async fn read_from<P: AsRef<Path>>(path: P) -> anyhow::Result<Kubeconfig> {
-    // Serde yaml doesn't have async support so we have to read the whole file in
-    let raw = read(path)
-        .await
-        .map_err(|e| anyhow::anyhow!(format!("Error loading bootstrap file: {}", e)))?;
-    let config = serde_yaml::from_slice(&raw)
-        .map_err(|e| anyhow::anyhow!(format!("Error parsing bootstrap file: {}", e)))?;
-
-    Ok(config)
-}
-
 #[cfg(target_family = "unix")]
 async fn restrict_permissions_of_private_file(file: &File) -> io::Result<()> {
     let permissions = std::os::unix::fs::PermissionsExt::from_mode(0o600);
@@ -0,0 +1,582 @@
+//! Per-pod and per-container cgroups on Linux.
+//!
+//! Providers that run workloads on threads inside the kubelet process can use a
+//! [`CgroupManager`] to move those threads into a cgroup v2 hierarchy of their own, so that the
+//! CPU limits of a pod are enforced by the kernel in addition to whatever the runtime enforces.
+//!
+//! The hierarchy is created beneath the cgroup the kubelet itself runs in, which must therefore be
+//! delegated to it (for example with `Delegate=yes` in a systemd unit):
+//!
+//! ```text
+//! <kubelet cgroup>/
+//!   <namespace>_<pod name>/     (cpu.max is the sum of the container limits)
+//!     <container name>/         (cpu.max and cpu.weight of the container)
+//! ```
+//!
+//! Because the workloads are threads rather than processes, every cgroup in the hierarchy is a
+//! threaded cgroup. Only threaded controllers can be used with those, and the memory controller
+//! isn't one: memory is charged to a process, not a thread, so the kernel can't limit or account
+//! memory per pod and it stays charged to the kubelet's own cgroup as a whole. Memory limits are
+//! therefore enforced only by the runtime, never by the kernel: the WASI provider, for example,
+//! refuses to grow a module's memory in wasmtime past its container's limit (see
+//! [`container_memory_limit`](crate::stats::container_memory_limit)). The CPU usage of every
+//! container is exported on the kubelet's `/metrics` endpoint and reported in `/stats/summary`.
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{GaugeVec, Opts};
+use tracing::{debug, info, warn};
+
+use crate::container::Container;
+use crate::environment::Clock;
+use crate::pod::dirs::{join_name, pod_dir};
+use crate::pod::{Pod, PodKey};
+use crate::resources::quantity::{Quantity, QuantityType};
+
+/// The scheduling period used for CPU limits, matching the kubelet's default
+const CPU_PERIOD_USEC: u64 = 100_000;
+/// The smallest quota the kernel accepts
+const MIN_CPU_QUOTA_USEC: u64 = 1_000;
+/// How many times removing a cgroup is retried while threads are still leaving it
+const REMOVE_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry, doubling with every attempt
+const REMOVE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Creates and removes the cgroups of pods and their containers.
+pub struct CgroupManager {
+    root: PathBuf,
+    clock: Arc<dyn Clock>,
+}
+
+impl CgroupManager {
+    /// Sets up a manager rooted at the kubelet's own cgroup and registers the container CPU
+    /// metrics. Returns `None` if cgroups can't be used, either because this isn't Linux with the
+    /// unified cgroup v2 hierarchy or because the kubelet's cgroup hasn't been delegated to it.
+    /// Removals that are retried wait on the given clock.
+    pub fn detect(clock: Arc<dyn Clock>) -> Option<Arc<Self>> {
+        let root = match own_cgroup() {
+            Ok(root) => root,
+            Err(e) => {
+                info!(error = %e, "Pod cgroups are not available, CPU limits will not be enforced by the kernel");
+                return None;
+            }
+        };
+        let manager = Arc::new(CgroupManager::new(root, clock));
+        if let Err(e) =
+            crate::metrics::registry().register(Box::new(StatsCollector::new(manager.root.clone())))
+        {
+            warn!(error = %e, "Unable to register pod cgroup metrics");
+        }
+        debug!(root = %manager.root.display(), "Using pod cgroups");
+        Some(manager)
+    }
+
+    pub(crate) fn new(root: PathBuf, clock: Arc<dyn Clock>) -> Self {
+        CgroupManager { root, clock }
+    }
+
+    /// Reads the CPU time used so far by a pod, or by one of its containers if one is named.
+    pub fn cpu_stats(&self, pod: &PodKey, container: Option<&str>) -> anyhow::Result<CpuStats> {
+        let pod_path = pod_dir(&self.root, pod)?;
+        match container {
+            Some(container) => read_cpu_stats(&join_name(&pod_path, container)?),
+            None => read_cpu_stats(&pod_path),
+        }
+    }
+
+    /// Creates the cgroup of a container, along with the cgroup of its pod if this is the first of
+    /// the pod's containers, and applies their CPU limits.
+    pub fn create_container(&self, pod: &Pod, container: &Container) -> anyhow::Result<Cgroup> {
+        let pod_path = pod_dir(&self.root, &PodKey::from(pod))?;
+        if !pod_path.exists() {
+            create_threaded(&self.root, &pod_path)?;
+            write(&pod_path, "cpu.max", &cpu_max(pod_cpu_limit(pod)))?;
+        }
+
+        let path = join_name(&pod_path, container.name())?;
+        if !path.exists() {
+            create_threaded(&pod_path, &path)?;
+        }
+        write(
+            &path,
+            "cpu.max",
+            &cpu_max(cpu_millis(container, Bound::Limit)),
+        )?;
+        if let Some(requests) = cpu_millis(container, Bound::Request) {
+            write(&path, "cpu.weight", &cpu_weight(requests).to_string())?;
+        }
+        Ok(Cgroup {
+            path,
+            root: self.root.clone(),
+        })
+    }
+
+    /// Removes the cgroups of the pod and all of its containers. The threads of the pod must have
+    /// left them first, which happens once every [`CgroupGuard`] has been dropped. A module that
+    /// was just interrupted may still be unwinding, so removal is retried for a short while as
+    /// long as the kernel reports the cgroup busy.
+    pub async fn remove_pod(&self, pod: &PodKey) -> anyhow::Result<()> {
+        let pod_path = pod_dir(&self.root, pod)?;
+        if !pod_path.exists() {
+            return Ok(());
+        }
+        for entry in std::fs::read_dir(&pod_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let path = entry.path();
+                retry_busy(&*self.clock, || std::fs::remove_dir(&path)).await?;
+            }
+        }
+        retry_busy(&*self.clock, || std::fs::remove_dir(&pod_path)).await?;
+        Ok(())
+    }
+}
+
+/// The cgroup of a single container.
+#[derive(Clone, Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    root: PathBuf,
+}
+
+/// Keeps the current thread in a container's cgroup, moving it back to the kubelet's cgroup
+/// when dropped.
+pub struct CgroupGuard {
+    root: PathBuf,
+}
+
+/// The CPU time used by a cgroup.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuStats {
+    /// Total CPU time consumed
+    pub usage: Duration,
+    /// Total time the cgroup was throttled because it reached its limit
+    pub throttled: Duration,
+}
+
+impl Cgroup {
+    /// Moves the current thread into the cgroup until the returned guard is dropped. This should
+    /// be called from the thread that runs the container, which must not be shared with anything
+    /// else while the guard is held.
+    pub fn enter(&self) -> anyhow::Result<CgroupGuard> {
+        write(
+            &self.path,
+            "cgroup.threads",
+            &current_thread_id()?.to_string(),
+        )?;
+        Ok(CgroupGuard {
+            root: self.root.clone(),
+        })
+    }
+
+    /// Reads the CPU time used by the container so far.
+    pub fn stats(&self) -> anyhow::Result<CpuStats> {
+        read_cpu_stats(&self.path)
+    }
+}
+
+impl Drop for CgroupGuard {
+    fn drop(&mut self) {
+        let moved = current_thread_id()
+            .and_then(|tid| write(&self.root, "cgroup.threads", &tid.to_string()));
+        if let Err(e) = moved {
+            warn!(error = %e, "Unable to move thread out of container cgroup");
+        }
+    }
+}
+
+/// Finds the cgroup of the kubelet process in the unified hierarchy.
+#[cfg(target_os = "linux")]
+fn own_cgroup() -> anyhow::Result<PathBuf> {
+    let mount = Path::new("/sys/fs/cgroup");
+    if !mount.join("cgroup.controllers").exists() {
+        anyhow::bail!(
+            "the unified cgroup v2 hierarchy is not mounted at {}",
+            mount.display()
+        );
+    }
+    let membership = std::fs::read_to_string("/proc/self/cgroup")?;
+    let relative = parse_own_cgroup(&membership)
+        .ok_or_else(|| anyhow::anyhow!("the kubelet is not in a cgroup v2 cgroup"))?;
+    let root = mount.join(relative.trim_start_matches('/'));
+    let controllers = std::fs::read_to_string(root.join("cgroup.controllers"))?;
+    if !controllers.split_whitespace().any(|c| c == "cpu") {
+        anyhow::bail!(
+            "the cpu controller is not delegated to cgroup {}",
+            root.display()
+        );
+    }
+    // Without write access to the subtree there is no way to create pod cgroups
+    if nix::unistd::access(&root, nix::unistd::AccessFlags::W_OK).is_err() {
+        anyhow::bail!("cgroup {} is not writable", root.display());
+    }
+    Ok(root)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn own_cgroup() -> anyhow::Result<PathBuf> {
+    anyhow::bail!("cgroups are only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+fn current_thread_id() -> anyhow::Result<i32> {
+    Ok(nix::unistd::gettid().as_raw())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_thread_id() -> anyhow::Result<i32> {
+    anyhow::bail!("cgroups are only supported on Linux")
+}
+
+/// Parses the cgroup v2 entry (`0::<path>`) of `/proc/self/cgroup`
+fn parse_own_cgroup(membership: &str) -> Option<&str> {
+    membership
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::trim)
+}
+
+/// Creates a threaded child cgroup and lets it use the cpu controller. The child has to be made
+/// threaded before the controller can be enabled in a parent that also contains threads.
+fn create_threaded(parent: &Path, path: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir(path)
+        .map_err(|e| anyhow::anyhow!("Unable to create cgroup {}: {}", path.display(), e))?;
+    write(path, "cgroup.type", "threaded")?;
+    write(parent, "cgroup.subtree_control", "+cpu")
+}
+
+/// Runs a removal until it succeeds, fails with anything but `EBUSY` or runs out of attempts.
+async fn retry_busy(
+    clock: &dyn Clock,
+    mut remove: impl FnMut() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut backoff = REMOVE_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match remove() {
+            Err(e) if is_busy(&e) && attempt < REMOVE_ATTEMPTS => {
+                debug!(error = %e, attempt, "Cgroup is still in use, retrying removal");
+                clock.sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_busy(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(nix::errno::Errno::EBUSY as i32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_busy(_e: &std::io::Error) -> bool {
+    false
+}
+
+fn write(cgroup: &Path, file: &str, value: &str) -> anyhow::Result<()> {
+    let path = cgroup.join(file);
+    std::fs::write(&path, value)
+        .map_err(|e| anyhow::anyhow!("Unable to write {:?} to {}: {}", value, path.display(), e))
+}
+
+fn read_cpu_stats(cgroup: &Path) -> anyhow::Result<CpuStats> {
+    Ok(parse_cpu_stat(&std::fs::read_to_string(
+        cgroup.join("cpu.stat"),
+    )?))
+}
+
+fn parse_cpu_stat(raw: &str) -> CpuStats {
+    let mut stats = CpuStats::default();
+    for line in raw.lines() {
+        let mut fields = line.split_whitespace();
+        let (key, value) = match (fields.next(), fields.next().and_then(|v| v.parse().ok())) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+        match key {
+            "usage_usec" => stats.usage = Duration::from_micros(value),
+            "throttled_usec" => stats.throttled = Duration::from_micros(value),
+            _ => {}
+        }
+    }
+    stats
+}
+
+enum Bound {
+    Limit,
+    Request,
+}
+
+fn cpu_millis(container: &Container, bound: Bound) -> Option<u64> {
+    let resources = container.resources()?;
+    let quantities = match bound {
+        Bound::Limit => &resources.limits,
+        Bound::Request => &resources.requests,
+    };
+    match Quantity::from_kube_quantity(QuantityType::Cpu(quantities.get("cpu")?)) {
+        Ok(Quantity::Cpu(cores)) => Some((cores * 1000.0).round() as u64),
+        Ok(_) => None,
+        Err(e) => {
+            warn!(error = %e, container = container.name(), "Invalid CPU quantity, ignoring it");
+            None
+        }
+    }
+}
+
+/// A pod is only limited as a whole if every one of its containers has a limit
+fn pod_cpu_limit(pod: &Pod) -> Option<u64> {
+    pod.containers()
+        .iter()
+        .map(|c| cpu_millis(c, Bound::Limit))
+        .sum()
+}
+
+/// Formats a CPU limit in millicores as the contents of `cpu.max`
+fn cpu_max(limit: Option<u64>) -> String {
+    match limit {
+        Some(millis) => {
+            let quota = (millis * CPU_PERIOD_USEC / 1000).max(MIN_CPU_QUOTA_USEC);
+            format!("{} {}", quota, CPU_PERIOD_USEC)
+        }
+        None => format!("max {}", CPU_PERIOD_USEC),
+    }
+}
+
+/// Converts a CPU request in millicores to a `cpu.weight`, using the same conversion from CPU
+/// shares as the kubelet and runc
+fn cpu_weight(request_millis: u64) -> u64 {
+    let shares = (request_millis * 1024 / 1000).max(2);
+    1 + ((shares - 2) * 9999) / 262_142
+}
+
+/// Reads the CPU stats of every container cgroup whenever metrics are gathered
+struct StatsCollector {
+    root: PathBuf,
+    usage: GaugeVec,
+    throttled: GaugeVec,
+}
+
+impl StatsCollector {
+    fn new(root: PathBuf) -> Self {
+        let labels = &["namespace", "pod", "container"];
+        StatsCollector {
+            root,
+            usage: GaugeVec::new(
+                Opts::new(
+                    "container_cpu_usage_seconds",
+                    "Total CPU time consumed by a container, read from its cgroup",
+                ),
+                labels,
+            )
+            .expect("metric definition should be valid"),
+            throttled: GaugeVec::new(
+                Opts::new(
+                    "container_cpu_throttled_seconds",
+                    "Total time a container was throttled by its CPU limit, read from its cgroup",
+                ),
+                labels,
+            )
+            .expect("metric definition should be valid"),
+        }
+    }
+
+    fn refresh(&self) -> anyhow::Result<()> {
+        self.usage.reset();
+        self.throttled.reset();
+        for pod in std::fs::read_dir(&self.root)? {
+            let pod = pod?;
+            let pod_name = pod.file_name().to_string_lossy().into_owned();
+            let (namespace, name) = match pod_name.split_once('_') {
+                Some(parts) if pod.file_type()?.is_dir() => parts,
+                _ => continue,
+            };
+            for container in std::fs::read_dir(pod.path())? {
+                let container = container?;
+                if !container.file_type()?.is_dir() {
+                    continue;
+                }
+                let container_name = container.file_name().to_string_lossy().into_owned();
+                // The container may have been removed since the directory was listed
+                if let Ok(stats) = read_cpu_stats(&container.path()) {
+                    let labels = &[namespace, name, container_name.as_str()];
+                    self.usage
+                        .with_label_values(labels)
+                        .set(stats.usage.as_secs_f64());
+                    self.throttled
+                        .with_label_values(labels)
+                        .set(stats.throttled.as_secs_f64());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Collector for StatsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.usage
+            .desc()
+            .into_iter()
+            .chain(self.throttled.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if let Err(e) = self.refresh() {
+            warn!(error = %e, "Unable to read pod cgroup stats");
+        }
+        self.usage
+            .collect()
+            .into_iter()
+            .chain(self.throttled.collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environment::SystemClock;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn test_pod() -> Pod {
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "apps"},
+                "spec": {"containers": [
+                    {"name": "server", "resources": {"limits": {"cpu": "500m"}, "requests": {"cpu": "250m"}}},
+                    {"name": "sidecar", "resources": {"limits": {"cpu": "1"}}}
+                ]}
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_cpu_settings() {
+        assert_eq!(cpu_max(Some(500)), "50000 100000");
+        assert_eq!(cpu_max(Some(1)), "1000 100000");
+        assert_eq!(cpu_max(None), "max 100000");
+        assert_eq!(cpu_weight(1000), 39);
+        assert_eq!(cpu_weight(0), 1);
+        assert_eq!(pod_cpu_limit(&test_pod()), Some(1500));
+        assert_eq!(
+            parse_own_cgroup("0::/system.slice/krustlet.service\n"),
+            Some("/system.slice/krustlet.service")
+        );
+        assert_eq!(parse_own_cgroup("1:name=systemd:/\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_removal_is_retried_while_busy() {
+        tokio::time::pause();
+        let mut attempts = 0;
+        retry_busy(&SystemClock, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(std::io::Error::from_raw_os_error(
+                    nix::errno::Errno::EBUSY as i32,
+                ))
+            } else {
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let err = retry_busy(&SystemClock, || {
+            attempts += 1;
+            Err(std::io::Error::from_raw_os_error(
+                nix::errno::Errno::EBUSY as i32,
+            ))
+        })
+        .await
+        .unwrap_err();
+        assert!(is_busy(&err));
+        assert_eq!(attempts, REMOVE_ATTEMPTS);
+
+        // Anything else fails right away
+        let mut attempts = 0;
+        retry_busy(&SystemClock, || {
+            attempts += 1;
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_and_remove_pod_cgroups() {
+        let root = tempfile::tempdir().unwrap();
+        let manager = CgroupManager::new(root.path().to_owned(), Arc::new(SystemClock));
+        let pod = test_pod();
+        let server = manager
+            .create_container(&pod, &pod.containers()[0])
+            .unwrap();
+        manager
+            .create_container(&pod, &pod.containers()[1])
+            .unwrap();
+
+        let pod_path = root.path().join("apps_web");
+        let read = |path: &Path, file: &str| std::fs::read_to_string(path.join(file)).unwrap();
+        assert_eq!(read(&pod_path, "cpu.max"), "150000 100000");
+        assert_eq!(read(&pod_path, "cgroup.type"), "threaded");
+        assert_eq!(read(root.path(), "cgroup.subtree_control"), "+cpu");
+        assert_eq!(read(&pod_path.join("server"), "cpu.max"), "50000 100000");
+        assert_eq!(read(&pod_path.join("server"), "cpu.weight"), "10");
+
+        std::fs::write(
+            pod_path.join("server").join("cpu.stat"),
+            "usage_usec 1500000\nuser_usec 1000000\nthrottled_usec 250000\n",
+        )
+        .unwrap();
+        assert_eq!(
+            server.stats().unwrap(),
+            CpuStats {
+                usage: Duration::from_millis(1500),
+                throttled: Duration::from_millis(250),
+            }
+        );
+        let collector = StatsCollector::new(root.path().to_owned());
+        collector.refresh().unwrap();
+        assert_eq!(
+            collector
+                .usage
+                .with_label_values(&["apps", "web", "server"])
+                .get(),
+            1.5
+        );
+        assert_eq!(
+            manager
+                .cpu_stats(&PodKey::from(&pod), Some("server"))
+                .unwrap()
+                .usage,
+            Duration::from_millis(1500)
+        );
+
+        // The interface files of a real cgroup disappear with it, but not in a plain directory
+        for dir in &[
+            pod_path.join("server"),
+            pod_path.join("sidecar"),
+            pod_path.clone(),
+        ] {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_file() {
+                    std::fs::remove_file(path).unwrap();
+                }
+            }
+        }
+        manager.remove_pod(&PodKey::from(&pod)).await.unwrap();
+        assert!(!pod_path.exists());
+    }
+}
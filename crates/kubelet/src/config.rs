@@ -13,6 +13,7 @@
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[cfg(any(feature = "cli", feature = "docs"))]
 use std::iter::FromIterator;
@@ -24,9 +25,21 @@ use std::collections::HashMap;
 
 use serde::Deserialize;
 
+use crate::environment::Environment;
+use crate::resources::quantity::{Quantity, QuantityType};
+use crate::store::StoreScope;
+
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(0);
+const DEFAULT_PRE_PULL_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_SLOW_API_CALL_THRESHOLD: Duration = Duration::from_secs(1);
+const DEFAULT_REGISTRY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REGISTRY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IMAGE_PULL_DEADLINE: Duration = Duration::from_secs(600);
+const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+const DEFAULT_INSTANCE_POOL_MEMORY: u64 = 1024 * 1024 * 1024;
 
 /// The configuration needed for a kubelet to run properly.
 ///
@@ -66,6 +79,89 @@ pub struct Config {
     /// device plugins lives. This is also where device plugins
     /// should host their services.
     pub device_plugins_dir: PathBuf,
+    /// The total time the node will delay shutdown by to allow pods to terminate
+    /// gracefully when a shutdown is signaled by systemd-logind. A value of zero
+    /// disables graceful node shutdown
+    pub shutdown_grace_period: Duration,
+    /// Runtime overrides that pods may request through annotations (for example
+    /// `strip-clock` or `engine-opt-level`). Providers should reject pods asking
+    /// for an override that is not in this list
+    pub allowed_pod_overrides: Vec<String>,
+    /// How cached modules are shared between tenants (namespaces or pull
+    /// credentials) of the node
+    pub store_scope: StoreScope,
+    /// Image references to pull ahead of time and keep warm in the module store, so
+    /// that pods using them start without waiting on a pull
+    pub pre_pull_images: Vec<String>,
+    /// How often pre-pulled images are refreshed in the module store
+    pub pre_pull_interval: Duration,
+    /// Image pull secrets, as `namespace/name`, whose credentials are used to pre-pull images
+    /// from registries that don't allow anonymous pulls
+    pub pre_pull_secrets: Vec<String>,
+    /// How long to wait for a connection to a registry to be established
+    pub registry_connect_timeout: Duration,
+    /// How long to wait for a registry to send more of a response before giving up on it
+    pub registry_read_timeout: Duration,
+    /// The longest a single image pull may take, including authentication and
+    /// retrieving every layer
+    pub image_pull_deadline: Duration,
+    /// The CIDR pod IPs are assigned from. If not set, the pod CIDR assigned to the
+    /// node object is used, if any
+    pub pod_cidr: Option<String>,
+    /// The kubeconfig context to connect to the cluster with. If not set, the current context of
+    /// the kubeconfig is used
+    pub kube_context: Option<String>,
+    /// An executable that prints the kubeconfig to bootstrap with. If set, it is used instead of
+    /// the bootstrap file
+    pub bootstrap_plugin: Option<PathBuf>,
+    /// Requests to the Kubernetes API that take longer than this are logged along with the pod
+    /// or task that made them
+    pub slow_api_call_threshold: Duration,
+    /// A directory to also write container logs to, in the CRI format and the
+    /// `<namespace>_<name>_<uid>/<container>/<N>.log` layout used by the kubelet, for node log
+    /// collectors to pick up. Usually `/var/log/pods`
+    pub pod_log_dir: Option<PathBuf>,
+    /// Whether container log files are written in the CRI log format, with a timestamp and stream
+    /// on every line
+    pub cri_container_logs: bool,
+    /// The most bytes a container can write to its scratch space at `/tmp`, unless it sets an
+    /// `ephemeral-storage` limit. If not set, only containers with a limit have a quota
+    pub scratch_quota: Option<u64>,
+    /// The IP addresses of the cluster's DNS servers, which providers may use to resolve service
+    /// names for their workloads
+    pub cluster_dns: Vec<IpAddr>,
+    /// The DNS domain of the cluster, which service names are resolved in
+    pub cluster_domain: String,
+    /// The registries (such as `webassembly.azurecr.io`) or repository prefixes within them
+    /// (such as `ghcr.io/my-org`) pods may run images from. If empty, images may come from any
+    /// registry
+    pub allowed_registries: Vec<String>,
+    /// Whether pods may only run images that are pinned by digest
+    pub require_image_digests: bool,
+    /// Whether the digests of the SBOMs and provenance attestations attached to the images of a
+    /// pod are recorded in its annotations when it starts, for audit trails
+    pub record_image_attestations: bool,
+    /// How many instance slots are reserved up front for the modules the node runs, whose
+    /// compiled modules are kept so that restarts and scale-ups of the same image start faster.
+    /// If 0, modules are compiled afresh each time they are started
+    pub instance_pool_size: u32,
+    /// The most linear memory, in bytes, the pool's instance slots share between them. An
+    /// instance in the pool can't grow its memory past its slot's share
+    pub instance_pool_memory: u64,
+    /// Whether to wait for the lease of another Kubelet running with the same node name to
+    /// expire and then take the node over, rather than refusing to start
+    pub wait_for_node_lease: bool,
+    /// Whether a live lease held under the bare node name, as a stock kubelet or a Krustlet from
+    /// before lease identities were unique holds it, is taken over when the Kubelet first gets an
+    /// identity of its own, rather than treated as held by another process
+    pub take_over_legacy_lease: bool,
+    /// Quantities of resources, such as `memory=512Mi`, the node reports having in place of those
+    /// it detects
+    pub node_capacity: HashMap<String, String>,
+    /// The clock and network the Kubelet runs in. This can't be set from the configuration file
+    /// or the command line, and is the system clock and the real network unless an embedder,
+    /// such as an integration test, replaces them
+    pub environment: Environment,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -78,6 +174,9 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// Path to a file holding the bearer token for the admin endpoints, such as `/images`. The
+    /// admin endpoints are disabled if it isn't set.
+    pub admin_token_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -119,6 +218,8 @@ struct ConfigBuilder {
     pub server_tls_cert_file: Option<PathBuf>,
     #[serde(default, rename = "tlsPrivateKeyFile")]
     pub server_tls_private_key_file: Option<PathBuf>,
+    #[serde(default, rename = "adminTokenFile")]
+    pub server_admin_token_file: Option<PathBuf>,
     #[serde(default, rename = "allowLocalModules")]
     pub allow_local_modules: Option<bool>,
     #[serde(default, rename = "insecureRegistries")]
@@ -127,6 +228,90 @@ struct ConfigBuilder {
     pub plugins_dir: Option<PathBuf>,
     #[serde(default, rename = "devicePluginsDir")]
     pub device_plugins_dir: Option<PathBuf>,
+    #[serde(
+        default,
+        rename = "shutdownGracePeriod",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub shutdown_grace_period: Option<anyhow::Result<Duration>>,
+    #[serde(default, rename = "allowedPodOverrides")]
+    pub allowed_pod_overrides: Option<Vec<String>>,
+    #[serde(default, rename = "storeScope")]
+    pub store_scope: Option<StoreScope>,
+    #[serde(default, rename = "prePullImages")]
+    pub pre_pull_images: Option<Vec<String>>,
+    #[serde(
+        default,
+        rename = "prePullInterval",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub pre_pull_interval: Option<anyhow::Result<Duration>>,
+    #[serde(default, rename = "prePullSecrets")]
+    pub pre_pull_secrets: Option<Vec<String>>,
+    #[serde(
+        default,
+        rename = "registryConnectTimeout",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub registry_connect_timeout: Option<anyhow::Result<Duration>>,
+    #[serde(
+        default,
+        rename = "registryReadTimeout",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub registry_read_timeout: Option<anyhow::Result<Duration>>,
+    #[serde(
+        default,
+        rename = "imagePullDeadline",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub image_pull_deadline: Option<anyhow::Result<Duration>>,
+    #[serde(default, rename = "podCIDR")]
+    pub pod_cidr: Option<String>,
+    #[serde(default, rename = "kubeContext")]
+    pub kube_context: Option<String>,
+    #[serde(default, rename = "bootstrapPlugin")]
+    pub bootstrap_plugin: Option<PathBuf>,
+    #[serde(
+        default,
+        rename = "slowApiCallThreshold",
+        deserialize_with = "try_deserialize_duration"
+    )]
+    pub slow_api_call_threshold: Option<anyhow::Result<Duration>>,
+    #[serde(default, rename = "podLogDir")]
+    pub pod_log_dir: Option<PathBuf>,
+    #[serde(default, rename = "criContainerLogs")]
+    pub cri_container_logs: Option<bool>,
+    #[serde(
+        default,
+        rename = "scratchQuota",
+        deserialize_with = "try_deserialize_bytes"
+    )]
+    pub scratch_quota: Option<anyhow::Result<u64>>,
+    #[serde(default, rename = "clusterDNS")]
+    pub cluster_dns: Option<Vec<IpAddr>>,
+    #[serde(default, rename = "clusterDomain")]
+    pub cluster_domain: Option<String>,
+    #[serde(default, rename = "allowedRegistries")]
+    pub allowed_registries: Option<Vec<String>>,
+    #[serde(default, rename = "requireImageDigests")]
+    pub require_image_digests: Option<bool>,
+    #[serde(default, rename = "recordImageAttestations")]
+    pub record_image_attestations: Option<bool>,
+    #[serde(default, rename = "instancePoolSize")]
+    pub instance_pool_size: Option<u32>,
+    #[serde(
+        default,
+        rename = "instancePoolMemory",
+        deserialize_with = "try_deserialize_bytes"
+    )]
+    pub instance_pool_memory: Option<anyhow::Result<u64>>,
+    #[serde(default, rename = "waitForNodeLease")]
+    pub wait_for_node_lease: Option<bool>,
+    #[serde(default, rename = "takeOverLegacyLease")]
+    pub take_over_legacy_lease: Option<bool>,
+    #[serde(default, rename = "nodeCapacity")]
+    pub node_capacity: Option<HashMap<String, String>>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -165,6 +350,33 @@ impl Config {
             insecure_registries: None,
             plugins_dir,
             device_plugins_dir,
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
+            allowed_pod_overrides: vec![],
+            store_scope: StoreScope::default(),
+            pre_pull_images: vec![],
+            pre_pull_interval: DEFAULT_PRE_PULL_INTERVAL,
+            pre_pull_secrets: vec![],
+            registry_connect_timeout: DEFAULT_REGISTRY_CONNECT_TIMEOUT,
+            registry_read_timeout: DEFAULT_REGISTRY_READ_TIMEOUT,
+            image_pull_deadline: DEFAULT_IMAGE_PULL_DEADLINE,
+            pod_cidr: None,
+            kube_context: None,
+            bootstrap_plugin: None,
+            slow_api_call_threshold: DEFAULT_SLOW_API_CALL_THRESHOLD,
+            pod_log_dir: None,
+            cri_container_logs: false,
+            scratch_quota: None,
+            cluster_dns: vec![],
+            cluster_domain: DEFAULT_CLUSTER_DOMAIN.to_owned(),
+            allowed_registries: vec![],
+            require_image_digests: false,
+            record_image_attestations: false,
+            instance_pool_size: 0,
+            instance_pool_memory: DEFAULT_INSTANCE_POOL_MEMORY,
+            wait_for_node_lease: false,
+            take_over_legacy_lease: false,
+            node_capacity: HashMap::new(),
+            environment: Environment::default(),
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -173,6 +385,7 @@ impl Config {
                 port: DEFAULT_PORT,
                 cert_file,
                 private_key_file,
+                admin_token_file: None,
             },
         })
     }
@@ -236,6 +449,16 @@ impl Config {
         }
     }
 
+    /// Parses the command given on the command line, if any, which should be run instead of the
+    /// Kubelet. The version of your application should be passed to set the proper version for
+    /// the CLI.
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+    pub fn command_from_flags(version: &str) -> Option<Command> {
+        let app = Opts::clap().version(version);
+        Opts::from_clap(&app.get_matches()).command
+    }
+
     #[cfg(any(feature = "cli", feature = "docs"))]
     #[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
     fn new_from_file_and_flags_impl(version: &str, config_file_path: PathBuf) -> Self {
@@ -272,6 +495,12 @@ impl ConfigBuilder {
             .iter()
             .filter_map(|i| split_one_label(i))
             .collect();
+        let node_capacity: Vec<(String, String)> = opts
+            .node_capacity
+            .iter()
+            .filter_map(|i| split_one_label(i))
+            .filter(|(_, quantity)| !quantity.is_empty())
+            .collect();
 
         ConfigBuilder {
             node_ip: ok_result_of(opts.node_ip),
@@ -289,10 +518,45 @@ impl ConfigBuilder {
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
             plugins_dir: opts.plugins_dir,
             device_plugins_dir: opts.device_plugins_dir,
+            shutdown_grace_period: opts.shutdown_grace_period.map(|s| parse_duration(&s)),
+            allowed_pod_overrides: opts.allowed_pod_overrides.map(parse_comma_separated),
+            store_scope: opts.store_scope,
+            pre_pull_images: opts.pre_pull_images.map(parse_comma_separated),
+            pre_pull_interval: opts.pre_pull_interval.map(|s| parse_duration(&s)),
+            pre_pull_secrets: opts.pre_pull_secrets.map(parse_comma_separated),
+            registry_connect_timeout: opts.registry_connect_timeout.map(|s| parse_duration(&s)),
+            registry_read_timeout: opts.registry_read_timeout.map(|s| parse_duration(&s)),
+            image_pull_deadline: opts.image_pull_deadline.map(|s| parse_duration(&s)),
+            pod_cidr: opts.pod_cidr,
+            kube_context: opts.kube_context,
+            bootstrap_plugin: opts.bootstrap_plugin,
+            slow_api_call_threshold: opts.slow_api_call_threshold.map(|s| parse_duration(&s)),
+            pod_log_dir: opts.pod_log_dir,
+            cri_container_logs: opts.cri_container_logs,
+            scratch_quota: opts.scratch_quota.map(|s| parse_bytes(&s)),
+            cluster_dns: if opts.cluster_dns.is_empty() {
+                None
+            } else {
+                Some(opts.cluster_dns)
+            },
+            cluster_domain: opts.cluster_domain,
+            allowed_registries: opts.allowed_registries.map(parse_comma_separated),
+            require_image_digests: opts.require_image_digests,
+            record_image_attestations: opts.record_image_attestations,
+            instance_pool_size: opts.instance_pool_size,
+            instance_pool_memory: opts.instance_pool_memory.map(|s| parse_bytes(&s)),
+            wait_for_node_lease: opts.wait_for_node_lease,
+            take_over_legacy_lease: opts.take_over_legacy_lease,
+            node_capacity: if node_capacity.is_empty() {
+                None
+            } else {
+                Some(HashMap::from_iter(node_capacity))
+            },
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            server_admin_token_file: opts.admin_token_file,
         }
     }
 
@@ -328,9 +592,44 @@ impl ConfigBuilder {
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
             device_plugins_dir: other.device_plugins_dir.or(self.device_plugins_dir),
+            shutdown_grace_period: other.shutdown_grace_period.or(self.shutdown_grace_period),
+            allowed_pod_overrides: other.allowed_pod_overrides.or(self.allowed_pod_overrides),
+            store_scope: other.store_scope.or(self.store_scope),
+            pre_pull_images: other.pre_pull_images.or(self.pre_pull_images),
+            pre_pull_interval: other.pre_pull_interval.or(self.pre_pull_interval),
+            pre_pull_secrets: other.pre_pull_secrets.or(self.pre_pull_secrets),
+            registry_connect_timeout: other
+                .registry_connect_timeout
+                .or(self.registry_connect_timeout),
+            registry_read_timeout: other.registry_read_timeout.or(self.registry_read_timeout),
+            image_pull_deadline: other.image_pull_deadline.or(self.image_pull_deadline),
+            pod_cidr: other.pod_cidr.or(self.pod_cidr),
+            kube_context: other.kube_context.or(self.kube_context),
+            bootstrap_plugin: other.bootstrap_plugin.or(self.bootstrap_plugin),
+            slow_api_call_threshold: other
+                .slow_api_call_threshold
+                .or(self.slow_api_call_threshold),
+            pod_log_dir: other.pod_log_dir.or(self.pod_log_dir),
+            cri_container_logs: other.cri_container_logs.or(self.cri_container_logs),
+            scratch_quota: other.scratch_quota.or(self.scratch_quota),
+            cluster_dns: other.cluster_dns.or(self.cluster_dns),
+            cluster_domain: other.cluster_domain.or(self.cluster_domain),
+            allowed_registries: other.allowed_registries.or(self.allowed_registries),
+            require_image_digests: other.require_image_digests.or(self.require_image_digests),
+            record_image_attestations: other
+                .record_image_attestations
+                .or(self.record_image_attestations),
+            instance_pool_size: other.instance_pool_size.or(self.instance_pool_size),
+            instance_pool_memory: other.instance_pool_memory.or(self.instance_pool_memory),
+            wait_for_node_lease: other.wait_for_node_lease.or(self.wait_for_node_lease),
+            take_over_legacy_lease: other.take_over_legacy_lease.or(self.take_over_legacy_lease),
+            node_capacity: other.node_capacity.or(self.node_capacity),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
+            server_admin_token_file: other
+                .server_admin_token_file
+                .or(self.server_admin_token_file),
         }
     }
 
@@ -371,6 +670,38 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let shutdown_grace_period = self
+            .shutdown_grace_period
+            .unwrap_or(Ok(DEFAULT_SHUTDOWN_GRACE_PERIOD))
+            .map_err(|e| invalid_config_value_error(e, "shutdown grace period"))?;
+        let pre_pull_interval = self
+            .pre_pull_interval
+            .unwrap_or(Ok(DEFAULT_PRE_PULL_INTERVAL))
+            .map_err(|e| invalid_config_value_error(e, "pre-pull interval"))?;
+        let registry_connect_timeout = self
+            .registry_connect_timeout
+            .unwrap_or(Ok(DEFAULT_REGISTRY_CONNECT_TIMEOUT))
+            .map_err(|e| invalid_config_value_error(e, "registry connect timeout"))?;
+        let registry_read_timeout = self
+            .registry_read_timeout
+            .unwrap_or(Ok(DEFAULT_REGISTRY_READ_TIMEOUT))
+            .map_err(|e| invalid_config_value_error(e, "registry read timeout"))?;
+        let image_pull_deadline = self
+            .image_pull_deadline
+            .unwrap_or(Ok(DEFAULT_IMAGE_PULL_DEADLINE))
+            .map_err(|e| invalid_config_value_error(e, "image pull deadline"))?;
+        let slow_api_call_threshold = self
+            .slow_api_call_threshold
+            .unwrap_or(Ok(DEFAULT_SLOW_API_CALL_THRESHOLD))
+            .map_err(|e| invalid_config_value_error(e, "slow API call threshold"))?;
+        let scratch_quota = self
+            .scratch_quota
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "scratch quota"))?;
+        let instance_pool_memory = self
+            .instance_pool_memory
+            .unwrap_or(Ok(DEFAULT_INSTANCE_POOL_MEMORY))
+            .map_err(|e| invalid_config_value_error(e, "instance pool memory"))?;
 
         Ok(Config {
             node_ip,
@@ -384,9 +715,39 @@ impl ConfigBuilder {
             insecure_registries: self.insecure_registries,
             plugins_dir,
             device_plugins_dir,
+            shutdown_grace_period,
+            allowed_pod_overrides: self.allowed_pod_overrides.unwrap_or_default(),
+            store_scope: self.store_scope.unwrap_or_default(),
+            pre_pull_images: self.pre_pull_images.unwrap_or_default(),
+            pre_pull_interval,
+            pre_pull_secrets: self.pre_pull_secrets.unwrap_or_default(),
+            registry_connect_timeout,
+            registry_read_timeout,
+            image_pull_deadline,
+            pod_cidr: self.pod_cidr,
+            kube_context: self.kube_context,
+            bootstrap_plugin: self.bootstrap_plugin,
+            slow_api_call_threshold,
+            pod_log_dir: self.pod_log_dir,
+            cri_container_logs: self.cri_container_logs.unwrap_or(false),
+            scratch_quota,
+            cluster_dns: self.cluster_dns.unwrap_or_default(),
+            cluster_domain: self
+                .cluster_domain
+                .unwrap_or_else(|| DEFAULT_CLUSTER_DOMAIN.to_owned()),
+            allowed_registries: self.allowed_registries.unwrap_or_default(),
+            require_image_digests: self.require_image_digests.unwrap_or(false),
+            record_image_attestations: self.record_image_attestations.unwrap_or(false),
+            instance_pool_size: self.instance_pool_size.unwrap_or(0),
+            instance_pool_memory,
+            wait_for_node_lease: self.wait_for_node_lease.unwrap_or(false),
+            take_over_legacy_lease: self.take_over_legacy_lease.unwrap_or(false),
+            node_capacity: self.node_capacity.unwrap_or_default(),
+            environment: Environment::default(),
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
+                admin_token_file: self.server_admin_token_file,
                 addr: server_addr,
                 port: server_port,
             },
@@ -413,6 +774,55 @@ where
     Ok(Some(n))
 }
 
+fn try_deserialize_duration<'de, D>(d: D) -> Result<Option<anyhow::Result<Duration>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    Ok(Some(parse_duration(&s)))
+}
+
+/// Parses a duration of the form used in Kubernetes configuration (e.g. `30s`,
+/// `500ms`, `5m` or `1h`). A bare number is treated as a number of seconds.
+pub(crate) fn parse_duration(source: &str) -> anyhow::Result<Duration> {
+    let source = source.trim();
+    let split = source
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(source.len());
+    let (value, unit) = source.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid duration {:?}", source))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        _ => Err(anyhow::anyhow!(
+            "invalid duration {:?}: unit must be one of ms, s, m or h",
+            source
+        )),
+    }
+}
+
+fn try_deserialize_bytes<'de, D>(d: D) -> Result<Option<anyhow::Result<u64>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    Ok(Some(parse_bytes(&s)))
+}
+
+/// Parses a number of bytes given as a Kubernetes quantity (e.g. `64Mi` or `1G`).
+fn parse_bytes(source: &str) -> anyhow::Result<u64> {
+    let quantity =
+        k8s_openapi::apimachinery::pkg::api::resource::Quantity(source.trim().to_owned());
+    match Quantity::from_kube_quantity(QuantityType::Memory(&quantity)) {
+        Ok(Quantity::Memory(bytes)) => Ok(bytes as u64),
+        _ => Err(anyhow::anyhow!("invalid quantity of bytes {:?}", source)),
+    }
+}
+
 /// CLI options that can be configured for Kubelet
 ///
 /// These can be parsed from args using `Opts::into_app()`
@@ -461,6 +871,13 @@ pub struct Opts {
     )]
     private_key_file: Option<PathBuf>,
 
+    #[structopt(
+        long = "admin-token-file",
+        env = "KRUSTLET_ADMIN_TOKEN_FILE",
+        help = "The path to a file holding the bearer token for the admin endpoints, such as /images, which are disabled if it isn't set"
+    )]
+    admin_token_file: Option<PathBuf>,
+
     #[structopt(
         short = "n",
         long = "node-ip",
@@ -541,6 +958,220 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "shutdown-grace-period",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD",
+        help = "The total time to delay node shutdown by so pods can terminate gracefully (e.g. 30s). Defaults to 0s, which disables graceful node shutdown"
+    )]
+    shutdown_grace_period: Option<String>,
+
+    #[structopt(
+        long = "allowed-pod-overrides",
+        env = "KRUSTLET_ALLOWED_POD_OVERRIDES",
+        help = "Runtime overrides that pods may request through annotations (comma separated)"
+    )]
+    allowed_pod_overrides: Option<String>,
+
+    #[structopt(
+        long = "store-scope",
+        env = "KRUSTLET_STORE_SCOPE",
+        help = "How cached modules are shared between tenants: shared, namespace or credential. Defaults to shared"
+    )]
+    store_scope: Option<StoreScope>,
+
+    #[structopt(
+        long = "pre-pull-images",
+        env = "KRUSTLET_PRE_PULL_IMAGES",
+        help = "Image references to pull ahead of time and keep warm in the module store (comma separated)"
+    )]
+    pre_pull_images: Option<String>,
+
+    #[structopt(
+        long = "pre-pull-interval",
+        env = "KRUSTLET_PRE_PULL_INTERVAL",
+        help = "How often pre-pulled images are refreshed (e.g. 10m). Defaults to 5m"
+    )]
+    pre_pull_interval: Option<String>,
+
+    #[structopt(
+        long = "pre-pull-secrets",
+        env = "KRUSTLET_PRE_PULL_SECRETS",
+        help = "Image pull secrets to pre-pull images with, as namespace/name (comma separated)"
+    )]
+    pre_pull_secrets: Option<String>,
+
+    #[structopt(
+        long = "registry-connect-timeout",
+        env = "KRUSTLET_REGISTRY_CONNECT_TIMEOUT",
+        help = "How long to wait for a connection to a registry (e.g. 10s). Defaults to 10s"
+    )]
+    registry_connect_timeout: Option<String>,
+
+    #[structopt(
+        long = "registry-read-timeout",
+        env = "KRUSTLET_REGISTRY_READ_TIMEOUT",
+        help = "How long to wait for a registry to send more of a response (e.g. 30s). Defaults to 30s"
+    )]
+    registry_read_timeout: Option<String>,
+
+    #[structopt(
+        long = "image-pull-deadline",
+        env = "KRUSTLET_IMAGE_PULL_DEADLINE",
+        help = "The longest a single image pull may take (e.g. 10m). Defaults to 10m"
+    )]
+    image_pull_deadline: Option<String>,
+
+    #[structopt(
+        long = "pod-cidr",
+        env = "KRUSTLET_POD_CIDR",
+        help = "The CIDR pod IPs are assigned from (e.g. 10.244.0.0/24). Defaults to the pod CIDR of the node object"
+    )]
+    pod_cidr: Option<String>,
+
+    #[structopt(
+        long = "context",
+        env = "KRUSTLET_CONTEXT",
+        help = "The kubeconfig context to use. Defaults to the current context of the kubeconfig"
+    )]
+    kube_context: Option<String>,
+
+    #[structopt(
+        long = "bootstrap-plugin",
+        env = "KRUSTLET_BOOTSTRAP_PLUGIN",
+        help = "An executable that prints the kubeconfig to bootstrap with, such as one that exchanges a cloud instance identity for a token. Overrides the bootstrap file"
+    )]
+    bootstrap_plugin: Option<PathBuf>,
+
+    #[structopt(
+        long = "slow-api-call-threshold",
+        env = "KRUSTLET_SLOW_API_CALL_THRESHOLD",
+        help = "Requests to the Kubernetes API that take longer than this are logged (e.g. 500ms). Defaults to 1s"
+    )]
+    slow_api_call_threshold: Option<String>,
+
+    #[structopt(
+        long = "pod-log-dir",
+        env = "KRUSTLET_POD_LOG_DIR",
+        help = "A directory to also write container logs to in the kubelet's layout and the CRI log format, such as /var/log/pods, for node log collectors to pick up"
+    )]
+    pod_log_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "cri-container-logs",
+        env = "KRUSTLET_CRI_CONTAINER_LOGS",
+        help = "Whether to write container log files in the CRI log format, which lets the log endpoint return timestamps"
+    )]
+    cri_container_logs: Option<bool>,
+
+    #[structopt(
+        long = "scratch-quota",
+        env = "KRUSTLET_SCRATCH_QUOTA",
+        help = "The most a container can write to its scratch space at /tmp, as a quantity such as 64Mi, unless it sets an ephemeral-storage limit"
+    )]
+    scratch_quota: Option<String>,
+
+    #[structopt(
+        long = "cluster-dns",
+        env = "KRUSTLET_CLUSTER_DNS",
+        use_delimiter = true,
+        help = "The IP addresses of the cluster's DNS servers (comma separated), which WASI modules can resolve service names with"
+    )]
+    cluster_dns: Vec<IpAddr>,
+
+    #[structopt(
+        long = "cluster-domain",
+        env = "KRUSTLET_CLUSTER_DOMAIN",
+        help = "The DNS domain of the cluster. Defaults to cluster.local"
+    )]
+    cluster_domain: Option<String>,
+
+    #[structopt(
+        long = "allowed-registries",
+        env = "KRUSTLET_ALLOWED_REGISTRIES",
+        help = "The registries or repository prefixes, such as ghcr.io/my-org, pods may run images from (comma separated). Defaults to any registry"
+    )]
+    allowed_registries: Option<String>,
+
+    #[structopt(
+        long = "require-image-digests",
+        env = "KRUSTLET_REQUIRE_IMAGE_DIGESTS",
+        help = "Whether pods may only run images that are pinned by digest"
+    )]
+    require_image_digests: Option<bool>,
+
+    #[structopt(
+        long = "record-image-attestations",
+        env = "KRUSTLET_RECORD_IMAGE_ATTESTATIONS",
+        help = "Whether to record the digests of the SBOMs and provenance attestations attached to a pod's images in its annotations"
+    )]
+    record_image_attestations: Option<bool>,
+
+    #[structopt(
+        long = "instance-pool-size",
+        env = "KRUSTLET_INSTANCE_POOL_SIZE",
+        help = "How many instance slots to reserve for modules, keeping recently run modules compiled for restarts and scale-ups. Defaults to 0, which disables the pool"
+    )]
+    instance_pool_size: Option<u32>,
+
+    #[structopt(
+        long = "instance-pool-memory",
+        env = "KRUSTLET_INSTANCE_POOL_MEMORY",
+        help = "The most linear memory the pooled instances may share, as a quantity such as 512Mi. Defaults to 1Gi"
+    )]
+    instance_pool_memory: Option<String>,
+
+    #[structopt(
+        long = "wait-for-node-lease",
+        env = "KRUSTLET_WAIT_FOR_NODE_LEASE",
+        help = "Whether to wait for another Kubelet holding the node's lease to stop renewing it and then take the node over, rather than refusing to start"
+    )]
+    wait_for_node_lease: Option<bool>,
+
+    #[structopt(
+        long = "take-over-legacy-lease",
+        env = "KRUSTLET_TAKE_OVER_LEGACY_LEASE",
+        help = "Whether to take over a live node lease held under the bare node name, as an earlier Krustlet held it, the first time this Kubelet runs. Leave this off if the node may be run by a stock kubelet"
+    )]
+    take_over_legacy_lease: Option<bool>,
+
+    #[structopt(
+        long = "node-capacity",
+        env = "KRUSTLET_NODE_CAPACITY",
+        use_delimiter = true,
+        help = "Quantities of resources the node reports having in place of those it detects, as resource=quantity pairs separated by ',', such as cpu=2,memory=512Mi"
+    )]
+    node_capacity: Vec<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands that can be run instead of the Kubelet, using its configuration
+#[derive(StructOpt, Clone, Debug)]
+#[cfg(any(feature = "cli", feature = "docs"))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+pub enum Command {
+    /// Gathers the node's configuration, logs, status and module store inventory into a tarball
+    /// to attach to bug reports
+    SupportBundle {
+        /// Where to write the tarball
+        #[structopt(
+            short = "o",
+            long = "output",
+            default_value = "krustlet-support-bundle.tar"
+        )]
+        output: PathBuf,
+
+        /// A file the Kubelet logs to, whose most recent lines are included. Can be given more
+        /// than once
+        #[structopt(long = "log-file")]
+        log_files: Vec<PathBuf>,
+
+        /// How many of the most recent lines of each log file to include
+        #[structopt(long = "log-lines", default_value = "1000")]
+        log_lines: usize,
+    },
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -687,7 +1318,33 @@ mod test {
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "pluginsDir": "/some/plugins",
+            "shutdownGracePeriod": "45s",
+            "allowedPodOverrides": ["strip-clock"],
+            "storeScope": "namespace",
+            "prePullImages": ["webassembly.azurecr.io/hello-wasm:v1"],
+            "prePullInterval": "10m",
+            "prePullSecrets": ["kube-system/registry-credentials"],
+            "podCIDR": "10.42.0.0/24",
+            "kubeContext": "managed-cluster",
+            "bootstrapPlugin": "/usr/bin/identity-plugin",
+            "registryConnectTimeout": "5s",
+            "registryReadTimeout": "1m",
+            "imagePullDeadline": "15m",
+            "scratchQuota": "64Mi",
+            "clusterDNS": ["10.96.0.10"],
+            "clusterDomain": "krusty.local",
+            "allowedRegistries": ["webassembly.azurecr.io"],
+            "requireImageDigests": true,
+            "recordImageAttestations": true,
+            "instancePoolSize": 8,
+            "instancePoolMemory": "256Mi",
+            "waitForNodeLease": true,
+            "takeOverLegacyLease": true,
+            "nodeCapacity": {
+                "cpu": "1500m",
+                "memory": "512Mi"
+            }
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
@@ -717,6 +1374,46 @@ mod test {
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(45));
+        assert_eq!(config.allowed_pod_overrides, vec!["strip-clock".to_owned()]);
+        assert_eq!(config.store_scope, StoreScope::Namespace);
+        assert_eq!(
+            config.pre_pull_images,
+            vec!["webassembly.azurecr.io/hello-wasm:v1".to_owned()]
+        );
+        assert_eq!(config.pre_pull_interval, Duration::from_secs(600));
+        assert_eq!(
+            config.pre_pull_secrets,
+            vec!["kube-system/registry-credentials".to_owned()]
+        );
+        assert_eq!(config.scratch_quota, Some(64 * 1024 * 1024));
+        assert_eq!(config.pod_cidr.as_deref(), Some("10.42.0.0/24"));
+        assert_eq!(config.kube_context.as_deref(), Some("managed-cluster"));
+        assert_eq!(
+            config.bootstrap_plugin,
+            Some(PathBuf::from("/usr/bin/identity-plugin"))
+        );
+        assert_eq!(config.registry_connect_timeout, Duration::from_secs(5));
+        assert_eq!(config.registry_read_timeout, Duration::from_secs(60));
+        assert_eq!(config.image_pull_deadline, Duration::from_secs(900));
+        assert_eq!(
+            config.cluster_dns,
+            vec![IpAddr::V4(Ipv4Addr::new(10, 96, 0, 10))]
+        );
+        assert_eq!(config.cluster_domain, "krusty.local");
+        assert_eq!(
+            config.allowed_registries,
+            vec!["webassembly.azurecr.io".to_owned()]
+        );
+        assert!(config.require_image_digests);
+        assert!(config.record_image_attestations);
+        assert_eq!(config.instance_pool_size, 8);
+        assert_eq!(config.instance_pool_memory, 256 * 1024 * 1024);
+        assert!(config.wait_for_node_lease);
+        assert!(config.take_over_legacy_lease);
+        assert_eq!(config.node_capacity.len(), 2);
+        assert_eq!(config.node_capacity.get("cpu").unwrap(), "1500m");
+        assert_eq!(config.node_capacity.get("memory").unwrap(), "512Mi");
     }
 
     #[test]
@@ -782,6 +1479,26 @@ mod test {
             &config.plugins_dir.to_string_lossy(),
             "/fallback/plugins/dir"
         );
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(0));
+        assert!(config.allowed_pod_overrides.is_empty());
+        assert_eq!(config.store_scope, StoreScope::Shared);
+        assert!(config.pre_pull_images.is_empty());
+        assert_eq!(config.pre_pull_interval, Duration::from_secs(300));
+        assert!(config.pre_pull_secrets.is_empty());
+        assert_eq!(config.pod_cidr, None);
+        assert_eq!(config.registry_connect_timeout, Duration::from_secs(10));
+        assert_eq!(config.registry_read_timeout, Duration::from_secs(30));
+        assert_eq!(config.image_pull_deadline, Duration::from_secs(600));
+        assert!(config.cluster_dns.is_empty());
+        assert_eq!(config.cluster_domain, "cluster.local");
+        assert!(config.allowed_registries.is_empty());
+        assert!(!config.require_image_digests);
+        assert!(!config.record_image_attestations);
+        assert_eq!(config.instance_pool_size, 0);
+        assert_eq!(config.instance_pool_memory, 1024 * 1024 * 1024);
+        assert!(!config.wait_for_node_lease);
+        assert!(!config.take_over_legacy_lease);
+        assert!(config.node_capacity.is_empty());
     }
 
     #[test]
@@ -918,6 +1635,17 @@ mod test {
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
     }
 
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
     #[test]
     fn malformed_config_file_is_reported() {
         let config_builder = builder_from_json_string(
@@ -12,6 +12,9 @@ impl ClientConfigSource for Config {
         };
         ClientConfig {
             protocol,
+            connect_timeout: Some(self.registry_connect_timeout),
+            read_timeout: Some(self.registry_read_timeout),
+            pull_deadline: Some(self.image_pull_deadline),
             ..Default::default()
         }
     }
@@ -38,11 +41,39 @@ mod test {
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             node_labels: std::collections::HashMap::new(),
             node_name: "nope".to_owned(),
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            allowed_pod_overrides: vec![],
+            store_scope: crate::store::StoreScope::Shared,
+            pre_pull_images: vec![],
+            pre_pull_interval: std::time::Duration::from_secs(300),
+            pre_pull_secrets: vec![],
+            registry_connect_timeout: std::time::Duration::from_secs(10),
+            registry_read_timeout: std::time::Duration::from_secs(30),
+            image_pull_deadline: std::time::Duration::from_secs(600),
+            pod_cidr: None,
+            kube_context: None,
+            bootstrap_plugin: None,
+            slow_api_call_threshold: std::time::Duration::from_secs(1),
+            pod_log_dir: None,
+            cri_container_logs: false,
+            scratch_quota: None,
+            cluster_dns: vec![],
+            cluster_domain: "cluster.local".to_owned(),
+            allowed_registries: vec![],
+            require_image_digests: false,
+            record_image_attestations: false,
+            instance_pool_size: 0,
+            instance_pool_memory: 0,
+            wait_for_node_lease: false,
+            take_over_legacy_lease: false,
+            node_capacity: std::collections::HashMap::new(),
+            environment: Default::default(),
             server_config: crate::config::ServerConfig {
                 addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                admin_token_file: None,
             },
         }
     }
@@ -54,6 +85,36 @@ mod test {
         assert_eq!(ClientProtocol::Https, client_config.protocol);
     }
 
+    #[test]
+    fn oci_config_respects_registry_timeouts() {
+        let config = Config {
+            registry_connect_timeout: std::time::Duration::from_secs(5),
+            ..empty_config()
+        };
+        let client_config = config.client_config();
+        assert_eq!(
+            Some(std::time::Duration::from_secs(5)),
+            client_config.connect_timeout
+        );
+        assert_eq!(
+            Some(std::time::Duration::from_secs(30)),
+            client_config.read_timeout
+        );
+    }
+
+    #[test]
+    fn oci_config_respects_image_pull_deadline() {
+        let config = Config {
+            image_pull_deadline: std::time::Duration::from_secs(120),
+            ..empty_config()
+        };
+        let client_config = config.client_config();
+        assert_eq!(
+            Some(std::time::Duration::from_secs(120)),
+            client_config.pull_deadline
+        );
+    }
+
     #[test]
     fn oci_config_respects_config_insecure_registries() {
         let config = Config {
@@ -3,8 +3,8 @@ use std::io::SeekFrom;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 
 use crate::container::ContainerMap;
-use crate::handle::StopHandler;
-use crate::log::{stream, HandleFactory, Sender};
+use crate::handle::{RuntimeInfo, StopHandler};
+use crate::log::{follow, stream_formatted, HandleFactory, Sender};
 
 /// Represents a handle to a running "container" (whatever that might be). This
 /// can be used on its own, however, it is generally better to use it as a part
@@ -46,10 +46,37 @@ impl<H: StopHandler, F> Handle<H, F> {
     {
         let mut handle = self.handle_factory.new_handle();
         handle.seek(SeekFrom::Start(0)).await?;
-        tokio::spawn(stream(handle, sender));
+        let format = self.handle_factory.format();
+        let subscription = if sender.follow() {
+            self.handle_factory.subscribe()
+        } else {
+            None
+        };
+        match subscription {
+            Some(subscription) => tokio::spawn(follow(handle, sender, format, subscription)),
+            None => tokio::spawn(stream_formatted(handle, sender, format)),
+        };
         Ok(())
     }
 
+    /// The number of bytes of logs the running process has written, if known.
+    pub(crate) fn log_size<R>(&self) -> Option<u64>
+    where
+        F: HandleFactory<R>,
+    {
+        self.handle_factory.log_size()
+    }
+
+    /// The stdin of the running process, if clients can attach to it.
+    pub(crate) fn stdin(&self) -> Option<std::sync::Arc<crate::handle::AttachedStdin>> {
+        self.handle.stdin()
+    }
+
+    /// Describes the running process, if the handle knows how.
+    pub(crate) fn runtime_info(&self) -> Option<RuntimeInfo> {
+        self.handle.runtime_info()
+    }
+
     /// Wait for the running process to complete. Generally speaking,
     /// [`Handle::stop`] should be called first. This uses the underlying
     /// [`StopHandler`] implementation passed to the constructor
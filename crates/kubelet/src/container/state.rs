@@ -1,7 +1,8 @@
 //! Functions for running Container state machines.
 use crate::container::{patch_container_status, Status};
 use crate::container::{Container, ContainerKey};
-use crate::pod::Pod;
+use crate::pod::{catch_panic, quarantine_and_fail, Pod};
+use crate::state::replay::{self, Outcome};
 use chrono::Utc;
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::Pod as KubePod;
@@ -17,6 +18,9 @@ pub mod prelude {
 }
 
 /// Iteratively evaluate state machine until it returns Complete.
+///
+/// A panic in one of the states fails the container rather than the task running it, and
+/// quarantines its pod, which is reported `Failed`.
 #[instrument(
     level = "info", 
     skip(
@@ -113,21 +117,39 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
         }
 
         debug!(?state, "Pod container executing state handler");
-        let transition = {
-            state
-                .next(shared.clone(), &mut container_state, container_rx.clone())
-                .await
+        let transition = match catch_panic(state.next(
+            shared.clone(),
+            &mut container_state,
+            container_rx.clone(),
+        ))
+        .await
+        {
+            Ok(transition) => transition,
+            Err(message) => {
+                quarantine_and_fail(client, &latest_pod, &message).await;
+                Transition::Complete(Err(anyhow::anyhow!("Provider panicked: {}", message)))
+            }
         };
 
         state = match transition {
             Transition::Next(s) => {
-                let state = s.into();
+                let state: Box<dyn State<S>> = s.into();
                 debug!(?state, "Pod container transitioning to state");
+                replay::record_outcome(
+                    &latest_pod,
+                    Some(&container_name.to_string()),
+                    &Outcome::Next(replay::state_name(&state)),
+                );
                 state
             }
             Transition::Complete(result) => match result {
                 Ok(()) => {
                     debug!("Pod container state machine exited without error");
+                    replay::record_outcome(
+                        &latest_pod,
+                        Some(&container_name.to_string()),
+                        &Outcome::Complete(Ok(())),
+                    );
                     break result;
                 }
                 Err(ref e) => {
@@ -135,6 +157,11 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                         error = %e,
                         "Pod container state machine exited with error"
                     );
+                    replay::record_outcome(
+                        &latest_pod,
+                        Some(&container_name.to_string()),
+                        &Outcome::Complete(Err(format!("{:#}", e))),
+                    );
                     let status = Status::Terminated {
                         timestamp: Utc::now(),
                         message: format!("Container exited with error: {:?}.", e),
@@ -150,3 +177,114 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
         };
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::pin_mut;
+    use http::{Method, Request as HttpRequest, Response as HttpResponse};
+    use hyper::Body;
+    use std::sync::{Arc, Mutex};
+    use tower_test::mock;
+
+    struct ContainerState;
+
+    #[async_trait::async_trait]
+    impl ObjectState for ContainerState {
+        type Manifest = Container;
+        type Status = Status;
+        type SharedState = ();
+        async fn async_drop(self, _shared: &mut ()) {}
+    }
+
+    #[derive(Debug)]
+    struct Exploding;
+
+    #[async_trait::async_trait]
+    impl State<ContainerState> for Exploding {
+        async fn next(
+            self: Box<Self>,
+            _shared: SharedState<()>,
+            _state: &mut ContainerState,
+            _container: Manifest<Container>,
+        ) -> Transition<ContainerState> {
+            panic!("module exploded")
+        }
+
+        async fn status(
+            &self,
+            _state: &mut ContainerState,
+            _container: &Container,
+        ) -> anyhow::Result<Status> {
+            Ok(Status::Waiting {
+                timestamp: Utc::now(),
+                message: "Exploding".to_owned(),
+            })
+        }
+    }
+
+    /// A client that answers every request with the pod, recording the bodies of patches
+    fn mock_client(pod: KubePod) -> (kube::Client, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = patches.clone();
+        tokio::spawn(async move {
+            pin_mut!(handle);
+            while let Some((request, send)) = handle.next_request().await {
+                if request.method() == Method::PATCH {
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_slice(&body).unwrap());
+                }
+                send.send_response(
+                    HttpResponse::builder()
+                        .body(Body::from(serde_json::to_vec(&pod).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        (kube::Client::new(mock_service, "default"), patches)
+    }
+
+    #[tokio::test]
+    async fn test_panicking_state_fails_container_and_quarantines_pod() {
+        // Quarantined pods are global, so the pod gets a name no other test uses
+        let name = format!("exploding-{}", uuid::Uuid::new_v4());
+        let kube_pod: KubePod = serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": name, "namespace": "default", "uid": "1234" },
+            "spec": { "containers": [{ "name": "server" }] }
+        }))
+        .unwrap();
+        let pod = Pod::from(kube_pod.clone());
+        let (client, patches) = mock_client(kube_pod);
+        let (_updates, manifest) = Manifest::new(pod.clone(), krator::store::Store::new());
+
+        let err = run_to_completion(
+            &client,
+            Exploding,
+            Arc::new(tokio::sync::RwLock::new(())),
+            ContainerState,
+            manifest,
+            ContainerKey::App("server".to_owned()),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().contains("module exploded"));
+        assert_eq!(
+            crate::pod::quarantined(&pod).as_deref(),
+            Some("module exploded")
+        );
+        assert!(patches
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|patch| patch["status"]["reason"] == crate::pod::PROVIDER_PANIC));
+        crate::pod::release_quarantine(&pod);
+        assert_eq!(crate::pod::quarantined(&pod), None);
+    }
+}
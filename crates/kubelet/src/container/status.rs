@@ -121,6 +121,7 @@ pub async fn patch_container_status(
     match pod.find_container(&key) {
         Some(container) => {
             let kube_status = status.to_kubernetes(container.name());
+            crate::pod::record_container_status(pod, key.is_init(), kube_status.clone());
 
             let patches = match pod.container_status_index(&key) {
                 Some(idx) => {
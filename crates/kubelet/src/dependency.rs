@@ -0,0 +1,319 @@
+//! Tracks the ConfigMaps and Secrets that running pods depend on.
+//!
+//! Rather than fetching a ConfigMap or Secret every time a pod resolves an environment variable
+//! or mounts a volume, the [`DependencyTracker`] keeps a single watch open for each object that
+//! is referenced by at least one pod. Every consumer of an object reads from (or subscribes to)
+//! the same cached copy, and the watch is dropped once the last pod referencing it is released.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::api::{Api, ListParams};
+use kube_runtime::watcher::{watcher, Event};
+use serde::de::DeserializeOwned;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::environment::Clock;
+use crate::pod::{Pod, PodKey};
+
+/// How long to wait for a new watch to report the current state of an object before falling
+/// back to fetching it directly
+const INITIAL_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before retrying a watch that returned an error
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The last known state of a watched object
+#[derive(Clone, Debug)]
+enum Cached<K> {
+    /// The watch hasn't listed the object yet
+    Syncing,
+    /// The object doesn't exist
+    Missing,
+    Present(K),
+}
+
+struct Entry<K> {
+    pods: HashSet<PodKey>,
+    state: watch::Receiver<Cached<K>>,
+    task: JoinHandle<()>,
+}
+
+/// Watches keyed by the namespace and name of the object
+type Watches<K> = HashMap<(String, String), Entry<K>>;
+
+/// Shares a single watch per referenced ConfigMap or Secret between all the pods that use it.
+///
+/// Cloning the tracker is cheap and all clones share the same watches.
+#[derive(Clone)]
+pub struct DependencyTracker {
+    client: kube::Client,
+    clock: Arc<dyn Clock>,
+    config_maps: Arc<Mutex<Watches<ConfigMap>>>,
+    secrets: Arc<Mutex<Watches<Secret>>>,
+}
+
+/// A stream of updates to a single watched object.
+pub struct Subscription<K> {
+    state: watch::Receiver<Cached<K>>,
+}
+
+impl<K: Clone> Subscription<K> {
+    /// Waits for the object to change, returning its new contents. Deletions of the object are
+    /// skipped so that consumers keep the last known contents. Returns `None` once the object is
+    /// no longer being watched.
+    pub async fn changed(&mut self) -> Option<K> {
+        loop {
+            self.state.changed().await.ok()?;
+            if let Cached::Present(object) = &*self.state.borrow() {
+                return Some(object.clone());
+            }
+        }
+    }
+}
+
+impl DependencyTracker {
+    /// Creates a tracker that watches objects using the given client, waiting on the given clock
+    /// for watches to sync and before retrying them.
+    pub fn new(client: kube::Client, clock: Arc<dyn Clock>) -> Self {
+        DependencyTracker {
+            client,
+            clock,
+            config_maps: Default::default(),
+            secrets: Default::default(),
+        }
+    }
+
+    /// Returns the ConfigMap with the given name in the pod's namespace, starting a watch on it
+    /// for the pod if there isn't one already.
+    pub async fn config_map(&self, pod: &PodKey, name: &str) -> anyhow::Result<ConfigMap> {
+        self.get(&self.config_maps, pod, name).await
+    }
+
+    /// Returns the Secret with the given name in the pod's namespace, starting a watch on it for
+    /// the pod if there isn't one already.
+    pub async fn secret(&self, pod: &PodKey, name: &str) -> anyhow::Result<Secret> {
+        self.get(&self.secrets, pod, name).await
+    }
+
+    /// Subscribes to updates of the ConfigMap with the given name in the pod's namespace.
+    pub fn config_map_updates(&self, pod: &PodKey, name: &str) -> Subscription<ConfigMap> {
+        Subscription {
+            state: self.track(&self.config_maps, pod, name),
+        }
+    }
+
+    /// Subscribes to updates of the Secret with the given name in the pod's namespace.
+    pub fn secret_updates(&self, pod: &PodKey, name: &str) -> Subscription<Secret> {
+        Subscription {
+            state: self.track(&self.secrets, pod, name),
+        }
+    }
+
+    /// Releases every object referenced by the pod, dropping the watches of objects that no
+    /// other pod references. This should be called once the pod has terminated.
+    pub fn release(&self, pod: &PodKey) {
+        release_in(&self.config_maps, pod);
+        release_in(&self.secrets, pod);
+    }
+
+    async fn get<K>(
+        &self,
+        watches: &Mutex<Watches<K>>,
+        pod: &PodKey,
+        name: &str,
+    ) -> anyhow::Result<K>
+    where
+        K: kube::Resource<DynamicType = ()> + k8s_openapi::Resource,
+        K: Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    {
+        let mut state = self.track(watches, pod, name);
+        let synced = tokio::select! {
+            synced = async {
+                loop {
+                    let current = state.borrow().clone();
+                    match current {
+                        Cached::Syncing => {
+                            if state.changed().await.is_err() {
+                                return Cached::Syncing;
+                            }
+                        }
+                        other => return other,
+                    }
+                }
+            } => synced,
+            _ = self.clock.sleep(INITIAL_SYNC_TIMEOUT) => Cached::Syncing,
+        };
+
+        match synced {
+            Cached::Present(object) => Ok(object),
+            Cached::Missing => Err(anyhow::anyhow!(
+                "{} {} not found in namespace {}",
+                <K as k8s_openapi::Resource>::KIND,
+                name,
+                pod.namespace()
+            )),
+            Cached::Syncing => {
+                warn!(
+                    kind = <K as k8s_openapi::Resource>::KIND,
+                    name, "Watch has not synced yet, fetching object directly"
+                );
+                let api: Api<K> = Api::namespaced(self.client.clone(), &pod.namespace());
+                Ok(api.get(name).await?)
+            }
+        }
+    }
+
+    /// Registers the pod's interest in an object, starting a watch if this is the first pod to
+    /// reference it.
+    fn track<K>(
+        &self,
+        watches: &Mutex<Watches<K>>,
+        pod: &PodKey,
+        name: &str,
+    ) -> watch::Receiver<Cached<K>>
+    where
+        K: kube::Resource<DynamicType = ()> + k8s_openapi::Resource,
+        K: Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    {
+        let namespace = pod.namespace();
+        let mut watches = watches.lock().unwrap();
+        let entry = watches
+            .entry((namespace.clone(), name.to_owned()))
+            .or_insert_with(|| {
+                debug!(kind = <K as k8s_openapi::Resource>::KIND, %namespace, name, "Starting dependency watch");
+                let (tx, rx) = watch::channel(Cached::Syncing);
+                let api: Api<K> = Api::namespaced(self.client.clone(), &namespace);
+                Entry {
+                    pods: HashSet::new(),
+                    state: rx,
+                    task: tokio::spawn(watch_object(api, name.to_owned(), tx, self.clock.clone())),
+                }
+            });
+        entry.pods.insert(pod.clone());
+        entry.state.clone()
+    }
+}
+
+/// Reads a ConfigMap in the pod's namespace, from the tracker if there is one and otherwise
+/// directly from the API server.
+pub(crate) async fn config_map(
+    client: &kube::Client,
+    tracker: Option<&DependencyTracker>,
+    pod: &Pod,
+    name: &str,
+) -> anyhow::Result<ConfigMap> {
+    match tracker {
+        Some(tracker) => tracker.config_map(&PodKey::from(pod), name).await,
+        None => Ok(Api::namespaced(client.clone(), pod.namespace())
+            .get(name)
+            .await?),
+    }
+}
+
+/// Reads a Secret in the pod's namespace, from the tracker if there is one and otherwise
+/// directly from the API server.
+pub(crate) async fn secret(
+    client: &kube::Client,
+    tracker: Option<&DependencyTracker>,
+    pod: &Pod,
+    name: &str,
+) -> anyhow::Result<Secret> {
+    match tracker {
+        Some(tracker) => tracker.secret(&PodKey::from(pod), name).await,
+        None => Ok(Api::namespaced(client.clone(), pod.namespace())
+            .get(name)
+            .await?),
+    }
+}
+
+fn release_in<K>(watches: &Mutex<Watches<K>>, pod: &PodKey) {
+    watches.lock().unwrap().retain(|(namespace, name), entry| {
+        entry.pods.remove(pod);
+        if entry.pods.is_empty() {
+            debug!(%namespace, %name, "Dropping dependency watch");
+            entry.task.abort();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+async fn watch_object<K>(
+    api: Api<K>,
+    name: String,
+    tx: watch::Sender<Cached<K>>,
+    clock: Arc<dyn Clock>,
+) where
+    K: kube::Resource + Clone + DeserializeOwned + Debug + Send + 'static,
+{
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    let mut events = watcher(api, params).boxed();
+    while let Some(event) = events.next().await {
+        let state = match event {
+            Ok(Event::Applied(object)) => Cached::Present(object),
+            Ok(Event::Deleted(_)) => Cached::Missing,
+            Ok(Event::Restarted(objects)) => objects
+                .into_iter()
+                .next()
+                .map(Cached::Present)
+                .unwrap_or(Cached::Missing),
+            Err(e) => {
+                warn!(error = %e, %name, "Error watching dependency, retrying");
+                clock.sleep(WATCH_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if tx.send(state).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environment::SystemClock;
+    use std::convert::TryFrom;
+
+    fn mock_client() -> kube::Client {
+        kube::Client::try_from(kube::Config::new("http://127.0.0.1:8080".parse().unwrap())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shares_watches_until_last_pod_is_released() {
+        let tracker = DependencyTracker::new(mock_client(), Arc::new(SystemClock));
+        let first = PodKey::new("default", "first");
+        let second = PodKey::new("default", "second");
+        let other_namespace = PodKey::new("other", "first");
+
+        let _sub = tracker.config_map_updates(&first, "settings");
+        let _sub = tracker.config_map_updates(&second, "settings");
+        let _sub = tracker.config_map_updates(&other_namespace, "settings");
+        let _sub = tracker.secret_updates(&first, "settings");
+        assert_eq!(tracker.config_maps.lock().unwrap().len(), 2);
+        assert_eq!(tracker.secrets.lock().unwrap().len(), 1);
+
+        tracker.release(&first);
+        assert_eq!(tracker.config_maps.lock().unwrap().len(), 2);
+        assert!(tracker.secrets.lock().unwrap().is_empty());
+
+        tracker.release(&second);
+        tracker.release(&other_namespace);
+        assert!(tracker.config_maps.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_ends_when_watch_is_dropped() {
+        let tracker = DependencyTracker::new(mock_client(), Arc::new(SystemClock));
+        let pod = PodKey::new("default", "pod");
+        let mut updates = tracker.secret_updates(&pod, "token");
+        tracker.release(&pod);
+        assert!(updates.changed().await.is_none());
+    }
+}
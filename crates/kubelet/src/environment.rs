@@ -0,0 +1,278 @@
+//! The clock and network the Kubelet sees, which tests can replace.
+//!
+//! Node heartbeats, pod backoff and the Kubelet's periodic tasks read the time and wait through
+//! the [`Clock`] of the [`Environment`] in the Kubelet's [`Config`](crate::config::Config), and
+//! requests to the API server, registries and cluster DNS check whether they can be reached (and
+//! how names resolve) through its [`Net`]. The configuration uses the system clock and the real
+//! network unless it is given something else, so an integration test can give the Kubelet a
+//! [`ManualClock`] to fast-forward through heartbeats and backoff without real sleeps, and a
+//! [`SimulatedNet`] to partition the node from the API server:
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use kubelet::config::Config;
+//! use kubelet::environment::{Environment, ManualClock, SimulatedNet, API_SERVER};
+//!
+//! let clock = Arc::new(ManualClock::default());
+//! let net = Arc::new(SimulatedNet::default());
+//! let config = Config {
+//!     environment: Environment {
+//!         clock: clock.clone(),
+//!         net: net.clone(),
+//!     },
+//!     ..Default::default()
+//! };
+//!
+//! net.partition(API_SERVER);
+//! // Heartbeats are skipped while partitioned, and pending waits end as time moves forward
+//! clock.advance(Duration::from_secs(60));
+//! net.heal(API_SERVER);
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+
+/// The endpoint name under which the Kubernetes API server is checked for reachability
+pub const API_SERVER: &str = "apiserver";
+
+/// A source of the current time and of waits.
+#[async_trait::async_trait]
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> DateTime<Utc>;
+    /// Waits until the given duration has passed on this clock.
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The system clock, with waits on the Tokio timer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait::async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// A clock that only moves when it is advanced. Waits on it end once the clock has been advanced
+/// past their deadline.
+pub struct ManualClock {
+    state: Mutex<ManualState>,
+}
+
+struct ManualState {
+    now: DateTime<Utc>,
+    sleepers: Vec<(DateTime<Utc>, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the given time.
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock {
+            state: Mutex::new(ManualState {
+                now: start,
+                sleepers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Moves the clock forward, ending every wait whose deadline has now passed.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now = state.now + to_chrono(duration);
+        let now = state.now;
+        let (due, pending) = state
+            .sleepers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+        for (_, tx) in due {
+            // The waiting task may have been dropped, which is fine
+            let _ = tx.send(());
+        }
+    }
+
+    /// The number of waits that have not ended yet.
+    pub fn pending_sleeps(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.sleepers.retain(|(_, tx)| !tx.is_closed());
+        state.sleepers.len()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new(Utc::now())
+    }
+}
+
+#[async_trait::async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.state.lock().unwrap().now
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        if duration == Duration::from_secs(0) {
+            return;
+        }
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let (tx, rx) = oneshot::channel();
+            let deadline = state.now + to_chrono(duration);
+            state.sleepers.push((deadline, tx));
+            rx
+        };
+        // The sender is only dropped with the clock, in which case nothing will wake us
+        if rx.await.is_err() {
+            futures::future::pending::<()>().await
+        }
+    }
+}
+
+/// Converts a duration, saturating ones too long to represent (such as the `u64::MAX` seconds
+/// some tasks wait for) to the longest that can be.
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::max_value())
+}
+
+/// The network as the Kubelet sees it.
+pub trait Net: Send + Sync {
+    /// Whether the given endpoint can currently be reached. Endpoints are [`API_SERVER`], the host
+    /// name of a registry or the address of a cluster DNS server.
+    fn reachable(&self, endpoint: &str) -> bool;
+    /// The addresses a host name resolves to, or `None` to resolve it normally.
+    fn resolve(&self, host: &str) -> Option<Vec<IpAddr>>;
+}
+
+/// The real network, where every endpoint is assumed reachable and names resolve normally.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemNet;
+
+impl Net for SystemNet {
+    fn reachable(&self, _endpoint: &str) -> bool {
+        true
+    }
+
+    fn resolve(&self, _host: &str) -> Option<Vec<IpAddr>> {
+        None
+    }
+}
+
+/// A network in which endpoints can be partitioned off and host names given fixed addresses.
+#[derive(Debug, Default)]
+pub struct SimulatedNet {
+    partitioned: Mutex<HashSet<String>>,
+    hosts: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl SimulatedNet {
+    /// Makes the endpoint unreachable until it is healed.
+    pub fn partition(&self, endpoint: &str) {
+        self.partitioned.lock().unwrap().insert(endpoint.to_owned());
+    }
+
+    /// Makes a partitioned endpoint reachable again.
+    pub fn heal(&self, endpoint: &str) {
+        self.partitioned.lock().unwrap().remove(endpoint);
+    }
+
+    /// Resolves the host name to the given addresses. An empty list makes the name not exist.
+    pub fn add_host(&self, host: &str, addrs: Vec<IpAddr>) {
+        self.hosts
+            .lock()
+            .unwrap()
+            .insert(host.trim_end_matches('.').to_owned(), addrs);
+    }
+}
+
+impl Net for SimulatedNet {
+    fn reachable(&self, endpoint: &str) -> bool {
+        !self.partitioned.lock().unwrap().contains(endpoint)
+    }
+
+    fn resolve(&self, host: &str) -> Option<Vec<IpAddr>> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(host.trim_end_matches('.'))
+            .cloned()
+    }
+}
+
+/// The clock and network the Kubelet runs in.
+#[derive(Clone)]
+pub struct Environment {
+    /// The clock the Kubelet reads the time from and waits on
+    pub clock: Arc<dyn Clock>,
+    /// The network the Kubelet reaches the API server, registries and cluster DNS through
+    pub net: Arc<dyn Net>,
+}
+
+impl Default for Environment {
+    /// The system clock and the real network.
+    fn default() -> Self {
+        Environment {
+            clock: Arc::new(SystemClock),
+            net: Arc::new(SystemNet),
+        }
+    }
+}
+
+impl std::fmt::Debug for Environment {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.debug_struct("Environment").finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_clock_ends_sleeps_when_advanced() {
+        let clock = Arc::new(ManualClock::default());
+        let start = clock.now();
+        let sleeper = {
+            let clock = clock.clone();
+            tokio::spawn(async move { clock.sleep(Duration::from_secs(10)).await })
+        };
+        while clock.pending_sleeps() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.pending_sleeps(), 1);
+        clock.advance(Duration::from_secs(5));
+        sleeper.await.unwrap();
+        assert_eq!(clock.now() - start, chrono::Duration::seconds(10));
+    }
+
+    #[test]
+    fn test_simulated_net() {
+        let net = SimulatedNet::default();
+        net.partition(API_SERVER);
+        assert!(!net.reachable(API_SERVER));
+        assert!(net.reachable("webassembly.azurecr.io"));
+        net.heal(API_SERVER);
+        assert!(net.reachable(API_SERVER));
+
+        let addr: IpAddr = "10.0.0.7".parse().unwrap();
+        net.add_host("web.default.svc.cluster.local.", vec![addr]);
+        assert_eq!(
+            net.resolve("web.default.svc.cluster.local"),
+            Some(vec![addr])
+        );
+        assert_eq!(net.resolve("other.default.svc.cluster.local"), None);
+    }
+}
@@ -0,0 +1,336 @@
+//! Fault injection for testing how the Kubelet copes with failures.
+//!
+//! With the `fault-injection` feature, registry pulls, Kubernetes API requests and provider
+//! operations can be delayed or made to fail as described by a scenario, so integration tests can
+//! exercise backoff, eviction and recovery deterministically. Without the feature, [`inject`]
+//! does nothing and the scenario machinery isn't compiled in.
+//!
+//! A scenario is read from the YAML (or JSON) file named by the [`SCENARIO_ENV_VAR`] environment
+//! variable the first time a fault could be injected, or installed with [`set_scenario`]:
+//!
+//! ```yaml
+//! faults:
+//!   # Let the first pull through, then fail the next two after a delay
+//!   - point: registryPull
+//!     target: "webassembly.azurecr.io/hello-wasm*"
+//!     skip: 1
+//!     times: 2
+//!     delay: 5s
+//!     fail: simulated registry outage
+//!   # Fail every pod status update with a server error
+//!   - point: apiServer
+//!     target: "patch pods/status"
+//!     fail: simulated API server error
+//!     status: 500
+//! ```
+//!
+//! Every fault whose point and target match an operation counts it, and the operation gets the
+//! first fault that has seen more than `skip` (by default none) operations and no more than
+//! `skip + times` (by default unlimited). Targets may contain `*` wildcards, and a fault without
+//! one matches every operation at its point. The targets of each point are:
+//!
+//! * `registryPull`: the image reference, such as `docker.io/library/hello:1.0`
+//! * `apiServer`: the verb and resource of the request, such as `get pods` or `patch
+//!   nodes/status`
+//! * `provider`: the operation and the pod (or container) it is for, such as
+//!   `initializePodState default/web` or `startContainer default/web/server`
+use serde::Deserialize;
+use thiserror::Error;
+
+/// The environment variable naming the scenario file
+pub const SCENARIO_ENV_VAR: &str = "KRUSTLET_FAULT_SCENARIO";
+
+/// Where in the Kubelet a fault can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FaultPoint {
+    /// Pulling a module from a registry
+    RegistryPull,
+    /// A request to the Kubernetes API server
+    ApiServer,
+    /// An operation of the provider
+    Provider,
+}
+
+/// A failure injected into an operation.
+#[derive(Clone, Debug, Error)]
+#[error("injected fault: {message}")]
+pub struct InjectedFault {
+    /// The message the operation fails with
+    pub message: String,
+    /// The HTTP status code to respond with, for faults injected into API server requests
+    pub status: Option<u16>,
+}
+
+/// Delays or fails an operation if the scenario says so. `target` names what the operation is
+/// for, as described in the [module documentation](self).
+#[cfg(feature = "fault-injection")]
+pub async fn inject(point: FaultPoint, target: &str) -> Result<(), InjectedFault> {
+    let fault = match scenario::next_fault(point, target) {
+        Some(fault) => fault,
+        None => return Ok(()),
+    };
+    tracing::info!(?point, target, delay = ?fault.delay, fail = ?fault.fail, "Injecting fault");
+    if let Some(delay) = fault.delay {
+        tokio::time::sleep(delay).await;
+    }
+    match fault.fail {
+        Some(message) => Err(InjectedFault {
+            message,
+            status: fault.status,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Delays or fails an operation if the scenario says so. Fault injection isn't compiled in, so
+/// this does nothing.
+#[cfg(not(feature = "fault-injection"))]
+#[inline]
+pub async fn inject(_point: FaultPoint, _target: &str) -> Result<(), InjectedFault> {
+    Ok(())
+}
+
+#[cfg(feature = "fault-injection")]
+pub use scenario::{clear_scenario, set_scenario, Fault, Scenario};
+
+#[cfg(feature = "fault-injection")]
+mod scenario {
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use serde::Deserialize;
+
+    use super::{FaultPoint, SCENARIO_ENV_VAR};
+
+    /// The faults to inject.
+    #[derive(Clone, Debug, Default, Deserialize)]
+    pub struct Scenario {
+        /// The faults, in the order they are considered
+        #[serde(default)]
+        pub faults: Vec<Fault>,
+    }
+
+    /// A fault to inject into the operations that match it.
+    #[derive(Clone, Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Fault {
+        /// Where to inject the fault
+        pub point: FaultPoint,
+        /// A pattern the target of an operation has to match, if any
+        #[serde(default)]
+        pub target: Option<String>,
+        /// How many matching operations to let through before injecting the fault
+        #[serde(default)]
+        pub skip: u64,
+        /// How many operations to inject the fault into, if not all of them
+        #[serde(default)]
+        pub times: Option<u64>,
+        /// How long to delay the operation by, such as `500ms` or `5s`
+        #[serde(default)]
+        pub delay: Option<String>,
+        /// The message to fail the operation with, if it should fail
+        #[serde(default)]
+        pub fail: Option<String>,
+        /// The status code to fail API server requests with. Defaults to 503
+        #[serde(default)]
+        pub status: Option<u16>,
+    }
+
+    impl Scenario {
+        /// Reads a scenario from a YAML or JSON file.
+        pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+            let contents = std::fs::read_to_string(path)?;
+            let scenario: Scenario = serde_yaml::from_str(&contents)?;
+            for fault in &scenario.faults {
+                parse_delay(fault)?;
+            }
+            Ok(scenario)
+        }
+    }
+
+    /// What to do to a single operation.
+    pub(super) struct Action {
+        pub(super) delay: Option<Duration>,
+        pub(super) fail: Option<String>,
+        pub(super) status: Option<u16>,
+    }
+
+    struct ActiveFault {
+        fault: Fault,
+        delay: Option<Duration>,
+        seen: u64,
+    }
+
+    lazy_static::lazy_static! {
+        static ref ACTIVE: Mutex<Option<Vec<ActiveFault>>> = Mutex::new(None);
+    }
+
+    /// Replaces the scenario, starting the counts of every fault over.
+    pub fn set_scenario(scenario: Scenario) -> anyhow::Result<()> {
+        let faults = scenario
+            .faults
+            .into_iter()
+            .map(|fault| {
+                Ok(ActiveFault {
+                    delay: parse_delay(&fault)?,
+                    fault,
+                    seen: 0,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        *ACTIVE.lock().unwrap() = Some(faults);
+        Ok(())
+    }
+
+    /// Removes the scenario, so that no more faults are injected.
+    pub fn clear_scenario() {
+        *ACTIVE.lock().unwrap() = Some(Vec::new());
+    }
+
+    pub(super) fn next_fault(point: FaultPoint, target: &str) -> Option<Action> {
+        let mut active = ACTIVE.lock().unwrap();
+        if active.is_none() {
+            *active = Some(load_from_env());
+        }
+        let mut action = None;
+        for fault in active.iter_mut().flatten() {
+            let matched = fault.fault.point == point
+                && fault
+                    .fault
+                    .target
+                    .as_deref()
+                    .map(|pattern| pattern_matches(pattern, target))
+                    .unwrap_or(true);
+            if !matched {
+                continue;
+            }
+            fault.seen += 1;
+            let within = fault.seen > fault.fault.skip
+                && fault
+                    .fault
+                    .times
+                    .map(|times| fault.seen <= fault.fault.skip + times)
+                    .unwrap_or(true);
+            if within && action.is_none() {
+                action = Some(Action {
+                    delay: fault.delay,
+                    fail: fault.fault.fail.clone(),
+                    status: fault.fault.status,
+                });
+            }
+        }
+        action
+    }
+
+    /// Loads the scenario named by the environment, if any. A scenario that can't be loaded is a
+    /// broken test rather than something to carry on from, so it panics.
+    fn load_from_env() -> Vec<ActiveFault> {
+        let path = match std::env::var_os(SCENARIO_ENV_VAR) {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        let scenario = Scenario::from_file(Path::new(&path)).unwrap_or_else(|e| {
+            panic!(
+                "Unable to load fault scenario {}: {:#}",
+                Path::new(&path).display(),
+                e
+            )
+        });
+        scenario
+            .faults
+            .into_iter()
+            .map(|fault| ActiveFault {
+                delay: parse_delay(&fault).expect("delays are validated when loading"),
+                fault,
+                seen: 0,
+            })
+            .collect()
+    }
+
+    fn parse_delay(fault: &Fault) -> anyhow::Result<Option<Duration>> {
+        fault
+            .delay
+            .as_deref()
+            .map(crate::config::parse_duration)
+            .transpose()
+    }
+
+    /// Whether the target matches a pattern in which `*` matches any run of characters.
+    fn pattern_matches(pattern: &str, target: &str) -> bool {
+        let mut parts = pattern.split('*');
+        let first = parts.next().unwrap_or_default();
+        let mut rest = match target.strip_prefix(first) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        let parts: Vec<&str> = parts.collect();
+        let (last, middle) = match parts.split_last() {
+            Some(split) => split,
+            // No wildcard, so the whole target has to match
+            None => return rest.is_empty(),
+        };
+        for part in middle {
+            match rest.find(part) {
+                Some(i) => rest = &rest[i + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_pattern_matches() {
+            assert!(pattern_matches("get pods", "get pods"));
+            assert!(!pattern_matches("get pods", "get pods/status"));
+            assert!(pattern_matches("* pods/status", "patch pods/status"));
+            assert!(pattern_matches(
+                "docker.io/*:1.*",
+                "docker.io/library/hello:1.0"
+            ));
+            assert!(!pattern_matches(
+                "docker.io/*:1.*",
+                "ghcr.io/library/hello:1.0"
+            ));
+        }
+
+        #[tokio::test]
+        async fn test_skip_and_times() {
+            // The scenario is global, so only this test installs one and it uses its own point
+            set_scenario(
+                serde_yaml::from_str(
+                    r#"
+faults:
+  - point: provider
+    target: "startContainer default/*"
+    skip: 1
+    times: 2
+    fail: simulated failure
+"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+            let mut results = Vec::new();
+            for _ in 0..4 {
+                results.push(
+                    super::super::inject(FaultPoint::Provider, "startContainer default/web/c")
+                        .await
+                        .is_err(),
+                );
+            }
+            assert_eq!(results, vec![false, true, true, false]);
+            assert!(
+                super::super::inject(FaultPoint::Provider, "startContainer kube-system/dns")
+                    .await
+                    .is_ok()
+            );
+            clear_scenario();
+        }
+    }
+}
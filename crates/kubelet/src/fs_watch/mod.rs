@@ -14,11 +14,15 @@ use futures::Stream;
 #[cfg(not(target_os = "macos"))]
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
 use notify::{Event, Result as NotifyResult};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+#[cfg(target_os = "macos")]
 use tracing::error;
 
+use queue::EventReceiver;
+
+mod queue;
+
 pub struct FileSystemWatcher {
-    recv: UnboundedReceiver<NotifyResult<Event>>,
+    recv: EventReceiver,
     #[cfg(not(target_os = "macos"))]
     _watcher: RecommendedWatcher, // holds on to the watcher so it doesn't get dropped
 }
@@ -35,12 +39,9 @@ impl Stream for FileSystemWatcher {
 impl FileSystemWatcher {
     #[cfg(not(target_os = "macos"))]
     pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let (stream_tx, stream_rx) = unbounded_channel::<NotifyResult<Event>>();
-        let mut watcher: RecommendedWatcher = Watcher::new_immediate(move |res| {
-            if let Err(e) = stream_tx.send(res) {
-                error!(error = %e, "Unable to send inotify event into stream")
-            }
-        })?;
+        let (stream_tx, stream_rx) = queue::bounded(queue::MAX_QUEUED_EVENTS);
+        let mut watcher: RecommendedWatcher =
+            Watcher::new_immediate(move |res| stream_tx.send(res))?;
         watcher.configure(Config::PreciseEvents(true))?;
 
         watcher.watch(path, RecursiveMode::NonRecursive)?;
@@ -64,19 +65,19 @@ mod mac {
     use std::collections::HashSet;
     use std::path::PathBuf;
 
+    use super::queue::EventSender;
     use super::*;
     use notify::event::{CreateKind, EventKind, RemoveKind};
     use notify::Error as NotifyError;
     use tokio::fs::DirEntry;
-    use tokio::sync::mpsc::UnboundedSender;
     use tokio::time::{self, Duration};
     use tokio_stream::wrappers::ReadDirStream;
     use tokio_stream::StreamExt;
 
     const WAIT_TIME: u64 = 2;
 
-    pub fn dir_watcher<P: AsRef<Path>>(dir: P) -> UnboundedReceiver<NotifyResult<Event>> {
-        let (tx, rx) = unbounded_channel();
+    pub fn dir_watcher<P: AsRef<Path>>(dir: P) -> EventReceiver {
+        let (tx, rx) = queue::bounded(queue::MAX_QUEUED_EVENTS);
         let path = dir.as_ref().to_path_buf();
         tokio::spawn(async move {
             let mut path_cache: HashSet<PathBuf> = match get_dir_list(&path).await {
@@ -100,9 +101,7 @@ mod mac {
                             path = %path.display(),
                             "Unable to refresh directory, will attempt again"
                         );
-                        if let Err(e) = tx.send(Err(NotifyError::io(e))) {
-                            error!(result = ?e.0, "Unable to send error due to channel being closed");
-                        }
+                        tx.send(Err(NotifyError::io(e)));
                         continue;
                     }
                 };
@@ -139,22 +138,16 @@ mod mac {
             })
     }
 
-    fn send_creates(
-        tx: UnboundedSender<NotifyResult<Event>>,
-        items: impl Iterator<Item = PathBuf>,
-    ) {
+    fn send_creates(tx: EventSender, items: impl Iterator<Item = PathBuf>) {
         send_event_with_kind(tx, items, EventKind::Create(CreateKind::Any))
     }
 
-    fn send_deletes(
-        tx: UnboundedSender<NotifyResult<Event>>,
-        items: impl Iterator<Item = PathBuf>,
-    ) {
+    fn send_deletes(tx: EventSender, items: impl Iterator<Item = PathBuf>) {
         send_event_with_kind(tx, items, EventKind::Remove(RemoveKind::Any))
     }
 
     fn send_event_with_kind(
-        tx: UnboundedSender<NotifyResult<Event>>,
+        tx: EventSender,
         items: impl Iterator<Item = PathBuf>,
         kind: EventKind,
     ) {
@@ -168,14 +161,7 @@ mod mac {
             paths,
             ..Default::default()
         };
-        if let Err(e) = tx.send(Ok(event)) {
-            // At this point there isn't much we can do as the channel is closed. So just log an
-            // error
-            error!(
-                result = ?e.0,
-                "Unable to send event due to the channel being closed"
-            );
-        }
+        tx.send(Ok(event));
     }
 
     #[cfg(test)]
@@ -184,7 +170,7 @@ mod mac {
 
         #[tokio::test]
         async fn test_send_deletes() {
-            let (tx, mut rx) = unbounded_channel();
+            let (tx, mut rx) = queue::bounded(queue::MAX_QUEUED_EVENTS);
             let file1 = PathBuf::from("/foo/bar");
             let file2 = PathBuf::from("/bar/foo");
 
@@ -203,7 +189,7 @@ mod mac {
 
         #[tokio::test]
         async fn test_send_creates() {
-            let (tx, mut rx) = unbounded_channel();
+            let (tx, mut rx) = queue::bounded(queue::MAX_QUEUED_EVENTS);
             let file1 = PathBuf::from("/foo/bar");
             let file2 = PathBuf::from("/bar/foo");
 
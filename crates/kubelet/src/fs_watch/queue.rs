@@ -0,0 +1,225 @@
+//! A bounded queue for filesystem events.
+//!
+//! Events are produced by the OS watcher's thread (or the polling task on MacOS), which can't wait
+//! for the consumer, so the queue never blocks the sender. Instead, an event of the same kind as
+//! the last queued one is coalesced into it, and once the queue is full the oldest event is
+//! dropped and counted in the `fs_watch_events_dropped_total` metric. A coalesced event holds at
+//! most [`MAX_COALESCED_PATHS`] paths, and paths past that are dropped and counted the same way. A
+//! stalled consumer therefore loses events rather than growing the queue, or an event in it,
+//! without bound.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use notify::{Event, Result as NotifyResult};
+use prometheus::IntCounter;
+use tracing::warn;
+
+use crate::metrics::register;
+
+/// The most events that are queued before the oldest is dropped
+pub(crate) const MAX_QUEUED_EVENTS: usize = 1024;
+/// The most paths an event holds once others are coalesced into it
+pub(crate) const MAX_COALESCED_PATHS: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref EVENTS_COALESCED: IntCounter = register(IntCounter::new(
+        "fs_watch_events_coalesced_total",
+        "Number of filesystem events that were merged into an event already queued"
+    ));
+    static ref EVENTS_DROPPED: IntCounter = register(IntCounter::new(
+        "fs_watch_events_dropped_total",
+        "Number of filesystem events, or paths of coalesced events, dropped because the queue was full"
+    ));
+}
+
+struct Shared {
+    events: VecDeque<NotifyResult<Event>>,
+    capacity: usize,
+    senders: usize,
+    waker: Option<Waker>,
+}
+
+/// Creates a queue that holds at most `capacity` events.
+pub(crate) fn bounded(capacity: usize) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Mutex::new(Shared {
+        events: VecDeque::new(),
+        capacity,
+        senders: 1,
+        waker: None,
+    }));
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventReceiver { shared },
+    )
+}
+
+/// The sending half of an event queue. The queue is closed once every sender is dropped.
+pub(crate) struct EventSender {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl EventSender {
+    /// Queues an event without waiting, coalescing or dropping events if the queue is full.
+    pub(crate) fn send(&self, res: NotifyResult<Event>) {
+        let mut shared = self.shared.lock().unwrap();
+        let res = match (shared.events.back_mut(), res) {
+            (Some(Ok(last)), Ok(event)) if last.kind == event.kind => {
+                let mut dropped = 0;
+                for path in event.paths {
+                    if last.paths.contains(&path) {
+                        continue;
+                    }
+                    if last.paths.len() < MAX_COALESCED_PATHS {
+                        last.paths.push(path);
+                    } else {
+                        dropped += 1;
+                    }
+                }
+                EVENTS_COALESCED.inc();
+                if dropped > 0 {
+                    EVENTS_DROPPED.inc_by(dropped);
+                    warn!(
+                        dropped,
+                        "Coalesced filesystem event has too many paths, dropping the rest"
+                    );
+                }
+                None
+            }
+            (_, res) => Some(res),
+        };
+        if let Some(res) = res {
+            if shared.events.len() >= shared.capacity {
+                if let Some(dropped) = shared.events.pop_front() {
+                    EVENTS_DROPPED.inc();
+                    warn!(event = ?dropped, "Filesystem event queue is full, dropping oldest event");
+                }
+            }
+            shared.events.push_back(res);
+        }
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        EventSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The receiving half of an event queue.
+pub(crate) struct EventReceiver {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl EventReceiver {
+    /// Polls for the next event, which is `None` once the queue is empty and closed.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<NotifyResult<Event>>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.events.pop_front() {
+            Some(res) => Poll::Ready(Some(res)),
+            None if shared.senders == 0 => Poll::Ready(None),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Waits for the next event, which is `None` once the queue is empty and closed.
+    #[cfg(test)]
+    pub(crate) async fn recv(&mut self) -> Option<NotifyResult<Event>> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use notify::event::{CreateKind, EventKind, RemoveKind};
+    use std::path::PathBuf;
+
+    fn event(kind: EventKind, path: &str) -> NotifyResult<Event> {
+        Ok(Event {
+            kind,
+            paths: vec![PathBuf::from(path)],
+            ..Default::default()
+        })
+    }
+
+    fn paths(res: Option<NotifyResult<Event>>) -> Vec<PathBuf> {
+        res.expect("queue closed early")
+            .expect("got error from queue")
+            .paths
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_and_drop_oldest() {
+        let create = EventKind::Create(CreateKind::Any);
+        let remove = EventKind::Remove(RemoveKind::Any);
+        let (tx, mut rx) = bounded(2);
+        tx.send(event(create, "/a"));
+        tx.send(event(create, "/b"));
+        tx.send(event(create, "/a"));
+        tx.send(event(remove, "/a"));
+        // The queue is full, so the coalesced creates are dropped
+        tx.send(event(create, "/c"));
+        drop(tx);
+
+        assert_eq!(paths(rx.recv().await), vec![PathBuf::from("/a")]);
+        assert_eq!(paths(rx.recv().await), vec![PathBuf::from("/c")]);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_merges_paths() {
+        let create = EventKind::Create(CreateKind::Any);
+        let (tx, mut rx) = bounded(2);
+        tx.send(event(create, "/a"));
+        tx.send(event(create, "/b"));
+        tx.send(event(create, "/a"));
+        assert_eq!(
+            paths(rx.recv().await),
+            vec![PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_paths_are_bounded() {
+        let create = EventKind::Create(CreateKind::Any);
+        let (tx, mut rx) = bounded(2);
+        let dropped = EVENTS_DROPPED.get();
+        for i in 0..MAX_COALESCED_PATHS + 3 {
+            tx.send(event(create, &format!("/{}", i)));
+        }
+        drop(tx);
+
+        let merged = paths(rx.recv().await);
+        assert_eq!(merged.len(), MAX_COALESCED_PATHS);
+        assert_eq!(
+            merged.last(),
+            Some(&PathBuf::from(format!("/{}", MAX_COALESCED_PATHS - 1)))
+        );
+        assert!(EVENTS_DROPPED.get() >= dropped + 3);
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+
+/// How many writes can wait for the process to read them before writing blocks
+const STDIN_CAPACITY: usize = 16;
+
+/// The write end of the stdin of a running process, which clients attach to.
+///
+/// Data written by clients is handed to the process in the order it was written. Writing blocks
+/// while the process is behind on reading, so a client can't make the stdin buffer more than a
+/// few writes. Once the
+/// stdin is closed the process sees the end of it, which happens when the process is stopped or,
+/// for `stdinOnce` containers, when the first client detaches.
+pub struct AttachedStdin {
+    sender: Mutex<Option<SyncSender<Vec<u8>>>>,
+    once: bool,
+}
+
+/// The read end of an [`AttachedStdin`], which blocks until a client writes to the stdin.
+pub struct StdinReader {
+    receiver: Mutex<Receiver<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+/// Creates the two ends of the stdin of a process. If `once` is set, the stdin is closed as soon
+/// as the first client detaches.
+pub fn attached_stdin(once: bool) -> (AttachedStdin, StdinReader) {
+    let (sender, receiver) = sync_channel(STDIN_CAPACITY);
+    (
+        AttachedStdin {
+            sender: Mutex::new(Some(sender)),
+            once,
+        },
+        StdinReader {
+            receiver: Mutex::new(receiver),
+            buffer: Vec::new(),
+            position: 0,
+        },
+    )
+}
+
+impl AttachedStdin {
+    /// Writes data to the stdin, failing if it has been closed. This blocks while the process
+    /// is behind on reading.
+    pub fn write(&self, data: Vec<u8>) -> anyhow::Result<()> {
+        // The lock isn't held while blocked, so the stdin can still be closed
+        let sender = self.sender.lock().unwrap().clone();
+        sender
+            .and_then(|sender| sender.send(data).ok())
+            .ok_or_else(|| anyhow::anyhow!("stdin has been closed"))
+    }
+
+    /// Called when a client detaches, closing the stdin if only one client may attach.
+    pub fn detach(&self) {
+        if self.once {
+            self.close();
+        }
+    }
+
+    /// Closes the stdin, so the process reads the end of it once it has read everything written
+    /// so far.
+    pub fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.receiver.lock().unwrap().recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.position = 0;
+                }
+                // Every sender is gone, so this is the end of stdin
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attached_stdin() {
+        let (stdin, mut reader) = attached_stdin(true);
+        stdin.write(b"hello ".to_vec()).unwrap();
+        stdin.write(b"world".to_vec()).unwrap();
+        stdin.detach();
+        assert!(stdin.write(b"again".to_vec()).is_err());
+
+        let mut read = String::new();
+        reader.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello world");
+
+        let (stdin, _reader) = attached_stdin(false);
+        stdin.detach();
+        stdin.write(b"still open".to_vec()).unwrap();
+        stdin.close();
+        assert!(stdin.write(b"closed".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_writes_block_while_the_process_is_behind() {
+        let (stdin, mut reader) = attached_stdin(false);
+        for _ in 0..STDIN_CAPACITY {
+            stdin.write(b"x".to_vec()).unwrap();
+        }
+
+        let stdin = std::sync::Arc::new(stdin);
+        let (written, wrote) = std::sync::mpsc::channel();
+        let writer = stdin.clone();
+        std::thread::spawn(move || {
+            writer.write(b"y".to_vec()).unwrap();
+            written.send(()).unwrap();
+        });
+        let wait = std::time::Duration::from_millis(100);
+        assert!(wrote.recv_timeout(wait).is_err(), "write did not block");
+
+        let mut buf = [0; 1];
+        reader.read_exact(&mut buf).unwrap();
+        assert!(
+            wrote.recv_timeout(10 * wait).is_ok(),
+            "write stayed blocked"
+        );
+    }
+}
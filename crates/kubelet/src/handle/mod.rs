@@ -3,6 +3,10 @@
 //! A collection of handle types for use in providers. These are entirely
 //! optional, but abstract away much of the logic around managing logging,
 //! status updates, and stopping pods
+mod attach;
+mod runtime_info;
 mod stopper;
 
+pub use attach::{attached_stdin, AttachedStdin, StdinReader};
+pub use runtime_info::{module_digest, PodRuntimeInfo, Preopen, RuntimeInfo};
 pub use stopper::StopHandler;
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use sha2::Digest;
+
+use crate::pod::PodKey;
+
+/// What a runtime handle knows about the instance it is running, for debugging workloads that
+/// misbehave. Anything the runtime doesn't track is left unset.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuntimeInfo {
+    /// The digest of the module being run, such as `sha256:<hex>`
+    pub module_digest: Option<String>,
+    /// When the instance started running
+    pub started_at: Option<DateTime<Utc>>,
+    /// The fuel the instance has consumed, if fuel is metered
+    pub fuel_consumed: Option<u64>,
+    /// The bytes of memory the instance has allocated
+    pub memory_bytes: Option<u64>,
+    /// The host directories the instance can access
+    pub preopens: Vec<Preopen>,
+}
+
+/// A host directory opened for an instance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preopen {
+    /// The directory on the host
+    pub host_path: PathBuf,
+    /// Where the instance sees the directory
+    pub guest_path: PathBuf,
+}
+
+/// The runtime handles of a pod's containers, by container name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PodRuntimeInfo {
+    /// The pod the handles belong to
+    pub pod: PodKey,
+    /// The handles of the pod's containers that describe themselves
+    pub containers: BTreeMap<String, RuntimeInfo>,
+}
+
+impl PodRuntimeInfo {
+    /// The handles as JSON, with the seconds each instance has been running for as of `now`.
+    pub fn to_json(&self, now: DateTime<Utc>) -> serde_json::Value {
+        let containers: Vec<serde_json::Value> = self
+            .containers
+            .iter()
+            .map(|(name, info)| {
+                let preopens: Vec<serde_json::Value> = info
+                    .preopens
+                    .iter()
+                    .map(|p| {
+                        serde_json::json!({
+                            "hostPath": p.host_path.display().to_string(),
+                            "guestPath": p.guest_path.display().to_string(),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "name": name,
+                    "moduleDigest": info.module_digest,
+                    "startedAt": info.started_at.map(|t| t.to_rfc3339()),
+                    "uptimeSeconds": info.started_at.map(|t| (now - t).num_seconds().max(0)),
+                    "fuelConsumed": info.fuel_consumed,
+                    "memoryBytes": info.memory_bytes,
+                    "preopens": preopens,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "podRef": {"name": self.pod.name(), "namespace": self.pod.namespace()},
+            "containers": containers,
+        })
+    }
+}
+
+/// The digest of a module's bytes, in the form registries use.
+pub fn module_digest(module_data: &[u8]) -> String {
+    format!("sha256:{:x}", sha2::Sha256::digest(module_data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let started_at = "2021-07-01T00:00:00Z".parse().unwrap();
+        let mut containers = BTreeMap::new();
+        containers.insert(
+            "server".to_owned(),
+            RuntimeInfo {
+                module_digest: Some(module_digest(b"\0asm")),
+                started_at: Some(started_at),
+                memory_bytes: Some(65536),
+                preopens: vec![Preopen {
+                    host_path: PathBuf::from("/var/lib/krustlet/volumes/config"),
+                    guest_path: PathBuf::from("/config"),
+                }],
+                ..Default::default()
+            },
+        );
+        let info = PodRuntimeInfo {
+            pod: PodKey::new("default", "web"),
+            containers,
+        };
+        let json = info.to_json(started_at + chrono::Duration::seconds(90));
+        let container = &json["containers"][0];
+        assert_eq!(json["podRef"]["name"], "web");
+        assert_eq!(container["uptimeSeconds"], 90);
+        assert_eq!(container["fuelConsumed"], serde_json::Value::Null);
+        assert_eq!(container["preopens"][0]["guestPath"], "/config");
+        assert!(container["moduleDigest"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha256:"));
+    }
+}
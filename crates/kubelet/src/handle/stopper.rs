@@ -9,4 +9,14 @@ pub trait StopHandler {
     async fn stop(&mut self) -> anyhow::Result<()>;
     /// Wait for the implementor to stop anything it considers in the running state.
     async fn wait(&mut self) -> anyhow::Result<()>;
+    /// Describes what is running under the implementor, for debugging. The default
+    /// implementation describes nothing.
+    fn runtime_info(&self) -> Option<super::RuntimeInfo> {
+        None
+    }
+    /// The stdin of what is running under the implementor, if clients can attach to it. The
+    /// default implementation has none.
+    fn stdin(&self) -> Option<std::sync::Arc<super::AttachedStdin>> {
+        None
+    }
 }
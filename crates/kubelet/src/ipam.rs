@@ -0,0 +1,370 @@
+//! A simple host-local IP address manager for assigning pod IPs.
+//!
+//! Addresses are handed out from the pod CIDR of the node, which is either configured with
+//! `--pod-cidr` or read from the `spec.podCIDRs` of the node object. Like the `host-local`
+//! CNI plugin, the network address, the first address (reserved for a gateway) and, for
+//! IPv4, the broadcast address are never assigned.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::Api;
+use kube::error::ErrorResponse;
+use tracing::{debug, info, warn};
+
+use crate::pod::PodKey;
+
+/// Assigns pod IPs from a single CIDR, remembering which pod holds each address.
+pub struct HostLocalIpam {
+    network: IpAddr,
+    prefix_len: u8,
+    state: Mutex<Allocations>,
+}
+
+#[derive(Default)]
+struct Allocations {
+    by_pod: HashMap<PodKey, IpAddr>,
+    /// The offset the next search for a free address starts from, so that recently released
+    /// addresses aren't immediately reused
+    next: u128,
+}
+
+impl HostLocalIpam {
+    /// Creates an IPAM for a CIDR such as `10.244.0.0/24` or `fd00:10:244::/64`.
+    pub fn new(cidr: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = cidr
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("pod CIDR {} is missing a prefix length", cidr))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow::anyhow!("pod CIDR {} has an invalid address: {}", cidr, e))?;
+        let prefix_len: u8 = prefix_len
+            .parse()
+            .map_err(|e| anyhow::anyhow!("pod CIDR {} has an invalid prefix: {}", cidr, e))?;
+        let bits = address_bits(&addr);
+        if prefix_len > bits || bits - prefix_len < 2 {
+            return Err(anyhow::anyhow!(
+                "pod CIDR {} is too small to assign pod IPs from",
+                cidr
+            ));
+        }
+        let ipam = HostLocalIpam {
+            network: masked(addr, prefix_len),
+            prefix_len,
+            state: Mutex::new(Allocations::default()),
+        };
+        Ok(ipam)
+    }
+
+    /// Returns the IP assigned to the pod, assigning it a free one if it doesn't have one yet.
+    ///
+    /// If `preferred` is a free address in the range it is assigned, which lets pods keep the
+    /// address they were reporting before the kubelet restarted.
+    pub fn assign(&self, pod: &PodKey, preferred: Option<IpAddr>) -> anyhow::Result<IpAddr> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(ip) = state.by_pod.get(pod) {
+            return Ok(*ip);
+        }
+
+        let (first, last) = self.assignable_offsets();
+        let in_use = |state: &Allocations, ip: &IpAddr| state.by_pod.values().any(|a| a == ip);
+        if let Some(ip) = preferred {
+            let usable = self
+                .offset_of(&ip)
+                .map(|o| o >= first && o <= last)
+                .unwrap_or(false);
+            if usable && !in_use(&state, &ip) {
+                state.by_pod.insert(pod.clone(), ip);
+                return Ok(ip);
+            }
+        }
+
+        let size = last - first + 1;
+        // Cap the search so huge IPv6 ranges with nothing free don't spin forever
+        let attempts = size.min(state.by_pod.len() as u128 + 1);
+        for i in 0..attempts {
+            let offset = first + (state.next + i) % size;
+            let ip = self.address_at(offset);
+            if !in_use(&state, &ip) {
+                state.next = (state.next + i + 1) % size;
+                debug!(pod = ?pod, %ip, "Assigned pod IP");
+                state.by_pod.insert(pod.clone(), ip);
+                return Ok(ip);
+            }
+        }
+        Err(anyhow::anyhow!(
+            "no free pod IPs left in {}/{}",
+            self.network,
+            self.prefix_len
+        ))
+    }
+
+    /// The IP assigned to the pod, if any.
+    pub fn get(&self, pod: &PodKey) -> Option<IpAddr> {
+        self.state.lock().unwrap().by_pod.get(pod).copied()
+    }
+
+    /// Releases the IP assigned to the pod, returning it if there was one.
+    pub fn release(&self, pod: &PodKey) -> Option<IpAddr> {
+        let released = self.state.lock().unwrap().by_pod.remove(pod);
+        if let Some(ip) = &released {
+            debug!(pod = ?pod, %ip, "Released pod IP");
+        }
+        released
+    }
+
+    /// The first and last offsets from the network address that may be assigned.
+    fn assignable_offsets(&self) -> (u128, u128) {
+        let host_bits = address_bits(&self.network) - self.prefix_len;
+        let last_offset = if host_bits >= 128 {
+            u128::MAX
+        } else {
+            (1u128 << host_bits) - 1
+        };
+        match self.network {
+            // The last IPv4 address is the broadcast address
+            IpAddr::V4(_) => (2, last_offset - 1),
+            IpAddr::V6(_) => (2, last_offset),
+        }
+    }
+
+    fn offset_of(&self, ip: &IpAddr) -> Option<u128> {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip))
+                if masked(IpAddr::V4(*ip), self.prefix_len) == IpAddr::V4(network) =>
+            {
+                Some((u32::from(*ip) - u32::from(network)) as u128)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip))
+                if masked(IpAddr::V6(*ip), self.prefix_len) == IpAddr::V6(network) =>
+            {
+                Some(u128::from(*ip) - u128::from(network))
+            }
+            _ => None,
+        }
+    }
+
+    fn address_at(&self, offset: u128) -> IpAddr {
+        match self.network {
+            IpAddr::V4(network) => IpAddr::V4(Ipv4Addr::from(u32::from(network) + offset as u32)),
+            IpAddr::V6(network) => IpAddr::V6(Ipv6Addr::from(u128::from(network) + offset)),
+        }
+    }
+}
+
+/// Assigns pod IPs from the node's pod CIDR, reading it from the node object the first time a
+/// pod needs an IP rather than when the provider starts, as the node may not have been
+/// registered, or assigned a pod CIDR, by then.
+pub struct NodeIpam {
+    client: kube::Client,
+    node_name: String,
+    ipam: Mutex<Option<Arc<HostLocalIpam>>>,
+}
+
+impl NodeIpam {
+    /// Creates an IPAM for the node. If `pod_cidr` is configured it is used instead of the
+    /// node's.
+    pub fn new(
+        client: kube::Client,
+        node_name: &str,
+        pod_cidr: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let ipam = match pod_cidr {
+            Some(cidr) => Some(Arc::new(HostLocalIpam::new(cidr)?)),
+            None => None,
+        };
+        Ok(NodeIpam {
+            client,
+            node_name: node_name.to_owned(),
+            ipam: Mutex::new(ipam),
+        })
+    }
+
+    /// The IPAM for the node's pod CIDR, or `None` if the node doesn't have one (yet), in
+    /// which case pod IPs are not assigned.
+    pub async fn resolve(&self) -> Option<Arc<HostLocalIpam>> {
+        if let Some(ipam) = self.assigned() {
+            return Some(ipam);
+        }
+        let cidr = match node_pod_cidr(&self.client, &self.node_name).await {
+            Ok(Some(cidr)) => cidr,
+            Ok(None) => {
+                debug!("Node has no pod CIDR, pod IPs will not be assigned");
+                return None;
+            }
+            Err(e) => {
+                warn!(error = %e, "Unable to read pod CIDR of node, pod IPs will not be assigned");
+                return None;
+            }
+        };
+        let ipam = match HostLocalIpam::new(&cidr) {
+            Ok(ipam) => Arc::new(ipam),
+            Err(e) => {
+                warn!(error = %e, "Node has an invalid pod CIDR, pod IPs will not be assigned");
+                return None;
+            }
+        };
+        info!(pod_cidr = %cidr, "Assigning pod IPs from node pod CIDR");
+        // Another pod may have resolved it in the meantime, so keep whichever came first
+        Some(
+            self.ipam
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| ipam)
+                .clone(),
+        )
+    }
+
+    /// The IPAM, if the pod CIDR has been resolved.
+    pub fn assigned(&self) -> Option<Arc<HostLocalIpam>> {
+        self.ipam.lock().unwrap().clone()
+    }
+
+    /// The IP assigned to the pod, if any.
+    pub fn get(&self, pod: &PodKey) -> Option<IpAddr> {
+        self.assigned().and_then(|ipam| ipam.get(pod))
+    }
+
+    /// Releases the IP assigned to the pod, returning it if there was one.
+    pub fn release(&self, pod: &PodKey) -> Option<IpAddr> {
+        self.assigned().and_then(|ipam| ipam.release(pod))
+    }
+}
+
+/// Reads the pod CIDR assigned to the node, preferring the first of `spec.podCIDRs`. Returns
+/// `None` if the node doesn't exist yet or has no pod CIDR.
+pub async fn node_pod_cidr(
+    client: &kube::Client,
+    node_name: &str,
+) -> anyhow::Result<Option<String>> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let node = match node_client.get(node_name).await {
+        Ok(node) => node,
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(node.spec.and_then(|spec| {
+        spec.pod_cidrs
+            .into_iter()
+            .next()
+            .or(spec.pod_cidr)
+            .filter(|c| !c.is_empty())
+    }))
+}
+
+fn address_bits(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn masked(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assigns_and_releases_ips() {
+        let ipam = HostLocalIpam::new("10.244.0.0/30").unwrap();
+        let first = PodKey::new("default", "first");
+        let second = PodKey::new("default", "second");
+
+        // A /30 only has one address left once the network, gateway and broadcast
+        // addresses are excluded
+        let ip = ipam.assign(&first, None).unwrap();
+        assert_eq!(ip, "10.244.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(ipam.assign(&first, None).unwrap(), ip);
+        assert!(ipam.assign(&second, None).is_err());
+
+        assert_eq!(ipam.release(&first), Some(ip));
+        assert_eq!(ipam.assign(&second, None).unwrap(), ip);
+        assert_eq!(ipam.get(&first), None);
+    }
+
+    #[test]
+    fn test_keeps_preferred_ip() {
+        let ipam = HostLocalIpam::new("fd00:10:244::/64").unwrap();
+        let pod = PodKey::new("default", "pod");
+        let preferred: IpAddr = "fd00:10:244::42".parse().unwrap();
+        assert_eq!(ipam.assign(&pod, Some(preferred)).unwrap(), preferred);
+
+        let other = PodKey::new("default", "other");
+        let outside: IpAddr = "fd00:10:245::42".parse().unwrap();
+        assert_ne!(ipam.assign(&other, Some(outside)).unwrap(), outside);
+        assert!(HostLocalIpam::new("10.244.0.0").is_err());
+        assert!(HostLocalIpam::new("10.244.0.0/31").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_node_ipam_resolves_pod_cidr_once_node_has_one() {
+        use futures::pin_mut;
+        use http::{Request as HttpRequest, Response as HttpResponse, StatusCode};
+        use hyper::Body;
+        use tower_test::mock;
+
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let server = tokio::spawn(async move {
+            pin_mut!(handle);
+            // The node hasn't been registered yet the first time, and then has a pod CIDR
+            let (request, send) = handle.next_request().await.expect("service not called");
+            assert_eq!(request.uri().path(), "/api/v1/nodes/krustlet");
+            let not_found = serde_json::json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "status": "Failure",
+                "message": "nodes \"krustlet\" not found",
+                "reason": "NotFound",
+                "code": 404,
+            });
+            send.send_response(
+                HttpResponse::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from(serde_json::to_vec(&not_found).unwrap()))
+                    .unwrap(),
+            );
+            let (_, send) = handle.next_request().await.expect("service not called");
+            let node = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": { "name": "krustlet" },
+                "spec": { "podCIDR": "10.244.1.0/24", "podCIDRs": ["10.244.1.0/24"] },
+            });
+            send.send_response(
+                HttpResponse::builder()
+                    .body(Body::from(serde_json::to_vec(&node).unwrap()))
+                    .unwrap(),
+            );
+        });
+        let client = kube::Client::new(mock_service, "default");
+        let ipam = NodeIpam::new(client, "krustlet", None).unwrap();
+        assert!(ipam.resolve().await.is_none());
+        let pod = PodKey::new("default", "pod");
+        let ip = ipam
+            .resolve()
+            .await
+            .expect("pod CIDR should be resolved once the node has one")
+            .assign(&pod, None)
+            .unwrap();
+        assert_eq!(ip, "10.244.1.2".parse::<IpAddr>().unwrap());
+        server.await.unwrap();
+
+        // Once resolved the node isn't read again
+        assert_eq!(ipam.get(&pod), Some(ip));
+        assert!(ipam.resolve().await.is_some());
+        assert_eq!(ipam.release(&pod), Some(ip));
+    }
+}
@@ -0,0 +1,677 @@
+//! An instrumented client for the Kubernetes API.
+//!
+//! Every request made through the client is recorded in the Kubelet's metrics by verb and
+//! resource: how long it took, the response code, and whether it was a retry of a request that
+//! failed shortly before. Requests that take longer than a threshold are logged in the span of
+//! the caller, so the log line carries the pod (or other task) that made the request.
+//!
+//! The client also notices when the API server comes back after it couldn't be reached, so that
+//! work lost while it was away, such as status patches, can be redone. Shortly before a
+//! credential from an exec credential plugin expires, the client is rebuilt from its
+//! configuration, which runs the plugin again. When the API server rejects the client's
+//! credentials anyway, the client is rebuilt the same way and the request is retried once with
+//! the new credential.
+//!
+//! Requests made while the Kubelet's [`Net`](crate::environment::Net) says the API server is
+//! unreachable fail as though the API server were unavailable, without being sent.
+//!
+//! Libraries that can only build their own client from a configuration, such as krator, are
+//! given a configuration from [`loopback_config`] instead, which reaches the API server through
+//! an instrumented client.
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use http::header::{AUTHORIZATION, HOST};
+use http::{HeaderValue, Method, Request};
+use hyper::Body;
+use kube::config::{ExecConfig, Kubeconfig};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
+use tracing::{error, info, warn};
+
+use crate::environment::{Clock, Environment, API_SERVER};
+use crate::fault::{self, FaultPoint, InjectedFault};
+use crate::metrics::register;
+
+/// How long after a failed request the same request is counted as a retry
+const RETRY_WINDOW: Duration = Duration::from_secs(60);
+/// The least time between rebuilding the client to refresh its credentials, so a credential the
+/// API server will never accept doesn't run the exec plugin for every request
+const CREDENTIAL_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long before an exec credential expires the client is rebuilt to refresh it, so requests
+/// made just before it expires aren't rejected
+const CREDENTIAL_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Whether the last request failed because the API server couldn't be reached or was unavailable
+static API_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+/// How many times a request has succeeded after the API server was unavailable
+static RECONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    static ref REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "kube_api_request_duration_seconds",
+            "Time taken for the Kubernetes API to respond to a request"
+        ),
+        &["verb", "resource"]
+    ));
+    static ref REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "kube_api_requests_total",
+            "Number of requests made to the Kubernetes API, by response code"
+        ),
+        &["verb", "resource", "code"]
+    ));
+    static ref RETRIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "kube_api_request_retries_total",
+            "Number of requests to the Kubernetes API that repeated one that had just failed"
+        ),
+        &["verb", "resource"]
+    ));
+}
+
+/// Creates a client for the given configuration whose requests are instrumented, logging those
+/// that take longer than `slow_call_threshold`, and that only reaches the API server while the
+/// environment's network says it can. Credentials are refreshed by the environment's clock.
+pub fn instrumented_client(
+    config: kube::Config,
+    slow_call_threshold: Duration,
+    environment: &Environment,
+) -> anyhow::Result<kube::Client> {
+    let default_namespace = config.default_ns.clone();
+    let inner = Arc::new(RefreshingClient::new(config, environment.clock.clone())?);
+    let net = environment.net.clone();
+    let recent_failures: Arc<Mutex<HashMap<String, Instant>>> = Default::default();
+    let service = tower::service_fn(move |request: Request<Body>| {
+        let inner = inner.clone();
+        let recent_failures = recent_failures.clone();
+        let net = net.clone();
+        // The span of whoever made the request, which usually names the pod
+        let span = tracing::Span::current();
+        async move {
+            let verb = verb(request.method(), request.uri());
+            let resource = resource(request.uri().path());
+            let key = format!("{} {}", request.method(), request.uri());
+            if is_retry(&recent_failures, &key) {
+                RETRIES.with_label_values(&[verb, &resource]).inc();
+            }
+
+            let started = Instant::now();
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body)
+                .await
+                .map_err(kube::Error::HyperError)?;
+            let injected = if net.reachable(API_SERVER) {
+                fault::inject(FaultPoint::ApiServer, &format!("{} {}", verb, resource))
+                    .await
+                    .map_err(|fault| fault_response(&fault))
+            } else {
+                Err(status_response(
+                    503,
+                    "Unreachable",
+                    "the API server is unreachable",
+                ))
+            };
+            let response = match injected {
+                Ok(()) => {
+                    let retry = copy_request(&parts, &body);
+                    let response = inner
+                        .current()
+                        .send(Request::from_parts(parts, Body::from(body.to_vec())))
+                        .await;
+                    let unauthorized =
+                        matches!(&response, Ok(r) if r.status() == http::StatusCode::UNAUTHORIZED);
+                    if unauthorized
+                        && inner.refresh(
+                            "Kubernetes API rejected the client's credentials, refreshed them",
+                        )
+                    {
+                        inner.current().send(retry).await
+                    } else {
+                        response
+                    }
+                }
+                Err(response) => Ok(response),
+            };
+            let elapsed = started.elapsed();
+
+            let code = match &response {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(_) => "error".to_owned(),
+            };
+            REQUEST_DURATION
+                .with_label_values(&[verb, &resource])
+                .observe(elapsed.as_secs_f64());
+            REQUESTS.with_label_values(&[verb, &resource, &code]).inc();
+            let failed = match &response {
+                Ok(response) => {
+                    response.status().is_server_error() || response.status().as_u16() == 429
+                }
+                Err(_) => true,
+            };
+            let mut failures = recent_failures.lock().unwrap();
+            failures.retain(|_, at| at.elapsed() < RETRY_WINDOW);
+            if failed {
+                failures.insert(key.clone(), Instant::now());
+            } else {
+                failures.remove(&key);
+            }
+            drop(failures);
+            let unavailable = match &response {
+                Ok(response) => response.status() == http::StatusCode::SERVICE_UNAVAILABLE,
+                Err(_) => true,
+            };
+            if unavailable {
+                API_UNAVAILABLE.store(true, Ordering::SeqCst);
+            } else if API_UNAVAILABLE.swap(false, Ordering::SeqCst) {
+                RECONNECTIONS.fetch_add(1, Ordering::SeqCst);
+            }
+
+            // Watches are excluded, as they return as soon as the watch is established
+            if elapsed > slow_call_threshold && verb != "watch" {
+                warn!(
+                    parent: &span,
+                    verb,
+                    %resource,
+                    request = %key,
+                    %code,
+                    ?elapsed,
+                    "Slow request to the Kubernetes API"
+                );
+            }
+            response
+        }
+    });
+    Ok(kube::Client::new(service, default_namespace))
+}
+
+/// Serves the Kubernetes API through `client` on a loopback address, returning a configuration
+/// that reaches it. Clients built from the configuration make their requests through `client`,
+/// so they are instrumented too.
+///
+/// Only requests carrying the bearer token in the returned configuration are served. The token
+/// is removed before a request is sent on, so `client` authenticates it with its own
+/// credentials.
+pub async fn loopback_config(
+    client: kube::Client,
+    default_namespace: &str,
+) -> anyhow::Result<kube::Config> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+    let token = uuid::Uuid::new_v4().to_string();
+    let authorization = HeaderValue::from_str(&format!("Bearer {}", token))?;
+
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let client = client.clone();
+        let authorization = authorization.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(
+                move |mut request: Request<Body>| {
+                    let client = client.clone();
+                    let authorized = request.headers().get(AUTHORIZATION) == Some(&authorization);
+                    async move {
+                        if !authorized {
+                            return Ok::<_, Infallible>(status_response(
+                                401,
+                                "Unauthorized",
+                                "Unauthorized",
+                            ));
+                        }
+                        request.headers_mut().remove(AUTHORIZATION);
+                        request.headers_mut().remove(HOST);
+                        Ok(client.send(request).await.unwrap_or_else(|e| {
+                            status_response(503, "ServiceUnavailable", &e.to_string())
+                        }))
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::from_tcp(listener)?.serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!(error = %e, "Kubernetes API loopback stopped");
+        }
+    });
+
+    let kubeconfig: Kubeconfig = serde_json::from_value(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Config",
+        "clusters": [{
+            "name": "loopback",
+            "cluster": { "server": format!("http://{}", address) },
+        }],
+        "users": [{
+            "name": "loopback",
+            "user": { "token": token },
+        }],
+        "contexts": [{
+            "name": "loopback",
+            "context": {
+                "cluster": "loopback",
+                "user": "loopback",
+                "namespace": default_namespace,
+            },
+        }],
+        "current-context": "loopback",
+    }))?;
+    kube::Config::from_custom_kubeconfig(kubeconfig, &Default::default())
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to configure Kubernetes API loopback: {}", e))
+}
+
+/// Builds a client from a configuration, also returning when the credential it authenticates
+/// with expires, if that is known.
+type ClientBuilder =
+    dyn Fn(&kube::Config) -> anyhow::Result<(kube::Client, Option<DateTime<Utc>>)> + Send + Sync;
+
+/// A client that can be rebuilt from its configuration to refresh its credentials, such as
+/// those an exec credential plugin issued, before they expire or once they have.
+struct RefreshingClient {
+    config: kube::Config,
+    build: Box<ClientBuilder>,
+    clock: Arc<dyn Clock>,
+    /// The client, and when its credential expires
+    client: RwLock<(kube::Client, Option<DateTime<Utc>>)>,
+    refreshed: Mutex<DateTime<Utc>>,
+}
+
+impl RefreshingClient {
+    fn new(config: kube::Config, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        RefreshingClient::with_builder(config, clock, Box::new(build_client))
+    }
+
+    fn with_builder(
+        config: kube::Config,
+        clock: Arc<dyn Clock>,
+        build: Box<ClientBuilder>,
+    ) -> anyhow::Result<Self> {
+        let client = build(&config)?;
+        let refreshed = clock.now();
+        Ok(RefreshingClient {
+            config,
+            build,
+            clock,
+            client: RwLock::new(client),
+            refreshed: Mutex::new(refreshed),
+        })
+    }
+
+    /// The client, rebuilt first if its credential is about to expire.
+    fn current(&self) -> kube::Client {
+        let (client, expires) = self.client.read().unwrap().clone();
+        let now = self.clock.now();
+        let expiring = expires.map_or(false, |expires| match (expires - now).to_std() {
+            Ok(left) => left <= CREDENTIAL_EXPIRY_MARGIN,
+            // Already expired
+            Err(_) => true,
+        });
+        if expiring
+            && self.refresh("Kubernetes API credentials were about to expire, refreshed them")
+        {
+            return self.client.read().unwrap().0.clone();
+        }
+        client
+    }
+
+    /// Rebuilds the client unless it was rebuilt very recently, returning whether it was. The
+    /// reason is logged if it was.
+    fn refresh(&self, reason: &str) -> bool {
+        let mut refreshed = self.refreshed.lock().unwrap();
+        let now = self.clock.now();
+        let recent = (now - *refreshed)
+            .to_std()
+            .map_or(false, |since| since < CREDENTIAL_REFRESH_INTERVAL);
+        if recent {
+            return false;
+        }
+        *refreshed = now;
+        match (self.build)(&self.config) {
+            Ok(client) => {
+                info!("{}", reason);
+                *self.client.write().unwrap() = client;
+                true
+            }
+            Err(e) => {
+                warn!(error = %e, "Unable to refresh Kubernetes API credentials");
+                false
+            }
+        }
+    }
+}
+
+/// Builds a client from the configuration. An exec credential plugin is run here rather than
+/// left to kube, so that the expiry of the token it issues is known.
+fn build_client(config: &kube::Config) -> anyhow::Result<(kube::Client, Option<DateTime<Utc>>)> {
+    let exec = match &config.auth_info.exec {
+        Some(exec) => exec,
+        None => return Ok((kube::Client::try_from(config.clone())?, None)),
+    };
+    let credential = exec_credential(exec)?;
+    match credential.token {
+        Some(token) => {
+            let mut config = config.clone();
+            config.auth_info.exec = None;
+            config.auth_info.token = Some(token.into());
+            Ok((
+                kube::Client::try_from(config)?,
+                credential.expiration_timestamp,
+            ))
+        }
+        // Other credentials, such as client certificates, are left to kube to get from the plugin
+        None => Ok((
+            kube::Client::try_from(config.clone())?,
+            credential.expiration_timestamp,
+        )),
+    }
+}
+
+/// The `ExecCredential` an exec credential plugin writes to its standard output.
+#[derive(serde::Deserialize)]
+struct ExecCredential {
+    status: Option<ExecCredentialStatus>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus {
+    token: Option<String>,
+    expiration_timestamp: Option<DateTime<Utc>>,
+}
+
+/// Runs an exec credential plugin, returning the credential it issued.
+fn exec_credential(exec: &ExecConfig) -> anyhow::Result<ExecCredentialStatus> {
+    let mut command = std::process::Command::new(&exec.command);
+    if let Some(args) = &exec.args {
+        command.args(args);
+    }
+    for var in exec.env.iter().flatten() {
+        if let (Some(name), Some(value)) = (var.get("name"), var.get("value")) {
+            command.env(name, value);
+        }
+    }
+    let output = command.output().map_err(|e| {
+        anyhow::anyhow!(
+            "Unable to run exec credential plugin {}: {}",
+            exec.command,
+            e
+        )
+    })?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Exec credential plugin {} failed: {}",
+            exec.command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let credential: ExecCredential = serde_json::from_slice(&output.stdout)?;
+    credential.status.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Exec credential plugin {} issued no credential",
+            exec.command
+        )
+    })
+}
+
+/// A copy of a request whose body has already been read, to send again.
+fn copy_request(parts: &http::request::Parts, body: &[u8]) -> Request<Body> {
+    let mut request = Request::new(Body::from(body.to_vec()));
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    request
+}
+
+/// How many times the API server has been reached again after it was unavailable. Tasks that
+/// need to catch up after an outage watch this for changes.
+pub fn reconnections() -> u64 {
+    RECONNECTIONS.load(Ordering::SeqCst)
+}
+
+/// The response the API server would give to a request that failed the way the fault says.
+fn fault_response(fault: &InjectedFault) -> http::Response<Body> {
+    status_response(
+        fault.status.unwrap_or(503),
+        "InjectedFault",
+        &fault.to_string(),
+    )
+}
+
+/// A failure response with the given code, in the form the API server reports failures in.
+fn status_response(code: u16, reason: &str, message: &str) -> http::Response<Body> {
+    let status = serde_json::json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code,
+    });
+    let mut response = http::Response::new(Body::from(status.to_string()));
+    *response.status_mut() =
+        http::StatusCode::from_u16(code).unwrap_or(http::StatusCode::SERVICE_UNAVAILABLE);
+    response
+}
+
+fn is_retry(recent_failures: &Mutex<HashMap<String, Instant>>, key: &str) -> bool {
+    recent_failures
+        .lock()
+        .unwrap()
+        .get(key)
+        .map(|at| at.elapsed() < RETRY_WINDOW)
+        .unwrap_or(false)
+}
+
+/// The Kubernetes verb of a request, following the same naming as the API server's audit logs.
+fn verb(method: &Method, uri: &http::Uri) -> &'static str {
+    let query = uri.query().unwrap_or_default();
+    let named = has_name(uri.path());
+    match *method {
+        Method::GET
+            if query
+                .split('&')
+                .any(|p| p == "watch=true" || p == "watch=1") =>
+        {
+            "watch"
+        }
+        Method::GET if named => "get",
+        Method::GET => "list",
+        Method::POST => "create",
+        Method::PUT => "update",
+        Method::PATCH => "patch",
+        Method::DELETE if named => "delete",
+        Method::DELETE => "deletecollection",
+        _ => "other",
+    }
+}
+
+/// The segments of a resource path after the API group and version, such as
+/// `["namespaces", "default", "pods", "web", "status"]`.
+fn resource_segments(path: &str) -> Vec<&str> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let skip = match segments.first() {
+        // The core group: /api/v1/...
+        Some(&"api") => 2,
+        // Named groups: /apis/apps/v1/...
+        Some(&"apis") => 3,
+        _ => 0,
+    };
+    let mut segments: Vec<&str> = segments.into_iter().skip(skip).collect();
+    // Namespaced resources: namespaces/{namespace}/{resource}/...
+    if segments.len() > 2 && segments[0] == "namespaces" {
+        segments.drain(..2);
+    }
+    segments
+}
+
+fn has_name(path: &str) -> bool {
+    resource_segments(path).len() > 1
+}
+
+/// The resource a request is for, including the subresource (such as `pods/status`).
+fn resource(path: &str) -> String {
+    match resource_segments(path).as_slice() {
+        [] => "unknown".to_owned(),
+        [resource] | [resource, _] => (*resource).to_owned(),
+        [resource, _, subresource, ..] => format!("{}/{}", resource, subresource),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environment::{ManualClock, SimulatedNet};
+    use futures::pin_mut;
+    use k8s_openapi::api::core::v1::Node;
+    use tower_test::mock;
+
+    #[tokio::test]
+    async fn test_requests_fail_while_api_server_is_unreachable() {
+        let net = Arc::new(SimulatedNet::default());
+        net.partition(API_SERVER);
+        let environment = Environment {
+            net,
+            ..Default::default()
+        };
+        let client = instrumented_client(
+            kube::Config::new("http://127.0.0.1:1".parse().unwrap()),
+            Duration::from_secs(1),
+            &environment,
+        )
+        .unwrap();
+        let nodes: kube::Api<k8s_openapi::api::core::v1::Node> = kube::Api::all(client);
+        match nodes.get("krustlet").await {
+            Err(kube::Error::Api(e)) => {
+                assert_eq!(e.code, 503);
+                assert_eq!(e.reason, "Unreachable");
+            }
+            other => panic!("request was not failed: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loopback_sends_requests_through_the_client() {
+        let (mock_service, handle) = mock::pair::<Request<Body>, http::Response<Body>>();
+        let api_server = tokio::spawn(async move {
+            pin_mut!(handle);
+            let (request, send) = handle.next_request().await.expect("no request was sent");
+            assert_eq!(request.uri().path(), "/api/v1/nodes/krustlet");
+            assert!(request.headers().get(AUTHORIZATION).is_none());
+            let node = serde_json::json!({
+                "apiVersion": "v1",
+                "kind": "Node",
+                "metadata": { "name": "krustlet" },
+            });
+            send.send_response(http::Response::new(Body::from(node.to_string())));
+        });
+        let config = loopback_config(kube::Client::new(mock_service, "default"), "default")
+            .await
+            .unwrap();
+        assert_eq!(config.default_ns, "default");
+
+        let nodes: kube::Api<Node> =
+            kube::Api::all(kube::Client::try_from(config.clone()).unwrap());
+        let node = nodes.get("krustlet").await.unwrap();
+        assert_eq!(node.metadata.name.as_deref(), Some("krustlet"));
+        api_server.await.unwrap();
+
+        let unauthenticated = kube::Config::new(config.cluster_url.clone());
+        let nodes: kube::Api<Node> =
+            kube::Api::all(kube::Client::try_from(unauthenticated).unwrap());
+        match nodes.get("krustlet").await {
+            Err(kube::Error::Api(e)) => assert_eq!(e.code, 401),
+            other => panic!("unauthenticated request was served: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credentials_are_refreshed_before_they_expire() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let builds = Arc::new(AtomicU64::new(0));
+        let (issued_at, counter) = (clock.clone(), builds.clone());
+        let client = RefreshingClient::with_builder(
+            kube::Config::new("http://127.0.0.1:1".parse().unwrap()),
+            clock.clone(),
+            Box::new(move |config| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                // Every credential lasts five minutes
+                let expires = issued_at.now() + chrono::Duration::minutes(5);
+                Ok((kube::Client::try_from(config.clone())?, Some(expires)))
+            }),
+        )
+        .unwrap();
+        let built = || builds.load(Ordering::SeqCst);
+        assert_eq!(built(), 1);
+
+        clock.advance(Duration::from_secs(3 * 60));
+        client.current();
+        assert_eq!(
+            built(),
+            1,
+            "credential was refreshed long before it expired"
+        );
+
+        // 30 seconds before the credential expires
+        clock.advance(Duration::from_secs(90));
+        client.current();
+        assert_eq!(built(), 2, "credential was not refreshed before it expired");
+        client.current();
+        assert_eq!(built(), 2, "refreshed credential was refreshed again");
+
+        // Rejected credentials are still refreshed, though not over and over
+        clock.advance(Duration::from_secs(30));
+        assert!(client.refresh("rejected"));
+        assert!(!client.refresh("rejected"));
+        assert_eq!(built(), 3);
+    }
+
+    #[test]
+    fn test_classify_requests() {
+        let classify = |method: Method, uri: &str| {
+            let uri: http::Uri = uri.parse().unwrap();
+            (verb(&method, &uri), resource(uri.path()))
+        };
+        assert_eq!(
+            classify(Method::PATCH, "/api/v1/namespaces/default/pods/web/status"),
+            ("patch", "pods/status".to_owned())
+        );
+        assert_eq!(
+            classify(
+                Method::GET,
+                "/api/v1/pods?fieldSelector=spec.nodeName%3Dkrustlet&watch=true"
+            ),
+            ("watch", "pods".to_owned())
+        );
+        assert_eq!(
+            classify(Method::GET, "/api/v1/nodes/krustlet"),
+            ("get", "nodes".to_owned())
+        );
+        assert_eq!(
+            classify(Method::GET, "/api/v1/namespaces/default/configmaps"),
+            ("list", "configmaps".to_owned())
+        );
+        assert_eq!(
+            classify(
+                Method::PUT,
+                "/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases/krustlet"
+            ),
+            ("update", "leases".to_owned())
+        );
+        assert_eq!(
+            classify(Method::DELETE, "/api/v1/namespaces/default/pods/web"),
+            ("delete", "pods".to_owned())
+        );
+        assert_eq!(
+            classify(Method::POST, "/api/v1/namespaces"),
+            ("create", "namespaces".to_owned())
+        );
+    }
+}
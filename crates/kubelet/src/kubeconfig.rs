@@ -2,6 +2,9 @@ use std::env;
 use std::path::PathBuf;
 
 use dirs::home_dir;
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::Config;
+use tracing::{debug, info};
 
 pub const KUBECONFIG: &str = "KUBECONFIG";
 
@@ -10,6 +13,45 @@ pub(crate) fn exists() -> bool {
     path().unwrap_or_default().exists()
 }
 
+/// Loads the client config from the kubeconfig, using the given context or, if there isn't one,
+/// inferring the config the same way as `kubectl` does.
+///
+/// Users that authenticate with an exec credential plugin (such as a cloud provider CLI) are
+/// supported. The plugin is run to get a credential when a client is built from the config,
+/// and clients built with [`crate::kube_client::instrumented_client`] run it again shortly
+/// before the credential expires, and whenever the API server rejects it.
+pub(crate) async fn load(context: Option<&str>) -> anyhow::Result<Config> {
+    let kubeconfig = read().ok();
+    if let Some(user) = kubeconfig
+        .as_ref()
+        .and_then(|kubeconfig| exec_user(kubeconfig, context))
+    {
+        info!(%user, "Kubeconfig user authenticates with an exec credential plugin");
+    }
+    match (context, kubeconfig) {
+        (Some(context), Some(kubeconfig)) => load_context(kubeconfig, context).await,
+        (Some(context), None) => Err(anyhow::anyhow!(
+            "Unable to load context {} as there is no kubeconfig",
+            context
+        )),
+        (None, _) => Config::infer()
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to load config from host: {}", e)),
+    }
+}
+
+/// Loads the client config of the named context from the kubeconfig.
+async fn load_context(kubeconfig: Kubeconfig, context: &str) -> anyhow::Result<Config> {
+    debug!(%context, "Loading kubeconfig context");
+    let options = KubeConfigOptions {
+        context: Some(context.to_owned()),
+        ..Default::default()
+    };
+    Config::from_custom_kubeconfig(kubeconfig, &options)
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to load context {} from kubeconfig: {}", context, e))
+}
+
 /// Returns kubeconfig path from specified environment variable.
 fn path() -> Option<PathBuf> {
     env::var_os(KUBECONFIG)
@@ -21,3 +63,121 @@ fn path() -> Option<PathBuf> {
 fn default_path() -> Option<PathBuf> {
     home_dir().map(|h| h.join(".kube").join("config"))
 }
+
+fn read() -> anyhow::Result<Kubeconfig> {
+    let path = path().ok_or_else(|| anyhow::anyhow!("Unable to find a kubeconfig"))?;
+    let raw = std::fs::read(path)?;
+    Ok(serde_yaml::from_slice(&raw)?)
+}
+
+/// Returns the name of the user of the context (or the current context) if it authenticates
+/// with an exec credential plugin.
+fn exec_user(kubeconfig: &Kubeconfig, context: Option<&str>) -> Option<String> {
+    let context = context.or_else(|| kubeconfig.current_context.as_deref())?;
+    let user = &kubeconfig
+        .contexts
+        .iter()
+        .find(|c| c.name == context)?
+        .context
+        .user;
+    kubeconfig
+        .auth_infos
+        .iter()
+        .find(|a| &a.name == user && a.auth_info.exec.is_some())
+        .map(|a| a.name.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exec_user() {
+        let kubeconfig: Kubeconfig = serde_yaml::from_str(
+            r#"
+apiVersion: v1
+kind: Config
+clusters:
+- name: managed
+  cluster:
+    server: https://managed.example.com
+contexts:
+- name: managed
+  context:
+    cluster: managed
+    user: cloud
+- name: local
+  context:
+    cluster: managed
+    user: static
+current-context: local
+users:
+- name: cloud
+  user:
+    exec:
+      apiVersion: client.authentication.k8s.io/v1beta1
+      command: cloud-cli
+      args: ["get-token"]
+- name: static
+  user:
+    token: abc123
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(exec_user(&kubeconfig, None), None);
+        assert_eq!(
+            exec_user(&kubeconfig, Some("managed")).as_deref(),
+            Some("cloud")
+        );
+        assert_eq!(exec_user(&kubeconfig, Some("missing")), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_context() {
+        let kubeconfig = || -> Kubeconfig {
+            serde_yaml::from_str(
+                r#"
+apiVersion: v1
+kind: Config
+clusters:
+- name: dev
+  cluster:
+    server: https://dev.example.com
+- name: prod
+  cluster:
+    server: https://prod.example.com
+contexts:
+- name: dev
+  context:
+    cluster: dev
+    user: static
+    namespace: krustlet
+- name: prod
+  context:
+    cluster: prod
+    user: static
+current-context: dev
+users:
+- name: static
+  user:
+    token: abc123
+"#,
+            )
+            .unwrap()
+        };
+
+        let config = load_context(kubeconfig(), "prod").await.unwrap();
+        assert_eq!(config.cluster_url.host(), Some("prod.example.com"));
+        assert_eq!(config.default_ns, "default");
+
+        let config = load_context(kubeconfig(), "dev").await.unwrap();
+        assert_eq!(config.cluster_url.host(), Some("dev.example.com"));
+        assert_eq!(config.default_ns, "krustlet");
+
+        let err = load_context(kubeconfig(), "missing")
+            .await
+            .expect_err("a missing context should not load");
+        assert!(err.to_string().contains("Unable to load context missing"));
+    }
+}
@@ -1,21 +1,23 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
 use crate::config::Config;
-use crate::node;
+use crate::environment::{Clock, Environment, API_SERVER};
+use crate::node::{KubeNodeController, NodeController};
+use crate::node_shutdown::ShutdownInhibitor;
 use crate::operator::PodOperator;
 use crate::plugin_watcher::PluginRegistry;
+use crate::pod::PodIntentLog;
 use crate::provider::{DevicePluginSupport, PluginSupport, Provider};
 use crate::resources::device_plugin_manager::{serve_device_registry, DeviceManager};
 use crate::webserver::start as start_webserver;
 
 use futures::future::{FutureExt, TryFutureExt};
 use kube::api::ListParams;
-use std::convert::TryFrom;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::signal::ctrl_c;
 use tokio::task;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use krator::{ControllerBuilder, Manager};
 
@@ -35,6 +37,7 @@ pub struct Kubelet<P> {
     provider: Arc<P>,
     kube_config: kube::Config,
     config: Box<Config>,
+    node_controller: Arc<dyn NodeController>,
 }
 
 impl<P: Provider> Kubelet<P> {
@@ -45,8 +48,10 @@ impl<P: Provider> Kubelet<P> {
         kube_config: kube::Config,
         config: Config,
     ) -> anyhow::Result<Self> {
+        let provider = Arc::new(provider);
         Ok(Self {
-            provider: Arc::new(provider),
+            node_controller: Arc::new(KubeNodeController::new(provider.clone())),
+            provider,
             kube_config,
             // The config object can get a little bit for some reason, so put it
             // on the heap
@@ -54,20 +59,45 @@ impl<P: Provider> Kubelet<P> {
         })
     }
 
+    /// Use the given controller to register the node and keep it alive, instead of registering
+    /// a Node and its lease with the Kubernetes API.
+    pub fn with_node_controller(mut self, node_controller: impl NodeController + 'static) -> Self {
+        self.node_controller = Arc::new(node_controller);
+        self
+    }
+
     /// Begin answering requests for the Kubelet.
     ///
     /// This will listen on the given address, and will also begin watching for Pod
     /// events, which it will handle.
     pub async fn start(&self) -> anyhow::Result<()> {
-        let client = kube::Client::try_from(self.kube_config.clone())?;
-
-        // Create the node. If it already exists, this will exit
-        node::create(&client, &self.config, self.provider.clone()).await;
+        let client = crate::kube_client::instrumented_client(
+            self.kube_config.clone(),
+            self.config.slow_api_call_threshold,
+            &self.config.environment,
+        )?;
+        crate::node::problem::use_clock(self.config.environment.clock.clone());
+
+        // Clean up after pods that were interrupted the last time the Kubelet ran, before
+        // any new work for them arrives from the API server
+        let intent_log = Arc::new(self.recover_pods().await?);
+
+        self.node_controller
+            .register(&client, &self.config)
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to register node: {}", e))?;
 
         // Flag to indicate graceful shutdown has started.
         let signal = Arc::new(AtomicBool::new(false));
         let signal_task = start_signal_task(Arc::clone(&signal)).fuse().boxed();
 
+        // Hold off system shutdown (if configured) so pods have a chance to terminate
+        // gracefully. The lock is released once the Kubelet has finished shutting down.
+        let mut inhibitor = ShutdownInhibitor::acquire(self.config.shutdown_grace_period).await;
+        let node_shutdown_task = start_node_shutdown_watcher(inhibitor.as_mut())
+            .fuse()
+            .boxed();
+
         let plugin_registrar = start_plugin_registry(
             self.provider
                 .provider_state()
@@ -94,9 +124,41 @@ impl<P: Provider> Kubelet<P> {
             .boxed();
 
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
-            .fuse()
-            .boxed();
+        let node_updater = start_node_updater(
+            self.node_controller.clone(),
+            client.clone(),
+            self.config.as_ref().clone(),
+        )
+        .fuse()
+        .boxed();
+
+        // Catch pod statuses up after the API server has been unreachable
+        let status_reconciler = start_status_reconciler(
+            client.clone(),
+            self.config.node_name.clone(),
+            self.config.environment.clone(),
+        )
+        .fuse()
+        .boxed();
+
+        // Keep the configured images warm in the provider's store
+        let pre_puller = start_pre_puller(
+            self.provider.store(),
+            client.clone(),
+            self.config.node_name.clone(),
+            self.config.pre_pull_images.clone(),
+            self.config.pre_pull_secrets.clone(),
+            self.config.pre_pull_interval,
+            self.config.environment.clock.clone(),
+        )
+        .fuse()
+        .boxed();
+
+        // Reclaim space in the provider's module cache
+        let store_collector =
+            start_store_collector(self.provider.store(), self.config.environment.clock.clone())
+                .fuse()
+                .boxed();
 
         // If any of these tasks fail, we can initiate graceful shutdown.
         let services = Box::pin(async {
@@ -104,10 +166,22 @@ impl<P: Provider> Kubelet<P> {
                 res = signal_task => if let Err(e) = res {
                     error!(error = %e, "Signal task completed with error");
                 },
+                res = node_shutdown_task => if let Err(e) = res {
+                    error!(error = %e, "Node shutdown watcher completed with error");
+                },
                 res = webserver => error!(result = ?res, "Webserver task completed with result"),
                 res = node_updater => if let Err(e) = res {
                     error!(error = %e, "Node updater task completed with error");
                 },
+                res = status_reconciler => if let Err(e) = res {
+                    error!(error = %e, "Status reconciler task completed with error");
+                },
+                res = pre_puller => if let Err(e) = res {
+                    error!(error = %e, "Pre-puller task completed with error");
+                },
+                res = store_collector => if let Err(e) = res {
+                    error!(error = %e, "Store garbage collector task completed with error");
+                },
                 res = plugin_registrar => if let Err(e) = res {
                     error!(error = %e, "Plugin registrar task completed with error");
                 },
@@ -123,7 +197,7 @@ impl<P: Provider> Kubelet<P> {
         // Periodically checks for shutdown signal and cleans up resources gracefully if caught.
         let signal_handler = start_signal_handler(Arc::clone(&signal)).fuse().boxed();
 
-        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone());
+        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone(), intent_log);
         let node_selector = format!("spec.nodeName={}", &self.config.node_name);
         let params = ListParams {
             field_selector: Some(node_selector),
@@ -131,21 +205,34 @@ impl<P: Provider> Kubelet<P> {
         };
 
         let controller_builder = ControllerBuilder::new(operator).with_params(params);
-        let mut manager = Manager::new(&self.kube_config);
+        // krator builds its own client from a configuration, so it is handed one that reaches
+        // the API server through the instrumented client
+        let loopback_config =
+            crate::kube_client::loopback_config(client.clone(), &self.kube_config.default_ns)
+                .await?;
+        let mut manager = Manager::new(&loopback_config);
         manager.register_controller(controller_builder);
-        let operator_task = manager.start().boxed();
+        let mut operator_task = manager.start().boxed();
 
         // These must all be running for graceful shutdown. An error here exits ungracefully.
         let core = Box::pin(async {
             tokio::select! {
                 res = signal_handler => match res {
-                    Ok(()) => self.provider.shutdown(&self.config.node_name).await,
+                    // Keep handling pod events while the provider shuts down, so that pods it
+                    // evicts are still terminated by their state machines
+                    Ok(()) => tokio::select! {
+                        res = self.shutdown_provider() => res,
+                        _ = &mut operator_task => {
+                            warn!("Pod operator completed while the provider was shutting down");
+                            Ok(())
+                        }
+                    },
                     Err(e) => {
                         error!(error = %e, "Signal handler task joined with error");
                         Err(e)
                     }
                 },
-                _ = operator_task => {
+                _ = &mut operator_task => {
                     warn!("Pod operator has completed");
                     Ok(())
                 }
@@ -156,8 +243,51 @@ impl<P: Provider> Kubelet<P> {
         // return an error. Services will return if signal is set because pod_informer will drop
         // error_sender and error_handler will exit.
         tokio::try_join!(core, services)?;
+
+        // Pods have been given their chance to terminate, so let the system shutdown continue
+        drop(inhibitor);
         Ok(())
     }
+
+    /// Opens the pod intent log and hands every pod that was left unfinished to the provider.
+    async fn recover_pods(&self) -> anyhow::Result<PodIntentLog> {
+        let (intent_log, unfinished) = PodIntentLog::open(&self.config.data_dir).await?;
+        if !unfinished.is_empty() {
+            info!(
+                num_pods = unfinished.len(),
+                "Recovering pods left unfinished by the last run"
+            );
+        }
+        for pod in unfinished.iter() {
+            if let Err(e) = self.provider.recover_pod(pod).await {
+                warn!(error = %e, pod_name = pod.pod.name(), "Unable to recover pod");
+            }
+        }
+        intent_log.reset().await?;
+        Ok(intent_log)
+    }
+
+    /// Runs the provider's shutdown hook, bounded by the shutdown grace period if one is set.
+    async fn shutdown_provider(&self) -> anyhow::Result<()> {
+        let grace_period = self.config.shutdown_grace_period;
+        if grace_period == std::time::Duration::from_secs(0) {
+            return self.provider.shutdown(&self.config.node_name, None).await;
+        }
+        let deadline = tokio::time::Instant::now() + grace_period;
+        let shutdown = self
+            .provider
+            .shutdown(&self.config.node_name, Some(deadline));
+        match tokio::time::timeout_at(deadline, shutdown).await {
+            Ok(res) => res,
+            Err(_) => {
+                warn!(
+                    ?grace_period,
+                    "Provider did not shut down within the shutdown grace period"
+                );
+                Ok(())
+            }
+        }
+    }
 }
 
 // We cannot `#[derive(Clone)]` because that would place the
@@ -168,6 +298,7 @@ impl<P> Clone for Kubelet<P> {
             provider: self.provider.clone(),
             kube_config: self.kube_config.clone(),
             config: self.config.clone(),
+            node_controller: self.node_controller.clone(),
         }
     }
 }
@@ -180,6 +311,21 @@ async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Awaits a system shutdown signal from the inhibitor, if there is one. Without an inhibitor this
+/// waits forever.
+async fn start_node_shutdown_watcher(
+    inhibitor: Option<&mut ShutdownInhibitor>,
+) -> anyhow::Result<()> {
+    match inhibitor {
+        Some(inhibitor) => {
+            inhibitor.wait_for_shutdown().await?;
+            warn!("Caught system shutdown signal.");
+            Ok(())
+        }
+        None => futures::future::pending().await,
+    }
+}
+
 async fn start_plugin_registry(registrar: Option<Arc<PluginRegistry>>) -> anyhow::Result<()> {
     match registrar {
         Some(r) => r.run().await,
@@ -216,11 +362,99 @@ async fn start_device_manager(device_manager: Option<Arc<DeviceManager>>) -> any
 }
 
 /// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
-    let sleep_interval = std::time::Duration::from_secs(10);
+async fn start_node_updater(
+    node_controller: Arc<dyn NodeController>,
+    client: kube::Client,
+    config: Config,
+) -> anyhow::Result<()> {
+    let sleep_interval = node_controller.heartbeat_interval();
+    let environment = config.environment.clone();
     loop {
-        node::update(&client, &node_name).await;
-        tokio::time::sleep(sleep_interval).await;
+        if environment.net.reachable(API_SERVER) {
+            node_controller.heartbeat(&client, &config).await?;
+        } else {
+            warn!("API server is unreachable, skipping node heartbeat");
+        }
+        environment.clock.sleep(sleep_interval).await;
+    }
+}
+
+/// How often the status reconciler checks whether the API server has come back
+const STATUS_RECONCILE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Reconciles the statuses of the node's pods whenever the API server can be reached again after
+/// it couldn't, retrying until a reconciliation completes.
+async fn start_status_reconciler(
+    client: kube::Client,
+    node_name: String,
+    environment: Environment,
+) -> anyhow::Result<()> {
+    let mut reconciled = crate::kube_client::reconnections();
+    let mut reachable = true;
+    loop {
+        environment
+            .clock
+            .sleep(STATUS_RECONCILE_CHECK_INTERVAL)
+            .await;
+        let reconnections = crate::kube_client::reconnections();
+        if !environment.net.reachable(API_SERVER) {
+            reachable = false;
+            continue;
+        }
+        if reachable && reconnections == reconciled {
+            continue;
+        }
+        match crate::pod::reconcile_statuses(&client, &node_name).await {
+            Ok(patched) => {
+                info!(
+                    patched,
+                    "Reconciled pod statuses after the API server was unreachable"
+                );
+                reconciled = reconnections;
+                reachable = true;
+            }
+            Err(e) => warn!(error = %e, "Unable to reconcile pod statuses, will try again"),
+        }
+    }
+}
+
+/// Periodically pre-pulls images into the store. Without a store this waits forever.
+async fn start_pre_puller(
+    store: Option<Arc<dyn crate::store::Store + Send + Sync>>,
+    client: kube::Client,
+    node_name: String,
+    images: Vec<String>,
+    secrets: Vec<String>,
+    interval: std::time::Duration,
+    clock: Arc<dyn Clock>,
+) -> anyhow::Result<()> {
+    match store {
+        Some(store) => {
+            crate::pre_pull::run(store, client, node_name, images, secrets, interval, clock).await
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// How often the store's module cache is garbage collected
+const STORE_GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Periodically collects garbage in the store, starting as soon as the Kubelet does so that
+/// caches left by earlier versions are migrated. Without a store this waits forever.
+async fn start_store_collector(
+    store: Option<Arc<dyn crate::store::Store + Send + Sync>>,
+    clock: Arc<dyn Clock>,
+) -> anyhow::Result<()> {
+    let store = match store {
+        Some(store) => store,
+        None => return futures::future::pending().await,
+    };
+    loop {
+        match store.collect_garbage().await {
+            Ok(freed) => debug!(freed, "Collected garbage in module store"),
+            Err(e) => warn!(error = %e, "Unable to collect garbage in module store"),
+        }
+        clock.sleep(STORE_GC_INTERVAL).await;
     }
 }
 
@@ -256,13 +490,17 @@ mod test {
     use krator::ObjectState;
     use kube::api::ObjectMeta;
     use std::collections::BTreeMap;
+    use std::convert::TryFrom;
     use tokio::sync::RwLock;
 
     fn mock_client() -> kube::Client {
         kube::Client::try_from(kube::Config::new("http://127.0.0.1:8080".parse().unwrap())).unwrap()
     }
 
-    struct MockProvider;
+    #[derive(Default)]
+    struct MockProvider {
+        shutdown_deadline: std::sync::Mutex<Option<Option<tokio::time::Instant>>>,
+    }
 
     struct ProviderState;
 
@@ -311,6 +549,19 @@ mod test {
             Arc::new(RwLock::new(ProviderState {}))
         }
 
+        // Never finishes on its own when given a deadline, so that it has to be cancelled
+        async fn shutdown(
+            &self,
+            _node_name: &str,
+            deadline: Option<tokio::time::Instant>,
+        ) -> anyhow::Result<()> {
+            *self.shutdown_deadline.lock().unwrap() = Some(deadline);
+            if deadline.is_some() {
+                futures::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+
         async fn logs(
             &self,
             _namespace: String,
@@ -322,6 +573,51 @@ mod test {
         }
     }
 
+    async fn test_kubelet(shutdown_grace_period: std::time::Duration) -> Kubelet<MockProvider> {
+        let config = Config {
+            shutdown_grace_period,
+            ..Default::default()
+        };
+        Kubelet::new(
+            MockProvider::default(),
+            kube::Config::new("http://127.0.0.1:8080".parse().unwrap()),
+            config,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_provider_shutdown_is_cancelled_at_the_deadline() {
+        tokio::time::pause();
+        let grace_period = std::time::Duration::from_secs(30);
+        let kubelet = test_kubelet(grace_period).await;
+        let started = tokio::time::Instant::now();
+
+        kubelet.shutdown_provider().await.unwrap();
+
+        let deadline = kubelet
+            .provider
+            .shutdown_deadline
+            .lock()
+            .unwrap()
+            .expect("shutdown hook should have been called");
+        assert_eq!(deadline, Some(started + grace_period));
+        assert!(tokio::time::Instant::now() >= started + grace_period);
+    }
+
+    #[tokio::test]
+    async fn test_provider_shutdown_without_grace_period_has_no_deadline() {
+        let kubelet = test_kubelet(std::time::Duration::from_secs(0)).await;
+
+        kubelet.shutdown_provider().await.unwrap();
+
+        assert_eq!(
+            *kubelet.provider.shutdown_deadline.lock().unwrap(),
+            Some(None)
+        );
+    }
+
     #[tokio::test]
     async fn test_env_vars() {
         let container = Container::new(&KubeContainer {
@@ -449,4 +745,100 @@ mod test {
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
     }
+
+    /// Counts its heartbeats instead of sending them.
+    #[derive(Default)]
+    struct CountingNodeController {
+        heartbeats: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingNodeController {
+        fn heartbeats(&self) -> usize {
+            self.heartbeats.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NodeController for CountingNodeController {
+        async fn register(&self, _client: &kube::Client, _config: &Config) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn heartbeat(&self, _client: &kube::Client, _config: &Config) -> anyhow::Result<()> {
+            self.heartbeats.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Lets the spawned tasks run until the given number of them wait on the clock.
+    async fn until_sleeping(clock: &crate::environment::ManualClock, sleepers: usize) {
+        while clock.pending_sleeps() < sleepers {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_and_backoff_follow_the_injected_clock() {
+        use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
+        use crate::environment::{ManualClock, SimulatedNet};
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let net = Arc::new(SimulatedNet::default());
+        let config = Config {
+            environment: Environment {
+                clock: clock.clone(),
+                net: net.clone(),
+            },
+            ..Default::default()
+        };
+        let controller = Arc::new(CountingNodeController::default());
+        let interval = controller.heartbeat_interval();
+        let updater = tokio::spawn(start_node_updater(
+            controller.clone(),
+            mock_client(),
+            config,
+        ));
+
+        // The first heartbeat is sent straight away, the next only once a full interval passed
+        until_sleeping(&clock, 1).await;
+        assert_eq!(controller.heartbeats(), 1);
+        clock.advance(interval / 2);
+        until_sleeping(&clock, 1).await;
+        assert_eq!(controller.heartbeats(), 1);
+        clock.advance(interval / 2);
+        until_sleeping(&clock, 1).await;
+        assert_eq!(controller.heartbeats(), 2);
+
+        // Heartbeats are skipped while the API server is unreachable, and resume once it is back
+        net.partition(API_SERVER);
+        clock.advance(interval);
+        until_sleeping(&clock, 1).await;
+        assert_eq!(controller.heartbeats(), 2);
+        net.heal(API_SERVER);
+        clock.advance(interval);
+        until_sleeping(&clock, 1).await;
+        assert_eq!(controller.heartbeats(), 3);
+        updater.abort();
+
+        // Backing off waits 10s and then 20s of the injected clock, not of the real one
+        let clock = Arc::new(ManualClock::default());
+        let mut backoff = ExponentialBackoffStrategy::default().with_clock(clock.clone());
+        let waiting = tokio::spawn(async move {
+            backoff.wait().await;
+            backoff.wait().await;
+        });
+        until_sleeping(&clock, 1).await;
+        clock.advance(Duration::from_secs(10));
+        until_sleeping(&clock, 1).await;
+        clock.advance(Duration::from_secs(10));
+        tokio::task::yield_now().await;
+        assert_eq!(
+            clock.pending_sleeps(),
+            1,
+            "the second wait should still be pending"
+        );
+        clock.advance(Duration::from_secs(10));
+        waiting.await.unwrap();
+    }
 }
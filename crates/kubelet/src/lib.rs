@@ -87,7 +87,9 @@
 mod bootstrapping;
 mod config_interpreter;
 mod kubelet;
+mod node_shutdown;
 mod operator;
+mod pre_pull;
 
 pub(crate) mod kubeconfig;
 pub(crate) mod webserver;
@@ -111,22 +113,33 @@ pub(crate) mod grpc_sock;
 pub(crate) mod mio_uds_windows;
 
 pub mod backoff;
+pub mod cgroup;
 pub mod config;
 pub mod container;
+pub mod dependency;
+pub mod environment;
+pub mod fault;
 pub mod handle;
+pub mod ipam;
+pub mod kube_client;
 pub mod log;
+pub mod metrics;
 pub mod node;
 pub mod plugin_watcher;
 pub mod pod;
 pub mod provider;
 pub mod resources;
+pub mod scratch;
 pub mod secret;
 pub mod state;
+pub mod stats;
 pub mod store;
+pub mod support_bundle;
 pub mod volume;
 
 pub use self::kubelet::Kubelet;
-pub use bootstrapping::bootstrap;
+pub use bootstrapping::identity as bootstrap_identity;
+pub use bootstrapping::{bootstrap, bootstrap_with_identity};
 
 #[cfg(feature = "derive")]
 #[allow(unused_imports)]
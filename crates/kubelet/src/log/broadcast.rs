@@ -0,0 +1,184 @@
+//! Live delivery of container output to log followers.
+//!
+//! Following a log by polling its file costs a read every half second for every follower, and
+//! delays every line by up to as much. Instead, a provider can write a container's log through a
+//! [`BroadcastWriter`], which also publishes everything written to the followers of the log. A
+//! follower reads what was written before it subscribed from the file, from where the
+//! [`Subscription`] says the output it receives starts, so no output is missed or repeated.
+//! Every write is published with where it starts in the file, so a follower that falls behind
+//! can catch up from the file and skip what it has already read when it next receives.
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use hyper::body::Bytes;
+use tokio::sync::broadcast;
+
+/// How many writes are kept for followers that fall behind, before they fall back to reading the
+/// file
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// The longest partial line kept for new followers, beyond which the line is split
+const MAX_PENDING_LINE: usize = 64 * 1024;
+
+/// The output of a container that is written to its log file, published to any followers.
+pub struct LogBroadcast {
+    sender: broadcast::Sender<Chunk>,
+    state: Mutex<WriteState>,
+}
+
+/// Where the log has been written up to.
+#[derive(Default)]
+struct WriteState {
+    /// The length of the log file up to the end of its last full line
+    line_start: u64,
+    /// What has been written since the last full line
+    pending: Vec<u8>,
+}
+
+impl Default for LogBroadcast {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        LogBroadcast {
+            sender,
+            state: Mutex::new(WriteState::default()),
+        }
+    }
+}
+
+impl LogBroadcast {
+    /// Wraps the writer of the log file, so what is written to it is also published. Every
+    /// writer of the file has to be wrapped, for followers to know where each write starts.
+    pub fn writer<W: Write>(self: &Arc<Self>, inner: W) -> BroadcastWriter<W> {
+        BroadcastWriter {
+            inner,
+            broadcast: self.clone(),
+        }
+    }
+
+    /// Subscribes to the output written from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let state = self.state.lock().unwrap();
+        Subscription {
+            receiver: self.sender.subscribe(),
+            history_len: state.line_start,
+            pending: state.pending.clone(),
+        }
+    }
+}
+
+/// A write to a log, and where in the log file it starts.
+#[derive(Clone, Debug)]
+pub(crate) struct Chunk {
+    pub(crate) offset: u64,
+    pub(crate) data: Bytes,
+}
+
+/// A follower's subscription to the output of a container.
+pub struct Subscription {
+    pub(crate) receiver: broadcast::Receiver<Chunk>,
+    /// How much of the log file to read before the published output, which ends with a full line
+    pub(crate) history_len: u64,
+    /// The start of the line the published output continues
+    pub(crate) pending: Vec<u8>,
+}
+
+/// A writer that publishes everything written to the inner writer to the followers of a log.
+///
+/// The channel to followers closes once every writer of the log has been dropped.
+pub struct BroadcastWriter<W: Write> {
+    inner: W,
+    broadcast: Arc<LogBroadcast>,
+}
+
+impl<W: Write> Write for BroadcastWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        // Writing and publishing under the lock keeps the order of the file and the channel the
+        // same when the log has more than one writer
+        let mut state = self.broadcast.state.lock().unwrap();
+        self.inner.write_all(data)?;
+        let offset = state.line_start + state.pending.len() as u64;
+        match data.iter().rposition(|b| *b == b'\n') {
+            Some(pos) => {
+                state.line_start += (state.pending.len() + pos + 1) as u64;
+                state.pending.clear();
+                state.pending.extend_from_slice(&data[pos + 1..]);
+            }
+            None => state.pending.extend_from_slice(data),
+        }
+        if state.pending.len() > MAX_PENDING_LINE {
+            state.line_start += state.pending.len() as u64;
+            state.pending.clear();
+        }
+        // Sending only fails when there are no followers
+        let _ = self.broadcast.sender.send(Chunk {
+            offset,
+            data: Bytes::copy_from_slice(data),
+        });
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log::{follow, LogFileFormat, Options, Sender};
+
+    #[tokio::test]
+    async fn test_follow_from_history() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let broadcast = Arc::new(LogBroadcast::default());
+        let mut writer = broadcast.writer(file.reopen().unwrap());
+        writer.write_all(b"one\ntw").unwrap();
+
+        let subscription = broadcast.subscribe();
+        assert_eq!(subscription.history_len, 4);
+        let (tx, body) = hyper::Body::channel();
+        let opts: Options = serde_json::from_value(serde_json::json!({"follow": true})).unwrap();
+        let follower = tokio::spawn(follow(
+            tokio::fs::File::from_std(file.reopen().unwrap()),
+            Sender::new(tx, opts),
+            LogFileFormat::Plain,
+            subscription,
+        ));
+        writer.write_all(b"o\nthree\n").unwrap();
+        // Followers stop once the container can't write any more output
+        drop(writer);
+        drop(broadcast);
+
+        let (followed, output) = tokio::join!(follower, hyper::body::to_bytes(body));
+        followed.unwrap().unwrap();
+        assert_eq!(output.unwrap(), "one\ntwo\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn test_follower_catches_up_after_falling_behind() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let broadcast = Arc::new(LogBroadcast::default());
+        let mut writer = broadcast.writer(file.reopen().unwrap());
+
+        let (tx, body) = hyper::Body::channel();
+        let opts: Options = serde_json::from_value(serde_json::json!({"follow": true})).unwrap();
+        let follower = tokio::spawn(follow(
+            tokio::fs::File::from_std(file.reopen().unwrap()),
+            Sender::new(tx, opts),
+            LogFileFormat::Plain,
+            broadcast.subscribe(),
+        ));
+        // The follower doesn't run until the test yields, so it misses most of these
+        let lines = 2 * CHANNEL_CAPACITY;
+        for n in 0..lines {
+            writer.write_all(format!("{}\n", n).as_bytes()).unwrap();
+        }
+        drop(writer);
+        drop(broadcast);
+
+        let (followed, output) = tokio::join!(follower, hyper::body::to_bytes(body));
+        followed.unwrap().unwrap();
+        let expected: String = (0..lines).map(|n| format!("{}\n", n)).collect();
+        assert_eq!(output.unwrap(), expected);
+    }
+}
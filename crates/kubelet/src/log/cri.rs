@@ -0,0 +1,271 @@
+//! Writing and reading of container logs in the CRI log format.
+//!
+//! This is the format container runtimes write logs in, and that node log collectors and the
+//! kubelet expect: every line of output becomes an entry of the form
+//! `<timestamp> <stream> <tag> <message>`, where the timestamp is RFC 3339 with nanosecond
+//! precision, the stream is `stdout` or `stderr`, and the tag is `F` for a full line or `P` for
+//! part of a line that continues in the next entry. Lines longer than [`MAX_ENTRY_LEN`] are split
+//! into partial entries, so that readers never have to buffer an unbounded line.
+use std::io::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// The most bytes of a line written in a single entry, the same as containerd's default
+pub const MAX_ENTRY_LEN: usize = 16 * 1024;
+
+/// The output stream a log entry was written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// A writer that encodes one stream of output as CRI log entries before writing them to the
+/// inner writer.
+///
+/// Input is buffered until a full line is available, and each entry is written with a single
+/// call, so the stdout and stderr of a container can share a file opened in append mode. A
+/// trailing line without a newline is written when the writer is dropped.
+pub struct CriWriter<W: Write> {
+    inner: W,
+    stream: Stream,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> CriWriter<W> {
+    /// Creates a new writer for the given stream, wrapping `inner`.
+    pub fn new(inner: W, stream: Stream) -> Self {
+        CriWriter {
+            inner,
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    fn write_entry(&mut self, message: &[u8], partial: bool) -> std::io::Result<()> {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut entry = Vec::with_capacity(timestamp.len() + message.len() + 12);
+        entry.extend_from_slice(timestamp.as_bytes());
+        entry.push(b' ');
+        entry.extend_from_slice(self.stream.as_str().as_bytes());
+        entry.extend_from_slice(if partial { b" P " } else { b" F " });
+        entry.extend_from_slice(message);
+        entry.push(b'\n');
+        self.inner.write_all(&entry)
+    }
+}
+
+impl<W: Write> Write for CriWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        loop {
+            match self
+                .buf
+                .iter()
+                .take(MAX_ENTRY_LEN + 1)
+                .position(|b| *b == b'\n')
+            {
+                Some(pos) => {
+                    let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                    self.write_entry(&line[..line.len() - 1], false)?;
+                }
+                None if self.buf.len() > MAX_ENTRY_LEN => {
+                    // Split on a character boundary, so that every entry is valid UTF-8
+                    let mut end = MAX_ENTRY_LEN;
+                    while end > 1 && self.buf[end] & 0xC0 == 0x80 {
+                        end -= 1;
+                    }
+                    let part: Vec<u8> = self.buf.drain(..end).collect();
+                    self.write_entry(&part, true)?;
+                }
+                None => break,
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for CriWriter<W> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let line = std::mem::take(&mut self.buf);
+            let _ = self.write_entry(&line, false);
+        }
+        let _ = self.inner.flush();
+    }
+}
+
+/// A single entry read from a CRI log file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry<'a> {
+    /// When the entry was written
+    pub timestamp: DateTime<Utc>,
+    /// The stream the entry was written to
+    pub stream: Stream,
+    /// Whether the line continues in the next entry of the stream
+    pub partial: bool,
+    /// The (part of the) line that was written
+    pub message: &'a str,
+}
+
+/// Parses a line of a CRI log file, returning `None` if it isn't a valid entry.
+pub fn parse(line: &str) -> Option<Entry<'_>> {
+    let mut fields = line.splitn(4, ' ');
+    let timestamp = DateTime::parse_from_rfc3339(fields.next()?)
+        .ok()?
+        .with_timezone(&Utc);
+    let stream = match fields.next()? {
+        "stdout" => Stream::Stdout,
+        "stderr" => Stream::Stderr,
+        _ => return None,
+    };
+    let partial = match fields.next()? {
+        "P" => true,
+        "F" => false,
+        _ => return None,
+    };
+    Some(Entry {
+        timestamp,
+        stream,
+        partial,
+        message: fields.next().unwrap_or_default(),
+    })
+}
+
+/// Turns the entries of a CRI log file back into the lines of output, as returned by the log
+/// endpoint.
+pub(crate) struct Decoder {
+    timestamps: bool,
+    since: Option<DateTime<Utc>>,
+    /// The start of a line that was split into partial entries, for each stream
+    partial: [Option<(DateTime<Utc>, String)>; 2],
+}
+
+impl Decoder {
+    /// Creates a decoder for lines written at or after `since`, prefixing them with the time
+    /// they were written if `timestamps` is set.
+    pub(crate) fn new(timestamps: bool, since: Option<DateTime<Utc>>) -> Self {
+        Decoder {
+            timestamps,
+            since,
+            partial: [None, None],
+        }
+    }
+
+    /// Decodes an entry, returning the line of output it completes, if any. Lines that aren't
+    /// valid entries are returned unchanged.
+    pub(crate) fn decode(&mut self, line: String) -> Option<String> {
+        let entry = match parse(&line) {
+            Some(entry) => entry,
+            None => return Some(line),
+        };
+        let partial = &mut self.partial[entry.stream as usize];
+        let (timestamp, message) = match partial.take() {
+            Some((timestamp, mut start)) => {
+                start.push_str(entry.message);
+                (timestamp, start)
+            }
+            None => (entry.timestamp, entry.message.to_owned()),
+        };
+        if entry.partial {
+            *partial = Some((timestamp, message));
+            return None;
+        }
+        if self.since.map(|since| timestamp < since).unwrap_or(false) {
+            return None;
+        }
+        if self.timestamps {
+            Some(format!(
+                "{} {}",
+                timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+                message
+            ))
+        } else {
+            Some(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_writes_entries() {
+        let mut out = Vec::new();
+        {
+            let mut writer = CriWriter::new(&mut out, Stream::Stderr);
+            writer.write_all(b"first\nsec").unwrap();
+            writer.write_all(b"ond\nleftover").unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        let entries: Vec<Entry> = out.lines().map(|l| parse(l).unwrap()).collect();
+        let messages: Vec<(&str, bool)> = entries.iter().map(|e| (e.message, e.partial)).collect();
+        assert_eq!(
+            messages,
+            vec![("first", false), ("second", false), ("leftover", false)]
+        );
+        assert!(entries.iter().all(|e| e.stream == Stream::Stderr));
+    }
+
+    #[test]
+    fn test_splits_long_lines() {
+        let line = "x".repeat(MAX_ENTRY_LEN * 2 + 10);
+        let mut out = Vec::new();
+        {
+            let mut writer = CriWriter::new(&mut out, Stream::Stdout);
+            writer.write_all(line.as_bytes()).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        let tags: Vec<bool> = out.lines().map(|l| parse(l).unwrap().partial).collect();
+        assert_eq!(tags, vec![true, true, false]);
+
+        let mut decoder = Decoder::new(false, None);
+        let decoded: Vec<String> = out
+            .lines()
+            .filter_map(|l| decoder.decode(l.to_owned()))
+            .collect();
+        assert_eq!(decoded, vec![line]);
+    }
+
+    #[test]
+    fn test_decodes_entries() {
+        let lines = [
+            "2021-07-01T00:00:00.000000001Z stdout F before",
+            "2021-07-01T00:00:02.5Z stdout P hello ",
+            "2021-07-01T00:00:03Z stderr F oops",
+            "2021-07-01T00:00:04Z stdout F world",
+            "not an entry",
+        ];
+        let since = "2021-07-01T00:00:01Z".parse().unwrap();
+
+        let mut decoder = Decoder::new(false, Some(since));
+        let decoded: Vec<String> = lines
+            .iter()
+            .filter_map(|l| decoder.decode((*l).to_owned()))
+            .collect();
+        assert_eq!(decoded, vec!["oops", "hello world", "not an entry"]);
+
+        let mut decoder = Decoder::new(true, None);
+        assert_eq!(
+            decoder.decode(lines[0].to_owned()).unwrap(),
+            "2021-07-01T00:00:00.000000001Z before"
+        );
+    }
+}
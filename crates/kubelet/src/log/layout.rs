@@ -0,0 +1,107 @@
+//! The directory layout the kubelet writes container logs in.
+//!
+//! Node log collectors such as fluent-bit and promtail find container logs by this layout, with
+//! the pod's identity in the directory names: `<root>/<namespace>_<name>_<uid>/<container>/<N>.log`,
+//! where `N` counts the runs of the container. As with the kubelet, the log of the previous run of
+//! a container is kept alongside the current one, and the logs of a pod are removed along with it.
+use std::path::{Path, PathBuf};
+
+use crate::pod::dirs::join_name;
+use crate::pod::Pod;
+
+/// The directory of a pod's logs under the given root.
+pub fn pod_log_dir(root: &Path, pod: &Pod) -> PathBuf {
+    root.join(pod_log_dir_name(pod))
+}
+
+pub(crate) fn pod_log_dir_name(pod: &Pod) -> String {
+    format!("{}_{}_{}", pod.namespace(), pod.name(), pod.pod_uid())
+}
+
+/// Creates the log file for a new run of a container, returning its path. Logs of runs before
+/// the previous one are removed.
+pub async fn create_container_log(
+    root: &Path,
+    pod: &Pod,
+    container: &str,
+) -> anyhow::Result<PathBuf> {
+    let dir = join_name(&join_name(root, &pod_log_dir_name(pod))?, container)?;
+    create_container_log_in(&dir).await
+}
+
+/// Creates the log file for a new run of the container whose log directory is given.
+pub(crate) async fn create_container_log_in(dir: &Path) -> anyhow::Result<PathBuf> {
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let mut runs = Vec::new();
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(run) = run_number(&entry.path()) {
+            runs.push(run);
+        }
+    }
+    runs.sort_unstable();
+    let next = runs.last().map(|run| run + 1).unwrap_or(0);
+    for run in runs.iter().rev().skip(1) {
+        tokio::fs::remove_file(dir.join(format!("{}.log", run))).await?;
+    }
+
+    let path = dir.join(format!("{}.log", next));
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    Ok(path)
+}
+
+/// Removes all the logs in a pod's log directory, as given by [`pod_log_dir`].
+pub async fn remove_pod_logs(pod_log_dir: &Path) -> anyhow::Result<()> {
+    match tokio::fs::remove_dir_all(pod_log_dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn run_number(path: &Path) -> Option<u32> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    #[tokio::test]
+    async fn test_container_log_runs() {
+        let root = tempfile::tempdir().unwrap();
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "default", "uid": "1234"}
+            }))
+            .unwrap(),
+        );
+        let dir = root.path().join("default_web_1234").join("server");
+
+        for expected in &["0.log", "1.log", "2.log"] {
+            let path = create_container_log(root.path(), &pod, "server")
+                .await
+                .unwrap();
+            assert_eq!(path, dir.join(expected));
+        }
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["1.log", "2.log"]);
+
+        let pod_dir = pod_log_dir(root.path(), &pod);
+        remove_pod_logs(&pod_dir).await.unwrap();
+        assert!(!pod_dir.exists());
+        remove_pod_logs(&pod_dir).await.unwrap();
+    }
+}
@@ -2,10 +2,18 @@
 use anyhow::bail;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use std::io::SeekFrom;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, error};
 
+use broadcast::Subscription;
+
+pub mod broadcast;
+pub mod cri;
+pub mod layout;
+
 /// Possible errors sending log data.
 #[derive(Debug)]
 pub enum SendError {
@@ -129,9 +137,55 @@ impl Sender {
     }
 }
 
+/// The format a container's log file is written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFileFormat {
+    /// The output of the container, as it was written
+    Plain,
+    /// CRI log entries, as written by [`cri::CriWriter`]
+    Cri,
+}
+
+impl Default for LogFileFormat {
+    fn default() -> Self {
+        LogFileFormat::Plain
+    }
+}
+
+/// Turns the lines of a log file into the lines sent to the client.
+enum LineDecoder {
+    Plain,
+    Cri(cri::Decoder),
+}
+
+impl LineDecoder {
+    fn new(format: LogFileFormat, sender: &Sender) -> Self {
+        match format {
+            LogFileFormat::Plain => LineDecoder::Plain,
+            LogFileFormat::Cri => {
+                let since = sender.since_time().or_else(|| {
+                    sender
+                        .since()
+                        .and_then(|since| chrono::Duration::from_std(since).ok())
+                        .map(|since| Utc::now() - since)
+                });
+                LineDecoder::Cri(cri::Decoder::new(sender.timestamps(), since))
+            }
+        }
+    }
+
+    fn decode(&mut self, line: String) -> Option<String> {
+        match self {
+            LineDecoder::Plain => Some(line),
+            LineDecoder::Cri(decoder) => decoder.decode(line),
+        }
+    }
+}
+
 /// Stream last `n` lines.
 async fn tail<R: AsyncRead + std::marker::Unpin>(
     lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    decoder: &mut LineDecoder,
     sender: &mut Sender,
     n: usize,
 ) -> Result<(), SendError> {
@@ -147,6 +201,10 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
+        let line = match decoder.decode(line) {
+            Some(line) => line,
+            None => continue,
+        };
         if line_buf.len() == n {
             line_buf.pop_front();
         }
@@ -163,9 +221,10 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
 /// Stream log to end.
 async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
     lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
+    decoder: &mut LineDecoder,
     sender: &mut Sender,
 ) -> Result<(), SendError> {
-    while let Some(mut line) = match lines.next_line().await {
+    while let Some(line) = match lines.next_line().await {
         Ok(line) => line,
         Err(e) => {
             error!(error = %e, "Error reading from log");
@@ -175,6 +234,10 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
+        let mut line = match decoder.decode(line) {
+            Some(line) => line,
+            None => continue,
+        };
         line.push('\n');
         sender.send(line).await?;
     }
@@ -183,20 +246,32 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
 
 /// Future that streams logs from provided `AsyncRead` to provided `Sender`.
 pub async fn stream<R: AsyncRead + std::marker::Unpin>(
+    handle: R,
+    sender: Sender,
+) -> anyhow::Result<()> {
+    stream_formatted(handle, sender, LogFileFormat::Plain).await
+}
+
+/// Future that streams logs in the given format from provided `AsyncRead` to provided `Sender`.
+/// Logs in the CRI format are sent without their entry prefixes, honoring the `timestamps`,
+/// `sinceSeconds` and `sinceTime` options of the request.
+pub async fn stream_formatted<R: AsyncRead + std::marker::Unpin>(
     handle: R,
     mut sender: Sender,
+    format: LogFileFormat,
 ) -> anyhow::Result<()> {
     let buf = tokio::io::BufReader::new(handle);
     let mut lines = buf.lines();
+    let mut decoder = LineDecoder::new(format, &sender);
 
     if let Some(n) = sender.tail() {
-        match tail(&mut lines, &mut sender, n).await {
+        match tail(&mut lines, &mut decoder, &mut sender, n).await {
             Ok(_) => (),
             Err(SendError::ChannelClosed) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
         }
     } else {
-        match stream_to_end(&mut lines, &mut sender).await {
+        match stream_to_end(&mut lines, &mut decoder, &mut sender).await {
             Ok(_) => (),
             Err(SendError::ChannelClosed) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
@@ -205,7 +280,7 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 
     if sender.follow() {
         loop {
-            match stream_to_end(&mut lines, &mut sender).await {
+            match stream_to_end(&mut lines, &mut decoder, &mut sender).await {
                 Ok(_) => (),
                 Err(SendError::ChannelClosed) => return Ok(()),
                 Err(SendError::Abnormal(e)) => bail!(e),
@@ -218,6 +293,102 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     Ok(())
 }
 
+/// Future that streams logs in the given format from provided `AsyncRead` to provided `Sender`,
+/// and then follows the output published to the given subscription rather than polling the
+/// file. The stream ends once the output is closed, which is when its container exits.
+pub async fn follow<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: R,
+    mut sender: Sender,
+    format: LogFileFormat,
+    subscription: Subscription,
+) -> anyhow::Result<()> {
+    match follow_subscription(handle, &mut sender, format, subscription).await {
+        Ok(_) | Err(SendError::ChannelClosed) => Ok(()),
+        Err(SendError::Abnormal(e)) => bail!(e),
+    }
+}
+
+async fn follow_subscription<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
+    handle: R,
+    sender: &mut Sender,
+    format: LogFileFormat,
+    subscription: Subscription,
+) -> Result<(), SendError> {
+    let Subscription {
+        mut receiver,
+        history_len,
+        pending,
+    } = subscription;
+    // The published output starts where the history ends
+    let mut lines = tokio::io::BufReader::new(handle.take(history_len)).lines();
+    let mut decoder = LineDecoder::new(format, sender);
+    match sender.tail() {
+        Some(n) => tail(&mut lines, &mut decoder, sender, n).await?,
+        None => stream_to_end(&mut lines, &mut decoder, sender).await?,
+    }
+
+    // The rest of the file is read again if the follower falls behind the published output
+    let mut handle = lines.into_inner().into_inner().into_inner();
+    // How much of the file has been sent, up to the end of the last full line
+    let mut position = history_len;
+    let mut buf = pending;
+    loop {
+        match receiver.recv().await {
+            Ok(chunk) => {
+                // Output read from the file after falling behind is published again as well
+                let received = position + buf.len() as u64;
+                let end = chunk.offset + chunk.data.len() as u64;
+                if end <= received {
+                    continue;
+                }
+                let skip = received.saturating_sub(chunk.offset) as usize;
+                buf.extend_from_slice(&chunk.data[skip..]);
+                send_lines(&mut buf, &mut position, &mut decoder, sender).await?;
+            }
+            Err(RecvError::Closed) => {
+                if !buf.is_empty() {
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    if let Some(line) = decoder.decode(line) {
+                        sender.send(line).await?;
+                    }
+                }
+                return Ok(());
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                // Pick up from the file what was missed, then carry on with the published output
+                debug!(
+                    skipped,
+                    "Log follower fell behind, catching up from the file"
+                );
+                handle.seek(SeekFrom::Start(position)).await?;
+                buf.clear();
+                handle.read_to_end(&mut buf).await?;
+                send_lines(&mut buf, &mut position, &mut decoder, sender).await?;
+            }
+        }
+    }
+}
+
+/// Sends the full lines at the start of `buf`, advancing `position` past them and leaving any
+/// partial line in `buf`.
+async fn send_lines(
+    buf: &mut Vec<u8>,
+    position: &mut u64,
+    decoder: &mut LineDecoder,
+    sender: &mut Sender,
+) -> Result<(), SendError> {
+    while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buf.drain(..=pos).collect();
+        *position += line.len() as u64;
+        let line = String::from_utf8_lossy(&line[..line.len() - 1]).into_owned();
+        if let Some(mut line) = decoder.decode(line) {
+            line.push('\n');
+            sender.send(line).await?;
+        }
+    }
+    Ok(())
+}
+
 // TODO: Both providers make a handle containing a tempfile. If this is a common pattern,
 // it might make sense to provide that implementation here. This would add `tempfile` as a
 // dependency of `kubelet`.
@@ -225,4 +396,20 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// The format the logs are written in.
+    fn format(&self) -> LogFileFormat {
+        LogFileFormat::Plain
+    }
+
+    /// Subscribes to the output that is published as it is written, if the provider publishes
+    /// it and the output can still be written to.
+    fn subscribe(&self) -> Option<Subscription> {
+        None
+    }
+
+    /// The number of bytes of logs the container has written, if known.
+    fn log_size(&self) -> Option<u64> {
+        None
+    }
 }
@@ -0,0 +1,74 @@
+//! Prometheus metrics exposed by the Kubelet on its `/metrics` endpoint.
+//!
+//! Providers can register their own metrics with the [`registry`] so that they are served
+//! alongside the Kubelet's.
+use prometheus::{Encoder, IntGaugeVec, Opts, Registry, TextEncoder};
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Registry = Registry::new_custom(Some("krustlet".to_owned()), None)
+        .expect("metrics registry should be valid");
+
+    /// The pull quota a registry reported for the current window.
+    pub(crate) static ref REGISTRY_RATE_LIMIT: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "registry_rate_limit",
+            "Number of requests the registry allows in each rate limit window"
+        ),
+        &["registry"]
+    ));
+
+    /// The pull quota a registry reported as remaining in the current window.
+    pub(crate) static ref REGISTRY_RATE_LIMIT_REMAINING: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "registry_rate_limit_remaining",
+            "Number of requests the registry allows in the remainder of the current rate limit window"
+        ),
+        &["registry"]
+    ));
+
+    /// The bytes of cached modules attributed to each tenant of a scoped module store.
+    pub(crate) static ref STORE_TENANT_USAGE: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "store_tenant_usage_bytes",
+            "Bytes of cached modules attributed to the tenant of the module store"
+        ),
+        &["tenant"]
+    ));
+}
+
+/// The registry the Kubelet's metrics are registered with.
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+pub(crate) fn register<M: prometheus::core::Collector + Clone + 'static>(
+    metric: prometheus::Result<M>,
+) -> M {
+    let metric = metric.expect("metric definition should be valid");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric should only be registered once");
+    metric
+}
+
+/// Renders all registered metrics in the Prometheus text format.
+pub(crate) fn render() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        REGISTRY_RATE_LIMIT_REMAINING
+            .with_label_values(&["example.com"])
+            .set(42);
+        let rendered = render().unwrap();
+        assert!(rendered
+            .contains("krustlet_registry_rate_limit_remaining{registry=\"example.com\"} 42"));
+    }
+}
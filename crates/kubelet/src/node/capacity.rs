@@ -0,0 +1,373 @@
+//! Detection of the resources the node has to offer pods.
+//!
+//! On small edge devices the Kubelet is often confined to less than the whole machine, whether by
+//! the cgroup of a container or service unit, or by a CPU set, so the CPU and memory the node
+//! reports are the least of what the hardware has and what those limits allow. Memory is read from
+//! `/proc/meminfo` as a 64-bit count of kibibytes, which is right on 32-bit systems too, where the
+//! `sysinfo` counts overflow and an unlimited cgroup v1 reports a limit of just under 2GiB, which
+//! is recognised as unlimited even on machines with more memory than that. Ephemeral storage is
+//! the size of the filesystem holding the data directory.
+//!
+//! Whatever can't be detected falls back to a modest default, and the node's configuration can
+//! set the quantity of any resource outright, which takes precedence over detection.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// The CPU reported when it can't be detected, in millicores
+const DEFAULT_CPU_MILLIS: u64 = 4000;
+/// The memory reported when it can't be detected, in bytes
+const DEFAULT_MEMORY: u64 = 4_129_587_200;
+/// The ephemeral storage reported when it can't be detected, in bytes
+const DEFAULT_EPHEMERAL_STORAGE: u64 = 62_725_623_808;
+/// The memory limit a cgroup v1 without one reports on a 32-bit kernel: the largest page aligned
+/// signed 32-bit count of bytes
+const V1_UNLIMITED_MEMORY_32BIT: u64 = 0x7FFF_F000;
+
+/// The quantities of every resource the node has, keyed by resource name, which it reports as both
+/// its capacity and what is allocatable to pods.
+pub(crate) fn node_capacity(config: &Config) -> BTreeMap<String, String> {
+    let system = System::new(PathBuf::from("/"));
+    let mut capacity = BTreeMap::new();
+    capacity.insert("cpu".to_owned(), cpu_quantity(system.cpu_millis()));
+    capacity.insert(
+        "memory".to_owned(),
+        kibibytes(system.memory().unwrap_or_else(|| {
+            warn!("Unable to detect node memory, reporting the default");
+            DEFAULT_MEMORY
+        })),
+    );
+    capacity.insert(
+        "ephemeral-storage".to_owned(),
+        kibibytes(filesystem_size(&config.data_dir).unwrap_or_else(|| {
+            warn!("Unable to detect node ephemeral storage, reporting the default");
+            DEFAULT_EPHEMERAL_STORAGE
+        })),
+    );
+    capacity.insert("hugepages-1Gi".to_owned(), "0".to_owned());
+    capacity.insert("hugepages-2Mi".to_owned(), "0".to_owned());
+    capacity.insert("pods".to_owned(), config.max_pods.to_string());
+    for (resource, quantity) in &config.node_capacity {
+        debug!(%resource, %quantity, "Using configured node capacity");
+        capacity.insert(resource.clone(), quantity.clone());
+    }
+    capacity
+}
+
+/// The files the limits on the Kubelet are read from, beneath a root that tests can replace.
+struct System {
+    root: PathBuf,
+}
+
+impl System {
+    fn new(root: PathBuf) -> Self {
+        System { root }
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(self.root.join(path.trim_start_matches('/'))).ok()
+    }
+
+    /// The CPU the Kubelet may use, in millicores: the CPUs it may be scheduled on, limited by
+    /// the CPU quota of its cgroup and those above it.
+    fn cpu_millis(&self) -> u64 {
+        let cpus = self
+            .read("proc/self/status")
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Cpus_allowed_list:"))
+                    .and_then(count_cpus)
+            })
+            .or_else(|| {
+                self.read("sys/devices/system/cpu/online")
+                    .and_then(|online| count_cpus(&online))
+            });
+        let online = match cpus {
+            Some(cpus) => cpus * 1000,
+            None => {
+                warn!("Unable to detect node CPUs, reporting the default");
+                DEFAULT_CPU_MILLIS
+            }
+        };
+        self.cgroup_limits(|cgroup| match cgroup {
+            Cgroup::V2(dir) => self
+                .read(&format!("{}/cpu.max", dir))
+                .and_then(|max| parse_cpu_max(&max)),
+            Cgroup::V1Cpu(dir) => {
+                let quota = self.read(&format!("{}/cpu.cfs_quota_us", dir))?;
+                let period = self.read(&format!("{}/cpu.cfs_period_us", dir))?;
+                parse_cpu_max(&format!("{} {}", quota.trim(), period.trim()))
+            }
+            Cgroup::V1Memory(_) => None,
+        })
+        .map_or(online, |limit| limit.min(online))
+        // A node always has some CPU, however tight its quota
+        .max(1)
+    }
+
+    /// The memory the Kubelet may use, in bytes: the machine's memory, limited by the memory limit
+    /// of its cgroup and those above it.
+    fn memory(&self) -> Option<u64> {
+        let total = self.read("proc/meminfo").and_then(|m| parse_meminfo(&m))?;
+        let limit = self.cgroup_limits(|cgroup| match cgroup {
+            Cgroup::V2(dir) => self
+                .read(&format!("{}/memory.max", dir))
+                .and_then(|limit| limit.trim().parse().ok()),
+            Cgroup::V1Memory(dir) => self
+                .read(&format!("{}/memory.limit_in_bytes", dir))
+                .and_then(|limit| limit.trim().parse().ok())
+                .filter(|limit| *limit != V1_UNLIMITED_MEMORY_32BIT),
+            Cgroup::V1Cpu(_) => None,
+        });
+        // Limits above the machine's memory, such as the "unlimited" of a cgroup, don't apply
+        Some(limit.map_or(total, |limit| limit.min(total)))
+    }
+
+    /// The tightest of a limit set on the Kubelet's cgroups, from its own up to the root.
+    fn cgroup_limits(&self, limit: impl Fn(Cgroup) -> Option<u64>) -> Option<u64> {
+        let membership = self.read("proc/self/cgroup")?;
+        let (v2, cpu, memory) = parse_cgroup_membership(&membership);
+        let mut limits = Vec::new();
+        if let Some(path) = v2 {
+            limits.extend(
+                ancestors(path).filter_map(|p| limit(Cgroup::V2(format!("sys/fs/cgroup{}", p)))),
+            );
+        }
+        // Containers on cgroup v1 see their own cgroup at the root of the hierarchy, rather than at
+        // the path in their membership, which walking up to the root also covers. Each controller
+        // has a hierarchy of its own, in which the Kubelet may be in a different cgroup
+        if let Some(path) = cpu {
+            limits.extend(
+                ancestors(path).filter_map(|p| {
+                    limit(Cgroup::V1Cpu(format!("sys/fs/cgroup/cpu,cpuacct{}", p)))
+                }),
+            );
+        }
+        if let Some(path) = memory {
+            limits.extend(
+                ancestors(path)
+                    .filter_map(|p| limit(Cgroup::V1Memory(format!("sys/fs/cgroup/memory{}", p)))),
+            );
+        }
+        limits.into_iter().min()
+    }
+}
+
+/// The directory of a cgroup, relative to the root, in whichever hierarchy it is in.
+enum Cgroup {
+    V2(String),
+    V1Cpu(String),
+    V1Memory(String),
+}
+
+/// The Kubelet's cgroup in the unified hierarchy and in the v1 CPU and memory hierarchies, from
+/// the contents of `/proc/self/cgroup`.
+fn parse_cgroup_membership(membership: &str) -> (Option<&str>, Option<&str>, Option<&str>) {
+    let (mut v2, mut cpu, mut memory) = (None, None, None);
+    for line in membership.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue,
+        };
+        if id == "0" && controllers.is_empty() {
+            v2 = Some(path);
+        }
+        for controller in controllers.split(',') {
+            match controller {
+                "cpu" => cpu = Some(path),
+                "memory" => memory = Some(path),
+                _ => (),
+            }
+        }
+    }
+    (v2, cpu, memory)
+}
+
+/// A cgroup path and every path above it, such as `/a/b`, `/a` and the root, which is empty.
+fn ancestors(path: &str) -> impl Iterator<Item = String> + '_ {
+    let path = path.trim_end_matches('/');
+    std::iter::successors(Some(path), |p| p.rfind('/').map(|i| &p[..i])).map(|p| p.to_owned())
+}
+
+/// The number of CPUs in a list such as `0-3,6`.
+fn count_cpus(list: &str) -> Option<u64> {
+    list.trim().split(',').try_fold(0, |count, range| {
+        let mut bounds = range.trim().splitn(2, '-');
+        let start: u64 = bounds.next()?.parse().ok()?;
+        let end: u64 = match bounds.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        Some(count + end.checked_sub(start)? + 1)
+    })
+}
+
+/// The CPU limit in millicores of a quota and period in microseconds such as `200000 100000`, or
+/// `None` if it is unlimited.
+fn parse_cpu_max(max: &str) -> Option<u64> {
+    let mut fields = max.split_whitespace();
+    let quota: u64 = fields.next()?.parse().ok()?;
+    let period: u64 = fields.next()?.parse().ok()?;
+    if period == 0 {
+        return None;
+    }
+    Some(quota * 1000 / period)
+}
+
+/// The total memory in bytes from the contents of `/proc/meminfo`.
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let kib: u64 = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    kib.checked_mul(1024)
+}
+
+#[cfg(target_family = "unix")]
+fn filesystem_size(dir: &Path) -> Option<u64> {
+    // The data directory may not have been created yet, so use the nearest directory that has
+    let existing = dir.ancestors().find(|d| d.exists())?;
+    let stats = nix::sys::statvfs::statvfs(existing).ok()?;
+    // The block counts are only 32 bits wide on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, block_size) = (stats.blocks() as u64, stats.fragment_size() as u64);
+    blocks.checked_mul(block_size)
+}
+
+#[cfg(target_family = "windows")]
+fn filesystem_size(_dir: &Path) -> Option<u64> {
+    None
+}
+
+fn cpu_quantity(millis: u64) -> String {
+    if millis % 1000 == 0 {
+        (millis / 1000).to_string()
+    } else {
+        format!("{}m", millis)
+    }
+}
+
+fn kibibytes(bytes: u64) -> String {
+    format!("{}Ki", bytes / 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(count_cpus("0-3,6\n"), Some(5));
+        assert_eq!(count_cpus("0"), Some(1));
+        assert_eq!(count_cpus("3-1"), None);
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1500));
+        assert_eq!(parse_cpu_max("max 100000"), None);
+        assert_eq!(parse_cpu_max("-1 100000"), None);
+        // More memory than a 32-bit system can count in bytes in a word
+        assert_eq!(
+            parse_meminfo("MemTotal:        8054880 kB\nMemFree: 1 kB\n"),
+            Some(8054880 * 1024)
+        );
+        assert_eq!(
+            ancestors("/system.slice/krustlet.service").collect::<Vec<_>>(),
+            vec!["/system.slice/krustlet.service", "/system.slice", ""]
+        );
+        assert_eq!(cpu_quantity(2000), "2");
+        assert_eq!(cpu_quantity(1500), "1500m");
+    }
+
+    #[test]
+    fn test_cgroup_limits_constrain_hardware() {
+        let root = tempfile::tempdir().unwrap();
+        let system = System::new(root.path().to_owned());
+        write(root.path(), "proc/self/status", "Cpus_allowed_list:\t0-3\n");
+        write(root.path(), "proc/meminfo", "MemTotal: 4000000 kB\n");
+        write(root.path(), "proc/self/cgroup", "0::/edge/krustlet\n");
+        assert_eq!(system.cpu_millis(), 4000);
+        assert_eq!(system.memory(), Some(4_096_000_000));
+
+        write(root.path(), "sys/fs/cgroup/edge/cpu.max", "50000 100000\n");
+        write(
+            root.path(),
+            "sys/fs/cgroup/edge/krustlet/cpu.max",
+            "max 100000\n",
+        );
+        write(
+            root.path(),
+            "sys/fs/cgroup/edge/krustlet/memory.max",
+            "536870912\n",
+        );
+        assert_eq!(system.cpu_millis(), 500);
+        assert_eq!(system.memory(), Some(536_870_912));
+
+        // An unlimited cgroup v1 on a 32-bit kernel
+        write(
+            root.path(),
+            "proc/self/cgroup",
+            "4:cpu,cpuacct:/docker/abc\n3:memory:/docker/abc\n",
+        );
+        write(
+            root.path(),
+            "sys/fs/cgroup/memory/memory.limit_in_bytes",
+            "2147479552\n",
+        );
+        write(root.path(), "proc/meminfo", "MemTotal: 1000000 kB\n");
+        assert_eq!(system.cpu_millis(), 4000);
+        assert_eq!(system.memory(), Some(1_024_000_000));
+
+        // The same on a machine with more memory than the sentinel
+        write(root.path(), "proc/meminfo", "MemTotal: 4000000 kB\n");
+        assert_eq!(system.memory(), Some(4_096_000_000));
+    }
+
+    #[test]
+    fn test_cgroup_v1_controllers_use_their_own_cgroups() {
+        let root = tempfile::tempdir().unwrap();
+        let system = System::new(root.path().to_owned());
+        write(root.path(), "proc/self/status", "Cpus_allowed_list:\t0-3\n");
+        write(root.path(), "proc/meminfo", "MemTotal: 4000000 kB\n");
+        write(
+            root.path(),
+            "proc/self/cgroup",
+            "4:cpu,cpuacct:/cpu-slice/krustlet\n3:memory:/memory-slice/krustlet\n",
+        );
+        write(
+            root.path(),
+            "sys/fs/cgroup/cpu,cpuacct/cpu-slice/cpu.cfs_quota_us",
+            "150000\n",
+        );
+        write(
+            root.path(),
+            "sys/fs/cgroup/cpu,cpuacct/cpu-slice/cpu.cfs_period_us",
+            "100000\n",
+        );
+        write(
+            root.path(),
+            "sys/fs/cgroup/memory/memory-slice/memory.limit_in_bytes",
+            "536870912\n",
+        );
+        // Limits in the other controller's cgroup path don't apply
+        write(
+            root.path(),
+            "sys/fs/cgroup/memory/cpu-slice/memory.limit_in_bytes",
+            "1048576\n",
+        );
+        assert_eq!(system.cpu_millis(), 1500);
+        assert_eq!(system.memory(), Some(536_870_912));
+    }
+}
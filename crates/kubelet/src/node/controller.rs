@@ -0,0 +1,358 @@
+//! Pluggable management of the node lifecycle.
+//!
+//! The Kubelet hands registering the node and keeping it alive to a [`NodeController`]. The
+//! default, [`KubeNodeController`], registers a Node and its lease with the cluster the Kubelet
+//! runs pods for. Embedders that need something else, such as registering a virtual node in
+//! another control plane or running pods for a node that is managed elsewhere, can provide their
+//! own controller through [`crate::Kubelet::with_node_controller`] and still reuse all of the pod
+//! machinery.
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::config::Config;
+use crate::provider::Provider;
+
+/// How often the node's lease and status are renewed by default
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Manages the lifecycle of the node the Kubelet runs pods for.
+#[async_trait]
+pub trait NodeController: Send + Sync {
+    /// Registers the node. This is called once when the Kubelet starts, before any pods are
+    /// handled. Returning an error stops the Kubelet from starting.
+    async fn register(&self, client: &kube::Client, config: &Config) -> anyhow::Result<()>;
+
+    /// Tells the control plane that the node is still alive, for example by renewing its lease
+    /// and status. This is called every [`NodeController::heartbeat_interval`] until the Kubelet
+    /// shuts down. Returning an error starts a graceful shutdown of the Kubelet.
+    async fn heartbeat(&self, client: &kube::Client, config: &Config) -> anyhow::Result<()>;
+
+    /// How long to wait between heartbeats.
+    fn heartbeat_interval(&self) -> Duration {
+        DEFAULT_HEARTBEAT_INTERVAL
+    }
+}
+
+/// The default `NodeController`, which registers a Node and its lease with the Kubernetes API,
+/// letting the provider customize the Node, and keeps both up to date. It refuses to register a
+/// node whose lease another process is still renewing, and stops if one takes the lease over.
+pub struct KubeNodeController<P> {
+    provider: Arc<P>,
+}
+
+impl<P: Provider> KubeNodeController<P> {
+    /// Creates a controller that lets the given provider customize the Node it registers.
+    pub fn new(provider: Arc<P>) -> Self {
+        KubeNodeController { provider }
+    }
+}
+
+#[async_trait]
+impl<P: Provider> NodeController for KubeNodeController<P> {
+    async fn register(&self, client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+        // Refuse to run a node another process is running, before touching any of its objects
+        super::lease::acquire(client, config).await?;
+        // If the node already exists, this will exit
+        super::create(client, config, self.provider.clone()).await;
+        Ok(())
+    }
+
+    async fn heartbeat(&self, client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+        let lease_version = super::lease::ensure_held(client, config).await?;
+        super::update(
+            client,
+            &config.node_name,
+            lease_version.as_deref(),
+            &*config.environment.clock,
+        )
+        .await;
+        Ok(())
+    }
+}
+
+/// A `NodeController` that neither registers nor renews anything, for nodes whose lifecycle is
+/// managed outside of the Kubelet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnmanagedNodeController;
+
+#[async_trait]
+impl NodeController for UnmanagedNodeController {
+    async fn register(&self, _client: &kube::Client, _config: &Config) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn heartbeat(&self, _client: &kube::Client, _config: &Config) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn heartbeat_interval(&self) -> Duration {
+        // There is nothing to renew, so don't wake up needlessly
+        Duration::from_secs(u64::from(u32::MAX))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::{Pod, Status};
+    use crate::provider::{DevicePluginSupport, PluginSupport, VolumeSupport};
+    use futures::pin_mut;
+    use http::{Method, Request as HttpRequest, Response as HttpResponse};
+    use hyper::Body;
+    use krator::ObjectState;
+    use std::sync::Mutex;
+    use tower_test::mock;
+
+    struct MockProvider;
+
+    struct ProviderState;
+
+    impl VolumeSupport for ProviderState {}
+    impl PluginSupport for ProviderState {}
+    impl DevicePluginSupport for ProviderState {}
+
+    struct PodState;
+
+    #[async_trait]
+    impl ObjectState for PodState {
+        type Manifest = Pod;
+        type Status = Status;
+        type SharedState = ProviderState;
+        async fn async_drop(self, _provider_state: &mut ProviderState) {}
+    }
+
+    #[async_trait]
+    impl Provider for MockProvider {
+        type ProviderState = ProviderState;
+        type InitialState = crate::pod::state::Stub;
+        type TerminatedState = crate::pod::state::Stub;
+        type PodState = PodState;
+
+        const ARCH: &'static str = "mock";
+
+        async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+            Ok(PodState)
+        }
+
+        fn provider_state(&self) -> krator::SharedState<ProviderState> {
+            Arc::new(tokio::sync::RwLock::new(ProviderState))
+        }
+
+        async fn logs(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            _sender: crate::log::Sender,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Requests = Arc<Mutex<Vec<(Method, String)>>>;
+
+    /// A client whose requests are recorded and answered with the lease or node they are for
+    fn mock_client(lease: serde_json::Value) -> (kube::Client, Requests) {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let requests: Requests = Default::default();
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            pin_mut!(handle);
+            while let Some((request, send)) = handle.next_request().await {
+                let path = request.uri().path().to_owned();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((request.method().clone(), path.clone()));
+                let body = if path.contains("/leases/") {
+                    lease.clone()
+                } else if path.contains("/events") {
+                    serde_json::json!({
+                        "apiVersion": "v1",
+                        "kind": "Event",
+                        "metadata": {"name": "event", "namespace": "default"},
+                        "involvedObject": {},
+                    })
+                } else {
+                    serde_json::json!({
+                        "apiVersion": "v1",
+                        "kind": "Node",
+                        "metadata": {"name": "krustlet", "uid": "1234"},
+                    })
+                };
+                send.send_response(
+                    HttpResponse::builder()
+                        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        (kube::Client::new(mock_service, "default"), requests)
+    }
+
+    fn lease(holder: &str) -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": {"name": "krustlet", "namespace": "kube-node-lease"},
+            "spec": {
+                "holderIdentity": holder,
+                "renewTime": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                "leaseDurationSeconds": 40,
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_unmanaged_controller_makes_no_requests() {
+        let (client, requests) = mock_client(lease(""));
+        let controller = UnmanagedNodeController;
+        let config = Config::default();
+        controller.register(&client, &config).await.unwrap();
+        controller.heartbeat(&client, &config).await.unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+        assert!(controller.heartbeat_interval() > DEFAULT_HEARTBEAT_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn test_kube_controller_heartbeat_renews_lease_and_status() {
+        let (client, requests) = mock_client(lease(""));
+        let controller = KubeNodeController::new(Arc::new(MockProvider));
+        let config = Config {
+            node_name: "krustlet".to_owned(),
+            ..Default::default()
+        };
+        controller.heartbeat(&client, &config).await.unwrap();
+        assert_eq!(controller.heartbeat_interval(), DEFAULT_HEARTBEAT_INTERVAL);
+
+        let requests = requests.lock().unwrap();
+        for expected in &[
+            "/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases/krustlet",
+            "/api/v1/nodes/krustlet/status",
+        ] {
+            assert!(
+                requests
+                    .iter()
+                    .any(|(method, path)| method == Method::PATCH && path == expected),
+                "expected a patch of {} in {:?}",
+                expected,
+                requests
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kube_controller_refuses_node_held_by_another_kubelet() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            node_name: "krustlet".to_owned(),
+            data_dir: data_dir.path().to_owned(),
+            ..Default::default()
+        };
+        let (client, requests) = mock_client(lease("another-kubelet"));
+        let controller = KubeNodeController::new(Arc::new(MockProvider));
+
+        let err = controller.register(&client, &config).await.unwrap_err();
+        assert!(err.to_string().contains("another-kubelet"));
+        // Nothing but the lease is looked at, so the node isn't touched
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(method, path)| method == Method::GET && path.contains("/leases/")));
+    }
+
+    #[tokio::test]
+    async fn test_kube_controller_takes_over_legacy_lease_only_when_told() {
+        let (client, requests) = mock_client(lease("krustlet"));
+        let controller = KubeNodeController::new(Arc::new(MockProvider));
+        let stock_kubelet = tempfile::tempdir().unwrap();
+        let config = Config {
+            node_name: "krustlet".to_owned(),
+            data_dir: stock_kubelet.path().to_owned(),
+            ..Default::default()
+        };
+        let err = controller.register(&client, &config).await.unwrap_err();
+        assert!(err.to_string().contains("held by krustlet"));
+
+        let earlier_krustlet = tempfile::tempdir().unwrap();
+        let config = Config {
+            node_name: "krustlet".to_owned(),
+            data_dir: earlier_krustlet.path().to_owned(),
+            take_over_legacy_lease: true,
+            ..Default::default()
+        };
+        controller.register(&client, &config).await.unwrap();
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(method, path)| method == Method::PATCH && path.contains("/leases/")));
+    }
+
+    #[tokio::test]
+    async fn test_kube_controller_renews_lease_only_at_the_checked_version() {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let lease_patches = Arc::new(Mutex::new(Vec::new()));
+        let recorded = lease_patches.clone();
+        tokio::spawn(async move {
+            pin_mut!(handle);
+            while let Some((request, send)) = handle.next_request().await {
+                let is_lease = request.uri().path().contains("/leases/");
+                let is_patch = request.method() == Method::PATCH;
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let (status, body) = match (is_lease, is_patch) {
+                    // Another process renews the lease between the check and the renewal
+                    (true, true) => {
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+                        (
+                            409,
+                            serde_json::json!({
+                                "status": "Failure",
+                                "message": "the object has been modified",
+                                "reason": "Conflict",
+                                "code": 409,
+                            }),
+                        )
+                    }
+                    (true, false) => {
+                        let mut lease = lease("");
+                        lease["metadata"]["resourceVersion"] = "42".into();
+                        (200, lease)
+                    }
+                    _ => (
+                        200,
+                        serde_json::json!({
+                            "apiVersion": "v1",
+                            "kind": "Node",
+                            "metadata": {"name": "krustlet", "uid": "1234"},
+                        }),
+                    ),
+                };
+                send.send_response(
+                    HttpResponse::builder()
+                        .status(status)
+                        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        let client = kube::Client::new(mock_service, "default");
+        let controller = KubeNodeController::new(Arc::new(MockProvider));
+        let config = Config {
+            node_name: "krustlet".to_owned(),
+            ..Default::default()
+        };
+
+        // The conflict is left for the next heartbeat to check, rather than retried or fatal
+        controller.heartbeat(&client, &config).await.unwrap();
+        let lease_patches = lease_patches.lock().unwrap();
+        assert_eq!(lease_patches.len(), 1);
+        assert_eq!(lease_patches[0]["metadata"]["resourceVersion"], "42");
+    }
+}
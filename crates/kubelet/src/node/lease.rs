@@ -0,0 +1,286 @@
+//! Guards against two processes running a node with the same name.
+//!
+//! Each Kubelet holds its node's lease under an identity of its own, which is kept in its data
+//! directory so that it survives restarts. When the Kubelet starts and finds the lease held by
+//! another identity that is still renewing it, whether another Krustlet or a kubelet, it refuses
+//! to start or, if it is configured to wait for the node lease, waits for that lease to expire
+//! and then takes the node over. A Kubelet that finds its lease taken over while it is running
+//! stops, rather than fighting the new holder over the Node, and every renewal of the lease is
+//! conditional on it not having changed since it was last checked.
+//!
+//! A lease held under the bare node name is what a stock kubelet, or a Krustlet from before
+//! identities were unique, holds. It is treated like any other process's lease unless the Kubelet
+//! is configured to take over legacy leases, in which case a Kubelet that has only just got an
+//! identity of its own takes it over as its own earlier lease.
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use kube::api::{Api, Patch, PatchParams};
+use kube::error::ErrorResponse;
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+
+/// The namespace node leases live in
+const LEASE_NAMESPACE: &str = "kube-node-lease";
+/// How long a node lease lasts without being renewed
+pub(crate) const LEASE_DURATION_SECONDS: i32 = 300;
+/// The file in the data directory the Kubelet keeps its lease holder identity in
+const IDENTITY_FILE: &str = "node-lease-identity";
+
+lazy_static::lazy_static! {
+    static ref IDENTITY: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Who holds a node lease, as this Kubelet sees it.
+#[derive(Debug, PartialEq)]
+enum Holder {
+    /// Nobody, or this Kubelet, so the lease is ours to renew
+    Free,
+    /// Another process, until the lease expires at the given time
+    Other {
+        identity: String,
+        until: DateTime<Utc>,
+    },
+}
+
+/// The identity the Kubelet holds its node's lease under. Until the node is registered, this is
+/// the node name, which is what Kubelets used before they had identities of their own.
+pub(crate) fn holder_identity(node_name: &str) -> String {
+    IDENTITY
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| node_name.to_owned())
+}
+
+/// Takes hold of the node's lease before the node is registered, failing if another process
+/// holds it, or waiting for its lease to expire if the configuration says to.
+pub(crate) async fn acquire(client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+    let (identity, created) = load_identity(&config.data_dir, &config.hostname).await?;
+    *IDENTITY.write().unwrap() = Some(identity.clone());
+    // A lease held under the bare node name may have been written by a Kubelet from before
+    // identities were unique, which is most likely an earlier run of this one if it has only just
+    // got its own. It may just as well be a stock kubelet's, so it is only ours if we're told so
+    let previous = if created && config.take_over_legacy_lease {
+        Some(config.node_name.as_str())
+    } else {
+        None
+    };
+
+    let leases: Api<Lease> = Api::namespaced(client.clone(), LEASE_NAMESPACE);
+    loop {
+        let lease = match leases.get(&config.node_name).await {
+            Ok(lease) => lease,
+            // The lease is created with the node
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(()),
+            Err(e) => return Err(anyhow::anyhow!("Unable to fetch node lease: {}", e)),
+        };
+        let now = config.environment.clock.now();
+        match holder(&lease, &identity, previous, now) {
+            Holder::Free => match take(&leases, &lease, &config.node_name, &identity, now).await {
+                Ok(()) => return Ok(()),
+                Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {
+                    debug!("Node lease changed while taking hold of it, checking it again");
+                }
+                Err(e) => return Err(anyhow::anyhow!("Unable to take hold of node lease: {}", e)),
+            },
+            Holder::Other {
+                identity: other,
+                until,
+            } if config.wait_for_node_lease => {
+                warn!(
+                    holder = %other,
+                    %until,
+                    "Node lease is held by another process, waiting for it to expire"
+                );
+                let remaining = (until - now).to_std().unwrap_or_default();
+                config
+                    .environment
+                    .clock
+                    .sleep(remaining + Duration::from_secs(1))
+                    .await;
+            }
+            Holder::Other {
+                identity: other,
+                until,
+            } => {
+                anyhow::bail!(
+                    "Node {} is already held by {}, whose lease expires at {}. Another Kubelet may be running with the same node name; stop it, or set the Kubelet to wait for the node lease to take the node over once it expires",
+                    config.node_name,
+                    other,
+                    until
+                );
+            }
+        }
+    }
+}
+
+/// Checks that no other process has taken the node's lease over since the last heartbeat,
+/// returning the resource version of the lease as it was checked so that renewing it can be made
+/// conditional on it not having changed since. Errors fetching the lease are left for the lease
+/// renewal to deal with, in which case there is no version to renew it at.
+pub(crate) async fn ensure_held(
+    client: &kube::Client,
+    config: &Config,
+) -> anyhow::Result<Option<String>> {
+    let node_name = config.node_name.as_str();
+    let identity = holder_identity(node_name);
+    let leases: Api<Lease> = Api::namespaced(client.clone(), LEASE_NAMESPACE);
+    let lease = match leases.get(node_name).await {
+        Ok(lease) => lease,
+        Err(e) => {
+            debug!(error = %e, "Unable to fetch node lease to check its holder");
+            return Ok(None);
+        }
+    };
+    match holder(&lease, &identity, None, config.environment.clock.now()) {
+        Holder::Free => Ok(lease.metadata.resource_version),
+        Holder::Other { identity: other, .. } => Err(anyhow::anyhow!(
+            "Node lease has been taken over by {}, so another Kubelet is running with the node name {}",
+            other,
+            node_name
+        )),
+    }
+}
+
+/// Who holds the lease, given this Kubelet's identity and the identity it may have held the
+/// lease under before.
+fn holder(lease: &Lease, identity: &str, previous: Option<&str>, now: DateTime<Utc>) -> Holder {
+    let spec = match &lease.spec {
+        Some(spec) => spec,
+        None => return Holder::Free,
+    };
+    let other = match spec.holder_identity.as_deref() {
+        None | Some("") => return Holder::Free,
+        Some(holder) if holder == identity || Some(holder) == previous => return Holder::Free,
+        Some(holder) => holder,
+    };
+    let renewed = match spec
+        .renew_time
+        .as_ref()
+        .or_else(|| spec.acquire_time.as_ref())
+    {
+        Some(MicroTime(renewed)) => *renewed,
+        None => return Holder::Free,
+    };
+    let until =
+        renewed + chrono::Duration::seconds(spec.lease_duration_seconds.unwrap_or(0).into());
+    if until <= now {
+        Holder::Free
+    } else {
+        Holder::Other {
+            identity: other.to_owned(),
+            until,
+        }
+    }
+}
+
+/// Sets this Kubelet as the holder of the lease, unless it has changed since it was fetched.
+async fn take(
+    leases: &Api<Lease>,
+    lease: &Lease,
+    node_name: &str,
+    identity: &str,
+    now: DateTime<Utc>,
+) -> Result<(), kube::Error> {
+    let spec = lease.spec.clone().unwrap_or_default();
+    let mut patch = serde_json::json!({
+        "metadata": { "resourceVersion": lease.metadata.resource_version },
+        "spec": super::lease_spec_definition(node_name, now),
+    });
+    if spec.holder_identity.as_deref() != Some(identity) {
+        info!(previous = ?spec.holder_identity, "Taking hold of node lease");
+        patch["spec"]["leaseTransitions"] = (spec.lease_transitions.unwrap_or(0) + 1).into();
+    }
+    leases
+        .patch(node_name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .map(|_| ())
+}
+
+/// Reads the Kubelet's lease holder identity from its data directory, creating one if it has
+/// none yet. Returns whether the identity was created.
+async fn load_identity(data_dir: &Path, hostname: &str) -> anyhow::Result<(String, bool)> {
+    let path = data_dir.join(IDENTITY_FILE);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(identity) if !identity.trim().is_empty() => {
+            return Ok((identity.trim().to_owned(), false))
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => {
+            return Err(anyhow::anyhow!(
+                "Unable to read node lease identity from {}: {}",
+                path.display(),
+                e
+            ))
+        }
+    }
+    let identity = format!("{}_{}", hostname, uuid::Uuid::new_v4());
+    tokio::fs::create_dir_all(data_dir).await?;
+    tokio::fs::write(&path, &identity).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Unable to write node lease identity to {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    Ok((identity, true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lease(holder: &str, renewed: DateTime<Utc>) -> Lease {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "node", "namespace": LEASE_NAMESPACE },
+            "spec": {
+                "holderIdentity": holder,
+                "renewTime": renewed.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                "leaseDurationSeconds": 40
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_only_live_leases_of_others_are_held() {
+        let now = Utc::now();
+        let live = lease("other", now - chrono::Duration::seconds(10));
+        match holder(&live, "me", None, now) {
+            Holder::Other { identity, until } => {
+                assert_eq!(identity, "other");
+                assert_eq!(until, now + chrono::Duration::seconds(30));
+            }
+            Holder::Free => panic!("live lease of another process should be held"),
+        }
+        let expired = lease("other", now - chrono::Duration::seconds(60));
+        assert_eq!(holder(&expired, "me", None, now), Holder::Free);
+        let own = lease("me", now);
+        assert_eq!(holder(&own, "me", None, now), Holder::Free);
+        let previous = lease("node", now);
+        assert_eq!(holder(&previous, "me", Some("node"), now), Holder::Free);
+        assert_ne!(holder(&previous, "me", None, now), Holder::Free);
+    }
+
+    #[tokio::test]
+    async fn test_identity_survives_restarts() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let (identity, created) = load_identity(data_dir.path(), "host").await.unwrap();
+        assert!(created);
+        assert!(identity.starts_with("host_"));
+        let (reloaded, created) = load_identity(data_dir.path(), "host").await.unwrap();
+        assert!(!created);
+        assert_eq!(reloaded, identity);
+        let (other, _) = load_identity(tempfile::tempdir().unwrap().path(), "host")
+            .await
+            .unwrap();
+        assert_ne!(other, identity);
+    }
+}
@@ -2,7 +2,8 @@
 //! nodes operating within the cluster.
 use crate::config::Config;
 use crate::container::Status as ContainerStatus;
-use crate::pod::{Phase, Pod};
+use crate::environment::Clock;
+use crate::pod::{new_event, Phase, Pod};
 use crate::provider::Provider;
 use chrono::prelude::*;
 use futures::{StreamExt, TryStreamExt};
@@ -10,6 +11,7 @@ use k8s_openapi::api::coordination::v1::Lease;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::core::v1::{Event as KubeEvent, ObjectReference};
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
@@ -18,6 +20,14 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument, trace, warn};
 
+mod capacity;
+mod controller;
+mod lease;
+pub mod problem;
+pub mod taint;
+
+pub use controller::{KubeNodeController, NodeController, UnmanagedNodeController};
+
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 macro_rules! retry {
@@ -73,6 +83,10 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     {
         Ok(_) => {
             debug!("Node already exists, skipping node creation");
+            // The node's limits or configured capacity may have changed since it was created
+            if let Err(e) = update_capacity(&node_client, config).await {
+                warn!(error = %e, "Unable to update node capacity");
+            }
             return;
         }
         Err(Error::Api(ErrorResponse { code: 404, .. })) => (),
@@ -97,22 +111,12 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
 
     node_labels_definition(P::ARCH, &config, &mut builder);
 
-    // TODO Do we want to detect this?
-    builder.add_capacity("cpu", "4");
-    builder.add_capacity("ephemeral-storage", "61255492Ki");
-    builder.add_capacity("hugepages-1Gi", "0");
-    builder.add_capacity("hugepages-2Mi", "0");
-    builder.add_capacity("memory", "4032800Ki");
-    builder.add_capacity("pods", &config.max_pods.to_string());
-
-    builder.add_allocatable("cpu", "4");
-    builder.add_allocatable("ephemeral-storage", "61255492Ki");
-    builder.add_allocatable("hugepages-1Gi", "0");
-    builder.add_allocatable("hugepages-2Mi", "0");
-    builder.add_allocatable("memory", "4032800Ki");
-    builder.add_allocatable("pods", &config.max_pods.to_string());
-
-    let ts = Utc::now();
+    for (resource, quantity) in capacity::node_capacity(config) {
+        builder.add_capacity(&resource, &quantity);
+        builder.add_allocatable(&resource, &quantity);
+    }
+
+    let ts = config.environment.clock.now();
     builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
     builder.add_condition(
         "OutOfDisk",
@@ -127,6 +131,10 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
 
     builder.set_port(config.server_config.port as i32);
 
+    if let Some(cidr) = &config.pod_cidr {
+        builder.set_pod_cidr(cidr);
+    }
+
     match provider.node(&mut builder).await {
         Ok(()) => (),
         Err(e) => warn!("Provider node annotation error: {:?}", e),
@@ -137,7 +145,8 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     match retry!(node_client.create(&PostParams::default(), &node).await, times: 4) {
         Ok(node) => {
             let node_uid = node.metadata.uid.unwrap();
-            if let Err(e) = create_lease(&node_uid, &config.node_name, &client).await {
+            let now = config.environment.clock.now();
+            if let Err(e) = create_lease(&node_uid, &config.node_name, now, &client).await {
                 error!(error = %e, "Failed to create lease");
                 return;
             }
@@ -287,32 +296,66 @@ async fn evict_pod(
 ///
 /// This is how we report liveness to the upstream.
 /// If we are unable to update the node after several retries we panic, as we could be in an
-/// inconsistent state
-#[instrument(level = "info", skip(client))]
-pub async fn update(client: &kube::Client, node_name: &str) {
+/// inconsistent state. If `lease_version` is given, the lease is only renewed if it is still at
+/// that resource version, and is otherwise left for the next update to check again.
+#[instrument(level = "info", skip(client, clock))]
+pub async fn update(
+    client: &kube::Client,
+    node_name: &str,
+    lease_version: Option<&str>,
+    clock: &dyn Clock,
+) {
     debug!("Updating node");
     if let Ok(uid) = uid(client, node_name).await {
         trace!("Fetched current node object to update");
-        retry!(update_lease(&uid, node_name, client).await, times: 4)
-            .expect("Could not update lease");
-        retry!(update_status(node_name, client).await, times: 4)
+        match retry!(
+            update_lease(&uid, node_name, lease_version, clock.now(), client).await,
+            times: 4,
+            break_on: &Error::Api(ErrorResponse { code: 409, .. })
+        ) {
+            Err(Error::Api(ErrorResponse { code: 409, .. })) => {
+                warn!("Node lease changed since it was checked, leaving it for the next update")
+            }
+            result => {
+                result.expect("Could not update lease");
+            }
+        }
+        retry!(update_status(node_name, clock.now(), client).await, times: 4)
             .expect("Could not update node status");
+        if let Err(e) = taint::apply(client, node_name).await {
+            warn!(error = %e, "Unable to update node taints, will try again on the next update");
+        }
+        record_problem_events(&uid, node_name, client).await;
     }
 }
 
-async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result<()> {
+async fn update_status(
+    node_name: &str,
+    now: DateTime<Utc>,
+    client: &kube::Client,
+) -> anyhow::Result<()> {
     // TODO: Update the lastTransitionTime properly
+    let now = now.to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    let mut conditions = vec![serde_json::json!({
+        "lastHeartbeatTime": now,
+        "message": "kubelet is posting ready status",
+        "reason": "KubeletReady",
+        "status": "True",
+        "type": "Ready"
+    })];
+    conditions.extend(problem::conditions().into_iter().map(|c| {
+        serde_json::json!({
+            "lastHeartbeatTime": now,
+            "lastTransitionTime": c.last_transition.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "message": c.message,
+            "reason": c.reason,
+            "status": if c.active { "True" } else { "False" },
+            "type": c.type_
+        })
+    }));
     let status_patch = serde_json::json!({
         "status": {
-            "conditions": [
-                {
-                    "lastHeartbeatTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-                    "message": "kubelet is posting ready status",
-                    "reason": "KubeletReady",
-                    "status": "True",
-                    "type": "Ready"
-                }
-            ],
+            "conditions": conditions,
         }
     });
     let node_client: Api<KubeNode> = Api::all(client.clone());
@@ -327,6 +370,52 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
     Ok(())
 }
 
+/// Sets the capacity and allocatable resources of an existing node to those detected or
+/// configured, leaving any other resources, such as those of device plugins, as they are.
+async fn update_capacity(node_client: &Api<KubeNode>, config: &Config) -> anyhow::Result<()> {
+    let capacity = capacity::node_capacity(config);
+    let status_patch = serde_json::json!({
+        "status": {
+            "capacity": capacity,
+            "allocatable": capacity,
+        }
+    });
+    node_client
+        .patch_status(
+            &config.node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(status_patch),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to patch node status: {}", e))?;
+    Ok(())
+}
+
+/// Records an Event for the node for every problem that started or ended since the last update.
+/// Events are informational, so failing to record one is only logged.
+async fn record_problem_events(node_uid: &str, node_name: &str, client: &kube::Client) {
+    let events: Api<KubeEvent> = Api::namespaced(client.clone(), "default");
+    for problem in problem::take_events() {
+        let involved_object = ObjectReference {
+            kind: Some("Node".to_owned()),
+            name: Some(node_name.to_owned()),
+            uid: Some(node_uid.to_owned()),
+            ..Default::default()
+        };
+        let event = new_event(
+            involved_object,
+            Some(node_name.to_owned()),
+            problem.type_,
+            &problem.reason,
+            &problem.message,
+            problem.timestamp,
+        );
+        if let Err(e) = events.create(&PostParams::default(), &event).await {
+            warn!(error = %e, "Unable to record node problem event");
+        }
+    }
+}
+
 /// Create a node lease
 ///
 /// These creates a new node lease and claims the node for a set
@@ -336,11 +425,16 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
 /// As far as I can tell, leases ALWAYS go in the 'kube-node-lease'
 /// namespace, no exceptions.
 #[instrument(level = "info", err, skip(client))]
-async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) -> Result<(), Error> {
+async fn create_lease(
+    node_uid: &str,
+    node_name: &str,
+    now: DateTime<Utc>,
+    client: &kube::Client,
+) -> Result<(), Error> {
     debug!("Creating lease for node");
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let lease = lease_definition(node_uid, node_name, now);
     let lease = serde_json::from_value(lease)
         .expect("failed to deserialize lease from lease definition JSON");
 
@@ -367,7 +461,8 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
 }
 
 /// Update the Kubernetes node lease, essentially requesting that we keep
-/// the lease for another period.
+/// the lease for another period. If a resource version is given, the update fails with a
+/// conflict unless the lease is still at that version.
 ///
 /// TODO: Our patch is overzealous right now. We just need to update the
 /// timestamp.
@@ -375,12 +470,17 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
 async fn update_lease(
     node_uid: &str,
     node_name: &str,
+    resource_version: Option<&str>,
+    now: DateTime<Utc>,
     client: &kube::Client,
 ) -> Result<Lease, Error> {
     debug!("Updating lease for node");
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let mut lease = lease_definition(node_uid, node_name, now);
+    if let Some(resource_version) = resource_version {
+        lease["metadata"]["resourceVersion"] = resource_version.into();
+    }
 
     let resp = leases
         .patch(
@@ -401,7 +501,7 @@ async fn update_lease(
 /// The lease tells Kubernetes that we want to claim the node for a while
 /// longer. And then tells Kubernetes how long it should wait before
 /// expecting a new lease.
-fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
+fn lease_definition(node_uid: &str, node_name: &str, now: DateTime<Utc>) -> serde_json::Value {
     serde_json::json!(
         {
             "apiVersion": "coordination.k8s.io/v1",
@@ -417,25 +517,25 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
                     }
                 ]
             },
-            "spec": lease_spec_definition(node_name)
+            "spec": lease_spec_definition(node_name, now)
         }
     )
 }
 
 /// Defines a new coordiation lease for Kubernetes
 ///
-/// We set the lease times, the lease duration, and the node name.
-fn lease_spec_definition(node_name: &str) -> serde_json::Value {
+/// We set the lease times, the lease duration, and the identity we hold the lease under.
+fn lease_spec_definition(node_name: &str, now: DateTime<Utc>) -> serde_json::Value {
     // Workaround for https://github.com/deislabs/krustlet/issues/5
     // In the future, use LeaseSpec rather than a JSON value
-    let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    let now = now.to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
 
     serde_json::json!(
         {
-            "holderIdentity": node_name,
+            "holderIdentity": lease::holder_identity(node_name),
             "acquireTime": now,
             "renewTime": now,
-            "leaseDurationSeconds": 300
+            "leaseDurationSeconds": lease::LEASE_DURATION_SECONDS
         }
     )
 }
@@ -761,6 +861,7 @@ mod test {
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                admin_token_file: None,
             },
             bootstrap_file: "doesnt/matter".into(),
             allow_local_modules: false,
@@ -768,6 +869,33 @@ mod test {
             data_dir: PathBuf::new(),
             plugins_dir: PathBuf::new(),
             device_plugins_dir: PathBuf::new(),
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            allowed_pod_overrides: vec![],
+            store_scope: crate::store::StoreScope::Shared,
+            pre_pull_images: vec![],
+            pre_pull_interval: std::time::Duration::from_secs(300),
+            pre_pull_secrets: vec![],
+            registry_connect_timeout: std::time::Duration::from_secs(10),
+            registry_read_timeout: std::time::Duration::from_secs(30),
+            image_pull_deadline: std::time::Duration::from_secs(600),
+            pod_cidr: None,
+            kube_context: None,
+            bootstrap_plugin: None,
+            slow_api_call_threshold: std::time::Duration::from_secs(1),
+            pod_log_dir: None,
+            cri_container_logs: false,
+            scratch_quota: None,
+            cluster_dns: vec![],
+            cluster_domain: "cluster.local".to_owned(),
+            allowed_registries: vec![],
+            require_image_digests: false,
+            record_image_attestations: false,
+            instance_pool_size: 0,
+            instance_pool_memory: 0,
+            wait_for_node_lease: false,
+            take_over_legacy_lease: false,
+            node_capacity: HashMap::new(),
+            environment: Default::default(),
             node_labels,
             max_pods: 110,
         };
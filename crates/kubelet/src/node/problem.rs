@@ -0,0 +1,194 @@
+//! Reporting of persistent node problems.
+//!
+//! Providers and the Kubelet's own subsystems report faults that won't go away on their own,
+//! such as an unreachable registry or a failing disk, with [`report`], and clear them with
+//! [`resolve`] once they recover. Every problem that has been reported becomes a condition on the
+//! node, which is `True` while the problem lasts, and each time a problem starts or ends an Event
+//! is recorded for the node. Both are published by the node status loop, so cluster automation
+//! (for example a remediation controller or a taint manager) can react to them.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::environment::{Clock, SystemClock};
+
+/// The registry could not be reached to pull modules
+pub const REGISTRY_UNREACHABLE: &str = "RegistryUnreachable";
+/// The runtime that runs modules is not working
+pub const RUNTIME_PROBLEM: &str = "RuntimeProblem";
+/// The Kubelet's data directory could not be written to
+pub const DISK_PROBLEM: &str = "DiskProblem";
+
+/// The most events kept while waiting for the node status loop, in case it isn't running
+const MAX_PENDING_EVENTS: usize = 100;
+
+lazy_static::lazy_static! {
+    static ref PROBLEMS: Mutex<Problems> = Mutex::new(Problems::default());
+}
+
+struct Problems {
+    conditions: BTreeMap<String, ProblemCondition>,
+    events: Vec<ProblemEvent>,
+    /// Timestamps the transitions and events
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Problems {
+    fn default() -> Self {
+        Problems {
+            conditions: BTreeMap::new(),
+            events: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Problems {
+    fn push_event(&mut self, event: ProblemEvent) {
+        if self.events.len() == MAX_PENDING_EVENTS {
+            self.events.remove(0);
+        }
+        self.events.push(event);
+    }
+}
+
+/// The current state of a reported problem, as shown in the node's conditions.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProblemCondition {
+    pub(crate) type_: String,
+    pub(crate) active: bool,
+    pub(crate) reason: String,
+    pub(crate) message: String,
+    pub(crate) last_transition: DateTime<Utc>,
+}
+
+/// A problem starting or ending, recorded as an Event for the node.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProblemEvent {
+    /// `Warning` when a problem starts and `Normal` when it ends
+    pub(crate) type_: &'static str,
+    pub(crate) reason: String,
+    pub(crate) message: String,
+    pub(crate) timestamp: DateTime<Utc>,
+}
+
+/// Timestamps problems with the given clock from now on, rather than the system clock. The
+/// Kubelet uses the clock of its environment.
+pub(crate) fn use_clock(clock: Arc<dyn Clock>) {
+    PROBLEMS.lock().unwrap().clock = clock;
+}
+
+/// Reports that the node has a persistent problem. `condition_type` is the type of the node
+/// condition that describes the problem (for example [`REGISTRY_UNREACHABLE`]), and `reason` a
+/// short CamelCase explanation. Reporting a problem that is already active only updates its
+/// message.
+pub fn report(condition_type: &str, reason: &str, message: &str) {
+    let mut problems = PROBLEMS.lock().unwrap();
+    let now = problems.clock.now();
+    let started = !problems
+        .conditions
+        .get(condition_type)
+        .map(|c| c.active && c.reason == reason)
+        .unwrap_or(false);
+    let last_transition = match problems.conditions.get(condition_type) {
+        Some(condition) if condition.active => condition.last_transition,
+        _ => now,
+    };
+    problems.conditions.insert(
+        condition_type.to_owned(),
+        ProblemCondition {
+            type_: condition_type.to_owned(),
+            active: true,
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+            last_transition,
+        },
+    );
+    if started {
+        problems.push_event(ProblemEvent {
+            type_: "Warning",
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+            timestamp: now,
+        });
+    }
+}
+
+/// Reports that a problem the node had is over. Resolving a problem that isn't active does
+/// nothing.
+pub fn resolve(condition_type: &str) {
+    let mut problems = PROBLEMS.lock().unwrap();
+    let now = problems.clock.now();
+    let condition = match problems.conditions.get_mut(condition_type) {
+        Some(condition) if condition.active => condition,
+        _ => return,
+    };
+    condition.active = false;
+    condition.reason = format!("No{}", condition_type);
+    condition.message = format!("{} problem is resolved", condition_type);
+    condition.last_transition = now;
+    let event = ProblemEvent {
+        type_: "Normal",
+        reason: condition.reason.clone(),
+        message: condition.message.clone(),
+        timestamp: now,
+    };
+    problems.push_event(event);
+}
+
+/// The conditions of every problem that has been reported, active or not.
+pub(crate) fn conditions() -> Vec<ProblemCondition> {
+    PROBLEMS
+        .lock()
+        .unwrap()
+        .conditions
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Takes the events that haven't been recorded yet.
+pub(crate) fn take_events() -> Vec<ProblemEvent> {
+    std::mem::take(&mut PROBLEMS.lock().unwrap().events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report_and_resolve() {
+        // The problems are global, so use a condition no other test reports
+        let condition_type = "TestProblem";
+        let find = || conditions().into_iter().find(|c| c.type_ == condition_type);
+        let events = || -> Vec<ProblemEvent> {
+            let mut problems = PROBLEMS.lock().unwrap();
+            let (ours, others) = std::mem::take(&mut problems.events)
+                .into_iter()
+                .partition(|e| e.reason.contains("Test"));
+            problems.events = others;
+            ours
+        };
+
+        resolve(condition_type);
+        assert!(find().is_none());
+
+        report(condition_type, "TestFailing", "first failure");
+        report(condition_type, "TestFailing", "second failure");
+        let condition = find().unwrap();
+        assert!(condition.active);
+        assert_eq!(condition.message, "second failure");
+        let recorded = events();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].type_, "Warning");
+
+        resolve(condition_type);
+        let condition = find().unwrap();
+        assert!(!condition.active);
+        assert_eq!(condition.reason, "NoTestProblem");
+        let recorded = events();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].type_, "Normal");
+    }
+}
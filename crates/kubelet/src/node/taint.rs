@@ -0,0 +1,339 @@
+//! Taints the Kubelet puts on its own node while it is running.
+//!
+//! Providers and the Kubelet's own subsystems taint the node with [`add`] when it shouldn't get
+//! new pods, for example [`PROVIDER_UNHEALTHY`] while the provider's runtime is broken, and
+//! [`remove`] the taint once it recovers. The node status loop applies the changes to the Node,
+//! leaving taints that something else put on the node alone, so the scheduler stops placing pods
+//! on the node while it is degraded and starts again afterwards. Pods that tolerate a taint, as
+//! decided by [`tolerates`], keep being scheduled and, for `NoExecute` taints, keep running.
+//!
+//! The taints only live in memory, so the first update after the Kubelet starts also removes
+//! any taint with a key under [`KRUSTLET_TAINT_PREFIX`] that nothing has added since, such as
+//! one left behind by a previous run that stopped while the node was degraded.
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Node as KubeNode, Pod as KubePod, Taint, Toleration};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, ListParams, PatchParams};
+use tracing::{info, warn};
+
+use crate::pod::Pod;
+
+/// The prefix of the keys of the taints the Kubelet owns. Taints that are added with other keys
+/// are still removed when asked for, but never reconciled.
+pub const KRUSTLET_TAINT_PREFIX: &str = "node.krustlet.io/";
+
+/// The provider can't run pods at the moment
+pub const PROVIDER_UNHEALTHY: &str = "node.krustlet.io/provider-unhealthy";
+
+/// Taint effect that stops new pods being scheduled on the node
+pub const NO_SCHEDULE: &str = "NoSchedule";
+/// Taint effect that makes the scheduler avoid the node where it can
+pub const PREFER_NO_SCHEDULE: &str = "PreferNoSchedule";
+/// Taint effect that also evicts running pods that don't tolerate the taint
+pub const NO_EXECUTE: &str = "NoExecute";
+
+lazy_static::lazy_static! {
+    static ref TAINTS: Mutex<ManagedTaints> = Mutex::new(ManagedTaints::default());
+}
+
+#[derive(Default)]
+struct ManagedTaints {
+    /// The taint wanted for every key the Kubelet has managed, or `None` if it should be removed
+    taints: BTreeMap<String, Option<Taint>>,
+    /// Incremented on every change, so changes made while the node is updated aren't lost
+    version: u64,
+    /// The version last applied to the node, if the taints were ever applied
+    applied: Option<u64>,
+}
+
+impl ManagedTaints {
+    fn add(&mut self, taint: Taint) {
+        let current = self.taints.get(&taint.key);
+        let unchanged = matches!(current, Some(Some(current)) if same_taint(current, &taint));
+        if !unchanged {
+            self.taints.insert(taint.key.clone(), Some(taint));
+            self.version += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(Some(_)) = self.taints.get(key) {
+            self.taints.insert(key.to_owned(), None);
+            self.version += 1;
+        }
+    }
+
+    fn is_tainted(&self, key: &str) -> bool {
+        matches!(self.taints.get(key), Some(Some(_)))
+    }
+}
+
+/// Taints the node with `key`, replacing any taint with the same key the Kubelet added before.
+/// `effect` is one of [`NO_SCHEDULE`], [`PREFER_NO_SCHEDULE`] or [`NO_EXECUTE`].
+pub fn add(key: &str, value: &str, effect: &str) {
+    let taint = Taint {
+        effect: effect.to_owned(),
+        key: key.to_owned(),
+        value: Some(value.to_owned()),
+        time_added: None,
+    };
+    TAINTS.lock().unwrap().add(taint);
+}
+
+/// Removes the taint with `key` the Kubelet added to the node. Removing a taint that isn't
+/// there does nothing.
+pub fn remove(key: &str) {
+    TAINTS.lock().unwrap().remove(key);
+}
+
+/// Whether the node is tainted with `key` by the Kubelet.
+pub fn is_tainted(key: &str) -> bool {
+    TAINTS.lock().unwrap().is_tainted(key)
+}
+
+/// Whether one of the pod's tolerations tolerates the taint, using the scheduler's rules: a
+/// toleration without a key tolerates every taint, and one without an effect tolerates every
+/// effect.
+pub fn tolerates(pod: &Pod, taint: &Taint) -> bool {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .map(|spec| {
+            spec.tolerations
+                .iter()
+                .any(|t| toleration_matches(t, taint))
+        })
+        .unwrap_or(false)
+}
+
+fn toleration_matches(toleration: &Toleration, taint: &Taint) -> bool {
+    let effect = toleration.effect.as_deref().unwrap_or_default();
+    if !effect.is_empty() && effect != taint.effect {
+        return false;
+    }
+    let key = toleration.key.as_deref().unwrap_or_default();
+    if !key.is_empty() && key != taint.key {
+        return false;
+    }
+    match toleration.operator.as_deref() {
+        Some("Exists") => true,
+        Some("Equal") | None | Some("") => {
+            // Only `Exists` can match every key
+            !key.is_empty()
+                && toleration.value.as_deref().unwrap_or_default()
+                    == taint.value.as_deref().unwrap_or_default()
+        }
+        Some(_) => false,
+    }
+}
+
+/// Applies the taints added or removed since the last update to the node.
+pub(crate) async fn apply(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let (wanted, version) = {
+        let managed = TAINTS.lock().unwrap();
+        if managed.applied == Some(managed.version) {
+            return Ok(());
+        }
+        (managed.taints.clone(), managed.version)
+    };
+
+    let nodes: Api<KubeNode> = Api::all(client.clone());
+    let node = nodes.get(node_name).await?;
+    let current = node.spec.map(|s| s.taints).unwrap_or_default();
+    let taints = merge(&current, &wanted);
+    if taints != current {
+        // The resource version makes the patch fail rather than drop a taint someone else added
+        // in the meantime, in which case it is tried again on the next update
+        let patch = serde_json::json!({
+            "metadata": {"resourceVersion": node.metadata.resource_version},
+            "spec": {"taints": taints},
+        });
+        nodes
+            .patch(
+                node_name,
+                &PatchParams::default(),
+                &kube::api::Patch::Merge(patch),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to patch node taints: {}", e))?;
+        let added: Vec<&Taint> = taints.iter().filter(|t| !current.contains(t)).collect();
+        let removed: Vec<&Taint> = current.iter().filter(|t| !taints.contains(t)).collect();
+        for taint in &added {
+            info!(key = %taint.key, effect = %taint.effect, "Tainted node");
+        }
+        for taint in &removed {
+            info!(key = %taint.key, effect = %taint.effect, "Removed taint from node");
+        }
+        let evicting: Vec<&Taint> = added
+            .into_iter()
+            .filter(|t| t.effect == NO_EXECUTE)
+            .collect();
+        if !evicting.is_empty() {
+            log_untolerating_pods(client, node_name, &evicting).await;
+        }
+    }
+
+    TAINTS.lock().unwrap().applied = Some(version);
+    Ok(())
+}
+
+/// Logs the pods on the node that will be evicted because they don't tolerate a new `NoExecute`
+/// taint.
+async fn log_untolerating_pods(client: &kube::Client, node_name: &str, taints: &[&Taint]) {
+    let pods: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    match pods.list(&params).await {
+        Ok(list) => {
+            for pod in list.items.into_iter().map(Pod::from) {
+                if let Some(taint) = taints.iter().find(|t| !tolerates(&pod, t)) {
+                    info!(
+                        pod = %format!("{}/{}", pod.namespace(), pod.name()),
+                        key = %taint.key,
+                        "Pod does not tolerate node taint and will be evicted"
+                    );
+                }
+            }
+        }
+        Err(e) => warn!(error = %e, "Unable to list pods affected by node taint"),
+    }
+}
+
+/// The node's taints with the Kubelet's changes applied. Taints the Kubelet owns that it doesn't
+/// want are removed, and taints with keys the Kubelet doesn't manage are kept as they are.
+fn merge(current: &[Taint], wanted: &BTreeMap<String, Option<Taint>>) -> Vec<Taint> {
+    let mut taints: Vec<Taint> = current
+        .iter()
+        .filter(|t| match wanted.get(&t.key) {
+            None => !t.key.starts_with(KRUSTLET_TAINT_PREFIX),
+            Some(Some(taint)) => same_taint(t, taint),
+            Some(None) => false,
+        })
+        .cloned()
+        .collect();
+    for taint in wanted.values().flatten() {
+        if !taints.iter().any(|t| same_taint(t, taint)) {
+            let mut taint = taint.clone();
+            if taint.effect == NO_EXECUTE {
+                // Tolerations with a toleration period count from when the taint was added
+                taint.time_added = Some(Time(Utc::now()));
+            }
+            taints.push(taint);
+        }
+    }
+    taints
+}
+
+fn same_taint(a: &Taint, b: &Taint) -> bool {
+    a.key == b.key && a.effect == b.effect && a.value == b.value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn taint(key: &str, value: &str, effect: &str) -> Taint {
+        Taint {
+            effect: effect.to_owned(),
+            key: key.to_owned(),
+            value: Some(value.to_owned()),
+            time_added: None,
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_other_taints() {
+        let other = taint("example.com/maintenance", "true", NO_SCHEDULE);
+        let stale = taint(PROVIDER_UNHEALTHY, "old", NO_SCHEDULE);
+        let mut wanted = BTreeMap::new();
+        wanted.insert(
+            PROVIDER_UNHEALTHY.to_owned(),
+            Some(taint(PROVIDER_UNHEALTHY, "true", NO_SCHEDULE)),
+        );
+        wanted.insert("node.krustlet.io/removed".to_owned(), None);
+
+        let merged = merge(&[other.clone(), stale], &wanted);
+        assert_eq!(
+            merged,
+            vec![
+                other.clone(),
+                taint(PROVIDER_UNHEALTHY, "true", NO_SCHEDULE)
+            ]
+        );
+        // Applying the same changes again changes nothing
+        assert_eq!(merge(&merged, &wanted), merged);
+
+        wanted.insert(PROVIDER_UNHEALTHY.to_owned(), None);
+        assert_eq!(merge(&merged, &wanted), vec![other.clone()]);
+    }
+
+    #[test]
+    fn test_merge_reconciles_owned_taints() {
+        let other = taint("example.com/maintenance", "true", NO_SCHEDULE);
+        // Left behind by a previous run of the Kubelet, which nothing in this run has added
+        let stale = taint(PROVIDER_UNHEALTHY, "true", NO_SCHEDULE);
+        let merged = merge(&[other.clone(), stale.clone()], &BTreeMap::new());
+        assert_eq!(merged, vec![other.clone()]);
+
+        let mut wanted = BTreeMap::new();
+        wanted.insert(PROVIDER_UNHEALTHY.to_owned(), Some(stale.clone()));
+        assert_eq!(
+            merge(&[other.clone(), stale.clone()], &wanted),
+            vec![other, stale]
+        );
+    }
+
+    #[test]
+    fn test_tolerates() {
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "default"},
+                "spec": {
+                    "containers": [],
+                    "tolerations": [
+                        {"key": PROVIDER_UNHEALTHY, "operator": "Exists", "effect": NO_SCHEDULE},
+                        {"key": "example.com/zone", "value": "a"},
+                    ],
+                },
+            }))
+            .unwrap(),
+        );
+        assert!(tolerates(
+            &pod,
+            &taint(PROVIDER_UNHEALTHY, "true", NO_SCHEDULE)
+        ));
+        assert!(!tolerates(
+            &pod,
+            &taint(PROVIDER_UNHEALTHY, "true", NO_EXECUTE)
+        ));
+        assert!(tolerates(&pod, &taint("example.com/zone", "a", NO_EXECUTE)));
+        assert!(!tolerates(
+            &pod,
+            &taint("example.com/zone", "b", NO_EXECUTE)
+        ));
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        // Other tests change the global taints, so this one uses its own
+        let mut managed = ManagedTaints::default();
+        let key = "node.krustlet.io/test";
+        assert!(!managed.is_tainted(key));
+        managed.add(taint(key, "true", NO_SCHEDULE));
+        assert_eq!(managed.version, 1);
+        // Adding the same taint again isn't a change
+        managed.add(taint(key, "true", NO_SCHEDULE));
+        assert_eq!(managed.version, 1);
+        assert!(managed.is_tainted(key));
+        managed.add(taint(key, "false", NO_SCHEDULE));
+        assert_eq!(managed.version, 2);
+        managed.remove(key);
+        assert!(!managed.is_tainted(key));
+        assert_eq!(managed.version, 3);
+        // Removing a taint that isn't there isn't either
+        managed.remove(key);
+        assert_eq!(managed.version, 3);
+    }
+}
@@ -0,0 +1,169 @@
+//! Graceful node shutdown support, mirroring the kubelet's `GracefulNodeShutdown` feature.
+//!
+//! On Linux, an inhibitor lock is taken with systemd-logind at startup. This delays system
+//! shutdown until the lock is released, giving the Kubelet a chance to terminate pods once logind
+//! signals over D-Bus that a shutdown is about to happen. On other platforms this is a no-op.
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+/// A held shutdown inhibitor lock. Dropping this releases the lock, allowing the system
+/// shutdown to continue.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) struct ShutdownInhibitor {
+    // Kept only so the lock is released when the inhibitor is dropped
+    #[cfg(target_os = "linux")]
+    _lock: zvariant::OwnedFd,
+    shutdown: oneshot::Receiver<()>,
+}
+
+impl ShutdownInhibitor {
+    /// Takes an inhibitor lock that delays system shutdown for up to `grace_period`. Returns
+    /// `None` if graceful shutdown is disabled (the grace period is zero), the platform doesn't
+    /// support it, or the lock could not be acquired.
+    pub(crate) async fn acquire(grace_period: Duration) -> Option<Self> {
+        if grace_period == Duration::from_secs(0) {
+            return None;
+        }
+        match platform::acquire(grace_period).await {
+            Ok(inhibitor) => {
+                info!(?grace_period, "Acquired shutdown inhibitor lock");
+                Some(inhibitor)
+            }
+            Err(e) => {
+                warn!(error = %e, "Unable to acquire shutdown inhibitor lock, graceful node shutdown is disabled");
+                None
+            }
+        }
+    }
+
+    /// Waits until the system signals that it is shutting down.
+    pub(crate) async fn wait_for_shutdown(&mut self) -> anyhow::Result<()> {
+        (&mut self.shutdown).await.map_err(|_| {
+            anyhow::anyhow!("Shutdown inhibitor stopped listening for shutdown signals")
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::ShutdownInhibitor;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tracing::{debug, error, warn};
+    use zbus::dbus_proxy;
+    use zvariant::OwnedFd;
+
+    #[dbus_proxy(
+        interface = "org.freedesktop.login1.Manager",
+        default_service = "org.freedesktop.login1",
+        default_path = "/org/freedesktop/login1"
+    )]
+    trait Manager {
+        /// Takes an inhibitor lock. The lock is held until the returned file descriptor is closed
+        fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+
+        /// Emitted with `true` right before a shutdown starts and `false` if it is cancelled
+        #[dbus_proxy(signal)]
+        fn prepare_for_shutdown(&self, start: bool) -> zbus::Result<()>;
+
+        /// The maximum time logind will allow an inhibitor to delay shutdown for
+        #[dbus_proxy(property, name = "InhibitDelayMaxUSec")]
+        fn inhibit_delay_max_usec(&self) -> zbus::Result<u64>;
+    }
+
+    pub(super) async fn acquire(grace_period: Duration) -> anyhow::Result<ShutdownInhibitor> {
+        let (lock_tx, lock_rx) = oneshot::channel();
+        let (shutdown_tx, shutdown) = oneshot::channel();
+
+        // The zbus connection is blocking, so it lives on its own thread for as long as we care
+        // about shutdown signals
+        std::thread::Builder::new()
+            .name("logind-inhibitor".into())
+            .spawn(move || {
+                if let Err(e) = watch(grace_period, lock_tx, shutdown_tx) {
+                    error!(error = %e, "Error while watching for logind shutdown signals");
+                }
+            })?;
+
+        let lock = lock_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("logind inhibitor thread exited unexpectedly"))??;
+        Ok(ShutdownInhibitor {
+            _lock: lock,
+            shutdown,
+        })
+    }
+
+    fn take_lock(grace_period: Duration) -> anyhow::Result<(zbus::Connection, OwnedFd)> {
+        let connection = zbus::Connection::new_system()?;
+        let manager = ManagerProxy::new(&connection)?;
+        let max_delay = Duration::from_micros(manager.inhibit_delay_max_usec()?);
+        if max_delay < grace_period {
+            warn!(
+                ?max_delay,
+                ?grace_period,
+                "logind InhibitDelayMaxSec is shorter than the shutdown grace period, pods may not be terminated gracefully"
+            );
+        }
+        let lock = manager.inhibit(
+            "shutdown",
+            "krustlet",
+            "Krustlet needs time to gracefully terminate pods",
+            "delay",
+        )?;
+        Ok((connection, lock))
+    }
+
+    fn watch(
+        grace_period: Duration,
+        lock_tx: oneshot::Sender<anyhow::Result<OwnedFd>>,
+        shutdown_tx: oneshot::Sender<()>,
+    ) -> anyhow::Result<()> {
+        let connection = match take_lock(grace_period) {
+            Ok((connection, lock)) => {
+                // If the receiver is gone nobody is waiting on us, so there is nothing to do
+                if lock_tx.send(Ok(lock)).is_err() {
+                    return Ok(());
+                }
+                connection
+            }
+            Err(e) => {
+                let _ = lock_tx.send(Err(e));
+                return Ok(());
+            }
+        };
+
+        let manager = ManagerProxy::new(&connection)?;
+        let (signal_tx, signal_rx) = std::sync::mpsc::channel();
+        manager.connect_prepare_for_shutdown(move |start| {
+            let _ = signal_tx.send(start);
+            Ok(())
+        })?;
+        loop {
+            manager.next_signal()?;
+            match signal_rx.try_recv() {
+                Ok(true) => {
+                    debug!("logind signaled that the system is shutting down");
+                    let _ = shutdown_tx.send(());
+                    return Ok(());
+                }
+                Ok(false) => debug!("logind signaled that a shutdown was cancelled"),
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use super::ShutdownInhibitor;
+    use std::time::Duration;
+
+    pub(super) async fn acquire(_grace_period: Duration) -> anyhow::Result<ShutdownInhibitor> {
+        Err(anyhow::anyhow!(
+            "graceful node shutdown is only supported on Linux"
+        ))
+    }
+}
@@ -1,21 +1,42 @@
+use crate::fault::{self, FaultPoint};
 use crate::pod::initialize_pod_container_statuses;
-use crate::pod::Pod;
-use crate::provider::Provider;
+use crate::pod::{
+    catch_panic, diff_pods, quarantine_and_fail, quarantined, Pod, PodChange, PodIntentLog,
+    PodWork, PodWorkQueue,
+};
+use crate::provider::{NotImplementedError, Provider};
+use crate::state::replay::{self, Recorded};
+use futures::StreamExt;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::ObjectState;
 use krator::SharedState;
 use krator::{Manifest, Operator};
 use kube::Api;
 use std::sync::Arc;
+use tracing::{debug, info, warn};
 
 pub(crate) struct PodOperator<P: Provider> {
     provider: Arc<P>,
     client: kube::Client,
+    intent_log: Arc<PodIntentLog>,
+    work_queue: Arc<PodWorkQueue>,
 }
 
 impl<P: Provider> PodOperator<P> {
-    pub fn new(provider: Arc<P>, client: kube::Client) -> Self {
-        PodOperator { provider, client }
+    pub fn new(provider: Arc<P>, client: kube::Client, intent_log: Arc<PodIntentLog>) -> Self {
+        let work_queue = Arc::new(PodWorkQueue::default());
+        crate::pod::set_event_client(client.clone());
+        tokio::spawn(run_pod_work(
+            provider.clone(),
+            client.clone(),
+            work_queue.clone(),
+        ));
+        PodOperator {
+            provider,
+            client,
+            intent_log,
+            work_queue,
+        }
     }
 }
 
@@ -24,11 +45,33 @@ impl<P: Provider> Operator for PodOperator<P> {
     type Manifest = crate::pod::Pod;
     type Status = crate::pod::Status;
     type ObjectState = P::PodState;
-    type InitialState = P::InitialState;
-    type DeletedState = P::TerminatedState;
+    type InitialState = Recorded<P::PodState, P::InitialState>;
+    type DeletedState = Recorded<P::PodState, P::TerminatedState>;
 
     async fn initialize_object_state(&self, manifest: &Pod) -> anyhow::Result<P::PodState> {
-        self.provider.initialize_pod_state(manifest).await
+        fault::inject(
+            FaultPoint::Provider,
+            &format!("initializePodState {}", pod_target(manifest)),
+        )
+        .await?;
+        // New pods are admitted through the work queue, so they wait behind pods that are being
+        // deleted. Should the queue go away, the pod is admitted anyway
+        let (admitted, admission) = tokio::sync::oneshot::channel();
+        self.work_queue
+            .push(PodWork::Create(manifest.clone(), admitted))
+            .await;
+        admission.await.ok();
+        match catch_panic(self.provider.initialize_pod_state(manifest)).await {
+            Ok(pod_state) => pod_state,
+            Err(message) => {
+                // The pod has no state machine yet to report its status
+                quarantine_and_fail(&self.client, manifest, &message).await;
+                Err(anyhow::anyhow!(
+                    "Provider panicked initializing pod state: {}",
+                    message
+                ))
+            }
+        }
     }
 
     async fn shared_state(&self) -> SharedState<<P::PodState as ObjectState>::SharedState> {
@@ -40,11 +83,146 @@ impl<P: Provider> Operator for PodOperator<P> {
         let namespace = initial_manifest.namespace();
         let name = initial_manifest.name().to_string();
         let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
+        self.intent_log.added(&initial_manifest).await;
+        replay::record_manifest(&initial_manifest);
+
+        // The stream of updates ends once the pod is deregistered
+        tokio::spawn(route_pod_updates(
+            self.work_queue.clone(),
+            self.intent_log.clone(),
+            manifest.clone(),
+        ));
 
         initialize_pod_container_statuses(name, manifest, &api).await
     }
 
-    async fn deregistration_hook(&self, _manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+    async fn deregistration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+        let pod = manifest.latest();
+        self.intent_log.finished(&pod).await;
+        crate::pod::forget_status(&pod);
+        crate::pod::release_quarantine(&pod);
         Ok(())
     }
 }
+
+/// Names a pod in the targets of injected faults.
+fn pod_target(pod: &Pod) -> String {
+    format!("{}/{}", pod.namespace(), pod.name())
+}
+
+/// Classifies every update to a pod and queues the work it needs.
+async fn route_pod_updates(
+    work_queue: Arc<PodWorkQueue>,
+    intent_log: Arc<PodIntentLog>,
+    mut manifest: Manifest<Pod>,
+) {
+    let mut previous = manifest.latest();
+    while let Some(latest) = manifest.next().await {
+        let changes = diff_pods(&previous, &latest);
+        // Status updates are ignored, as they don't change what the Kubelet has to do
+        let deleted =
+            latest.deletion_timestamp().is_some() && previous.deletion_timestamp().is_none();
+        if !changes.is_empty() || deleted {
+            intent_log.updated(&latest).await;
+            replay::record_manifest(&latest);
+        }
+        if deleted {
+            debug!(pod_name = latest.name(), "Pod was marked for deletion");
+            work_queue.push(PodWork::Delete(latest.clone())).await;
+        } else if !changes.is_empty() {
+            debug!(pod_name = latest.name(), ?changes, "Pod was updated");
+            work_queue
+                .push(PodWork::Update(latest.clone(), changes))
+                .await;
+        }
+        previous = latest;
+    }
+}
+
+/// Hands queued pod work to the provider and admits new pods, one piece at a time. Updates to
+/// quarantined pods are dropped, and a pod whose work panics is quarantined.
+async fn run_pod_work<P: Provider>(
+    provider: Arc<P>,
+    client: kube::Client,
+    work_queue: Arc<PodWorkQueue>,
+) {
+    loop {
+        let work = work_queue.pop().await;
+        let pod = work.pod().clone();
+        if matches!(work, PodWork::Update(..)) && quarantined(&pod).is_some() {
+            debug!(pod_name = pod.name(), "Ignoring update to quarantined pod");
+            continue;
+        }
+        if let Err(message) = catch_panic(handle_pod_work(&*provider, work)).await {
+            quarantine_and_fail(&client, &pod, &message).await;
+        }
+    }
+}
+
+async fn handle_pod_work<P: Provider>(provider: &P, work: PodWork) {
+    match work {
+        PodWork::Create(pod, admitted) => {
+            debug!(pod_name = pod.name(), "Admitting pod");
+            admitted.send(()).ok();
+        }
+        PodWork::Delete(pod) => {
+            let terminating = match fault::inject(
+                FaultPoint::Provider,
+                &format!("podTerminating {}", pod_target(&pod)),
+            )
+            .await
+            {
+                Ok(()) => provider.pod_terminating(&pod).await,
+                Err(fault) => Err(fault.into()),
+            };
+            if let Err(e) = terminating {
+                warn!(error = %e, pod_name = pod.name(), "Unable to start terminating pod");
+            }
+        }
+        PodWork::Update(pod, changes) => route_pod_update(provider, &pod, &changes).await,
+    }
+}
+
+async fn route_pod_update<P: Provider>(provider: &P, pod: &Pod, changes: &[PodChange]) {
+    if changes.contains(&PodChange::Metadata) {
+        if let Err(e) = provider.update_pod_metadata(pod).await {
+            warn!(error = %e, pod_name = pod.name(), "Unable to update pod metadata");
+        }
+    }
+
+    let spec_changes: Vec<PodChange> = changes
+        .iter()
+        .filter(|c| matches!(c, PodChange::Tolerations | PodChange::ActiveDeadline))
+        .cloned()
+        .collect();
+    if !spec_changes.is_empty() {
+        if let Err(e) = provider.update_pod_spec(pod, &spec_changes).await {
+            warn!(error = %e, pod_name = pod.name(), "Unable to update pod spec");
+        }
+    }
+
+    for change in changes {
+        let container = match change.container() {
+            Some(container) => container,
+            None => continue,
+        };
+        match provider.update_container(pod, container, change).await {
+            Ok(()) => (),
+            Err(e) if e.is::<NotImplementedError>() => info!(
+                pod_name = pod.name(),
+                container, ?change,
+                "Provider cannot update containers in place, the container keeps running with its previous configuration"
+            ),
+            Err(e) => {
+                warn!(error = %e, pod_name = pod.name(), container, "Unable to update container")
+            }
+        }
+    }
+
+    if changes.contains(&PodChange::Other) {
+        warn!(
+            pod_name = pod.name(),
+            "Pod spec changed in a way that cannot be applied to a running pod, ignoring the change"
+        );
+    }
+}
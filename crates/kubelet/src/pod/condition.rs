@@ -0,0 +1,328 @@
+//! Pod conditions
+
+use super::Pod;
+use crate::environment::Clock;
+use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
+use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+/// The types of pod condition reported by the Kubelet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConditionType {
+    /// The pod has been scheduled to a node.
+    PodScheduled,
+    /// All init containers have completed successfully.
+    Initialized,
+    /// All containers in the pod are ready.
+    ContainersReady,
+    /// The pod is able to serve requests.
+    Ready,
+}
+
+impl std::fmt::Display for ConditionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConditionType::PodScheduled => "PodScheduled",
+            ConditionType::Initialized => "Initialized",
+            ConditionType::ContainersReady => "ContainersReady",
+            ConditionType::Ready => "Ready",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How far a pod has progressed towards being ready. This is used to compute the full set of pod
+/// conditions from a state's `status` method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Readiness {
+    /// Init containers have not all completed yet.
+    NotInitialized,
+    /// Init containers have completed, but not all containers are ready.
+    NotReady,
+    /// All containers are ready.
+    Ready,
+    /// All containers in the pod have run to completion.
+    Completed,
+}
+
+/// Create a single pod condition. The `lastTransitionTime` of the condition the pod currently
+/// reports is preserved if its status has not changed, and is read from `clock` otherwise.
+pub fn make_condition(
+    pod: &Pod,
+    condition_type: ConditionType,
+    status: bool,
+    reason: Option<&str>,
+    message: Option<&str>,
+    clock: &dyn Clock,
+) -> KubePodCondition {
+    let type_ = condition_type.to_string();
+    let status = if status { "True" } else { "False" }.to_string();
+    let last_transition_time = find_condition(pod, &type_)
+        .filter(|c| c.status == status)
+        .and_then(|c| c.last_transition_time.clone())
+        .unwrap_or_else(|| Time(clock.now()));
+    KubePodCondition {
+        type_,
+        status,
+        reason: reason.map(|r| r.to_string()),
+        message: message.map(|m| m.to_string()),
+        last_transition_time: Some(last_transition_time),
+        last_probe_time: None,
+    }
+}
+
+/// Create the `PodScheduled`, `Initialized`, `ContainersReady` and `Ready` conditions for a pod
+/// with the given readiness, timestamping transitions with `clock`.
+pub fn make_conditions(
+    pod: &Pod,
+    readiness: Readiness,
+    clock: &dyn Clock,
+) -> Vec<KubePodCondition> {
+    let status = pod.as_kube_pod().status.clone().unwrap_or_default();
+
+    let initialized = match readiness {
+        // Pods without init containers are initialized straight away
+        Readiness::NotInitialized if !pod.init_containers().is_empty() => {
+            let incomplete = unfinished_containers(&status.init_container_statuses, |s| {
+                s.state
+                    .as_ref()
+                    .and_then(|s| s.terminated.as_ref())
+                    .map(|t| t.exit_code == 0)
+                    .unwrap_or(false)
+            });
+            make_condition(
+                pod,
+                ConditionType::Initialized,
+                false,
+                Some("ContainersNotInitialized"),
+                Some(&format!(
+                    "containers with incomplete status: [{}]",
+                    incomplete
+                )),
+                clock,
+            )
+        }
+        _ => make_condition(pod, ConditionType::Initialized, true, None, None, clock),
+    };
+
+    let containers_ready = match readiness {
+        Readiness::Ready => {
+            make_condition(pod, ConditionType::ContainersReady, true, None, None, clock)
+        }
+        Readiness::Completed => make_condition(
+            pod,
+            ConditionType::ContainersReady,
+            false,
+            Some("PodCompleted"),
+            None,
+            clock,
+        ),
+        Readiness::NotInitialized | Readiness::NotReady => {
+            let unready = unfinished_containers(&status.container_statuses, |s| s.ready);
+            make_condition(
+                pod,
+                ConditionType::ContainersReady,
+                false,
+                Some("ContainersNotReady"),
+                Some(&format!("containers with unready status: [{}]", unready)),
+                clock,
+            )
+        }
+    };
+
+    // The pod is ready once all of its containers are and every readiness gate is satisfied
+    let ready = match (
+        containers_ready.status == "True",
+        unsatisfied_readiness_gates(pod),
+    ) {
+        (true, None) => make_condition(pod, ConditionType::Ready, true, None, None, clock),
+        (true, Some(message)) => make_condition(
+            pod,
+            ConditionType::Ready,
+            false,
+            Some("ReadinessGatesNotReady"),
+            Some(&message),
+            clock,
+        ),
+        (false, _) => make_condition(
+            pod,
+            ConditionType::Ready,
+            false,
+            containers_ready.reason.as_deref(),
+            containers_ready.message.as_deref(),
+            clock,
+        ),
+    };
+
+    vec![
+        make_condition(pod, ConditionType::PodScheduled, true, None, None, clock),
+        initialized,
+        containers_ready,
+        ready,
+    ]
+}
+
+/// Create the `Ready` condition for a pod with the given readiness, but only if it differs from
+/// the one the pod currently reports. This is used to react to conditions set by external
+/// controllers for the pod's readiness gates.
+pub fn updated_ready_condition(
+    pod: &Pod,
+    readiness: Readiness,
+    clock: &dyn Clock,
+) -> Option<KubePodCondition> {
+    let ready = make_conditions(pod, readiness, clock)
+        .into_iter()
+        .find(|c| c.type_ == ConditionType::Ready.to_string())?;
+    let current = find_condition(pod, &ready.type_);
+    match current {
+        Some(c) if c.status == ready.status && c.reason == ready.reason => None,
+        _ => Some(ready),
+    }
+}
+
+/// Returns true if the pod specifies any readiness gates.
+pub fn has_readiness_gates(pod: &Pod) -> bool {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .map(|s| !s.readiness_gates.is_empty())
+        .unwrap_or(false)
+}
+
+/// Returns a message describing the readiness gates of the pod that are not satisfied, or `None`
+/// if all of them are.
+fn unsatisfied_readiness_gates(pod: &Pod) -> Option<String> {
+    let gates = match pod.as_kube_pod().spec.as_ref() {
+        Some(spec) => &spec.readiness_gates,
+        None => return None,
+    };
+    let unsatisfied: Vec<String> = gates
+        .iter()
+        .filter_map(|gate| match find_condition(pod, &gate.condition_type) {
+            None => Some(format!(
+                "corresponding condition of pod readiness gate {:?} does not exist.",
+                gate.condition_type
+            )),
+            Some(c) if c.status != "True" => Some(format!(
+                "the status of pod readiness gate {:?} is not \"True\", but {}",
+                gate.condition_type, c.status
+            )),
+            Some(_) => None,
+        })
+        .collect();
+    if unsatisfied.is_empty() {
+        None
+    } else {
+        Some(unsatisfied.join(", "))
+    }
+}
+
+fn find_condition<'a>(pod: &'a Pod, condition_type: &str) -> Option<&'a KubePodCondition> {
+    pod.as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.iter().find(|c| c.type_ == condition_type))
+}
+
+/// Returns the space separated names of containers whose status does not satisfy `finished`.
+fn unfinished_containers(
+    statuses: &[KubeContainerStatus],
+    finished: impl Fn(&KubeContainerStatus) -> bool,
+) -> String {
+    statuses
+        .iter()
+        .filter(|s| !finished(s))
+        .map(|s| s.name.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::environment::{ManualClock, SystemClock};
+    use chrono::Utc;
+    use k8s_openapi::api::core::v1::{
+        Pod as KubePod, PodReadinessGate, PodSpec, PodStatus as KubePodStatus,
+    };
+
+    fn pod_with_conditions(conditions: Vec<KubePodCondition>) -> Pod {
+        Pod::from(KubePod {
+            status: Some(KubePodStatus {
+                conditions,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn gated_pod(conditions: Vec<KubePodCondition>) -> Pod {
+        let mut pod = pod_with_conditions(conditions).into_kube_pod();
+        pod.spec = Some(PodSpec {
+            readiness_gates: vec![PodReadinessGate {
+                condition_type: "example.com/load-balancer".to_string(),
+            }],
+            ..Default::default()
+        });
+        Pod::from(pod)
+    }
+
+    #[test]
+    fn test_transition_time_is_preserved() {
+        let clock = ManualClock::new(Utc::now());
+        let then = Time(clock.now() - chrono::Duration::hours(1));
+        let pod = pod_with_conditions(vec![KubePodCondition {
+            type_: "Initialized".to_string(),
+            status: "True".to_string(),
+            last_transition_time: Some(then.clone()),
+            ..Default::default()
+        }]);
+
+        let unchanged = make_condition(&pod, ConditionType::Initialized, true, None, None, &clock);
+        assert_eq!(unchanged.last_transition_time, Some(then.clone()));
+
+        let changed = make_condition(&pod, ConditionType::Initialized, false, None, None, &clock);
+        assert_eq!(changed.last_transition_time, Some(Time(clock.now())));
+    }
+
+    #[test]
+    fn test_ready_follows_containers_ready() {
+        let pod = pod_with_conditions(vec![]);
+        let conditions = make_conditions(&pod, Readiness::NotReady, &SystemClock);
+        let status_of = |t: &str| {
+            conditions
+                .iter()
+                .find(|c| c.type_ == t)
+                .map(|c| c.status.clone())
+                .unwrap()
+        };
+        assert_eq!(status_of("PodScheduled"), "True");
+        assert_eq!(status_of("Initialized"), "True");
+        assert_eq!(status_of("ContainersReady"), "False");
+        assert_eq!(status_of("Ready"), "False");
+    }
+
+    #[test]
+    fn test_readiness_gates_block_ready() {
+        let pod = gated_pod(vec![]);
+        let ready = updated_ready_condition(&pod, Readiness::Ready, &SystemClock).unwrap();
+        assert_eq!(ready.status, "False");
+        assert_eq!(ready.reason.as_deref(), Some("ReadinessGatesNotReady"));
+
+        let pod = gated_pod(vec![
+            KubePodCondition {
+                type_: "example.com/load-balancer".to_string(),
+                status: "True".to_string(),
+                ..Default::default()
+            },
+            ready,
+        ]);
+        let ready = updated_ready_condition(&pod, Readiness::Ready, &SystemClock).unwrap();
+        assert_eq!(ready.status, "True");
+        assert_eq!(ready.reason, None);
+
+        let pod = gated_pod(vec![ready]);
+        assert!(has_readiness_gates(&pod));
+        assert!(updated_ready_condition(&pod, Readiness::NotReady, &SystemClock).is_some());
+    }
+}
@@ -0,0 +1,162 @@
+//! Classifies the changes between two versions of a pod.
+//!
+//! Most of a pod's spec is immutable once it has been created, but the API does allow a few
+//! fields to be changed in place: labels and annotations, container images, tolerations (which
+//! can only be added) and the active deadline. Telling those apart lets the Kubelet hand each kind
+//! of change to a targeted provider hook instead of treating every update the same way.
+use std::collections::BTreeSet;
+
+use k8s_openapi::api::core::v1::{Container as KubeContainer, PodSpec};
+
+use super::Pod;
+
+/// A single kind of change between two versions of a pod.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PodChange {
+    /// The labels or annotations of the pod changed
+    Metadata,
+    /// The image of the named container (or init container) changed
+    Image(String),
+    /// The environment of the named container (or init container) changed. The API doesn't
+    /// allow this for pods, but mirrored static pods and other direct sources of manifests can
+    Env(String),
+    /// Tolerations were added to the pod
+    Tolerations,
+    /// The `activeDeadlineSeconds` of the pod changed
+    ActiveDeadline,
+    /// Some other part of the spec changed, which can't be applied to a running pod
+    Other,
+}
+
+impl PodChange {
+    /// Whether the change affects a single container rather than the pod as a whole.
+    pub fn container(&self) -> Option<&str> {
+        match self {
+            PodChange::Image(name) | PodChange::Env(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the changes from `old` to `new`, without duplicates and in a stable order. Changes to
+/// the status or to bookkeeping metadata such as the resource version are ignored, so an empty
+/// result means nothing the provider cares about changed.
+pub fn diff_pods(old: &Pod, new: &Pod) -> Vec<PodChange> {
+    let mut changes = BTreeSet::new();
+    if old.labels() != new.labels() || old.annotations() != new.annotations() {
+        changes.insert(PodChange::Metadata);
+    }
+
+    let default_spec = PodSpec::default();
+    let old_spec = old.kube_pod.spec.as_ref().unwrap_or(&default_spec);
+    let new_spec = new.kube_pod.spec.as_ref().unwrap_or(&default_spec);
+    diff_containers(&old_spec.containers, &new_spec.containers, &mut changes);
+    diff_containers(
+        &old_spec.init_containers,
+        &new_spec.init_containers,
+        &mut changes,
+    );
+    if old_spec.tolerations != new_spec.tolerations {
+        changes.insert(PodChange::Tolerations);
+    }
+    if old_spec.active_deadline_seconds != new_spec.active_deadline_seconds {
+        changes.insert(PodChange::ActiveDeadline);
+    }
+
+    // Anything left over once the changes above are applied to the old spec is something else
+    let mut patched = old_spec.clone();
+    patched.tolerations = new_spec.tolerations.clone();
+    patched.active_deadline_seconds = new_spec.active_deadline_seconds;
+    for (old, new) in patched
+        .containers
+        .iter_mut()
+        .chain(patched.init_containers.iter_mut())
+        .zip(new_spec.containers.iter().chain(&new_spec.init_containers))
+    {
+        if old.name == new.name {
+            old.image = new.image.clone();
+            old.env = new.env.clone();
+        }
+    }
+    if &patched != new_spec {
+        changes.insert(PodChange::Other);
+    }
+
+    changes.into_iter().collect()
+}
+
+fn diff_containers(
+    old: &[KubeContainer],
+    new: &[KubeContainer],
+    changes: &mut BTreeSet<PodChange>,
+) {
+    for new in new {
+        // Containers can't be added or removed, which is caught as some other change
+        let old = match old.iter().find(|c| c.name == new.name) {
+            Some(old) => old,
+            None => continue,
+        };
+        if old.image != new.image {
+            changes.insert(PodChange::Image(new.name.clone()));
+        }
+        if old.env != new.env {
+            changes.insert(PodChange::Env(new.name.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(value: serde_json::Value) -> Pod {
+        Pod::from(serde_json::from_value::<KubePod>(value).unwrap())
+    }
+
+    fn base() -> serde_json::Value {
+        serde_json::json!({
+            "metadata": {"name": "web", "labels": {"app": "web"}, "resourceVersion": "1"},
+            "spec": {
+                "containers": [
+                    {"name": "server", "image": "example.com/server:v1"},
+                    {"name": "sidecar", "image": "example.com/sidecar:v1"}
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn test_diff_pods() {
+        let old = pod(base());
+        let mut bookkeeping = base();
+        bookkeeping["metadata"]["resourceVersion"] = "2".into();
+        bookkeeping["status"] = serde_json::json!({"phase": "Running"});
+        assert!(diff_pods(&old, &pod(bookkeeping)).is_empty());
+
+        let mut updated = base();
+        updated["metadata"]["labels"]["tier"] = "frontend".into();
+        updated["spec"]["containers"][1]["image"] = "example.com/sidecar:v2".into();
+        updated["spec"]["containers"][0]["env"] =
+            serde_json::json!([{"name": "DEBUG", "value": "1"}]);
+        updated["spec"]["tolerations"] =
+            serde_json::json!([{"key": "example.com/spot", "operator": "Exists"}]);
+        assert_eq!(
+            diff_pods(&old, &pod(updated)),
+            vec![
+                PodChange::Metadata,
+                PodChange::Image("sidecar".into()),
+                PodChange::Env("server".into()),
+                PodChange::Tolerations,
+            ]
+        );
+
+        let mut other = base();
+        other["spec"]["containers"][0]["args"] = serde_json::json!(["--verbose"]);
+        other["spec"]["containers"][0]["image"] = "example.com/server:v2".into();
+        assert_eq!(
+            diff_pods(&old, &pod(other)),
+            vec![PodChange::Image("server".into()), PodChange::Other]
+        );
+    }
+}
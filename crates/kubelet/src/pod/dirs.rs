@@ -0,0 +1,259 @@
+//! The directories the Kubelet keeps on the host for a pod.
+//!
+//! A pod gets a directory beneath the provider's volume directory, which its volumes are mounted
+//! in, and a directory beneath the pod log root when container logs are also written in the
+//! kubelet's layout:
+//!
+//! ```text
+//! <volume root>/
+//!   <namespace>_<pod name>/
+//!     <volume name>/
+//! <log root>/
+//!   <namespace>_<pod name>_<uid>/
+//!     <container name>/
+//! ```
+//!
+//! Other per-pod directories, such as the pod's scratch space and cgroup, are named with
+//! [`pod_dir`] as well.
+//!
+//! Kubelets before this layout named a pod's volume directory `<pod name>-<namespace>`. Volumes
+//! they mounted there can outlive an upgrade, so that directory is still cleaned up along with the
+//! pod's other directories.
+//!
+//! Every name that comes from a pod, such as those of the pod itself, its containers and volumes,
+//! and the paths of the items projected into volumes, is joined onto these directories with
+//! [`join_name`] or [`join_relative`], which refuse anything that would end up outside of them.
+use std::path::{Component, Path, PathBuf};
+
+use tracing::warn;
+
+use crate::log::layout;
+use crate::pod::{Pod, PodKey};
+
+/// The directories of a single pod.
+#[derive(Clone, Debug)]
+pub struct PodDirs {
+    volumes: Option<PathBuf>,
+    legacy_volumes: Option<PathBuf>,
+    logs: Option<PathBuf>,
+}
+
+impl PodDirs {
+    /// Finds the directories of the pod beneath the given roots, without creating them. A pod
+    /// only has the directories whose root is given.
+    pub fn new(
+        pod: &Pod,
+        volume_root: Option<&Path>,
+        log_root: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let volumes = volume_root
+            .map(|root| pod_dir(root, &PodKey::from(pod)))
+            .transpose()?;
+        let legacy_volumes = volume_root
+            .map(|root| join_name(root, &format!("{}-{}", pod.name(), pod.namespace())))
+            .transpose()?;
+        let logs = log_root
+            .map(|root| join_name(root, &layout::pod_log_dir_name(pod)))
+            .transpose()?;
+        Ok(PodDirs {
+            volumes,
+            legacy_volumes,
+            logs,
+        })
+    }
+
+    /// The directory the pod's volumes are mounted in, if it has one.
+    pub fn volumes(&self) -> Option<&Path> {
+        self.volumes.as_deref()
+    }
+
+    /// The directory earlier Kubelets mounted the pod's volumes in, if it has a volume directory.
+    /// Volumes mounted there before an upgrade may still be, but nothing new is mounted there.
+    pub fn legacy_volumes(&self) -> Option<&Path> {
+        self.legacy_volumes.as_deref()
+    }
+
+    /// The directory of the pod's logs in the kubelet's layout, if they are written there.
+    pub fn logs(&self) -> Option<&Path> {
+        self.logs.as_deref()
+    }
+
+    /// Creates the log file for a new run of a container, returning its path, if the pod's logs
+    /// are written in the kubelet's layout.
+    pub async fn create_container_log(&self, container: &str) -> anyhow::Result<Option<PathBuf>> {
+        let logs = match &self.logs {
+            Some(logs) => logs,
+            None => return Ok(None),
+        };
+        let dir = join_name(logs, container)?;
+        layout::create_container_log_in(&dir).await.map(Some)
+    }
+
+    /// Removes the pod's directories, including the volume directory of earlier Kubelets. Volumes
+    /// have to be unmounted first: anything left in a volume directory other than empty
+    /// directories is kept, in case it is a volume that is still mounted, and reported as an
+    /// error. Removing directories that are already gone succeeds, so this can be called again
+    /// after a failure.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        for volumes in self.volumes.iter().chain(self.legacy_volumes.iter()) {
+            let volumes = volumes.clone();
+            tokio::task::spawn_blocking(move || remove_volume_dir(&volumes)).await??;
+        }
+        if let Some(logs) = &self.logs {
+            layout::remove_pod_logs(logs).await?;
+        }
+        Ok(())
+    }
+}
+
+fn remove_volume_dir(dir: &Path) -> anyhow::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut remaining = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if let Err(e) = std::fs::remove_dir(&path) {
+            warn!(error = %e, path = %path.display(), "Leaving pod volume directory in place");
+            remaining.push(path.display().to_string());
+        }
+    }
+    if !remaining.is_empty() {
+        anyhow::bail!(
+            "Pod volume directory {} still has contents that may be mounted: {}",
+            dir.display(),
+            remaining.join(", ")
+        );
+    }
+    match std::fs::remove_dir(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The directory of a pod beneath `root`. Pod names and namespaces can't contain underscores, so
+/// no two pods share a directory.
+pub fn pod_dir(root: &Path, pod: &PodKey) -> anyhow::Result<PathBuf> {
+    join_name(root, &format!("{}_{}", pod.namespace(), pod.name()))
+}
+
+/// Joins a single file or directory name onto a directory, refusing names that are empty, are
+/// `.` or `..`, or contain a path separator.
+pub fn join_name(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let mut components = Path::new(name).components();
+    let valid = !name.contains(|c| c == '/' || c == '\\' || c == '\0')
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+    if !valid {
+        anyhow::bail!("{:?} is not a valid file name", name);
+    }
+    Ok(dir.join(name))
+}
+
+/// Joins a relative path of `/` separated names onto a directory, refusing paths that are
+/// absolute or would leave the directory.
+pub fn join_relative(dir: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    if path.is_empty() || path.starts_with('/') {
+        anyhow::bail!("{:?} is not a relative path", path);
+    }
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .try_fold(dir.to_owned(), |joined, name| join_name(&joined, name))
+        .map_err(|_| anyhow::anyhow!("{:?} is not a relative path within its directory", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(name: &str) -> Pod {
+        pod_in(name, "default")
+    }
+
+    fn pod_in(name: &str, namespace: &str) -> Pod {
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": { "name": name, "namespace": namespace, "uid": "1234" }
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_joins_stay_within_dir() {
+        let dir = Path::new("/data");
+        assert_eq!(join_name(dir, "web").unwrap(), dir.join("web"));
+        for name in &["", ".", "..", "a/b", "..\\b", "/etc"] {
+            assert!(join_name(dir, name).is_err(), "{:?} was joined", name);
+        }
+        assert_eq!(
+            join_relative(dir, "config/app.toml").unwrap(),
+            dir.join("config").join("app.toml")
+        );
+        for path in &["", "/etc/passwd", "../secret", "config/../../secret"] {
+            assert!(join_relative(dir, path).is_err(), "{:?} was joined", path);
+        }
+        assert!(PodDirs::new(&pod("../web"), Some(dir), None).is_err());
+    }
+
+    #[test]
+    fn test_pods_do_not_share_dirs() {
+        let dir = Path::new("/data");
+        let volumes = |pod: &Pod| {
+            PodDirs::new(pod, Some(dir), None)
+                .unwrap()
+                .volumes()
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(volumes(&pod("web")), dir.join("default_web"));
+        assert_ne!(volumes(&pod_in("a-b", "c")), volumes(&pod_in("a", "b-c")));
+    }
+
+    #[tokio::test]
+    async fn test_remove_is_idempotent_and_keeps_contents() {
+        let root = tempfile::tempdir().unwrap();
+        let logs = tempfile::tempdir().unwrap();
+        let dirs = PodDirs::new(&pod("web"), Some(root.path()), Some(logs.path())).unwrap();
+        let volumes = dirs.volumes().unwrap().to_owned();
+        std::fs::create_dir_all(volumes.join("unmounted")).unwrap();
+        let log = dirs.create_container_log("server").await.unwrap().unwrap();
+        assert!(log.starts_with(dirs.logs().unwrap()));
+
+        std::fs::create_dir_all(volumes.join("mounted")).unwrap();
+        std::fs::write(volumes.join("mounted").join("file"), "data").unwrap();
+        assert!(dirs.remove().await.is_err());
+        assert!(volumes.join("mounted").join("file").exists());
+        assert!(!volumes.join("unmounted").exists());
+
+        std::fs::remove_dir_all(volumes.join("mounted")).unwrap();
+        dirs.remove().await.unwrap();
+        assert!(!volumes.exists());
+        assert!(!dirs.logs().unwrap().exists());
+        dirs.remove().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_cleans_up_legacy_volume_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = PodDirs::new(&pod("web"), Some(root.path()), None).unwrap();
+        let legacy = dirs.legacy_volumes().unwrap().to_owned();
+        assert_eq!(legacy, root.path().join("web-default"));
+        std::fs::create_dir_all(legacy.join("unmounted")).unwrap();
+        std::fs::create_dir_all(legacy.join("mounted")).unwrap();
+        std::fs::write(legacy.join("mounted").join("file"), "data").unwrap();
+
+        assert!(dirs.remove().await.is_err());
+        assert!(legacy.join("mounted").join("file").exists());
+        assert!(!legacy.join("unmounted").exists());
+
+        std::fs::remove_dir_all(legacy.join("mounted")).unwrap();
+        dirs.remove().await.unwrap();
+        assert!(!legacy.exists());
+    }
+}
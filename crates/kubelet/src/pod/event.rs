@@ -0,0 +1,82 @@
+//! Records Events about pods, for `kubectl describe` and anything else watching them.
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Event as KubeEvent, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PostParams};
+use tracing::warn;
+
+use super::Pod;
+
+/// The type of Events about normal operation.
+pub const EVENT_TYPE_NORMAL: &str = "Normal";
+/// The type of Events about something going wrong.
+pub const EVENT_TYPE_WARNING: &str = "Warning";
+
+/// Records an Event about a pod. Events are informational, so failing to record one is only
+/// logged.
+pub async fn record_event(
+    client: &kube::Client,
+    pod: &Pod,
+    type_: &str,
+    reason: &str,
+    message: &str,
+) {
+    let events: Api<KubeEvent> = Api::namespaced(client.clone(), pod.namespace());
+    let node_name = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.node_name.clone());
+    let involved_object = ObjectReference {
+        api_version: Some("v1".to_owned()),
+        kind: Some("Pod".to_owned()),
+        name: Some(pod.name().to_owned()),
+        namespace: Some(pod.namespace().to_owned()),
+        uid: pod.as_kube_pod().metadata.uid.clone(),
+        ..Default::default()
+    };
+    let event = new_event(
+        involved_object,
+        node_name,
+        type_,
+        reason,
+        message,
+        Utc::now(),
+    );
+    if let Err(e) = events.create(&PostParams::default(), &event).await {
+        warn!(error = %e, reason, "Unable to record pod event");
+    }
+}
+
+/// An Event reported by the Kubelet on the given host about an object, named after the object.
+pub(crate) fn new_event(
+    involved_object: ObjectReference,
+    host: Option<String>,
+    type_: &str,
+    reason: &str,
+    message: &str,
+    timestamp: DateTime<Utc>,
+) -> KubeEvent {
+    let timestamp = Time(timestamp);
+    KubeEvent {
+        metadata: ObjectMeta {
+            generate_name: involved_object
+                .name
+                .as_ref()
+                .map(|name| format!("{}.", name)),
+            ..Default::default()
+        },
+        involved_object,
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        type_: Some(type_.to_owned()),
+        count: Some(1),
+        first_timestamp: Some(timestamp.clone()),
+        last_timestamp: Some(timestamp),
+        source: Some(EventSource {
+            component: Some("krustlet".to_owned()),
+            host,
+        }),
+        ..Default::default()
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
@@ -5,9 +7,9 @@ use tracing::{debug, error, info};
 use crate::container::{
     ContainerKey, ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
 };
-use crate::handle::StopHandler;
+use crate::handle::{PodRuntimeInfo, StopHandler};
 use crate::log::{HandleFactory, Sender};
-use crate::pod::Pod;
+use crate::pod::{Pod, PodKey};
 use crate::provider::ProviderError;
 
 /// Handle is the top level handle into managing a pod. It manages updating
@@ -60,6 +62,69 @@ impl<H: StopHandler, F> Handle<H, F> {
         handle.output(sender).await
     }
 
+    /// Attaches a client to the specified container, streaming its output from now on into the
+    /// given sender and, if `stdin` is given, writing what the client sends to the container's
+    /// stdin until the client detaches.
+    pub async fn attach<R>(
+        &self,
+        container_name: &str,
+        stdin: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
+        sender: Sender,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+        F: HandleFactory<R>,
+    {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut_by_name(container_name.to_owned())
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            })?;
+        if let Some(mut stdin) = stdin {
+            let attached = handle.stdin().ok_or_else(|| {
+                anyhow::anyhow!("container {} does not accept stdin", container_name)
+            })?;
+            // Writes block while the container is behind on reading its stdin, which holds up
+            // the client's stream rather than buffering what it sends
+            tokio::task::spawn_blocking(move || {
+                while let Some(data) = stdin.blocking_recv() {
+                    if attached.write(data).is_err() {
+                        break;
+                    }
+                }
+                attached.detach();
+            });
+        }
+        handle.output(sender).await
+    }
+
+    /// The number of bytes of logs written by each of the pod's containers, by container name,
+    /// for the containers whose log sizes are known.
+    pub async fn log_usage<R>(&self) -> HashMap<String, u64>
+    where
+        F: HandleFactory<R>,
+    {
+        let handles = self.container_handles.read().await;
+        handles
+            .iter()
+            .filter_map(|(key, handle)| Some((key.name(), handle.log_size()?)))
+            .collect()
+    }
+
+    /// Describes the runtime handles of the pod's containers.
+    pub async fn runtime_info(&self) -> PodRuntimeInfo {
+        let handles = self.container_handles.read().await;
+        PodRuntimeInfo {
+            pod: PodKey::from(&self.pod),
+            containers: handles
+                .iter()
+                .filter_map(|(key, handle)| Some((key.name(), handle.runtime_info()?)))
+                .collect(),
+        }
+    }
+
     /// Signal the pod and all its running containers to stop and wait for them
     /// to complete.
     pub async fn stop(&self) -> anyhow::Result<()> {
@@ -81,6 +146,19 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 
+    /// Signal one of the pod's containers to stop, leaving the others running.
+    pub async fn stop_container(&self, container_name: &str) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut_by_name(container_name.to_owned())
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: container_name.to_owned(),
+            })?;
+        info!(container_name, "Stopping container");
+        handle.stop().await
+    }
+
     /// Wait for all containers in the pod to complete
     pub async fn wait(&mut self) -> anyhow::Result<()> {
         let mut handles = self.container_handles.write().await;
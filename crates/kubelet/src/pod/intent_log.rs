@@ -0,0 +1,345 @@
+//! A write-ahead log of the work the Kubelet has started on its pods.
+//!
+//! Every pod the Kubelet is handed is recorded when it is added, updated and deleted, and once
+//! the Kubelet is finished with it. If the Kubelet crashes part way through handling a pod, the
+//! log tells it on restart which pods may have been left half-created or half-removed, so that
+//! the provider can clean them up before the Kubelet connects to the API server again. The API
+//! server delivers any of those pods that still exist again afterwards, which starts them over.
+//!
+//! Each entry holds the whole manifest of the pod, so once the log has grown to
+//! `COMPACT_AFTER_ENTRIES` entries and most of them are about pods the Kubelet has finished
+//! with, it is rewritten with only the latest entry of each unfinished pod.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::{Pod, PodKey};
+use crate::node::problem;
+
+/// The name of the log file in the Kubelet's data directory
+pub(crate) const INTENT_LOG_FILE: &str = "pod-intents.log";
+
+/// The number of entries the log may grow to before it is compacted
+const COMPACT_AFTER_ENTRIES: usize = 1024;
+
+/// A single entry of the log.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "intent", rename_all = "camelCase")]
+enum Intent {
+    /// The pod was handed to the Kubelet
+    Add { pod: Box<Pod> },
+    /// The pod's manifest changed
+    Update { pod: Box<Pod> },
+    /// The pod's manifest was marked for deletion
+    Delete { pod: Box<Pod> },
+    /// The Kubelet is finished with the pod
+    Done { namespace: String, name: String },
+}
+
+/// A pod the Kubelet had not finished with when it last stopped.
+#[derive(Clone, Debug)]
+pub struct UnfinishedPod {
+    /// The last manifest of the pod that was recorded
+    pub pod: Pod,
+    /// Whether the pod was being deleted
+    pub deleting: bool,
+}
+
+/// An append-only log of pod intents, synced to disk before the work it records is started.
+pub struct PodIntentLog {
+    path: PathBuf,
+    inner: Mutex<LogFile>,
+}
+
+struct LogFile {
+    file: tokio::fs::File,
+    /// The number of entries in the file
+    entries: usize,
+    /// The unfinished pods the entries in the file replay to
+    replay: Replay,
+}
+
+impl PodIntentLog {
+    /// Opens the log in the given data directory, returning it along with the pods that were
+    /// unfinished when it was last written. Once the caller has dealt with the unfinished pods,
+    /// it should [`PodIntentLog::reset`] the log.
+    pub async fn open(data_dir: &Path) -> anyhow::Result<(Self, Vec<UnfinishedPod>)> {
+        let path = data_dir.join(INTENT_LOG_FILE);
+        let mut replay = Replay::default();
+        let mut entries = 0;
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => {
+                for (index, line) in contents.lines().enumerate() {
+                    entries += 1;
+                    match serde_json::from_str(line) {
+                        Ok(intent) => replay.apply(intent),
+                        // The last entry is torn if the Kubelet crashed while writing it
+                        Err(e) => {
+                            warn!(error = %e, line = index + 1, "Skipping unreadable pod intent")
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        };
+        let unfinished = replay.unfinished();
+        tokio::fs::create_dir_all(data_dir).await?;
+        let file = open_append(&path).await?;
+        Ok((
+            PodIntentLog {
+                path,
+                inner: Mutex::new(LogFile {
+                    file,
+                    entries,
+                    replay,
+                }),
+            },
+            unfinished,
+        ))
+    }
+
+    /// The path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Discards everything recorded so far, once the unfinished pods have been dealt with.
+    pub async fn reset(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner.file.set_len(0).await?;
+        inner.file.sync_data().await?;
+        inner.entries = 0;
+        inner.replay = Replay::default();
+        Ok(())
+    }
+
+    /// Records that the pod was handed to the Kubelet.
+    pub async fn added(&self, pod: &Pod) {
+        self.append(Intent::Add {
+            pod: Box::new(pod.clone()),
+        })
+        .await
+    }
+
+    /// Records that the pod's manifest changed, which includes it being marked for deletion.
+    pub async fn updated(&self, pod: &Pod) {
+        let pod = Box::new(pod.clone());
+        let intent = if pod.deletion_timestamp().is_some() {
+            Intent::Delete { pod }
+        } else {
+            Intent::Update { pod }
+        };
+        self.append(intent).await
+    }
+
+    /// Records that the Kubelet is finished with the pod.
+    pub async fn finished(&self, pod: &Pod) {
+        self.append(Intent::Done {
+            namespace: pod.namespace().to_owned(),
+            name: pod.name().to_owned(),
+        })
+        .await
+    }
+
+    /// Appends an entry and syncs it to disk, compacting the log first if it has grown too long.
+    /// Failing to record an intent only loses the ability to recover the pod after a crash, so
+    /// errors are logged rather than returned.
+    async fn append(&self, intent: Intent) {
+        let mut line = match serde_json::to_vec(&intent) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "Unable to serialize pod intent");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+        inner.replay.apply(intent);
+        let res = async {
+            if inner.entries >= COMPACT_AFTER_ENTRIES
+                && inner.entries >= 2 * inner.replay.pods.len()
+            {
+                // The entry that is being appended is already part of the replay
+                inner.file = compact(&self.path, &inner.replay).await?;
+                inner.entries = inner.replay.pods.len();
+                return Ok(());
+            }
+            inner.file.write_all(&line).await?;
+            inner.file.sync_data().await?;
+            inner.entries += 1;
+            Ok::<_, std::io::Error>(())
+        }
+        .await;
+        match res {
+            Ok(()) => problem::resolve(problem::DISK_PROBLEM),
+            Err(e) => {
+                warn!(error = %e, path = %self.path.display(), "Unable to write pod intent log");
+                problem::report(
+                    problem::DISK_PROBLEM,
+                    "DataDirUnwritable",
+                    &format!("Unable to write {}: {}", self.path.display(), e),
+                );
+            }
+        }
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// Rewrites the log with one entry for each unfinished pod, returning the file to append to.
+/// The entries are written to a new file that replaces the log once it is synced, so a crash
+/// part way through leaves the old log in place.
+async fn compact(path: &Path, replay: &Replay) -> std::io::Result<tokio::fs::File> {
+    let mut contents = Vec::new();
+    for unfinished in replay.unfinished() {
+        let pod = Box::new(unfinished.pod);
+        let intent = if unfinished.deleting {
+            Intent::Delete { pod }
+        } else {
+            Intent::Add { pod }
+        };
+        serde_json::to_writer(&mut contents, &intent)?;
+        contents.push(b'\n');
+    }
+    let compacted = path.with_extension("log.compact");
+    let mut file = tokio::fs::File::create(&compacted).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&compacted, path).await?;
+    open_append(path).await
+}
+
+/// The unfinished pods that the entries of the log replay to.
+#[derive(Default)]
+struct Replay {
+    /// The pods along with the position of the entry that first added them
+    pods: BTreeMap<PodKey, (usize, UnfinishedPod)>,
+    next: usize,
+}
+
+impl Replay {
+    fn apply(&mut self, intent: Intent) {
+        let index = self.next;
+        self.next += 1;
+        let (pod, deleting) = match intent {
+            Intent::Add { pod } | Intent::Update { pod } => (pod, false),
+            Intent::Delete { pod } => (pod, true),
+            Intent::Done { namespace, name } => {
+                self.pods.remove(&PodKey::new(namespace, name));
+                return;
+            }
+        };
+        let key = PodKey::from(&*pod);
+        let existing = self.pods.get(&key);
+        let order = existing.map(|(order, _)| *order).unwrap_or(index);
+        // A pod that is being deleted stays that way, even if later updates are recorded
+        let deleting = deleting || existing.map(|(_, p)| p.deleting).unwrap_or(false);
+        self.pods.insert(
+            key,
+            (
+                order,
+                UnfinishedPod {
+                    pod: *pod,
+                    deleting,
+                },
+            ),
+        );
+    }
+
+    /// The pods that were not finished, in the order they were added.
+    fn unfinished(&self) -> Vec<UnfinishedPod> {
+        let mut pods: Vec<&(usize, UnfinishedPod)> = self.pods.values().collect();
+        pods.sort_by_key(|(order, _)| *order);
+        pods.into_iter().map(|(_, p)| p.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(name: &str, deleting: bool) -> Pod {
+        let mut metadata = serde_json::json!({"name": name, "namespace": "default"});
+        if deleting {
+            metadata["deletionTimestamp"] = "2021-07-01T00:00:00Z".into();
+        }
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({ "metadata": metadata })).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_replay_unfinished_pods() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, unfinished) = PodIntentLog::open(dir.path()).await.unwrap();
+        assert!(unfinished.is_empty());
+
+        log.added(&pod("finished", false)).await;
+        log.added(&pod("running", false)).await;
+        log.added(&pod("deleting", false)).await;
+        log.updated(&pod("running", false)).await;
+        log.updated(&pod("deleting", true)).await;
+        log.finished(&pod("finished", false)).await;
+        drop(log);
+
+        // Simulate a crash part way through writing an entry
+        let mut contents = std::fs::read_to_string(dir.path().join(INTENT_LOG_FILE)).unwrap();
+        contents.push_str("{\"intent\":\"add\",\"pod");
+        std::fs::write(dir.path().join(INTENT_LOG_FILE), contents).unwrap();
+
+        let (log, unfinished) = PodIntentLog::open(dir.path()).await.unwrap();
+        drop(log);
+        let unfinished: Vec<(&str, bool)> = unfinished
+            .iter()
+            .map(|p| (p.pod.name(), p.deleting))
+            .collect();
+        assert_eq!(unfinished, vec![("running", false), ("deleting", true)]);
+
+        // Unfinished pods are kept until the log is reset
+        let (log, unfinished) = PodIntentLog::open(dir.path()).await.unwrap();
+        assert_eq!(unfinished.len(), 2);
+        log.reset().await.unwrap();
+        log.added(&pod("restarted", false)).await;
+        drop(log);
+        let (_log, unfinished) = PodIntentLog::open(dir.path()).await.unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].pod.name(), "restarted");
+    }
+
+    #[tokio::test]
+    async fn test_log_is_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (log, _) = PodIntentLog::open(dir.path()).await.unwrap();
+        log.added(&pod("kept", false)).await;
+        for i in 0..COMPACT_AFTER_ENTRIES {
+            let churned = pod(&format!("churned-{}", i), false);
+            log.added(&churned).await;
+            log.finished(&churned).await;
+        }
+        log.updated(&pod("kept", true)).await;
+        drop(log);
+
+        let contents = std::fs::read_to_string(dir.path().join(INTENT_LOG_FILE)).unwrap();
+        assert!(contents.lines().count() < COMPACT_AFTER_ENTRIES);
+        let (_log, unfinished) = PodIntentLog::open(dir.path()).await.unwrap();
+        let unfinished: Vec<(&str, bool)> = unfinished
+            .iter()
+            .map(|p| (p.pod.name(), p.deleting))
+            .collect();
+        assert_eq!(unfinished, vec![("kept", true)]);
+    }
+}
@@ -1,12 +1,39 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+mod condition;
+mod diff;
+pub mod dirs;
+mod event;
 mod handle;
+mod intent_log;
+mod quarantine;
+mod queue;
+mod reconcile;
 pub mod state;
 mod status;
 
+pub use condition::{
+    has_readiness_gates, make_condition, make_conditions, updated_ready_condition, ConditionType,
+    Readiness,
+};
+pub use diff::{diff_pods, PodChange};
+pub use dirs::PodDirs;
+pub(crate) use event::new_event;
+pub use event::{record_event, EVENT_TYPE_NORMAL, EVENT_TYPE_WARNING};
 pub use handle::Handle;
+pub(crate) use intent_log::INTENT_LOG_FILE;
+pub use intent_log::{PodIntentLog, UnfinishedPod};
+pub(crate) use quarantine::{
+    catch_panic, quarantine, quarantine_and_fail, release_quarantine, set_event_client,
+    ProviderPanicked,
+};
+pub use quarantine::{provider_panic_status, quarantined, PROVIDER_PANIC};
+pub(crate) use queue::{PodWork, PodWorkQueue};
+pub use reconcile::reconcile_statuses;
+pub(crate) use reconcile::{forget_status, record_container_status, record_status};
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
+    make_registered_status, make_status, make_status_with_conditions, make_status_with_containers,
+    patch_status, Phase, Status,
 };
 
 use crate::container::{Container, ContainerKey};
@@ -66,6 +93,21 @@ impl Pod {
         spec.service_account_name.as_deref()
     }
 
+    /// Get the supplemental group that owns the pod's volumes, from `securityContext.fsGroup`
+    pub fn fs_group(&self) -> Option<i64> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.security_context.as_ref()?.fs_group
+    }
+
+    /// Get the pod's `securityContext.fsGroupChangePolicy`
+    pub fn fs_group_change_policy(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.security_context
+            .as_ref()?
+            .fs_group_change_policy
+            .as_deref()
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> &Vec<KubeVolume> {
         self.kube_pod
@@ -87,6 +129,16 @@ impl Pod {
         status.pod_ip.as_deref()
     }
 
+    /// Set the pod's ip, such as one just assigned to it that the API server hasn't reported
+    /// back yet
+    pub fn set_pod_ip(&mut self, pod_ip: &str) {
+        let status = self.kube_pod.status.get_or_insert_with(Default::default);
+        status.pod_ip = Some(pod_ip.to_owned());
+        status.pod_ips = vec![k8s_openapi::api::core::v1::PodIP {
+            ip: Some(pod_ip.to_owned()),
+        }];
+    }
+
     /// Get the pod's uid
     pub fn pod_uid(&self) -> &str {
         self.kube_pod
@@ -0,0 +1,175 @@
+//! Isolation of pods whose provider calls panic.
+//!
+//! A panic in the provider while it handles one pod would otherwise unwind through the task
+//! running that pod's state machine, or through the task handing every pod's updates to the
+//! provider, leaving the pod (or all of them) stuck. Instead, the Kubelet catches panics escaping
+//! provider calls for a single pod and quarantines the pod: it is marked `Failed` with a
+//! [`PROVIDER_PANIC`] reason, an Event with the panic message is recorded for it, and no further
+//! updates to it are handed to the provider. Only cleaning up after the pod once it is deleted
+//! still is. The Kubelet and its other pods keep running.
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Mutex, RwLock};
+use std::task::Poll;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use krator::{Manifest, ObjectState, SharedState, State, Transition};
+use kube::Api;
+use tracing::error;
+
+use super::status::StatusBuilder;
+use super::{record_event, Phase, Pod, PodKey, Status, EVENT_TYPE_WARNING};
+
+/// The reason given for pods whose provider calls panicked
+pub const PROVIDER_PANIC: &str = "ProviderPanic";
+
+lazy_static::lazy_static! {
+    static ref QUARANTINED: Mutex<HashMap<PodKey, String>> = Mutex::new(HashMap::new());
+    static ref EVENT_CLIENT: RwLock<Option<kube::Client>> = RwLock::new(None);
+}
+
+/// Sets the client Events about quarantined pods are recorded with.
+pub(crate) fn set_event_client(client: kube::Client) {
+    *EVENT_CLIENT.write().unwrap() = Some(client);
+}
+
+/// Runs a future, returning the message of the panic instead of unwinding if it panics.
+pub(crate) async fn catch_panic<F: Future>(future: F) -> Result<F::Output, String> {
+    let mut future = Box::pin(future);
+    futures::future::poll_fn(move |cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(panic_message(payload.as_ref()))),
+        }
+    })
+    .await
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic with a non-string payload".to_owned()
+    }
+}
+
+/// Quarantines a pod after a provider call for it panicked, recording an Event with the message.
+pub(crate) async fn quarantine(pod: &Pod, message: &str) {
+    error!(
+        pod_name = pod.name(),
+        namespace = pod.namespace(),
+        panic = message,
+        "Provider panicked handling pod, quarantining it"
+    );
+    QUARANTINED
+        .lock()
+        .unwrap()
+        .insert(PodKey::from(pod), message.to_owned());
+    let client = EVENT_CLIENT.read().unwrap().clone();
+    if let Some(client) = client {
+        record_event(
+            &client,
+            pod,
+            EVENT_TYPE_WARNING,
+            PROVIDER_PANIC,
+            &format!("Provider panicked: {}", message),
+        )
+        .await;
+    }
+}
+
+/// Quarantines a pod and reports it `Failed` with [`provider_panic_status`], for panics outside
+/// of the pod's state machine, which would otherwise go on reporting the pod's status as before.
+pub(crate) async fn quarantine_and_fail(client: &kube::Client, pod: &Pod, message: &str) {
+    quarantine(pod, message).await;
+    let status = provider_panic_status(message);
+    super::record_status(pod, &status);
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    super::patch_status(&api, pod.name(), status).await;
+}
+
+/// The message of the panic a pod was quarantined for, if it is quarantined.
+pub fn quarantined(pod: &Pod) -> Option<String> {
+    QUARANTINED.lock().unwrap().get(&PodKey::from(pod)).cloned()
+}
+
+/// Releases a pod from quarantine once it has been deleted.
+pub(crate) fn release_quarantine(pod: &Pod) {
+    QUARANTINED.lock().unwrap().remove(&PodKey::from(pod));
+}
+
+/// The status of a quarantined pod.
+pub fn provider_panic_status(message: &str) -> Status {
+    StatusBuilder::new()
+        .phase(Phase::Failed)
+        .reason(PROVIDER_PANIC)
+        .message(&format!("Provider panicked: {}", message))
+        .build()
+}
+
+/// The state a pod's state machine ends in when a provider call in one of its states panicked.
+#[derive(Debug)]
+pub(crate) struct ProviderPanicked {
+    message: String,
+}
+
+impl ProviderPanicked {
+    pub(crate) fn new(message: String) -> Self {
+        ProviderPanicked { message }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> State<S> for ProviderPanicked
+where
+    S: ObjectState<Manifest = Pod, Status = Status>,
+{
+    async fn next(
+        self: Box<Self>,
+        _shared: SharedState<S::SharedState>,
+        _state: &mut S,
+        _manifest: Manifest<Pod>,
+    ) -> Transition<S> {
+        Transition::Complete(Err(anyhow::anyhow!("Provider panicked: {}", self.message)))
+    }
+
+    async fn status(&self, _state: &mut S, _pod: &Pod) -> anyhow::Result<Status> {
+        Ok(provider_panic_status(&self.message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    #[tokio::test]
+    async fn test_panics_are_caught_and_pods_quarantined() {
+        assert_eq!(catch_panic(async { 1 }).await, Ok(1));
+        let caught = catch_panic(async {
+            tokio::task::yield_now().await;
+            panic!("module exploded: {}", 42)
+        })
+        .await;
+        assert_eq!(caught, Err::<(), _>("module exploded: 42".to_owned()));
+
+        // Quarantined pods are global, so the pod gets a name no other test uses
+        let name = format!("bad-{}", uuid::Uuid::new_v4());
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": { "name": name, "namespace": "default" }
+            }))
+            .unwrap(),
+        );
+        assert_eq!(quarantined(&pod), None);
+        quarantine(&pod, "module exploded").await;
+        assert_eq!(quarantined(&pod).as_deref(), Some("module exploded"));
+        release_quarantine(&pod);
+        assert_eq!(quarantined(&pod), None);
+    }
+}
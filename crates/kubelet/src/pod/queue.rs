@@ -0,0 +1,357 @@
+//! The queue of work the Kubelet does for pods.
+//!
+//! Updates to running pods and the start of pod deletions are queued here and handed to the
+//! provider one at a time, and new pods wait here to be admitted before their state machines
+//! start. Deletions have their own lane that is always drained first, so a flood of new pods or
+//! updates can't delay the cleanup of terminating pods, and new pods are admitted before updates
+//! are handled. Work is coalesced per pod: a pod never has more than one update queued, and
+//! queued updates are dropped once the pod starts terminating. The update lane is bounded, and
+//! pushing to a full lane waits for room.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts};
+use tokio::sync::{oneshot, Notify, Semaphore};
+use tokio::time::Instant;
+
+use super::{Pod, PodChange, PodKey};
+use crate::metrics::register;
+
+/// The most updates that can be queued before pushing more has to wait
+const MAX_QUEUED_UPDATES: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref QUEUE_DEPTH: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("pod_work_queue_depth", "Number of pods with work queued in each lane"),
+        &["lane"]
+    ));
+    static ref QUEUE_AGE: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "pod_work_queue_age_seconds",
+            "Time pod work spent queued before it was handed to the provider"
+        ),
+        &["lane"]
+    ));
+    static ref QUEUE_COALESCED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "pod_work_queue_coalesced_total",
+            "Number of pushes that were merged into work already queued for the same pod"
+        ),
+        &["lane"]
+    ));
+}
+
+/// A unit of work for a single pod.
+#[derive(Debug)]
+pub(crate) enum PodWork {
+    /// The pod was handed to the Kubelet, and its state machine is waiting to be admitted
+    Create(Pod, oneshot::Sender<()>),
+    /// The pod was marked for deletion
+    Delete(Pod),
+    /// Parts of the pod changed since the last work for it was queued
+    Update(Pod, Vec<PodChange>),
+}
+
+impl PodWork {
+    fn key(&self) -> PodKey {
+        match self {
+            PodWork::Create(pod, _) | PodWork::Delete(pod) | PodWork::Update(pod, _) => {
+                PodKey::from(pod)
+            }
+        }
+    }
+
+    /// The pod the work is for.
+    pub(crate) fn pod(&self) -> &Pod {
+        match self {
+            PodWork::Create(pod, _) | PodWork::Delete(pod) | PodWork::Update(pod, _) => pod,
+        }
+    }
+}
+
+struct Queued {
+    key: PodKey,
+    work: PodWork,
+    enqueued: Instant,
+}
+
+#[derive(Default)]
+struct Lanes {
+    deletes: VecDeque<Queued>,
+    creates: VecDeque<Queued>,
+    updates: VecDeque<Queued>,
+}
+
+impl Lanes {
+    fn record_depth(&self) {
+        QUEUE_DEPTH
+            .with_label_values(&["delete"])
+            .set(self.deletes.len() as i64);
+        QUEUE_DEPTH
+            .with_label_values(&["create"])
+            .set(self.creates.len() as i64);
+        QUEUE_DEPTH
+            .with_label_values(&["update"])
+            .set(self.updates.len() as i64);
+    }
+}
+
+/// A three lane queue of pod work, with deletions taking priority over creations, and creations
+/// over updates.
+pub(crate) struct PodWorkQueue {
+    lanes: Mutex<Lanes>,
+    /// Permits for free slots in the update lane
+    update_slots: Semaphore,
+    ready: Notify,
+}
+
+impl Default for PodWorkQueue {
+    fn default() -> Self {
+        PodWorkQueue::with_capacity(MAX_QUEUED_UPDATES)
+    }
+}
+
+impl PodWorkQueue {
+    fn with_capacity(capacity: usize) -> Self {
+        PodWorkQueue {
+            lanes: Mutex::new(Lanes::default()),
+            update_slots: Semaphore::new(capacity),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queues work for a pod, waiting for room if it is an update and the update lane is full.
+    pub(crate) async fn push(&self, work: PodWork) {
+        let key = work.key();
+        match work {
+            PodWork::Create(..) => {
+                let mut lanes = self.lanes.lock().unwrap();
+                lanes.creates.push_back(Queued {
+                    key,
+                    work,
+                    enqueued: Instant::now(),
+                });
+                lanes.record_depth();
+            }
+            PodWork::Delete(pod) => {
+                let mut lanes = self.lanes.lock().unwrap();
+                // Updates to a pod that is going away are pointless
+                let before = lanes.updates.len();
+                lanes.updates.retain(|q| q.key != key);
+                self.update_slots.add_permits(before - lanes.updates.len());
+                match lanes.deletes.iter_mut().find(|q| q.key == key) {
+                    Some(queued) => {
+                        QUEUE_COALESCED.with_label_values(&["delete"]).inc();
+                        queued.work = PodWork::Delete(pod);
+                    }
+                    None => lanes.deletes.push_back(Queued {
+                        key,
+                        work: PodWork::Delete(pod),
+                        enqueued: Instant::now(),
+                    }),
+                }
+                lanes.record_depth();
+            }
+            PodWork::Update(pod, changes) => {
+                if self.coalesce_update(&key, &pod, &changes) {
+                    return;
+                }
+                let permit = self
+                    .update_slots
+                    .acquire()
+                    .await
+                    .expect("update slots are never closed");
+                let mut lanes = self.lanes.lock().unwrap();
+                // Work for the pod may have been queued while waiting for a slot
+                if lanes.deletes.iter().any(|q| q.key == key) {
+                    return;
+                }
+                if let Some(queued) = lanes.updates.iter_mut().find(|q| q.key == key) {
+                    QUEUE_COALESCED.with_label_values(&["update"]).inc();
+                    merge_update(&mut queued.work, pod, changes);
+                    return;
+                }
+                // The slot is given back when the update is popped
+                permit.forget();
+                lanes.updates.push_back(Queued {
+                    key,
+                    work: PodWork::Update(pod, changes),
+                    enqueued: Instant::now(),
+                });
+                lanes.record_depth();
+            }
+        }
+        self.ready.notify_one();
+    }
+
+    /// Merges an update into work already queued for the same pod, returning whether it was.
+    fn coalesce_update(&self, key: &PodKey, pod: &Pod, changes: &[PodChange]) -> bool {
+        let mut lanes = self.lanes.lock().unwrap();
+        if lanes.deletes.iter().any(|q| &q.key == key) {
+            QUEUE_COALESCED.with_label_values(&["delete"]).inc();
+            return true;
+        }
+        match lanes.updates.iter_mut().find(|q| &q.key == key) {
+            Some(queued) => {
+                QUEUE_COALESCED.with_label_values(&["update"]).inc();
+                merge_update(&mut queued.work, pod.clone(), changes.to_vec());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits for the next piece of work, taking deletions before creations and creations before
+    /// updates.
+    pub(crate) async fn pop(&self) -> PodWork {
+        loop {
+            {
+                let mut lanes = self.lanes.lock().unwrap();
+                let next = if let Some(queued) = lanes.deletes.pop_front() {
+                    Some(("delete", queued))
+                } else if let Some(queued) = lanes.creates.pop_front() {
+                    Some(("create", queued))
+                } else {
+                    lanes.updates.pop_front().map(|queued| {
+                        self.update_slots.add_permits(1);
+                        ("update", queued)
+                    })
+                };
+                if let Some((lane, queued)) = next {
+                    lanes.record_depth();
+                    QUEUE_AGE
+                        .with_label_values(&[lane])
+                        .observe(queued.enqueued.elapsed().as_secs_f64());
+                    return queued.work;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Folds a newer update into a queued one, keeping the newest manifest and every change.
+fn merge_update(queued: &mut PodWork, pod: Pod, changes: Vec<PodChange>) {
+    if let PodWork::Update(queued_pod, queued_changes) = queued {
+        *queued_pod = pod;
+        queued_changes.extend(changes);
+        queued_changes.sort();
+        queued_changes.dedup();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(name: &str, label: &str) -> Pod {
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": name, "namespace": "default", "labels": {"version": label}}
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn describe(work: PodWork) -> (String, String, Vec<PodChange>) {
+        match work {
+            PodWork::Create(pod, _) => ("create".into(), pod.name().to_owned(), Vec::new()),
+            PodWork::Delete(pod) => ("delete".into(), pod.name().to_owned(), Vec::new()),
+            PodWork::Update(pod, changes) => (
+                pod.labels()["version"].clone(),
+                pod.name().to_owned(),
+                changes,
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deletes_first_and_updates_coalesce() {
+        let queue = PodWorkQueue::with_capacity(2);
+        queue
+            .push(PodWork::Update(pod("a", "1"), vec![PodChange::Metadata]))
+            .await;
+        queue
+            .push(PodWork::Update(pod("b", "1"), vec![PodChange::Metadata]))
+            .await;
+        queue
+            .push(PodWork::Update(
+                pod("a", "2"),
+                vec![PodChange::Image("server".into())],
+            ))
+            .await;
+        queue.push(PodWork::Delete(pod("c", "1"))).await;
+        queue.push(PodWork::Delete(pod("b", "1"))).await;
+
+        assert_eq!(
+            describe(queue.pop().await),
+            ("delete".into(), "c".into(), vec![])
+        );
+        assert_eq!(
+            describe(queue.pop().await),
+            ("delete".into(), "b".into(), vec![])
+        );
+        assert_eq!(
+            describe(queue.pop().await),
+            (
+                "2".into(),
+                "a".into(),
+                vec![PodChange::Metadata, PodChange::Image("server".into())]
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_update_lane_waits() {
+        let queue = std::sync::Arc::new(PodWorkQueue::with_capacity(1));
+        queue.push(PodWork::Update(pod("a", "1"), vec![])).await;
+        let pushed = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.push(PodWork::Update(pod("b", "1"), vec![])).await })
+        };
+        let mut pushed = pushed;
+        let waited = tokio::time::timeout(std::time::Duration::from_millis(50), &mut pushed).await;
+        assert!(waited.is_err());
+
+        assert_eq!(describe(queue.pop().await).1, "a");
+        pushed.await.unwrap();
+        assert_eq!(describe(queue.pop().await).1, "b");
+    }
+
+    #[tokio::test]
+    async fn test_creates_wait_behind_deletes() {
+        let queue = PodWorkQueue::with_capacity(2);
+        queue
+            .push(PodWork::Update(pod("a", "1"), vec![PodChange::Metadata]))
+            .await;
+        let (admitted, _) = oneshot::channel();
+        queue.push(PodWork::Create(pod("b", "1"), admitted)).await;
+        queue.push(PodWork::Delete(pod("c", "1"))).await;
+        // Deleting a pod doesn't drop its admission, which its state machine is waiting for
+        let (admitted, _) = oneshot::channel();
+        queue.push(PodWork::Create(pod("c", "2"), admitted)).await;
+
+        let order: Vec<(String, String)> = vec![
+            queue.pop().await,
+            queue.pop().await,
+            queue.pop().await,
+            queue.pop().await,
+        ]
+        .into_iter()
+        .map(|work| {
+            let (kind, name, _) = describe(work);
+            (kind, name)
+        })
+        .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("delete".into(), "c".into()),
+                ("create".into(), "b".into()),
+                ("create".into(), "c".into()),
+                ("1".into(), "a".into()),
+            ]
+        );
+    }
+}
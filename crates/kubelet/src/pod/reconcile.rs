@@ -0,0 +1,427 @@
+//! Reconciliation of the statuses the Kubelet reports for its pods.
+//!
+//! Status patches that fail while the API server can't be reached are lost, leaving pods with
+//! whatever status the API server last saw. The Kubelet remembers the status it last wanted each
+//! of its pods to have, and once the API server is back compares it with the status of every pod
+//! on the node from a single list, patching only the pods whose reported status differs, a few at
+//! a time.
+//!
+//! Timestamps are left out of the comparison, since the API server may store them at a coarser
+//! precision than they were sent with; a pod that only differs in them is already up to date.
+//!
+//! Each patch is conditional on the pod still being at the resource version it was listed at, so
+//! a status the Kubelet reported in the meantime isn't overwritten with an older one. Pods that
+//! changed are left for the next reconciliation.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use krator::ObjectStatus;
+use kube::api::{Api, ListParams, PatchParams};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{Pod, PodKey, Status};
+
+/// How many pods are patched at once
+const RECONCILE_CONCURRENCY: usize = 8;
+
+lazy_static::lazy_static! {
+    static ref DESIRED: Mutex<HashMap<PodKey, DesiredStatus>> = Mutex::new(HashMap::new());
+}
+
+/// The status the Kubelet last wanted a pod to have.
+#[derive(Debug, Default)]
+struct DesiredStatus {
+    /// The pod-level fields of the status, such as its phase and conditions
+    fields: serde_json::Map<String, Value>,
+    /// The statuses of the pod's containers, and whether each is an init container, in the order
+    /// they were first reported
+    containers: Vec<(bool, KubeContainerStatus)>,
+}
+
+impl DesiredStatus {
+    fn set_container(&mut self, init: bool, status: KubeContainerStatus) {
+        match self
+            .containers
+            .iter_mut()
+            .find(|(i, s)| *i == init && s.name == status.name)
+        {
+            Some((_, existing)) => *existing = status,
+            None => self.containers.push((init, status)),
+        }
+    }
+
+    /// The patch that brings the reported status of a pod to this one, if it differs, unless the
+    /// pod has changed since it was reported.
+    fn patch(&self, reported: &KubePod) -> Option<Value> {
+        let reported_status = reported.status.clone().unwrap_or_default();
+        let reported_fields = serde_json::to_value(&reported_status).unwrap_or(Value::Null);
+        let mut status: serde_json::Map<String, Value> = self
+            .fields
+            .iter()
+            .filter(|(key, value)| !covers(&reported_fields[key.as_str()], value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        for (init, key) in &[
+            (false, "containerStatuses"),
+            (true, "initContainerStatuses"),
+        ] {
+            let reported = if *init {
+                &reported_status.init_container_statuses
+            } else {
+                &reported_status.container_statuses
+            };
+            let desired: Vec<&KubeContainerStatus> = self
+                .containers
+                .iter()
+                .filter(|(i, _)| i == init)
+                .map(|(_, s)| s)
+                .collect();
+            let stale = desired.iter().any(|d| {
+                !reported.iter().any(|r| {
+                    r.name == d.name
+                        && covers(
+                            &serde_json::to_value(r).unwrap_or(Value::Null),
+                            &reported_container_fields(d),
+                        )
+                })
+            });
+            if stale {
+                // Container statuses are replaced as a whole, so keep those we know nothing of
+                let mut statuses: Vec<&KubeContainerStatus> = reported
+                    .iter()
+                    .map(|r| {
+                        desired
+                            .iter()
+                            .find(|d| d.name == r.name)
+                            .copied()
+                            .unwrap_or(r)
+                    })
+                    .collect();
+                statuses.extend(
+                    desired
+                        .iter()
+                        .filter(|d| !reported.iter().any(|r| r.name == d.name)),
+                );
+                status.insert((*key).to_owned(), serde_json::json!(statuses));
+            }
+        }
+
+        if status.is_empty() {
+            None
+        } else {
+            Some(serde_json::json!({
+                "metadata": { "resourceVersion": reported.metadata.resource_version },
+                "status": status,
+            }))
+        }
+    }
+}
+
+/// The fields of a container status the Kubelet reports, leaving out those the API server fills
+/// in, such as the image ID.
+fn reported_container_fields(status: &KubeContainerStatus) -> Value {
+    serde_json::json!({
+        "state": status.state,
+        "ready": status.ready,
+        "started": status.started,
+    })
+}
+
+/// Whether a reported value has everything in the desired one, ignoring timestamps. Lists of
+/// objects with a `type`, such as conditions, are compared by type, and other lists in order.
+fn covers(reported: &Value, desired: &Value) -> bool {
+    match (reported, desired) {
+        (Value::Object(reported), Value::Object(desired)) => desired.iter().all(|(key, value)| {
+            is_timestamp(key) || covers(reported.get(key).unwrap_or(&Value::Null), value)
+        }),
+        (Value::Array(reported), Value::Array(desired)) => {
+            if desired.iter().all(|d| d.get("type").is_some()) {
+                desired.iter().all(|d| {
+                    reported
+                        .iter()
+                        .any(|r| r.get("type") == d.get("type") && covers(r, d))
+                })
+            } else {
+                reported.len() == desired.len()
+                    && reported.iter().zip(desired).all(|(r, d)| covers(r, d))
+            }
+        }
+        (reported, desired) => reported == desired,
+    }
+}
+
+fn is_timestamp(key: &str) -> bool {
+    key.ends_with("Time") || key.ends_with("At")
+}
+
+/// Remembers the status the Kubelet wants a pod to have, merging it into what it wanted before.
+pub(crate) fn record_status(pod: &Pod, status: &Status) {
+    let patch = status.json_patch();
+    let fields = match patch.get("status") {
+        Some(Value::Object(fields)) => fields.clone(),
+        _ => return,
+    };
+    let mut desired = DESIRED.lock().unwrap();
+    let desired = desired.entry(PodKey::from(pod)).or_default();
+    for (key, value) in fields {
+        let init = match key.as_str() {
+            "containerStatuses" => false,
+            "initContainerStatuses" => true,
+            _ => {
+                desired.fields.insert(key, value);
+                continue;
+            }
+        };
+        let statuses: Vec<KubeContainerStatus> = serde_json::from_value(value).unwrap_or_default();
+        for status in statuses {
+            desired.set_container(init, status);
+        }
+    }
+}
+
+/// Remembers the status the Kubelet wants one of a pod's containers to have.
+pub(crate) fn record_container_status(pod: &Pod, init: bool, status: KubeContainerStatus) {
+    DESIRED
+        .lock()
+        .unwrap()
+        .entry(PodKey::from(pod))
+        .or_default()
+        .set_container(init, status);
+}
+
+/// Forgets the status of a pod the Kubelet no longer runs.
+pub(crate) fn forget_status(pod: &Pod) {
+    DESIRED.lock().unwrap().remove(&PodKey::from(pod));
+}
+
+/// Patches every pod on the node whose reported status differs from the one the Kubelet last
+/// wanted it to have, returning how many were patched. Pods that changed since they were listed,
+/// or whose patch fails, are left for the next reconciliation; only failing to list the node's
+/// pods is an error.
+pub async fn reconcile_statuses(client: &kube::Client, node_name: &str) -> anyhow::Result<usize> {
+    let pods: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let reported = pods
+        .list(&params)
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to list pods on node: {}", e))?;
+
+    let patches: Vec<(PodKey, Value)> = {
+        let desired = DESIRED.lock().unwrap();
+        reported
+            .items
+            .iter()
+            .filter_map(|pod| {
+                let key = PodKey::from(pod);
+                let patch = desired.get(&key)?.patch(pod)?;
+                Some((key, patch))
+            })
+            .collect()
+    };
+    debug!(
+        pods = reported.items.len(),
+        stale = patches.len(),
+        "Reconciling pod statuses"
+    );
+
+    let patched = std::sync::atomic::AtomicUsize::new(0);
+    futures::stream::iter(patches)
+        .for_each_concurrent(RECONCILE_CONCURRENCY, |(key, patch)| {
+            let patched = &patched;
+            async move {
+                let api: Api<KubePod> = Api::namespaced(client.clone(), &key.namespace());
+                match api
+                    .patch_status(
+                        &key.name(),
+                        &PatchParams::default(),
+                        &kube::api::Patch::Strategic(patch),
+                    )
+                    .await
+                {
+                    Ok(_) => {
+                        patched.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    Err(kube::Error::Api(kube::error::ErrorResponse { code: 409, .. })) => {
+                        debug!(pod_name = %key.name(), namespace = %key.namespace(), "Pod changed since it was listed, leaving its status for the next reconciliation");
+                    }
+                    Err(e) => {
+                        warn!(error = %e, pod_name = %key.name(), namespace = %key.namespace(), "Unable to reconcile pod status");
+                    }
+                }
+            }
+        })
+        .await;
+    Ok(patched.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::{make_status, Phase};
+
+    fn kube_pod(status: Value) -> KubePod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "web", "namespace": "default", "resourceVersion": "7" },
+            "spec": { "containers": [{ "name": "server" }, { "name": "sidecar" }] },
+            "status": status
+        }))
+        .unwrap()
+    }
+
+    fn container(name: &str, state: Value, ready: bool) -> KubeContainerStatus {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "state": state,
+            "ready": ready,
+            "restartCount": 0,
+            "image": "",
+            "imageID": ""
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_only_stale_fields_are_patched() {
+        let mut desired = DesiredStatus {
+            fields: make_status(Phase::Running, "Running").json_patch()["status"]
+                .as_object()
+                .unwrap()
+                .clone(),
+            containers: vec![],
+        };
+        desired.fields.insert(
+            "conditions".to_owned(),
+            serde_json::json!([{ "type": "Ready", "status": "True", "lastTransitionTime": "2021-06-01T00:00:00Z" }]),
+        );
+        let running = serde_json::json!({ "running": { "startedAt": "2021-06-01T00:00:00Z" } });
+        desired.set_container(false, container("server", running.clone(), true));
+
+        let reported = kube_pod(serde_json::json!({
+            "phase": "Running",
+            "reason": "Running",
+            "message": "Running",
+            "conditions": [
+                { "type": "Initialized", "status": "True" },
+                { "type": "Ready", "status": "True", "lastTransitionTime": "2021-06-01T00:00:01Z" }
+            ],
+            "containerStatuses": [
+                container("server", running.clone(), true),
+                container("sidecar", serde_json::json!({ "waiting": {} }), false)
+            ]
+        }));
+        assert_eq!(desired.patch(&reported), None);
+
+        let reported = kube_pod(serde_json::json!({
+            "phase": "Pending",
+            "reason": "Running",
+            "message": "Running",
+            "conditions": [{ "type": "Ready", "status": "True" }],
+            "containerStatuses": [
+                container("server", serde_json::json!({ "waiting": {} }), false),
+                container("sidecar", serde_json::json!({ "waiting": {} }), false)
+            ]
+        }));
+        let patch = desired
+            .patch(&reported)
+            .expect("stale pod should be patched");
+        let status = patch["status"].as_object().unwrap();
+        let mut keys: Vec<_> = status.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["containerStatuses", "phase"]);
+        assert_eq!(status["phase"], "Running");
+        assert_eq!(patch["metadata"]["resourceVersion"], "7");
+        let containers = status["containerStatuses"].as_array().unwrap();
+        assert_eq!(containers.len(), 2);
+        assert_eq!(containers[0]["ready"], true);
+        assert_eq!(containers[1]["name"], "sidecar");
+    }
+
+    #[test]
+    fn test_recorded_statuses_merge() {
+        let pod = Pod::from(kube_pod(serde_json::json!({})));
+        record_status(&pod, &crate::pod::make_registered_status(&pod));
+        record_status(&pod, &make_status(Phase::Running, "Running"));
+        record_container_status(
+            &pod,
+            false,
+            container("server", serde_json::json!({ "running": {} }), true),
+        );
+        {
+            let desired = DESIRED.lock().unwrap();
+            let desired = &desired[&PodKey::from(&pod)];
+            assert_eq!(desired.fields["phase"], "Running");
+            assert_eq!(desired.containers.len(), 2);
+            assert!(desired.containers[0].1.ready);
+            assert_eq!(desired.containers[1].1.name, "sidecar");
+        }
+        forget_status(&pod);
+        assert!(!DESIRED.lock().unwrap().contains_key(&PodKey::from(&pod)));
+    }
+
+    #[tokio::test]
+    async fn test_pods_changed_since_listed_are_skipped() {
+        use futures::pin_mut;
+        use http::{Method, Request as HttpRequest, Response as HttpResponse};
+        use hyper::Body;
+        use tower_test::mock;
+
+        let mut listed = kube_pod(serde_json::json!({ "phase": "Pending" }));
+        listed.metadata.name = Some("conflicted".to_owned());
+        let pod = Pod::from(listed.clone());
+        record_status(&pod, &make_status(Phase::Running, "Running"));
+
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let patches = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let recorded = patches.clone();
+        tokio::spawn(async move {
+            pin_mut!(handle);
+            while let Some((request, send)) = handle.next_request().await {
+                let (status, body) = if request.method() == Method::PATCH {
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_slice::<Value>(&body).unwrap());
+                    // The Kubelet reported a newer status after the pod was listed
+                    (
+                        409,
+                        serde_json::json!({
+                            "status": "Failure",
+                            "message": "the object has been modified",
+                            "reason": "Conflict",
+                            "code": 409,
+                        }),
+                    )
+                } else {
+                    (
+                        200,
+                        serde_json::json!({
+                            "apiVersion": "v1",
+                            "kind": "PodList",
+                            "metadata": {},
+                            "items": [listed],
+                        }),
+                    )
+                };
+                send.send_response(
+                    HttpResponse::builder()
+                        .status(status)
+                        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        let client = kube::Client::new(mock_service, "default");
+
+        assert_eq!(reconcile_statuses(&client, "node").await.unwrap(), 0);
+        let patches = patches.lock().unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0]["metadata"]["resourceVersion"], "7");
+        forget_status(&pod);
+    }
+}
@@ -119,6 +119,20 @@ pub fn make_status(phase: Phase, reason: &str) -> Status {
         .build()
 }
 
+/// Create Pod status patch that also sets the Pod conditions.
+pub fn make_status_with_conditions(
+    phase: Phase,
+    reason: &str,
+    conditions: Vec<KubePodCondition>,
+) -> Status {
+    StatusBuilder::new()
+        .phase(phase)
+        .reason(reason)
+        .message(reason)
+        .conditions(conditions)
+        .build()
+}
+
 /// Create basic Pod status patch.
 pub fn make_status_with_containers(
     phase: Phase,
@@ -143,6 +157,7 @@ pub struct Status {
     container_statuses: Option<Vec<KubeContainerStatus>>,
     init_container_statuses: Option<Vec<KubeContainerStatus>>,
     conditions: Option<Vec<KubePodCondition>>,
+    pod_ip: Option<String>,
 }
 
 #[derive(Default)]
@@ -154,6 +169,7 @@ pub struct StatusBuilder {
     container_statuses: Option<Vec<KubeContainerStatus>>,
     init_container_statuses: Option<Vec<KubeContainerStatus>>,
     conditions: Option<Vec<KubePodCondition>>,
+    pod_ip: Option<String>,
 }
 
 impl StatusBuilder {
@@ -204,6 +220,12 @@ impl StatusBuilder {
         self
     }
 
+    /// Set the IP assigned to the Pod.
+    pub fn pod_ip(mut self, pod_ip: &str) -> StatusBuilder {
+        self.pod_ip = Some(pod_ip.to_string());
+        self
+    }
+
     /// Finalize Pod Status from builder.
     pub fn build(self) -> Status {
         // NOTE: Right now this is basically the same as just implementing it on `Status` (i.e. they
@@ -216,6 +238,7 @@ impl StatusBuilder {
             container_statuses: self.container_statuses,
             init_container_statuses: self.init_container_statuses,
             conditions: self.conditions,
+            pod_ip: self.pod_ip,
         }
     }
 }
@@ -278,6 +301,11 @@ impl ObjectStatus for Status {
             status.insert("conditions".to_string(), serde_json::json!(c));
         }
 
+        if let Some(ip) = self.pod_ip.clone() {
+            status.insert("podIPs".to_string(), serde_json::json!([{ "ip": ip }]));
+            status.insert("podIP".to_string(), serde_json::Value::String(ip));
+        }
+
         serde_json::json!(
             {
                 "metadata": {
@@ -0,0 +1,161 @@
+//! Pulls configured images ahead of time so that pods using them start without waiting on a
+//! pull.
+//!
+//! Images come from the `prePullImages` configuration and from the comma separated
+//! [`PRE_PULL_ANNOTATION_KEY`] annotation on the node, which is re-read on every cycle so the
+//! list can be changed without restarting the Kubelet. Each pre-pull waits until the store
+//! has not pulled anything for a pod for a while, uses the credentials of the first configured
+//! `prePullSecrets` secret that has some for the image's registry, and is deferred by the store
+//! if the registry's rate limit is nearly exhausted.
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::Api;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use tracing::{debug, info, warn};
+
+use crate::environment::Clock;
+use crate::secret::RegistryAuthResolver;
+use crate::store::Store;
+
+/// The node annotation listing additional images to pre-pull.
+pub const PRE_PULL_ANNOTATION_KEY: &str = "kubelet.krustlet.dev/pre-pull-images";
+
+/// How often a pre-pull waiting for the store to be idle checks again.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically pulls the configured images into the store.
+pub(crate) async fn run(
+    store: Arc<dyn Store + Send + Sync>,
+    client: kube::Client,
+    node_name: String,
+    configured: Vec<String>,
+    secrets: Vec<String>,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) -> anyhow::Result<()> {
+    let resolvers = resolvers_for(&client, &secrets);
+    let node_client: Api<KubeNode> = Api::all(client);
+    loop {
+        let annotation = match node_client.get(&node_name).await {
+            Ok(node) => node
+                .metadata
+                .annotations
+                .get(PRE_PULL_ANNOTATION_KEY)
+                .cloned(),
+            Err(e) => {
+                warn!(error = %e, "Unable to fetch node to read pre-pull annotation");
+                None
+            }
+        };
+
+        for image_ref in images_to_pre_pull(&configured, annotation.as_deref()) {
+            while store.is_busy() {
+                clock.sleep(IDLE_POLL_INTERVAL).await;
+            }
+            let auth = resolve_auth(&resolvers, &image_ref).await;
+            debug!(%image_ref, "Pre-pulling image");
+            if let Err(e) = store.pre_pull(&image_ref, &auth).await {
+                warn!(%image_ref, error = %e, "Unable to pre-pull image");
+            }
+        }
+
+        clock.sleep(interval).await;
+    }
+}
+
+/// Creates a resolver for each `namespace/name` secret, skipping any that aren't in that form.
+fn resolvers_for(client: &kube::Client, secrets: &[String]) -> Vec<RegistryAuthResolver> {
+    secrets
+        .iter()
+        .filter_map(|secret| match parse_secret(secret) {
+            Some((namespace, name)) => Some(RegistryAuthResolver::for_secrets(
+                client.clone(),
+                namespace,
+                vec![name.to_owned()],
+            )),
+            None => {
+                info!(secret = %secret, "Ignoring pre-pull secret not of the form namespace/name");
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_secret(secret: &str) -> Option<(&str, &str)> {
+    let mut parts = secret.trim().splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(namespace), Some(name)) if !namespace.is_empty() && !name.is_empty() => {
+            Some((namespace, name))
+        }
+        _ => None,
+    }
+}
+
+/// The credentials of the first secret that has some for the image's registry, or anonymous
+/// access if none do.
+async fn resolve_auth(resolvers: &[RegistryAuthResolver], image_ref: &Reference) -> RegistryAuth {
+    for resolver in resolvers {
+        match resolver.resolve_registry_auth(image_ref).await {
+            Ok(RegistryAuth::Anonymous) => (),
+            Ok(auth) => return auth,
+            Err(e) => warn!(%image_ref, error = %e, "Unable to read pre-pull secret"),
+        }
+    }
+    RegistryAuth::Anonymous
+}
+
+/// Combines the configured images with those listed in the node annotation, skipping
+/// duplicates and any reference that can't be parsed.
+fn images_to_pre_pull(configured: &[String], annotation: Option<&str>) -> Vec<Reference> {
+    let mut images: Vec<Reference> = Vec::new();
+    let annotated = annotation.unwrap_or_default().split(',');
+    for image in configured.iter().map(String::as_str).chain(annotated) {
+        let image = image.trim();
+        if image.is_empty() {
+            continue;
+        }
+        match Reference::try_from(image) {
+            Ok(r) if !images.contains(&r) => images.push(r),
+            Ok(_) => (),
+            Err(e) => info!(image, error = %e, "Ignoring invalid pre-pull image reference"),
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_images_to_pre_pull() {
+        let configured = vec!["webassembly.azurecr.io/hello-wasm:v1".to_owned()];
+        let images = images_to_pre_pull(
+            &configured,
+            Some("webassembly.azurecr.io/hello-wasm:v1, webassembly.azurecr.io/hello-world:v2,"),
+        );
+        assert_eq!(
+            images,
+            vec![
+                Reference::try_from("webassembly.azurecr.io/hello-wasm:v1").unwrap(),
+                Reference::try_from("webassembly.azurecr.io/hello-world:v2").unwrap(),
+            ]
+        );
+        assert!(images_to_pre_pull(&[], None).is_empty());
+    }
+
+    #[test]
+    fn test_parse_secret() {
+        assert_eq!(
+            parse_secret(" kube-system/registry-credentials"),
+            Some(("kube-system", "registry-credentials"))
+        );
+        assert_eq!(parse_secret("registry-credentials"), None);
+        assert_eq!(parse_secret("/registry-credentials"), None);
+        assert_eq!(parse_secret("kube-system/"), None);
+    }
+}
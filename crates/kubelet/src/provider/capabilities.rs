@@ -0,0 +1,173 @@
+//! Declarations of the pod features a provider supports.
+//!
+//! A provider that can't run part of a pod spec, such as host networking or a type of volume,
+//! says so in its [`ProviderCapabilities`]. Pods asking for anything the provider lacks are
+//! rejected as soon as they are registered, with a message naming each unsupported feature,
+//! rather than failing somewhere inside the provider.
+use k8s_openapi::api::core::v1::Volume as KubeVolume;
+
+use crate::pod::Pod;
+
+/// The volume types the Kubelet knows how to mount, by their field names in the pod spec.
+pub const KUBELET_VOLUME_TYPES: &[&str] = &[
+    "configMap",
+    "secret",
+    "persistentVolumeClaim",
+    "ephemeral",
+    "hostPath",
+    "downwardAPI",
+    "projected",
+    "csi",
+];
+
+/// The pod features a provider supports.
+///
+/// The default supports everything the Kubelet itself does, so providers only need to turn off
+/// what they can't do.
+#[derive(Clone, Debug)]
+pub struct ProviderCapabilities {
+    /// Whether pods may have init containers
+    pub init_containers: bool,
+    /// Whether pods may use the node's network namespace (`hostNetwork`)
+    pub host_network: bool,
+    /// Whether pods may use the node's process namespace (`hostPID`)
+    pub host_pid: bool,
+    /// Whether pods may use the node's IPC namespace (`hostIPC`)
+    pub host_ipc: bool,
+    /// Whether containers may run privileged
+    pub privileged: bool,
+    /// The volume types pods may use, by their field names in the pod spec (such as
+    /// `configMap`)
+    pub volume_types: Vec<String>,
+}
+
+impl Default for ProviderCapabilities {
+    fn default() -> Self {
+        ProviderCapabilities {
+            init_containers: true,
+            host_network: true,
+            host_pid: true,
+            host_ipc: true,
+            privileged: true,
+            volume_types: KUBELET_VOLUME_TYPES
+                .iter()
+                .map(|t| (*t).to_owned())
+                .collect(),
+        }
+    }
+}
+
+impl ProviderCapabilities {
+    /// Checks that the provider supports everything the pod asks for, returning a description
+    /// of every unsupported feature if it doesn't.
+    pub fn admit(&self, pod: &Pod) -> anyhow::Result<()> {
+        let mut unsupported = Vec::new();
+        let spec = pod.as_kube_pod().spec.as_ref();
+        let requested = |flag: Option<Option<bool>>| flag.flatten().unwrap_or(false);
+
+        if !self.init_containers && !pod.init_containers().is_empty() {
+            unsupported.push("init containers".to_owned());
+        }
+        if !self.host_network && requested(spec.map(|s| s.host_network)) {
+            unsupported.push("host networking (hostNetwork)".to_owned());
+        }
+        if !self.host_pid && requested(spec.map(|s| s.host_pid)) {
+            unsupported.push("the host's process namespace (hostPID)".to_owned());
+        }
+        if !self.host_ipc && requested(spec.map(|s| s.host_ipc)) {
+            unsupported.push("the host's IPC namespace (hostIPC)".to_owned());
+        }
+        if !self.privileged {
+            for container in pod.all_containers() {
+                let privileged = container
+                    .security_context()
+                    .and_then(|s| s.privileged)
+                    .unwrap_or(false);
+                if privileged {
+                    unsupported.push(format!("privileged container {}", container.name()));
+                }
+            }
+        }
+        for volume in pod.volumes() {
+            match volume_type(volume) {
+                Some(t) if self.volume_types.iter().any(|s| *s == t) => (),
+                Some(t) => unsupported.push(format!("volume {} of type {}", volume.name, t)),
+                None => unsupported.push(format!("volume {} of unknown type", volume.name)),
+            }
+        }
+
+        if unsupported.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Pod uses features the provider does not support: {}",
+                unsupported.join(", ")
+            ))
+        }
+    }
+}
+
+/// The type of a volume, as the name of the field that defines its source in the pod spec.
+fn volume_type(volume: &KubeVolume) -> Option<String> {
+    match serde_json::to_value(volume) {
+        Ok(serde_json::Value::Object(fields)) => fields.into_iter().find_map(|(name, value)| {
+            if name != "name" && !value.is_null() {
+                Some(name)
+            } else {
+                None
+            }
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(spec: serde_json::Value) -> Pod {
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "default"},
+                "spec": spec,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_admits_supported_pods() {
+        let pod = pod(serde_json::json!({
+            "hostNetwork": true,
+            "containers": [{"name": "server", "securityContext": {"privileged": true}}],
+            "initContainers": [{"name": "setup"}],
+            "volumes": [{"name": "config", "configMap": {"name": "web-config"}}],
+        }));
+        assert!(ProviderCapabilities::default().admit(&pod).is_ok());
+    }
+
+    #[test]
+    fn test_names_unsupported_features() {
+        let capabilities = ProviderCapabilities {
+            host_network: false,
+            privileged: false,
+            ..Default::default()
+        };
+        let pod = pod(serde_json::json!({
+            "hostNetwork": true,
+            "containers": [
+                {"name": "server", "securityContext": {"privileged": true}},
+                {"name": "sidecar", "securityContext": {"privileged": false}},
+            ],
+            "volumes": [
+                {"name": "config", "configMap": {"name": "web-config"}},
+                {"name": "shared", "nfs": {"server": "nfs.example.com", "path": "/"}},
+            ],
+        }));
+        assert_eq!(
+            capabilities.admit(&pod).unwrap_err().to_string(),
+            "Pod uses features the provider does not support: host networking (hostNetwork), privileged container server, volume shared of type nfs"
+        );
+    }
+}
@@ -2,21 +2,26 @@
 use std::collections::HashMap;
 
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
-use kube::api::Api;
+use k8s_openapi::api::core::v1::EnvVarSource;
 use std::sync::Arc;
 use thiserror::Error;
 use tracing::{debug, error, info};
 
 use crate::container::Container;
+use crate::dependency::{self, DependencyTracker};
+use crate::handle::PodRuntimeInfo;
 use crate::log::Sender;
 use crate::node::Builder;
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Pod;
 use crate::pod::Status as PodStatus;
+use crate::pod::{Pod, PodChange, UnfinishedPod};
 use crate::resources::DeviceManager;
 use krator::{ObjectState, State};
 
+mod capabilities;
+
+pub use capabilities::{ProviderCapabilities, KUBELET_VOLUME_TYPES};
+
 /// A back-end for a Kubelet.
 ///
 /// The primary responsibility of a Provider is to execute a workload (or schedule it on an external executor)
@@ -116,25 +121,59 @@ pub trait Provider: Sized + Send + Sync + 'static {
         Ok(())
     }
 
+    /// The store the provider pulls modules into, if it uses one. Providing the store allows
+    /// the Kubelet to pre-pull the images configured for the node.
+    fn store(&self) -> Option<Arc<dyn crate::store::Store + Send + Sync>> {
+        None
+    }
+
+    /// The tracker the provider records the disk usage of its pods in, if it measures it.
+    /// Providing the tracker allows the Kubelet to report the usage in `/stats/summary`.
+    fn disk_usage_tracker(&self) -> Option<Arc<crate::stats::DiskUsageTracker>> {
+        None
+    }
+
     /// Hook to allow provider to introduced shared state into Pod state.
     // TODO: Is there a way to provide a default implementation of this if Self::PodState: Default?
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState>;
 
     /// Hook to allow the provider to react to the Kubelet being shut down
     ///
-    /// It receives only the node name as a parameter in case the provider wants to set a condition
-    /// on the object - for example to to signify that it did not crash but performed an orderly
-    /// shutdown.
+    /// This is where providers should release what they hold outside of individual pods: flush
+    /// caches, stop hosts and close sockets. It receives the node name in case the provider wants
+    /// to set a condition on the object - for example to to signify that it did not crash but
+    /// performed an orderly shutdown.
     ///
-    /// There are currently no mechanisms in place to propagate the shutdown trigger to the state
-    /// machine, providers will have to implement this themselves via a [`std::sync::mpsc::channel`]
-    /// or the shared state or something similar.
+    /// The Kubelet makes the following guarantees about when the hook is called:
+    ///
+    /// * It is called at most once, after a shutdown was triggered by an interrupt, a system
+    ///   shutdown or one of the Kubelet's background services failing. It is not called if the
+    ///   pod operator exits on its own.
+    /// * The webserver, node status updater, image pre-puller, plugin registrar and device
+    ///   manager have all been stopped, so no new log or exec requests arrive while it runs.
+    ///   Requests that were already streaming may still be in flight.
+    /// * The pod operator keeps running until the hook returns, so pods the provider evicts
+    ///   still run through their state machines (and their `async_drop`). Pod operations that
+    ///   were in flight when the shutdown started are not waited for; providers that need their
+    ///   pods to be gone should wait for that themselves, as [`crate::node::drain`] does.
+    /// * If `deadline` is set, the hook is cancelled once it passes and the Kubelet exits. The
+    ///   deadline is the shutdown grace period after the hook was called, and is `None` when no
+    ///   grace period is configured. Providers should bound their own waits by it so that the
+    ///   cleanup that matters most isn't the part that gets cancelled.
+    ///
+    /// Returning an error makes [`crate::Kubelet::start`] return that error once the Kubelet has
+    /// shut down.
     ///
     /// # Arguments
     ///
     /// * `node_name` - The name of the node object that was created by this Kubelet instance
+    /// * `deadline` - The point at which the hook will be cancelled, if any
     ///
-    async fn shutdown(&self, node_name: &str) -> anyhow::Result<()> {
+    async fn shutdown(
+        &self,
+        node_name: &str,
+        _deadline: Option<tokio::time::Instant>,
+    ) -> anyhow::Result<()> {
         info!(node_name, "Shutdown triggered for node, since no custom shutdown behavior was implemented Kubelet will simply shut down now");
         Ok(())
     }
@@ -148,6 +187,22 @@ pub trait Provider: Sized + Send + Sync + 'static {
         sender: Sender,
     ) -> anyhow::Result<()>;
 
+    /// Attach a client to a running container, streaming the container's output from now on
+    /// into the sender and, if `stdin` is given, what the client writes to the container's stdin.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn attach(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _stdin: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
+        _sender: Sender,
+    ) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
     /// Execute a given command on a workload and then return the result.
     ///
     /// The default implementation of this returns a message that this feature is
@@ -156,6 +211,68 @@ pub trait Provider: Sized + Send + Sync + 'static {
         Err(NotImplementedError.into())
     }
 
+    /// Describe the runtime handles of every pod the provider is running, for debugging.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn runtime_info(&self) -> anyhow::Result<Vec<PodRuntimeInfo>> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Hook called when only the labels or annotations of a pod changed, for example to refresh
+    /// Downward API files that expose them.
+    ///
+    /// The pod's state machine always sees the latest manifest, so the default implementation
+    /// does nothing.
+    async fn update_pod_metadata(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Hook called when a part of the pod spec that applies to the pod as a whole and may be
+    /// changed in place ([`PodChange::Tolerations`] or [`PodChange::ActiveDeadline`]) changed.
+    ///
+    /// The default implementation does nothing.
+    async fn update_pod_spec(&self, _pod: &Pod, _changes: &[PodChange]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Hook called when the image or environment of a running container changed
+    /// ([`PodChange::Image`] or [`PodChange::Env`]).
+    ///
+    /// The default implementation returns a message that this feature is not available, in which
+    /// case the container keeps running with its previous configuration. Override this only when
+    /// the provider can replace a container in place.
+    async fn update_container(
+        &self,
+        _pod: &Pod,
+        _container: &str,
+        _change: &PodChange,
+    ) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Hook called as soon as a pod is marked for deletion, ahead of any new pods or updates to
+    /// other pods that are still waiting to be handled. The pod's state machine still runs to
+    /// its deleted state afterwards, so this is only a chance to start releasing resources
+    /// early, such as by stopping the pod's containers.
+    ///
+    /// The default implementation does nothing.
+    async fn pod_terminating(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Hook called when the Kubelet starts, before it connects to the API server, for every pod
+    /// it had not finished handling when it last stopped, for example because it crashed while
+    /// the pod was being created or deleted.
+    ///
+    /// Providers should release anything the pod may have left half-created, such as volumes or
+    /// runtime state on disk. Pods that still exist are delivered again by the API server once
+    /// the Kubelet connects, and start over from their initial state. The default implementation
+    /// does nothing.
+    async fn recover_pod(&self, _pod: &UnfinishedPod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Resolve the environment variables for a container.
     ///
     /// This generally should not be overwritten unless you need to handle
@@ -175,13 +292,8 @@ pub trait Provider: Sized + Send + Sync + 'static {
             let value = match env_var.value {
                 Some(v) => v,
                 None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
+                    on_missing_env_value(env_var.value_from, client, None, pod, &field_map(pod))
+                        .await
                 }
             };
             env.insert(key, value);
@@ -196,6 +308,12 @@ pub trait VolumeSupport {
     fn volume_path(&self) -> Option<&std::path::Path> {
         None
     }
+
+    /// Gets the tracker that watches the ConfigMaps and Secrets referenced by pods, so they are
+    /// kept up to date rather than fetched each time they are used. Defaults to `None`
+    fn dependency_tracker(&self) -> Option<DependencyTracker> {
+        None
+    }
 }
 
 /// A trait for specifying whether plugins are supported. Defaults to `None`
@@ -214,6 +332,22 @@ pub trait DevicePluginSupport {
     }
 }
 
+/// A trait for specifying whether the images pods run are checked against a node-level
+/// [`RegistryPolicy`](crate::store::RegistryPolicy), and whether their attestations are
+/// recorded. Defaults to neither
+pub trait ImagePolicySupport {
+    /// Gets the policy that the images of pods have to satisfy to be admitted
+    fn registry_policy(&self) -> Option<Arc<crate::store::RegistryPolicy>> {
+        None
+    }
+
+    /// Whether the digests of the SBOMs and provenance attestations attached to the images of
+    /// a pod are recorded in its annotations when it starts
+    fn record_image_attestations(&self) -> bool {
+        false
+    }
+}
+
 /// Resolve the environment variables for a container.
 ///
 /// This generally should not be overwritten unless you need to handle
@@ -225,6 +359,18 @@ pub async fn env_vars(
     container: &Container,
     pod: &Pod,
     client: &kube::Client,
+) -> HashMap<String, String> {
+    env_vars_with_tracker(container, pod, client, None).await
+}
+
+/// Resolve the environment variables for a container, as [`env_vars`] does. If a
+/// [`DependencyTracker`] is given, ConfigMaps and Secrets are read from its watches instead of
+/// being fetched for every pod.
+pub async fn env_vars_with_tracker(
+    container: &Container,
+    pod: &Pod,
+    client: &kube::Client,
+    tracker: Option<&DependencyTracker>,
 ) -> HashMap<String, String> {
     let mut env = HashMap::new();
 
@@ -233,7 +379,7 @@ pub async fn env_vars(
         let value = match env_var.value {
             Some(v) => v,
             None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
+                on_missing_env_value(env_var.value_from, client, tracker, pod, &field_map(pod))
                     .await
             }
         };
@@ -249,7 +395,8 @@ pub async fn env_vars(
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
     client: &kube::Client,
-    ns: &str,
+    tracker: Option<&DependencyTracker>,
+    pod: &Pod,
     fields: &HashMap<String, String>,
 ) -> String {
     let env_src = match env_var_source {
@@ -260,10 +407,7 @@ async fn on_missing_env_value(
     // ConfigMaps
     if let Some(cfkey) = env_src.config_map_key_ref.as_ref() {
         let name = cfkey.name.as_deref().unwrap_or_default();
-        match Api::<ConfigMap>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
+        match dependency::config_map(client, tracker, pod, name).await {
             Ok(cfgmap) => {
                 // I am not totally clear on what the outcome should
                 // be of a cfgmap key miss. So for now just return an
@@ -279,10 +423,7 @@ async fn on_missing_env_value(
     // Secrets
     if let Some(seckey) = env_src.secret_key_ref.as_ref() {
         let name = seckey.name.as_deref().unwrap_or_default();
-        match Api::<Secret>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
+        match dependency::secret(client, tracker, pod, name).await {
             Ok(mut secret) => {
                 // I am not totally clear on what the outcome should
                 // be of a secret key miss. So for now just return an
@@ -0,0 +1,253 @@
+//! Per-container scratch space for temporary files.
+//!
+//! Providers can give every container a directory of its own to use as `/tmp`, which is emptied
+//! whenever the container starts and removed along with its pod. Its size is limited by the
+//! container's `ephemeral-storage` limit, or by a node wide default quota:
+//!
+//! ```text
+//! <root>/
+//!   <namespace>_<pod name>/
+//!     <container name>/
+//! ```
+//!
+//! On Linux, when the Kubelet is allowed to mount filesystems, a directory with a quota is a
+//! tmpfs of that size, so the kernel enforces the quota and writes past it fail with `ENOSPC`
+//! like they would on a full disk. Otherwise the Kubelet measures the directory every few seconds
+//! and reports when a container has gone over its quota, so the provider can stop it.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::container::Container;
+use crate::environment::Clock;
+use crate::pod::dirs::{join_name, pod_dir};
+use crate::pod::{Pod, PodKey};
+use crate::stats::{container_ephemeral_storage_limit, dir_usage};
+
+/// How often the usage of a scratch directory is measured when the kernel doesn't enforce its
+/// quota
+const USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Creates and removes the scratch directories of containers.
+pub struct ScratchSpace {
+    root: PathBuf,
+    default_quota: Option<u64>,
+}
+
+impl ScratchSpace {
+    /// Creates a manager of scratch directories beneath `root`, limiting containers that don't set
+    /// an `ephemeral-storage` limit to `default_quota` bytes.
+    pub fn new(root: PathBuf, default_quota: Option<u64>) -> Self {
+        ScratchSpace {
+            root,
+            default_quota,
+        }
+    }
+
+    /// Creates an empty scratch directory for a container that is starting, removing anything
+    /// a previous run of the container left behind.
+    pub async fn create(&self, pod: &Pod, container: &Container) -> anyhow::Result<ScratchDir> {
+        let path = join_name(&pod_dir(&self.root, &PodKey::from(pod))?, container.name())?;
+        let quota = container_ephemeral_storage_limit(container).or(self.default_quota);
+        let dir = path.clone();
+        let tmpfs = tokio::task::spawn_blocking(move || -> anyhow::Result<bool> {
+            remove(&dir)?;
+            std::fs::create_dir_all(&dir)?;
+            Ok(match quota {
+                Some(quota) => mount_tmpfs(&dir, quota),
+                None => false,
+            })
+        })
+        .await??;
+        Ok(ScratchDir { path, quota, tmpfs })
+    }
+
+    /// The number of bytes used by the scratch directory of each of the pod's containers, by
+    /// container name.
+    pub async fn pod_usage(&self, pod: &PodKey) -> anyhow::Result<BTreeMap<String, u64>> {
+        let pod_path = pod_dir(&self.root, pod)?;
+        tokio::task::spawn_blocking(move || -> anyhow::Result<BTreeMap<String, u64>> {
+            let mut usage = BTreeMap::new();
+            if !pod_path.exists() {
+                return Ok(usage);
+            }
+            for entry in std::fs::read_dir(&pod_path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                usage.insert(name, dir_usage(&entry.path())?);
+            }
+            Ok(usage)
+        })
+        .await?
+    }
+
+    /// Removes the scratch directories of the pod and all of its containers.
+    pub async fn remove_pod(&self, pod: &PodKey) -> anyhow::Result<()> {
+        let pod_path = pod_dir(&self.root, pod)?;
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            if !pod_path.exists() {
+                return Ok(());
+            }
+            for entry in std::fs::read_dir(&pod_path)? {
+                remove(&entry?.path())?;
+            }
+            std::fs::remove_dir_all(&pod_path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// The scratch directory of a single container.
+#[derive(Clone, Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+    quota: Option<u64>,
+    tmpfs: bool,
+}
+
+impl ScratchDir {
+    /// The directory on the host.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The most bytes the container can write to the directory, if it is limited.
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// Watches the usage of the directory, calling `exceeded` with the number of bytes used once
+    /// it is over the quota. Returns `None` if there is nothing to watch, because the directory
+    /// doesn't have a quota or the kernel enforces it. The usage is measured whenever `clock`
+    /// reaches the next check, and watching stops when the returned watch is dropped.
+    pub fn watch_quota<F>(&self, clock: Arc<dyn Clock>, exceeded: F) -> Option<QuotaWatch>
+    where
+        F: FnOnce(u64) + Send + 'static,
+    {
+        let quota = match self.quota {
+            Some(quota) if !self.tmpfs => quota,
+            _ => return None,
+        };
+        let path = self.path.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                clock.sleep(USAGE_CHECK_INTERVAL).await;
+                let dir = path.clone();
+                let used = tokio::task::spawn_blocking(move || dir_usage(&dir))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|used| used.map_err(anyhow::Error::from));
+                match used {
+                    Ok(used) if used > quota => {
+                        exceeded(used);
+                        return;
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        warn!(error = %e, path = %path.display(), "Unable to measure scratch space")
+                    }
+                }
+            }
+        });
+        Some(QuotaWatch { handle })
+    }
+}
+
+/// Watches the usage of a scratch directory until it is dropped.
+pub struct QuotaWatch {
+    handle: JoinHandle<()>,
+}
+
+impl Drop for QuotaWatch {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Removes a scratch directory, unmounting it first if it is a tmpfs.
+fn remove(dir: &Path) -> std::io::Result<()> {
+    unmount_tmpfs(dir);
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mount_tmpfs(dir: &Path, quota: u64) -> bool {
+    use nix::mount::{mount, MsFlags};
+    let options = format!("size={},mode=0700", quota);
+    match mount(
+        Some("tmpfs"),
+        dir,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some(options.as_str()),
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::debug!(error = %e, path = %dir.display(), "Unable to mount tmpfs for scratch space, measuring its usage instead");
+            false
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_tmpfs(_dir: &Path, _quota: u64) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn unmount_tmpfs(dir: &Path) {
+    // Fails harmlessly if the directory isn't a mount point
+    let _ = nix::mount::umount(dir);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount_tmpfs(_dir: &Path) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, Pod as KubePod};
+
+    #[tokio::test]
+    async fn test_scratch_dir_lifecycle() {
+        let root = tempfile::tempdir().unwrap();
+        let space = ScratchSpace::new(root.path().to_owned(), Some(1024));
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "default"}
+            }))
+            .unwrap(),
+        );
+        let container = Container::new(
+            &serde_json::from_value::<KubeContainer>(serde_json::json!({
+                "name": "server",
+                "resources": {"limits": {"ephemeral-storage": "2Ki"}}
+            }))
+            .unwrap(),
+        );
+
+        let dir = space.create(&pod, &container).await.unwrap();
+        assert_eq!(dir.path(), root.path().join("default_web").join("server"));
+        assert_eq!(dir.quota(), Some(2048));
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(dir.path().join("nested").join("file"), vec![0; 100]).unwrap();
+        assert_eq!(dir_usage(dir.path()).unwrap(), 100);
+        let usage = space.pod_usage(&PodKey::from(&pod)).await.unwrap();
+        assert_eq!(usage.get("server"), Some(&100));
+
+        // A restarted container starts with an empty directory
+        let dir = space.create(&pod, &container).await.unwrap();
+        assert_eq!(dir_usage(dir.path()).unwrap(), 0);
+
+        space.remove_pod(&PodKey::from(&pod)).await.unwrap();
+        assert!(!root.path().join("default_web").exists());
+    }
+}
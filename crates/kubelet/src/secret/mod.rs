@@ -23,6 +23,16 @@ impl RegistryAuthResolver {
         }
     }
 
+    /// Creates a resolver for the named image pull secrets in the given namespace, for pulls
+    /// made on behalf of no pod in particular
+    pub fn for_secrets(client: kube::Client, namespace: &str, secret_names: Vec<String>) -> Self {
+        RegistryAuthResolver {
+            kube_client: client,
+            pod_namespace: namespace.to_owned(),
+            image_pull_secret_names: secret_names,
+        }
+    }
+
     /// Get the registry authentication method appropriate to the given image reference
     pub async fn resolve_registry_auth(
         &self,
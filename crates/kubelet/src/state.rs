@@ -47,6 +47,7 @@
 //!
 
 pub mod common;
+pub mod replay;
 
 #[cfg(feature = "derive")]
 #[doc(hidden)]
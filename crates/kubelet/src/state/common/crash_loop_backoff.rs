@@ -36,8 +36,12 @@ impl<P: GenericProvider> State<P::PodState> for CrashLoopBackoff<P> {
         Transition::next(self, next)
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "CrashLoopBackoff"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "CrashLoopBackoff",
+            make_conditions(pod, Readiness::NotReady, &*pod_state.clock()),
+        ))
     }
 }
 
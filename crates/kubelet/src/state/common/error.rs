@@ -2,7 +2,7 @@
 
 use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
-use super::{GenericPodState, GenericProvider, ThresholdTrigger};
+use super::{GenericPodState, GenericProvider, GenericProviderState, ThresholdTrigger};
 use crate::pod::state::prelude::*;
 
 /// The Pod failed to run.
@@ -32,7 +32,7 @@ impl<P: GenericProvider> Error<P> {
 impl<P: GenericProvider> State<P::PodState> for Error<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
         _pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
@@ -42,7 +42,8 @@ impl<P: GenericProvider> State<P::PodState> for Error<P> {
                 Transition::next(self, next)
             }
             ThresholdTrigger::Untriggered => {
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                let clock = provider_state.read().await.clock();
+                clock.sleep(std::time::Duration::from_secs(5)).await;
                 let next = Registered::<P>::default();
                 Transition::next(self, next)
             }
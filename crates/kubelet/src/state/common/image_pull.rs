@@ -3,9 +3,44 @@
 use super::image_pull_backoff::ImagePullBackoff;
 use super::volume_mount::VolumeMount;
 use super::{BackoffSequence, GenericPodState, GenericProvider, GenericProviderState};
+use crate::container::Container;
+use crate::node::problem;
 use crate::pod::state::prelude::*;
+use crate::pod::{patch_status, record_event, EVENT_TYPE_WARNING};
+use crate::provider::ImagePolicySupport;
+use crate::secret::RegistryAuthResolver;
+use crate::state::replay::{self, RecordedModules};
+use crate::store::{PullErrorKind, Store};
 
-use tracing::{error, instrument};
+use k8s_openapi::api::core::v1::{
+    ContainerState, ContainerStateWaiting, ContainerStatus as KubeContainerStatus, Pod as KubePod,
+};
+use kube::api::{Patch, PatchParams};
+use kube::Api;
+use oci_distribution::manifest;
+use oci_distribution::Reference;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tracing::{error, instrument, warn};
+
+/// The annotation the digests of a container's SBOMs are recorded in, followed by the
+/// container's name
+const SBOM_ANNOTATION_PREFIX: &str = "sbom.krustlet.dev/";
+/// The annotation the digests of a container's provenance attestations are recorded in,
+/// followed by the container's name
+const PROVENANCE_ANNOTATION_PREFIX: &str = "provenance.krustlet.dev/";
+
+/// The outcome of pulling a pod's modules, which is recorded for replays.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum PullOutcome {
+    Pulled(RecordedModules),
+    Failed {
+        kind: PullErrorKind,
+        message: String,
+    },
+}
 
 /// Kubelet is pulling container images.
 pub struct ImagePull<P: GenericProvider> {
@@ -43,17 +78,61 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
-        let (client, store) = {
+        let (client, store, record_image_attestations) = {
             // Minimise the amount of time we hold any locks
             let state_reader = provider_state.read().await;
-            (state_reader.client(), state_reader.store())
+            (
+                state_reader.client(),
+                state_reader.store(),
+                state_reader.record_image_attestations(),
+            )
         };
-        let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
-        let modules = match store.fetch_pod_modules(&pod, &auth_resolver).await {
-            Ok(m) => m,
-            Err(e) => {
-                error!(error = %e);
-                return Transition::next(self, ImagePullBackoff::<P>::default());
+        let auth_resolver = RegistryAuthResolver::new(client.clone(), &pod);
+        let outcome = replay::provider_result(&pod, "imagePull", async {
+            Ok(match store.fetch_pod_modules(&pod, &auth_resolver).await {
+                Ok(modules) => {
+                    // Attestations are only for audit trails, so they are recorded without
+                    // holding up the pod, and replays don't fetch them
+                    if record_image_attestations {
+                        tokio::spawn(record_attestations(
+                            client.clone(),
+                            store.clone(),
+                            pod.clone(),
+                        ));
+                    }
+                    PullOutcome::Pulled(RecordedModules(modules))
+                }
+                Err(e) => PullOutcome::Failed {
+                    kind: PullErrorKind::classify(&e),
+                    message: format!("{:#}", e),
+                },
+            })
+        })
+        .await
+        .unwrap_or_else(|e| PullOutcome::Failed {
+            kind: PullErrorKind::Other,
+            message: format!("{:#}", e),
+        });
+        let modules = match outcome {
+            PullOutcome::Pulled(RecordedModules(modules)) => modules,
+            PullOutcome::Failed { kind, message } => {
+                error!(error = %message, reason = %kind, "Unable to pull images for pod");
+                if kind == PullErrorKind::Timeout {
+                    problem::report(problem::REGISTRY_UNREACHABLE, "RegistryTimeout", &message);
+                }
+
+                record_event(&client, &pod, EVENT_TYPE_WARNING, kind.reason(), &message).await;
+                let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+                patch_status(&api, pod.name(), pull_error_status(&pod, kind, &message)).await;
+
+                if kind.is_retryable() {
+                    return Transition::next(self, ImagePullBackoff::<P>::default());
+                }
+                return Transition::Complete(Err(anyhow::anyhow!(
+                    "{}: {}",
+                    kind.reason(),
+                    message
+                )));
             }
         };
         pod_state.set_modules(modules).await;
@@ -61,10 +140,123 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
         Transition::next(self, VolumeMount::<P>::default())
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "ImagePull"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "ImagePull",
+            make_conditions(pod, Readiness::NotInitialized, &*pod_state.clock()),
+        ))
     }
 }
 
+/// Records the digests of the SBOMs and provenance attestations attached to the modules pulled
+/// for the pod's images in its annotations. These are for audit trails rather than needed to
+/// run the pod, so failures are only logged.
+async fn record_attestations(client: kube::Client, store: Arc<dyn Store + Send + Sync>, pod: Pod) {
+    let auth_resolver = RegistryAuthResolver::new(client.clone(), &pod);
+    let mut annotations = serde_json::Map::new();
+    for container in pod.all_containers() {
+        let image = match container.image() {
+            Ok(Some(image)) => image,
+            _ => continue,
+        };
+        // A tag may have moved on since the module was pulled, so the attestations are looked
+        // up for the module that was
+        let image = match pulled_image(&*store, &image).await {
+            Ok(image) => image,
+            Err(e) => {
+                warn!(error = %e, %image, "Unable to look up the digest of pulled image");
+                continue;
+            }
+        };
+        let referrers = match auth_resolver.resolve_registry_auth(&image).await {
+            Ok(auth) => store.referrers(&image, &auth, None).await,
+            Err(e) => Err(e),
+        };
+        let referrers = match referrers {
+            Ok(referrers) => referrers,
+            Err(e) => {
+                warn!(error = %e, %image, "Unable to list attestations of image");
+                continue;
+            }
+        };
+        let digests_of = |wanted: fn(&str) -> bool| {
+            referrers
+                .iter()
+                .filter(|r| r.artifact_type.as_deref().map(wanted).unwrap_or(false))
+                .map(|r| r.digest.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let sboms = digests_of(manifest::is_sbom);
+        let provenance = digests_of(|t| t == manifest::IN_TOTO_ARTIFACT_TYPE);
+        if !sboms.is_empty() {
+            let key = format!("{}{}", SBOM_ANNOTATION_PREFIX, container.name());
+            annotations.insert(key, sboms.into());
+        }
+        if !provenance.is_empty() {
+            let key = format!("{}{}", PROVENANCE_ANNOTATION_PREFIX, container.name());
+            annotations.insert(key, provenance.into());
+        }
+    }
+    if annotations.is_empty() {
+        return;
+    }
+
+    let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+    let patch = serde_json::json!({ "metadata": { "annotations": annotations } });
+    if let Err(e) = api
+        .patch(pod.name(), &PatchParams::default(), &Patch::Merge(&patch))
+        .await
+    {
+        warn!(error = %e, "Unable to record image attestations in pod annotations");
+    }
+}
+
+/// The image pinned to the digest of the module the store pulled for it, or the image itself if
+/// the store doesn't know that digest.
+async fn pulled_image(
+    store: &(dyn Store + Send + Sync),
+    image: &Reference,
+) -> anyhow::Result<Reference> {
+    if image.digest().is_some() {
+        return Ok(image.clone());
+    }
+    match store.digest(image).await? {
+        Some(digest) => Reference::try_from(format!(
+            "{}/{}@{}",
+            image.registry(),
+            image.repository(),
+            digest
+        ))
+        .map_err(|e| anyhow::anyhow!("invalid digest {} for image {}: {}", digest, image, e)),
+        None => Ok(image.clone()),
+    }
+}
+
+/// Create a status patch that marks every container as waiting on the failed pull.
+fn pull_error_status(pod: &Pod, kind: PullErrorKind, message: &str) -> PodStatus {
+    let waiting = |container: &Container| KubeContainerStatus {
+        name: container.name().to_string(),
+        ready: false,
+        started: Some(false),
+        state: Some(ContainerState {
+            waiting: Some(ContainerStateWaiting {
+                reason: Some(kind.reason().to_string()),
+                message: Some(message.to_string()),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    StatusBuilder::new()
+        .phase(Phase::Pending)
+        .reason(kind.reason())
+        .message(message)
+        .container_statuses(pod.containers().iter().map(waiting).collect())
+        .init_container_statuses(pod.init_containers().iter().map(waiting).collect())
+        .build()
+}
+
 impl<P: GenericProvider> TransitionTo<ImagePullBackoff<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for ImagePull<P> {}
@@ -5,7 +5,9 @@
 
 use crate::pod::state::prelude::PodStatus;
 use crate::pod::Pod;
-use crate::provider::{DevicePluginSupport, PluginSupport, VolumeSupport};
+use crate::provider::{
+    DevicePluginSupport, ImagePolicySupport, PluginSupport, ProviderCapabilities, VolumeSupport,
+};
 use krator::{ObjectState, State};
 use std::collections::HashMap;
 
@@ -14,6 +16,7 @@ pub mod error;
 pub mod image_pull;
 pub mod image_pull_backoff;
 pub mod registered;
+pub mod rejected;
 pub mod resources;
 pub mod terminated;
 pub mod volume_mount;
@@ -46,6 +49,11 @@ pub trait GenericProviderState: 'static + Send + Sync {
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Gets the clock pods wait on before retrying. The default is the system clock, and
+    /// providers should return the clock of the Kubelet's environment.
+    fn clock(&self) -> std::sync::Arc<dyn crate::environment::Clock> {
+        std::sync::Arc::new(crate::environment::SystemClock)
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -69,13 +77,22 @@ pub trait GenericPodState: ObjectState<Manifest = Pod, Status = PodStatus> {
     /// Increments an error count and returns whether the number of errors
     /// has passed the provider's threshold for entering CrashLoopBackoff.
     async fn record_error(&mut self) -> ThresholdTrigger;
+    /// Gets the clock that timestamps the pod's condition transitions. The default is the system
+    /// clock, and providers should return the clock of the Kubelet's environment.
+    fn clock(&self) -> std::sync::Arc<dyn crate::environment::Clock> {
+        std::sync::Arc::new(crate::environment::SystemClock)
+    }
 }
 
 /// A provider that wants to use the generic states implemented in this
 /// module.
 pub trait GenericProvider: 'static + Send + Sync {
     /// The state of the provider itself.
-    type ProviderState: GenericProviderState + VolumeSupport + PluginSupport + DevicePluginSupport;
+    type ProviderState: GenericProviderState
+        + VolumeSupport
+        + PluginSupport
+        + DevicePluginSupport
+        + ImagePolicySupport;
     /// The state that is passed between Pod state handlers.
     type PodState: GenericPodState + ObjectState<SharedState = Self::ProviderState>;
     /// The state to which pods should transition after they have completed
@@ -83,6 +100,12 @@ pub trait GenericProvider: 'static + Send + Sync {
     /// any pod binary (for example, the state which runs init containers).
     type RunState: Default + State<Self::PodState>;
 
+    /// The pod features the provider supports. Pods that ask for anything else are rejected
+    /// when they are registered. The default supports everything the Kubelet does.
+    fn capabilities() -> ProviderCapabilities {
+        ProviderCapabilities::default()
+    }
+
     /// Validates that the pod specification is compatible with the provider.
     /// If not, implementations should return an Err value with
     /// a description of why the pod cannot be run.
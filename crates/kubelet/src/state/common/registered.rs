@@ -1,11 +1,17 @@
 //! The Kubelet is aware of the Pod.
 
 use crate::pod::state::prelude::*;
+use crate::pod::{record_event, EVENT_TYPE_WARNING};
+use crate::provider::ImagePolicySupport;
 use tracing::{debug, error, info, instrument};
 
 use super::error::Error;
+use super::rejected::Rejected;
 use super::resources::Resources;
-use super::GenericProvider;
+use super::{GenericPodState, GenericProvider, GenericProviderState};
+
+/// The reason pods running images the node's registry policy doesn't allow are rejected with.
+const IMAGE_NOT_ALLOWED: &str = "ImageNotAllowed";
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,12 +36,12 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     #[instrument(
         level = "info",
-        skip(self, _provider_state, _pod_state, pod),
+        skip(self, provider_state, _pod_state, pod),
         fields(pod_name)
     )]
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         _pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
@@ -44,6 +50,31 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
         tracing::Span::current().record("pod_name", &pod.name());
 
         debug!("Preparing to register pod");
+        if let Err(e) = P::capabilities().admit(&pod) {
+            error!(error = %e, "Rejecting pod");
+            let next = Rejected::<P>::new(e.to_string());
+            return Transition::next(self, next);
+        }
+        let (client, policy) = {
+            let state = provider_state.read().await;
+            (state.client(), state.registry_policy())
+        };
+        if let Some(policy) = policy {
+            if let Err(e) = policy.admit(&pod) {
+                error!(error = %e, "Rejecting pod");
+                let message = e.to_string();
+                record_event(
+                    &client,
+                    &pod,
+                    EVENT_TYPE_WARNING,
+                    IMAGE_NOT_ALLOWED,
+                    &message,
+                )
+                .await;
+                let next = Rejected::<P>::with_reason(IMAGE_NOT_ALLOWED, message);
+                return Transition::next(self, next);
+            }
+        }
         match P::validate_pod_and_containers_runnable(&pod) {
             Ok(_) => (),
             Err(e) => {
@@ -57,10 +88,161 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
         Transition::next(self, next)
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "Registered"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "Registered",
+            make_conditions(pod, Readiness::NotInitialized, &*pod_state.clock()),
+        ))
     }
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for Registered<P> {}
+impl<P: GenericProvider> TransitionTo<Rejected<P>> for Registered<P> {}
 impl<P: GenericProvider> TransitionTo<Resources<P>> for Registered<P> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::{DevicePluginSupport, PluginSupport, VolumeSupport};
+    use crate::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
+    use crate::store::RegistryPolicy;
+    use futures::pin_mut;
+    use http::{Request as HttpRequest, Response as HttpResponse};
+    use hyper::Body;
+    use krator::ObjectStatus;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tower_test::mock;
+
+    struct MockProvider;
+
+    struct ProviderState {
+        client: kube::Client,
+    }
+
+    impl VolumeSupport for ProviderState {}
+    impl PluginSupport for ProviderState {}
+    impl DevicePluginSupport for ProviderState {}
+    impl ImagePolicySupport for ProviderState {
+        fn registry_policy(&self) -> Option<Arc<RegistryPolicy>> {
+            Some(Arc::new(RegistryPolicy::new(
+                vec!["webassembly.azurecr.io".to_owned()],
+                false,
+            )))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GenericProviderState for ProviderState {
+        fn client(&self) -> kube::Client {
+            self.client.clone()
+        }
+        fn store(&self) -> Arc<dyn crate::store::Store + Sync + Send> {
+            unreachable!("pods aren't pulled while they are registered")
+        }
+        async fn stop(&self, _pod: &Pod) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct PodState;
+
+    #[async_trait::async_trait]
+    impl ObjectState for PodState {
+        type Manifest = Pod;
+        type Status = PodStatus;
+        type SharedState = ProviderState;
+        async fn async_drop(self, _provider_state: &mut ProviderState) {}
+    }
+
+    #[async_trait::async_trait]
+    impl GenericPodState for PodState {
+        async fn set_env_vars(&mut self, _env_vars: HashMap<String, HashMap<String, String>>) {}
+        async fn set_modules(&mut self, _modules: HashMap<String, Vec<u8>>) {}
+        async fn set_volumes(&mut self, _volumes: HashMap<String, crate::volume::VolumeRef>) {}
+        async fn backoff(&mut self, _sequence: BackoffSequence) {}
+        async fn reset_backoff(&mut self, _sequence: BackoffSequence) {}
+        async fn record_error(&mut self) -> ThresholdTrigger {
+            ThresholdTrigger::Untriggered
+        }
+    }
+
+    impl GenericProvider for MockProvider {
+        type ProviderState = ProviderState;
+        type PodState = PodState;
+        type RunState = crate::pod::state::Stub;
+
+        fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn validate_container_runnable(
+            _container: &crate::container::Container,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Events = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// A client that records the Events created with it
+    fn mock_client() -> (kube::Client, Events) {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let events: Events = Default::default();
+        let recorded = events.clone();
+        tokio::spawn(async move {
+            pin_mut!(handle);
+            while let Some((request, send)) = handle.next_request().await {
+                assert!(request.uri().path().ends_with("/events"));
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                recorded.lock().unwrap().push(event.clone());
+                send.send_response(
+                    HttpResponse::builder()
+                        .body(Body::from(serde_json::to_vec(&event).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        (kube::Client::new(mock_service, "default"), events)
+    }
+
+    #[tokio::test]
+    async fn test_pod_with_disallowed_image_is_rejected() {
+        let (client, events) = mock_client();
+        let shared = Arc::new(tokio::sync::RwLock::new(ProviderState { client }));
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {"name": "hello", "namespace": "apps", "uid": "1234"},
+                "spec": {
+                    "nodeName": "krustlet",
+                    "containers": [{"name": "hello", "image": "docker.io/library/hello:v1"}],
+                },
+            }))
+            .unwrap(),
+        );
+        let (_manifest_tx, manifest) = Manifest::new(pod.clone(), krator::store::Store::new());
+
+        let state: Box<dyn State<PodState>> = Box::new(Registered::<MockProvider>::default());
+        let next: Box<dyn State<PodState>> = match state.next(shared, &mut PodState, manifest).await
+        {
+            Transition::Next(next) => next.into(),
+            Transition::Complete(result) => panic!("pod completed with {:?}", result),
+        };
+        let status = next.status(&mut PodState, &pod).await.unwrap().json_patch();
+        assert_eq!(status["status"]["phase"], "Failed");
+        assert_eq!(status["status"]["reason"], IMAGE_NOT_ALLOWED);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["type"], EVENT_TYPE_WARNING);
+        assert_eq!(events[0]["reason"], IMAGE_NOT_ALLOWED);
+        assert_eq!(events[0]["involvedObject"]["uid"], "1234");
+        assert_eq!(events[0]["source"]["host"], "krustlet");
+        assert!(events[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("docker.io/library/hello:v1"));
+    }
+}
@@ -0,0 +1,57 @@
+//! The Pod asks for features the provider does not support, or breaks the node's policy.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+
+/// The Pod asks for features the provider does not support, or breaks the node's policy, so it
+/// is never run.
+pub struct Rejected<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    reason: &'static str,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for Rejected<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("Rejected: {}", self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> Rejected<P> {
+    /// Creates an instance of the Rejected state.
+    pub fn new(message: String) -> Self {
+        Self::with_reason("UnsupportedPodSpec", message)
+    }
+
+    /// Creates an instance of the Rejected state that reports a reason other than an
+    /// unsupported pod spec.
+    pub fn with_reason(reason: &'static str, message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            reason,
+            message,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for Rejected<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        // Retrying can't help, as the pod spec can't change in the ways that matter
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(self.reason)
+            .message(&self.message)
+            .build())
+    }
+}
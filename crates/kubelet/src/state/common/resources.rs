@@ -117,8 +117,12 @@ impl<P: GenericProvider> State<P::PodState> for Resources<P> {
         Transition::next(self, next)
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "Resources"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "Resources",
+            make_conditions(pod, Readiness::NotInitialized, &*pod_state.clock()),
+        ))
     }
 }
 
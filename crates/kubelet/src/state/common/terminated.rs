@@ -1,6 +1,6 @@
 //! Pod was deleted.
 
-use super::{GenericProvider, GenericProviderState};
+use super::{GenericPodState, GenericProvider, GenericProviderState};
 use crate::pod::state::prelude::*;
 
 /// Pod was deleted.
@@ -40,7 +40,11 @@ impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
         Transition::Complete(stop_result)
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Succeeded, "Terminated"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Succeeded,
+            "Terminated",
+            make_conditions(pod, Readiness::Completed, &*pod_state.clock()),
+        ))
     }
 }
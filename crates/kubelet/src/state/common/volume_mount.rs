@@ -1,12 +1,16 @@
 //! Kubelet is pulling container images.
 
+use std::convert::TryFrom;
+
 use tracing::{error, info, instrument};
 
 use super::{GenericPodState, GenericProvider, GenericProviderState};
 use crate::pod::state::prelude::*;
+use crate::pod::PodDirs;
 use crate::provider::{PluginSupport, VolumeSupport};
 use crate::state::common::error::Error;
-use crate::volume::VolumeRef;
+use crate::state::replay;
+use crate::volume::{FsGroupChangePolicy, VolumeRef};
 
 /// Kubelet is pulling container images.
 pub struct VolumeMount<P: GenericProvider> {
@@ -44,7 +48,7 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
-        let (client, volume_path, plugin_registry) = {
+        let (client, volume_path, plugin_registry, tracker) = {
             let state_reader = provider_state.read().await;
             let vol_path = match state_reader.volume_path() {
                 Some(p) => p.to_owned(),
@@ -57,33 +61,65 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
                 state_reader.client(),
                 vol_path,
                 state_reader.plugin_registry(),
+                state_reader.dependency_tracker(),
             )
         };
 
         // Get the map of VolumeRefs
-        let mut volumes = match VolumeRef::volumes_from_pod(&pod, &client, plugin_registry).await {
-            Ok(v) => v,
+        let mut volumes =
+            match VolumeRef::volumes_from_pod_with_tracker(&pod, &client, plugin_registry, tracker)
+                .await
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(error = %e);
+                    let next = Error::<P>::new(e.to_string());
+                    return Transition::next(self, next);
+                }
+            };
+        // Now mount each volume
+        let base_path = match PodDirs::new(&pod, Some(&volume_path), None) {
+            Ok(dirs) => dirs.volumes().unwrap_or(&volume_path).to_owned(),
             Err(e) => {
                 error!(error = %e);
                 let next = Error::<P>::new(e.to_string());
                 return Transition::next(self, next);
             }
         };
-        // Now mount each volume
-        let base_path = volume_path.join(pod_dir_name(&pod));
-        let mounts = volumes
-            .iter_mut()
-            .map(|(k, v)| (k, v, base_path.clone()))
-            .map(|(k, v, p)| async move {
-                v.mount(p)
-                    .await
-                    .map_err(|e| anyhow::anyhow!("Unable to mount volume {}: {}", k, e))
-            });
-        if let Err(e) = futures::future::join_all(mounts)
-            .await
-            .into_iter()
-            .collect::<anyhow::Result<()>>()
-        {
+        let mounted = replay::provider_result(&pod, "volumeMount", async {
+            let mounts = volumes
+                .iter_mut()
+                .map(|(k, v)| (k, v, base_path.clone()))
+                .map(|(k, v, p)| async move {
+                    v.mount(p)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Unable to mount volume {}: {}", k, e))
+                });
+            futures::future::join_all(mounts)
+                .await
+                .into_iter()
+                .collect::<anyhow::Result<()>>()?;
+            // Hand writable volumes to the fsGroup before the provider gets their paths
+            if let Some(fs_group) = pod.fs_group() {
+                let policy = FsGroupChangePolicy::from_pod_setting(pod.fs_group_change_policy());
+                for (name, volume) in volumes.iter() {
+                    let applied = match u32::try_from(fs_group) {
+                        Ok(gid) => volume.apply_fs_group(gid, policy).await,
+                        Err(_) => Err(anyhow::anyhow!("fsGroup {} is not a valid group", fs_group)),
+                    };
+                    if let Err(e) = applied {
+                        return Err(anyhow::anyhow!(
+                            "Unable to apply fsGroup to volume {}: {}",
+                            name,
+                            e
+                        ));
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = mounted {
             error!(error = %e);
             let next = Error::<P>::new(e.to_string());
             return Transition::next(self, next);
@@ -92,13 +128,13 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
         Transition::next_unchecked(self, P::RunState::default())
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "VolumeMount"))
+    async fn status(&self, pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "VolumeMount",
+            make_conditions(pod, Readiness::NotInitialized, &*pod_state.clock()),
+        ))
     }
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for VolumeMount<P> {}
-
-fn pod_dir_name(pod: &Pod) -> String {
-    format!("{}-{}", pod.name(), pod.namespace())
-}
@@ -0,0 +1,874 @@
+//! Recording and deterministic replay of pod state machines.
+//!
+//! When the [`RECORD_ENV_VAR`] environment variable names a file (or [`start_recording`] is
+//! called), the Kubelet appends everything that drives its state machines to it as JSON lines:
+//! the manifest of every pod when it is added and whenever its spec changes, the results of
+//! provider operations wrapped in [`provider_result`], and every transition the pod and
+//! container state machines make. [`replay`] then re-drives a pod state machine from a recording
+//! offline, feeding it the recorded manifests and handing back the recorded provider results
+//! instead of calling the provider, and reports the first step at which the replayed transitions
+//! differ from the recorded ones. This makes lifecycle bugs that depend on timing reproducible on
+//! a developer's machine.
+//!
+//! Manifests are recorded as the Kubelet receives them, so a manifest recorded between two
+//! transitions is delivered to the replayed state machine before the second one starts. Values
+//! that may be secret, such as the environment of containers, are redacted from recordings as
+//! they are from support bundles, and pulled modules are recorded by their digests only.
+//!
+//! Whether or not a recording is being made, the most recent transitions of every pod's state
+//! machines are kept in memory so that [`histories`] can be included in support bundles.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use krator::{Manifest, ObjectState, SharedState, State, Transition};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
+
+use crate::pod::{catch_panic, quarantine, Pod, PodKey, ProviderPanicked, Status as PodStatus};
+
+/// The environment variable naming the file to record to
+pub const RECORD_ENV_VAR: &str = "KRUSTLET_RECORD_FILE";
+
+/// How many of the most recent steps of each pod's state machines are kept in its history
+const HISTORY_STEPS: usize = 64;
+/// How many pods histories are kept for, forgetting the pods that were least recently updated
+const HISTORY_PODS: usize = 256;
+
+/// A single line of a recording.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    /// The namespace of the pod the entry is for
+    pub namespace: String,
+    /// The name of the pod the entry is for
+    pub name: String,
+    /// The container the entry is for, if it is for a container state machine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// What was recorded
+    pub entry: Entry,
+}
+
+/// Something that was recorded about a state machine.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Entry {
+    /// The pod's manifest as it was received
+    Manifest {
+        /// The manifest
+        pod: Box<Pod>,
+    },
+    /// The result of a provider operation
+    ProviderResult {
+        /// The name of the operation
+        operation: String,
+        /// The value the operation returned, if it succeeded
+        #[serde(default)]
+        value: Option<serde_json::Value>,
+        /// The error the operation failed with, if it failed
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// The state machine transitioned to a state
+    Transition {
+        /// The name of the state
+        state: String,
+    },
+    /// The state machine completed
+    Complete {
+        /// The error it completed with, if any
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+/// The outcome of a single step of a state machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The state machine transitioned to the named state
+    Next(String),
+    /// The state machine completed, with the error it failed with if any
+    Complete(Result<(), String>),
+}
+
+impl Outcome {
+    fn from_transition<S: ObjectState>(
+        transition: Transition<S>,
+    ) -> (Self, Option<Box<dyn State<S>>>) {
+        match transition {
+            Transition::Next(next) => {
+                let next: Box<dyn State<S>> = next.into();
+                (Outcome::Next(state_name(&next)), Some(next))
+            }
+            Transition::Complete(result) => (
+                Outcome::Complete(result.map_err(|e| format!("{:#}", e))),
+                None,
+            ),
+        }
+    }
+
+    fn entry(&self) -> Entry {
+        match self {
+            Outcome::Next(state) => Entry::Transition {
+                state: state.clone(),
+            },
+            Outcome::Complete(result) => Entry::Complete {
+                error: result.clone().err(),
+            },
+        }
+    }
+}
+
+/// The name of a state, which is the name of its type.
+pub(crate) fn state_name(state: &dyn fmt::Debug) -> String {
+    let debug = format!("{:?}", state);
+    debug
+        .split(|c: char| c == ' ' || c == '{' || c == '(')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// A step a pod's state machine, or one of its containers' state machines, took.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    /// When the step was taken
+    pub at: DateTime<Utc>,
+    /// The container whose state machine took the step, if it wasn't the pod's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// The transition or completion
+    pub entry: Entry,
+}
+
+/// The most recent steps of the state machines of recently updated pods.
+#[derive(Default)]
+struct Histories {
+    /// The steps of each pod, and when its history was last updated
+    pods: HashMap<PodKey, (u64, VecDeque<Step>)>,
+    /// Counts updates, so the least recently updated history can be found
+    updates: u64,
+}
+
+impl Histories {
+    fn push(&mut self, pod: PodKey, step: Step) {
+        self.updates += 1;
+        let updated = self.updates;
+        if !self.pods.contains_key(&pod) && self.pods.len() >= HISTORY_PODS {
+            let oldest = self
+                .pods
+                .iter()
+                .min_by_key(|(_, (updated, _))| *updated)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.pods.remove(&oldest);
+            }
+        }
+        let (last_updated, steps) = self.pods.entry(pod).or_default();
+        *last_updated = updated;
+        if steps.len() >= HISTORY_STEPS {
+            steps.pop_front();
+        }
+        steps.push_back(step);
+    }
+}
+
+struct Recorder {
+    /// Whether the environment has been checked for a file to record to
+    loaded: bool,
+    file: Option<std::fs::File>,
+}
+
+lazy_static::lazy_static! {
+    static ref RECORDER: Mutex<Recorder> = Mutex::new(Recorder {
+        loaded: false,
+        file: None,
+    });
+    static ref HISTORIES: Mutex<Histories> = Mutex::new(Histories::default());
+    /// The recorded provider results handed out to replayed pods, by operation
+    static ref REPLAYING: Mutex<HashMap<PodKey, HashMap<String, VecDeque<Entry>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Starts appending everything that drives the Kubelet's state machines to a file.
+pub fn start_recording(path: &Path) -> anyhow::Result<()> {
+    let file = open_recording(path)?;
+    let mut recorder = RECORDER.lock().unwrap();
+    recorder.loaded = true;
+    recorder.file = Some(file);
+    Ok(())
+}
+
+/// Stops recording.
+pub fn stop_recording() {
+    let mut recorder = RECORDER.lock().unwrap();
+    recorder.loaded = true;
+    recorder.file = None;
+}
+
+fn open_recording(path: &Path) -> anyhow::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Unable to open recording {}: {}", path.display(), e))
+}
+
+fn record(pod: &Pod, container: Option<&str>, entry: impl FnOnce() -> Entry) {
+    let mut recorder = RECORDER.lock().unwrap();
+    if !recorder.loaded {
+        recorder.loaded = true;
+        if let Some(path) = std::env::var_os(RECORD_ENV_VAR) {
+            match open_recording(&PathBuf::from(path)) {
+                Ok(file) => recorder.file = Some(file),
+                Err(e) => warn!(error = %e, "Unable to start recording state machines"),
+            }
+        }
+    }
+    let file = match recorder.file.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+    let record = Record {
+        namespace: pod.namespace().to_owned(),
+        name: pod.name().to_owned(),
+        container: container.map(ToOwned::to_owned),
+        entry: entry(),
+    };
+    let written = serde_json::to_value(&record)
+        .and_then(|mut record| {
+            redact_entry(&mut record["entry"]);
+            serde_json::to_vec(&record)
+        })
+        .map_err(anyhow::Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            file.write_all(&line)?;
+            Ok(())
+        });
+    if let Err(e) = written {
+        warn!(error = %e, "Unable to write to state machine recording");
+    }
+}
+
+/// Redacts anything that may be secret from a recorded entry, so recordings can be shared.
+fn redact_entry(entry: &mut serde_json::Value) {
+    if let Some(error) = entry.get_mut("error") {
+        if let Some(message) = error.as_str() {
+            *error = crate::support_bundle::redact_line(message).into();
+        }
+    }
+    crate::support_bundle::redact(entry);
+}
+
+/// Records a manifest of a pod as it was received.
+pub(crate) fn record_manifest(pod: &Pod) {
+    record(pod, None, || Entry::Manifest {
+        pod: Box::new(pod.clone()),
+    })
+}
+
+/// Records a step of a pod state machine, or of one of its containers' state machines.
+pub(crate) fn record_outcome(pod: &Pod, container: Option<&str>, outcome: &Outcome) {
+    HISTORIES.lock().unwrap().push(
+        PodKey::from(pod),
+        Step {
+            at: Utc::now(),
+            container: container.map(ToOwned::to_owned),
+            entry: outcome.entry(),
+        },
+    );
+    record(pod, container, || outcome.entry())
+}
+
+/// The most recent steps the state machines of recently updated pods took, oldest first, as
+/// JSON.
+pub fn histories() -> serde_json::Value {
+    let histories = HISTORIES.lock().unwrap();
+    let sorted: BTreeMap<&PodKey, &VecDeque<Step>> = histories
+        .pods
+        .iter()
+        .map(|(key, (_, steps))| (key, steps))
+        .collect();
+    let pods: Vec<serde_json::Value> = sorted
+        .into_iter()
+        .map(|(key, steps)| {
+            serde_json::json!({
+                "podRef": {"name": key.name(), "namespace": key.namespace()},
+                "steps": steps,
+            })
+        })
+        .collect();
+    serde_json::json!({ "pods": pods })
+}
+
+/// Runs a provider operation whose result decides what a pod's state machine does next,
+/// recording the result. While the pod is being replayed, the recorded result is returned
+/// instead and the operation isn't run at all.
+pub async fn provider_result<T, F>(pod: &Pod, operation: &str, result: F) -> anyhow::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let replayed = REPLAYING
+        .lock()
+        .unwrap()
+        .get_mut(&PodKey::from(pod))
+        .map(|operations| {
+            operations
+                .get_mut(operation)
+                .and_then(|results| results.pop_front())
+        });
+    match replayed {
+        Some(Some(Entry::ProviderResult { value, error, .. })) => match error {
+            Some(error) => Err(anyhow::Error::msg(error)),
+            None => Ok(serde_json::from_value(
+                value.unwrap_or(serde_json::Value::Null),
+            )?),
+        },
+        Some(_) => Err(anyhow::anyhow!(
+            "The recording has no more results of {} for pod {}",
+            operation,
+            pod.name()
+        )),
+        None => {
+            let result = result.await;
+            record(pod, None, || match &result {
+                Ok(value) => Entry::ProviderResult {
+                    operation: operation.to_owned(),
+                    value: serde_json::to_value(value).ok(),
+                    error: None,
+                },
+                Err(e) => Entry::ProviderResult {
+                    operation: operation.to_owned(),
+                    value: None,
+                    error: Some(format!("{:#}", e)),
+                },
+            });
+            result
+        }
+    }
+}
+
+/// The modules pulled for a pod's containers, by container name, as the result of a provider
+/// operation. Modules are recorded by their digests rather than their contents, so replayed pods
+/// are handed empty modules. Their containers' runtimes aren't started when replaying anyway.
+#[derive(Debug, Default)]
+pub struct RecordedModules(pub HashMap<String, Vec<u8>>);
+
+impl Serialize for RecordedModules {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let digests: std::collections::BTreeMap<&String, String> = self
+            .0
+            .iter()
+            .map(|(container, module)| (container, crate::handle::module_digest(module)))
+            .collect();
+        digests.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RecordedModules {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let digests = HashMap::<String, String>::deserialize(deserializer)?;
+        Ok(RecordedModules(
+            digests.into_iter().map(|(c, _)| (c, Vec::new())).collect(),
+        ))
+    }
+}
+
+/// A pod state, recording the transitions it and the states after it make. The Kubelet wraps
+/// the states of every pod in one, so that pod state machines can be recorded.
+pub struct Recorded<S: ObjectState, I> {
+    /// The state being recorded, which is only taken once it has run
+    inner: Option<Box<dyn State<S>>>,
+    _initial: PhantomData<fn() -> I>,
+}
+
+impl<S: ObjectState, I: State<S> + Default> Default for Recorded<S, I> {
+    fn default() -> Self {
+        Recorded {
+            inner: Some(Box::new(I::default())),
+            _initial: PhantomData,
+        }
+    }
+}
+
+impl<S: ObjectState, I> fmt::Debug for Recorded<S, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.inner {
+            Some(inner) => fmt::Debug::fmt(inner, f),
+            None => f.write_str("Recorded"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, I> State<S> for Recorded<S, I>
+where
+    S: ObjectState<Manifest = Pod, Status = PodStatus>,
+    I: 'static,
+{
+    async fn next(
+        mut self: Box<Self>,
+        shared: SharedState<S::SharedState>,
+        state: &mut S,
+        manifest: Manifest<Pod>,
+    ) -> Transition<S> {
+        let inner = self
+            .inner
+            .take()
+            .expect("recorded states are only run once");
+        let pod = manifest.latest();
+        // A panic in the provider fails the pod rather than the task running its state machine
+        let (outcome, next) = match catch_panic(inner.next(shared, state, manifest)).await {
+            Ok(transition) => Outcome::from_transition(transition),
+            Err(message) => {
+                quarantine(&pod, &message).await;
+                let next: Box<dyn State<S>> = Box::new(ProviderPanicked::new(message));
+                (Outcome::Next(state_name(&next)), Some(next))
+            }
+        };
+        record_outcome(&pod, None, &outcome);
+        match (next, outcome) {
+            (Some(next), _) => Transition::next_unchecked(
+                self,
+                Recorded::<S, I> {
+                    inner: Some(next),
+                    _initial: PhantomData,
+                },
+            ),
+            (None, Outcome::Complete(result)) => {
+                Transition::Complete(result.map_err(anyhow::Error::msg))
+            }
+            (None, Outcome::Next(_)) => unreachable!("transitions always have a next state"),
+        }
+    }
+
+    async fn status(&self, state: &mut S, pod: &Pod) -> anyhow::Result<PodStatus> {
+        let status = self
+            .inner
+            .as_ref()
+            .expect("recorded states report their status before they are run")
+            .status(state, pod)
+            .await?;
+        // Remembered so that the status can be reconciled if patching it fails
+        crate::pod::record_status(pod, &status);
+        Ok(status)
+    }
+}
+
+/// A recording of state machines.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    /// The recorded entries, in the order they were recorded
+    pub records: Vec<Record>,
+}
+
+impl Recording {
+    /// Reads a recording from a file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Unable to read recording {}: {}", path.display(), e))?;
+        Recording::parse(&contents)
+    }
+
+    /// Parses a recording from its JSON lines.
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let records = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| anyhow::anyhow!("Invalid record on line {}: {}", i + 1, e))
+            })
+            .collect::<anyhow::Result<Vec<Record>>>()?;
+        Ok(Recording { records })
+    }
+
+    /// The pods that appear in the recording, in the order they first appear.
+    pub fn pods(&self) -> Vec<PodKey> {
+        let mut pods: Vec<PodKey> = Vec::new();
+        for record in &self.records {
+            let key = PodKey::new(&record.namespace, &record.name);
+            if !pods.contains(&key) {
+                pods.push(key);
+            }
+        }
+        pods
+    }
+
+    fn pod_entries<'a>(&'a self, pod: &'a PodKey) -> impl Iterator<Item = &'a Entry> + 'a {
+        self.records
+            .iter()
+            .filter(move |r| {
+                r.container.is_none() && r.namespace == pod.namespace() && r.name == pod.name()
+            })
+            .map(|r| &r.entry)
+    }
+}
+
+/// The result of replaying a pod state machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replay {
+    /// The steps of the state machine in the recording
+    pub recorded: Vec<Outcome>,
+    /// The steps of the replayed state machine, up to and including the first that differs
+    pub replayed: Vec<Outcome>,
+}
+
+impl Replay {
+    /// The index of the first step at which the replay differed from the recording, if any.
+    pub fn diverged_at(&self) -> Option<usize> {
+        self.replayed
+            .iter()
+            .zip(&self.recorded)
+            .position(|(replayed, recorded)| replayed != recorded)
+            .or_else(|| {
+                if self.replayed.len() < self.recorded.len() {
+                    Some(self.replayed.len())
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Re-drives the state machine of a pod from a recording, starting from `initial_state` with the
+/// first recorded manifest of the pod. The shared and pod state are the caller's, so providers
+/// can replay against state that doesn't touch a real runtime. Replaying stops once the state
+/// machine completes or does something the recording doesn't.
+pub async fn replay<S>(
+    recording: &Recording,
+    pod: &PodKey,
+    initial_state: impl State<S>,
+    shared: SharedState<S::SharedState>,
+    mut pod_state: S,
+) -> anyhow::Result<Replay>
+where
+    S: ObjectState<Manifest = Pod, Status = PodStatus>,
+{
+    // Group the recorded manifests by the step they arrived before
+    let mut steps: Vec<(Vec<Pod>, Outcome)> = Vec::new();
+    let mut pending: Vec<Pod> = Vec::new();
+    let mut results: HashMap<String, VecDeque<Entry>> = HashMap::new();
+    for entry in recording.pod_entries(pod) {
+        match entry {
+            Entry::Manifest { pod } => pending.push((**pod).clone()),
+            Entry::ProviderResult { operation, .. } => results
+                .entry(operation.clone())
+                .or_default()
+                .push_back(entry.clone()),
+            Entry::Transition { state } => {
+                steps.push((std::mem::take(&mut pending), Outcome::Next(state.clone())))
+            }
+            Entry::Complete { error } => steps.push((
+                std::mem::take(&mut pending),
+                Outcome::Complete(error.clone().map_or(Ok(()), Err)),
+            )),
+        }
+    }
+    let initial = steps
+        .first_mut()
+        .and_then(|(manifests, _)| {
+            if manifests.is_empty() {
+                None
+            } else {
+                Some(manifests.remove(0))
+            }
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "The recording has no manifest of pod {} before its first transition",
+                pod.name()
+            )
+        })?;
+
+    REPLAYING.lock().unwrap().insert(pod.clone(), results);
+    let (manifest_tx, manifest) = Manifest::new(initial, krator::store::Store::new());
+    let mut state: Box<dyn State<S>> = Box::new(initial_state);
+    let mut replayed = Vec::new();
+    for (manifests, recorded) in &steps {
+        for pod in manifests {
+            // The receiver is held below, so this can't fail
+            let _ = manifest_tx.send(pod.clone());
+        }
+        if let Err(e) = state.status(&mut pod_state, &manifest.latest()).await {
+            warn!(error = %e, "Replayed state returned error for its status");
+        }
+        let (outcome, next) = Outcome::from_transition(
+            state
+                .next(shared.clone(), &mut pod_state, manifest.clone())
+                .await,
+        );
+        let diverged = &outcome != recorded;
+        replayed.push(outcome);
+        match next {
+            Some(next) if !diverged => state = next,
+            _ => break,
+        }
+    }
+    REPLAYING.lock().unwrap().remove(pod);
+    pod_state.async_drop(&mut *shared.write().await).await;
+
+    Ok(Replay {
+        recorded: steps.into_iter().map(|(_, outcome)| outcome).collect(),
+        replayed,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pod::state::prelude::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[derive(Debug)]
+    struct ProviderState;
+
+    #[derive(Debug)]
+    struct PodState;
+
+    #[async_trait::async_trait]
+    impl ObjectState for PodState {
+        type Manifest = Pod;
+        type Status = PodStatus;
+        type SharedState = ProviderState;
+        async fn async_drop(self, _shared_state: &mut Self::SharedState) {}
+    }
+
+    /// Starts the pod, which is only done once the provider says it is ready.
+    #[derive(Debug, Default)]
+    struct Starting;
+
+    #[derive(Debug, Default)]
+    struct Done;
+
+    impl TransitionTo<Starting> for Starting {}
+    impl TransitionTo<Done> for Starting {}
+
+    #[async_trait::async_trait]
+    impl State<PodState> for Starting {
+        async fn next(
+            self: Box<Self>,
+            _shared: SharedState<ProviderState>,
+            _state: &mut PodState,
+            manifest: Manifest<Pod>,
+        ) -> Transition<PodState> {
+            let pod = manifest.latest();
+            let ready: bool = provider_result(&pod, "ready", async {
+                // Only ever run while recording, the replay gets the recorded results
+                assert!(REPLAYING.lock().unwrap().is_empty());
+                Ok(pod.labels().contains_key("ready"))
+            })
+            .await
+            .unwrap();
+            if ready {
+                Transition::next(self, Done)
+            } else {
+                Transition::next(self, Starting)
+            }
+        }
+
+        async fn status(&self, _state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+            Ok(Default::default())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl State<PodState> for Done {
+        async fn next(
+            self: Box<Self>,
+            _shared: SharedState<ProviderState>,
+            _state: &mut PodState,
+            _manifest: Manifest<Pod>,
+        ) -> Transition<PodState> {
+            Transition::Complete(Ok(()))
+        }
+
+        async fn status(&self, _state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+            Ok(Default::default())
+        }
+    }
+
+    fn pod(ready: bool) -> Pod {
+        let labels = if ready {
+            serde_json::json!({"ready": "true"})
+        } else {
+            serde_json::json!({})
+        };
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "replayed", "namespace": "default", "labels": labels}
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        let shared = Arc::new(RwLock::new(ProviderState));
+
+        // Record the pod becoming ready after its first attempt to start
+        start_recording(&path).unwrap();
+        let (manifest_tx, manifest) = Manifest::new(pod(false), krator::store::Store::new());
+        record_manifest(&manifest.latest());
+        let mut state: Box<dyn State<PodState>> =
+            Box::new(Recorded::<PodState, Starting>::default());
+        let mut pod_state = PodState;
+        loop {
+            match state
+                .next(shared.clone(), &mut pod_state, manifest.clone())
+                .await
+            {
+                Transition::Next(next) => state = next.into(),
+                Transition::Complete(result) => break result.unwrap(),
+            }
+            if state_name(&state) == "Starting" {
+                manifest_tx.send(pod(true)).unwrap();
+                record_manifest(&pod(true));
+            }
+        }
+        stop_recording();
+
+        let recording = Recording::from_file(&path).unwrap();
+        let key = PodKey::new("default", "replayed");
+        assert!(recording.pods().contains(&key));
+        let replay = replay(&recording, &key, Starting, shared, PodState)
+            .await
+            .unwrap();
+        assert_eq!(
+            replay.recorded,
+            vec![
+                Outcome::Next("Starting".to_owned()),
+                Outcome::Next("Done".to_owned()),
+                Outcome::Complete(Ok(())),
+            ]
+        );
+        assert_eq!(replay.diverged_at(), None);
+    }
+
+    #[test]
+    fn test_recordings_are_redacted() {
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "redacted", "namespace": "default"},
+                "spec": {"containers": [{
+                    "name": "server",
+                    "image": "server:1",
+                    "env": [{"name": "DATABASE_URL", "value": "postgres://user:hunter2@db"}],
+                }]},
+            }))
+            .unwrap(),
+        );
+        let module = vec![0, 97, 115, 109];
+        let entries = vec![
+            Entry::Manifest {
+                pod: Box::new(pod.clone()),
+            },
+            Entry::ProviderResult {
+                operation: "imagePull".to_owned(),
+                value: serde_json::to_value(RecordedModules(
+                    vec![("server".to_owned(), module.clone())]
+                        .into_iter()
+                        .collect(),
+                ))
+                .ok(),
+                error: None,
+            },
+            Entry::ProviderResult {
+                operation: "volumeMount".to_owned(),
+                value: None,
+                error: Some("unable to mount with token=abc123".to_owned()),
+            },
+        ];
+        let lines: Vec<String> = entries
+            .into_iter()
+            .map(|entry| {
+                let mut record = serde_json::to_value(Record {
+                    namespace: "default".to_owned(),
+                    name: "redacted".to_owned(),
+                    container: None,
+                    entry,
+                })
+                .unwrap();
+                redact_entry(&mut record["entry"]);
+                record.to_string()
+            })
+            .collect();
+        let recorded = lines.join("\n");
+
+        assert!(!recorded.contains("hunter2"));
+        assert!(!recorded.contains("abc123"));
+        assert!(recorded.contains(&crate::handle::module_digest(&module)));
+        let recording = Recording::parse(&recorded).unwrap();
+        match &recording.records[0].entry {
+            Entry::Manifest { pod } => assert_eq!(pod.containers()[0].name(), "server"),
+            entry => panic!("expected a manifest, got {:?}", entry),
+        }
+        match &recording.records[1].entry {
+            Entry::ProviderResult {
+                value: Some(value), ..
+            } => {
+                let modules: RecordedModules = serde_json::from_value(value.clone()).unwrap();
+                assert_eq!(modules.0["server"], Vec::<u8>::new());
+            }
+            entry => panic!("expected pulled modules, got {:?}", entry),
+        }
+    }
+
+    #[test]
+    fn test_histories_are_bounded() {
+        let mut histories = Histories::default();
+        let step = |state: &str| Step {
+            at: Utc::now(),
+            container: None,
+            entry: Entry::Transition {
+                state: state.to_owned(),
+            },
+        };
+        for i in 0..HISTORY_STEPS + 1 {
+            histories.push(PodKey::new("default", "busy"), step(&i.to_string()));
+        }
+        let steps = &histories.pods[&PodKey::new("default", "busy")].1;
+        assert_eq!(steps.len(), HISTORY_STEPS);
+        assert!(matches!(&steps[0].entry, Entry::Transition { state } if state == "1"));
+        for i in 0..HISTORY_PODS {
+            histories.push(PodKey::new("default", &i.to_string()), step("Starting"));
+        }
+
+        // The busy pod was updated least recently, so it was forgotten to make room
+        assert_eq!(histories.pods.len(), HISTORY_PODS);
+        assert!(!histories.pods.contains_key(&PodKey::new("default", "busy")));
+        histories.push(PodKey::new("default", "busy"), step("Running"));
+        assert!(!histories.pods.contains_key(&PodKey::new("default", "0")));
+        assert_eq!(histories.pods[&PodKey::new("default", "busy")].1.len(), 1);
+    }
+
+    #[test]
+    fn test_diverged_at() {
+        let replay = Replay {
+            recorded: vec![
+                Outcome::Next("Starting".to_owned()),
+                Outcome::Complete(Ok(())),
+            ],
+            replayed: vec![
+                Outcome::Next("Starting".to_owned()),
+                Outcome::Complete(Err("failed".to_owned())),
+            ],
+        };
+        assert_eq!(replay.diverged_at(), Some(1));
+    }
+}
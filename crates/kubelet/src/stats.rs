@@ -0,0 +1,353 @@
+//! Disk usage accounting for pods.
+//!
+//! Providers measure how much of the node's disk each pod uses for the logs and scratch space of
+//! its containers, and record it in a [`DiskUsageTracker`]. The tracker publishes the usage as
+//! metrics and in the Kubelet's `/stats/summary` endpoint, where the scratch space of a container
+//! is reported as its `rootfs`, as it holds what the container writes outside of its volumes.
+//!
+//! Pods may use as much disk as the sum of their containers' `ephemeral-storage` limits, if
+//! every container sets one. Providers evict pods that use more, with the
+//! [`EPHEMERAL_STORAGE_EXCEEDED`] reason.
+//!
+//! When the provider runs its pods in cgroups, the summary also reports the CPU time each pod
+//! and container has used, read from the [`CgroupManager`].
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use prometheus::{IntGaugeVec, Opts};
+use tracing::warn;
+
+use crate::cgroup::{CgroupManager, CpuStats};
+use crate::container::Container;
+use crate::metrics::register;
+use crate::pod::{Pod, PodKey};
+use crate::resources::quantity::{Quantity, QuantityType};
+
+/// The reason given for pods that are evicted for using more disk than their limit
+pub const EPHEMERAL_STORAGE_EXCEEDED: &str = "EphemeralStorageExceeded";
+
+lazy_static::lazy_static! {
+    static ref POD_EPHEMERAL_STORAGE: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new(
+            "pod_ephemeral_storage_bytes",
+            "Bytes of the node's disk used by a pod, by what they are used for"
+        ),
+        &["namespace", "pod", "source"]
+    ));
+}
+
+/// The disk used by a container.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerDiskUsage {
+    /// Bytes used by the container's logs
+    pub logs: u64,
+    /// Bytes used by the container's scratch space
+    pub scratch: u64,
+}
+
+/// The disk used by a pod, by container name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodDiskUsage {
+    /// The disk used by each of the pod's containers
+    pub containers: BTreeMap<String, ContainerDiskUsage>,
+}
+
+impl PodDiskUsage {
+    /// The number of bytes used by the pod's logs.
+    pub fn logs(&self) -> u64 {
+        self.containers.values().map(|c| c.logs).sum()
+    }
+
+    /// The number of bytes used by the pod's scratch space.
+    pub fn scratch(&self) -> u64 {
+        self.containers.values().map(|c| c.scratch).sum()
+    }
+
+    /// The number of bytes used by the pod.
+    pub fn total(&self) -> u64 {
+        self.logs() + self.scratch()
+    }
+
+    /// Checks the usage against the pod's `ephemeral-storage` limit, returning the message to
+    /// evict the pod with if it is over.
+    pub fn exceeded_limit(&self, pod: &Pod) -> Option<String> {
+        let limit = ephemeral_storage_limit(pod)?;
+        let used = self.total();
+        if used > limit {
+            Some(format!(
+                "Pod ephemeral local storage usage of {} bytes exceeds the total limit of containers of {} bytes",
+                used, limit
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+struct PodRecord {
+    uid: String,
+    usage: PodDiskUsage,
+}
+
+/// The latest disk usage of every pod on the node.
+pub struct DiskUsageTracker {
+    node_name: String,
+    pods: Mutex<HashMap<PodKey, PodRecord>>,
+    cgroups: Option<Arc<CgroupManager>>,
+}
+
+impl DiskUsageTracker {
+    /// Creates a tracker for the pods of the named node.
+    pub fn new(node_name: &str) -> Self {
+        DiskUsageTracker {
+            node_name: node_name.to_owned(),
+            pods: Mutex::new(HashMap::new()),
+            cgroups: None,
+        }
+    }
+
+    /// Reports the CPU usage of pods from their cgroups in the summary, if they have any.
+    pub fn with_cgroups(mut self, cgroups: Option<Arc<CgroupManager>>) -> Self {
+        self.cgroups = cgroups;
+        self
+    }
+
+    /// The CPU usage of a pod or one of its containers, if it runs in a cgroup that can be read.
+    fn cpu(&self, pod: &PodKey, container: Option<&str>) -> Option<serde_json::Value> {
+        let stats: CpuStats = self.cgroups.as_ref()?.cpu_stats(pod, container).ok()?;
+        Some(serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            "usageCoreNanoSeconds": stats.usage.as_nanos() as u64,
+        }))
+    }
+
+    /// Records the latest disk usage of a pod.
+    pub fn record(&self, pod: &Pod, usage: PodDiskUsage) {
+        for (source, bytes) in &[("logs", usage.logs()), ("scratch", usage.scratch())] {
+            POD_EPHEMERAL_STORAGE
+                .with_label_values(&[pod.namespace(), pod.name(), *source])
+                .set(*bytes as i64);
+        }
+        self.pods.lock().unwrap().insert(
+            PodKey::from(pod),
+            PodRecord {
+                uid: pod.pod_uid().to_owned(),
+                usage,
+            },
+        );
+    }
+
+    /// Forgets a pod that has been removed from the node.
+    pub fn remove(&self, pod: &PodKey) {
+        if self.pods.lock().unwrap().remove(pod).is_some() {
+            let (namespace, name) = (pod.namespace(), pod.name());
+            for source in &["logs", "scratch"] {
+                let _ = POD_EPHEMERAL_STORAGE.remove_label_values(&[&namespace, &name, *source]);
+            }
+        }
+    }
+
+    /// The disk usage of the node's pods in the form of the kubelet's summary API.
+    pub fn summary(&self) -> serde_json::Value {
+        let pods = self.pods.lock().unwrap();
+        let mut keys: Vec<&PodKey> = pods.keys().collect();
+        keys.sort();
+        let pods: Vec<serde_json::Value> = keys
+            .into_iter()
+            .map(|key| {
+                let record = &pods[key];
+                let containers: Vec<serde_json::Value> = record
+                    .usage
+                    .containers
+                    .iter()
+                    .map(|(name, usage)| {
+                        let mut container = serde_json::json!({
+                            "name": name,
+                            "rootfs": {"usedBytes": usage.scratch},
+                            "logs": {"usedBytes": usage.logs},
+                        });
+                        if let Some(cpu) = self.cpu(key, Some(name)) {
+                            container["cpu"] = cpu;
+                        }
+                        container
+                    })
+                    .collect();
+                let mut pod = serde_json::json!({
+                    "podRef": {
+                        "name": key.name(),
+                        "namespace": key.namespace(),
+                        "uid": record.uid,
+                    },
+                    "containers": containers,
+                    "ephemeral-storage": {"usedBytes": record.usage.total()},
+                });
+                if let Some(cpu) = self.cpu(key, None) {
+                    pod["cpu"] = cpu;
+                }
+                pod
+            })
+            .collect();
+        serde_json::json!({
+            "node": {"nodeName": self.node_name},
+            "pods": pods,
+        })
+    }
+}
+
+/// The pod's `ephemeral-storage` limit, which is the sum of its containers' limits if every
+/// container sets one.
+pub fn ephemeral_storage_limit(pod: &Pod) -> Option<u64> {
+    pod.containers()
+        .iter()
+        .map(container_ephemeral_storage_limit)
+        .sum()
+}
+
+/// The container's `ephemeral-storage` limit, if it sets a valid one.
+pub(crate) fn container_ephemeral_storage_limit(container: &Container) -> Option<u64> {
+    let limit = container.resources()?.limits.get("ephemeral-storage")?;
+    match Quantity::from_kube_quantity(QuantityType::Memory(limit)) {
+        Ok(Quantity::Memory(bytes)) => Some(bytes as u64),
+        Ok(_) => None,
+        Err(e) => {
+            warn!(error = %e, container = container.name(), "Invalid ephemeral-storage quantity, ignoring it");
+            None
+        }
+    }
+}
+
+/// The container's memory limit in bytes, if it sets a valid one.
+pub fn container_memory_limit(container: &Container) -> Option<u64> {
+    let limit = container.resources()?.limits.get("memory")?;
+    match Quantity::from_kube_quantity(QuantityType::Memory(limit)) {
+        Ok(Quantity::Memory(bytes)) => Some(bytes as u64),
+        Ok(_) => None,
+        Err(e) => {
+            warn!(error = %e, container = container.name(), "Invalid memory quantity, ignoring it");
+            None
+        }
+    }
+}
+
+/// The number of bytes used by the files beneath a directory, which is none if it doesn't exist.
+pub fn dir_usage(dir: &Path) -> std::io::Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+    let mut used = 0;
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            used += dir_usage(&entry.path())?;
+        } else if file_type.is_file() {
+            used += entry.metadata()?.len();
+        }
+    }
+    Ok(used)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    fn pod(limits: &[Option<&str>]) -> Pod {
+        let containers: Vec<serde_json::Value> = limits
+            .iter()
+            .enumerate()
+            .map(|(i, limit)| match limit {
+                Some(limit) => serde_json::json!({
+                    "name": format!("c{}", i),
+                    "resources": {"limits": {"ephemeral-storage": limit}},
+                }),
+                None => serde_json::json!({"name": format!("c{}", i)}),
+            })
+            .collect();
+        Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "web", "namespace": "default", "uid": "1234"},
+                "spec": {"containers": containers},
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn usage(logs: u64, scratch: u64) -> PodDiskUsage {
+        let mut usage = PodDiskUsage::default();
+        usage
+            .containers
+            .insert("c0".to_owned(), ContainerDiskUsage { logs, scratch });
+        usage
+    }
+
+    #[test]
+    fn test_exceeded_limit() {
+        assert_eq!(
+            ephemeral_storage_limit(&pod(&[Some("1Ki"), Some("1Ki")])),
+            Some(2048)
+        );
+        // Pods are only limited if every container is
+        assert_eq!(ephemeral_storage_limit(&pod(&[Some("1Ki"), None])), None);
+
+        let limited = pod(&[Some("1Ki"), Some("1Ki")]);
+        assert!(usage(1000, 1000).exceeded_limit(&limited).is_none());
+        assert!(usage(1000, 1049).exceeded_limit(&limited).is_some());
+        assert!(usage(1000, 1049)
+            .exceeded_limit(&pod(&[Some("1Ki"), None]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_summary() {
+        let tracker = DiskUsageTracker::new("krustlet");
+        let pod = pod(&[None]);
+        tracker.record(&pod, usage(10, 20));
+        assert_eq!(
+            tracker.summary(),
+            serde_json::json!({
+                "node": {"nodeName": "krustlet"},
+                "pods": [{
+                    "podRef": {"name": "web", "namespace": "default", "uid": "1234"},
+                    "containers": [{
+                        "name": "c0",
+                        "rootfs": {"usedBytes": 20},
+                        "logs": {"usedBytes": 10},
+                    }],
+                    "ephemeral-storage": {"usedBytes": 30},
+                }],
+            })
+        );
+        tracker.remove(&PodKey::from(&pod));
+        assert_eq!(tracker.summary()["pods"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_summary_reports_cgroup_cpu() {
+        let root = tempfile::tempdir().unwrap();
+        let container = root.path().join("default_web").join("c0");
+        std::fs::create_dir_all(&container).unwrap();
+        std::fs::write(container.join("cpu.stat"), "usage_usec 1500\n").unwrap();
+        std::fs::write(
+            root.path().join("default_web").join("cpu.stat"),
+            "usage_usec 2000\n",
+        )
+        .unwrap();
+        let tracker =
+            DiskUsageTracker::new("krustlet").with_cgroups(Some(Arc::new(CgroupManager::new(
+                root.path().to_owned(),
+                Arc::new(crate::environment::SystemClock),
+            ))));
+        tracker.record(&pod(&[None]), usage(10, 20));
+
+        let summary = tracker.summary();
+        let pod = &summary["pods"][0];
+        assert_eq!(pod["cpu"]["usageCoreNanoSeconds"], 2_000_000);
+        assert_eq!(
+            pod["containers"][0]["cpu"]["usageCoreNanoSeconds"],
+            1_500_000
+        );
+    }
+}
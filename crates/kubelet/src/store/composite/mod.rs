@@ -1,8 +1,10 @@
 //! `composite` implements building complex stores from simpler ones.
 
+use crate::store::CachedModule;
 use crate::store::PullPolicy;
 use crate::store::Store;
 use async_trait::async_trait;
+use oci_distribution::manifest::OciIndexEntry;
 use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
 use std::sync::Arc;
@@ -73,6 +75,92 @@ impl Store for CompositeStore {
             self.base.get(image_ref, pull_policy, auth).await
         }
     }
+
+    async fn get_for_namespace(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        namespace: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor
+                .get_for_namespace(image_ref, pull_policy, auth, namespace)
+                .await
+        } else {
+            self.base
+                .get_for_namespace(image_ref, pull_policy, auth, namespace)
+                .await
+        }
+    }
+
+    async fn pre_pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.pre_pull(image_ref, auth).await
+        } else {
+            self.base.pre_pull(image_ref, auth).await
+        }
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+        let mut modules = self.base.list().await?;
+        modules.extend(self.interceptor.list().await?);
+        Ok(modules)
+    }
+
+    async fn delete(&self, image_ref: &Reference) -> anyhow::Result<bool> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.delete(image_ref).await
+        } else {
+            self.base.delete(image_ref).await
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.base.is_busy() || self.interceptor.is_busy()
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<u64> {
+        Ok(self.base.collect_garbage().await? + self.interceptor.collect_garbage().await?)
+    }
+
+    async fn digest(&self, image_ref: &Reference) -> anyhow::Result<Option<String>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.digest(image_ref).await
+        } else {
+            self.base.digest(image_ref).await
+        }
+    }
+
+    async fn referrers(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor
+                .referrers(image_ref, auth, artifact_type)
+                .await
+        } else {
+            self.base.referrers(image_ref, auth, artifact_type).await
+        }
+    }
+
+    async fn pull_referrer(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor
+                .pull_referrer(image_ref, auth, referrer)
+                .await
+        } else {
+            self.base.pull_referrer(image_ref, auth, referrer).await
+        }
+    }
 }
 
 #[cfg(test)]
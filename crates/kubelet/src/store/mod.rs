@@ -2,19 +2,37 @@
 pub mod composite;
 pub mod fs;
 pub mod oci;
+mod policy;
+mod pull_error;
 
-use oci_distribution::client::ImageData;
+pub use policy::RegistryPolicy;
+pub use pull_error::PullErrorKind;
+
+use oci_distribution::client::{ImageData, RateLimit};
+use oci_distribution::manifest::OciIndexEntry;
 use oci_distribution::secrets::RegistryAuth;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
+use anyhow::Context;
 use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
 use oci_distribution::Reference;
-use tracing::{debug, instrument};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::{debug, info, instrument};
 
 use crate::container::PullPolicy;
+use crate::environment::Net;
+use crate::fault::{self, FaultPoint};
+use crate::metrics::{REGISTRY_RATE_LIMIT, REGISTRY_RATE_LIMIT_REMAINING, STORE_TENANT_USAGE};
+use crate::node::problem;
 use crate::pod::Pod;
 use crate::store::oci::Client;
 
@@ -59,6 +77,107 @@ pub trait Store: Sync {
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>>;
 
+    /// Get a module's data on behalf of a pod in the given namespace.
+    ///
+    /// Stores that scope their cache by tenant (see [`StoreScope`]) use the namespace
+    /// and pull credentials to decide whether an already cached module may be used.
+    /// The default implementation ignores the namespace and calls [`Store::get`].
+    async fn get_for_namespace(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        _namespace: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.get(image_ref, pull_policy, auth).await
+    }
+
+    /// Pull a module ahead of time so that it is already cached when a pod needs it, or
+    /// refresh it if it is cached already.
+    ///
+    /// These pulls are not urgent, so implementations may skip them when pulling would risk
+    /// exhausting a registry's rate limit. The default implementation gets the module as if
+    /// its pull policy were `Always`.
+    async fn pre_pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+        self.get(image_ref, PullPolicy::Always, auth)
+            .await
+            .map(|_| ())
+    }
+
+    /// List the modules held in the store's local cache.
+    ///
+    /// The default implementation lists nothing, for stores that don't cache modules.
+    async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+        Ok(vec![])
+    }
+
+    /// Delete a module from the store's local cache, whichever tenants have pulled it. Returns
+    /// whether the module was cached. Pods that have already started are not affected, and the
+    /// module is pulled again the next time a pod needs it.
+    ///
+    /// The default implementation deletes nothing, for stores that don't cache modules.
+    async fn delete(&self, _image_ref: &Reference) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether the store is pulling modules for pods, or has done so recently. Background
+    /// work such as pre-pulling waits until the store is idle so it doesn't compete with pods
+    /// for bandwidth or registry quota.
+    ///
+    /// The default implementation is never busy.
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    /// Reclaim space in the store's local cache, such as that held by layers no cached module
+    /// uses any more. Returns the number of bytes freed.
+    ///
+    /// The default implementation frees nothing, for stores that don't cache modules.
+    async fn collect_garbage(&self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    /// The registry digest of the module the store last handed out for an image, if it is known.
+    /// Unlike the image reference, this identifies the exact module a pod runs even when the
+    /// reference is a tag.
+    ///
+    /// The default implementation returns `None`, for stores that don't track digests.
+    async fn digest(&self, _image_ref: &Reference) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// List the artifacts, such as SBOMs and provenance attestations, attached to an image,
+    /// optionally only those of one artifact type. These are always fetched from the registry,
+    /// as they can be attached after the image is pushed.
+    ///
+    /// The default implementation lists nothing, for stores that don't fetch from registries.
+    async fn referrers(
+        &self,
+        _image_ref: &Reference,
+        _auth: &RegistryAuth,
+        _artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        Ok(vec![])
+    }
+
+    /// Fetch the content of an artifact attached to an image, as listed by
+    /// [`Store::referrers`].
+    ///
+    /// The default implementation returns an error, as the default implementation of
+    /// [`Store::referrers`] lists no artifacts to fetch.
+    async fn pull_referrer(
+        &self,
+        image_ref: &Reference,
+        _auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow::anyhow!(
+            "image {} has no referrer {}",
+            image_ref,
+            referrer.digest
+        ))
+    }
+
     /// Fetch all container modules for a given `Pod` storing the name of the
     /// container and the module's data as key/value pairs in a hashmap.
     ///
@@ -88,8 +207,13 @@ pub trait Store: Sync {
                 let registry_authentication = auth.resolve_registry_auth(&reference).await?;
                 Ok((
                     container.name().to_string(),
-                    self.get(&reference, pull_policy, &registry_authentication)
-                        .await?,
+                    self.get_for_namespace(
+                        &reference,
+                        pull_policy,
+                        &registry_authentication,
+                        pod.namespace(),
+                    )
+                    .await?,
                 ))
             }
         });
@@ -102,25 +226,387 @@ pub trait Store: Sync {
     }
 }
 
+/// How modules cached by a [`LocalStore`] are shared between the tenants of a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreScope {
+    /// Any pod may use a module once it has been cached.
+    Shared,
+    /// A cached module may only be used by pods in a namespace that has pulled (or
+    /// verified access to) the module.
+    Namespace,
+    /// A cached module may only be used by pods using pull credentials that have pulled
+    /// (or verified access to) the module.
+    Credential,
+}
+
+impl Default for StoreScope {
+    fn default() -> Self {
+        StoreScope::Shared
+    }
+}
+
+impl FromStr for StoreScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(StoreScope::Shared),
+            "namespace" => Ok(StoreScope::Namespace),
+            "credential" => Ok(StoreScope::Credential),
+            other => Err(anyhow::anyhow!(
+                "unknown store scope {:?}, expected one of shared, namespace or credential",
+                other
+            )),
+        }
+    }
+}
+
+/// The identity a module is cached on behalf of when a [`LocalStore`] is scoped.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    /// Determines the tenant for a request in the given scope. Returns `None` for
+    /// [`StoreScope::Shared`], where there are no tenants.
+    ///
+    /// Credentials must never be written to disk, so in [`StoreScope::Credential`] they are
+    /// identified by an HMAC keyed with a secret of the node's own, which can't be reversed or
+    /// guessed at offline by anyone who can read the store but not the secret.
+    pub fn new(
+        scope: StoreScope,
+        namespace: &str,
+        auth: &RegistryAuth,
+        secret: &[u8],
+    ) -> Option<Self> {
+        match scope {
+            StoreScope::Shared => None,
+            StoreScope::Namespace => Some(Tenant(format!("namespace-{}", namespace))),
+            StoreScope::Credential => {
+                let identity = match auth {
+                    RegistryAuth::Anonymous => "anonymous".to_owned(),
+                    RegistryAuth::Basic(username, password) => {
+                        format!("basic:{}:{}", username, password)
+                    }
+                };
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(identity.as_bytes());
+                Some(Tenant(format!(
+                    "credential-{:x}",
+                    mac.finalize().into_bytes()
+                )))
+            }
+        }
+    }
+
+    /// A file system safe identifier for the tenant.
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The record of a tenant having pulled, or verified its access to, a cached module.
+#[derive(Clone, Debug)]
+pub struct TenantRecord {
+    /// The image ref of the module
+    pub image_ref: Reference,
+    /// The tenant the module is cached on behalf of
+    pub tenant: Tenant,
+    /// When the tenant last used the module
+    pub last_used: SystemTime,
+}
+
+/// A module held in a store's local cache.
+#[derive(Clone, Debug)]
+pub struct CachedModule {
+    /// The image reference the module was pulled by
+    pub image_ref: Reference,
+    /// The digest of the image, if the registry reported one
+    pub digest: Option<String>,
+    /// The size of the module in bytes
+    pub size: u64,
+}
+
+/// The fraction of a registry's rate limit that is kept in reserve for pulls of modules
+/// that aren't cached yet.
+const RATE_LIMIT_RESERVE: f64 = 0.1;
+
+/// How long a tenant's record of a cached module lasts without the tenant using the module.
+/// Modules are removed from a scoped store once no tenant has a record of them.
+const TENANT_RECORD_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long after a pod's pull the store still counts as busy.
+const BUSY_COOLDOWN: Duration = Duration::from_secs(30);
+
 /// A `Store` implementation which obtains module data from remote registries
 /// but caches it in local storage.
 pub struct LocalStore<S: Storer, C: Client> {
     storer: Arc<RwLock<S>>,
     client: Arc<Mutex<C>>,
+    background_client: Option<Arc<Mutex<C>>>,
+    scope: StoreScope,
+    tenant_secret: Arc<Vec<u8>>,
+    pull_deadline: Option<Duration>,
+    net: Arc<dyn Net>,
+    activity: Arc<PullActivity>,
+}
+
+/// Tracks the pulls made on behalf of pods, so background work can tell when the store is idle.
+#[derive(Default)]
+struct PullActivity {
+    active: AtomicUsize,
+    last_finished: std::sync::Mutex<Option<Instant>>,
+}
+
+impl PullActivity {
+    fn start(self: &Arc<Self>) -> PullGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        PullGuard(self.clone())
+    }
+
+    fn is_busy(&self) -> bool {
+        if self.active.load(Ordering::SeqCst) > 0 {
+            return true;
+        }
+        match *self.last_finished.lock().unwrap() {
+            Some(t) => t.elapsed() < BUSY_COOLDOWN,
+            None => false,
+        }
+    }
+}
+
+/// Marks a pod's pull as finished when dropped.
+struct PullGuard(Arc<PullActivity>);
+
+impl Drop for PullGuard {
+    fn drop(&mut self) {
+        *self.0.last_finished.lock().unwrap() = Some(Instant::now());
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
-    #[instrument(level = "info", skip(self, auth))]
-    async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
-        debug!("Pulling image ref from registry");
-        let image_data = self.client.lock().await.pull(image_ref, auth).await?;
+    /// Sets how cached modules are shared between tenants. Defaults to [`StoreScope::Shared`].
+    pub fn with_scope(mut self, scope: StoreScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Sets the longest a single pull of a module may take before it is abandoned. By
+    /// default pulls may take as long as they need.
+    pub fn with_pull_deadline(mut self, deadline: Duration) -> Self {
+        self.pull_deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the network the store checks registries can be reached through. Defaults to the
+    /// real network.
+    pub fn with_net(mut self, net: Arc<dyn Net>) -> Self {
+        self.net = net;
+        self
+    }
+
+    /// Sets a second client that background work, such as pre-pulls and fetching attestations, uses so that it never
+    /// holds up pods waiting on the client their pulls use. By default they share one client.
+    pub fn with_background_client(mut self, client: C) -> Self {
+        self.background_client = Some(Arc::new(Mutex::new(client)));
+        self
+    }
+
+    /// The client background work pulls with.
+    fn background_client(&self) -> &Mutex<C> {
+        self.background_client.as_deref().unwrap_or(&self.client)
+    }
+
+    /// Gets the number of bytes of cached modules attributed to the tenant.
+    pub async fn tenant_usage(&self, tenant: &Tenant) -> anyhow::Result<u64> {
+        self.storer.read().await.tenant_usage(tenant).await
+    }
+
+    /// Releases the tenant's reference to a cached module, removing it from the cache if
+    /// no other tenant references it. Returns whether the module was removed.
+    pub async fn release(&self, image_ref: &Reference, tenant: &Tenant) -> anyhow::Result<bool> {
         self.storer
             .write()
             .await
-            .store(image_ref, image_data)
-            .await?;
+            .remove_tenant(image_ref, tenant)
+            .await
+    }
+
+    /// Whether non-urgent pulls from the module's registry, such as refreshing a cached module,
+    /// should be deferred because little of the registry's rate limit is left.
+    pub async fn should_defer_pull(&self, image_ref: &Reference) -> bool {
+        self.rate_limit(image_ref.registry())
+            .await
+            .map(|r| r.is_nearly_exhausted(RATE_LIMIT_RESERVE))
+            .unwrap_or(false)
+    }
+
+    /// The most exhausted rate limit the registry has reported to any of the store's clients,
+    /// as they share the registry's quota.
+    async fn rate_limit(&self, registry: &str) -> Option<RateLimit> {
+        let mut rate_limit = self.client.lock().await.rate_limit(registry);
+        if let Some(background) = &self.background_client {
+            if let Some(other) = background.lock().await.rate_limit(registry) {
+                if rate_limit
+                    .as_ref()
+                    .map_or(true, |r| other.remaining < r.remaining)
+                {
+                    rate_limit = Some(other);
+                }
+            }
+        }
+        rate_limit
+    }
+
+    /// Publishes the rate limit last reported by the module's registry as metrics.
+    async fn record_rate_limit(&self, image_ref: &Reference) {
+        let registry = image_ref.registry();
+        if let Some(rate_limit) = self.rate_limit(registry).await {
+            REGISTRY_RATE_LIMIT
+                .with_label_values(&[registry])
+                .set(rate_limit.limit as i64);
+            REGISTRY_RATE_LIMIT_REMAINING
+                .with_label_values(&[registry])
+                .set(rate_limit.remaining as i64);
+        }
+    }
+
+    /// Checks the digest of the module with the registry, pulling it again if the registry's
+    /// copy differs from the cached one. This also verifies that the credentials grant access
+    /// to the module.
+    #[instrument(level = "info", skip(self, client, auth))]
+    async fn refresh(
+        &self,
+        client: &Mutex<C>,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        debug!("Checking cached image ref against registry");
+        let digest = client.lock().await.fetch_digest(image_ref, auth).await;
+        self.record_rate_limit(image_ref).await;
+        let digest = digest?;
+        registry_reached();
+        let already_got_with_digest = self
+            .storer
+            .read()
+            .await
+            .is_present_with_digest(image_ref, digest)
+            .await;
+        if !already_got_with_digest {
+            self.pull(client, image_ref, auth).await?
+        }
         Ok(())
     }
+
+    #[instrument(level = "info", skip(self, client, auth))]
+    async fn pull(
+        &self,
+        client: &Mutex<C>,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        debug!("Pulling image ref from registry");
+        // Each pull gets a staging path of its own, so pulls of the same module at the same
+        // time, such as a pre-pull and a pod's pull, don't write over each other
+        let staging_path = self.storer.read().await.staging_path(image_ref);
+        let pull = self.pull_within_deadline(client, image_ref, auth, staging_path.as_deref());
+        let deadline = match self.pull_deadline {
+            Some(d) => d,
+            None => return pull.await,
+        };
+        match tokio::time::timeout(deadline, pull).await {
+            Ok(result) => result,
+            Err(elapsed) => {
+                // The abandoned pull may have left a partial module behind
+                if let Some(p) = &staging_path {
+                    if let Err(remove_err) = tokio::fs::remove_file(&p).await {
+                        debug!(error = %remove_err, "Unable to remove partially pulled module");
+                    }
+                }
+                Err(elapsed).with_context(|| {
+                    format!(
+                        "pull of image ref {} exceeded deadline of {:?}",
+                        image_ref, deadline
+                    )
+                })
+            }
+        }
+    }
+
+    async fn pull_within_deadline(
+        &self,
+        client: &Mutex<C>,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        staging_path: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        fault::inject(FaultPoint::RegistryPull, &image_ref.whole()).await?;
+        if !self.net.reachable(image_ref.registry()) {
+            anyhow::bail!("Registry {} is unreachable", image_ref.registry());
+        }
+        let staging_path = match staging_path {
+            Some(p) => p,
+            None => {
+                let image_data = client.lock().await.pull(image_ref, auth).await;
+                self.record_rate_limit(image_ref).await;
+                let image_data = image_data?;
+                registry_reached();
+                return self.storer.write().await.store(image_ref, image_data).await;
+            }
+        };
+
+        // Stream the module to disk rather than holding all of it in memory, and only
+        // move it into place once the whole thing has arrived
+        let digest = self
+            .pull_to_file(client, image_ref, auth, staging_path)
+            .await;
+        self.record_rate_limit(image_ref).await;
+        match digest {
+            Ok(digest) => {
+                registry_reached();
+                self.storer
+                    .write()
+                    .await
+                    .commit(image_ref, staging_path, digest)
+                    .await
+            }
+            Err(e) => {
+                if let Err(remove_err) = tokio::fs::remove_file(staging_path).await {
+                    debug!(error = %remove_err, "Unable to remove partially pulled module");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn pull_to_file(
+        &self,
+        client: &Mutex<C>,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        path: &Path,
+    ) -> anyhow::Result<Option<String>> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::File::create(path).await?;
+        let digest = client
+            .lock()
+            .await
+            .pull_into(image_ref, auth, &mut file)
+            .await?;
+        file.sync_all().await?;
+        Ok(digest)
+    }
+}
+
+/// Clears the node's registry problem once a module was actually fetched from, or checked
+/// against, a registry. Modules served from the cache say nothing about whether registries can
+/// be reached, so they leave the problem in place.
+fn registry_reached() {
+    problem::resolve(problem::REGISTRY_UNREACHABLE);
 }
 
 #[async_trait]
@@ -131,34 +617,152 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
         pull_policy: PullPolicy,
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>> {
+        // Without a namespace, requests in a namespace scoped store are treated as coming
+        // from a tenant of their own so they cannot see anything pulled by a namespace
+        self.get_for_namespace(image_ref, pull_policy, auth, "")
+            .await
+    }
+
+    async fn get_for_namespace(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+        namespace: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let tenant = Tenant::new(self.scope, namespace, auth, &self.tenant_secret);
+        let _activity = self.activity.start();
         match pull_policy {
             PullPolicy::IfNotPresent => {
-                if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
+                let (present, has_tenant) = {
+                    let storer = self.storer.read().await;
+                    let present = storer.is_present(image_ref).await;
+                    let has_tenant = match &tenant {
+                        Some(t) => storer.has_tenant(image_ref, t).await,
+                        None => true,
+                    };
+                    (present, has_tenant)
+                };
+                if !present {
+                    self.pull(&self.client, image_ref, auth).await?
+                } else if !has_tenant {
+                    // Another tenant pulled this module, so make sure this one is
+                    // actually allowed to have it before handing it over
+                    self.refresh(&self.client, image_ref, auth).await?
                 }
             }
             PullPolicy::Always => {
-                let digest = self
-                    .client
-                    .lock()
-                    .await
-                    .fetch_digest(image_ref, auth)
-                    .await?;
-                let already_got_with_digest = self
-                    .storer
-                    .read()
-                    .await
-                    .is_present_with_digest(image_ref, digest)
-                    .await;
-                if !already_got_with_digest {
-                    self.pull(image_ref, auth).await?
+                let present = self.storer.read().await.is_present(image_ref).await;
+                if present && self.should_defer_pull(image_ref).await {
+                    // Refreshing a cached module isn't worth spending the last of the
+                    // registry's quota on
+                    info!(
+                        "Registry rate limit is nearly exhausted, using cached copy of image ref"
+                    );
+                } else {
+                    self.refresh(&self.client, image_ref, auth).await?
+                }
+            }
+            PullPolicy::Never => {
+                if let Some(t) = &tenant {
+                    if !self.storer.read().await.has_tenant(image_ref, t).await {
+                        return Err(anyhow::anyhow!(
+                            "Image ref {} has not been pulled for this tenant and the pull policy is Never",
+                            image_ref
+                        ));
+                    }
                 }
             }
-            PullPolicy::Never => (),
         };
 
+        if let Some(t) = &tenant {
+            self.storer.write().await.add_tenant(image_ref, t).await?;
+        }
         self.storer.read().await.get_local(image_ref).await
     }
+
+    async fn pre_pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+        if self.should_defer_pull(image_ref).await {
+            info!(%image_ref, "Registry rate limit is nearly exhausted, deferring pre-pull");
+            return Ok(());
+        }
+        let client = self.background_client();
+        if self.storer.read().await.is_present(image_ref).await {
+            self.refresh(client, image_ref, auth).await
+        } else {
+            self.pull(client, image_ref, auth).await
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.activity.is_busy()
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+        self.storer.read().await.list().await
+    }
+
+    async fn delete(&self, image_ref: &Reference) -> anyhow::Result<bool> {
+        self.storer.write().await.delete(image_ref).await
+    }
+
+    async fn collect_garbage(&self) -> anyhow::Result<u64> {
+        let records = self.storer.read().await.tenant_records().await?;
+        let now = SystemTime::now();
+        let mut tenants = HashSet::new();
+        for record in records {
+            let idle = now.duration_since(record.last_used).unwrap_or_default();
+            if idle < TENANT_RECORD_TTL {
+                tenants.insert(record.tenant);
+                continue;
+            }
+            debug!(image_ref = %record.image_ref, tenant = record.tenant.id(), "Tenant has not used cached module recently, releasing it");
+            if self.release(&record.image_ref, &record.tenant).await? {
+                info!(image_ref = %record.image_ref, "Removed cached module that no tenant uses");
+            }
+        }
+
+        let freed = self.storer.write().await.collect_garbage().await?;
+        STORE_TENANT_USAGE.reset();
+        for tenant in tenants {
+            let usage = self.tenant_usage(&tenant).await?;
+            STORE_TENANT_USAGE
+                .with_label_values(&[tenant.id()])
+                .set(usage as i64);
+        }
+        Ok(freed)
+    }
+
+    async fn digest(&self, image_ref: &Reference) -> anyhow::Result<Option<String>> {
+        Ok(self.storer.read().await.digest(image_ref).await)
+    }
+
+    async fn referrers(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        // Artifacts are only for audit trails, so fetching them never holds up pods' pulls
+        self.background_client()
+            .lock()
+            .await
+            .referrers(image_ref, auth, artifact_type)
+            .await
+    }
+
+    async fn pull_referrer(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.background_client()
+            .lock()
+            .await
+            .pull_referrer(image_ref, auth, referrer)
+            .await
+    }
 }
 
 /// A backing store for the `LocalStore` implementation of `Store`. The Storer
@@ -168,6 +772,33 @@ pub trait Storer {
     /// Saves a module's data into the backing store indexed by its image `Reference`.
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()>;
 
+    /// A path that a module's data can be streamed to while it is being pulled, before
+    /// being moved into the backing store with [`Storer::commit`]. Pulls of the same module
+    /// can run at the same time, so each call must return a path no other pull is using.
+    ///
+    /// The default implementation returns `None`, in which case modules are pulled into
+    /// memory and saved with [`Storer::store`].
+    fn staging_path(&self, _image_ref: &Reference) -> Option<PathBuf> {
+        None
+    }
+
+    /// Moves a module that was streamed to the [`Storer::staging_path`] into the backing
+    /// store, recording its digest if it has one.
+    ///
+    /// This must either fully replace any previously stored data for the module or fail
+    /// without changing it.
+    async fn commit(
+        &mut self,
+        image_ref: &Reference,
+        _staged: &Path,
+        _digest: Option<String>,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "Storer does not support staging image ref {}",
+            image_ref
+        ))
+    }
+
     /// Get a module's data from the backing store given its image `Reference`.
     ///
     /// The implementation must fail if the image is not present
@@ -180,4 +811,62 @@ pub trait Storer {
 
     /// Whether the specified module is already present in the backing store with the specified digest.
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool;
+
+    /// The registry digest of the specified module as it was last stored, if it is known.
+    ///
+    /// The default implementation returns `None`, for backing stores that don't record digests.
+    async fn digest(&self, _image_ref: &Reference) -> Option<String> {
+        None
+    }
+
+    /// Records that the tenant has pulled, or verified its access to, the specified module.
+    ///
+    /// The default implementation records nothing, which means tenants of a scoped
+    /// `LocalStore` always have their access verified with the registry.
+    async fn add_tenant(&mut self, _image_ref: &Reference, _tenant: &Tenant) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the tenant has previously pulled, or verified its access to, the specified module.
+    async fn has_tenant(&self, _image_ref: &Reference, _tenant: &Tenant) -> bool {
+        false
+    }
+
+    /// Lists the records of tenants having pulled, or verified their access to, the modules in
+    /// the backing store.
+    async fn tenant_records(&self) -> anyhow::Result<Vec<TenantRecord>> {
+        Ok(vec![])
+    }
+
+    /// The number of bytes of module data attributed to the tenant.
+    async fn tenant_usage(&self, _tenant: &Tenant) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    /// Removes the tenant's record for the specified module, deleting the module once no
+    /// tenants reference it. Returns whether the module was deleted.
+    async fn remove_tenant(
+        &mut self,
+        _image_ref: &Reference,
+        _tenant: &Tenant,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Lists the modules in the backing store.
+    async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+        Ok(vec![])
+    }
+
+    /// Deletes the specified module from the backing store, along with the records of its
+    /// tenants. Returns whether the module was present.
+    async fn delete(&mut self, _image_ref: &Reference) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Removes data from the backing store that no module needs any more. Returns the number of
+    /// bytes freed.
+    async fn collect_garbage(&mut self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
 }
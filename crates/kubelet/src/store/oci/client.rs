@@ -1,10 +1,13 @@
 //! Client for fetching container modules from OCI
 use async_trait::async_trait;
-use oci_distribution::client::ImageData;
-use oci_distribution::manifest;
+use oci_distribution::client::{ImageData, RateLimit};
+use oci_distribution::compression::Compression;
+use oci_distribution::errors::ClientError;
+use oci_distribution::manifest::{self, OciIndexEntry};
 use oci_distribution::secrets::RegistryAuth;
 
 use oci_distribution::Reference;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 /// An image client capable of fetching images from a storage location
 #[async_trait]
@@ -54,6 +57,66 @@ pub trait Client {
             .digest
             .ok_or_else(|| anyhow::anyhow!("image {} does not have a digest", image_ref))
     }
+
+    /// Stream the module data for the given image reference into `out`, returning the
+    /// image digest if available.
+    ///
+    /// The default implementation pulls the whole image into memory with
+    /// [`Client::pull`] and writes out its first layer. Clients that can stream the
+    /// module as it is downloaded should override this.
+    async fn pull_into(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        out: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> anyhow::Result<Option<String>> {
+        let image_data = self.pull(image_ref, auth).await?;
+        let layer = image_data
+            .layers
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("No module layer present in image data"))?;
+        out.write_all(&layer.data).await?;
+        out.flush().await?;
+        Ok(image_data.digest)
+    }
+
+    /// List the artifacts, such as SBOMs and provenance attestations, that refer to the given
+    /// image, optionally only those of one artifact type.
+    ///
+    /// The default implementation lists nothing, for clients of storage locations that don't
+    /// hold artifacts.
+    async fn referrers(
+        &mut self,
+        _image_ref: &Reference,
+        _auth: &RegistryAuth,
+        _artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        Ok(vec![])
+    }
+
+    /// Fetch the content of an artifact that refers to the given image.
+    ///
+    /// The default implementation returns an error, as the default implementation of
+    /// [`Client::referrers`] lists no artifacts to fetch.
+    async fn pull_referrer(
+        &mut self,
+        image_ref: &Reference,
+        _auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow::anyhow!(
+            "image {} has no referrer {}",
+            image_ref,
+            referrer.digest
+        ))
+    }
+
+    /// The most recent rate limit reported by the given registry.
+    ///
+    /// The default implementation returns `None`, meaning the client is never rate limited.
+    fn rate_limit(&self, _registry: &str) -> Option<RateLimit> {
+        None
+    }
 }
 
 #[async_trait]
@@ -70,4 +133,54 @@ impl Client for oci_distribution::Client {
     ) -> anyhow::Result<String> {
         self.fetch_manifest_digest(image, auth).await
     }
+
+    async fn pull_into(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        out: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> anyhow::Result<Option<String>> {
+        let (image_manifest, digest) = self.pull_manifest(image, auth).await?;
+        // FIXME: like `pull`, this assumes the module is the only layer of the image
+        let layer = image_manifest
+            .layers
+            .iter()
+            .find(|l| !manifest::is_nondistributable(&l.media_type))
+            .ok_or_else(|| anyhow::anyhow!("no layers to pull"))?;
+        // Modules may be pushed compressed, in which case they are decompressed as they
+        // are pulled
+        let (media_type, compression) = Compression::split_media_type(&layer.media_type);
+        if media_type != manifest::WASM_LAYER_MEDIA_TYPE {
+            return Err(ClientError::IncompatibleLayerMediaType(layer.media_type.clone()).into());
+        }
+        if compression == Compression::None {
+            self.pull_blob(image, auth, &layer.digest, out).await?;
+        } else {
+            self.pull_layer_decompressed(image, auth, layer, out)
+                .await?;
+        }
+        Ok(Some(digest))
+    }
+
+    async fn referrers(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        self.pull_referrers(image, auth, artifact_type).await
+    }
+
+    async fn pull_referrer(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        oci_distribution::Client::pull_referrer(self, image, auth, referrer).await
+    }
+
+    fn rate_limit(&self, registry: &str) -> Option<RateLimit> {
+        oci_distribution::Client::rate_limit(self, registry)
+    }
 }
@@ -1,16 +1,29 @@
-use crate::store::Storer;
+use crate::environment::SystemNet;
+use crate::store::{CachedModule, Storer, Tenant, TenantRecord};
 use oci_distribution::client::ImageData;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use oci_distribution::Reference;
+use sha2::Digest;
+use tokio::io::AsyncReadExt;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use super::client::Client;
-use crate::store::LocalStore;
+use crate::store::{LocalStore, StoreScope};
+
+/// The directory, under the root of the store, that holds module layers. Underscores can't
+/// appear in registry host names, so this can't clash with the directory of a registry.
+const LAYERS_DIR: &str = "_layers";
+/// The file, under the root of the store, holding the secret tenant identities are derived
+/// with.
+const TENANT_SECRET_FILE: &str = "_tenant_secret";
+/// The prefix of the directory holding the records of an image pinned to a digest
+const DIGEST_DIR_PREFIX: &str = "@";
 
 /// A module store that keeps modules cached on the file system
 ///
@@ -27,10 +40,56 @@ impl<C: Client + Send> FileStore<C> {
                 root_dir: root_dir.as_ref().into(),
             })),
             client: Arc::new(Mutex::new(client)),
+            background_client: None,
+            scope: StoreScope::default(),
+            tenant_secret: Arc::new(load_tenant_secret(root_dir.as_ref())),
+            pull_deadline: None,
+            net: Arc::new(SystemNet),
+            activity: Arc::default(),
         }
     }
 }
 
+/// Reads the secret tenant identities are derived with from the root of the store, creating it
+/// if the store doesn't have one yet. If it can't be kept, a secret good for the life of the
+/// Kubelet is used instead, and tenants verify their access to cached modules again after a
+/// restart.
+fn load_tenant_secret(root_dir: &Path) -> Vec<u8> {
+    let path = root_dir.join(TENANT_SECRET_FILE);
+    match std::fs::read(&path) {
+        Ok(secret) if !secret.is_empty() => return secret,
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => {
+            warn!(error = %e, path = %path.display(), "Unable to read store tenant secret");
+            return rand::random::<[u8; 32]>().to_vec();
+        }
+    }
+    let secret = rand::random::<[u8; 32]>().to_vec();
+    if let Err(e) = write_secret(&path, &secret) {
+        warn!(error = %e, path = %path.display(), "Unable to save store tenant secret");
+    }
+    secret
+}
+
+fn write_secret(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(secret)
+}
+
+/// Stores modules on the file system.
+///
+/// Layers are stored once under their digest, and each image records which layer
+/// holds its module, so images that share a layer don't duplicate it on disk. A layer
+/// keeps a reference for each image that uses it and is deleted when the last one
+/// is released.
 pub struct FileStorer {
     root_dir: PathBuf,
 }
@@ -44,67 +103,762 @@ impl FileStorer {
     }
 
     fn pull_path(&self, r: &Reference) -> PathBuf {
-        let mut path = self.root_dir.join(r.registry());
-        path.push(r.repository());
-        path.push(r.tag().unwrap_or("latest"));
-        path
+        self.root_dir.join(image_dir(r))
     }
 
-    fn pull_file_path(&self, r: &Reference) -> PathBuf {
+    /// The location of modules stored before layers were deduplicated.
+    fn legacy_pull_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("module.wasm")
     }
 
+    /// A new path to stage a module in, unique to the pull it is for.
+    fn staging_path_for(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r)
+            .join(format!("module.wasm.{}.partial", uuid::Uuid::new_v4()))
+    }
+
     fn digest_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("digest.txt")
     }
+
+    fn layer_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("layer.txt")
+    }
+
+    fn tenants_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("tenants")
+    }
+
+    fn tenant_file_path(&self, r: &Reference, tenant: &Tenant) -> PathBuf {
+        self.tenants_path(r).join(tenant.id())
+    }
+
+    fn layer_path(&self, layer_digest: &str) -> PathBuf {
+        layer_path_in(&self.root_dir, layer_digest)
+    }
+
+    fn layer_refs_path(&self, layer_digest: &str) -> PathBuf {
+        layer_refs_path_in(&self.root_dir, layer_digest)
+    }
+
+    fn layer_ref_file_path(&self, layer_digest: &str, r: &Reference) -> PathBuf {
+        self.layer_ref_file_path_for_dir(layer_digest, &image_dir(r))
+    }
+
+    /// The reference to a layer from the image whose records are in `dir`, relative to the root
+    /// of the store.
+    fn layer_ref_file_path_for_dir(&self, layer_digest: &str, dir: &Path) -> PathBuf {
+        let id = sha2::Sha256::digest(dir.to_string_lossy().as_bytes());
+        self.layer_refs_path(layer_digest).join(format!("{:x}", id))
+    }
+
+    /// The digest of the layer holding the module of the image ref, if it has been stored.
+    async fn layer_digest(&self, r: &Reference) -> Option<String> {
+        tokio::fs::read_to_string(self.layer_file_path(r))
+            .await
+            .ok()
+            .map(|d| d.trim().to_owned())
+    }
+
+    async fn module_path(&self, r: &Reference) -> Option<PathBuf> {
+        if let Some(layer_digest) = self.layer_digest(r).await {
+            let path = self.layer_path(&layer_digest);
+            if path.exists() {
+                return Some(path);
+            }
+        }
+        // Modules cached before layers were deduplicated are used where they are until garbage
+        // collection moves them into the layer store
+        let legacy_path = self.legacy_pull_file_path(r);
+        if legacy_path.exists() {
+            Some(legacy_path)
+        } else {
+            None
+        }
+    }
+
+    async fn add_layer_ref(&self, layer_digest: &str, r: &Reference) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(self.layer_refs_path(layer_digest)).await?;
+        // The ref records the image's directory so garbage collection can check that the
+        // image still uses the layer
+        tokio::fs::write(
+            self.layer_ref_file_path(layer_digest, r),
+            image_dir(r).to_string_lossy().as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Releases the image's reference to a layer, deleting the layer if no other image
+    /// references it. Returns whether the layer was deleted.
+    async fn release_layer(&self, layer_digest: &str, r: &Reference) -> anyhow::Result<bool> {
+        let ref_path = self.layer_ref_file_path(layer_digest, r);
+        if ref_path.exists() {
+            tokio::fs::remove_file(&ref_path).await?;
+        }
+        let refs_path = self.layer_refs_path(layer_digest);
+        let has_other_refs = match tokio::fs::read_dir(&refs_path).await {
+            Ok(mut entries) => entries.next_entry().await?.is_some(),
+            Err(_) => false,
+        };
+        if has_other_refs {
+            return Ok(false);
+        }
+        debug!(layer_digest, "No images reference layer, removing it");
+        if refs_path.exists() {
+            tokio::fs::remove_dir(&refs_path).await?;
+        }
+        let layer_path = self.layer_path(layer_digest);
+        if layer_path.exists() {
+            tokio::fs::remove_file(&layer_path).await?;
+        }
+        Ok(true)
+    }
+
+    /// Moves the records of images cached before the parts of their refs were escaped in
+    /// [`image_dir`], which used the parts as they are, to where they are kept now, along with
+    /// the references to their layers. Only Windows escapes the parts, so elsewhere every image
+    /// is already where it belongs.
+    async fn migrate_unescaped_image_dirs(&mut self) -> anyhow::Result<()> {
+        let root_dir = self.root_dir.clone();
+        let unescaped = tokio::task::spawn_blocking(move || {
+            let mut images = Vec::new();
+            if root_dir.exists() {
+                unescaped_image_dirs_in(&root_dir, &root_dir, &mut images)?;
+            }
+            Ok::<_, anyhow::Error>(images)
+        })
+        .await??;
+        for (old_dir, image_ref) in unescaped {
+            let new_path = self.pull_path(&image_ref);
+            let old_path = self.root_dir.join(&old_dir);
+            if new_path.exists() {
+                // The image was pulled again since, so the old records are stale. Garbage
+                // collection removes their reference to the layer once they are gone
+                debug!(
+                    ?image_ref,
+                    "Removing records of image kept in an unescaped directory"
+                );
+                tokio::fs::remove_dir_all(&old_path).await?;
+                continue;
+            }
+            debug!(
+                ?image_ref,
+                "Moving records of image to an escaped directory"
+            );
+            if let Some(parent) = new_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::rename(&old_path, &new_path).await?;
+            if let Some(layer_digest) = self.layer_digest(&image_ref).await {
+                self.add_layer_ref(&layer_digest, &image_ref).await?;
+                let old_ref = self.layer_ref_file_path_for_dir(&layer_digest, &old_dir);
+                if old_ref.exists() {
+                    tokio::fs::remove_file(&old_ref).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves modules cached before layers were deduplicated, which were kept in the directory
+    /// of their image, into the layer store.
+    async fn migrate_legacy_modules(&mut self) -> anyhow::Result<()> {
+        let root_dir = self.root_dir.clone();
+        let legacy_images = tokio::task::spawn_blocking(move || {
+            let mut images = Vec::new();
+            if root_dir.exists() {
+                legacy_modules_in(&root_dir, &root_dir, &mut images)?;
+            }
+            Ok::<_, anyhow::Error>(images)
+        })
+        .await??;
+        for image_ref in legacy_images {
+            let legacy_path = self.legacy_pull_file_path(&image_ref);
+            let layer_stored = match self.layer_digest(&image_ref).await {
+                Some(layer_digest) => self.layer_path(&layer_digest).exists(),
+                None => false,
+            };
+            if layer_stored {
+                // A commit was interrupted after storing the layer but before removing the
+                // legacy module, which it replaced
+                tokio::fs::remove_file(&legacy_path).await?;
+                continue;
+            }
+            debug!(
+                ?image_ref,
+                "Moving legacy cached module into the layer store"
+            );
+            let digest = tokio::fs::read_to_string(self.digest_file_path(&image_ref))
+                .await
+                .ok();
+            self.commit(&image_ref, &legacy_path, digest).await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl Storer for FileStorer {
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
-        let path = self.pull_file_path(image_ref);
-        if !path.exists() {
-            return Err(anyhow::anyhow!(
-                "Image ref {} not available locally",
-                image_ref
-            ));
-        }
+        let path = match self.module_path(image_ref).await {
+            Some(p) => p,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Image ref {} not available locally",
+                    image_ref
+                ))
+            }
+        };
 
         debug!(?image_ref, "Fetching image ref from disk");
         Ok(tokio::fs::read(path).await?)
     }
+
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()> {
+        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
+        if image_data.layers.is_empty() {
+            return Err(anyhow::anyhow!("No module layer present in image data"));
+        }
+        let staging_path = self.staging_path_for(image_ref);
         tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
+        tokio::fs::write(&staging_path, &image_data.layers[0].data).await?;
+        self.commit(image_ref, &staging_path, image_data.digest)
+            .await
+    }
+
+    fn staging_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        Some(self.staging_path_for(image_ref))
+    }
+
+    async fn commit(
+        &mut self,
+        image_ref: &Reference,
+        staged: &Path,
+        digest: Option<String>,
+    ) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
+
+        let layer_digest = file_sha256_digest(staged).await?;
+        let layer_path = self.layer_path(&layer_digest);
+        if layer_path.exists() {
+            debug!(?image_ref, %layer_digest, "Layer already stored, reusing it");
+            tokio::fs::remove_file(staged).await?;
+        } else {
+            if let Some(parent) = layer_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Renaming is atomic, so readers never see a partially written layer
+            tokio::fs::rename(staged, &layer_path).await?;
+        }
+        // Take the reference before pointing the image at the layer, so it can't be
+        // collected in between
+        self.add_layer_ref(&layer_digest, image_ref).await?;
+
         let digest_path = self.digest_file_path(image_ref);
-        // We delete the digest file before writing the image file, rather
-        // than simply overwriting the digest file after writing the image file.
+        // We delete the digest file before pointing the image at the new layer, rather
+        // than simply overwriting the digest file afterwards.
         // This addresses failure modes where, for example, the image file
         // gets updated but the digest file write fails and the store ends
         // up associating the wrong digest with the file on disk.
         if digest_path.exists() {
             tokio::fs::remove_file(&digest_path).await?;
         }
-        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
-        let module_path = self.pull_file_path(image_ref);
-        if image_data.layers.is_empty() {
-            return Err(anyhow::anyhow!("No module layer present in image data"));
+        let previous_layer = self.layer_digest(image_ref).await;
+        let layer_file_path = self.layer_file_path(image_ref);
+        let layer_file_staging_path = layer_file_path.with_extension("txt.partial");
+        tokio::fs::write(&layer_file_staging_path, &layer_digest).await?;
+        tokio::fs::rename(&layer_file_staging_path, &layer_file_path).await?;
+
+        let legacy_path = self.legacy_pull_file_path(image_ref);
+        if legacy_path.exists() {
+            tokio::fs::remove_file(&legacy_path).await?;
+        }
+        if let Some(previous) = previous_layer.filter(|p| p != &layer_digest) {
+            self.release_layer(&previous, image_ref).await?;
         }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
-        if let Some(d) = image_data.digest {
+
+        if let Some(d) = digest {
             tokio::fs::write(&digest_path, d).await?;
         }
         Ok(())
     }
 
     async fn is_present(&self, image_ref: &Reference) -> bool {
-        let path = self.pull_file_path(image_ref);
-        path.exists()
+        self.module_path(image_ref).await.is_some()
     }
 
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool {
         let path = self.digest_file_path(image_ref);
         path.exists() && file_content_is(path, digest).await
     }
+
+    async fn digest(&self, image_ref: &Reference) -> Option<String> {
+        let digest = tokio::fs::read_to_string(self.digest_file_path(image_ref))
+            .await
+            .ok()?;
+        Some(digest.trim().to_owned()).filter(|d| !d.is_empty())
+    }
+
+    async fn add_tenant(&mut self, image_ref: &Reference, tenant: &Tenant) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(self.tenants_path(image_ref)).await?;
+        // The record holds when the tenant last used the module
+        tokio::fs::write(
+            self.tenant_file_path(image_ref, tenant),
+            chrono::Utc::now().to_rfc3339(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn tenant_records(&self) -> anyhow::Result<Vec<TenantRecord>> {
+        let root_dir = self.root_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut records = Vec::new();
+            if root_dir.exists() {
+                tenant_records_in(&root_dir, &root_dir, &mut records)?;
+            }
+            Ok(records)
+        })
+        .await?
+    }
+
+    async fn has_tenant(&self, image_ref: &Reference, tenant: &Tenant) -> bool {
+        self.tenant_file_path(image_ref, tenant).exists()
+    }
+
+    async fn tenant_usage(&self, tenant: &Tenant) -> anyhow::Result<u64> {
+        let root_dir = self.root_dir.clone();
+        let tenant_id = tenant.id().to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut layers = HashSet::new();
+            tenant_layers_in(&root_dir, &root_dir, &tenant_id, &mut layers)?;
+            // Layers shared between the tenant's images only count once
+            Ok(layers
+                .iter()
+                .map(|l| {
+                    std::fs::metadata(layer_path_in(&root_dir, l))
+                        .map(|m| m.len())
+                        .unwrap_or(0)
+                })
+                .sum())
+        })
+        .await?
+    }
+
+    async fn remove_tenant(
+        &mut self,
+        image_ref: &Reference,
+        tenant: &Tenant,
+    ) -> anyhow::Result<bool> {
+        let tenant_path = self.tenant_file_path(image_ref, tenant);
+        if tenant_path.exists() {
+            tokio::fs::remove_file(&tenant_path).await?;
+        }
+        let tenants_path = self.tenants_path(image_ref);
+        let has_other_tenants = match tokio::fs::read_dir(&tenants_path).await {
+            Ok(mut entries) => entries.next_entry().await?.is_some(),
+            Err(_) => false,
+        };
+        if has_other_tenants || !self.pull_path(image_ref).exists() {
+            return Ok(false);
+        }
+        debug!(?image_ref, "No tenants reference image ref, removing it");
+        let layer_digest = self.layer_digest(image_ref).await;
+        tokio::fs::remove_dir_all(self.pull_path(image_ref)).await?;
+        if let Some(layer_digest) = layer_digest {
+            self.release_layer(&layer_digest, image_ref).await?;
+        }
+        Ok(true)
+    }
+
+    async fn collect_garbage(&mut self) -> anyhow::Result<u64> {
+        self.migrate_unescaped_image_dirs().await?;
+        self.migrate_legacy_modules().await?;
+        let root_dir = self.root_dir.clone();
+        tokio::task::spawn_blocking(move || collect_garbage_in(&root_dir)).await?
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+        let root_dir = self.root_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut modules = Vec::new();
+            if root_dir.exists() {
+                cached_modules_in(&root_dir, &root_dir, &mut modules)?;
+            }
+            Ok(modules)
+        })
+        .await?
+    }
+
+    async fn delete(&mut self, image_ref: &Reference) -> anyhow::Result<bool> {
+        if !self.pull_path(image_ref).exists() {
+            return Ok(false);
+        }
+        debug!(?image_ref, "Deleting image ref from disk");
+        let layer_digest = self.layer_digest(image_ref).await;
+        tokio::fs::remove_dir_all(self.pull_path(image_ref)).await?;
+        if let Some(layer_digest) = layer_digest {
+            self.release_layer(&layer_digest, image_ref).await?;
+        }
+        Ok(true)
+    }
+}
+
+/// The directory, relative to the root of the store, holding an image's records. Images pinned
+/// to a digest are kept in a directory named after the digest prefixed with `@`, which tags can't
+/// start with, rather than under their tag.
+fn image_dir(r: &Reference) -> PathBuf {
+    let mut path = PathBuf::from(registry_component(r.registry()));
+    for part in r.repository().split('/') {
+        path.push(path_component(part));
+    }
+    match r.digest() {
+        Some(digest) => path.push(path_component(&format!("{}{}", DIGEST_DIR_PREFIX, digest))),
+        None => path.push(path_component(r.tag().unwrap_or("latest"))),
+    }
+    path
+}
+
+/// Makes part of an image ref usable as a file name. Windows file names can't contain the `:`
+/// that separates a registry's port, and are compared case insensitively, which would make tags
+/// that differ only in case share a directory, so both are escaped there. Nothing needs escaping
+/// elsewhere.
+#[cfg(target_family = "windows")]
+fn path_component(part: &str) -> String {
+    let mut escaped = String::with_capacity(part.len());
+    for c in part.chars() {
+        if c.is_ascii_uppercase() || matches!(c, ':' | '%' | '<' | '>' | '"' | '|' | '?' | '*') {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(not(target_family = "windows"))]
+fn path_component(part: &str) -> String {
+    part.to_owned()
+}
+
+/// Makes a registry usable as a file name. Host names are case insensitive, so on Windows, where
+/// upper case letters are escaped, the registry is lower cased first so that it only ever gets
+/// one directory.
+#[cfg(target_family = "windows")]
+fn registry_component(registry: &str) -> String {
+    path_component(&registry.to_lowercase())
+}
+
+#[cfg(not(target_family = "windows"))]
+fn registry_component(registry: &str) -> String {
+    registry.to_owned()
+}
+
+/// Reverses [`path_component`]. Image refs can't contain `%`, so this leaves the names of
+/// directories that weren't escaped alone.
+fn from_path_component(component: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut rest = component.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The path of a layer with the given digest, e.g. `_layers/sha256/<hex>`.
+fn layer_path_in(root_dir: &Path, layer_digest: &str) -> PathBuf {
+    let (algorithm, hex) = layer_digest
+        .split_once(':')
+        .unwrap_or(("sha256", layer_digest));
+    root_dir.join(LAYERS_DIR).join(algorithm).join(hex)
+}
+
+/// The directory holding the references to a layer, one file per image.
+fn layer_refs_path_in(root_dir: &Path, layer_digest: &str) -> PathBuf {
+    let mut path = layer_path_in(root_dir, layer_digest).into_os_string();
+    path.push(".refs");
+    path.into()
+}
+
+/// Collects the digests of the layers of all images under `dir` that have a record for the
+/// tenant.
+fn tenant_layers_in(
+    root_dir: &Path,
+    dir: &Path,
+    tenant_id: &str,
+    layers: &mut HashSet<String>,
+) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() || path == root_dir.join(LAYERS_DIR) {
+            continue;
+        }
+        if path.file_name().map(|n| n == "tenants").unwrap_or(false) {
+            if path.join(tenant_id).exists() {
+                if let Some(parent) = path.parent() {
+                    if let Ok(digest) = std::fs::read_to_string(parent.join("layer.txt")) {
+                        layers.insert(digest.trim().to_owned());
+                    }
+                }
+            }
+        } else {
+            tenant_layers_in(root_dir, &path, tenant_id, layers)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the tenant records of all images under `dir`.
+fn tenant_records_in(
+    root_dir: &Path,
+    dir: &Path,
+    records: &mut Vec<TenantRecord>,
+) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() || path == root_dir.join(LAYERS_DIR) {
+            continue;
+        }
+        if path.file_name().map(|n| n == "tenants").unwrap_or(false) {
+            let image_ref = match path.parent().and_then(|p| image_ref_of(root_dir, p)) {
+                Some(image_ref) => image_ref,
+                None => continue,
+            };
+            for record in std::fs::read_dir(&path)? {
+                let record = record?.path();
+                let tenant = match record.file_name() {
+                    Some(name) => Tenant(name.to_string_lossy().into_owned()),
+                    None => continue,
+                };
+                let last_used = std::fs::read_to_string(&record)
+                    .ok()
+                    .and_then(|t| chrono::DateTime::parse_from_rfc3339(t.trim()).ok())
+                    .map(std::time::SystemTime::from)
+                    // Records made before they held a time were last written when last used
+                    .or_else(|| std::fs::metadata(&record).and_then(|m| m.modified()).ok())
+                    .unwrap_or_else(std::time::SystemTime::now);
+                records.push(TenantRecord {
+                    image_ref: image_ref.clone(),
+                    tenant,
+                    last_used,
+                });
+            }
+        } else {
+            tenant_records_in(root_dir, &path, records)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the modules of all images under `dir`.
+fn cached_modules_in(
+    root_dir: &Path,
+    dir: &Path,
+    modules: &mut Vec<CachedModule>,
+) -> anyhow::Result<()> {
+    let is_image_dir = match std::fs::read_to_string(dir.join("layer.txt")) {
+        Ok(layer_digest) => {
+            match image_ref_of(root_dir, dir) {
+                Some(image_ref) => modules.push(CachedModule {
+                    image_ref,
+                    digest: std::fs::read_to_string(dir.join("digest.txt")).ok(),
+                    size: std::fs::metadata(layer_path_in(root_dir, layer_digest.trim()))
+                        .map(|m| m.len())
+                        .unwrap_or(0),
+                }),
+                None => {
+                    debug!(path = %dir.display(), "Ignoring image directory that isn't a valid image ref")
+                }
+            }
+            true
+        }
+        Err(_) => false,
+    };
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_tenants_dir =
+            is_image_dir && path.file_name().map(|n| n == "tenants").unwrap_or(false);
+        if path.is_dir() && path != root_dir.join(LAYERS_DIR) && !is_tenants_dir {
+            cached_modules_in(root_dir, &path, modules)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the image refs of all images under `dir` whose module is stored in their own
+/// directory, as it was before layers were deduplicated.
+fn legacy_modules_in(
+    root_dir: &Path,
+    dir: &Path,
+    images: &mut Vec<Reference>,
+) -> anyhow::Result<()> {
+    if dir.join("module.wasm").is_file() {
+        match image_ref_of(root_dir, dir) {
+            Some(image_ref) => images.push(image_ref),
+            None => {
+                debug!(path = %dir.display(), "Ignoring legacy module that isn't in a valid image directory")
+            }
+        }
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_tenants_dir = path.file_name().map(|n| n == "tenants").unwrap_or(false);
+        if path.is_dir() && path != root_dir.join(LAYERS_DIR) && !is_tenants_dir {
+            legacy_modules_in(root_dir, &path, images)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the images under `dir` whose records are kept in a directory named after the parts of
+/// their ref as they are, rather than as [`image_dir`] escapes them, with the directory relative
+/// to the root of the store.
+fn unescaped_image_dirs_in(
+    root_dir: &Path,
+    dir: &Path,
+    images: &mut Vec<(PathBuf, Reference)>,
+) -> anyhow::Result<()> {
+    let is_image_dir = ["layer.txt", "module.wasm", "digest.txt"]
+        .iter()
+        .any(|record| dir.join(record).is_file());
+    if is_image_dir {
+        if let Some(image) = unescaped_image_of(root_dir, dir) {
+            images.push(image);
+        }
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_tenants_dir =
+            is_image_dir && path.file_name().map(|n| n == "tenants").unwrap_or(false);
+        if path.is_dir() && path != root_dir.join(LAYERS_DIR) && !is_tenants_dir {
+            unescaped_image_dirs_in(root_dir, &path, images)?;
+        }
+    }
+    Ok(())
+}
+
+/// The image whose records are in `dir` if the directory is named after the parts of its ref as
+/// they are, and isn't where [`image_dir`] keeps the image.
+fn unescaped_image_of(root_dir: &Path, dir: &Path) -> Option<(PathBuf, Reference)> {
+    let relative = dir.strip_prefix(root_dir).ok()?.to_owned();
+    let escaped = |r: &Reference| image_dir(r).to_string_lossy().to_lowercase();
+    // Directories that are already escaped, or that only differ in case from the escaped one and
+    // so are the same directory on a case insensitive file system, are where they belong
+    let current = relative.to_string_lossy().to_lowercase();
+    if image_ref_of(root_dir, dir).map(|r| escaped(&r)) == Some(current.clone()) {
+        return None;
+    }
+    let components: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let image_ref: Reference = match components.as_slice() {
+        [registry, repository @ .., tag] if !repository.is_empty() => {
+            format!("{}/{}:{}", registry, repository.join("/"), tag)
+                .parse()
+                .ok()?
+        }
+        _ => return None,
+    };
+    if escaped(&image_ref) == current {
+        return None;
+    }
+    Some((relative, image_ref))
+}
+
+/// The image ref whose records are in `dir`, reversing [`image_dir`].
+fn image_ref_of(root_dir: &Path, dir: &Path) -> Option<Reference> {
+    let components: Vec<String> = dir
+        .strip_prefix(root_dir)
+        .ok()?
+        .components()
+        .map(|c| from_path_component(&c.as_os_str().to_string_lossy()))
+        .collect::<Option<_>>()?;
+    match components.as_slice() {
+        [registry, repository @ .., last] if !repository.is_empty() => {
+            let repository = repository.join("/");
+            match last.strip_prefix(DIGEST_DIR_PREFIX) {
+                Some(digest) => format!("{}/{}@{}", registry, repository, digest),
+                None => format!("{}/{}:{}", registry, repository, last),
+            }
+            .parse()
+            .ok()
+        }
+        _ => None,
+    }
+}
+
+/// Removes stale references, and then any layers without references.
+fn collect_garbage_in(root_dir: &Path) -> anyhow::Result<u64> {
+    let layers_dir = root_dir.join(LAYERS_DIR);
+    if !layers_dir.exists() {
+        return Ok(0);
+    }
+    let mut freed = 0;
+    for algorithm in std::fs::read_dir(&layers_dir)? {
+        let algorithm = algorithm?;
+        let algorithm_name = algorithm.file_name().to_string_lossy().into_owned();
+        for layer in std::fs::read_dir(algorithm.path())? {
+            let layer_path = layer?.path();
+            if !layer_path.is_file() {
+                continue;
+            }
+            let hex = match layer_path.file_name() {
+                Some(n) => n.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let layer_digest = format!("{}:{}", algorithm_name, hex);
+            let refs_path = layer_refs_path_in(root_dir, &layer_digest);
+            let mut referenced = false;
+            if refs_path.exists() {
+                for reference in std::fs::read_dir(&refs_path)? {
+                    let reference = reference?.path();
+                    let image_dir = std::fs::read_to_string(&reference).unwrap_or_default();
+                    let in_use =
+                        std::fs::read_to_string(root_dir.join(image_dir).join("layer.txt"))
+                            .map(|d| d.trim() == layer_digest)
+                            .unwrap_or(false);
+                    if in_use {
+                        referenced = true;
+                    } else {
+                        std::fs::remove_file(&reference)?;
+                    }
+                }
+            }
+            if !referenced {
+                debug!(%layer_digest, "Collecting unreferenced layer");
+                freed += std::fs::metadata(&layer_path).map(|m| m.len()).unwrap_or(0);
+                std::fs::remove_file(&layer_path)?;
+                if refs_path.exists() {
+                    std::fs::remove_dir_all(&refs_path)?;
+                }
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// Computes the digest of a file without reading all of it into memory.
+async fn file_sha256_digest(path: &Path) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
 }
 
 impl<C: Client + Send> Clone for FileStore<C> {
@@ -112,6 +866,12 @@ impl<C: Client + Send> Clone for FileStore<C> {
         Self {
             storer: self.storer.clone(),
             client: self.client.clone(),
+            background_client: self.background_client.clone(),
+            scope: self.scope,
+            tenant_secret: self.tenant_secret.clone(),
+            pull_deadline: self.pull_deadline,
+            net: self.net.clone(),
+            activity: self.activity.clone(),
         }
     }
 }
@@ -131,7 +891,7 @@ mod test {
     use super::*;
     use crate::container::PullPolicy;
     use crate::store::Store;
-    use oci_distribution::client::{ImageData, ImageLayer};
+    use oci_distribution::client::{ImageData, ImageLayer, RateLimit};
     use oci_distribution::secrets::RegistryAuth;
     use std::collections::HashMap;
     use std::convert::TryFrom;
@@ -161,12 +921,14 @@ mod test {
     #[derive(Clone)]
     struct FakeImageClient {
         images: Arc<RwLock<HashMap<String, ImageData>>>,
+        rate_limit: Arc<RwLock<Option<RateLimit>>>,
     }
 
     impl FakeImageClient {
         fn new(entries: Vec<(&'static str, Vec<u8>, &'static str)>) -> Self {
             let client = FakeImageClient {
                 images: Default::default(),
+                rate_limit: Default::default(),
             };
             for (name, content, digest) in entries {
                 let mut images = client
@@ -214,6 +976,13 @@ mod test {
                 None => Err(anyhow::anyhow!("error pulling module")),
             }
         }
+
+        fn rate_limit(&self, _registry: &str) -> Option<RateLimit> {
+            self.rate_limit
+                .read()
+                .expect("should be able to read rate limit")
+                .clone()
+        }
     }
 
     struct TemporaryDirectory {
@@ -252,6 +1021,83 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn file_module_store_pre_pulls_with_background_client() -> anyhow::Result<()> {
+        let pod_client = FakeImageClient::new(vec![]);
+        let background_client =
+            FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store =
+            FileStore::new(pod_client, &scratch_dir.path).with_background_client(background_client);
+        store.pre_pull(&fake_ref, &RegistryAuth::Anonymous).await?;
+        assert!(!store.is_busy(), "pre-pulls should not make the store busy");
+
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(3, module_bytes.len());
+        assert!(store.is_busy(), "pod pulls should make the store busy");
+        Ok(())
+    }
+
+    /// Streams a module a byte at a time, so that concurrent pulls interleave their writes.
+    struct TricklingImageClient(Vec<u8>);
+
+    #[async_trait]
+    impl Client for TricklingImageClient {
+        async fn pull(
+            &mut self,
+            _image_ref: &Reference,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<ImageData> {
+            Ok(ImageData {
+                layers: vec![ImageLayer::oci_v1(self.0.clone())],
+                digest: Some("sha256:123".to_owned()),
+            })
+        }
+
+        async fn pull_into(
+            &mut self,
+            _image_ref: &Reference,
+            _auth: &RegistryAuth,
+            out: &mut (dyn tokio::io::AsyncWrite + Unpin + Send),
+        ) -> anyhow::Result<Option<String>> {
+            use tokio::io::AsyncWriteExt;
+            for byte in &self.0 {
+                out.write_all(&[*byte]).await?;
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            out.flush().await?;
+            Ok(Some("sha256:123".to_owned()))
+        }
+    }
+
+    #[tokio::test]
+    async fn file_module_store_pre_pulls_and_pulls_the_same_module_at_once() -> anyhow::Result<()> {
+        let module: Vec<u8> = (0..64).collect();
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(TricklingImageClient(module.clone()), &scratch_dir.path)
+            .with_background_client(TricklingImageClient(module.clone()));
+        let (pre_pulled, pulled) = tokio::join!(
+            store.pre_pull(&fake_ref, &RegistryAuth::Anonymous),
+            store.get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous
+            ),
+        );
+        pre_pulled?;
+        assert_eq!(module, pulled?);
+
+        let storer = FileStorer::new(&scratch_dir.path);
+        assert_eq!(module, storer.get_local(&fake_ref).await?);
+        assert_eq!(0, staged_modules(&storer.pull_path(&fake_ref)));
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_can_pull_if_policy_always() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
@@ -434,4 +1280,518 @@ mod test {
         assert_eq!(6, module_bytes_after[1]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn file_module_store_hides_modules_from_other_namespaces_if_policy_never(
+    ) -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store =
+            FileStore::new(fake_client, &scratch_dir.path).with_scope(StoreScope::Namespace);
+        store
+            .get_for_namespace(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                "tenant-a",
+            )
+            .await?;
+        let other_tenant = store
+            .get_for_namespace(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryAuth::Anonymous,
+                "tenant-b",
+            )
+            .await;
+        assert!(
+            other_tenant.is_err(),
+            "expected module pulled by another namespace to be hidden"
+        );
+        let same_tenant = store
+            .get_for_namespace(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryAuth::Anonymous,
+                "tenant-a",
+            )
+            .await?;
+        assert_eq!(3, same_tenant.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_verifies_access_for_new_tenants() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path)
+            .with_scope(StoreScope::Namespace);
+        store
+            .get_for_namespace(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                "tenant-a",
+            )
+            .await?;
+        // The registry no longer has the image, so a new tenant can't verify its access
+        fake_client.images.write().unwrap().clear();
+        let other_tenant = store
+            .get_for_namespace(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                "tenant-b",
+            )
+            .await;
+        assert!(
+            other_tenant.is_err(),
+            "expected new tenant to need registry access"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_accounts_and_collects_per_tenant() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store =
+            FileStore::new(fake_client, &scratch_dir.path).with_scope(StoreScope::Namespace);
+        for namespace in &["tenant-a", "tenant-b"] {
+            store
+                .get_for_namespace(
+                    &fake_ref,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                    namespace,
+                )
+                .await?;
+        }
+        let tenant_a = Tenant::new(
+            StoreScope::Namespace,
+            "tenant-a",
+            &RegistryAuth::Anonymous,
+            &[],
+        )
+        .expect("namespace scope should have a tenant");
+        let tenant_b = Tenant::new(
+            StoreScope::Namespace,
+            "tenant-b",
+            &RegistryAuth::Anonymous,
+            &[],
+        )
+        .expect("namespace scope should have a tenant");
+        assert_eq!(3, store.tenant_usage(&tenant_a).await?);
+        assert!(!store.release(&fake_ref, &tenant_a).await?);
+        assert_eq!(0, store.tenant_usage(&tenant_a).await?);
+        assert_eq!(3, store.tenant_usage(&tenant_b).await?);
+        assert!(store.release(&fake_ref, &tenant_b).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_lists_and_deletes_modules() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:2.0", vec![4, 5], "sha256:45"),
+        ]);
+        let bar_ref = Reference::try_from("foo/bar:1.0")?;
+        let baz_ref = Reference::try_from("foo/baz:2.0")?;
+        let scratch_dir = create_temp_dir();
+        let store =
+            FileStore::new(fake_client, &scratch_dir.path).with_scope(StoreScope::Namespace);
+        store
+            .get_for_namespace(
+                &bar_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+                "tenant-a",
+            )
+            .await?;
+        store
+            .get(&baz_ref, PullPolicy::IfNotPresent, &RegistryAuth::Anonymous)
+            .await?;
+
+        let mut modules = store.list().await?;
+        modules.sort_by_key(|m| m.image_ref.whole());
+        let listed: Vec<(String, Option<String>, u64)> = modules
+            .into_iter()
+            .map(|m| (m.image_ref.whole(), m.digest, m.size))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (bar_ref.whole(), Some("sha256:123".to_owned()), 3),
+                (baz_ref.whole(), Some("sha256:45".to_owned()), 2),
+            ]
+        );
+
+        // Deleting ignores which tenants have pulled the module
+        assert!(store.delete(&bar_ref).await?);
+        assert!(!store.delete(&bar_ref).await?);
+        let listed: Vec<String> = store
+            .list()
+            .await?
+            .iter()
+            .map(|m| m.image_ref.whole())
+            .collect();
+        assert_eq!(listed, vec![baz_ref.whole()]);
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_defers_updates_if_rate_limited() -> anyhow::Result<()> {
+        let mut fake_client =
+            FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        fake_client.update("foo/bar:1.0", vec![4, 5, 6, 7], "sha256:4567");
+        *fake_client.rate_limit.write().unwrap() = Some(RateLimit {
+            limit: 100,
+            remaining: 2,
+            window: None,
+        });
+        let module_bytes_after = store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(3, module_bytes_after.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_cleans_up_failed_pulls() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let missing_ref = Reference::try_from("foo/baz:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        let module_bytes = store
+            .get(
+                &missing_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await;
+        assert!(module_bytes.is_err());
+
+        let storer = FileStorer::new(&scratch_dir.path);
+        assert!(!storer.is_present(&missing_ref).await);
+        assert_eq!(0, staged_modules(&storer.pull_path(&missing_ref)));
+        Ok(())
+    }
+
+    fn staged_modules(pull_path: &Path) -> usize {
+        std::fs::read_dir(pull_path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|e| e.file_name().to_string_lossy().ends_with(".partial"))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    fn stored_layers(root_dir: &Path) -> usize {
+        std::fs::read_dir(root_dir.join(LAYERS_DIR).join("sha256"))
+            .map(|entries| {
+                entries
+                    .filter(|e| e.as_ref().map(|e| e.path().is_file()).unwrap_or(false))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn file_module_store_shares_layers_between_images() -> anyhow::Result<()> {
+        let mut fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:1.0", vec![1, 2, 3], "sha256:123"),
+        ]);
+        let bar_ref = Reference::try_from("foo/bar:1.0")?;
+        let baz_ref = Reference::try_from("foo/baz:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path)
+            .with_scope(StoreScope::Namespace);
+        for image_ref in &[&bar_ref, &baz_ref] {
+            store
+                .get_for_namespace(
+                    image_ref,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                    "tenant-a",
+                )
+                .await?;
+        }
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        let tenant = Tenant::new(
+            StoreScope::Namespace,
+            "tenant-a",
+            &RegistryAuth::Anonymous,
+            &[],
+        )
+        .expect("namespace scope should have a tenant");
+        // Shared layers only count once towards a tenant's usage
+        assert_eq!(3, store.tenant_usage(&tenant).await?);
+
+        // Updating one image keeps the layer the other still uses
+        fake_client.update("foo/bar:1.0", vec![4, 5, 6, 7], "sha256:4567");
+        store
+            .get_for_namespace(
+                &bar_ref,
+                PullPolicy::Always,
+                &RegistryAuth::Anonymous,
+                "tenant-a",
+            )
+            .await?;
+        assert_eq!(2, stored_layers(&scratch_dir.path));
+        assert_eq!(0, store.collect_garbage().await?);
+
+        assert!(store.release(&baz_ref, &tenant).await?);
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        assert!(store.release(&bar_ref, &tenant).await?);
+        assert_eq!(0, stored_layers(&scratch_dir.path));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_releases_modules_tenants_stopped_using() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:1.0", vec![4, 5], "sha256:45"),
+        ]);
+        let bar_ref = Reference::try_from("foo/bar:1.0")?;
+        let baz_ref = Reference::try_from("foo/baz:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store =
+            FileStore::new(fake_client, &scratch_dir.path).with_scope(StoreScope::Namespace);
+        for image_ref in &[&bar_ref, &baz_ref] {
+            store
+                .get_for_namespace(
+                    image_ref,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                    "tenant-a",
+                )
+                .await?;
+        }
+        let tenant = Tenant::new(
+            StoreScope::Namespace,
+            "tenant-a",
+            &RegistryAuth::Anonymous,
+            &[],
+        )
+        .expect("namespace scope should have a tenant");
+        let storer = FileStorer::new(&scratch_dir.path);
+        std::fs::write(
+            storer.tenant_file_path(&bar_ref, &tenant),
+            (chrono::Utc::now() - chrono::Duration::days(30)).to_rfc3339(),
+        )?;
+
+        store.collect_garbage().await?;
+        assert!(!storer.is_present(&bar_ref).await);
+        assert!(storer.is_present(&baz_ref).await);
+        assert_eq!(2, store.tenant_usage(&tenant).await?);
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        Ok(())
+    }
+
+    #[test]
+    fn file_module_store_keys_credential_tenants_with_its_secret() {
+        let scratch_dir = create_temp_dir();
+        let secret = load_tenant_secret(&scratch_dir.path);
+        assert_eq!(secret, load_tenant_secret(&scratch_dir.path));
+        let other_secret = load_tenant_secret(&create_temp_dir().path);
+        assert_ne!(secret, other_secret);
+
+        let auth = RegistryAuth::Basic("user".to_owned(), "password".to_owned());
+        let tenant = Tenant::new(StoreScope::Credential, "", &auth, &secret).unwrap();
+        assert_eq!(
+            tenant,
+            Tenant::new(StoreScope::Credential, "other", &auth, &secret).unwrap()
+        );
+        assert_ne!(
+            tenant,
+            Tenant::new(StoreScope::Credential, "", &auth, &other_secret).unwrap()
+        );
+        let unsalted = format!(
+            "credential-{:x}",
+            sha2::Sha256::digest(b"basic:user:password")
+        );
+        assert_ne!(unsalted, tenant.id());
+    }
+
+    #[tokio::test]
+    async fn file_module_store_collects_unreferenced_layers() -> anyhow::Result<()> {
+        let scratch_dir = create_temp_dir();
+        let orphan = layer_path_in(&scratch_dir.path, "sha256:abcd");
+        std::fs::create_dir_all(orphan.parent().unwrap())?;
+        std::fs::write(&orphan, vec![1, 2, 3])?;
+
+        let store = FileStore::new(FakeImageClient::new(vec![]), &scratch_dir.path);
+        assert_eq!(3, store.collect_garbage().await?);
+        assert!(!orphan.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_migrates_legacy_modules() -> anyhow::Result<()> {
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let legacy_dir = scratch_dir.path.join(image_dir(&fake_ref));
+        std::fs::create_dir_all(&legacy_dir)?;
+        std::fs::write(legacy_dir.join("module.wasm"), vec![1, 2, 3])?;
+        std::fs::write(legacy_dir.join("digest.txt"), "sha256:123")?;
+
+        let store = FileStore::new(FakeImageClient::new(vec![]), &scratch_dir.path);
+        // Modules cached by earlier versions can be used before they are migrated
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+
+        assert_eq!(0, store.collect_garbage().await?);
+        assert!(!legacy_dir.join("module.wasm").exists());
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        let storer = FileStorer::new(&scratch_dir.path);
+        assert!(
+            storer
+                .is_present_with_digest(&fake_ref, "sha256:123".to_owned())
+                .await
+        );
+        assert_eq!(
+            Some("sha256:123".to_owned()),
+            store.digest(&fake_ref).await?
+        );
+        let modules = store.list().await?;
+        assert_eq!(1, modules.len());
+        assert_eq!(fake_ref.whole(), modules[0].image_ref.whole());
+        Ok(())
+    }
+
+    struct StalledImageClient;
+
+    #[async_trait]
+    impl Client for StalledImageClient {
+        async fn pull(
+            &mut self,
+            _image_ref: &Reference,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<ImageData> {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            Err(anyhow::anyhow!("pull should have been abandoned"))
+        }
+    }
+
+    #[tokio::test]
+    async fn file_module_store_abandons_pulls_past_deadline() -> anyhow::Result<()> {
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(StalledImageClient, &scratch_dir.path)
+            .with_pull_deadline(std::time::Duration::from_millis(10));
+        let err = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .expect_err("pull should time out");
+        assert_eq!(
+            crate::store::PullErrorKind::Timeout,
+            crate::store::PullErrorKind::classify(&err)
+        );
+        let storer = FileStorer::new(&scratch_dir.path);
+        assert_eq!(0, staged_modules(&storer.pull_path(&fake_ref)));
+        Ok(())
+    }
+
+    #[test]
+    fn file_module_store_maps_image_dirs_back_to_refs() {
+        let root = Path::new("store");
+        let image_ref = Reference::try_from("localhost:5000/team/app:v1.0").unwrap();
+        let found = image_ref_of(root, &root.join(image_dir(&image_ref))).unwrap();
+        assert_eq!(image_ref.whole(), found.whole());
+
+        let digest = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let pinned = Reference::try_from(format!("localhost:5000/team/app@{}", digest)).unwrap();
+        assert_ne!(
+            image_dir(&pinned),
+            image_dir(&Reference::try_from("localhost:5000/team/app:latest").unwrap())
+        );
+        let found = image_ref_of(root, &root.join(image_dir(&pinned))).unwrap();
+        assert_eq!(found.digest(), Some(digest));
+        assert_eq!(pinned.whole(), found.whole());
+    }
+
+    #[tokio::test]
+    async fn file_module_store_leaves_escaped_image_dirs_alone() -> anyhow::Result<()> {
+        let scratch_dir = create_temp_dir();
+        let image_ref = Reference::try_from("localhost:5000/team/app:v1.0")?;
+        let digest_ref = Reference::try_from(
+            "localhost:5000/team/app@sha256:0000000000000000000000000000000000000000000000000000000000000000",
+        )?;
+        for r in &[&image_ref, &digest_ref] {
+            let dir = scratch_dir.path.join(image_dir(r));
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("digest.txt"), "sha256:123")?;
+        }
+
+        let mut storer = FileStorer::new(&scratch_dir.path);
+        storer.migrate_unescaped_image_dirs().await?;
+        assert!(scratch_dir.path.join(image_dir(&image_ref)).exists());
+        assert!(scratch_dir.path.join(image_dir(&digest_ref)).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(target_family = "windows")]
+    async fn file_module_store_migrates_unescaped_image_dirs_on_windows() -> anyhow::Result<()> {
+        let image_ref = Reference::try_from("localhost/app:V1")?;
+        let scratch_dir = create_temp_dir();
+        let old_dir = scratch_dir.path.join("localhost").join("app").join("V1");
+        std::fs::create_dir_all(&old_dir)?;
+        std::fs::write(old_dir.join("module.wasm"), vec![1, 2, 3])?;
+
+        let store = FileStore::new(FakeImageClient::new(vec![]), &scratch_dir.path);
+        store.collect_garbage().await?;
+        assert!(!old_dir.exists());
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        let module_bytes = store
+            .get(&image_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        // The layer is still referenced from where the image is now
+        store.collect_garbage().await?;
+        assert_eq!(1, stored_layers(&scratch_dir.path));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_family = "windows")]
+    fn file_module_store_escapes_image_dirs_on_windows() {
+        let upper = Reference::try_from("Localhost:5000/app:V1").unwrap();
+        let lower = Reference::try_from("localhost:5000/app:v1").unwrap();
+        let upper_dir = image_dir(&upper).to_string_lossy().into_owned();
+        let lower_dir = image_dir(&lower).to_string_lossy().into_owned();
+        assert!(!upper_dir.contains(':'));
+        // Tags are case sensitive, and must not share a directory on a case insensitive file
+        // system
+        assert_ne!(upper_dir.to_lowercase(), lower_dir.to_lowercase());
+        assert!(upper_dir.starts_with("localhost%3A5000"));
+
+        let root = Path::new("C:\\store");
+        let found = image_ref_of(root, &root.join(image_dir(&upper))).unwrap();
+        assert_eq!("localhost:5000/app:V1", found.whole());
+    }
 }
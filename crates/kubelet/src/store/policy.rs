@@ -0,0 +1,140 @@
+//! A node-level policy on where container images may come from.
+//!
+//! Operators can limit the images pods on the node may run to those from a list of registries
+//! (such as `webassembly.azurecr.io`) or repository prefixes within one (such as
+//! `ghcr.io/my-org`), and can require every image to be pinned by digest. Pods referencing images
+//! outside the policy are rejected when they are registered, before anything is pulled.
+use oci_distribution::Reference;
+
+use crate::config::Config;
+use crate::pod::Pod;
+
+/// Where the images pods on the node run may come from.
+#[derive(Clone, Debug, Default)]
+pub struct RegistryPolicy {
+    allowed: Vec<String>,
+    require_digest: bool,
+}
+
+impl RegistryPolicy {
+    /// Creates a policy allowing images from the given registries or repository prefixes, or
+    /// from anywhere if there are none, and only images pinned by digest if `require_digest`
+    /// is set.
+    pub fn new(allowed_registries: Vec<String>, require_digest: bool) -> Self {
+        RegistryPolicy {
+            allowed: allowed_registries
+                .into_iter()
+                .map(|p| p.trim().trim_end_matches('/').to_owned())
+                .filter(|p| !p.is_empty())
+                .collect(),
+            require_digest,
+        }
+    }
+
+    /// Creates the policy set in the Kubelet's configuration, if it sets one.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.allowed_registries.is_empty() && !config.require_image_digests {
+            return None;
+        }
+        Some(RegistryPolicy::new(
+            config.allowed_registries.clone(),
+            config.require_image_digests,
+        ))
+    }
+
+    /// Checks that every container of the pod runs an image the policy allows, returning a
+    /// description of every image that isn't if it doesn't.
+    pub fn admit(&self, pod: &Pod) -> anyhow::Result<()> {
+        let mut violations = Vec::new();
+        for container in pod.all_containers() {
+            let violation = match container.image() {
+                Ok(Some(image)) => self.check(&image).err(),
+                Ok(None) => None,
+                Err(e) => Some(format!("has an invalid image reference: {}", e)),
+            };
+            if let Some(violation) = violation {
+                violations.push(format!("container {} {}", container.name(), violation));
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Pod violates the node's image policy: {}",
+                violations.join("; ")
+            ))
+        }
+    }
+
+    /// Checks a single image against the policy.
+    pub fn check(&self, image: &Reference) -> Result<(), String> {
+        if !self.allowed.is_empty() {
+            let name = format!("{}/{}", image.registry(), image.repository());
+            if !self.allowed.iter().any(|prefix| within(&name, prefix)) {
+                return Err(format!(
+                    "uses image {}, which is not from an allowed registry ({})",
+                    image.whole(),
+                    self.allowed.join(", ")
+                ));
+            }
+        }
+        if self.require_digest && image.digest().is_none() {
+            return Err(format!(
+                "uses image {}, which is not pinned by digest",
+                image.whole()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether an image name is the prefix or within it, only matching whole path components so
+/// that `ghcr.io/org` doesn't allow `ghcr.io/organization`.
+fn within(name: &str, prefix: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn reference(image: &str) -> Reference {
+        Reference::try_from(image).unwrap()
+    }
+
+    #[test]
+    fn test_allowed_registries() {
+        let policy = RegistryPolicy::new(
+            vec![
+                "webassembly.azurecr.io".to_owned(),
+                "ghcr.io/org/".to_owned(),
+            ],
+            false,
+        );
+        assert!(policy
+            .check(&reference("webassembly.azurecr.io/hello-wasm:v1"))
+            .is_ok());
+        assert!(policy.check(&reference("ghcr.io/org/app:1.0")).is_ok());
+        assert!(policy
+            .check(&reference("ghcr.io/organization/app:1.0"))
+            .is_err());
+        assert!(policy
+            .check(&reference("docker.io/library/app:1.0"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_require_digest() {
+        let policy = RegistryPolicy::new(vec![], true);
+        assert!(policy
+            .check(&reference(
+                "ghcr.io/org/app@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+            ))
+            .is_ok());
+        assert!(policy.check(&reference("ghcr.io/org/app:1.0")).is_err());
+    }
+}
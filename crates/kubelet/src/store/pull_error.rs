@@ -0,0 +1,141 @@
+//! Classification of the errors that can occur when pulling a module.
+use oci_distribution::errors::{ClientError, OciError, OciErrorCode};
+use serde::{Deserialize, Serialize};
+
+/// The class of an image pull failure. This determines the waiting reason reported in container
+/// statuses and whether the pull is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PullErrorKind {
+    /// The registry rejected the credentials, or no credentials were provided
+    Unauthorized,
+    /// The image or repository doesn't exist
+    NotFound,
+    /// The registry rate limited the request
+    RateLimited,
+    /// The registry did not respond in time
+    Timeout,
+    /// The pulled content did not match its digest
+    DigestMismatch,
+    /// The image (or one of its layers) has a media type that can't be run
+    UnsupportedMediaType,
+    /// Any other failure
+    Other,
+}
+
+impl PullErrorKind {
+    /// Determines the class of an error returned by a [`Store`](crate::store::Store).
+    pub fn classify(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|cause| {
+                if let Some(e) = cause.downcast_ref::<OciError>() {
+                    Some(Self::from_oci_code(&e.code))
+                } else if let Some(e) = cause.downcast_ref::<ClientError>() {
+                    Some(match e {
+                        ClientError::AuthenticationFailed(_) => PullErrorKind::Unauthorized,
+                        ClientError::HttpStatus { status, .. } => Self::from_status(*status),
+                        ClientError::UnsupportedMediaType(_)
+                        | ClientError::IncompatibleLayerMediaType(_) => {
+                            PullErrorKind::UnsupportedMediaType
+                        }
+                        ClientError::DigestMismatch { .. } => PullErrorKind::DigestMismatch,
+                    })
+                } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                    if e.is_timeout() {
+                        Some(PullErrorKind::Timeout)
+                    } else {
+                        e.status().map(|s| Self::from_status(s.as_u16()))
+                    }
+                } else if cause.is::<tokio::time::error::Elapsed>() {
+                    Some(PullErrorKind::Timeout)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(PullErrorKind::Other)
+    }
+
+    fn from_oci_code(code: &OciErrorCode) -> Self {
+        match code {
+            OciErrorCode::Unauthorized | OciErrorCode::Denied => PullErrorKind::Unauthorized,
+            OciErrorCode::BlobUnknown
+            | OciErrorCode::ManifestBlobUnknown
+            | OciErrorCode::ManifestUnknown
+            | OciErrorCode::NameUnknown => PullErrorKind::NotFound,
+            OciErrorCode::Toomanyrequests => PullErrorKind::RateLimited,
+            OciErrorCode::DigestInvalid | OciErrorCode::SizeInvalid => {
+                PullErrorKind::DigestMismatch
+            }
+            OciErrorCode::Unsupported => PullErrorKind::UnsupportedMediaType,
+            _ => PullErrorKind::Other,
+        }
+    }
+
+    fn from_status(status: u16) -> Self {
+        match status {
+            401 | 403 => PullErrorKind::Unauthorized,
+            404 => PullErrorKind::NotFound,
+            408 | 504 => PullErrorKind::Timeout,
+            415 => PullErrorKind::UnsupportedMediaType,
+            429 => PullErrorKind::RateLimited,
+            _ => PullErrorKind::Other,
+        }
+    }
+
+    /// The reason reported for containers that are waiting on a pull that failed this way.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PullErrorKind::Unauthorized => "ErrImagePullUnauthorized",
+            PullErrorKind::NotFound => "ErrImageNotFound",
+            PullErrorKind::RateLimited => "ErrImagePullRateLimited",
+            PullErrorKind::Timeout => "ErrImagePullTimeout",
+            PullErrorKind::DigestMismatch => "ErrImageDigestMismatch",
+            PullErrorKind::UnsupportedMediaType => "ErrImageUnsupportedMediaType",
+            PullErrorKind::Other => "ErrImagePull",
+        }
+    }
+
+    /// Whether retrying the pull could succeed. Images that don't exist or can never be run
+    /// are not retried.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            PullErrorKind::NotFound | PullErrorKind::UnsupportedMediaType
+        )
+    }
+}
+
+impl std::fmt::Display for PullErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify_errors() {
+        let not_found: anyhow::Error = ClientError::HttpStatus {
+            status: 404,
+            url: "https://example.com/v2/".to_owned(),
+        }
+        .into();
+        assert_eq!(PullErrorKind::NotFound, PullErrorKind::classify(&not_found));
+        assert!(!PullErrorKind::NotFound.is_retryable());
+
+        let rate_limited = anyhow::Error::new(ClientError::HttpStatus {
+            status: 429,
+            url: "https://example.com/v2/".to_owned(),
+        })
+        .context("Unable to pull module");
+        assert_eq!(
+            PullErrorKind::RateLimited,
+            PullErrorKind::classify(&rate_limited)
+        );
+        assert!(PullErrorKind::RateLimited.is_retryable());
+
+        let other = anyhow::anyhow!("something went wrong");
+        assert_eq!(PullErrorKind::Other, PullErrorKind::classify(&other));
+    }
+}
@@ -0,0 +1,621 @@
+//! Support bundles, which gather what is needed to debug a node into a single tarball.
+//!
+//! A bundle holds the Kubelet's configuration, the most recent lines of its logs, snapshots of
+//! the node and its pods from the API server, the pod intent log (the work the Kubelet started
+//! on each pod), the inventory of the module store, and the metrics, disk usage, runtime handles
+//! and recent state machine transitions of every pod reported by the running Kubelet's server. Anything that can't be gathered,
+//! for example because the Kubelet isn't running, is noted in `errors.txt` rather than failing
+//! the bundle, since bundles are most needed when something is broken.
+//!
+//! Values that may be secret, such as environment variables and arguments of containers and
+//! anything that looks like a credential, in manifests and log lines alike, are replaced with
+//! [`REDACTED`]. The contents of credential files (such as
+//! the TLS private key or the admin token) are never included, only their paths.
+use std::convert::TryFrom;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use k8s_openapi::api::core::v1::{Event as KubeEvent, Node as KubeNode, Pod as KubePod};
+use kube::api::{Api, ListParams};
+use serde_json::Value;
+use tracing::info;
+
+use crate::config::Config;
+use crate::pod::INTENT_LOG_FILE;
+use crate::store::Store;
+
+/// The value that replaces anything redacted from a bundle
+pub const REDACTED: &str = "<redacted>";
+
+/// The directory every file of a bundle is put in
+const BUNDLE_DIR: &str = "krustlet-support-bundle";
+
+/// How long to wait for the Kubelet's server to answer
+const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fields whose values are redacted wherever they appear, matched against lowercased field names
+const SECRET_FIELDS: &[&str] = &["password", "token", "secret", "credential", "auth"];
+
+/// Fields of containers whose values are redacted as they may hold secrets passed on the command
+/// line
+const COMMAND_LINE_FIELDS: &[&str] = &["args", "command"];
+
+/// Words in log lines that are followed by a credential
+const CREDENTIAL_SCHEMES: &[&str] = &["bearer", "basic"];
+
+/// The annotation kubectl stores the whole last applied manifest in, environment and all
+const LAST_APPLIED_ANNOTATION: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+/// What to put in a support bundle, besides what is always gathered.
+#[derive(Clone, Debug)]
+pub struct BundleOptions {
+    /// Where to write the tarball
+    pub output: PathBuf,
+    /// Files the Kubelet logs to
+    pub log_files: Vec<PathBuf>,
+    /// How many of the most recent lines of each log file to include
+    pub log_lines: usize,
+}
+
+/// Gathers a support bundle for the node the config is for, listing the modules cached in the
+/// given store, and writes it to the output of the options.
+pub async fn create(
+    config: &Config,
+    store: Option<Arc<dyn Store + Send + Sync>>,
+    options: &BundleOptions,
+) -> anyhow::Result<()> {
+    let mut bundle = Bundle::default();
+
+    let mut config_json = config_json(config);
+    redact(&mut config_json);
+    bundle.add_json("config.json", &config_json);
+
+    for log_file in &options.log_files {
+        let name = log_file
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "krustlet.log".to_owned());
+        match tail(log_file, options.log_lines).await {
+            Ok(lines) => bundle.add(&format!("logs/{}", name), lines.into_bytes()),
+            Err(e) => bundle.error(&format!("logs/{}", name), e),
+        }
+    }
+
+    match intent_log(&config.data_dir).await {
+        Ok(Some(log)) => bundle.add(INTENT_LOG_FILE, log.into_bytes()),
+        Ok(None) => (),
+        Err(e) => bundle.error(INTENT_LOG_FILE, e),
+    }
+
+    match store {
+        Some(store) => match store.list().await {
+            Ok(modules) => {
+                let modules: Vec<Value> = modules
+                    .iter()
+                    .map(|m| {
+                        serde_json::json!({
+                            "image": m.image_ref.whole(),
+                            "digest": m.digest,
+                            "size": m.size,
+                        })
+                    })
+                    .collect();
+                bundle.add_json("store/images.json", &Value::from(modules));
+            }
+            Err(e) => bundle.error("store/images.json", e),
+        },
+        None => bundle.error(
+            "store/images.json",
+            anyhow::anyhow!("the provider has no module store"),
+        ),
+    }
+
+    match crate::kubeconfig::load(config.kube_context.as_deref()).await {
+        Ok(kubeconfig) => match kube::Client::try_from(kubeconfig) {
+            Ok(client) => add_cluster_snapshots(&mut bundle, &client, &config.node_name).await,
+            Err(e) => bundle.error("cluster", e.into()),
+        },
+        Err(e) => bundle.error("cluster", e),
+    }
+
+    add_server_reports(&mut bundle, config).await;
+
+    let output = options.output.clone();
+    let count = bundle.files.len();
+    tokio::task::spawn_blocking(move || bundle.write(&output)).await??;
+    info!(output = %options.output.display(), files = count, "Wrote support bundle");
+    Ok(())
+}
+
+/// Adds the node, the pods scheduled to it and the node's events, as the API server has them.
+async fn add_cluster_snapshots(bundle: &mut Bundle, client: &kube::Client, node_name: &str) {
+    let nodes: Api<KubeNode> = Api::all(client.clone());
+    match nodes.get(node_name).await {
+        Ok(node) => bundle.add_object("cluster/node.json", &node),
+        Err(e) => bundle.error("cluster/node.json", e.into()),
+    }
+
+    let pods: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    match pods.list(&params).await {
+        Ok(list) => bundle.add_object("cluster/pods.json", &list.items),
+        Err(e) => bundle.error("cluster/pods.json", e.into()),
+    }
+
+    let events: Api<KubeEvent> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!(
+        "involvedObject.kind=Node,involvedObject.name={}",
+        node_name
+    ));
+    match events.list(&params).await {
+        Ok(list) => bundle.add_object("cluster/node-events.json", &list.items),
+        Err(e) => bundle.error("cluster/node-events.json", e.into()),
+    }
+}
+
+/// Adds what the running Kubelet's server reports: its metrics, the disk usage of its pods and,
+/// if the admin endpoints are enabled, its runtime handles.
+async fn add_server_reports(bundle: &mut Bundle, config: &Config) {
+    let client = match reqwest::Client::builder()
+        // The bundle is gathered on the node itself, from a server that may well be using a
+        // self-signed certificate
+        .danger_accept_invalid_certs(true)
+        .timeout(SERVER_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => return bundle.error("server", e.into()),
+    };
+    let addr = match config.server_config.addr {
+        IpAddr::V4(addr) if addr.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(addr) if addr.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        addr => addr,
+    };
+    let base = match addr {
+        IpAddr::V4(addr) => format!("https://{}:{}", addr, config.server_config.port),
+        IpAddr::V6(addr) => format!("https://[{}]:{}", addr, config.server_config.port),
+    };
+    let admin_token = match &config.server_config.admin_token_file {
+        Some(path) => match crate::webserver::read_admin_token(path) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                bundle.error("server/runtime.json", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut reports = vec![
+        ("metrics", "server/metrics.txt", None),
+        ("stats/summary", "server/stats-summary.json", None),
+    ];
+    if let Some(token) = &admin_token {
+        reports.push(("debug/runtime", "server/runtime.json", Some(token)));
+        reports.push(("debug/states", "server/state-histories.json", Some(token)));
+    }
+    for (path, name, token) in reports {
+        let mut request = client.get(&format!("{}/{}", base, path));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.and_then(|r| r.error_for_status());
+        match response {
+            Ok(response) => match response.bytes().await {
+                Ok(body) => bundle.add(name, body.to_vec()),
+                Err(e) => bundle.error(name, e.into()),
+            },
+            Err(e) => bundle.error(name, e.into()),
+        }
+    }
+}
+
+/// The configuration as JSON, using the field names of the config file.
+fn config_json(config: &Config) -> Value {
+    serde_json::json!({
+        "nodeIP": config.node_ip.to_string(),
+        "hostname": config.hostname,
+        "nodeName": config.node_name,
+        "nodeLabels": config.node_labels,
+        "address": config.server_config.addr.to_string(),
+        "port": config.server_config.port,
+        "tlsCertificateFile": config.server_config.cert_file,
+        "tlsPrivateKeyFile": config.server_config.private_key_file,
+        "adminTokenFile": config.server_config.admin_token_file,
+        "dataDir": config.data_dir,
+        "maxPods": config.max_pods,
+        "bootstrapFile": config.bootstrap_file,
+        "bootstrapPlugin": config.bootstrap_plugin,
+        "allowLocalModules": config.allow_local_modules,
+        "insecureRegistries": config.insecure_registries,
+        "pluginsDir": config.plugins_dir,
+        "devicePluginsDir": config.device_plugins_dir,
+        "shutdownGracePeriod": format!("{:?}", config.shutdown_grace_period),
+        "allowedPodOverrides": config.allowed_pod_overrides,
+        "storeScope": format!("{:?}", config.store_scope).to_lowercase(),
+        "prePullImages": config.pre_pull_images,
+        "prePullInterval": format!("{:?}", config.pre_pull_interval),
+        "prePullSecrets": config.pre_pull_secrets,
+        "registryConnectTimeout": format!("{:?}", config.registry_connect_timeout),
+        "registryReadTimeout": format!("{:?}", config.registry_read_timeout),
+        "imagePullDeadline": format!("{:?}", config.image_pull_deadline),
+        "podCIDR": config.pod_cidr,
+        "kubeContext": config.kube_context,
+        "slowApiCallThreshold": format!("{:?}", config.slow_api_call_threshold),
+        "podLogDir": config.pod_log_dir,
+        "criContainerLogs": config.cri_container_logs,
+        "scratchQuota": config.scratch_quota,
+        "clusterDNS": config.cluster_dns,
+        "clusterDomain": config.cluster_domain,
+        "allowedRegistries": config.allowed_registries,
+        "requireImageDigests": config.require_image_digests,
+        "recordImageAttestations": config.record_image_attestations,
+        "instancePoolSize": config.instance_pool_size,
+        "instancePoolMemory": config.instance_pool_memory,
+        "waitForNodeLease": config.wait_for_node_lease,
+        "takeOverLegacyLease": config.take_over_legacy_lease,
+        "nodeCapacity": config.node_capacity,
+    })
+}
+
+/// Replaces anything that may be secret in a JSON value with [`REDACTED`]: fields named like a
+/// credential, the values of environment variables, the arguments and commands of containers and
+/// kubectl's copy of the last applied manifest.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                let lowercase = name.to_lowercase();
+                if name == LAST_APPLIED_ANNOTATION
+                    || SECRET_FIELDS.iter().any(|s| lowercase.contains(s))
+                {
+                    // Paths to credential files are safe to keep, and useful
+                    if !lowercase.ends_with("file") {
+                        *field = Value::from(REDACTED);
+                    }
+                } else if name == "env" {
+                    redact_env(field);
+                } else if COMMAND_LINE_FIELDS.contains(&name.as_str()) {
+                    redact_command_line(field);
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+fn redact_env(env: &mut Value) {
+    match env {
+        Value::Array(vars) => {
+            for var in vars.iter_mut() {
+                match var.get_mut("value") {
+                    Some(value) => *value = Value::from(REDACTED),
+                    None => redact(var),
+                }
+            }
+        }
+        // Not a container's environment
+        other => redact(other),
+    }
+}
+
+fn redact_command_line(command_line: &mut Value) {
+    match command_line {
+        Value::Array(words) => words
+            .iter_mut()
+            .filter(|word| word.is_string())
+            .for_each(|word| *word = Value::from(REDACTED)),
+        // Not a container's command line
+        other => redact(other),
+    }
+}
+
+/// Replaces anything that may be secret in a log line with [`REDACTED`]. Lines logged as JSON
+/// are redacted like any other JSON value. Otherwise the values of `key=value` and `key: value`
+/// fields named like a credential, credentials following an authorization scheme and the user
+/// info of URLs are redacted.
+pub fn redact_line(line: &str) -> String {
+    if let Ok(mut value) = serde_json::from_str::<Value>(line) {
+        if value.is_object() {
+            redact(&mut value);
+            return value.to_string();
+        }
+    }
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    let mut redact_next = false;
+    loop {
+        let trimmed = rest.trim_start();
+        redacted.push_str(&rest[..rest.len() - trimmed.len()]);
+        if trimmed.is_empty() {
+            return redacted;
+        }
+        let (word, after) = trimmed.split_at(word_len(trimmed));
+        rest = after;
+        let lowercase = word.to_lowercase();
+        if CREDENTIAL_SCHEMES.contains(&lowercase.trim_matches('"')) {
+            redact_next = true;
+            redacted.push_str(word);
+        } else if redact_next {
+            redact_next = false;
+            redacted.push_str(REDACTED);
+        } else if let Some(split) = word
+            .find(|c: char| c == '=' || c == ':')
+            .filter(|split| !word[*split..].starts_with("://"))
+        {
+            let (key, value) = (&word[..=split], &word[split + 1..]);
+            let secret = is_secret_key(&key[..split]);
+            redacted.push_str(key);
+            if secret && value.is_empty() {
+                redact_next = true;
+            } else if secret {
+                redacted.push_str(REDACTED);
+            } else {
+                redacted.push_str(&redact_url(value));
+            }
+        } else {
+            redacted.push_str(&redact_url(word));
+        }
+    }
+}
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.trim_matches('"').to_lowercase();
+    SECRET_FIELDS.iter().any(|s| key.contains(s))
+}
+
+/// The length of the word at the start of a log line, which runs until the next whitespace that
+/// isn't in a quoted string.
+fn word_len(line: &str) -> usize {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return i,
+            _ => (),
+        }
+    }
+    line.len()
+}
+
+/// Redacts the user info, such as a password, of any URL in a word.
+fn redact_url(word: &str) -> String {
+    if let Some(scheme_end) = word.find("://") {
+        let authority = &word[scheme_end + 3..];
+        let host_start = authority.find('/').unwrap_or_else(|| authority.len());
+        if let Some(at) = authority[..host_start].rfind('@') {
+            return format!(
+                "{}{}{}",
+                &word[..scheme_end + 3],
+                REDACTED,
+                &authority[at..]
+            );
+        }
+    }
+    word.to_owned()
+}
+
+/// The last `lines` lines of a file, with anything that may be secret redacted.
+async fn tail(path: &Path, lines: usize) -> anyhow::Result<String> {
+    let contents = tokio::fs::read(path).await?;
+    let contents = String::from_utf8_lossy(&contents);
+    let all: Vec<&str> = contents.lines().collect();
+    let start = all.len().saturating_sub(lines);
+    Ok(all[start..]
+        .iter()
+        .map(|l| format!("{}\n", redact_line(l)))
+        .collect())
+}
+
+/// The pod intent log with its manifests redacted, if there is one.
+async fn intent_log(data_dir: &Path) -> anyhow::Result<Option<String>> {
+    let contents = match tokio::fs::read_to_string(data_dir.join(INTENT_LOG_FILE)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let redacted = contents
+        .lines()
+        .map(|line| match serde_json::from_str::<Value>(line) {
+            Ok(mut entry) => {
+                redact(&mut entry);
+                format!("{}\n", entry)
+            }
+            // A torn write at the end of the log, which can't hold anything useful
+            Err(_) => String::new(),
+        })
+        .collect();
+    Ok(Some(redacted))
+}
+
+/// The files of a bundle, gathered in memory before they are written out together.
+#[derive(Default)]
+struct Bundle {
+    files: Vec<(String, Vec<u8>)>,
+    errors: Vec<String>,
+}
+
+impl Bundle {
+    fn add(&mut self, name: &str, contents: Vec<u8>) {
+        self.files.push((name.to_owned(), contents));
+    }
+
+    fn add_json(&mut self, name: &str, value: &Value) {
+        let contents = serde_json::to_vec_pretty(value).unwrap_or_default();
+        self.add(name, contents);
+    }
+
+    /// Adds an object from the API server, without its managed fields and with anything secret
+    /// redacted.
+    fn add_object<T: serde::Serialize>(&mut self, name: &str, object: &T) {
+        match serde_json::to_value(object) {
+            Ok(mut value) => {
+                strip_managed_fields(&mut value);
+                redact(&mut value);
+                self.add_json(name, &value);
+            }
+            Err(e) => self.error(name, e.into()),
+        }
+    }
+
+    fn error(&mut self, name: &str, error: anyhow::Error) {
+        self.errors.push(format!("{}: {:#}", name, error));
+    }
+
+    fn write(mut self, output: &Path) -> anyhow::Result<()> {
+        if !self.errors.is_empty() {
+            let errors: String = self.errors.iter().map(|e| format!("{}\n", e)).collect();
+            self.add("errors.txt", errors.into_bytes());
+        }
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let file = std::fs::File::create(output)?;
+        let mut tar = tar::Builder::new(std::io::BufWriter::new(file));
+        for (name, contents) in &self.files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            tar.append_data(
+                &mut header,
+                format!("{}/{}", BUNDLE_DIR, name),
+                contents.as_slice(),
+            )?;
+        }
+        tar.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+fn strip_managed_fields(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            fields.remove("managedFields");
+            fields.values_mut().for_each(strip_managed_fields);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_managed_fields),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let mut pod = serde_json::json!({
+            "metadata": {
+                "annotations": {LAST_APPLIED_ANNOTATION: "{\"spec\":{}}", "team": "web"},
+            },
+            "spec": {
+                "containers": [{
+                    "name": "server",
+                    "command": ["server.wasm"],
+                    "args": ["--db-password", "hunter2"],
+                    "env": [
+                        {"name": "DATABASE_URL", "value": "postgres://user:hunter2@db"},
+                        {"name": "NODE", "valueFrom": {"fieldRef": {"fieldPath": "spec.nodeName"}}},
+                    ],
+                }],
+            },
+            "registryPassword": "hunter2",
+            "adminTokenFile": "/etc/krustlet/admin-token",
+        });
+        redact(&mut pod);
+        assert_eq!(
+            pod["metadata"]["annotations"][LAST_APPLIED_ANNOTATION],
+            REDACTED
+        );
+        assert_eq!(pod["metadata"]["annotations"]["team"], "web");
+        let env = &pod["spec"]["containers"][0]["env"];
+        assert_eq!(env[0]["value"], REDACTED);
+        assert_eq!(
+            env[1]["valueFrom"]["fieldRef"]["fieldPath"],
+            "spec.nodeName"
+        );
+        let container = &pod["spec"]["containers"][0];
+        assert_eq!(container["command"], serde_json::json!([REDACTED]));
+        assert_eq!(container["args"], serde_json::json!([REDACTED, REDACTED]));
+        assert_eq!(pod["registryPassword"], REDACTED);
+        assert_eq!(pod["adminTokenFile"], "/etc/krustlet/admin-token");
+    }
+
+    #[test]
+    fn test_redact_line() {
+        assert_eq!(
+            redact_line(r#"INFO pulling image token=abc123 password="hunter 2" image=foo:1"#),
+            format!(
+                r#"INFO pulling image token={} password={} image=foo:1"#,
+                REDACTED, REDACTED
+            )
+        );
+        assert_eq!(
+            redact_line("Authorization: Bearer abc123 from postgres://user:hunter2@db/app"),
+            format!(
+                "Authorization: Bearer {} from postgres://{}@db/app",
+                REDACTED, REDACTED
+            )
+        );
+        assert_eq!(
+            redact_line("registry secret: hunter2 at https://example.com:443/v2"),
+            format!(
+                "registry secret: {} at https://example.com:443/v2",
+                REDACTED
+            )
+        );
+        let json: Value = serde_json::from_str(&redact_line(
+            r#"{"level":"INFO","fields":{"message":"pulled","auth":"hunter2"}}"#,
+        ))
+        .unwrap();
+        assert_eq!(json["fields"]["auth"], REDACTED);
+        assert_eq!(json["fields"]["message"], "pulled");
+    }
+
+    #[test]
+    fn test_bundle_is_a_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("bundle.tar");
+        let mut bundle = Bundle::default();
+        bundle.add("config.json", b"{}".to_vec());
+        let long_name = format!("{}/file", "x".repeat(120));
+        bundle.add(&long_name, vec![1; 513]);
+        bundle.error("server", anyhow::anyhow!("not running"));
+        bundle.write(&output).unwrap();
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&output).unwrap());
+        let files: Vec<(String, Vec<u8>)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = Vec::new();
+                std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+                (path, contents)
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (format!("{}/config.json", BUNDLE_DIR), b"{}".to_vec()),
+                (format!("{}/{}", BUNDLE_DIR, long_name), vec![1; 513]),
+                (
+                    format!("{}/errors.txt", BUNDLE_DIR),
+                    b"server: not running\n".to_vec()
+                ),
+            ]
+        );
+    }
+}
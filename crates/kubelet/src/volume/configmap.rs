@@ -2,9 +2,14 @@ use std::path::Path;
 
 use k8s_openapi::api::core::v1::{ConfigMap, KeyToPath, Volume as KubeVolume};
 use k8s_openapi::ByteString;
+use tokio::task::JoinHandle;
 use tracing::warn;
 
 use super::*;
+use crate::dependency::DependencyTracker;
+use crate::pod::dirs::{join_name, join_relative};
+use crate::pod::PodKey;
+
 /// A type that can manage a ConfigMap volume with mounting and unmounting support
 pub struct ConfigMapVolume {
     vol_name: String,
@@ -12,6 +17,8 @@ pub struct ConfigMapVolume {
     client: kube::Api<ConfigMap>,
     items: Vec<KeyToPath>,
     mounted_path: Option<PathBuf>,
+    tracker: Option<(DependencyTracker, PodKey)>,
+    refresh: Option<JoinHandle<()>>,
 }
 
 impl ConfigMapVolume {
@@ -30,19 +37,34 @@ impl ConfigMapVolume {
             client: Api::namespaced(client, namespace),
             items: cm_source.items.clone(),
             mounted_path: None,
+            tracker: None,
+            refresh: None,
         })
     }
 
+    /// Reads the ConfigMap from the tracker's watch on behalf of the given pod instead of
+    /// fetching it. Once mounted, the files are rewritten whenever the ConfigMap changes.
+    pub fn with_dependency_tracker(mut self, tracker: DependencyTracker, pod: PodKey) -> Self {
+        self.tracker = Some((tracker, pod));
+        self
+    }
+
     /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
     /// hasn't been mounted yet
     pub fn get_path(&self) -> Option<&Path> {
         self.mounted_path.as_deref()
     }
 
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
     /// Mounts the ConfigMap volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let path = base_path.as_ref().join(&self.vol_name);
+        let path = join_name(base_path.as_ref(), &self.vol_name)?;
         tokio::fs::create_dir_all(&path).await?;
 
         self.mount_at(path.clone()).await?;
@@ -60,34 +82,34 @@ impl ConfigMapVolume {
     /// and already exist. This method will not set any permissions, so the caller is responsible
     /// for setting permissions on the directory
     pub(crate) async fn mount_at(&mut self, path: PathBuf) -> anyhow::Result<()> {
-        let config_map = self.client.get(&self.cm_name).await?;
-        let binary_data = config_map.binary_data;
-        let binary_data = binary_data
-            .into_iter()
-            .filter_map(
-                |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
-                    ItemMount::DoNotMount => None,
-                },
-            )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        let binary_data = futures::future::join_all(binary_data);
-
-        let data = config_map.data;
-        let data = data
-            .into_iter()
-            .filter_map(|(key, data)| match mount_setting_for(&key, &self.items) {
-                ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
-                ItemMount::DoNotMount => None,
-            })
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        let data = futures::future::join_all(data);
-
-        let (binary_data, data) = futures::future::join(binary_data, data).await;
-        binary_data
-            .into_iter()
-            .chain(data)
-            .collect::<tokio::io::Result<_>>()?;
+        let (config_map, updates) = match &self.tracker {
+            Some((tracker, pod)) => (
+                tracker.config_map(pod, &self.cm_name).await?,
+                Some(tracker.config_map_updates(pod, &self.cm_name)),
+            ),
+            None => (self.client.get(&self.cm_name).await?, None),
+        };
+        let files = config_map_files(&path, config_map, &self.items)?;
+        let mut written = replace_files(&HashSet::new(), files).await?;
+
+        if let Some(mut updates) = updates {
+            let (items, path) = (self.items.clone(), path.clone());
+            self.refresh = Some(tokio::spawn(async move {
+                while let Some(config_map) = updates.changed().await {
+                    let refreshed = refresh_read_only_dir(&path, async {
+                        let files = config_map_files(&path, config_map, &items)?;
+                        replace_files(&written, files).await
+                    })
+                    .await;
+                    match refreshed {
+                        Ok(now_written) => written = now_written,
+                        Err(e) => {
+                            warn!(error = %e, path = %path.display(), "Unable to refresh ConfigMap volume")
+                        }
+                    }
+                }
+            }));
+        }
 
         // Update the mounted directory
         self.mounted_path = Some(path);
@@ -95,9 +117,17 @@ impl ConfigMapVolume {
         Ok(())
     }
 
+    /// Stops rewriting the mounted files when the ConfigMap changes
+    pub(crate) fn stop_refresh(&mut self) {
+        if let Some(refresh) = self.refresh.take() {
+            refresh.abort();
+        }
+    }
+
     /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
     /// hasn't been mounted will log a warning, but otherwise not error
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        self.stop_refresh();
         match self.mounted_path.take() {
             Some(p) => {
                 // Because things are set to read only, we need to remove the read only flag so it
@@ -120,3 +150,75 @@ impl ConfigMapVolume {
         Ok(())
     }
 }
+
+/// The files the ConfigMap's keys are mounted as in the volume directory at `path`.
+fn config_map_files(
+    path: &Path,
+    config_map: ConfigMap,
+    items: &[KeyToPath],
+) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let binary_data = config_map
+        .binary_data
+        .into_iter()
+        .map(|(key, ByteString(data))| (key, data));
+    let data = config_map
+        .data
+        .into_iter()
+        .map(|(key, data)| (key, data.into_bytes()));
+    binary_data
+        .chain(data)
+        .filter_map(|(key, data)| match mount_setting_for(&key, items) {
+            ItemMount::MountAt(mount_path) => Some((join_relative(path, &mount_path), data)),
+            ItemMount::DoNotMount => None,
+        })
+        .map(|(file_path, data)| Ok((file_path?, data)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_map(data: serde_json::Value) -> ConfigMap {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": "test-cm" },
+            "data": data,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_refresh_replaces_files_and_removes_deleted_keys() {
+        let tempdir = tempfile::tempdir().expect("Unable to create tempdir");
+        let path = tempdir.path();
+
+        let files = config_map_files(
+            path,
+            config_map(serde_json::json!({ "kept": "one", "removed": "two" })),
+            &[],
+        )
+        .unwrap();
+        let written = replace_files(&HashSet::new(), files).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.join("removed")).unwrap(),
+            "two"
+        );
+
+        let files = config_map_files(
+            path,
+            config_map(serde_json::json!({ "kept": "three" })),
+            &[],
+        )
+        .unwrap();
+        let written = replace_files(&written, files).await.unwrap();
+        assert_eq!(written, vec![path.join("kept")].into_iter().collect());
+        assert_eq!(std::fs::read_to_string(path.join("kept")).unwrap(), "three");
+        assert!(!path.join("removed").exists());
+
+        // Nothing is left behind from staging the new contents
+        let entries: Vec<_> = std::fs::read_dir(path).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+}
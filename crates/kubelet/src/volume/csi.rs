@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use k8s_csi::v1_3_0::node_client::NodeClient;
+use k8s_csi::v1_3_0::volume_capability::access_mode::Mode as CSIMode;
+use k8s_csi::v1_3_0::volume_capability::{
+    AccessMode as CSIAccessMode, AccessType as CSIAccessType, MountVolume as CSIMountVolume,
+};
+use k8s_csi::v1_3_0::{NodePublishVolumeRequest, NodeUnpublishVolumeRequest, VolumeCapability};
+use k8s_openapi::api::core::v1::{CSIVolumeSource, Volume as KubeVolume};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use super::*;
+use crate::grpc_sock;
+use crate::pod::dirs::join_name;
+
+/// A type that can manage an inline ephemeral CSI volume with mounting and unmounting support.
+///
+/// Unlike a PVC backed CSI volume, an inline volume only lives as long as the pod. It is
+/// published straight to the pod's volume directory (drivers are never asked to stage it) and
+/// unpublished when the volume is unmounted.
+pub struct CsiVolume {
+    name: String,
+    volume_id: String,
+    client: kube::Client,
+    namespace: String,
+    source: CSIVolumeSource,
+    volume_context: BTreeMap<String, String>,
+    csi_client: NodeClient<tonic::transport::Channel>,
+    mounted_path: Option<PathBuf>,
+}
+
+impl CsiVolume {
+    /// Creates a new inline CSI volume from a Kubernetes volume object. Passing a non-CSI volume
+    /// type will result in an error
+    pub async fn new(
+        vol: &KubeVolume,
+        pod: &Pod,
+        client: kube::Client,
+        plugin_registry: Option<Arc<PluginRegistry>>,
+    ) -> anyhow::Result<Self> {
+        let source = vol.csi.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called a CSI volume constructor with a non-CSI volume")
+        })?;
+        let plugin_registry = plugin_registry.ok_or_else(|| {
+            anyhow::anyhow!(
+                "cannot mount volume {}: CSI driver support not implemented",
+                vol.name
+            )
+        })?;
+        let endpoint = plugin_registry
+            .get_endpoint(&source.driver)
+            .await
+            .ok_or_else(|| {
+                anyhow::anyhow!("could not get endpoint of CSI plugin {}", source.driver)
+            })?;
+        let chan = grpc_sock::client::socket_channel(endpoint).await?;
+
+        Ok(CsiVolume {
+            name: vol.name.clone(),
+            volume_id: ephemeral_volume_id(pod.pod_uid(), &vol.name),
+            client,
+            namespace: pod.namespace().to_owned(),
+            source: source.clone(),
+            volume_context: volume_context(source, pod),
+            csi_client: NodeClient::new(chan),
+            mounted_path: None,
+        })
+    }
+
+    /// Returns whether the volume is mounted read-only
+    pub fn read_only(&self) -> bool {
+        self.source.read_only.unwrap_or_default()
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
+    /// Mounts the CSI volume in the given directory. The actual path will be
+    /// $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = join_name(base_path.as_ref(), &self.name)?;
+        tokio::fs::create_dir_all(&path).await?;
+
+        let secrets = self.publish_secrets().await?;
+        let req = NodePublishVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            target_path: path.to_string_lossy().to_string(),
+            staging_target_path: String::new(),
+            volume_capability: Some(VolumeCapability {
+                // Inline volumes are only ever used by the one pod that declares them
+                access_mode: Some(CSIAccessMode {
+                    mode: CSIMode::SingleNodeWriter as i32,
+                }),
+                access_type: Some(CSIAccessType::Mount(CSIMountVolume {
+                    fs_type: self.source.fs_type.clone().unwrap_or_default(),
+                    mount_flags: Default::default(),
+                })),
+            }),
+            readonly: self.source.read_only.unwrap_or_default(),
+            secrets,
+            publish_context: Default::default(),
+            volume_context: self.volume_context.clone(),
+        };
+        self.csi_client.node_publish_volume(req).await?;
+
+        self.mounted_path = Some(path);
+        Ok(())
+    }
+
+    /// Unmounts the directory. Calling `unmount` on a directory that hasn't been mounted will log a
+    /// warning, but otherwise not error
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        match self.mounted_path.take() {
+            Some(p) => {
+                let req = NodeUnpublishVolumeRequest {
+                    volume_id: self.volume_id.clone(),
+                    target_path: p.to_string_lossy().to_string(),
+                };
+                self.csi_client.node_unpublish_volume(req).await?;
+                // Now remove the empty directory
+                //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
+                #[cfg(target_family = "windows")]
+                tokio::task::spawn_blocking(|| remove_dir_all::remove_dir_all(p)).await??;
+
+                #[cfg(target_family = "unix")]
+                tokio::fs::remove_dir_all(p).await?;
+            }
+            None => {
+                warn!("Attempted to unmount CSI directory that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the secret referenced by `nodePublishSecretRef`, which always lives in the pod's
+    /// namespace for inline volumes
+    async fn publish_secrets(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let name = match self
+            .source
+            .node_publish_secret_ref
+            .as_ref()
+            .and_then(|r| r.name.as_ref())
+        {
+            Some(name) => name,
+            None => return Ok(BTreeMap::default()),
+        };
+        let secret_client: Api<Secret> = Api::namespaced(self.client.clone(), &self.namespace);
+        let secret = secret_client.get(name).await?;
+        // NOTE: The CSI API wants the secret values as Strings, so like PVC volumes any non UTF-8
+        // data is converted lossily
+        Ok(secret
+            .data
+            .into_iter()
+            .map(|(k, v)| (k, String::from_utf8_lossy(&v.0).into_owned()))
+            .collect())
+    }
+}
+
+/// Generates the ID of an inline volume the same way the kubelet does, so that it is unique per
+/// pod and stable across restarts
+fn ephemeral_volume_id(pod_uid: &str, volume_name: &str) -> String {
+    let digest = Sha256::digest(format!("{}{}", pod_uid, volume_name).as_bytes());
+    format!("csi-{:x}", digest)
+}
+
+/// The attributes of the volume, plus the pod information the kubelet passes to drivers of
+/// ephemeral volumes
+fn volume_context(source: &CSIVolumeSource, pod: &Pod) -> BTreeMap<String, String> {
+    let mut context = source.volume_attributes.clone();
+    context.insert("csi.storage.k8s.io/ephemeral".into(), "true".into());
+    context.insert("csi.storage.k8s.io/pod.name".into(), pod.name().to_owned());
+    context.insert(
+        "csi.storage.k8s.io/pod.namespace".into(),
+        pod.namespace().to_owned(),
+    );
+    context.insert(
+        "csi.storage.k8s.io/pod.uid".into(),
+        pod.pod_uid().to_owned(),
+    );
+    context.insert(
+        "csi.storage.k8s.io/serviceAccount.name".into(),
+        pod.service_account_name().unwrap_or_default().to_owned(),
+    );
+    context
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    #[test]
+    fn test_volume_context() {
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(serde_json::json!({
+                "metadata": {"name": "secrets", "namespace": "apps", "uid": "1234"},
+                "spec": {"serviceAccountName": "reader", "containers": []}
+            }))
+            .unwrap(),
+        );
+        let mut source = CSIVolumeSource {
+            driver: "secrets-store.csi.k8s.io".into(),
+            ..Default::default()
+        };
+        source
+            .volume_attributes
+            .insert("secretProviderClass".into(), "vault".into());
+
+        let context = volume_context(&source, &pod);
+        assert_eq!(context["secretProviderClass"], "vault");
+        assert_eq!(context["csi.storage.k8s.io/ephemeral"], "true");
+        assert_eq!(context["csi.storage.k8s.io/pod.uid"], "1234");
+        assert_eq!(context["csi.storage.k8s.io/serviceAccount.name"], "reader");
+
+        let id = ephemeral_volume_id("1234", "secrets");
+        assert!(id.starts_with("csi-"));
+        assert_eq!(id, ephemeral_volume_id("1234", "secrets"));
+        assert_ne!(id, ephemeral_volume_id("5678", "secrets"));
+    }
+}
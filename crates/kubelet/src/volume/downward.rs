@@ -10,6 +10,7 @@ use k8s_openapi::{
 use tracing::warn;
 
 use crate::container::Container;
+use crate::pod::dirs::{join_name, join_relative};
 use crate::resources::quantity::{self, Quantity, QuantityType, Suffix};
 
 use super::*;
@@ -44,10 +45,16 @@ impl DownwardApiVolume {
         self.mounted_path.as_deref()
     }
 
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
     /// Mounts the Downward API volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let path = base_path.as_ref().join(&self.vol_name);
+        let path = join_name(base_path.as_ref(), &self.vol_name)?;
         tokio::fs::create_dir_all(&path).await?;
         self.mount_at(path.clone()).await?;
 
@@ -69,13 +76,16 @@ impl DownwardApiVolume {
             .items
             .iter()
             .filter_map(|d| {
-                d.field_ref
-                    .as_ref()
-                    .map(|f| (path.join(&d.path), data_from_field_ref(f, &self.pod)))
+                d.field_ref.as_ref().map(|f| {
+                    (
+                        join_relative(&path, &d.path),
+                        data_from_field_ref(f, &self.pod),
+                    )
+                })
             })
             .map(|(p, res)| async move {
                 let data = res?;
-                tokio::fs::write(p, &data).await.map_err(|e| e.into())
+                tokio::fs::write(p?, &data).await.map_err(|e| e.into())
             });
         let field_refs = futures::future::join_all(field_refs);
 
@@ -84,13 +94,16 @@ impl DownwardApiVolume {
             .items
             .iter()
             .filter_map(|d| {
-                d.resource_field_ref
-                    .as_ref()
-                    .map(|f| (path.join(&d.path), data_from_resource_ref(f, &containers)))
+                d.resource_field_ref.as_ref().map(|f| {
+                    (
+                        join_relative(&path, &d.path),
+                        data_from_resource_ref(f, &containers),
+                    )
+                })
             })
             .map(|(p, res)| async move {
                 let data = res?;
-                tokio::fs::write(p, &data).await.map_err(|e| e.into())
+                tokio::fs::write(p?, &data).await.map_err(|e| e.into())
             });
         let resource_refs = futures::future::join_all(resource_refs);
 
@@ -106,6 +119,19 @@ impl DownwardApiVolume {
         Ok(())
     }
 
+    /// Rewrites the files of a mounted volume from an updated version of the pod, so that
+    /// changes to its labels and annotations show up in the volume. Does nothing if the volume
+    /// hasn't been mounted yet
+    pub async fn update_pod(&mut self, pod: &Pod) -> anyhow::Result<()> {
+        self.pod = pod.clone();
+        let path = match &self.mounted_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let dir = path.clone();
+        refresh_read_only_dir(&dir, self.mount_at(path)).await
+    }
+
     /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
     /// hasn't been mounted will log a warning, but otherwise not error
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
@@ -670,6 +696,44 @@ mycustomannotation="{}""#,
             .expect_err("A valid path outside of metadata should fail");
     }
 
+    #[tokio::test]
+    async fn test_update_pod() {
+        let mut fake_pod = serde_json::json!({
+            "metadata": {"name": "test-pod", "labels": {"tier": "frontend"}},
+            "spec": {
+                "containers": [],
+                "volumes": [{
+                    "name": "podinfo",
+                    "downwardAPI": {"items": [{"path": "labels", "fieldRef": {"fieldPath": "metadata.labels"}}]}
+                }]
+            }
+        });
+        let pod: Pod = serde_json::from_value(fake_pod.clone()).unwrap();
+        let mut downward = DownwardApiVolume::new(&pod.volumes()[0], pod.clone()).unwrap();
+        let tempdir = tempfile::tempdir().expect("Unable to create tempdir");
+        downward.mount(tempdir.path()).await.unwrap();
+
+        fake_pod["metadata"]["labels"]["tier"] = "backend".into();
+        let updated: Pod = serde_json::from_value(fake_pod).unwrap();
+        downward
+            .update_pod(&updated)
+            .await
+            .expect("Updating a read-only volume should work");
+        let vol_dir = downward.get_path().unwrap().to_owned();
+        assert_content(
+            vol_dir.join("labels"),
+            "tier=\"backend\"",
+            "Labels should be rewritten",
+        )
+        .await;
+        assert!(tokio::fs::metadata(&vol_dir)
+            .await
+            .unwrap()
+            .permissions()
+            .readonly());
+        downward.unmount().await.unwrap();
+    }
+
     async fn assert_content(path: PathBuf, expected: &str, message: &str) {
         let content = tokio::fs::read_to_string(path)
             .await
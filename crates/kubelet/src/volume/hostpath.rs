@@ -15,7 +15,7 @@ impl HostPathVolume {
             anyhow::anyhow!("Called a HostPath volume constructor with a non-HostPath volume")
         })?;
         Ok(HostPathVolume {
-            host_path: PathBuf::from(&source.path),
+            host_path: parse_host_path(&source.path)?,
         })
     }
 
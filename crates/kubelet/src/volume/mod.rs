@@ -1,7 +1,7 @@
 //! A module for use in managing volumes in providers. Use of this module is not
 //! mandatory to create a Provider, but it does provide common implementation
 //! logic for supported volume providers.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -10,19 +10,26 @@ use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Secret, Volume as KubeVo
 use kube::api::Api;
 use tracing::error;
 
+use crate::dependency::DependencyTracker;
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Pod;
+use crate::pod::{Pod, PodKey};
 
 mod configmap;
+mod csi;
 mod downward;
 mod hostpath;
+mod ownership;
+mod paths;
 mod persistentvolumeclaim;
 mod projected;
 mod secret;
 
 pub use configmap::ConfigMapVolume;
+pub use csi::CsiVolume;
 pub use downward::DownwardApiVolume;
 pub use hostpath::HostPathVolume;
+pub use ownership::FsGroupChangePolicy;
+pub use paths::{guest_mount_path, parse_host_path};
 pub use persistentvolumeclaim::PvcVolume;
 pub use projected::ProjectedVolume;
 pub use secret::SecretVolume;
@@ -45,6 +52,8 @@ pub enum VolumeRef {
     /// Projected volume, a new volume type used for all projected data types (ConfigMap, Secret,
     /// and Downward API)
     Projected(ProjectedVolume),
+    /// Inline ephemeral CSI volume
+    Csi(CsiVolume),
 }
 
 impl VolumeRef {
@@ -53,17 +62,92 @@ impl VolumeRef {
         pod: &Pod,
         client: &kube::Client,
         plugin_registry: Option<Arc<PluginRegistry>>,
+    ) -> anyhow::Result<HashMap<String, Self>> {
+        Self::volumes_from_pod_with_tracker(pod, client, plugin_registry, None).await
+    }
+
+    /// Resolves the volumes for a pod. If a [`DependencyTracker`] is given, ConfigMap, Secret and
+    /// projected volumes read their objects from its watches and are kept up to date.
+    pub async fn volumes_from_pod_with_tracker(
+        pod: &Pod,
+        client: &kube::Client,
+        plugin_registry: Option<Arc<PluginRegistry>>,
+        tracker: Option<DependencyTracker>,
     ) -> anyhow::Result<HashMap<String, Self>> {
         let vols = pod
             .volumes()
             .iter()
-            .map(|v| (v, plugin_registry.clone()))
-            .map(|(vol, pr)| async move {
-                Ok((vol.name.clone(), to_volume_ref(vol, pod, client, pr).await?))
+            .map(|v| (v, plugin_registry.clone(), tracker.clone()))
+            .map(|(vol, pr, tracker)| async move {
+                let volume = to_volume_ref(vol, pod, client, pr).await?;
+                let volume = match tracker {
+                    Some(tracker) => volume.with_dependency_tracker(tracker, PodKey::from(pod)),
+                    None => volume,
+                };
+                Ok((vol.name.clone(), volume))
             });
         futures::future::join_all(vols).await.into_iter().collect()
     }
 
+    /// Unmounts the volumes of a pod that a previous run of the Kubelet left behind in the given
+    /// directory, such as after it crashed part way through starting or removing the pod. CSI
+    /// and PVC volumes are unpublished through their drivers. Volumes that have no directory are
+    /// skipped, and every volume is tried even if some of them fail.
+    pub async fn unmount_leftovers(
+        pod: &Pod,
+        dir: &Path,
+        client: &kube::Client,
+        plugin_registry: Option<Arc<PluginRegistry>>,
+    ) -> anyhow::Result<()> {
+        let mut failed = Vec::new();
+        for vol in pod.volumes() {
+            let res = async {
+                let path = crate::pod::dirs::join_name(dir, &vol.name)?;
+                if tokio::fs::metadata(&path).await.is_err() {
+                    return Ok(());
+                }
+                let mut volume = to_volume_ref(vol, pod, client, plugin_registry.clone()).await?;
+                volume.adopt_mount(path);
+                volume.unmount().await
+            }
+            .await;
+            if let Err(e) = res {
+                error!(error = %e, volume_name = %vol.name, "Unable to unmount leftover volume");
+                failed.push(format!("{}: {}", vol.name, e));
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!("Unable to unmount volumes {}", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    fn adopt_mount(&mut self, path: PathBuf) {
+        match self {
+            VolumeRef::ConfigMap(cm) => cm.adopt_mount(path),
+            VolumeRef::Secret(sec) => sec.adopt_mount(path),
+            VolumeRef::PersistentVolumeClaim(pv) => pv.adopt_mount(path),
+            // Host paths aren't mounted in the pod's directory
+            VolumeRef::HostPath(_) => (),
+            VolumeRef::DownwardApi(d) => d.adopt_mount(path),
+            VolumeRef::Projected(p) => p.adopt_mount(path),
+            VolumeRef::Csi(c) => c.adopt_mount(path),
+        }
+    }
+
+    fn with_dependency_tracker(self, tracker: DependencyTracker, pod: PodKey) -> Self {
+        match self {
+            VolumeRef::ConfigMap(cm) => {
+                VolumeRef::ConfigMap(cm.with_dependency_tracker(tracker, pod))
+            }
+            VolumeRef::Secret(sec) => VolumeRef::Secret(sec.with_dependency_tracker(tracker, pod)),
+            VolumeRef::Projected(p) => {
+                VolumeRef::Projected(p.with_dependency_tracker(tracker, pod))
+            }
+            other => other,
+        }
+    }
+
     /// A convenience wrapper that calls the correct get_path method for the variant. Returns the
     /// path the volume is mounted at on the host, `None` if the volume hasn't been mounted
     pub fn get_path(&self) -> Option<&Path> {
@@ -74,6 +158,7 @@ impl VolumeRef {
             VolumeRef::HostPath(host) => host.get_path(),
             VolumeRef::DownwardApi(d) => d.get_path(),
             VolumeRef::Projected(p) => p.get_path(),
+            VolumeRef::Csi(c) => c.get_path(),
         }
     }
 
@@ -88,6 +173,38 @@ impl VolumeRef {
             // We need to clone the path here so we are sure that it is owned since this mount call
             // results in recursion
             VolumeRef::Projected(p) => p.mount(path.as_ref().to_owned()).await,
+            VolumeRef::Csi(c) => c.mount(path).await,
+        }
+    }
+
+    /// Gives a mounted volume to the pod's fsGroup so that the pod can write to it. Only
+    /// PersistentVolumeClaim and inline CSI volumes that aren't mounted read-only are changed.
+    /// Unlike the kubelet, the ConfigMap, Secret, downward API and projected volumes are left
+    /// alone, as they are written by the Kubelet and mounted read-only anyway, and so are host
+    /// paths, which the kubelet never changes either
+    pub async fn apply_fs_group(
+        &self,
+        fs_group: u32,
+        policy: FsGroupChangePolicy,
+    ) -> anyhow::Result<()> {
+        let path = match self {
+            VolumeRef::PersistentVolumeClaim(pv) if !pv.read_only() => pv.get_path(),
+            VolumeRef::Csi(c) if !c.read_only() => c.get_path(),
+            _ => None,
+        };
+        match path {
+            Some(path) => ownership::set_volume_ownership(path, fs_group, policy).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Updates the volume after the labels or annotations of its pod changed. Only volumes that
+    /// expose pod metadata through the Downward API are affected
+    pub async fn update_pod_metadata(&mut self, pod: &Pod) -> anyhow::Result<()> {
+        match self {
+            VolumeRef::DownwardApi(d) => d.update_pod(pod).await,
+            VolumeRef::Projected(p) => p.update_pod(pod).await,
+            _ => Ok(()),
         }
     }
 
@@ -101,6 +218,7 @@ impl VolumeRef {
             VolumeRef::HostPath(_) => Ok(()),
             VolumeRef::DownwardApi(d) => d.unmount().await,
             VolumeRef::Projected(p) => p.unmount().await,
+            VolumeRef::Csi(c) => c.unmount().await,
         }
     }
 }
@@ -118,6 +236,64 @@ fn mount_setting_for(key: &str, items_to_mount: &[KeyToPath]) -> ItemMount {
     }
 }
 
+/// Runs a write into a mounted volume directory, lifting the read-only flag that was set on the
+/// directory after it was mounted until the write completes
+async fn refresh_read_only_dir<T>(
+    path: &Path,
+    write: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let mut perms = tokio::fs::metadata(path).await?.permissions();
+    let read_only = perms.readonly();
+    if read_only {
+        perms.set_readonly(false);
+        tokio::fs::set_permissions(path, perms.clone()).await?;
+    }
+    let result = write.await;
+    if read_only {
+        perms.set_readonly(true);
+        tokio::fs::set_permissions(path, perms).await?;
+    }
+    result
+}
+
+/// Replaces the files last written into a mounted volume directory with `files`, returning the
+/// paths now written. Each file is written beside its final path and renamed into place, so a
+/// module never reads one half written, and files that are no longer wanted, such as those of
+/// keys removed from a ConfigMap or Secret, are deleted
+async fn replace_files(
+    previous: &HashSet<PathBuf>,
+    files: Vec<(PathBuf, Vec<u8>)>,
+) -> anyhow::Result<HashSet<PathBuf>> {
+    let writes = files.into_iter().map(|(path, data)| async move {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+        let mut staging_name = std::ffi::OsString::from("..");
+        staging_name.push(file_name);
+        staging_name.push(".tmp");
+        let staging_path = path.with_file_name(staging_name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&staging_path, &data).await?;
+        tokio::fs::rename(&staging_path, &path).await?;
+        anyhow::Result::<PathBuf>::Ok(path)
+    });
+    let written = futures::future::join_all(writes)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<HashSet<_>>>()?;
+
+    for stale in previous.difference(&written) {
+        match tokio::fs::remove_file(stale).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(written)
+}
+
 enum ItemMount {
     MountAt(String),
     DoNotMount,
@@ -154,6 +330,10 @@ async fn to_volume_ref(
         Ok(VolumeRef::PersistentVolumeClaim(
             PvcVolume::new(vol, pod.namespace(), client.clone(), plugin_registry).await?,
         ))
+    } else if vol.ephemeral.is_some() {
+        Ok(VolumeRef::PersistentVolumeClaim(
+            PvcVolume::new_ephemeral(vol, pod, client.clone(), plugin_registry).await?,
+        ))
     } else if vol.host_path.is_some() {
         Ok(VolumeRef::HostPath(HostPathVolume::new(vol)?))
     } else if vol.downward_api.is_some() {
@@ -167,9 +347,13 @@ async fn to_volume_ref(
             pod.to_owned(),
             client.clone(),
         )?))
+    } else if vol.csi.is_some() {
+        Ok(VolumeRef::Csi(
+            CsiVolume::new(vol, pod, client.clone(), plugin_registry).await?,
+        ))
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, and DownwardAPI"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, DownwardAPI, Projected, CSI, and Ephemeral"
         ))
     }
 }
@@ -0,0 +1,265 @@
+//! Applies the `fsGroup` of a pod's security context to its volumes.
+//!
+//! This follows the same rules as the kubelet: every file in the volume is given to the fsGroup
+//! and made group readable and writable, and every directory additionally gets the setgid bit so
+//! that new files inherit the group.
+//!
+//! Windows has no numeric groups, so the closest equivalent is applied there instead: the account
+//! the Kubelet runs as, which modules run as too, is granted modify access to the volume with an
+//! access control entry that new files and directories inherit. Other accounts on the node are
+//! granted nothing.
+use std::path::Path;
+
+/// How the ownership of a volume is changed to match the pod's fsGroup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsGroupChangePolicy {
+    /// Always change the ownership and permissions of everything in the volume
+    Always,
+    /// Only change the ownership and permissions if those of the root of the volume don't match,
+    /// which avoids walking large volumes on every mount
+    OnRootMismatch,
+}
+
+impl FsGroupChangePolicy {
+    /// Parses the `fsGroupChangePolicy` of a pod's security context, defaulting to `Always`.
+    pub fn from_pod_setting(setting: Option<&str>) -> Self {
+        match setting {
+            Some("OnRootMismatch") => FsGroupChangePolicy::OnRootMismatch,
+            _ => FsGroupChangePolicy::Always,
+        }
+    }
+}
+
+/// Gives everything under `path` to the group `fs_group` and makes it group writable.
+#[cfg(target_family = "unix")]
+pub(crate) async fn set_volume_ownership(
+    path: &Path,
+    fs_group: u32,
+    policy: FsGroupChangePolicy,
+) -> anyhow::Result<()> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if policy == FsGroupChangePolicy::OnRootMismatch && !root_mismatch(&path, fs_group)? {
+            tracing::debug!(path = %path.display(), fs_group, "Volume ownership already matches fsGroup, skipping");
+            return Ok(());
+        }
+        walk(&path, fs_group)
+    })
+    .await?
+}
+
+/// Grants the account modules run as modify access to everything under `path`, which is the
+/// closest Windows has to the `fs_group`.
+#[cfg(target_family = "windows")]
+pub(crate) async fn set_volume_ownership(
+    path: &Path,
+    fs_group: u32,
+    policy: FsGroupChangePolicy,
+) -> anyhow::Result<()> {
+    let grant = format!("*{}:{}", current_user_sid().await?, MODIFY_INHERITED);
+    let mut icacls = tokio::process::Command::new("icacls");
+    icacls.arg(path).args(&["/grant", &grant, "/Q"]);
+    // Changing the root propagates the inherited entry to everything that inherits from it, so
+    // only `Always` needs to walk the volume for files that have inheritance turned off
+    if policy == FsGroupChangePolicy::Always {
+        icacls.arg("/T");
+    }
+    let output = icacls.output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Unable to grant access to {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+    tracing::debug!(path = %path.display(), fs_group, "Granted module account access to volume in place of fsGroup");
+    Ok(())
+}
+
+/// The `icacls` rights of modify access that files and directories inherit
+#[cfg(target_family = "windows")]
+const MODIFY_INHERITED: &str = "(OI)(CI)M";
+
+/// The SID of the account the Kubelet, and so every module, runs as. Grants name the account by
+/// SID so they don't depend on the language of the system.
+#[cfg(target_family = "windows")]
+async fn current_user_sid() -> anyhow::Result<String> {
+    let output = tokio::process::Command::new("whoami")
+        .args(&["/user", "/fo", "csv", "/nh"])
+        .output()
+        .await?;
+    // The output is the account name and its SID as a quoted CSV row
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .rsplit(',')
+        .next()
+        .map(|sid| sid.trim_matches('"'))
+        .filter(|sid| output.status.success() && sid.starts_with("S-"))
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unable to find the SID of the current account: {}",
+                stdout.trim()
+            )
+        })
+}
+
+#[cfg(not(any(target_family = "unix", target_family = "windows")))]
+pub(crate) async fn set_volume_ownership(
+    path: &Path,
+    fs_group: u32,
+    _policy: FsGroupChangePolicy,
+) -> anyhow::Result<()> {
+    tracing::warn!(path = %path.display(), fs_group, "fsGroup is not supported on this platform, volume ownership is unchanged");
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+const GROUP_READ_WRITE: u32 = 0o060;
+#[cfg(target_family = "unix")]
+const GROUP_EXECUTE: u32 = 0o010;
+#[cfg(target_family = "unix")]
+const SETGID: u32 = 0o2000;
+
+#[cfg(target_family = "unix")]
+fn required_mode(is_dir: bool) -> u32 {
+    if is_dir {
+        GROUP_READ_WRITE | GROUP_EXECUTE | SETGID
+    } else {
+        GROUP_READ_WRITE
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn root_mismatch(path: &Path, fs_group: u32) -> anyhow::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path)?;
+    let required = required_mode(true);
+    Ok(metadata.gid() != fs_group || metadata.mode() & required != required)
+}
+
+#[cfg(target_family = "unix")]
+fn walk(path: &Path, fs_group: u32) -> anyhow::Result<()> {
+    use nix::unistd::{fchownat, FchownatFlags, Gid};
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    fchownat(
+        None,
+        path,
+        None,
+        Some(Gid::from_raw(fs_group)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .map_err(|e| anyhow::anyhow!("Unable to change group of {}: {}", path.display(), e))?;
+    // The permissions of a symlink are meaningless, and changing them would change the target
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    let mut permissions = metadata.permissions();
+    permissions.set_mode(permissions.mode() | required_mode(metadata.is_dir()));
+    std::fs::set_permissions(path, permissions)?;
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            walk(&entry?.path(), fs_group)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(target_family = "unix")]
+mod test {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[tokio::test]
+    async fn test_set_volume_ownership() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        let file = nested.join("data");
+        std::fs::write(&file, "data").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        // Only the groups the test runs as can be assigned without privileges
+        let gid = std::fs::metadata(dir.path()).unwrap().gid();
+        assert!(root_mismatch(dir.path(), gid).unwrap());
+
+        set_volume_ownership(dir.path(), gid, FsGroupChangePolicy::Always)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&file).unwrap().mode() & 0o7777, 0o660);
+        assert_eq!(std::fs::metadata(&nested).unwrap().mode() & SETGID, SETGID);
+        assert!(!root_mismatch(dir.path(), gid).unwrap());
+
+        // Once the root matches, OnRootMismatch leaves the rest of the volume alone
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        set_volume_ownership(dir.path(), gid, FsGroupChangePolicy::OnRootMismatch)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&file).unwrap().mode() & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn test_policy_from_pod_setting() {
+        assert_eq!(
+            FsGroupChangePolicy::from_pod_setting(Some("OnRootMismatch")),
+            FsGroupChangePolicy::OnRootMismatch
+        );
+        assert_eq!(
+            FsGroupChangePolicy::from_pod_setting(Some("Always")),
+            FsGroupChangePolicy::Always
+        );
+        assert_eq!(
+            FsGroupChangePolicy::from_pod_setting(None),
+            FsGroupChangePolicy::Always
+        );
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_family = "windows")]
+mod windows_test {
+    use super::*;
+
+    async fn account_can_modify(path: &Path) -> bool {
+        let output = tokio::process::Command::new("icacls")
+            .arg(path)
+            .output()
+            .await
+            .unwrap();
+        let user = std::env::var("USERNAME").unwrap().to_lowercase();
+        let granted: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.contains("(OI)(CI)(M)"))
+            .map(str::to_lowercase)
+            .collect();
+        // The SID is resolved to the account's name, and no other account is granted the rights
+        !granted.is_empty() && granted.iter().all(|line| line.contains(&user))
+    }
+
+    #[tokio::test]
+    async fn test_set_volume_ownership() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+
+        set_volume_ownership(dir.path(), 2000, FsGroupChangePolicy::OnRootMismatch)
+            .await
+            .unwrap();
+        assert!(account_can_modify(dir.path()).await);
+
+        // Directories created afterwards inherit the entry
+        let created = dir.path().join("created");
+        std::fs::create_dir(&created).unwrap();
+        set_volume_ownership(dir.path(), 2000, FsGroupChangePolicy::Always)
+            .await
+            .unwrap();
+        assert!(account_can_modify(&created).await);
+    }
+}
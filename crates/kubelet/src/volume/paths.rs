@@ -0,0 +1,166 @@
+//! Interprets the paths in volumes and volume mounts for the host the Kubelet runs on.
+//!
+//! On Windows, host paths may start with a drive letter (`C:\data`, or `C:/data` as manifests
+//! are often written) or name a UNC share (`\\server\share\data`), and mount paths written for
+//! Windows containers (`C:\data`) are mapped to the forward slashed paths modules see
+//! (`/data`). Elsewhere paths are used as they are written. On every platform, a `subPath` has
+//! to stay within its volume.
+use std::path::{Path, PathBuf};
+
+use k8s_openapi::api::core::v1::VolumeMount;
+
+/// The path on the host of a `hostPath` volume.
+#[cfg(target_family = "windows")]
+pub fn parse_host_path(path: &str) -> anyhow::Result<PathBuf> {
+    use std::path::{Component, Prefix};
+
+    let normalized = PathBuf::from(path.replace('/', "\\"));
+    let mut components = normalized.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Prefix(prefix)), Some(Component::RootDir))
+            if matches!(
+                prefix.kind(),
+                Prefix::Disk(_) | Prefix::VerbatimDisk(_) | Prefix::UNC(..) | Prefix::VerbatimUNC(..)
+            ) =>
+        {
+            Ok(normalized)
+        }
+        // A UNC share without a path beneath it has no root directory component
+        (Some(Component::Prefix(prefix)), None)
+            if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)) =>
+        {
+            Ok(normalized)
+        }
+        _ => Err(anyhow::anyhow!(
+            "Host path {} must be absolute and start with a drive letter, such as C:\\data, or name a UNC share, such as \\\\server\\share",
+            path
+        )),
+    }
+}
+
+/// The path on the host of a `hostPath` volume.
+#[cfg(not(target_family = "windows"))]
+pub fn parse_host_path(path: &str) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(path))
+}
+
+/// The path a volume mount is seen at by a module, including its `subPath`.
+pub fn guest_mount_path(mount: &VolumeMount) -> anyhow::Result<PathBuf> {
+    let mut guest_path = guest_path(&mount.mount_path)?;
+    if let Some(sub_path) = mount.sub_path.as_deref().filter(|p| !p.is_empty()) {
+        guest_path.push(relative_sub_path(sub_path)?);
+    }
+    Ok(guest_path)
+}
+
+#[cfg(target_family = "windows")]
+fn guest_path(mount_path: &str) -> anyhow::Result<PathBuf> {
+    let normalized = mount_path.replace('\\', "/");
+    if normalized.starts_with("//") {
+        anyhow::bail!(
+            "Volumes cannot be mounted at UNC path {}, use a path such as C:\\data",
+            mount_path
+        );
+    }
+    // Modules don't see drives, so a path on a drive is mounted at the same path without it
+    let without_drive = match normalized.as_bytes() {
+        [drive, b':', ..] if drive.is_ascii_alphabetic() => &normalized[2..],
+        _ => normalized.as_str(),
+    };
+    if !without_drive.starts_with('/') {
+        anyhow::bail!("Mount path {} must be absolute", mount_path);
+    }
+    Ok(PathBuf::from(without_drive))
+}
+
+#[cfg(not(target_family = "windows"))]
+fn guest_path(mount_path: &str) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(mount_path))
+}
+
+/// Checks that a `subPath` stays within its volume, as the API server does.
+fn relative_sub_path(sub_path: &str) -> anyhow::Result<&Path> {
+    if sub_path.starts_with('/')
+        || sub_path.split('/').any(|p| p == "..")
+        || escapes_on_windows(sub_path)
+    {
+        anyhow::bail!(
+            "Sub path {} must be a relative path that stays within its volume",
+            sub_path
+        );
+    }
+    Ok(Path::new(sub_path))
+}
+
+/// Whether a `subPath` leaves its volume once backslashes and drive letters are taken into
+/// account.
+#[cfg(target_family = "windows")]
+fn escapes_on_windows(sub_path: &str) -> bool {
+    sub_path.starts_with('\\')
+        || sub_path.split('\\').any(|p| p == "..")
+        || matches!(sub_path.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic())
+}
+
+#[cfg(not(target_family = "windows"))]
+fn escapes_on_windows(_sub_path: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mount(mount_path: &str, sub_path: Option<&str>) -> VolumeMount {
+        VolumeMount {
+            mount_path: mount_path.to_owned(),
+            name: "data".to_owned(),
+            sub_path: sub_path.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sub_path_stays_in_volume() {
+        assert!(guest_mount_path(&mount("/data", Some("nested/dir"))).is_ok());
+        assert!(guest_mount_path(&mount("/data", Some("../etc"))).is_err());
+        assert!(guest_mount_path(&mount("/data", Some("/etc"))).is_err());
+        assert_eq!(
+            guest_mount_path(&mount("/data", Some(""))).unwrap(),
+            PathBuf::from("/data")
+        );
+    }
+
+    #[test]
+    #[cfg(target_family = "windows")]
+    fn test_windows_host_paths() {
+        assert_eq!(
+            parse_host_path("C:/var/data").unwrap(),
+            PathBuf::from("C:\\var\\data")
+        );
+        assert_eq!(
+            parse_host_path("\\\\fileserver\\share\\models").unwrap(),
+            PathBuf::from("\\\\fileserver\\share\\models")
+        );
+        assert!(parse_host_path("//fileserver/share").is_ok());
+        assert!(parse_host_path("C:data").is_err());
+        assert!(parse_host_path("\\data").is_err());
+        assert!(parse_host_path("data").is_err());
+    }
+
+    #[test]
+    #[cfg(target_family = "windows")]
+    fn test_windows_mount_paths() {
+        assert_eq!(
+            guest_mount_path(&mount("C:\\data", Some("nested\\dir"))).unwrap(),
+            PathBuf::from("/data").join("nested\\dir")
+        );
+        assert_eq!(
+            guest_mount_path(&mount("/data", None)).unwrap(),
+            PathBuf::from("/data")
+        );
+        assert!(guest_mount_path(&mount("\\\\server\\share", None)).is_err());
+        assert!(guest_mount_path(&mount("D:data", None)).is_err());
+        assert!(guest_mount_path(&mount("C:\\data", Some("nested\\..\\..\\etc"))).is_err());
+        assert!(guest_mount_path(&mount("C:\\data", Some("D:\\secrets"))).is_err());
+    }
+}
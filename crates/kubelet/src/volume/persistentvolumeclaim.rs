@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use k8s_csi::v1_3_0::node_service_capability::{rpc, Rpc, Type as CapabilityType};
 use k8s_csi::v1_3_0::volume_capability::access_mode::Mode as CSIMode;
@@ -27,9 +28,16 @@ use tracing::log::{info, warn};
 
 use crate::grpc_sock;
 use crate::plugin_watcher::PluginRegistry;
+use crate::pod::dirs::join_name;
+use crate::pod::Pod;
 
 use super::*;
 
+/// How long to wait for the PVC of a generic ephemeral volume to be created and bound
+const EPHEMERAL_CLAIM_TIMEOUT: Duration = Duration::from_secs(120);
+/// How often to check whether the PVC of a generic ephemeral volume has been bound
+const EPHEMERAL_CLAIM_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 /// VolumeError describes the possible error states when mounting persistent volume claims.
 #[derive(Error, Debug)]
 #[allow(clippy::enum_variant_names)]
@@ -169,6 +177,54 @@ impl PvcVolume {
         })?;
 
         let spec = get_pvc_spec(source, &client, namespace).await?;
+        Self::from_claim(vol, source, spec, client, plugin_registry).await
+    }
+
+    /// Creates a new PVC volume from a generic ephemeral volume. The PVC created for the pod from
+    /// the volume claim template is mounted once it has been bound. Passing a non-ephemeral volume
+    /// type, or a pod the PVC wasn't created for, will result in an error
+    pub async fn new_ephemeral(
+        vol: &KubeVolume,
+        pod: &Pod,
+        client: kube::Client,
+        plugin_registry: Option<Arc<PluginRegistry>>,
+    ) -> anyhow::Result<Self> {
+        let plugin_registry = match plugin_registry {
+            Some(p) => p,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "cannot mount volume {}: CSI driver support not implemented",
+                    vol.name
+                ))
+            }
+        };
+
+        let ephemeral = vol.ephemeral.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called an ephemeral volume constructor with a non-ephemeral volume")
+        })?;
+        if ephemeral.volume_claim_template.is_none() {
+            return Err(anyhow::anyhow!(
+                "ephemeral volume {} has no volume claim template",
+                vol.name
+            ));
+        }
+        // The ephemeral volume controller always names the PVC after the pod and the volume
+        let source = PersistentVolumeClaimVolumeSource {
+            claim_name: format!("{}-{}", pod.name(), vol.name),
+            read_only: None,
+        };
+
+        let spec = wait_for_ephemeral_claim(&source.claim_name, pod, &client).await?;
+        Self::from_claim(vol, &source, spec, client, plugin_registry).await
+    }
+
+    async fn from_claim(
+        vol: &KubeVolume,
+        source: &PersistentVolumeClaimVolumeSource,
+        spec: PersistentVolumeClaimSpec,
+        client: kube::Client,
+        plugin_registry: Arc<PluginRegistry>,
+    ) -> anyhow::Result<Self> {
         let csi_client = get_csi_client(&client, &spec, plugin_registry).await?;
         let csi_pv_source = get_csi(&client, source, &spec).await?;
 
@@ -183,18 +239,29 @@ impl PvcVolume {
         })
     }
 
+    /// Returns whether the volume is mounted read-only
+    pub fn read_only(&self) -> bool {
+        self.csi_pv_source.read_only.unwrap_or_default()
+    }
+
     /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
     /// hasn't been mounted yet
     pub fn get_path(&self) -> Option<&Path> {
         self.mounted_path.as_deref()
     }
 
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
     /// Mounts the PVC volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
         let stage_unstage_volume = supports_stage_unstage(&mut self.csi_client).await?;
 
-        let path = base_path.as_ref().join(&self.name);
+        let path = join_name(base_path.as_ref(), &self.name)?;
 
         // we keep this around even if the driver does not support STAGE_UNSTAGE_VOLUME. unmount() still
         // needs it.
@@ -523,6 +590,60 @@ async fn get_pvc_spec(
     Ok(spec)
 }
 
+/// Waits for the PVC of a generic ephemeral volume to be bound, returning its spec. Like the
+/// kubelet, this refuses to use a PVC that isn't owned by the pod, as that means it was created
+/// by something other than the ephemeral volume controller.
+async fn wait_for_ephemeral_claim(
+    claim_name: &str,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<PersistentVolumeClaimSpec> {
+    let pvc_client: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), pod.namespace());
+    let wait = async {
+        loop {
+            let pvc = match pvc_client.get(claim_name).await {
+                Ok(pvc) => Some(pvc),
+                // The PVC is created asynchronously by the ephemeral volume controller
+                Err(kube::Error::Api(e)) if e.code == 404 => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(pvc) = pvc {
+                let owned_by_pod = pvc
+                    .metadata
+                    .owner_references
+                    .iter()
+                    .any(|o| o.controller == Some(true) && o.uid == pod.pod_uid());
+                if !owned_by_pod {
+                    return Err(anyhow::anyhow!(
+                        "PersistentVolumeClaim {} was not created for pod {}",
+                        claim_name,
+                        pod.name()
+                    ));
+                }
+                if let Some(spec) = pvc.spec {
+                    if spec.volume_name.is_some() {
+                        validate(&spec)?;
+                        return Ok(spec);
+                    }
+                }
+            }
+            info!(
+                "Waiting for PersistentVolumeClaim {} of ephemeral volume to be bound",
+                claim_name
+            );
+            tokio::time::sleep(EPHEMERAL_CLAIM_POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(EPHEMERAL_CLAIM_TIMEOUT, wait)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "PersistentVolumeClaim {} of ephemeral volume was not bound in time",
+                claim_name
+            )
+        })?
+}
+
 async fn get_secrets_map(
     secret_ref: Option<SecretReference>,
     client: &kube::Client,
@@ -570,3 +691,174 @@ fn get_access_type(mode: VolumeMode, csi: &CSIPersistentVolumeSource) -> CSIAcce
         }),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::pin_mut;
+    use http::{Request as HttpRequest, Response as HttpResponse, StatusCode};
+    use hyper::Body;
+    use tower_test::mock;
+
+    const POD_UID: &str = "pod-uid";
+
+    fn test_pod() -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "web", "namespace": "default", "uid": POD_UID },
+            "spec": {
+                "containers": [{ "name": "web", "image": "webassembly.azurecr.io/web:v1" }],
+                "volumes": [{
+                    "name": "scratch",
+                    "ephemeral": {
+                        "volumeClaimTemplate": {
+                            "spec": {
+                                "accessModes": ["ReadWriteOnce"],
+                                "resources": { "requests": { "storage": "1Gi" } }
+                            }
+                        }
+                    }
+                }]
+            }
+        }))
+        .unwrap()
+    }
+
+    fn claim(owner_uid: &str, volume_name: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "PersistentVolumeClaim",
+            "metadata": {
+                "name": "web-scratch",
+                "namespace": "default",
+                "ownerReferences": [{
+                    "apiVersion": "v1",
+                    "kind": "Pod",
+                    "name": "web",
+                    "uid": owner_uid,
+                    "controller": true
+                }]
+            },
+            "spec": {
+                "accessModes": ["ReadWriteOnce"],
+                "resources": { "requests": { "storage": "1Gi" } },
+                "volumeMode": "Filesystem",
+                "volumeName": volume_name
+            }
+        })
+    }
+
+    fn not_found() -> serde_json::Value {
+        serde_json::json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": "persistentvolumeclaims \"web-scratch\" not found",
+            "reason": "NotFound",
+            "code": 404
+        })
+    }
+
+    /// A client whose API server answers each request for the claim with the next response.
+    fn mock_client(
+        responses: Vec<(StatusCode, serde_json::Value)>,
+    ) -> (kube::Client, tokio::task::JoinHandle<()>) {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let server = tokio::spawn(async move {
+            pin_mut!(handle);
+            for (status, body) in responses {
+                let (request, send) = handle.next_request().await.expect("service not called");
+                assert_eq!(
+                    request.uri().path(),
+                    "/api/v1/namespaces/default/persistentvolumeclaims/web-scratch"
+                );
+                send.send_response(
+                    HttpResponse::builder()
+                        .status(status)
+                        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                );
+            }
+        });
+        (kube::Client::new(mock_service, "default"), server)
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_ephemeral_claim_waits_until_bound() {
+        tokio::time::pause();
+        let (client, server) = mock_client(vec![
+            (StatusCode::NOT_FOUND, not_found()),
+            (StatusCode::OK, claim(POD_UID, None)),
+            (StatusCode::OK, claim(POD_UID, Some("pv-1"))),
+        ]);
+        let spec = wait_for_ephemeral_claim("web-scratch", &test_pod(), &client)
+            .await
+            .expect("claim should be used once bound");
+        assert_eq!(spec.volume_name.as_deref(), Some("pv-1"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_ephemeral_claim_rejects_claims_of_other_owners() {
+        let (client, server) =
+            mock_client(vec![(StatusCode::OK, claim("other-uid", Some("pv-1")))]);
+        let err = wait_for_ephemeral_claim("web-scratch", &test_pod(), &client)
+            .await
+            .expect_err("claim not created for the pod should be rejected");
+        assert!(err.to_string().contains("was not created for pod web"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_ephemeral_claim_times_out() {
+        tokio::time::pause();
+        let polls = (EPHEMERAL_CLAIM_TIMEOUT.as_secs() / EPHEMERAL_CLAIM_POLL_INTERVAL.as_secs())
+            as usize
+            + 1;
+        let (client, server) = mock_client(vec![(StatusCode::NOT_FOUND, not_found()); polls]);
+        let err = wait_for_ephemeral_claim("web-scratch", &test_pod(), &client)
+            .await
+            .expect_err("claim that is never created should time out");
+        assert!(err.to_string().contains("was not bound in time"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_new_ephemeral_rejects_invalid_volumes() {
+        let pod = test_pod();
+        let client = kube::Client::new(
+            mock::pair::<HttpRequest<Body>, HttpResponse<Body>>().0,
+            "default",
+        );
+        let registry = Some(Arc::new(PluginRegistry::default()));
+
+        let volume = pod.volumes()[0].clone();
+        let err = PvcVolume::new_ephemeral(&volume, &pod, client.clone(), None)
+            .await
+            .expect_err("ephemeral volumes need CSI support");
+        assert!(err
+            .to_string()
+            .contains("CSI driver support not implemented"));
+
+        let not_ephemeral = KubeVolume {
+            name: "scratch".to_owned(),
+            ..Default::default()
+        };
+        let err = PvcVolume::new_ephemeral(&not_ephemeral, &pod, client.clone(), registry.clone())
+            .await
+            .expect_err("only ephemeral volumes should be accepted");
+        assert!(err.to_string().contains("non-ephemeral volume"));
+
+        let mut no_template = volume;
+        no_template
+            .ephemeral
+            .as_mut()
+            .unwrap()
+            .volume_claim_template = None;
+        let err = PvcVolume::new_ephemeral(&no_template, &pod, client, registry)
+            .await
+            .expect_err("ephemeral volumes need a claim template");
+        assert!(err.to_string().contains("has no volume claim template"));
+    }
+}
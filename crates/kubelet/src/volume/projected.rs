@@ -10,6 +10,9 @@ use k8s_openapi::Resource;
 use tracing::warn;
 
 use super::*;
+use crate::dependency::DependencyTracker;
+use crate::pod::dirs::{join_name, join_relative};
+use crate::pod::PodKey;
 
 /// A type that can manage a Secret volume with mounting and unmounting support
 pub struct ProjectedVolume {
@@ -53,7 +56,7 @@ impl ServiceAccountSource {
         )?;
         // Get the token from the API
         let token_resp: TokenRequest = self.client.request(req).await?;
-        let mount_path = path.as_ref().join(&self.file_name);
+        let mount_path = join_relative(path.as_ref(), &self.file_name)?;
 
         let token = token_resp
             .status
@@ -99,12 +102,47 @@ impl ProjectedVolume {
         })
     }
 
+    /// Reads the projected ConfigMaps and Secrets from the tracker's watches on behalf of the
+    /// given pod instead of fetching them, rewriting their files whenever they change.
+    pub fn with_dependency_tracker(mut self, tracker: DependencyTracker, pod: PodKey) -> Self {
+        self.volumes = self
+            .volumes
+            .into_iter()
+            .map(|v| match v {
+                VolumeRef::ConfigMap(c) => {
+                    VolumeRef::ConfigMap(c.with_dependency_tracker(tracker.clone(), pod.clone()))
+                }
+                VolumeRef::Secret(s) => {
+                    VolumeRef::Secret(s.with_dependency_tracker(tracker.clone(), pod.clone()))
+                }
+                other => other,
+            })
+            .collect();
+        self
+    }
+
     /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
     /// hasn't been mounted yet
     pub fn get_path(&self) -> Option<&Path> {
         self.mounted_path.as_deref()
     }
 
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
+    /// Rewrites the projected Downward API files from an updated version of the pod.
+    pub async fn update_pod(&mut self, pod: &Pod) -> anyhow::Result<()> {
+        for volume in self.volumes.iter_mut() {
+            if let VolumeRef::DownwardApi(d) = volume {
+                d.update_pod(pod).await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Mounts the Secret volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     #[async_recursion::async_recursion]
@@ -112,7 +150,7 @@ impl ProjectedVolume {
         &mut self,
         base_path: P,
     ) -> anyhow::Result<()> {
-        let path = base_path.as_ref().join(&self.vol_name);
+        let path = join_name(base_path.as_ref(), &self.vol_name)?;
         tokio::fs::create_dir_all(&path).await?;
 
         let vol_futures =
@@ -156,6 +194,13 @@ impl ProjectedVolume {
     /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
     /// hasn't been mounted will log a warning, but otherwise not error
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        for v in self.volumes.iter_mut() {
+            match v {
+                VolumeRef::ConfigMap(c) => c.stop_refresh(),
+                VolumeRef::Secret(s) => s.stop_refresh(),
+                _ => (),
+            }
+        }
         match self.mounted_path.take() {
             Some(p) => {
                 //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
@@ -2,9 +2,13 @@ use std::path::Path;
 
 use k8s_openapi::api::core::v1::{KeyToPath, Secret, Volume as KubeVolume};
 use k8s_openapi::ByteString;
+use tokio::task::JoinHandle;
 use tracing::warn;
 
 use super::*;
+use crate::dependency::DependencyTracker;
+use crate::pod::dirs::{join_name, join_relative};
+use crate::pod::PodKey;
 
 /// A type that can manage a Secret volume with mounting and unmounting support
 pub struct SecretVolume {
@@ -13,6 +17,8 @@ pub struct SecretVolume {
     client: kube::Api<Secret>,
     items: Vec<KeyToPath>,
     mounted_path: Option<PathBuf>,
+    tracker: Option<(DependencyTracker, PodKey)>,
+    refresh: Option<JoinHandle<()>>,
 }
 
 impl SecretVolume {
@@ -31,19 +37,34 @@ impl SecretVolume {
             client: Api::namespaced(client, namespace),
             items: sec_source.items.clone(),
             mounted_path: None,
+            tracker: None,
+            refresh: None,
         })
     }
 
+    /// Reads the Secret from the tracker's watch on behalf of the given pod instead of fetching
+    /// it. Once mounted, the files are rewritten whenever the Secret changes.
+    pub fn with_dependency_tracker(mut self, tracker: DependencyTracker, pod: PodKey) -> Self {
+        self.tracker = Some((tracker, pod));
+        self
+    }
+
     /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
     /// hasn't been mounted yet
     pub fn get_path(&self) -> Option<&Path> {
         self.mounted_path.as_deref()
     }
 
+    /// Takes over a mount at the given path that a previous run of the Kubelet left behind, so
+    /// that it can be unmounted
+    pub(crate) fn adopt_mount(&mut self, path: PathBuf) {
+        self.mounted_path = Some(path);
+    }
+
     /// Mounts the Secret volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let path = base_path.as_ref().join(&self.vol_name);
+        let path = join_name(base_path.as_ref(), &self.vol_name)?;
         tokio::fs::create_dir_all(&path).await?;
 
         self.mount_at(path.clone()).await?;
@@ -61,31 +82,51 @@ impl SecretVolume {
     /// and already exist. This method will not set any permissions, so the caller is responsible
     /// for setting permissions on the directory
     pub(crate) async fn mount_at(&mut self, path: PathBuf) -> anyhow::Result<()> {
-        let secret = self.client.get(&self.sec_name).await?;
-
-        let data = secret.data;
-        let data = data
-            .into_iter()
-            .filter_map(
-                |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
-                    ItemMount::DoNotMount => None,
-                },
-            )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        futures::future::join_all(data)
-            .await
-            .into_iter()
-            .collect::<tokio::io::Result<_>>()?;
+        let (secret, updates) = match &self.tracker {
+            Some((tracker, pod)) => (
+                tracker.secret(pod, &self.sec_name).await?,
+                Some(tracker.secret_updates(pod, &self.sec_name)),
+            ),
+            None => (self.client.get(&self.sec_name).await?, None),
+        };
+        let files = secret_files(&path, secret, &self.items)?;
+        let mut written = replace_files(&HashSet::new(), files).await?;
+
+        if let Some(mut updates) = updates {
+            let (items, path) = (self.items.clone(), path.clone());
+            self.refresh = Some(tokio::spawn(async move {
+                while let Some(secret) = updates.changed().await {
+                    let refreshed = refresh_read_only_dir(&path, async {
+                        let files = secret_files(&path, secret, &items)?;
+                        replace_files(&written, files).await
+                    })
+                    .await;
+                    match refreshed {
+                        Ok(now_written) => written = now_written,
+                        Err(e) => {
+                            warn!(error = %e, path = %path.display(), "Unable to refresh Secret volume")
+                        }
+                    }
+                }
+            }));
+        }
 
         self.mounted_path = Some(path);
 
         Ok(())
     }
 
+    /// Stops rewriting the mounted files when the Secret changes
+    pub(crate) fn stop_refresh(&mut self) {
+        if let Some(refresh) = self.refresh.take() {
+            refresh.abort();
+        }
+    }
+
     /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
     /// hasn't been mounted will log a warning, but otherwise not error
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        self.stop_refresh();
         match self.mounted_path.take() {
             Some(p) => {
                 // Because things are set to read only, we need to remove the read only flag so it
@@ -108,3 +149,22 @@ impl SecretVolume {
         Ok(())
     }
 }
+
+/// The files the Secret's keys are mounted as in the volume directory at `path`.
+fn secret_files(
+    path: &Path,
+    secret: Secret,
+    items: &[KeyToPath],
+) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    secret
+        .data
+        .into_iter()
+        .filter_map(
+            |(key, ByteString(data))| match mount_setting_for(&key, items) {
+                ItemMount::MountAt(mount_path) => Some((join_relative(path, &mount_path), data)),
+                ItemMount::DoNotMount => None,
+            },
+        )
+        .map(|(file_path, data)| Ok((file_path?, data)))
+        .collect()
+}
@@ -1,20 +1,38 @@
 //! Server is an HTTP(S) server for answering Kubelet callbacks.
 //!
-//! Logs and exec calls are the main things that a server should handle.
+//! Logs and exec calls are the main things that a server should handle. Clients attach to
+//! containers over a WebSocket using the `channel.k8s.io` subprotocol, in which the first byte of
+//! every message is the stream it belongs to. If an admin token is
+//! configured, the server also has admin endpoints for operators, which require the token as a
+//! bearer token.
 
 use crate::config::ServerConfig;
 use crate::log::{Options, Sender};
 use crate::provider::{NotImplementedError, Provider};
+use crate::store::Store;
+use anyhow::Context;
+use futures::{SinkExt, StreamExt};
 use http::status::StatusCode;
 use http::Response;
+use hyper::body::HttpBody;
 use hyper::Body;
+use oci_distribution::Reference;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, instrument, warn};
+use warp::ws::{Message, WebSocket, Ws};
 use warp::Filter;
 
 const PING: &str = "this is the Krustlet HTTP server";
 
+/// The streams of an attached WebSocket, identified by the first byte of each message
+const STDIN_CHANNEL: u8 = 0;
+const STDOUT_CHANNEL: u8 = 1;
+const ERROR_CHANNEL: u8 = 3;
+/// The versions of the streaming subprotocol the server speaks, most preferred first
+const CHANNEL_PROTOCOLS: &[&str] = &["v4.channel.k8s.io", "channel.k8s.io"];
+
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
@@ -42,7 +60,75 @@ pub(crate) async fn start<T: Provider>(
             post_exec(provider, namespace, pod, container)
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let attach_provider = provider.clone();
+    let attach = warp::path!("attach" / String / String / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace,
+                  pod,
+                  container,
+                  query: HashMap<String, String>,
+                  protocols: Option<String>,
+                  ws: Ws| {
+                let provider = attach_provider.clone();
+                let stdin = wants_stdin(&query);
+                let reply = ws.on_upgrade(move |socket| {
+                    attach_session(provider, namespace, pod, container, stdin, socket)
+                });
+                warp::reply::with_header(
+                    reply,
+                    "sec-websocket-protocol",
+                    channel_protocol(protocols.as_deref()),
+                )
+            },
+        );
+
+    let metrics = warp::get().and(warp::path("metrics")).and_then(get_metrics);
+
+    let stats_provider = provider.clone();
+    let stats = warp::get()
+        .and(warp::path!("stats" / "summary"))
+        .and_then(move || {
+            let provider = stats_provider.clone();
+            get_stats_summary(provider)
+        });
+
+    let admin_token = match &config.admin_token_file {
+        Some(path) => Some(Arc::new(read_admin_token(path)?)),
+        None => None,
+    };
+
+    let images = image_routes(provider.clone(), admin_token.clone());
+
+    let runtime_provider = provider.clone();
+    let runtime_token = admin_token.clone();
+    let runtime = warp::get()
+        .and(warp::path!("debug" / "runtime"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization| {
+            let provider = runtime_provider.clone();
+            let admin_token = runtime_token.clone();
+            get_runtime_info(provider, admin_token, authorization)
+        });
+
+    let states_token = admin_token.clone();
+    let states = warp::get()
+        .and(warp::path!("debug" / "states"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization| get_state_histories(states_token.clone(), authorization));
+
+    let routes = ping
+        .or(health)
+        .or(metrics)
+        .or(stats)
+        .or(logs)
+        .or(exec)
+        .or(attach)
+        .or(images)
+        .or(runtime)
+        .or(states);
 
     warp::serve(routes)
         .tls()
@@ -87,6 +173,35 @@ async fn get_container_logs<T: Provider>(
     }
 }
 
+/// Get the Kubelet's metrics in the Prometheus text format.
+///
+/// Implements the kubelet path /metrics
+async fn get_metrics() -> Result<Response<Body>, Infallible> {
+    match crate::metrics::render() {
+        Ok(metrics) => Ok(Response::new(metrics.into())),
+        Err(e) => {
+            error!(error = %e, "Error rendering metrics");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// Get the disk usage of the node's pods.
+///
+/// Implements the kubelet path /stats/summary
+async fn get_stats_summary<T: Provider>(provider: Arc<T>) -> Result<Response<Body>, Infallible> {
+    match provider.disk_usage_tracker() {
+        Some(tracker) => Ok(json_response(tracker.summary())),
+        None => Ok(return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            "Stats not implemented in provider.".to_owned(),
+        )),
+    }
+}
+
 /// Run a pod exec command and get the output
 ///
 /// Implements the kubelet path /exec/{namespace}/{pod}/{container}
@@ -102,8 +217,560 @@ async fn post_exec<T: Provider>(
     ))
 }
 
+/// Attach a WebSocket client to a running container.
+///
+/// Implements the kubelet path /attach/{namespace}/{pod}/{container}
+#[instrument(level = "info", skip(provider, socket))]
+async fn attach_session<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    stdin: bool,
+    socket: WebSocket,
+) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (body_sender, mut output) = Body::channel();
+    // Attaching only shows what the container writes from now on
+    let sender = Sender::new(
+        body_sender,
+        Options {
+            tail: Some(0),
+            follow: true,
+            previous: false,
+            timestamps: false,
+            since: None,
+            since_time: None,
+            limit_bytes: None,
+        },
+    );
+    let (stdin_tx, stdin_rx) = tokio::sync::mpsc::channel(16);
+    let stdin_rx = if stdin { Some(stdin_rx) } else { None };
+    if let Err(e) = provider
+        .attach(namespace, pod, container, stdin_rx, sender)
+        .await
+    {
+        error!(error = %e, "Error attaching to container");
+        let message = if e.is::<NotImplementedError>() {
+            "Attach not implemented in provider.".to_owned()
+        } else {
+            format!("Server error: {}", e)
+        };
+        let _ = ws_tx
+            .send(Message::binary(channel_frame(
+                ERROR_CHANNEL,
+                message.as_bytes(),
+            )))
+            .await;
+        let _ = ws_tx.close().await;
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            message = ws_rx.next() => match message {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => {
+                    if let Some((&STDIN_CHANNEL, data)) = message.as_bytes().split_first() {
+                        if stdin_tx.send(data.to_vec()).await.is_err() {
+                            debug!("Container stdin is closed");
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!(error = %e, "Error reading from attached client");
+                    break;
+                }
+                None => break,
+            },
+            chunk = output.data() => match chunk {
+                Some(Ok(chunk)) => {
+                    let message = Message::binary(channel_frame(STDOUT_CHANNEL, &chunk));
+                    if ws_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                // The container exited
+                _ => break,
+            },
+        }
+    }
+    // Dropping the sender detaches from stdin
+    drop(stdin_tx);
+    let _ = ws_tx.close().await;
+}
+
+/// Whether an attach request asks for stdin, which clients send as either `stdin=true` or
+/// `input=1`
+fn wants_stdin(query: &HashMap<String, String>) -> bool {
+    ["stdin", "input"].iter().any(|key| {
+        matches!(
+            query.get(*key).map(String::as_str),
+            Some("true") | Some("1")
+        )
+    })
+}
+
+/// Picks the streaming subprotocol to answer a WebSocket request with.
+fn channel_protocol(requested: Option<&str>) -> &'static str {
+    let requested: Vec<&str> = requested
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .collect();
+    CHANNEL_PROTOCOLS
+        .iter()
+        .copied()
+        .find(|p| requested.contains(p))
+        .unwrap_or(CHANNEL_PROTOCOLS[CHANNEL_PROTOCOLS.len() - 1])
+}
+
+fn channel_frame(channel: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.push(channel);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// The admin routes that list and delete the modules cached by the provider's store.
+fn image_routes<T: Provider>(
+    provider: Arc<T>,
+    admin_token: Option<Arc<String>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list_images_provider = provider.clone();
+    let list_images_token = admin_token.clone();
+    let list_images = warp::get()
+        .and(warp::path!("images"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |authorization| {
+            let provider = list_images_provider.clone();
+            let admin_token = list_images_token.clone();
+            get_images(provider, admin_token, authorization)
+        });
+
+    let delete_image = warp::delete()
+        .and(warp::path("images"))
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |image_ref: warp::path::Tail, authorization| {
+            let provider = provider.clone();
+            let admin_token = admin_token.clone();
+            delete_image(
+                provider,
+                admin_token,
+                authorization,
+                image_ref.as_str().to_owned(),
+            )
+        });
+
+    list_images.or(delete_image)
+}
+
+/// List the modules cached by the provider's store.
+///
+/// Implements the admin path GET /images
+#[instrument(level = "info", skip(provider, admin_token, authorization))]
+async fn get_images<T: Provider>(
+    provider: Arc<T>,
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    let store = match admin_store(provider.as_ref(), admin_token, authorization) {
+        Ok(store) => store,
+        Err(response) => return Ok(response),
+    };
+    match store.list().await {
+        Ok(modules) => {
+            let modules: Vec<serde_json::Value> = modules
+                .iter()
+                .map(|m| {
+                    serde_json::json!({
+                        "image": m.image_ref.whole(),
+                        "digest": m.digest,
+                        "size": m.size,
+                    })
+                })
+                .collect();
+            Ok(json_response(serde_json::Value::from(modules)))
+        }
+        Err(e) => {
+            error!(error = %e, "Error listing cached modules");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// Delete a module from the provider's store, so that it is pulled again the next time a pod
+/// needs it.
+///
+/// Implements the admin path DELETE /images/{image ref}
+#[instrument(level = "info", skip(provider, admin_token, authorization))]
+async fn delete_image<T: Provider>(
+    provider: Arc<T>,
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+    image_ref: String,
+) -> Result<Response<Body>, Infallible> {
+    let store = match admin_store(provider.as_ref(), admin_token, authorization) {
+        Ok(store) => store,
+        Err(response) => return Ok(response),
+    };
+    let reference: Reference = match image_ref.parse() {
+        Ok(r) => r,
+        Err(e) => {
+            return Ok(return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid image ref {}: {}", image_ref, e),
+            ))
+        }
+    };
+    match store.delete(&reference).await {
+        Ok(true) => Ok(return_with_code(
+            StatusCode::OK,
+            format!("Deleted image ref {}", reference),
+        )),
+        Ok(false) => Ok(return_with_code(
+            StatusCode::NOT_FOUND,
+            format!("Image ref {} is not cached", reference),
+        )),
+        Err(e) => {
+            error!(error = %e, "Error deleting cached module");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// Describe the provider's runtime handles for every pod, for support bundles.
+///
+/// Implements the admin path GET /debug/runtime
+#[instrument(level = "info", skip(provider, admin_token, authorization))]
+async fn get_runtime_info<T: Provider>(
+    provider: Arc<T>,
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    if let Err(response) = authorize_admin(admin_token, authorization) {
+        return Ok(response);
+    }
+    match provider.runtime_info().await {
+        Ok(pods) => {
+            let now = chrono::Utc::now();
+            let pods: Vec<serde_json::Value> = pods.iter().map(|p| p.to_json(now)).collect();
+            Ok(json_response(serde_json::json!({ "pods": pods })))
+        }
+        Err(e) if e.is::<NotImplementedError>() => Ok(return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            "Runtime info not implemented in provider.".to_owned(),
+        )),
+        Err(e) => {
+            error!(error = %e, "Error describing runtime handles");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// Describe the most recent transitions of the state machines of every pod, for support bundles.
+///
+/// Implements the admin path GET /debug/states
+#[instrument(level = "info", skip(admin_token, authorization))]
+async fn get_state_histories(
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    if let Err(response) = authorize_admin(admin_token, authorization) {
+        return Ok(response);
+    }
+    Ok(json_response(crate::state::replay::histories()))
+}
+
+/// Checks that an admin request is authorized, returning the provider's store if it is or the
+/// response to send if it isn't.
+fn admin_store<T: Provider>(
+    provider: &T,
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+) -> Result<Arc<dyn Store + Send + Sync>, Response<Body>> {
+    authorize_admin(admin_token, authorization)?;
+    provider.store().ok_or_else(|| {
+        return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            "Provider does not have a module store.".to_owned(),
+        )
+    })
+}
+
+/// Checks that an admin request is authorized, returning the response to send if it isn't.
+fn authorize_admin(
+    admin_token: Option<Arc<String>>,
+    authorization: Option<String>,
+) -> Result<(), Response<Body>> {
+    let admin_token = admin_token.ok_or_else(|| {
+        return_with_code(
+            StatusCode::NOT_FOUND,
+            "Admin endpoints are not enabled.".to_owned(),
+        )
+    })?;
+    let authorized = authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .map(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+        .unwrap_or(false);
+    if !authorized {
+        return Err(return_with_code(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn read_admin_token(path: &std::path::Path) -> anyhow::Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read admin token file {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("Admin token file {} is empty", path.display());
+    }
+    Ok(token.to_owned())
+}
+
+/// Compares tokens without revealing how much of them matched through the time taken.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn json_response(value: serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(value.to_string().into());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
 fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
     let mut response = Response::new(body.into());
     *response.status_mut() = code;
     response
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::container::PullPolicy;
+    use crate::plugin_watcher::PluginRegistry;
+    use crate::pod::{Pod, Status};
+    use crate::provider::{DevicePluginSupport, PluginSupport};
+    use crate::resources::DeviceManager;
+    use crate::store::CachedModule;
+    use krator::ObjectState;
+    use oci_distribution::secrets::RegistryAuth;
+    use tokio::sync::RwLock;
+
+    /// A store that holds the modules it lists in memory
+    struct MockStore(std::sync::Mutex<Vec<CachedModule>>);
+
+    #[async_trait::async_trait]
+    impl Store for MockStore {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("image ref {} is not cached", image_ref)
+        }
+
+        async fn list(&self) -> anyhow::Result<Vec<CachedModule>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|m| CachedModule {
+                    image_ref: m.image_ref.clone(),
+                    digest: m.digest.clone(),
+                    size: m.size,
+                })
+                .collect())
+        }
+
+        async fn delete(&self, image_ref: &Reference) -> anyhow::Result<bool> {
+            let mut modules = self.0.lock().unwrap();
+            let before = modules.len();
+            modules.retain(|m| m.image_ref.whole() != image_ref.whole());
+            Ok(modules.len() != before)
+        }
+    }
+
+    struct MockProvider {
+        store: Arc<MockStore>,
+    }
+
+    struct ProviderState;
+
+    impl PluginSupport for ProviderState {
+        fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+            None
+        }
+    }
+
+    impl DevicePluginSupport for ProviderState {
+        fn device_plugin_manager(&self) -> Option<Arc<DeviceManager>> {
+            None
+        }
+    }
+
+    struct PodState;
+
+    #[async_trait::async_trait]
+    impl ObjectState for PodState {
+        type Manifest = Pod;
+        type Status = Status;
+        type SharedState = ProviderState;
+        async fn async_drop(self, _provider_state: &mut ProviderState) {}
+    }
+
+    #[async_trait::async_trait]
+    impl Provider for MockProvider {
+        type ProviderState = ProviderState;
+        type InitialState = crate::pod::state::Stub;
+        type TerminatedState = crate::pod::state::Stub;
+        type PodState = PodState;
+
+        const ARCH: &'static str = "mock";
+
+        async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<Self::PodState> {
+            Ok(PodState)
+        }
+
+        fn provider_state(&self) -> krator::SharedState<ProviderState> {
+            Arc::new(RwLock::new(ProviderState))
+        }
+
+        fn store(&self) -> Option<Arc<dyn Store + Send + Sync>> {
+            Some(self.store.clone())
+        }
+
+        async fn logs(
+            &self,
+            _namespace: String,
+            _pod: String,
+            _container: String,
+            _sender: Sender,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn mock_provider() -> Arc<MockProvider> {
+        let module = |image_ref: &str| CachedModule {
+            image_ref: image_ref.parse().unwrap(),
+            digest: Some("sha256:123".to_owned()),
+            size: 3,
+        };
+        Arc::new(MockProvider {
+            store: Arc::new(MockStore(std::sync::Mutex::new(vec![
+                module("localhost:5000/app:v1"),
+                module("localhost:5000/team/app:v2"),
+            ]))),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_image_routes_require_the_admin_token() {
+        let routes = image_routes(mock_provider(), None);
+        let res = warp::test::request()
+            .path("/images")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let routes = image_routes(mock_provider(), Some(Arc::new("secret".to_owned())));
+        for authorization in &[None, Some("secret"), Some("Bearer wrong")] {
+            let mut list = warp::test::request().path("/images");
+            let mut delete = warp::test::request()
+                .method("DELETE")
+                .path("/images/localhost:5000/app:v1");
+            if let Some(authorization) = authorization {
+                list = list.header("authorization", *authorization);
+                delete = delete.header("authorization", *authorization);
+            }
+            assert_eq!(list.reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                delete.reply(&routes).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_image_routes_list_and_delete_cached_modules() {
+        let provider = mock_provider();
+        let routes = image_routes(provider.clone(), Some(Arc::new("secret".to_owned())));
+        let list = || {
+            warp::test::request()
+                .path("/images")
+                .header("authorization", "Bearer secret")
+        };
+        let delete = |image_ref: &str| {
+            warp::test::request()
+                .method("DELETE")
+                .path(&format!("/images/{}", image_ref))
+                .header("authorization", "Bearer secret")
+        };
+
+        let res = list().reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            listed,
+            serde_json::json!([
+                {"image": "localhost:5000/app:v1", "digest": "sha256:123", "size": 3},
+                {"image": "localhost:5000/team/app:v2", "digest": "sha256:123", "size": 3},
+            ])
+        );
+
+        // Image refs with nested repositories are taken from the rest of the path
+        let res = delete("localhost:5000/team/app:v2").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = delete("localhost:5000/team/app:v2").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = delete("localhost:5000/Not/A/Ref").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = list().reply(&routes).await;
+        let listed: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_attach_parameters() {
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert!(wants_stdin(&query(&[("stdin", "true")])));
+        assert!(wants_stdin(&query(&[("input", "1")])));
+        assert!(!wants_stdin(&query(&[("stdout", "true")])));
+
+        assert_eq!(
+            channel_protocol(Some("channel.k8s.io, v4.channel.k8s.io")),
+            "v4.channel.k8s.io"
+        );
+        assert_eq!(channel_protocol(None), "channel.k8s.io");
+        assert_eq!(channel_frame(STDOUT_CHANNEL, b"hi"), vec![1, b'h', b'i']);
+    }
+}
@@ -3,10 +3,12 @@
 //! *Note*: This client is very feature poor. We hope to expand this to be a complete
 //! OCI distribution client in the future.
 
+use crate::compression::{copy_decompressed, Compression};
 use crate::errors::*;
 use crate::manifest::{
-    OciDescriptor, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
-    IMAGE_MANIFEST_MEDIA_TYPE,
+    is_nondistributable, OciDescriptor, OciImageIndex, OciIndexEntry, OciManifest, Versioned,
+    IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MANIFEST_MEDIA_TYPE,
 };
 use crate::secrets::RegistryAuth;
 use crate::secrets::*;
@@ -22,6 +24,8 @@ use serde::Deserialize;
 use sha2::Digest;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tracing::{debug, warn};
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
@@ -88,6 +92,67 @@ impl ImageLayer {
     }
 }
 
+/// Identifies a set of credentials without keeping them.
+fn credential_digest(authentication: &RegistryAuth) -> String {
+    match authentication {
+        RegistryAuth::Anonymous => "anonymous".to_owned(),
+        RegistryAuth::Basic(username, password) => {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(username.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(password.as_bytes());
+            format!("{:x}", hasher.finalize())
+        }
+    }
+}
+
+/// The pull quota a registry reported in its `RateLimit-Limit` and `RateLimit-Remaining`
+/// response headers, as sent by Docker Hub.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The number of requests allowed in each window
+    pub limit: u64,
+    /// The number of requests left in the current window
+    pub remaining: u64,
+    /// The length of the window, if the registry reported it
+    pub window: Option<Duration>,
+}
+
+impl RateLimit {
+    /// Parses the rate limit from response headers. Returns `None` if the registry did not
+    /// send them.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let (limit, window) = parse_rate_limit_header(headers.get("ratelimit-limit")?)?;
+        let (remaining, _) = parse_rate_limit_header(headers.get("ratelimit-remaining")?)?;
+        Some(RateLimit {
+            limit,
+            remaining,
+            window,
+        })
+    }
+
+    /// Whether no more than `fraction` of the quota remains.
+    pub fn is_nearly_exhausted(&self, fraction: f64) -> bool {
+        (self.remaining as f64) <= (self.limit as f64) * fraction
+    }
+}
+
+/// How long a rate limit is assumed to last when the registry didn't report its window.
+const DEFAULT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Parses a header value of the form `100;w=21600`.
+fn parse_rate_limit_header(
+    value: &reqwest::header::HeaderValue,
+) -> Option<(u64, Option<Duration>)> {
+    let mut parts = value.to_str().ok()?.split(';');
+    let count = parts.next()?.trim().parse().ok()?;
+    let window = parts
+        .filter_map(|p| p.trim().strip_prefix("w="))
+        .find_map(|w| w.parse().ok())
+        .map(Duration::from_secs);
+    Some((count, window))
+}
+
 /// The OCI client connects to an OCI registry and fetches OCI images.
 ///
 /// An OCI registry is a container registry that adheres to the OCI Distribution
@@ -106,6 +171,10 @@ impl ImageLayer {
 pub struct Client {
     config: ClientConfig,
     tokens: HashMap<String, RegistryTokenType>,
+    /// The credentials each registry's token was obtained with, identified by digest
+    token_credentials: HashMap<String, String>,
+    /// The rate limit each registry last reported, and when it reported it
+    rate_limits: RwLock<HashMap<String, (RateLimit, Instant)>>,
     client: reqwest::Client,
 }
 
@@ -134,6 +203,10 @@ impl TryFrom<ClientConfig> for Client {
             }
         };
 
+        if let Some(timeout) = config.connect_timeout {
+            client_builder = client_builder.connect_timeout(timeout);
+        }
+
         for c in &config.extra_root_certificates {
             let cert = match c.encoding {
                 CertificateEncoding::Der => reqwest::Certificate::from_der(c.data.as_slice())?,
@@ -145,6 +218,8 @@ impl TryFrom<ClientConfig> for Client {
         Ok(Self {
             config,
             tokens: HashMap::new(),
+            token_credentials: HashMap::new(),
+            rate_limits: RwLock::new(HashMap::new()),
             client: client_builder.build()?,
         })
     }
@@ -159,6 +234,8 @@ impl Client {
             Self {
                 config,
                 tokens: HashMap::new(),
+                token_credentials: HashMap::new(),
+                rate_limits: RwLock::new(HashMap::new()),
                 client: reqwest::Client::new(),
             }
         })
@@ -169,19 +246,80 @@ impl Client {
         Self::new(config_source.client_config())
     }
 
+    /// Get the most recent rate limit reported by the given registry, if it reported one whose
+    /// window hasn't passed yet.
+    pub fn rate_limit(&self, registry: &str) -> Option<RateLimit> {
+        let limits = self.rate_limits.read().ok()?;
+        let (rate_limit, recorded) = limits.get(registry)?;
+        // The quota is reset once the window it was counted in is over
+        let window = rate_limit.window.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW);
+        if recorded.elapsed() >= window {
+            None
+        } else {
+            Some(rate_limit.clone())
+        }
+    }
+
+    /// Records the rate limit reported in the headers of a manifest response. Being rate
+    /// limited means there is no quota left, even if the registry didn't say so.
+    fn record_rate_limit(&self, image: &Reference, res: &reqwest::Response) {
+        let mut rate_limit = RateLimit::from_headers(res.headers());
+        if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let limit = rate_limit.as_ref().map(|r| r.limit).unwrap_or(0);
+            let window = rate_limit.as_ref().and_then(|r| r.window);
+            rate_limit = Some(RateLimit {
+                limit,
+                remaining: 0,
+                window,
+            });
+        }
+        if let (Some(rate_limit), Ok(mut limits)) = (rate_limit, self.rate_limits.write()) {
+            debug!(
+                registry = image.registry(),
+                ?rate_limit,
+                "Registry reported a rate limit"
+            );
+            limits.insert(image.registry().to_owned(), (rate_limit, Instant::now()));
+        }
+    }
+
     /// Pull an image and return the bytes
     ///
     /// The client will check if it's already been authenticated and if
     /// not will attempt to do.
+    ///
+    /// If the config sets a pull deadline, the pull fails with a
+    /// [`tokio::time::error::Elapsed`] error once it is exceeded.
     pub async fn pull(
         &mut self,
         image: &Reference,
         auth: &RegistryAuth,
         accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<ImageData> {
+        match self.config.pull_deadline {
+            Some(deadline) => {
+                tokio::time::timeout(deadline, self._pull(image, auth, accepted_media_types))
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "pull of image {} exceeded deadline of {:?}",
+                            image, deadline
+                        )
+                    })?
+            }
+            None => self._pull(image, auth, accepted_media_types).await,
+        }
+    }
+
+    async fn _pull(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
     ) -> anyhow::Result<ImageData> {
         debug!("Pulling image: {:?}", image);
 
-        if !self.tokens.contains_key(&self.get_registry(image)) {
+        if !self.is_authenticated(image, auth) {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
@@ -190,18 +328,32 @@ impl Client {
         self.validate_layers(&manifest, accepted_media_types)
             .await?;
 
-        let layers = manifest.layers.into_iter().map(|layer| {
-            // This avoids moving `self` which is &mut Self
-            // into the async block. We only want to capture
-            // as &Self
-            let this = &self;
-            async move {
-                let mut out: Vec<u8> = Vec::new();
-                debug!("Pulling image layer");
-                this.pull_layer(image, &layer.digest, &mut out).await?;
-                Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
-            }
-        });
+        let layers = manifest
+            .layers
+            .into_iter()
+            .filter(|layer| {
+                let skip = is_nondistributable(&layer.media_type);
+                if skip {
+                    debug!(
+                        digest = %layer.digest,
+                        media_type = %layer.media_type,
+                        "Skipping nondistributable image layer"
+                    );
+                }
+                !skip
+            })
+            .map(|layer| {
+                // This avoids moving `self` which is &mut Self
+                // into the async block. We only want to capture
+                // as &Self
+                let this = &self;
+                async move {
+                    let mut out: Vec<u8> = Vec::new();
+                    debug!("Pulling image layer");
+                    this._pull_blob(image, &layer.digest, &mut out).await?;
+                    Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
+                }
+            });
 
         let layers = future::try_join_all(layers).await?;
 
@@ -231,7 +383,7 @@ impl Client {
     ) -> anyhow::Result<String> {
         debug!("Pushing image: {:?}", image_ref);
 
-        if !self.tokens.contains_key(&self.get_registry(&image_ref)) {
+        if !self.is_authenticated(image_ref, auth) {
             self.auth(image_ref, auth, &RegistryOperation::Push).await?;
         }
 
@@ -277,25 +429,22 @@ impl Client {
         operation: &RegistryOperation,
     ) -> anyhow::Result<()> {
         debug!("Authorizing for image: {:?}", image);
+        // A token obtained with other credentials must not be used for these ones, even if
+        // the registry turns out not to need any
+        let registry = self.get_registry(image);
+        self.tokens.remove(&registry);
+        self.token_credentials
+            .insert(registry, credential_digest(authentication));
         // The version request will tell us where to go.
         let url = format!(
             "{}://{}/v2/",
             self.config.protocol.scheme_for(&self.get_registry(image)),
             self.get_registry(&image)
         );
-        let res = self.client.get(&url).send().await?;
-        let dist_hdr = match res.headers().get(reqwest::header::WWW_AUTHENTICATE) {
-            Some(h) => h,
+        let res = self.read(self.client.get(&url).send()).await?;
+        let (realm, service, challenge_scope) = match strongest_challenge(res.headers())? {
             None => return Ok(()),
-        };
-
-        let auth = WwwAuthenticate::parse_header(&dist_hdr.as_bytes().into())?;
-        // If challenge_opt is not set it means that no challenge was present, even though the header
-        // was present.
-        let challenge_opt = match auth.get::<BearerChallenge>() {
-            Some(co) => co,
-            None => {
-                // Fall back to HTTP Basic Auth
+            Some(AuthChallenge::Basic) => {
                 if let RegistryAuth::Basic(username, password) = authentication {
                     self.tokens.insert(
                         self.get_registry(image),
@@ -304,33 +453,88 @@ impl Client {
                 }
                 return Ok(());
             }
+            Some(AuthChallenge::Bearer {
+                realm,
+                service,
+                scope,
+            }) => (realm, service, scope),
         };
 
         // Allow for either push or pull authentication
-        let scope = match operation {
+        let mut scopes = vec![match operation {
             RegistryOperation::Pull => format!("repository:{}:pull", image.repository()),
             RegistryOperation::Push => format!("repository:{}:pull,push", image.repository()),
+        }];
+        // Some registries expect the scope they challenged with to be requested as well
+        if let Some(s) = challenge_scope {
+            if !scopes.contains(&s) {
+                scopes.push(s);
+            }
+        }
+
+        let token = match self
+            .request_token(&realm, service.as_deref(), &scopes, authentication)
+            .await
+        {
+            Ok(token) => token,
+            Err(e)
+                if is_authentication_failure(&e)
+                    && !matches!(authentication, RegistryAuth::Anonymous) =>
+            {
+                // The credentials may have been rejected for an image that is public anyway
+                warn!(
+                    "Credentials were rejected for image '{:?}', falling back to anonymous authentication",
+                    image
+                );
+                self.request_token(
+                    &realm,
+                    service.as_deref(),
+                    &scopes,
+                    &RegistryAuth::Anonymous,
+                )
+                .await
+                .map_err(|_| e)?
+            }
+            Err(e) => return Err(e),
         };
+        debug!("Succesfully authorized for image '{:?}'", image);
+        self.tokens
+            .insert(self.get_registry(image), RegistryTokenType::Bearer(token));
+        Ok(())
+    }
 
-        let challenge = &challenge_opt[0];
-        let realm = challenge.realm.as_ref().unwrap();
-        let service = challenge.service.as_ref();
-        let mut query = vec![("scope", &scope)];
+    /// Whether the client holds a token for the image's registry that was obtained with the
+    /// given credentials. Tokens are only reused for the credentials they were issued for, so
+    /// that one set of credentials can't be used to reach images only another may access.
+    fn is_authenticated(&self, image: &Reference, authentication: &RegistryAuth) -> bool {
+        let registry = self.get_registry(image);
+        self.tokens.contains_key(&registry)
+            && self.token_credentials.get(&registry) == Some(&credential_digest(authentication))
+    }
 
+    /// Requests a bearer token for the given scopes from the realm of a challenge.
+    async fn request_token(
+        &self,
+        realm: &str,
+        service: Option<&str>,
+        scopes: &[String],
+        authentication: &RegistryAuth,
+    ) -> anyhow::Result<RegistryToken> {
+        let mut query: Vec<(&str, &str)> = scopes.iter().map(|s| ("scope", s.as_str())).collect();
         if let Some(s) = service {
             query.push(("service", s))
         }
 
-        // TODO: At some point in the future, we should support sending a secret to the
-        // server for auth. This particular workflow is for read-only public auth.
         debug!("Making authentication call to {}", realm);
 
         let auth_res = self
-            .client
-            .get(realm)
-            .query(&query)
-            .apply_authentication(authentication)
-            .send()
+            .read(
+                self.client
+                    .get(realm)
+                    .query(&query)
+                    .apply_authentication(authentication)
+                    .send(),
+            )
             .await?;
 
         match auth_res.status() {
@@ -339,15 +543,12 @@ impl Client {
                 debug!("Received response from auth request: {}", text);
                 let token: RegistryToken = serde_json::from_str(&text)
                     .context("Failed to decode registry token from auth request")?;
-                debug!("Succesfully authorized for image '{:?}'", image);
-                self.tokens
-                    .insert(self.get_registry(image), RegistryTokenType::Bearer(token));
-                Ok(())
+                Ok(token)
             }
             _ => {
                 let reason = auth_res.text().await?;
-                debug!("Failed to authenticate for image '{:?}': {}", image, reason);
-                Err(anyhow::anyhow!("failed to authenticate: {}", reason))
+                debug!("Failed to authenticate with realm {}: {}", realm, reason);
+                Err(ClientError::AuthenticationFailed(reason).into())
             }
         }
     }
@@ -361,16 +562,16 @@ impl Client {
         image: &Reference,
         auth: &RegistryAuth,
     ) -> anyhow::Result<String> {
-        if !self.tokens.contains_key(&self.get_registry(image)) {
+        if !self.is_authenticated(image, auth) {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
         let url = self.to_v2_manifest_url(image);
         debug!("Pulling image manifest from {}", url);
         let res = self
-            .apply_auth(self.client.get(&url), image, None)
-            .send()
+            .read(self.apply_auth(self.client.get(&url), image, None).send())
             .await?;
+        self.record_rate_limit(image, &res);
 
         let status = res.status();
         let headers = res.headers().clone();
@@ -382,9 +583,7 @@ impl Client {
             reqwest::StatusCode::OK => digest_header_value(headers, &text),
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
-                let err = serde_json::from_str::<OciEnvelope>(&text)?;
-                // FIXME: This should not have to wrap the error.
-                Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
+                Err(OciEnvelope::into_error(&text, s, &url))
             }
             s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
             s => Err(anyhow::anyhow!(
@@ -395,6 +594,147 @@ impl Client {
         }
     }
 
+    /// List the artifacts, such as SBOMs and provenance attestations, that refer to an image
+    /// as their subject, optionally only those of the given artifact type.
+    ///
+    /// Registries that don't implement the referrers API are asked for the index tagged with
+    /// the image's digest instead, as the OCI distribution specification describes. An image
+    /// without any referrers has an empty list.
+    ///
+    /// The client will check if it's already been authenticated and if
+    /// not will attempt to do.
+    pub async fn pull_referrers(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        artifact_type: Option<&str>,
+    ) -> anyhow::Result<Vec<OciIndexEntry>> {
+        if !self.is_authenticated(image, auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+        let digest = match image.digest() {
+            Some(digest) => digest.to_owned(),
+            None => self.fetch_manifest_digest(image, auth).await?,
+        };
+
+        let url = self.to_v2_referrers_url(image, &digest);
+        let index = match self.pull_index(image, &url).await? {
+            Some(index) => index,
+            None => {
+                // The fallback tag replaces the digest's `:` with a `-`
+                let url = self.to_v2_manifest_url_for(image, &digest.replacen(':', "-", 1));
+                self.pull_index(image, &url).await?.unwrap_or_default()
+            }
+        };
+        Ok(index
+            .manifests
+            .into_iter()
+            .filter(|entry| match artifact_type {
+                // Registries are allowed to ignore the filter, so it is applied here too
+                Some(wanted) => entry.artifact_type.as_deref() == Some(wanted),
+                None => true,
+            })
+            .collect())
+    }
+
+    /// Pull the content of an artifact that refers to an image, such as an SBOM document,
+    /// which is the first layer of the artifact's manifest.
+    ///
+    /// The client will check if it's already been authenticated and if
+    /// not will attempt to do.
+    pub async fn pull_referrer(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        referrer: &OciIndexEntry,
+    ) -> anyhow::Result<Vec<u8>> {
+        if !self.is_authenticated(image, auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let url = self.to_v2_manifest_url_for(image, &referrer.digest);
+        debug!("Pulling referrer manifest from {}", url);
+        let mut accept = HeaderMap::new();
+        accept.insert("Accept", OCI_IMAGE_MANIFEST_MEDIA_TYPE.parse().unwrap());
+        let res = self
+            .read(
+                self.apply_auth(self.client.get(&url), image, None)
+                    .headers(accept)
+                    .send(),
+            )
+            .await?;
+        self.record_rate_limit(image, &res);
+        let status = res.status();
+        let text = res.text().await?;
+        if !status.is_success() {
+            return Err(OciEnvelope::into_error(&text, status, &url));
+        }
+        // The manifest is fetched by digest, so it can be verified like a blob
+        if referrer.digest.starts_with("sha256:") {
+            let actual = format!("sha256:{:x}", sha2::Sha256::digest(text.as_bytes()));
+            if actual != referrer.digest {
+                return Err(ClientError::DigestMismatch {
+                    expected: referrer.digest.clone(),
+                    actual,
+                }
+                .into());
+            }
+        }
+        let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Failed to parse referrer manifest {} as an OciManifest",
+                referrer.digest
+            )
+        })?;
+        let layer = manifest
+            .layers
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("referrer {} has no layers", referrer.digest))?;
+
+        let mut out = Vec::new();
+        self._pull_blob(image, &layer.digest, &mut out).await?;
+        Ok(out)
+    }
+
+    /// Pull an image index, which is `None` if there is none at the URL.
+    async fn pull_index(
+        &self,
+        image: &Reference,
+        url: &str,
+    ) -> anyhow::Result<Option<OciImageIndex>> {
+        debug!("Pulling image index from {}", url);
+        let mut accept = HeaderMap::new();
+        accept.insert("Accept", OCI_IMAGE_INDEX_MEDIA_TYPE.parse().unwrap());
+        let res = self
+            .read(
+                self.apply_auth(self.client.get(url), image, None)
+                    .headers(accept)
+                    .send(),
+            )
+            .await?;
+        self.record_rate_limit(image, &res);
+        match res.status() {
+            reqwest::StatusCode::OK => {
+                let text = res.text().await?;
+                let index = serde_json::from_str(&text).with_context(|| {
+                    format!("Failed to parse response from {} as an image index", url)
+                })?;
+                Ok(Some(index))
+            }
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            s if s.is_client_error() => {
+                let text = res.text().await?;
+                Err(OciEnvelope::into_error(&text, s, url))
+            }
+            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
+        }
+    }
+
     async fn validate_layers(
         &self,
         manifest: &OciManifest,
@@ -404,12 +744,21 @@ impl Client {
             return Err(anyhow::anyhow!("no layers to pull"));
         }
 
-        for layer in &manifest.layers {
+        // Nondistributable layers are skipped when pulling, so their media type doesn't matter
+        let layers: Vec<_> = manifest
+            .layers
+            .iter()
+            .filter(|layer| !is_nondistributable(&layer.media_type))
+            .collect();
+        if layers.is_empty() {
+            return Err(anyhow::anyhow!("no distributable layers to pull"));
+        }
+
+        for layer in layers {
             if !accepted_media_types.iter().any(|i| i.eq(&layer.media_type)) {
-                return Err(anyhow::anyhow!(
-                    "incompatible layer media type: {}",
-                    layer.media_type
-                ));
+                return Err(
+                    ClientError::IncompatibleLayerMediaType(layer.media_type.clone()).into(),
+                );
             }
         }
 
@@ -428,7 +777,7 @@ impl Client {
         image: &Reference,
         auth: &RegistryAuth,
     ) -> anyhow::Result<(OciManifest, String)> {
-        if !self.tokens.contains_key(image.registry()) {
+        if !self.is_authenticated(image, auth) {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
@@ -444,7 +793,10 @@ impl Client {
         debug!("Pulling image manifest from {}", url);
         let request = self.client.get(&url);
 
-        let res = self.apply_auth(request, image, None).send().await?;
+        let res = self
+            .read(self.apply_auth(request, image, None).send())
+            .await?;
+        self.record_rate_limit(image, &res);
 
         // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
         // Obviously, HTTP servers are going to send other codes. This tries to catch the
@@ -468,9 +820,8 @@ impl Client {
             }
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
-                let err = res.json::<OciEnvelope>().await?;
-                // FIXME: This should not have to wrap the error.
-                Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
+                let text = res.text().await?;
+                Err(OciEnvelope::into_error(&text, s, &url))
             }
             s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
             s => Err(anyhow::anyhow!(
@@ -494,7 +845,7 @@ impl Client {
         if let Some(media_type) = versioned.media_type {
             // TODO: support manifest lists?
             if media_type != IMAGE_MANIFEST_MEDIA_TYPE {
-                return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
+                return Err(ClientError::UnsupportedMediaType(media_type).into());
             }
         }
 
@@ -514,7 +865,7 @@ impl Client {
         image: &Reference,
         auth: &RegistryAuth,
     ) -> anyhow::Result<(OciManifest, String, String)> {
-        if !self.tokens.contains_key(image.registry()) {
+        if !self.is_authenticated(image, auth) {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
@@ -529,39 +880,155 @@ impl Client {
 
         let mut out: Vec<u8> = Vec::new();
         debug!("Pulling config layer");
-        self.pull_layer(image, &manifest.config.digest, &mut out)
+        self._pull_blob(image, &manifest.config.digest, &mut out)
             .await?;
 
         Ok((manifest, digest, String::from_utf8(out)?))
     }
 
-    /// Pull a single layer from an OCI registry.
+    /// Pull a single blob, such as a layer, from an OCI registry.
+    ///
+    /// The blob is streamed into `out` as it is downloaded, so it never has to be
+    /// held in memory all at once. Its sha256 digest is computed along the way, and
+    /// an error is returned if it doesn't match the requested digest. `out` may
+    /// already have been written to at that point, so callers streaming to a file
+    /// should write to a temporary one and only move it into place on success.
     ///
-    /// This pulls the layer for a particular image that is identified by
+    /// The client will check if it's already been authenticated and if
+    /// not will attempt to do.
+    pub async fn pull_blob<T: AsyncWrite + Unpin>(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        out: T,
+    ) -> anyhow::Result<()> {
+        if !self.is_authenticated(image, auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        self._pull_blob(image, digest, out).await
+    }
+
+    /// Pull a single blob from an OCI registry.
+    ///
+    /// This pulls the blob for a particular image that is identified by
     /// the given digest. The image reference is used to find the
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
     /// used for that.)
-    async fn pull_layer<T: AsyncWrite + Unpin>(
+    async fn _pull_blob<T: AsyncWrite + Unpin>(
         &self,
         image: &Reference,
         digest: &str,
         mut out: T,
     ) -> anyhow::Result<()> {
         let url = self.to_v2_blob_url(&self.get_registry(image), image.repository(), digest);
-        let mut stream = self
-            .apply_auth(self.client.get(&url), image, None)
-            .send()
-            .await?
-            .bytes_stream();
+        let res = self
+            .read(self.apply_auth(self.client.get(&url), image, None).send())
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await?;
+            return Err(OciEnvelope::into_error(&text, status, &url));
+        }
 
-        while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+        let mut hasher = sha2::Sha256::new();
+        let mut stream = res.bytes_stream();
+        loop {
+            // A stalled download is detected by how long it has been since the last chunk
+            // arrived, as a large blob may legitimately take a long time overall
+            let next = match self.config.read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                    .await
+                    .with_context(|| format!("timed out reading blob from {}", url))?,
+                None => stream.next().await,
+            };
+            let bytes = match next {
+                Some(bytes) => bytes?,
+                None => break,
+            };
+            hasher.update(&bytes);
+            out.write_all(&bytes).await?;
+        }
+        out.flush().await?;
+
+        // Only sha256 digests can be verified, anything else is trusted as is
+        if digest.starts_with("sha256:") {
+            let actual = format!("sha256:{:x}", hasher.finalize());
+            if actual != digest {
+                return Err(ClientError::DigestMismatch {
+                    expected: digest.to_owned(),
+                    actual,
+                }
+                .into());
+            }
         }
 
         Ok(())
     }
 
+    /// Pull a single layer from an OCI registry, decompressing it as it is
+    /// streamed into `out`.
+    ///
+    /// The compression is determined from the media type of the layer's
+    /// descriptor, so gzip and zstd compressed layers, whether tars or
+    /// WebAssembly modules, are written out uncompressed. The layer's digest is
+    /// verified once it has been pulled, and if it does not match an error is
+    /// returned and what was written to `out` must be discarded.
+    pub async fn pull_layer_decompressed<T: AsyncWrite + Unpin>(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        layer: &OciDescriptor,
+        mut out: T,
+    ) -> anyhow::Result<()> {
+        if is_nondistributable(&layer.media_type) {
+            return Err(anyhow::anyhow!(
+                "layer {} has nondistributable media type {}",
+                layer.digest,
+                layer.media_type
+            ));
+        }
+
+        if !self.is_authenticated(image, auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let url = self.to_v2_blob_url(&self.get_registry(image), image.repository(), &layer.digest);
+        let res = self
+            .read(self.apply_auth(self.client.get(&url), image, None).send())
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await?;
+            return Err(OciEnvelope::into_error(&text, status, &url));
+        }
+
+        let stream = res
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)));
+        copy_decompressed(
+            stream,
+            Compression::from_media_type(&layer.media_type),
+            &layer.digest,
+            &mut out,
+        )
+        .await
+    }
+
+    /// Waits for a response to a request, failing if it doesn't arrive within the
+    /// configured read timeout.
+    async fn read<T>(
+        &self,
+        response: impl std::future::Future<Output = reqwest::Result<T>>,
+    ) -> anyhow::Result<T> {
+        match self.config.read_timeout {
+            Some(timeout) => Ok(tokio::time::timeout(timeout, response).await??),
+            None => Ok(response.await?),
+        }
+    }
+
     /// Begins a session to push an image to registry
     ///
     /// Returns URL with session UUID
@@ -788,6 +1255,32 @@ impl Client {
         }
     }
 
+    /// Convert a Reference to the v2 URL of the manifest with the given tag or digest in its
+    /// repository.
+    fn to_v2_manifest_url_for(&self, reference: &Reference, tag_or_digest: &str) -> String {
+        let registry = self.get_registry(reference);
+        format!(
+            "{}://{}/v2/{}/manifests/{}",
+            self.config.protocol.scheme_for(&registry),
+            registry,
+            reference.repository(),
+            tag_or_digest,
+        )
+    }
+
+    /// Convert a Reference to the v2 URL listing the referrers of the manifest with the given
+    /// digest.
+    fn to_v2_referrers_url(&self, reference: &Reference, digest: &str) -> String {
+        let registry = self.get_registry(reference);
+        format!(
+            "{}://{}/v2/{}/referrers/{}",
+            self.config.protocol.scheme_for(&registry),
+            registry,
+            reference.repository(),
+            digest,
+        )
+    }
+
     /// Convert a Reference to a v2 blob (layer) URL.
     fn to_v2_blob_url(&self, registry: &str, repository: &str, digest: &str) -> String {
         format!(
@@ -887,6 +1380,16 @@ pub struct ClientConfig {
     /// A list of extra root certificate to trust. This can be used to connect
     /// to servers using self-signed certificates
     pub extra_root_certificates: Vec<Certificate>,
+
+    /// How long to wait for a connection to be established. Defaults to no timeout
+    pub connect_timeout: Option<Duration>,
+
+    /// How long to wait for a response, or for the next chunk of a blob, before
+    /// giving up on the request. Defaults to no timeout
+    pub read_timeout: Option<Duration>,
+
+    /// The longest a call to [`Client::pull`] may take in total. Defaults to no deadline
+    pub pull_deadline: Option<Duration>,
 }
 
 /// The protocol that the client should use to connect
@@ -987,6 +1490,59 @@ impl Challenge for BearerChallenge {
     }
 }
 
+/// The authentication scheme a registry asked for.
+#[derive(Debug, PartialEq)]
+enum AuthChallenge {
+    /// Fetch a token from the realm and send it as a bearer token
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+    /// Send the credentials with each request
+    Basic,
+}
+
+/// Picks the strongest supported challenge from the `WWW-Authenticate` headers of a response.
+/// Registries may send several challenges, either in a single header or across several
+/// headers. Bearer challenges are preferred, but only if they say where to get a token from.
+fn strongest_challenge(headers: &HeaderMap) -> anyhow::Result<Option<AuthChallenge>> {
+    let raw: Vec<Vec<u8>> = headers
+        .get_all(reqwest::header::WWW_AUTHENTICATE)
+        .iter()
+        .map(|h| h.as_bytes().to_vec())
+        .collect();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+
+    let auth = WwwAuthenticate::parse_header(&raw.into())?;
+    let bearer = auth.get::<BearerChallenge>().and_then(|challenges| {
+        challenges.into_iter().find_map(|c| {
+            let BearerChallenge {
+                realm,
+                service,
+                scope,
+            } = c;
+            realm.map(|realm| AuthChallenge::Bearer {
+                realm,
+                service,
+                scope,
+            })
+        })
+    });
+    // If no usable bearer challenge is present we fall back to HTTP Basic Auth, even if the
+    // registry didn't ask for it
+    Ok(Some(bearer.unwrap_or(AuthChallenge::Basic)))
+}
+
+fn is_authentication_failure(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ClientError>(),
+        Some(ClientError::AuthenticationFailed(_))
+    )
+}
+
 fn digest_header_value(headers: HeaderMap, body: &str) -> anyhow::Result<String> {
     let digest_header = headers.get("Docker-Content-Digest");
     match digest_header {
@@ -1030,6 +1586,46 @@ mod test {
     ];
     const DOCKER_IO_IMAGE: &str = "docker.io/library/hello-world:latest";
 
+    #[test]
+    fn test_strongest_challenge() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, strongest_challenge(&headers).unwrap());
+
+        headers.append(
+            reqwest::header::WWW_AUTHENTICATE,
+            "Basic realm=\"registry\"".parse().unwrap(),
+        );
+        assert_eq!(
+            Some(AuthChallenge::Basic),
+            strongest_challenge(&headers).unwrap()
+        );
+
+        // A bearer challenge without a realm can't be used
+        headers.append(
+            reqwest::header::WWW_AUTHENTICATE,
+            "Bearer service=\"registry.example.com\"".parse().unwrap(),
+        );
+        assert_eq!(
+            Some(AuthChallenge::Basic),
+            strongest_challenge(&headers).unwrap()
+        );
+
+        headers.append(
+            reqwest::header::WWW_AUTHENTICATE,
+            "Bearer realm=\"https://auth.example.com/token\",service=\"registry.example.com\",scope=\"repository:hello:pull\""
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            Some(AuthChallenge::Bearer {
+                realm: "https://auth.example.com/token".to_owned(),
+                service: Some("registry.example.com".to_owned()),
+                scope: Some("repository:hello:pull".to_owned()),
+            }),
+            strongest_challenge(&headers).unwrap()
+        );
+    }
+
     #[test]
     fn test_to_v2_blob_url() {
         let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
@@ -1044,6 +1640,133 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_to_v2_referrers_url() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let c = Client::default();
+        assert_eq!(
+            c.to_v2_referrers_url(&image, "sha256:deadbeef"),
+            "https://webassembly.azurecr.io/v2/hello-wasm/referrers/sha256:deadbeef"
+        );
+        assert_eq!(
+            c.to_v2_manifest_url_for(&image, "sha256-deadbeef"),
+            "https://webassembly.azurecr.io/v2/hello-wasm/manifests/sha256-deadbeef"
+        );
+    }
+
+    /// Serves a registry without the referrers API on a local port, recording the paths it is
+    /// asked for. The tag every image's referrers fall back to lists the given index.
+    fn registry_without_referrers_api(
+        index: serde_json::Value,
+    ) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let paths: std::sync::Arc<std::sync::Mutex<Vec<String>>> = Default::default();
+        let requested = paths.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_owned();
+                requested.lock().unwrap().push(path.clone());
+                let (status, body) = if path == "/v2/" {
+                    ("200 OK", String::new())
+                } else if path.contains("/manifests/sha256-") {
+                    ("200 OK", index.to_string())
+                } else {
+                    ("404 Not Found", String::new())
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (addr.to_string(), paths)
+    }
+
+    #[tokio::test]
+    async fn test_pull_referrers_falls_back_to_tag() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let sbom = format!("sha256:{}", "b".repeat(64));
+        let (registry, paths) = registry_without_referrers_api(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": [
+                {
+                    "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+                    "digest": sbom,
+                    "size": 100,
+                    "artifactType": "application/spdx+json",
+                },
+                {
+                    "mediaType": OCI_IMAGE_MANIFEST_MEDIA_TYPE,
+                    "digest": format!("sha256:{}", "c".repeat(64)),
+                    "size": 100,
+                    "artifactType": manifest::IN_TOTO_ARTIFACT_TYPE,
+                },
+            ],
+        }));
+        let image = Reference::try_from(format!("{}/hello@{}", registry, digest))
+            .expect("failed to parse reference");
+        let mut c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+
+        let referrers = c
+            .pull_referrers(&image, &RegistryAuth::Anonymous, None)
+            .await
+            .expect("failed to list referrers");
+        assert_eq!(referrers.len(), 2);
+        let sboms = c
+            .pull_referrers(
+                &image,
+                &RegistryAuth::Anonymous,
+                Some("application/spdx+json"),
+            )
+            .await
+            .expect("failed to list referrers");
+        assert_eq!(sboms.len(), 1);
+        assert_eq!(sboms[0].digest, sbom);
+
+        let paths = paths.lock().unwrap();
+        let referrers_path = format!("/v2/hello/referrers/{}", digest);
+        let fallback_path = format!("/v2/hello/manifests/{}", digest.replacen(':', "-", 1));
+        let referrers_at = paths.iter().position(|p| p.starts_with(&referrers_path));
+        let fallback_at = paths.iter().position(|p| *p == fallback_path);
+        assert!(
+            referrers_at.is_some(),
+            "referrers API not tried: {:?}",
+            paths
+        );
+        assert!(
+            referrers_at < fallback_at,
+            "tag not fallen back to: {:?}",
+            paths
+        );
+    }
+
     #[test]
     fn test_to_v2_manifest() {
         let c = Client::default();
@@ -1193,6 +1916,87 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_tokens_are_only_used_for_their_credentials() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let tenant_a = RegistryAuth::Basic("tenant-a".to_owned(), "secret".to_owned());
+        let tenant_b = RegistryAuth::Basic("tenant-b".to_owned(), "secret".to_owned());
+        let mut c = Client::default();
+        assert!(!c.is_authenticated(&image, &tenant_a));
+
+        let registry = c.get_registry(&image);
+        c.tokens.insert(
+            registry.clone(),
+            RegistryTokenType::Bearer(RegistryToken::Token {
+                token: "token-a".to_owned(),
+            }),
+        );
+        c.token_credentials
+            .insert(registry, credential_digest(&tenant_a));
+        assert!(c.is_authenticated(&image, &tenant_a));
+        assert!(!c.is_authenticated(&image, &tenant_b));
+        assert!(!c.is_authenticated(&image, &RegistryAuth::Anonymous));
+    }
+
+    #[test]
+    fn test_rate_limit_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, RateLimit::from_headers(&headers));
+
+        headers.insert("ratelimit-limit", "100;w=21600".parse().unwrap());
+        headers.insert("ratelimit-remaining", "8;w=21600".parse().unwrap());
+        let rate_limit = RateLimit::from_headers(&headers).expect("rate limit should be parsed");
+        assert_eq!(
+            RateLimit {
+                limit: 100,
+                remaining: 8,
+                window: Some(Duration::from_secs(21600)),
+            },
+            rate_limit
+        );
+        assert!(rate_limit.is_nearly_exhausted(0.1));
+        assert!(!rate_limit.is_nearly_exhausted(0.05));
+    }
+
+    #[test]
+    fn test_rate_limits_expire_with_their_window() {
+        let c = Client::default();
+        let exhausted = |window| RateLimit {
+            limit: 100,
+            remaining: 0,
+            window,
+        };
+        let recorded_ago = |elapsed| Instant::now().checked_sub(elapsed).unwrap();
+        {
+            let mut limits = c.rate_limits.write().unwrap();
+            limits.insert(
+                "current.example.com".to_owned(),
+                (
+                    exhausted(Some(Duration::from_secs(3600))),
+                    recorded_ago(Duration::from_secs(60)),
+                ),
+            );
+            limits.insert(
+                "expired.example.com".to_owned(),
+                (
+                    exhausted(Some(Duration::from_secs(30))),
+                    recorded_ago(Duration::from_secs(60)),
+                ),
+            );
+            limits.insert(
+                "unknown-window.example.com".to_owned(),
+                (exhausted(None), recorded_ago(DEFAULT_RATE_LIMIT_WINDOW)),
+            );
+        }
+        assert_eq!(
+            Some(exhausted(Some(Duration::from_secs(3600)))),
+            c.rate_limit("current.example.com")
+        );
+        assert_eq!(None, c.rate_limit("expired.example.com"));
+        assert_eq!(None, c.rate_limit("unknown-window.example.com"));
+        assert_eq!(None, c.rate_limit("other.example.com"));
+    }
+
     #[test]
     fn test_registry_token_deserialize() {
         // 'token' field, standalone
@@ -1427,9 +2231,9 @@ mod test {
             // This call likes to flake, so we try it at least 5 times
             let mut last_error = None;
             for i in 1..6 {
-                if let Err(e) = c.pull_layer(&reference, &layer0.digest, &mut file).await {
+                if let Err(e) = c._pull_blob(&reference, &layer0.digest, &mut file).await {
                     println!(
-                        "Got error on pull_layer call attempt {}. Will retry in 1s: {:?}",
+                        "Got error on _pull_blob call attempt {}. Will retry in 1s: {:?}",
                         i, e
                     );
                     last_error.replace(e);
@@ -0,0 +1,229 @@
+//! Decompression of image layers
+//!
+//! Layers are decompressed as they are read, so large layers never need to be
+//! buffered in memory all at once.
+use crate::errors::ClientError;
+use crate::manifest::{
+    IMAGE_DOCKER_LAYER_FOREIGN_MEDIA_TYPE, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE, IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use bytes::Buf;
+use futures_util::stream::{Stream, StreamExt};
+use sha2::Digest;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+/// The compression applied to a layer, as indicated by its media type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// The layer is not compressed
+    None,
+    /// The layer is compressed with gzip
+    Gzip,
+    /// The layer is compressed with zstd
+    Zstd,
+}
+
+impl Compression {
+    /// Determines the compression of a layer from its media type. Media types without a
+    /// known compression suffix are treated as uncompressed.
+    pub fn from_media_type(media_type: &str) -> Self {
+        match media_type {
+            IMAGE_LAYER_GZIP_MEDIA_TYPE
+            | IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE
+            | IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE
+            | IMAGE_DOCKER_LAYER_FOREIGN_MEDIA_TYPE => Compression::Gzip,
+            IMAGE_LAYER_ZSTD_MEDIA_TYPE | IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE => {
+                Compression::Zstd
+            }
+            _ => Self::split_media_type(media_type).1,
+        }
+    }
+
+    /// Splits a `+gzip` or `+zstd` suffix off a media type, such as that of a compressed
+    /// WebAssembly module layer, returning the media type of the uncompressed content and
+    /// its compression.
+    pub fn split_media_type(media_type: &str) -> (&str, Self) {
+        if let Some(base) = media_type.strip_suffix("+gzip") {
+            (base, Compression::Gzip)
+        } else if let Some(base) = media_type.strip_suffix("+zstd") {
+            (base, Compression::Zstd)
+        } else {
+            (media_type, Compression::None)
+        }
+    }
+
+    /// Wraps a reader of compressed data in one that yields the decompressed data.
+    pub fn decoder<'a, R: AsyncBufRead + Unpin + Send + 'a>(
+        self,
+        reader: R,
+    ) -> Box<dyn AsyncRead + Unpin + Send + 'a> {
+        match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzipDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(reader)),
+        }
+    }
+}
+
+/// Decompresses a blob as it streams in, writing the decompressed content to `out`.
+///
+/// The digest is that of the blob as stored in the registry, so it is computed over the
+/// compressed bytes before they are decoded, and only checked once the whole blob has
+/// arrived. As with other blobs, only sha256 digests can be verified. If the digest does not
+/// match, the decompressed content written to `out` must be discarded.
+pub async fn copy_decompressed<S, B, W>(
+    stream: S,
+    compression: Compression,
+    digest: &str,
+    out: &mut W,
+) -> anyhow::Result<()>
+where
+    S: Stream<Item = std::io::Result<B>> + Unpin + Send,
+    B: Buf + AsRef<[u8]> + Send,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let hasher = std::sync::Mutex::new(sha2::Sha256::new());
+    {
+        let hashed = stream.inspect(|chunk| {
+            if let Ok(bytes) = chunk {
+                hasher.lock().unwrap().update(bytes.as_ref());
+            }
+        });
+        let mut decoder = compression.decoder(StreamReader::new(hashed));
+        tokio::io::copy(&mut decoder, out).await?;
+    }
+    out.flush().await?;
+
+    if digest.starts_with("sha256:") {
+        let actual = format!("sha256:{:x}", hasher.into_inner().unwrap().finalize());
+        if actual != digest {
+            return Err(ClientError::DigestMismatch {
+                expected: digest.to_owned(),
+                actual,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::IMAGE_LAYER_MEDIA_TYPE;
+    use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+    use tokio::io::AsyncReadExt;
+
+    async fn read_all(mut reader: impl AsyncRead + Unpin) -> Vec<u8> {
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    #[test]
+    fn test_compression_from_media_type() {
+        assert_eq!(
+            Compression::from_media_type(IMAGE_LAYER_MEDIA_TYPE),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::from_media_type(IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_media_type(IMAGE_LAYER_ZSTD_MEDIA_TYPE),
+            Compression::Zstd
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decoder_round_trip() {
+        let data = b"hello from a compressed layer".to_vec();
+
+        let gzipped = read_all(GzipEncoder::new(&data[..])).await;
+        let decoded = read_all(Compression::Gzip.decoder(&gzipped[..])).await;
+        assert_eq!(decoded, data);
+
+        let zstded = read_all(ZstdEncoder::new(&data[..])).await;
+        let decoded = read_all(Compression::Zstd.decoder(&zstded[..])).await;
+        assert_eq!(decoded, data);
+
+        let decoded = read_all(Compression::None.decoder(&data[..])).await;
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_split_media_type() {
+        assert_eq!(
+            Compression::split_media_type("application/vnd.wasm.content.layer.v1+wasm+gzip"),
+            (
+                "application/vnd.wasm.content.layer.v1+wasm",
+                Compression::Gzip
+            )
+        );
+        assert_eq!(
+            Compression::from_media_type("application/vnd.wasm.content.layer.v1+wasm+zstd"),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::split_media_type("application/vnd.wasm.content.layer.v1+wasm"),
+            (
+                "application/vnd.wasm.content.layer.v1+wasm",
+                Compression::None
+            )
+        );
+    }
+
+    fn chunked(data: &[u8]) -> impl Stream<Item = std::io::Result<bytes::Bytes>> + Unpin + Send {
+        let chunks: Vec<_> = data
+            .chunks(4)
+            .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+            .collect();
+        futures_util::stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    async fn test_copy_decompressed_round_trip() {
+        let data = b"hello from a streamed compressed layer".to_vec();
+        for (compression, compressed) in vec![
+            (
+                Compression::Gzip,
+                read_all(GzipEncoder::new(&data[..])).await,
+            ),
+            (
+                Compression::Zstd,
+                read_all(ZstdEncoder::new(&data[..])).await,
+            ),
+        ] {
+            let digest = format!("sha256:{:x}", sha2::Sha256::digest(&compressed));
+            let mut out = Vec::new();
+            copy_decompressed(chunked(&compressed), compression, &digest, &mut out)
+                .await
+                .expect("blob with the right digest should be decompressed");
+            assert_eq!(out, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_decompressed_rejects_digest_mismatch() {
+        let data = b"hello from a tampered layer".to_vec();
+        let compressed = read_all(GzipEncoder::new(&data[..])).await;
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(&data));
+        let err = copy_decompressed(
+            chunked(&compressed),
+            Compression::Gzip,
+            &digest,
+            &mut Vec::new(),
+        )
+        .await
+        .expect_err("blob with the wrong digest should be rejected");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DigestMismatch { .. })
+        ));
+    }
+}
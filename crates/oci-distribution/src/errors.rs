@@ -27,11 +27,81 @@ impl std::fmt::Display for OciError {
     }
 }
 
+/// Errors the client detects itself, rather than ones described by the registry in an
+/// [`OciError`].
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    /// The registry rejected the credentials used to authenticate
+    AuthenticationFailed(String),
+    /// The registry responded with an error status but no OCI error body
+    HttpStatus {
+        /// The status code of the response
+        status: u16,
+        /// The URL that was requested
+        url: String,
+    },
+    /// The manifest has a media type that is not supported
+    UnsupportedMediaType(String),
+    /// A layer has a media type that was not accepted by the caller
+    IncompatibleLayerMediaType(String),
+    /// The content of a blob did not match its digest
+    DigestMismatch {
+        /// The digest that was requested
+        expected: String,
+        /// The digest of the content that was received
+        actual: String,
+    },
+}
+
+impl std::error::Error for ClientError {}
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::AuthenticationFailed(reason) => {
+                write!(f, "failed to authenticate: {}", reason)
+            }
+            ClientError::HttpStatus { status, url } => {
+                write!(f, "registry returned status {} for {}", status, url)
+            }
+            ClientError::UnsupportedMediaType(media_type) => {
+                write!(f, "unsupported media type: {}", media_type)
+            }
+            ClientError::IncompatibleLayerMediaType(media_type) => {
+                write!(f, "incompatible layer media type: {}", media_type)
+            }
+            ClientError::DigestMismatch { expected, actual } => {
+                write!(f, "digest mismatch: expected {}, got {}", expected, actual)
+            }
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub(crate) struct OciEnvelope {
     pub(crate) errors: Vec<OciError>,
 }
 
+impl OciEnvelope {
+    /// Converts an error response body into an error, keeping the first [`OciError`] available
+    /// for callers to downcast to. Falls back to a [`ClientError::HttpStatus`] if the body isn't
+    /// a valid OCI error.
+    pub(crate) fn into_error(body: &str, status: reqwest::StatusCode, url: &str) -> anyhow::Error {
+        match serde_json::from_str::<OciEnvelope>(body)
+            .ok()
+            .and_then(|e| e.errors.into_iter().next())
+        {
+            Some(err) => {
+                let message = format!("{} on {}", err, url);
+                anyhow::Error::new(err).context(message)
+            }
+            None => anyhow::Error::new(ClientError::HttpStatus {
+                status: status.as_u16(),
+                url: url.to_owned(),
+            }),
+        }
+    }
+}
+
 /// OCI error codes
 ///
 /// Outlined here: https://github.com/opencontainers/distribution-spec/blob/master/spec.md#errors-2
@@ -144,4 +214,30 @@ mod test {
         assert_eq!("authentication required", e.message);
         assert_eq!(serde_json::value::Value::Null, e.detail);
     }
+
+    #[test]
+    fn test_into_error_keeps_oci_error() {
+        let err = OciEnvelope::into_error(
+            EXAMPLE_ERROR,
+            reqwest::StatusCode::UNAUTHORIZED,
+            "https://example.com/v2/",
+        );
+        let e = err
+            .downcast_ref::<OciError>()
+            .expect("error should be an OciError");
+        assert_eq!(OciErrorCode::Unauthorized, e.code);
+
+        let err = OciEnvelope::into_error(
+            "too many requests",
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+            "https://example.com/v2/",
+        );
+        assert_eq!(
+            Some(&ClientError::HttpStatus {
+                status: 429,
+                url: "https://example.com/v2/".to_owned()
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+    }
 }
@@ -18,12 +18,53 @@ pub const IMAGE_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v
 /// The mediatype that Docker uses for a layer that is gzipped.
 pub const IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
     "application/vnd.docker.image.rootfs.diff.tar.gzip";
+/// The mediatype for a layer that is compressed with zstd.
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+/// The mediatype that Docker uses for a foreign layer, which must be fetched from the URLs
+/// in its descriptor rather than from the registry.
+pub const IMAGE_DOCKER_LAYER_FOREIGN_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
 /// The mediatype for a layer that is nondistributable.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar";
 /// The mediatype for a layer that is nondistributable and gzipped.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+/// The mediatype for a layer that is nondistributable and compressed with zstd.
+pub const IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
+
+/// The mediatype for an OCI image manifest.
+pub const OCI_IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The mediatype for an OCI image index, which the referrers API responds with.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+/// The artifact type of an SBOM in the SPDX JSON format.
+pub const SPDX_SBOM_ARTIFACT_TYPE: &str = "application/spdx+json";
+/// The artifact type of an SBOM in the CycloneDX JSON format.
+pub const CYCLONEDX_SBOM_ARTIFACT_TYPE: &str = "application/vnd.cyclonedx+json";
+/// The artifact type of an in-toto attestation, such as SLSA provenance.
+pub const IN_TOTO_ARTIFACT_TYPE: &str = "application/vnd.in-toto+json";
+
+/// Returns true if the artifact type is one of an SBOM.
+pub fn is_sbom(artifact_type: &str) -> bool {
+    matches!(
+        artifact_type,
+        SPDX_SBOM_ARTIFACT_TYPE | CYCLONEDX_SBOM_ARTIFACT_TYPE
+    )
+}
+
+/// Returns true if layers with the given media type are not distributed by the registry
+/// (nondistributable OCI layers and foreign Docker layers). Registries may refuse to serve
+/// these, so they are skipped when pulling.
+pub fn is_nondistributable(media_type: &str) -> bool {
+    matches!(
+        media_type,
+        IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE
+            | IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE
+            | IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE
+            | IMAGE_DOCKER_LAYER_FOREIGN_MEDIA_TYPE
+    )
+}
 
 // TODO: Annotation key constants. https://github.com/opencontainers/image-spec/blob/master/annotations.md#pre-defined-annotation-keys
 
@@ -78,6 +119,45 @@ impl Default for OciManifest {
     }
 }
 
+/// The OCI image index lists manifests, such as those of the artifacts that refer to an image.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/main/image-index.md
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciImageIndex {
+    /// This is a schema version, which is always `2`.
+    pub schema_version: u8,
+
+    /// The media type of the index, which should be
+    /// [`OCI_IMAGE_INDEX_MEDIA_TYPE`].
+    pub media_type: Option<String>,
+
+    /// The manifests in the index
+    #[serde(default)]
+    pub manifests: Vec<OciIndexEntry>,
+
+    /// The annotations for this index
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// A manifest listed in an [`OciImageIndex`].
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciIndexEntry {
+    /// The media type of the manifest.
+    pub media_type: String,
+    /// The digest of the manifest.
+    pub digest: String,
+    /// The size, in bytes, of the manifest.
+    pub size: i64,
+    /// The type of artifact the manifest is for, such as [`SPDX_SBOM_ARTIFACT_TYPE`], if it
+    /// is for one.
+    pub artifact_type: Option<String>,
+    /// The annotations of the manifest, which the referrers API copies from it.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
 /// Versioned provides a struct with the manifest's schemaVersion and mediaType.
 /// Incoming content with unknown schema versions can be decoded against this
 /// struct to check the version.
@@ -198,4 +278,27 @@ mod test {
                 .len()
         );
     }
+
+    #[test]
+    fn test_referrers_index() {
+        let index: OciImageIndex = serde_json::from_str(
+            r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 1234,
+                    "digest": "sha256:a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1a1",
+                    "artifactType": "application/spdx+json"
+                }
+            ]
+        }"#,
+        )
+        .expect("parsed index");
+        assert_eq!(1, index.manifests.len());
+        let sbom = &index.manifests[0];
+        assert!(is_sbom(sbom.artifact_type.as_deref().unwrap()));
+        assert_eq!(None, sbom.annotations);
+    }
 }
@@ -0,0 +1,199 @@
+//! Support for stripping default WASI capabilities from a module so that it runs
+//! deterministically.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use cap_std::time::{Instant, SystemTime};
+use wasi_common::clocks::{WasiClocks, WasiMonotonicClock, WasiSystemClock};
+use wasi_common::WasiCtx;
+
+/// The prefix used in the node's allowed pod overrides to permit stripping a capability
+const STRIP_OVERRIDE_PREFIX: &str = "strip-";
+
+/// A default WASI capability that can be removed from a module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Wall-clock and monotonic clock access
+    Clock,
+    /// Access to a source of randomness
+    Random,
+    /// The environment variables defined for the container
+    Env,
+}
+
+impl std::str::FromStr for Capability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clock" => Ok(Capability::Clock),
+            "random" => Ok(Capability::Random),
+            "env" => Ok(Capability::Env),
+            other => Err(anyhow::anyhow!(
+                "unknown capability {:?}, expected one of \"clock\", \"random\" or \"env\"",
+                other
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Clock => "clock",
+            Capability::Random => "random",
+            Capability::Env => "env",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The set of capabilities a pod has asked to have removed.
+#[derive(Clone, Debug, Default)]
+pub struct StrippedCapabilities {
+    capabilities: HashSet<Capability>,
+    random_seed: u64,
+}
+
+impl StrippedCapabilities {
+    /// Parses a comma separated list of capabilities from an annotation, rejecting any that the
+    /// node policy does not allow to be stripped.
+    pub fn from_annotation(value: &str, allowed_overrides: &[String]) -> anyhow::Result<Self> {
+        let capabilities = value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let capability: Capability = s.parse()?;
+                let override_name = format!("{}{}", STRIP_OVERRIDE_PREFIX, capability);
+                if !allowed_overrides.contains(&override_name) {
+                    return Err(anyhow::anyhow!(
+                        "node policy does not allow the {} override",
+                        override_name
+                    ));
+                }
+                Ok(capability)
+            })
+            .collect::<anyhow::Result<HashSet<_>>>()?;
+        Ok(StrippedCapabilities {
+            capabilities,
+            random_seed: 0,
+        })
+    }
+
+    /// Sets the seed used for the deterministic source of randomness.
+    pub fn with_random_seed(mut self, seed: u64) -> Self {
+        self.random_seed = seed;
+        self
+    }
+
+    /// Returns whether the given capability has been stripped.
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Replaces the clocks and randomness of the given context with deterministic sources as
+    /// requested. Environment stripping has to be handled when building the context.
+    pub fn apply(&self, ctx: &mut WasiCtx) {
+        if self.contains(Capability::Clock) {
+            let creation_time = ctx.clocks.creation_time;
+            ctx.clocks = WasiClocks {
+                system: Box::new(FixedSystemClock),
+                monotonic: Box::new(FixedMonotonicClock(creation_time)),
+                creation_time,
+            };
+        }
+        if self.contains(Capability::Random) {
+            ctx.random = std::cell::RefCell::new(Box::new(DeterministicRng(self.random_seed)));
+        }
+    }
+}
+
+/// A system clock that is always at the unix epoch.
+struct FixedSystemClock;
+
+impl WasiSystemClock for FixedSystemClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> SystemTime {
+        SystemTime::from_std(std::time::UNIX_EPOCH)
+    }
+}
+
+/// A monotonic clock that never advances.
+struct FixedMonotonicClock(Instant);
+
+impl WasiMonotonicClock for FixedMonotonicClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> Instant {
+        self.0
+    }
+}
+
+/// A seeded splitmix64 generator. This is not suitable for anything requiring real
+/// randomness, which is exactly the point.
+struct DeterministicRng(u64);
+
+impl cap_rand::RngCore for DeterministicRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), cap_rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cap_rand::RngCore;
+
+    #[test]
+    fn test_parse_allowed_capabilities() {
+        let allowed = vec!["strip-clock".to_owned(), "strip-random".to_owned()];
+        let stripped = StrippedCapabilities::from_annotation("clock, random", &allowed).unwrap();
+        assert!(stripped.contains(Capability::Clock));
+        assert!(stripped.contains(Capability::Random));
+        assert!(!stripped.contains(Capability::Env));
+    }
+
+    #[test]
+    fn test_reject_disallowed_capabilities() {
+        let allowed = vec!["strip-clock".to_owned()];
+        assert!(StrippedCapabilities::from_annotation("env", &allowed).is_err());
+        assert!(StrippedCapabilities::from_annotation("network", &allowed).is_err());
+    }
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut first = DeterministicRng(42);
+        let mut second = DeterministicRng(42);
+        let mut first_bytes = [0u8; 13];
+        let mut second_bytes = [0u8; 13];
+        first.fill_bytes(&mut first_bytes);
+        second.fill_bytes(&mut second_bytes);
+        assert_eq!(first_bytes, second_bytes);
+    }
+}
@@ -0,0 +1,525 @@
+//! A resolver for cluster service names, for modules to use until WASI has sockets and DNS.
+//!
+//! When the Kubelet is configured with cluster DNS servers, every module can import a `resolve`
+//! function from the `krustlet_dns` module:
+//!
+//! ```text
+//! (import "krustlet_dns" "resolve"
+//!     (func $resolve (param $name_ptr i32) (param $name_len i32)
+//!                    (param $out_ptr i32) (param $out_len i32) (result i32)))
+//! ```
+//!
+//! The name is read from the module's memory as UTF-8 and looked up with the cluster DNS servers,
+//! trying it in the pod's namespace first as the resolver of a Kubernetes pod would, so that
+//! `web`, `web.default` and `web.default.svc.cluster.local` all name the same service. The
+//! addresses it resolves to are written to the output buffer as text, one per line, and the
+//! number of bytes written is returned. On failure one of the negative `RESOLVE_*` codes is
+//! returned instead.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cap_rand::Rng;
+
+use kubelet::environment::Net;
+use tracing::debug;
+use wasmtime::{Caller, Linker};
+
+use crate::extensions::{HostExtension, ModuleState};
+
+/// The module the resolver's host functions are imported from
+pub const DNS_MODULE: &str = "krustlet_dns";
+/// Returned when the name doesn't resolve to any address
+pub const RESOLVE_NOT_FOUND: i32 = -1;
+/// Returned when the addresses don't fit in the output buffer
+pub const RESOLVE_BUFFER_TOO_SMALL: i32 = -2;
+/// Returned when the name isn't valid UTF-8 or a buffer is outside the module's memory
+pub const RESOLVE_INVALID_ARGUMENT: i32 = -3;
+/// Returned when none of the cluster DNS servers could be queried
+pub const RESOLVE_LOOKUP_FAILED: i32 = -4;
+
+const DNS_PORT: u16 = 53;
+/// The longest name DNS allows
+const MAX_NAME_LEN: usize = 255;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Names with at least this many dots are tried as they are before the search domains, as with
+/// the `ndots` option of the resolv.conf of Kubernetes pods
+const NDOTS: usize = 5;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// The cluster DNS configuration of the Kubelet.
+#[derive(Clone)]
+pub struct ClusterDnsConfig {
+    servers: Vec<SocketAddr>,
+    domain: String,
+    net: Arc<dyn Net>,
+}
+
+impl ClusterDnsConfig {
+    /// Creates the configuration from the Kubelet's, if it has any cluster DNS servers.
+    pub fn from_config(config: &kubelet::config::Config) -> Option<Self> {
+        if config.cluster_dns.is_empty() {
+            return None;
+        }
+        Some(ClusterDnsConfig {
+            servers: config
+                .cluster_dns
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, DNS_PORT))
+                .collect(),
+            domain: config.cluster_domain.trim_matches('.').to_owned(),
+            net: config.environment.net.clone(),
+        })
+    }
+
+    /// The resolver for modules of pods in the given namespace.
+    pub fn resolver(&self, namespace: &str) -> ClusterDns {
+        ClusterDns {
+            servers: self.servers.clone(),
+            net: self.net.clone(),
+            search: vec![
+                format!("{}.svc.{}", namespace, self.domain),
+                format!("svc.{}", self.domain),
+                self.domain.clone(),
+            ],
+        }
+    }
+}
+
+/// Resolves names with the cluster DNS servers on behalf of the modules of one namespace.
+#[derive(Clone)]
+pub struct ClusterDns {
+    servers: Vec<SocketAddr>,
+    net: Arc<dyn Net>,
+    search: Vec<String>,
+}
+
+impl ClusterDns {
+    /// Resolves a name to its addresses, which are empty if the name doesn't exist. IPv4
+    /// addresses are looked up first, and IPv6 addresses only if there are none. A candidate
+    /// that can't be looked up doesn't stop the others from being tried, but the lookup only
+    /// fails if none of them resolve.
+    pub fn resolve(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let mut last_error = None;
+        for candidate in self.candidates(name)? {
+            // Names the Kubelet's network environment resolves itself are never sent to a server
+            if let Some(addrs) = self.net.resolve(&candidate) {
+                if addrs.is_empty() {
+                    continue;
+                }
+                debug!(name, %candidate, ?addrs, "Resolved name for module from the environment");
+                return Ok(addrs);
+            }
+            for qtype in &[TYPE_A, TYPE_AAAA] {
+                match self.query(&candidate, *qtype) {
+                    Ok(addrs) if addrs.is_empty() => (),
+                    Ok(addrs) => {
+                        debug!(name, %candidate, ?addrs, "Resolved name for module");
+                        return Ok(addrs);
+                    }
+                    Err(e) => {
+                        debug!(name, %candidate, error = %e, "Unable to look up candidate name");
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The fully qualified names to try for a name, in order.
+    fn candidates(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        let name = name.trim();
+        if name.is_empty() || name.starts_with('.') || name.contains("..") {
+            anyhow::bail!("invalid name {:?}", name);
+        }
+        if let Some(absolute) = name.strip_suffix('.') {
+            return Ok(vec![absolute.to_owned()]);
+        }
+        let searched = self
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", name, domain));
+        if name.matches('.').count() >= NDOTS {
+            Ok(std::iter::once(name.to_owned()).chain(searched).collect())
+        } else {
+            Ok(searched.chain(std::iter::once(name.to_owned())).collect())
+        }
+    }
+
+    /// Asks each server in turn until one answers.
+    fn query(&self, name: &str, qtype: u16) -> anyhow::Result<Vec<IpAddr>> {
+        let id = query_id();
+        let request = encode_query(id, name, qtype)?;
+        let mut last_error = None;
+        for server in &self.servers {
+            if !self.net.reachable(&server.ip().to_string()) {
+                last_error = Some(anyhow::anyhow!("server {} is unreachable", server));
+                continue;
+            }
+            match exchange(*server, &request).and_then(|response| parse_response(id, &response)) {
+                Ok(addrs) => return Ok(addrs),
+                Err(e) => {
+                    debug!(%server, name, error = %e, "Cluster DNS query failed");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no cluster DNS servers configured")))
+    }
+}
+
+impl HostExtension for ClusterDns {
+    fn add_to_linker(&self, linker: &mut Linker<ModuleState>) -> anyhow::Result<()> {
+        let resolver = Arc::new(self.clone());
+        linker.func_wrap(
+            DNS_MODULE,
+            "resolve",
+            move |mut caller: Caller<'_, ModuleState>,
+                  name_ptr: u32,
+                  name_len: u32,
+                  out_ptr: u32,
+                  out_len: u32|
+                  -> i32 {
+                let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                    Some(memory) => memory,
+                    None => return RESOLVE_INVALID_ARGUMENT,
+                };
+                if name_len as usize > MAX_NAME_LEN {
+                    return RESOLVE_INVALID_ARGUMENT;
+                }
+                let mut name = vec![0; name_len as usize];
+                if memory.read(&caller, name_ptr as usize, &mut name).is_err() {
+                    return RESOLVE_INVALID_ARGUMENT;
+                }
+                let name = match String::from_utf8(name) {
+                    Ok(name) => name,
+                    Err(_) => return RESOLVE_INVALID_ARGUMENT,
+                };
+                let addrs = match resolver.resolve(&name) {
+                    Ok(addrs) if addrs.is_empty() => return RESOLVE_NOT_FOUND,
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        debug!(%name, error = %e, "Unable to resolve name for module");
+                        return RESOLVE_LOOKUP_FAILED;
+                    }
+                };
+                let output = addrs
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if output.len() > out_len as usize {
+                    return RESOLVE_BUFFER_TOO_SMALL;
+                }
+                if memory
+                    .write(&mut caller, out_ptr as usize, output.as_bytes())
+                    .is_err()
+                {
+                    return RESOLVE_INVALID_ARGUMENT;
+                }
+                output.len() as i32
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// A random ID for a query, so that responses to it can't be spoofed by guessing it.
+fn query_id() -> u16 {
+    // The Kubelet's own source of randomness, which no module is handed
+    let mut rng = unsafe { cap_rand::thread_rng() };
+    rng.gen()
+}
+
+/// Sends a query to a server over UDP and waits for its response. Modules run on blocking
+/// threads, so this blocks too.
+fn exchange(server: SocketAddr, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(request)?;
+    let mut response = vec![0; 512];
+    let len = socket.recv(&mut response)?;
+    response.truncate(len);
+    Ok(response)
+}
+
+/// Encodes a recursive query for the records of one type of a name.
+fn encode_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid name {:?}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Reads the addresses from the answers of a response. A name that doesn't exist has none.
+fn parse_response(id: u16, response: &[u8]) -> anyhow::Result<Vec<IpAddr>> {
+    let truncated = || anyhow::anyhow!("truncated DNS response");
+    let u16_at = |offset: usize| -> anyhow::Result<u16> {
+        response
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    if u16_at(0)? != id {
+        anyhow::bail!("DNS response is for a different query");
+    }
+    let flags = u16_at(2)?;
+    match flags & 0x000f {
+        0 => (),
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => anyhow::bail!("DNS server responded with error code {}", rcode),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(response, offset).ok_or_else(truncated)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(response, offset).ok_or_else(truncated)?;
+        let rtype = u16_at(offset)?;
+        let rdlength = u16_at(offset + 8)? as usize;
+        let rdata = response
+            .get(offset + 10..offset + 10 + rdlength)
+            .ok_or_else(truncated)?;
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+                addrs.push(IpAddr::from(octets));
+            }
+            // Aliases are followed by the server, so their targets are among the answers too
+            _ => (),
+        }
+        offset += 10 + rdlength;
+    }
+    Ok(addrs)
+}
+
+/// The offset just past the (possibly compressed) name that starts at an offset.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            // A pointer to the rest of the name elsewhere in the message
+            l if l & 0xc0 == 0xc0 => return Some(offset + 2),
+            l => offset += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kubelet::environment::SystemNet;
+
+    fn resolver() -> ClusterDns {
+        ClusterDnsConfig {
+            servers: vec![],
+            domain: "cluster.local".to_owned(),
+            net: Arc::new(SystemNet),
+        }
+        .resolver("apps")
+    }
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(
+            resolver().candidates("web").unwrap(),
+            vec![
+                "web.apps.svc.cluster.local",
+                "web.svc.cluster.local",
+                "web.cluster.local",
+                "web"
+            ]
+        );
+        assert_eq!(
+            resolver()
+                .candidates("web.other.svc.cluster.local.")
+                .unwrap(),
+            vec!["web.other.svc.cluster.local"]
+        );
+        assert_eq!(
+            resolver().candidates("a.b.c.d.e.f").unwrap()[0],
+            "a.b.c.d.e.f"
+        );
+        assert!(resolver().candidates("web..apps").is_err());
+    }
+
+    const SERVFAIL: u8 = 2;
+
+    /// Answers queries on a local port: names in `failing` fail, `web.svc.cluster.local` has
+    /// an IPv4 address and nothing else exists.
+    fn responder(failing: &'static [&'static str]) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || loop {
+            let mut request = vec![0; 512];
+            let (len, peer) = match socket.recv_from(&mut request) {
+                Ok(received) => received,
+                Err(_) => return,
+            };
+            request.truncate(len);
+            let mut labels = Vec::new();
+            let mut offset = 12;
+            while request[offset] != 0 {
+                let len = request[offset] as usize;
+                labels.push(
+                    String::from_utf8_lossy(&request[offset + 1..offset + 1 + len]).into_owned(),
+                );
+                offset += 1 + len;
+            }
+            let name = labels.join(".");
+            let qtype = u16::from_be_bytes([request[offset + 1], request[offset + 2]]);
+
+            let mut response = request.clone();
+            response[2] = 0x81;
+            if failing.contains(&name.as_str()) {
+                response[3] = 0x80 | SERVFAIL;
+            } else if name == "web.svc.cluster.local" && qtype == TYPE_A {
+                response[3] = 0x80;
+                response[7] = 1;
+                response.extend_from_slice(&[0xc0, 12]);
+                response.extend_from_slice(&TYPE_A.to_be_bytes());
+                response.extend_from_slice(&CLASS_IN.to_be_bytes());
+                response.extend_from_slice(&30u32.to_be_bytes());
+                response.extend_from_slice(&4u16.to_be_bytes());
+                response.extend_from_slice(&[10, 96, 0, 12]);
+            } else {
+                response[3] = 0x80 | RCODE_NXDOMAIN as u8;
+            }
+            socket.send_to(&response, peer).unwrap();
+        });
+        addr
+    }
+
+    /// Runs a module that resolves `name` with the host function, returning what it returned
+    /// and the text it was given.
+    fn resolve_in_module(dns: &ClusterDns, name: &str) -> (i32, String) {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(
+            &engine,
+            format!(
+                r#"(module
+                    (import "krustlet_dns" "resolve" (func $resolve (param i32 i32 i32 i32) (result i32)))
+                    (memory (export "memory") 1)
+                    (data (i32.const 0) "{}")
+                    (func (export "run") (result i32)
+                        (call $resolve (i32.const 0) (i32.const {}) (i32.const 256) (i32.const 64))))"#,
+                name,
+                name.len()
+            ),
+        )
+        .unwrap();
+        let mut store = wasmtime::Store::new(
+            &engine,
+            ModuleState {
+                wasi: wasi_cap_std_sync::WasiCtxBuilder::new().build(),
+                meter: crate::wasi_runtime::MemoryMeter::new(Default::default()),
+            },
+        );
+        let mut linker = Linker::new(&engine);
+        dns.add_to_linker(&mut linker).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let written = instance
+            .get_typed_func::<(), i32, _>(&mut store, "run")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        let mut output = vec![0; written.max(0) as usize];
+        memory.read(&store, 256, &mut output).unwrap();
+        (written, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_resolve_host_function() {
+        let dns = ClusterDnsConfig {
+            servers: vec![responder(&["web.apps.svc.cluster.local"])],
+            domain: "cluster.local".to_owned(),
+            net: Arc::new(SystemNet),
+        }
+        .resolver("apps");
+
+        // The failing first candidate doesn't stop the search
+        assert_eq!(
+            resolve_in_module(&dns, "web"),
+            (10, "10.96.0.12".to_owned())
+        );
+        assert_eq!(resolve_in_module(&dns, "missing").0, RESOLVE_NOT_FOUND);
+        assert_eq!(resolve_in_module(&dns, "web..").0, RESOLVE_LOOKUP_FAILED);
+
+        let failing = ClusterDnsConfig {
+            servers: vec![responder(&[
+                "nothing.apps.svc.cluster.local",
+                "nothing.svc.cluster.local",
+                "nothing.cluster.local",
+                "nothing",
+            ])],
+            domain: "cluster.local".to_owned(),
+            net: Arc::new(SystemNet),
+        }
+        .resolver("apps");
+        assert_eq!(
+            resolve_in_module(&failing, "nothing").0,
+            RESOLVE_LOOKUP_FAILED
+        );
+    }
+
+    #[test]
+    fn test_query_ids_differ() {
+        let ids: std::collections::HashSet<u16> = (0..16).map(|_| query_id()).collect();
+        assert!(ids.len() > 1);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = encode_query(7, "web.apps.svc.cluster.local", TYPE_A).unwrap();
+        // A response with one answer
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        // The answer's name points back at the question's
+        response.extend_from_slice(&[0xc0, 12]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&30u32.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&[10, 96, 0, 12]);
+        assert_eq!(
+            parse_response(7, &response).unwrap(),
+            vec![IpAddr::from([10, 96, 0, 12])]
+        );
+        assert!(parse_response(8, &response).is_err());
+
+        // The name doesn't exist
+        response[3] = 0x83;
+        assert!(parse_response(7, &response).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! Support for letting pods tune the wasmtime engine that runs their modules.
+//!
+//! Only a small set of options can be set, and each one has to be allowed by the node policy
+//! through an `engine-<option>` entry in the allowed pod overrides (for example
+//! `engine-opt-level`).
+use wasmtime::OptLevel;
+
+/// The prefix used in the node's allowed pod overrides to permit setting an engine option
+const ENGINE_OVERRIDE_PREFIX: &str = "engine-";
+/// The largest stack a pod may ask for, to keep a module from exhausting the runtime thread
+const MAX_WASM_STACK_LIMIT: usize = 8 * 1024 * 1024;
+
+/// The engine options a pod has asked for. Options that aren't set keep wasmtime's defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EngineOptions {
+    opt_level: Option<OptLevel>,
+    max_wasm_stack: Option<usize>,
+    simd: Option<bool>,
+    threads: Option<bool>,
+    cache: Option<bool>,
+}
+
+impl EngineOptions {
+    /// Parses a comma separated list of `option=value` pairs from an annotation, rejecting any
+    /// option that the node policy does not allow to be set.
+    ///
+    /// The supported options are `opt-level` (`none`, `speed` or `speed-and-size`),
+    /// `max-wasm-stack` (in bytes), and the `simd`, `threads` and `cache` switches (`true` or
+    /// `false`). Only `threads=false` is accepted: the runtime gives a module no way to start
+    /// threads, so enabling the threads proposal would only let it declare shared memories
+    /// that nothing else can use.
+    pub fn from_annotation(value: &str, allowed_overrides: &[String]) -> anyhow::Result<Self> {
+        let mut options = EngineOptions::default();
+        for pair in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .map(|(n, v)| (n.trim(), v.trim()))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("engine option {:?} is not of the form option=value", pair)
+                })?;
+            match name {
+                "opt-level" => options.opt_level = Some(parse_opt_level(value)?),
+                "max-wasm-stack" => options.max_wasm_stack = Some(parse_stack_size(value)?),
+                "simd" => options.simd = Some(parse_bool(name, value)?),
+                "threads" => options.threads = Some(parse_threads(value)?),
+                "cache" => options.cache = Some(parse_bool(name, value)?),
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "unknown engine option {:?}, expected one of \"opt-level\", \"max-wasm-stack\", \"simd\", \"threads\" or \"cache\"",
+                        other
+                    ))
+                }
+            }
+            let override_name = format!("{}{}", ENGINE_OVERRIDE_PREFIX, name);
+            if !allowed_overrides.contains(&override_name) {
+                return Err(anyhow::anyhow!(
+                    "node policy does not allow the {} override",
+                    override_name
+                ));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies the options to the engine configuration.
+    pub fn apply(&self, config: &mut wasmtime::Config) -> anyhow::Result<()> {
+        if let Some(opt_level) = self.opt_level {
+            config.cranelift_opt_level(opt_level);
+        }
+        if let Some(size) = self.max_wasm_stack {
+            config.max_wasm_stack(size)?;
+        }
+        if let Some(enabled) = self.simd {
+            config.wasm_simd(enabled);
+        }
+        if let Some(enabled) = self.threads {
+            config.wasm_threads(enabled);
+        }
+        if self.cache == Some(true) {
+            config.cache_config_load_default()?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_opt_level(value: &str) -> anyhow::Result<OptLevel> {
+    match value {
+        "none" => Ok(OptLevel::None),
+        "speed" => Ok(OptLevel::Speed),
+        "speed-and-size" => Ok(OptLevel::SpeedAndSize),
+        other => Err(anyhow::anyhow!(
+            "unknown opt-level {:?}, expected one of \"none\", \"speed\" or \"speed-and-size\"",
+            other
+        )),
+    }
+}
+
+fn parse_stack_size(value: &str) -> anyhow::Result<usize> {
+    let size: usize = value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid max-wasm-stack {:?}: {}", value, e))?;
+    if size == 0 || size > MAX_WASM_STACK_LIMIT {
+        return Err(anyhow::anyhow!(
+            "max-wasm-stack must be between 1 and {} bytes",
+            MAX_WASM_STACK_LIMIT
+        ));
+    }
+    Ok(size)
+}
+
+fn parse_threads(value: &str) -> anyhow::Result<bool> {
+    if parse_bool("threads", value)? {
+        return Err(anyhow::anyhow!(
+            "threads=true is not supported, as modules cannot start threads"
+        ));
+    }
+    Ok(false)
+}
+
+fn parse_bool(name: &str, value: &str) -> anyhow::Result<bool> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{} must be true or false, got {:?}", name, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_allowed_options() {
+        let allowed = vec![
+            "engine-opt-level".to_owned(),
+            "engine-max-wasm-stack".to_owned(),
+            "engine-simd".to_owned(),
+        ];
+        let options = EngineOptions::from_annotation(
+            "opt-level=none, max-wasm-stack=65536, simd=false",
+            &allowed,
+        )
+        .unwrap();
+        assert!(matches!(options.opt_level, Some(OptLevel::None)));
+        assert_eq!(options.max_wasm_stack, Some(65536));
+        assert_eq!(options.simd, Some(false));
+        assert_eq!(options.threads, None);
+
+        let mut config = wasmtime::Config::new();
+        options.apply(&mut config).unwrap();
+    }
+
+    #[test]
+    fn test_reject_invalid_options() {
+        let allowed = vec![
+            "engine-opt-level".to_owned(),
+            "engine-max-wasm-stack".to_owned(),
+        ];
+        assert!(EngineOptions::from_annotation("simd=true", &allowed).is_err());
+        assert!(EngineOptions::from_annotation("opt-level=fast", &allowed).is_err());
+        assert!(EngineOptions::from_annotation("opt-level", &allowed).is_err());
+        assert!(EngineOptions::from_annotation("max-wasm-stack=0", &allowed).is_err());
+        assert!(EngineOptions::from_annotation("fuel=100", &allowed).is_err());
+    }
+
+    #[test]
+    fn test_reject_threads() {
+        let allowed = vec!["engine-threads".to_owned()];
+        assert!(EngineOptions::from_annotation("threads=true", &allowed).is_err());
+        let options = EngineOptions::from_annotation("threads=false", &allowed).unwrap();
+        assert_eq!(options.threads, Some(false));
+    }
+}
@@ -0,0 +1,164 @@
+//! Support for choosing which exported function of a module is run.
+//!
+//! By default the `_start` function of a WASI command is called. A pod can instead name another
+//! export, which lets a library that bundles several commands, or a reactor-style module, be run
+//! without a wrapper binary. When the export takes parameters, they are parsed from the
+//! container's args according to the export's signature. Reactors are initialized by calling
+//! their `_initialize` export first, as the WASI ABI requires.
+use std::collections::HashMap;
+
+use kubelet::container::Container;
+use wasmtime::{FuncType, Val, ValType};
+
+/// The export called for WASI commands
+const COMMAND_EXPORT: &str = "_start";
+/// The export a reactor module uses to initialize itself before any other export is called
+pub const REACTOR_INITIALIZE_EXPORT: &str = "_initialize";
+
+/// The exported function a container runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entrypoint {
+    export: String,
+}
+
+impl Default for Entrypoint {
+    fn default() -> Self {
+        Entrypoint {
+            export: COMMAND_EXPORT.to_owned(),
+        }
+    }
+}
+
+impl Entrypoint {
+    /// Parses the entrypoint of the given container from an annotation. The value is either the
+    /// name of an export, which is used for every container of the pod, or a JSON object of
+    /// container names to export names (for example `{"convert": "to_csv"}`). Containers that
+    /// aren't listed run `_start`.
+    pub fn from_annotation(value: &str, container: &Container) -> anyhow::Result<Self> {
+        let value = value.trim();
+        let export = if value.starts_with('{') {
+            let mut exports: HashMap<String, String> = serde_json::from_str(value)?;
+            match exports.remove(container.name()) {
+                Some(export) => export,
+                None => return Ok(Entrypoint::default()),
+            }
+        } else {
+            value.to_owned()
+        };
+        if export.is_empty() {
+            return Err(anyhow::anyhow!(
+                "entrypoint of container {} must not be empty",
+                container.name()
+            ));
+        }
+        Ok(Entrypoint { export })
+    }
+
+    /// The name of the export to call.
+    pub fn export(&self) -> &str {
+        &self.export
+    }
+
+    /// Whether the module should be initialized as a reactor before the export is called.
+    pub fn is_reactor(&self) -> bool {
+        self.export != COMMAND_EXPORT
+    }
+
+    /// Builds the parameters of the export from the container's args. Exports without
+    /// parameters are called with none, in which case the args are only visible through WASI.
+    pub fn params(&self, ty: &FuncType, args: &[String]) -> anyhow::Result<Vec<Val>> {
+        let params: Vec<ValType> = ty.params().collect();
+        if params.is_empty() {
+            return Ok(Vec::new());
+        }
+        if params.len() != args.len() {
+            return Err(anyhow::anyhow!(
+                "export {} takes {} parameters but {} args were given",
+                self.export,
+                params.len(),
+                args.len()
+            ));
+        }
+        params
+            .iter()
+            .zip(args)
+            .map(|(ty, arg)| parse_param(ty, arg))
+            .collect()
+    }
+}
+
+fn parse_param(ty: &ValType, arg: &str) -> anyhow::Result<Val> {
+    let invalid =
+        |e: &dyn std::fmt::Display| anyhow::anyhow!("invalid {} arg {:?}: {}", ty, arg, e);
+    match ty {
+        ValType::I32 => arg.parse::<i32>().map(Val::I32).map_err(|e| invalid(&e)),
+        ValType::I64 => arg.parse::<i64>().map(Val::I64).map_err(|e| invalid(&e)),
+        ValType::F32 => arg
+            .parse::<f32>()
+            .map(|v| Val::F32(v.to_bits()))
+            .map_err(|e| invalid(&e)),
+        ValType::F64 => arg
+            .parse::<f64>()
+            .map(|v| Val::F64(v.to_bits()))
+            .map_err(|e| invalid(&e)),
+        other => Err(anyhow::anyhow!(
+            "parameters of type {} can't be passed as args",
+            other
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+    fn container() -> Container {
+        Container::new(&KubeContainer {
+            name: "convert".into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_entrypoint() {
+        assert_eq!(Entrypoint::default().export(), "_start");
+        assert!(!Entrypoint::default().is_reactor());
+
+        let entrypoint = Entrypoint::from_annotation("to_csv", &container()).unwrap();
+        assert_eq!(entrypoint.export(), "to_csv");
+        assert!(entrypoint.is_reactor());
+
+        let entrypoint =
+            Entrypoint::from_annotation(r#"{"convert": "to_json"}"#, &container()).unwrap();
+        assert_eq!(entrypoint.export(), "to_json");
+        let entrypoint =
+            Entrypoint::from_annotation(r#"{"other": "to_json"}"#, &container()).unwrap();
+        assert_eq!(entrypoint, Entrypoint::default());
+
+        assert!(Entrypoint::from_annotation("", &container()).is_err());
+    }
+
+    #[test]
+    fn test_typed_params() {
+        let entrypoint = Entrypoint::from_annotation("scale", &container()).unwrap();
+        let ty = FuncType::new(vec![ValType::I32, ValType::F64], vec![]);
+        let params = entrypoint
+            .params(&ty, &["3".to_owned(), "0.5".to_owned()])
+            .unwrap();
+        assert_eq!(params[0].unwrap_i32(), 3);
+        assert_eq!(params[1].unwrap_f64(), 0.5);
+
+        assert!(entrypoint.params(&ty, &["3".to_owned()]).is_err());
+        assert!(entrypoint
+            .params(&ty, &["three".to_owned(), "0.5".to_owned()])
+            .is_err());
+
+        // Args are left to WASI when the export takes no parameters
+        let ty = FuncType::new(vec![], vec![]);
+        assert!(entrypoint
+            .params(&ty, &["--verbose".to_owned()])
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! Extension points for linking additional host functions into modules.
+//!
+//! Applications embedding the [`WasiProvider`](crate::WasiProvider) can register
+//! [`HostExtension`]s under a name. Pods opt in to extensions by listing their names
+//! (comma separated) in the `alpha.wasi.krustlet.dev/host-extensions` annotation, and
+//! the extension is then linked into every module in that pod.
+//!
+//! # Example
+//! ```rust
+//! use wasi_provider::extensions::{HostExtension, HostExtensionRegistry, ModuleState};
+//! use wasmtime::Linker;
+//!
+//! struct Answer;
+//!
+//! impl HostExtension for Answer {
+//!     fn add_to_linker(&self, linker: &mut Linker<ModuleState>) -> anyhow::Result<()> {
+//!         linker.func_wrap("example", "answer", || -> i32 { 42 })?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut registry = HostExtensionRegistry::new();
+//! registry.register("answer", Answer).unwrap();
+//! ```
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use wasi_common::WasiCtx;
+use wasmtime::Linker;
+
+use crate::wasi_runtime::MemoryMeter;
+
+/// The state of the store a module runs in, which host functions can get at through their
+/// [`Caller`](wasmtime::Caller).
+pub struct ModuleState {
+    pub(crate) wasi: WasiCtx,
+    pub(crate) meter: MemoryMeter,
+}
+
+impl ModuleState {
+    /// The WASI context of the module.
+    pub fn wasi(&mut self) -> &mut WasiCtx {
+        &mut self.wasi
+    }
+}
+
+/// A set of host functions that can be linked into a module.
+pub trait HostExtension: Send + Sync {
+    /// Adds the extension's definitions to the linker used to instantiate a module.
+    fn add_to_linker(&self, linker: &mut Linker<ModuleState>) -> anyhow::Result<()>;
+}
+
+/// A registry of named [`HostExtension`]s available to pods.
+#[derive(Clone, Default)]
+pub struct HostExtensionRegistry {
+    extensions: HashMap<String, Arc<dyn HostExtension>>,
+}
+
+impl HostExtensionRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an extension under the given name. Returns an error if an extension with
+    /// that name has already been registered.
+    pub fn register<E: HostExtension + 'static>(
+        &mut self,
+        name: &str,
+        extension: E,
+    ) -> anyhow::Result<&mut Self> {
+        if self.extensions.contains_key(name) {
+            return Err(anyhow::anyhow!(
+                "a host extension named {} is already registered",
+                name
+            ));
+        }
+        self.extensions.insert(name.to_owned(), Arc::new(extension));
+        Ok(self)
+    }
+
+    /// Gets the extension registered with the given name.
+    pub fn get(&self, name: &str) -> Option<Arc<dyn HostExtension>> {
+        self.extensions.get(name).cloned()
+    }
+
+    /// Looks up all extensions named in a comma separated list, failing if any of them
+    /// have not been registered.
+    pub(crate) fn resolve(&self, names: &str) -> anyhow::Result<Vec<Arc<dyn HostExtension>>> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                self.get(name).ok_or_else(|| {
+                    anyhow::anyhow!("no host extension named {} is registered", name)
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Noop;
+
+    impl HostExtension for Noop {
+        fn add_to_linker(&self, _linker: &mut Linker<ModuleState>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_resolve_extensions() {
+        let mut registry = HostExtensionRegistry::new();
+        registry.register("first", Noop).unwrap();
+        registry.register("second", Noop).unwrap();
+        assert_eq!(registry.resolve("first, second").unwrap().len(), 2);
+        assert!(registry.resolve("first,third").is_err());
+        assert!(registry.register("first", Noop).is_err());
+    }
+}
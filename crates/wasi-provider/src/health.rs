@@ -0,0 +1,113 @@
+//! A watchdog that checks the wasmtime runtime still works, tainting the node while it doesn't.
+//!
+//! A module that panics the runtime only fails its own pod. Whether the runtime as a whole is
+//! broken is decided by regularly running a tiny probe module on a fresh engine: while the probe
+//! fails the node is tainted with [`taint::PROVIDER_UNHEALTHY`] and reports
+//! [`problem::RUNTIME_PROBLEM`], and both are cleared as soon as it passes again. A module
+//! panicking makes the watchdog probe straight away rather than at its next interval.
+use std::sync::Once;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tracing::{info, warn};
+use wasmtime::{Engine, Instance, Module, Store};
+
+use kubelet::node::{problem, taint};
+
+/// How often the runtime is probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the probe may take before the runtime is considered stuck
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The module the runtime is probed with, and the value it has to return
+const PROBE_MODULE: &str = r#"(module (func (export "probe") (result i32) i32.const 42))"#;
+const PROBE_RESULT: i32 = 42;
+
+lazy_static::lazy_static! {
+    static ref PROBE_NOW: Notify = Notify::new();
+}
+
+static START: Once = Once::new();
+
+/// Starts the watchdog, unless it is already running.
+pub(crate) fn start() {
+    START.call_once(|| {
+        tokio::spawn(watch());
+    });
+}
+
+/// Makes the watchdog probe the runtime without waiting for its next interval, for example
+/// after a module panicked it.
+pub(crate) fn probe_soon() {
+    PROBE_NOW.notify_one();
+}
+
+async fn watch() {
+    loop {
+        record(probe().await);
+        tokio::select! {
+            _ = tokio::time::sleep(PROBE_INTERVAL) => (),
+            _ = PROBE_NOW.notified() => (),
+        }
+    }
+}
+
+/// Runs the probe module on a blocking thread, returning why it failed if it did.
+async fn probe() -> Result<(), String> {
+    match tokio::time::timeout(PROBE_TIMEOUT, tokio::task::spawn_blocking(run_probe)).await {
+        Ok(Ok(Ok(()))) => Ok(()),
+        Ok(Ok(Err(e))) => Err(format!("{:#}", e)),
+        Ok(Err(e)) => Err(format!("runtime panicked: {}", e)),
+        Err(_) => Err(format!("probe did not finish within {:?}", PROBE_TIMEOUT)),
+    }
+}
+
+fn run_probe() -> anyhow::Result<()> {
+    let engine = Engine::default();
+    let module = Module::new(&engine, PROBE_MODULE)?;
+    let mut store = Store::new(&engine, ());
+    let instance = Instance::new(&mut store, &module, &[])?;
+    let probe = instance.get_typed_func::<(), i32, _>(&mut store, "probe")?;
+    let result = probe.call(&mut store, ())?;
+    if result != PROBE_RESULT {
+        anyhow::bail!("probe returned {} instead of {}", result, PROBE_RESULT);
+    }
+    Ok(())
+}
+
+/// Taints the node and reports the problem while the probe fails, and clears both once it
+/// passes.
+fn record(result: Result<(), String>) {
+    match result {
+        Ok(()) => {
+            if taint::is_tainted(taint::PROVIDER_UNHEALTHY) {
+                info!("Runtime probe passed again, accepting new pods");
+            }
+            problem::resolve(problem::RUNTIME_PROBLEM);
+            taint::remove(taint::PROVIDER_UNHEALTHY);
+        }
+        Err(message) => {
+            warn!(error = %message, "Runtime probe failed, keeping new pods away from the node");
+            problem::report(
+                problem::RUNTIME_PROBLEM,
+                "RuntimeProbeFailed",
+                &format!("The wasmtime runtime failed its health probe: {}", message),
+            );
+            taint::add(taint::PROVIDER_UNHEALTHY, "true", taint::NO_SCHEDULE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_taints_node_only_while_failing() {
+        probe().await.unwrap();
+
+        record(Err("broken".to_owned()));
+        assert!(taint::is_tainted(taint::PROVIDER_UNHEALTHY));
+        record(probe().await);
+        assert!(!taint::is_tainted(taint::PROVIDER_UNHEALTHY));
+    }
+}
@@ -35,28 +35,52 @@
 
 #![deny(missing_docs)]
 
+mod capabilities;
+pub mod dns;
+mod engine_options;
+mod entrypoint;
+pub mod extensions;
+mod health;
+mod log_format;
+mod pool;
+mod stdin;
 mod wasi_runtime;
 
-use std::collections::HashMap;
-use std::convert::TryFrom;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use kubelet::cgroup::CgroupManager;
+use kubelet::dependency::DependencyTracker;
+use kubelet::environment::Clock;
+use kubelet::handle::PodRuntimeInfo;
+use kubelet::ipam::NodeIpam;
+use kubelet::log::LogFileFormat;
 use kubelet::node::Builder;
 use kubelet::plugin_watcher::PluginRegistry;
+use kubelet::pod::dirs::join_name;
 use kubelet::pod::state::prelude::SharedState;
-use kubelet::pod::{Handle, Pod, PodKey};
+use kubelet::pod::{Handle, Pod, PodChange, PodDirs, PodKey, UnfinishedPod};
 use kubelet::provider::{
-    DevicePluginSupport, PluginSupport, Provider, ProviderError, VolumeSupport,
+    DevicePluginSupport, ImagePolicySupport, PluginSupport, Provider, ProviderCapabilities,
+    ProviderError, VolumeSupport,
 };
 use kubelet::resources::DeviceManager;
+use kubelet::scratch::ScratchSpace;
+use kubelet::secret::RegistryAuthResolver;
 use kubelet::state::common::registered::Registered;
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
-use kubelet::store::Store;
+use kubelet::stats::{DiskUsageTracker, PodDiskUsage};
+use kubelet::store::{RegistryPolicy, Store};
 use kubelet::volume::VolumeRef;
 use tokio::sync::RwLock;
+use tracing::warn;
+
+use dns::ClusterDnsConfig;
+use extensions::HostExtensionRegistry;
+use pool::InstancePool;
 use wasi_runtime::Runtime;
 
 mod states;
@@ -66,6 +90,7 @@ use states::pod::PodState;
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
 const VOLUME_DIR: &str = "volumes";
+const SCRATCH_DIR: &str = "scratch";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
@@ -75,17 +100,33 @@ pub struct WasiProvider {
 }
 
 type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, wasi_runtime::HandleFactory>>>>>;
+type RunContextMap = Arc<RwLock<HashMap<PodKey, SharedState<ModuleRunContext>>>>;
 
 /// Provider-level state shared between all pods
 #[derive(Clone)]
 pub struct ProviderState {
     handles: PodHandleMap,
+    run_contexts: RunContextMap,
     store: Arc<dyn Store + Sync + Send>,
     log_path: PathBuf,
+    pod_log_dir: Option<PathBuf>,
+    log_file_format: LogFileFormat,
     client: kube::Client,
     volume_path: PathBuf,
     plugin_registry: Arc<PluginRegistry>,
     device_plugin_manager: Arc<DeviceManager>,
+    allowed_pod_overrides: Vec<String>,
+    host_extensions: HostExtensionRegistry,
+    cluster_dns: Option<ClusterDnsConfig>,
+    registry_policy: Option<Arc<RegistryPolicy>>,
+    record_image_attestations: bool,
+    instance_pool: Option<Arc<InstancePool>>,
+    ipam: Arc<NodeIpam>,
+    dependency_tracker: DependencyTracker,
+    cgroups: Option<Arc<CgroupManager>>,
+    scratch: Arc<ScratchSpace>,
+    disk_usage: Arc<DiskUsageTracker>,
+    clock: Arc<dyn Clock>,
 }
 
 #[async_trait]
@@ -105,12 +146,61 @@ impl GenericProviderState for ProviderState {
             Ok(())
         }
     }
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+}
+
+impl ProviderState {
+    /// The directories the pod's volumes are mounted in and its logs are written to.
+    fn pod_dirs(&self, pod: &Pod) -> anyhow::Result<PodDirs> {
+        PodDirs::new(pod, Some(&self.volume_path), self.pod_log_dir.as_deref())
+    }
+
+    /// Measures the disk used by the pod's logs and scratch space, and records it. When logs are
+    /// also written in the kubelet's layout, only that copy is counted, as it holds every run of
+    /// each container, rather than adding up both copies.
+    async fn measure_disk_usage(&self, pod: &Pod) -> anyhow::Result<PodDiskUsage> {
+        let key = PodKey::from(pod);
+        let mut usage = PodDiskUsage::default();
+        let dirs = self.pod_dirs(pod)?;
+        if let Some(log_dir) = dirs.logs() {
+            for container in pod.all_containers() {
+                let dir = join_name(log_dir, container.name())?;
+                let logs =
+                    tokio::task::spawn_blocking(move || kubelet::stats::dir_usage(&dir)).await??;
+                if logs > 0 {
+                    usage
+                        .containers
+                        .entry(container.name().to_owned())
+                        .or_default()
+                        .logs += logs;
+                }
+            }
+        } else {
+            let handle = self.handles.read().await.get(&key).cloned();
+            if let Some(handle) = handle {
+                for (container, logs) in handle.log_usage::<tokio::fs::File>().await {
+                    usage.containers.entry(container).or_default().logs += logs;
+                }
+            }
+        }
+        for (container, scratch) in self.scratch.pod_usage(&key).await? {
+            usage.containers.entry(container).or_default().scratch = scratch;
+        }
+        self.disk_usage.record(pod, usage.clone());
+        Ok(usage)
+    }
 }
 
 impl VolumeSupport for ProviderState {
     fn volume_path(&self) -> Option<&Path> {
         Some(self.volume_path.as_ref())
     }
+
+    fn dependency_tracker(&self) -> Option<DependencyTracker> {
+        Some(self.dependency_tracker.clone())
+    }
 }
 
 impl PluginSupport for ProviderState {
@@ -125,6 +215,16 @@ impl DevicePluginSupport for ProviderState {
     }
 }
 
+impl ImagePolicySupport for ProviderState {
+    fn registry_policy(&self) -> Option<Arc<RegistryPolicy>> {
+        self.registry_policy.clone()
+    }
+
+    fn record_image_attestations(&self) -> bool {
+        self.record_image_attestations
+    }
+}
+
 impl WasiProvider {
     /// Create a new wasi provider from a module store and a kubelet config
     pub async fn new(
@@ -138,25 +238,72 @@ impl WasiProvider {
         let volume_path = config.data_dir.join(VOLUME_DIR);
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
-        let client = kube::Client::try_from(kubeconfig)?;
+        let client = kubelet::kube_client::instrumented_client(
+            kubeconfig,
+            config.slow_api_call_threshold,
+            &config.environment,
+        )?;
+        // The node may not be registered yet, so its pod CIDR is read when the first pod
+        // needs an IP
+        let ipam = Arc::new(NodeIpam::new(
+            client.clone(),
+            &config.node_name,
+            config.pod_cidr.as_deref(),
+        )?);
+        let dependency_tracker =
+            DependencyTracker::new(client.clone(), config.environment.clock.clone());
+        let cgroups = CgroupManager::detect(config.environment.clock.clone());
+        health::start();
         Ok(Self {
             shared: ProviderState {
                 handles: Default::default(),
+                run_contexts: Default::default(),
                 store,
                 log_path,
+                pod_log_dir: config.pod_log_dir.clone(),
+                log_file_format: if config.cri_container_logs {
+                    LogFileFormat::Cri
+                } else {
+                    LogFileFormat::Plain
+                },
                 volume_path,
                 client,
                 plugin_registry,
                 device_plugin_manager,
+                allowed_pod_overrides: config.allowed_pod_overrides.clone(),
+                host_extensions: HostExtensionRegistry::default(),
+                cluster_dns: ClusterDnsConfig::from_config(config),
+                registry_policy: RegistryPolicy::from_config(config).map(Arc::new),
+                record_image_attestations: config.record_image_attestations,
+                instance_pool: InstancePool::from_config(config)?.map(Arc::new),
+                ipam,
+                dependency_tracker,
+                cgroups: cgroups.clone(),
+                scratch: Arc::new(ScratchSpace::new(
+                    config.data_dir.join(SCRATCH_DIR),
+                    config.scratch_quota,
+                )),
+                disk_usage: Arc::new(
+                    DiskUsageTracker::new(&config.node_name).with_cgroups(cgroups),
+                ),
+                clock: config.environment.clock.clone(),
             },
         })
     }
+
+    /// Makes the host extensions in the registry available to pods that request them.
+    pub fn with_host_extensions(mut self, registry: HostExtensionRegistry) -> Self {
+        self.shared.host_extensions = registry;
+        self
+    }
 }
 
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, VolumeRef>,
     env_vars: HashMap<String, HashMap<String, String>>,
+    /// The containers being stopped to be started again with an updated manifest
+    replacing: HashSet<String>,
 }
 
 #[async_trait::async_trait]
@@ -172,6 +319,14 @@ impl Provider for WasiProvider {
         Arc::new(RwLock::new(self.shared.clone()))
     }
 
+    fn store(&self) -> Option<Arc<dyn Store + Send + Sync>> {
+        Some(self.shared.store.clone())
+    }
+
+    fn disk_usage_tracker(&self) -> Option<Arc<DiskUsageTracker>> {
+        Some(self.shared.disk_usage.clone())
+    }
+
     async fn node(&self, builder: &mut Builder) -> anyhow::Result<()> {
         builder.set_architecture("wasm-wasi");
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
@@ -180,7 +335,140 @@ impl Provider for WasiProvider {
     }
 
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
-        Ok(PodState::new(pod))
+        let state = PodState::new(pod, self.shared.pod_dirs(pod)?, self.shared.clock.clone());
+        self.shared
+            .run_contexts
+            .write()
+            .await
+            .insert(PodKey::from(pod), state.run_context());
+        Ok(state)
+    }
+
+    // Downward API volumes are the only place a running module can see the pod's metadata
+    async fn update_pod_metadata(&self, pod: &Pod) -> anyhow::Result<()> {
+        let run_context = match self
+            .shared
+            .run_contexts
+            .read()
+            .await
+            .get(&PodKey::from(pod))
+        {
+            Some(run_context) => run_context.clone(),
+            None => return Ok(()),
+        };
+        let mut run_context = run_context.write().await;
+        for volume in run_context.volumes.values_mut() {
+            volume.update_pod_metadata(pod).await?;
+        }
+        Ok(())
+    }
+
+    // A container is replaced by pulling the module of its new image, then stopping its running
+    // module so that its state machine starts it again from the latest manifest
+    async fn update_container(
+        &self,
+        pod: &Pod,
+        container_name: &str,
+        _change: &PodChange,
+    ) -> anyhow::Result<()> {
+        let key = PodKey::from(pod);
+        let container = match pod
+            .containers()
+            .into_iter()
+            .find(|c| c.name() == container_name)
+        {
+            Some(container) => container,
+            // Init containers have already run to completion, so there is nothing to replace
+            None => return Ok(()),
+        };
+        let run_context = self
+            .shared
+            .run_contexts
+            .read()
+            .await
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod.name().to_owned(),
+            })?;
+
+        let image = container
+            .image()?
+            .ok_or_else(|| anyhow::anyhow!("container {} has no image", container_name))?;
+        if let Some(policy) = &self.shared.registry_policy {
+            policy.check(&image).map_err(|e| anyhow::anyhow!(e))?;
+        }
+        let auth = RegistryAuthResolver::new(self.shared.client(), pod)
+            .resolve_registry_auth(&image)
+            .await?;
+        let module = self
+            .shared
+            .store
+            .get_for_namespace(
+                &image,
+                container.effective_pull_policy()?,
+                &auth,
+                pod.namespace(),
+            )
+            .await?;
+        {
+            let mut run_context = run_context.write().await;
+            run_context
+                .modules
+                .insert(container_name.to_owned(), module);
+            run_context.replacing.insert(container_name.to_owned());
+        }
+
+        let handle = self.shared.handles.read().await.get(&key).cloned();
+        let stopped = match handle {
+            Some(handle) => handle.stop_container(container_name).await,
+            None => Err(ProviderError::PodNotFound {
+                pod_name: pod.name().to_owned(),
+            }
+            .into()),
+        };
+        // A container that isn't running yet starts with the new module anyway
+        if stopped.is_err() {
+            run_context.write().await.replacing.remove(container_name);
+        }
+        stopped
+    }
+
+    // The pod's modules are interrupted as soon as it is marked for deletion rather than once
+    // its state machine reaches Terminated, so that they stop using the node while other work
+    // is still queued
+    async fn pod_terminating(&self, pod: &Pod) -> anyhow::Result<()> {
+        let key = PodKey::from(pod);
+        let run_context = self.shared.run_contexts.read().await.get(&key).cloned();
+        // Containers that were being replaced must not be started again
+        if let Some(run_context) = run_context {
+            run_context.write().await.replacing.clear();
+        }
+        self.shared.stop(pod).await
+    }
+
+    // Mounted volumes, scratch space and the pod cgroups outlive a crash of the Kubelet, unlike
+    // everything else the provider keeps for a pod. The pod's logs are kept, as they are whenever
+    // a pod restarts
+    async fn recover_pod(&self, pod: &UnfinishedPod) -> anyhow::Result<()> {
+        let key = PodKey::from(&pod.pod);
+        let dirs = PodDirs::new(&pod.pod, Some(&self.shared.volume_path), None)?;
+        // Volumes may have been mounted by a Kubelet from before the current directory layout
+        for volumes in dirs.volumes().into_iter().chain(dirs.legacy_volumes()) {
+            VolumeRef::unmount_leftovers(
+                &pod.pod,
+                volumes,
+                &self.shared.client,
+                Some(self.shared.plugin_registry.clone()),
+            )
+            .await?;
+        }
+        dirs.remove().await?;
+        self.shared.scratch.remove_pod(&key).await?;
+        match &self.shared.cgroups {
+            Some(cgroups) => cgroups.remove_pod(&key).await,
+            None => Ok(()),
+        }
     }
 
     async fn logs(
@@ -199,10 +487,63 @@ impl Provider for WasiProvider {
         handle.output(&container_name, sender).await
     }
 
-    // Evict all pods upon shutdown
-    async fn shutdown(&self, node_name: &str) -> anyhow::Result<()> {
-        node::drain(&self.shared.client, &node_name).await?;
-        Ok(())
+    async fn attach(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        stdin: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
+        sender: kubelet::log::Sender,
+    ) -> anyhow::Result<()> {
+        let handles = self.shared.handles.read().await;
+        let handle = handles
+            .get(&PodKey::new(&namespace, &pod_name))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.attach(&container_name, stdin, sender).await
+    }
+
+    async fn runtime_info(&self) -> anyhow::Result<Vec<PodRuntimeInfo>> {
+        let handles: Vec<_> = self.shared.handles.read().await.values().cloned().collect();
+        let mut pods = Vec::with_capacity(handles.len());
+        for handle in handles {
+            pods.push(handle.runtime_info().await);
+        }
+        pods.sort_by(|a, b| a.pod.cmp(&b.pod));
+        Ok(pods)
+    }
+
+    // Evict all pods upon shutdown, then stop whatever is still running (such as DaemonSet pods,
+    // which are never evicted) so that no module outlives the Kubelet
+    async fn shutdown(
+        &self,
+        node_name: &str,
+        deadline: Option<tokio::time::Instant>,
+    ) -> anyhow::Result<()> {
+        let drain = node::drain(&self.shared.client, &node_name);
+        let drained = match deadline {
+            // Leave some of the time to stop the remaining pods
+            Some(deadline) => {
+                let now = tokio::time::Instant::now();
+                let drain_deadline = now + deadline.saturating_duration_since(now) / 2;
+                tokio::time::timeout_at(drain_deadline, drain)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("node was not drained in time")))
+            }
+            None => drain.await,
+        };
+        if let Err(e) = &drained {
+            warn!(error = %e, "Unable to drain node, stopping the remaining pods");
+        }
+
+        let handles = self.shared.handles.read().await;
+        for (key, handle) in handles.iter() {
+            if let Err(e) = handle.stop().await {
+                warn!(error = %e, pod = ?key, "Unable to stop pod");
+            }
+        }
+        drained
     }
 }
 
@@ -211,6 +552,17 @@ impl GenericProvider for WasiProvider {
     type PodState = PodState;
     type RunState = crate::states::pod::initializing::Initializing;
 
+    fn capabilities() -> ProviderCapabilities {
+        // WebAssembly modules have no processes or IPC to share with the host, and nothing for
+        // privileges to grant them
+        ProviderCapabilities {
+            host_pid: false,
+            host_ipc: false,
+            privileged: false,
+            ..Default::default()
+        }
+    }
+
     fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
         Ok(())
     }
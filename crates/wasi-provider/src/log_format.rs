@@ -0,0 +1,220 @@
+//! Handling for the format of module output written to the container log file.
+use std::io::Write;
+
+use serde_json::{Map, Value};
+
+/// The key under which pod and container metadata is added to each parsed record
+const METADATA_KEY: &str = "kubernetes";
+
+/// The format in which a module's stdout is written to the log file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Output is written as-is
+    Raw,
+    /// Each line of output is parsed as a JSON object and enriched with the
+    /// given metadata. Lines that fail to parse are passed through verbatim
+    JsonLines(LogMetadata),
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Raw
+    }
+}
+
+impl LogFormat {
+    /// Parses the log format from the value of the log format annotation.
+    pub fn from_annotation(value: &str, metadata: LogMetadata) -> anyhow::Result<Self> {
+        match value {
+            "raw" => Ok(LogFormat::Raw),
+            "json-lines" => Ok(LogFormat::JsonLines(metadata)),
+            other => Err(anyhow::anyhow!(
+                "unknown log format {:?}, expected one of \"raw\" or \"json-lines\"",
+                other
+            )),
+        }
+    }
+}
+
+/// Metadata about the source of a log record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogMetadata {
+    pub namespace: String,
+    pub pod: String,
+    pub container: String,
+}
+
+impl LogMetadata {
+    fn to_value(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("namespace".into(), Value::String(self.namespace.clone()));
+        map.insert("pod".into(), Value::String(self.pod.clone()));
+        map.insert("container".into(), Value::String(self.container.clone()));
+        Value::Object(map)
+    }
+}
+
+/// A writer that treats its input as JSON lines, enriching every record with
+/// [`LogMetadata`] before writing it to the inner writer.
+///
+/// Input is buffered until a full line is available. Any line that isn't a
+/// JSON object is written through unchanged, as is a trailing partial line
+/// when the writer is dropped.
+pub struct JsonLinesWriter<W: Write> {
+    inner: W,
+    metadata: Value,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    /// Creates a new writer wrapping `inner`.
+    pub fn new(inner: W, metadata: LogMetadata) -> Self {
+        JsonLinesWriter {
+            inner,
+            metadata: metadata.to_value(),
+            buf: Vec::new(),
+        }
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        match serde_json::from_slice::<Value>(line) {
+            Ok(Value::Object(mut record)) => {
+                if !record.contains_key(METADATA_KEY) {
+                    record.insert(METADATA_KEY.into(), self.metadata.clone());
+                }
+                serde_json::to_writer(&mut self.inner, &Value::Object(record))?;
+                self.inner.write_all(b"\n")
+            }
+            // Anything that isn't a JSON object is passed through untouched
+            _ => {
+                self.inner.write_all(line)?;
+                self.inner.write_all(b"\n")
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for JsonLinesWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            self.write_line(&line[..line.len() - 1])?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for JsonLinesWriter<W> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let _ = self.inner.write_all(&self.buf);
+        }
+        let _ = self.inner.flush();
+    }
+}
+
+/// A writer that writes everything to two inner writers, such as the container log file and its
+/// copy in the kubelet's log layout.
+pub struct TeeWriter<A: Write, B: Write> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    /// Creates a new writer wrapping `first` and `second`.
+    pub fn new(first: A, second: B) -> Self {
+        TeeWriter { first, second }
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.first.write_all(data)?;
+        self.second.write_all(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn metadata() -> LogMetadata {
+        LogMetadata {
+            namespace: "default".into(),
+            pod: "mypod".into(),
+            container: "app".into(),
+        }
+    }
+
+    fn write_all(input: &[&[u8]]) -> String {
+        let mut out = Vec::new();
+        {
+            let mut writer = JsonLinesWriter::new(&mut out, metadata());
+            for chunk in input {
+                writer.write_all(chunk).unwrap();
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_enriches_json_records() {
+        let output = write_all(&[b"{\"msg\":\"hello\"}\n"]);
+        let record: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(record["msg"], "hello");
+        assert_eq!(record["kubernetes"]["namespace"], "default");
+        assert_eq!(record["kubernetes"]["pod"], "mypod");
+        assert_eq!(record["kubernetes"]["container"], "app");
+    }
+
+    #[test]
+    fn test_passes_through_invalid_lines() {
+        let output = write_all(&[b"not json\n", b"[1, 2]\n"]);
+        assert_eq!(output, "not json\n[1, 2]\n");
+    }
+
+    #[test]
+    fn test_buffers_partial_lines() {
+        let output = write_all(&[b"{\"msg\":", b"\"split\"}\nleftover"]);
+        let mut lines = output.lines();
+        let record: Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(record["msg"], "split");
+        assert_eq!(lines.next(), Some("leftover"));
+    }
+
+    #[test]
+    fn test_tee_writes_both() {
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        {
+            let mut writer =
+                JsonLinesWriter::new(TeeWriter::new(&mut first, &mut second), metadata());
+            writer.write_all(b"plain\n").unwrap();
+        }
+        assert_eq!(first, b"plain\n");
+        assert_eq!(second, b"plain\n");
+    }
+
+    #[test]
+    fn test_parse_annotation() {
+        assert_eq!(
+            LogFormat::from_annotation("raw", metadata()).unwrap(),
+            LogFormat::Raw
+        );
+        assert_eq!(
+            LogFormat::from_annotation("json-lines", metadata()).unwrap(),
+            LogFormat::JsonLines(metadata())
+        );
+        assert!(LogFormat::from_annotation("yaml", metadata()).is_err());
+    }
+}
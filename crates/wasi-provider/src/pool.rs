@@ -0,0 +1,403 @@
+//! A node-wide pool of instance slots, and of the compiled modules the node has recently run.
+//!
+//! Without the pool, every container start creates a wasmtime engine, compiles its module and
+//! allocates the instance's memories and tables from scratch. With it, each module is compiled
+//! once per engine configuration and the compiled module is kept by the digest of the module,
+//! and instances are allocated from slots that wasmtime's pooling allocator reserves up front.
+//! Restarting a container, or scaling up a deployment whose image the node already runs, skips
+//! compilation and the allocation of the instance's memories.
+//!
+//! Modules that only import WASI functions are also pre-instantiated: their imports resolve to
+//! the same host functions in every container, so the first start of a module resolves them
+//! and later starts reuse them. Modules that import anything else, such as a host extension or
+//! WASI HTTP, which are linked for each container with its own state, have their imports
+//! resolved on every start.
+//!
+//! The pool has a fixed number of instance slots, which share the node's instance pool memory
+//! budget equally, and an instance can't grow its memory past its slot. A container whose module
+//! needs more memory than a slot has, any of whose memories may grow past a slot, that
+//! otherwise doesn't fit the pool's limits, or that starts while every slot is in use, runs
+//! outside the pool just as it would without one.
+//!
+//! A container's memory limit doesn't depend on the pool: the limiter of the store the module
+//! runs in refuses to grow its memory past the limit, whether or not the module is pooled.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use kubelet::handle::module_digest;
+use tracing::{debug, warn};
+use wasmparser::{ImportSectionEntryType, MemoryType, Payload};
+use wasmtime::{
+    Engine, Instance, InstanceAllocationStrategy, InstanceLimits, InstancePre, Linker, Module,
+    ModuleLimits, PoolingAllocationStrategy, Store,
+};
+
+use crate::engine_options::EngineOptions;
+use crate::extensions::ModuleState;
+
+/// The size of a WebAssembly memory page
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+/// The most pages a 32-bit memory can have
+const MAX_MEMORY_PAGES: u64 = 65536;
+/// The modules WASI functions are imported from, by the current and the previous snapshot
+const WASI_MODULES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+
+/// A pool of instance slots, and the compiled modules instantiated in them.
+pub(crate) struct InstancePool {
+    size: u32,
+    memory_pages: u32,
+    in_use: Arc<AtomicU32>,
+    engines: Mutex<Vec<Arc<PooledEngine>>>,
+}
+
+/// An engine using the pooling allocator, for the pods that ask for the same engine options.
+struct PooledEngine {
+    options: EngineOptions,
+    engine: Engine,
+    /// The most recently compiled modules and their digests, oldest first
+    compiled: Mutex<VecDeque<(String, Arc<CompiledModule>)>>,
+}
+
+/// A module compiled for a pooled engine.
+struct CompiledModule {
+    module: Module,
+    /// Whether every import of the module is a WASI function
+    wasi_only: bool,
+    /// The module with its imports resolved, once a start of a module that only imports WASI
+    /// functions has resolved them
+    pre_instantiated: Mutex<Option<Arc<InstancePre<ModuleState>>>>,
+}
+
+/// A compiled module and the pool slot reserved for instantiating it, which is freed when this
+/// is dropped.
+pub(crate) struct PooledModule {
+    pub(crate) engine: Engine,
+    compiled: Arc<CompiledModule>,
+    _slot: Slot,
+}
+
+impl PooledModule {
+    /// The compiled module.
+    pub(crate) fn module(&self) -> &Module {
+        &self.compiled.module
+    }
+
+    /// Instantiates the module in `store`, resolving its imports with `linker` unless an earlier
+    /// start already resolved them.
+    pub(crate) fn instantiate(
+        &self,
+        store: &mut Store<ModuleState>,
+        linker: &Linker<ModuleState>,
+    ) -> anyhow::Result<Instance> {
+        if !self.compiled.wasi_only {
+            return linker.instantiate(store, &self.compiled.module);
+        }
+        let pre_instantiated = self.compiled.pre_instantiated.lock().unwrap().clone();
+        let pre_instantiated = match pre_instantiated {
+            Some(pre_instantiated) => pre_instantiated,
+            None => {
+                let pre_instantiated =
+                    Arc::new(linker.instantiate_pre(&mut *store, &self.compiled.module)?);
+                *self.compiled.pre_instantiated.lock().unwrap() = Some(pre_instantiated.clone());
+                pre_instantiated
+            }
+        };
+        pre_instantiated.instantiate(store)
+    }
+}
+
+struct Slot(Arc<AtomicU32>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InstancePool {
+    /// Creates a pool of `size` instance slots that share `memory_budget` bytes of linear memory.
+    pub(crate) fn new(size: u32, memory_budget: u64) -> anyhow::Result<Self> {
+        if size == 0 {
+            anyhow::bail!("instance pool must have at least one slot");
+        }
+        let memory_pages = (memory_budget / u64::from(size) / WASM_PAGE_SIZE).min(MAX_MEMORY_PAGES);
+        if memory_pages == 0 {
+            anyhow::bail!(
+                "instance pool memory of {} bytes is less than one {} byte page for each of its {} slots",
+                memory_budget,
+                WASM_PAGE_SIZE,
+                size
+            );
+        }
+        Ok(InstancePool {
+            size,
+            memory_pages: memory_pages as u32,
+            in_use: Arc::new(AtomicU32::new(0)),
+            engines: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Creates the pool set in the Kubelet's configuration, if it sets one.
+    pub(crate) fn from_config(config: &kubelet::config::Config) -> anyhow::Result<Option<Self>> {
+        if config.instance_pool_size == 0 {
+            return Ok(None);
+        }
+        InstancePool::new(config.instance_pool_size, config.instance_pool_memory).map(Some)
+    }
+
+    /// Reserves a slot for the module and returns it compiled for an engine with the given
+    /// options, or `None` if it has to run outside the pool.
+    pub(crate) fn checkout(
+        &self,
+        options: &EngineOptions,
+        module_data: &[u8],
+    ) -> Option<PooledModule> {
+        let slot = match self.acquire() {
+            Some(slot) => slot,
+            None => {
+                debug!(size = self.size, "Every instance pool slot is in use");
+                return None;
+            }
+        };
+        let pooled = match self.engine(options) {
+            Ok(pooled) => pooled,
+            Err(e) => {
+                warn!(error = %e, "Unable to create pooled engine, running module outside of the pool");
+                return None;
+            }
+        };
+        let digest = module_digest(module_data);
+        let cached = pooled
+            .compiled
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(d, _)| *d == digest)
+            .map(|(_, module)| module.clone());
+        let compiled = match cached {
+            Some(compiled) => compiled,
+            None => match self.compile(&pooled.engine, module_data) {
+                Ok(module) => {
+                    let module = Arc::new(module);
+                    let mut compiled = pooled.compiled.lock().unwrap();
+                    // There is no use keeping more modules than can be instantiated at once
+                    if compiled.len() >= self.size as usize {
+                        compiled.pop_front();
+                    }
+                    compiled.push_back((digest, module.clone()));
+                    module
+                }
+                Err(e) => {
+                    debug!(error = %e, "Module doesn't fit the instance pool, running it outside of the pool");
+                    return None;
+                }
+            },
+        };
+        Some(PooledModule {
+            engine: pooled.engine.clone(),
+            compiled,
+            _slot: slot,
+        })
+    }
+
+    /// Compiles the module for a pooled engine, failing if it doesn't fit a slot. Compiling
+    /// already fails if the initial size of a memory exceeds the slot, but a memory that may grow
+    /// larger, whether by its declared maximum or because it declares none, would compile and
+    /// then fail to grow in the pool where it wouldn't outside.
+    fn compile(&self, engine: &Engine, module_data: &[u8]) -> anyhow::Result<CompiledModule> {
+        let binary = wat::parse_bytes(module_data)?;
+        for maximum in memory_maximums(&binary)? {
+            match maximum {
+                Some(max) if max > u64::from(self.memory_pages) => anyhow::bail!(
+                    "memory may grow to {} pages, but the pool's slots have {}",
+                    max,
+                    self.memory_pages
+                ),
+                None if u64::from(self.memory_pages) < MAX_MEMORY_PAGES => anyhow::bail!(
+                    "memory has no maximum, so may grow past the {} pages of the pool's slots",
+                    self.memory_pages
+                ),
+                _ => {}
+            }
+        }
+        let module = Module::new(engine, &binary)?;
+        let wasi_only = module
+            .imports()
+            .all(|import| WASI_MODULES.contains(&import.module()));
+        Ok(CompiledModule {
+            module,
+            wasi_only,
+            pre_instantiated: Mutex::new(None),
+        })
+    }
+
+    fn acquire(&self) -> Option<Slot> {
+        let size = self.size;
+        self.in_use
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < size {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(Slot(self.in_use.clone()))
+    }
+
+    fn engine(&self, options: &EngineOptions) -> anyhow::Result<Arc<PooledEngine>> {
+        let mut engines = self.engines.lock().unwrap();
+        if let Some(pooled) = engines.iter().find(|e| e.options == *options) {
+            return Ok(pooled.clone());
+        }
+        let mut config = wasmtime::Config::new();
+        config.interruptable(true);
+        options.apply(&mut config)?;
+        // Every engine has as many slots as the pool, but only `size` are ever used between them
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling {
+            strategy: PoolingAllocationStrategy::default(),
+            module_limits: ModuleLimits {
+                memory_pages: self.memory_pages,
+                ..Default::default()
+            },
+            instance_limits: InstanceLimits {
+                count: self.size,
+                ..Default::default()
+            },
+        })?;
+        let pooled = Arc::new(PooledEngine {
+            options: options.clone(),
+            engine: Engine::new(&config)?,
+            compiled: Mutex::new(VecDeque::new()),
+        });
+        engines.push(pooled.clone());
+        Ok(pooled)
+    }
+}
+
+/// The declared maximum, in pages, of every memory a module imports or defines, whether it
+/// exports it or not. Memories that declare no maximum are `None`.
+fn memory_maximums(binary: &[u8]) -> anyhow::Result<Vec<Option<u64>>> {
+    let maximum = |memory: &MemoryType| match memory {
+        MemoryType::M32 { limits, .. } => limits.maximum.map(u64::from),
+        MemoryType::M64 { limits, .. } => limits.maximum,
+    };
+    let mut maximums = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(binary) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let ImportSectionEntryType::Memory(memory) = import?.ty {
+                        maximums.push(maximum(&memory));
+                    }
+                }
+            }
+            Payload::MemorySection(memories) => {
+                for memory in memories {
+                    maximums.push(maximum(&memory?));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(maximums)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget_is_split_between_slots() {
+        let pool = InstancePool::new(4, 16 * 1024 * 1024).unwrap();
+        assert_eq!(pool.memory_pages, 64);
+        assert!(InstancePool::new(4, 128 * 1024).is_err());
+        assert!(InstancePool::new(0, 16 * 1024 * 1024).is_err());
+    }
+
+    #[test]
+    fn test_slots_are_limited_and_modules_reused() {
+        let pool = InstancePool::new(1, 1024 * 1024).unwrap();
+        let module = b"(module (memory 1 1))";
+        let options = EngineOptions::default();
+
+        let first = pool
+            .checkout(&options, module)
+            .expect("module should be pooled");
+        assert!(pool.checkout(&options, module).is_none());
+        drop(first);
+
+        assert!(pool.checkout(&options, module).is_some());
+        assert_eq!(pool.engines.lock().unwrap().len(), 1);
+        assert_eq!(
+            pool.engines.lock().unwrap()[0]
+                .compiled
+                .lock()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_modules_that_may_outgrow_a_slot_are_not_pooled() {
+        // Each slot has 16 pages
+        let pool = InstancePool::new(1, 1024 * 1024).unwrap();
+        let options = EngineOptions::default();
+
+        assert!(pool
+            .checkout(&options, b"(module (memory (export \"memory\") 1 32))")
+            .is_none());
+        assert!(pool
+            .checkout(&options, b"(module (memory (export \"memory\") 17))")
+            .is_none());
+        // Memories without a maximum may grow to the most pages any memory can have
+        assert!(pool.checkout(&options, b"(module (memory 1))").is_none());
+        // Memories that aren't exported can still be grown by the module
+        assert!(pool.checkout(&options, b"(module (memory 1 32))").is_none());
+
+        let pooled = pool
+            .checkout(&options, b"(module (memory (export \"memory\") 1 16))")
+            .expect("module fitting a slot should be pooled");
+        let mut store = wasmtime::Store::new(&pooled.engine, module_state());
+        let instance = pooled
+            .instantiate(&mut store, &Linker::new(&pooled.engine))
+            .unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        assert!(memory.grow(&mut store, 15).is_ok());
+    }
+
+    #[test]
+    fn test_modules_importing_only_wasi_are_pre_instantiated() {
+        let pool = InstancePool::new(1, 1024 * 1024).unwrap();
+        let options = EngineOptions::default();
+        let start = |module: &[u8]| {
+            let pooled = pool
+                .checkout(&options, module)
+                .expect("module should be pooled");
+            let mut store = wasmtime::Store::new(&pooled.engine, module_state());
+            let mut linker = Linker::new(&pooled.engine);
+            wasmtime_wasi::add_to_linker(&mut linker, |state| state.wasi()).unwrap();
+            linker.func_wrap("host", "answer", || 42).unwrap();
+            pooled.instantiate(&mut store, &linker).unwrap();
+            pooled.compiled.pre_instantiated.lock().unwrap().is_some()
+        };
+
+        let wasi = br#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))"#;
+        assert!(start(wasi));
+        // The second start reuses the imports the first resolved
+        assert!(start(wasi));
+
+        let extended = br#"(module (import "host" "answer" (func (result i32))))"#;
+        assert!(!start(extended));
+    }
+
+    fn module_state() -> ModuleState {
+        ModuleState {
+            wasi: wasi_cap_std_sync::WasiCtxBuilder::new().build(),
+            meter: crate::wasi_runtime::MemoryMeter::new(Default::default()),
+        }
+    }
+}
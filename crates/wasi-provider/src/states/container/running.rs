@@ -1,13 +1,14 @@
 use super::terminated::Terminated;
+use super::waiting::Waiting;
 use super::ContainerState;
 use crate::ProviderState;
 use kubelet::container::state::prelude::*;
 use tokio::sync::mpsc::Receiver;
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
-#[transition_to(Terminated)]
+#[transition_to(Terminated, Waiting)]
 pub struct Running {
     rx: Receiver<Status>,
 }
@@ -20,12 +21,12 @@ impl Running {
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Running {
-    #[instrument(level = "info", skip(self, _shared_state, _state, _container))]
+    #[instrument(level = "info", skip(self, _shared_state, state, container))]
     async fn next(
         mut self: Box<Self>,
         _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
-        _container: Manifest<Container>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
     ) -> Transition<ContainerState> {
         debug!("Awaiting container status updates");
         while let Some(status) = self.rx.recv().await {
@@ -34,6 +35,12 @@ impl State<ContainerState> for Running {
                 failed, message, ..
             } = status
             {
+                // The provider stopped the module to replace it with an updated one
+                let name = container.latest().name().to_owned();
+                if state.run_context.write().await.replacing.remove(&name) {
+                    info!("Restarting container with its updated manifest");
+                    return Transition::next(self, Waiting);
+                }
                 return Transition::next(self, Terminated::new(message, failed));
             }
         }
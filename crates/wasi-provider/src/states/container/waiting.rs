@@ -3,13 +3,19 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use tokio::sync::mpsc;
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
 
 use kubelet::container::state::prelude::*;
+use kubelet::fault::{self, FaultPoint};
 use kubelet::pod::{Handle as PodHandle, PodKey};
 use kubelet::state::common::GenericProviderState;
-use kubelet::volume::VolumeRef;
+use kubelet::volume::{guest_mount_path, VolumeRef};
 
+use crate::capabilities::StrippedCapabilities;
+use crate::engine_options::EngineOptions;
+use crate::entrypoint::Entrypoint;
+use crate::log_format::{LogFormat, LogMetadata};
+use crate::stdin::{Stdin, StdinSource};
 use crate::wasi_runtime::{WasiHttpConfig, WasiRuntime};
 use crate::ProviderState;
 
@@ -20,6 +26,16 @@ use super::ContainerState;
 pub const MAX_CONNCURRENT_REQUESTS_ANNOTATION_KEY: &str =
     "alpha.wasi.krustlet.dev/max-concurrent-requests";
 pub const ALLOWED_DOMAINS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/allowed-domains";
+pub const LOG_FORMAT_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/log-format";
+pub const STRIP_CAPABILITIES_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/strip-capabilities";
+pub const RANDOM_SEED_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/random-seed";
+pub const HOST_EXTENSIONS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/host-extensions";
+pub const ENGINE_OPTIONS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/engine-options";
+pub const STDIN_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/stdin";
+pub const ENTRYPOINT_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/entrypoint";
+
+/// Where the container's scratch space is mounted in the module
+const SCRATCH_GUEST_PATH: &str = "/tmp";
 
 fn volume_path_map(
     container: &Container,
@@ -41,13 +57,7 @@ fn volume_path_map(
                 .get_path()
                 .map(|p| p.to_owned())
                 .ok_or_else(|| anyhow::anyhow!("Volume {} has not been mounted yet", vm.name))?;
-            let mut guest_path = PathBuf::from(&vm.mount_path);
-            if let Some(sub_path) = &vm.sub_path {
-                guest_path.push(sub_path);
-            }
-            // We can safely assume that this should be valid UTF-8 because it would have
-            // been validated by the k8s API
-            Ok((host_path, Some(guest_path)))
+            Ok((host_path, Some(guest_mount_path(vm)?)))
         })
         .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
 }
@@ -76,12 +86,42 @@ impl State<ContainerState> for Waiting {
 
         info!("Starting container for pod");
 
-        let (client, log_path) = {
+        let (
+            client,
+            log_path,
+            pod_dirs,
+            log_file_format,
+            allowed_pod_overrides,
+            extension_registry,
+            cluster_dns,
+            tracker,
+            cgroups,
+            scratch_space,
+            instance_pool,
+            pod_ip,
+            store,
+            clock,
+        ) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state.log_path.clone(),
+                provider_state.pod_dirs(&state.pod),
+                provider_state.log_file_format,
+                provider_state.allowed_pod_overrides.clone(),
+                provider_state.host_extensions.clone(),
+                provider_state.cluster_dns.clone(),
+                provider_state.dependency_tracker.clone(),
+                provider_state.cgroups.clone(),
+                provider_state.scratch.clone(),
+                provider_state.instance_pool.clone(),
+                provider_state.ipam.get(&PodKey::from(&state.pod)),
+                provider_state.store.clone(),
+                provider_state.clock.clone(),
+            )
         };
 
-        let (module_data, container_volumes, container_envs) = {
+        let (module_data, mut container_volumes, container_envs) = {
             let mut run_context = state.run_context.write().await;
             let module_data = match run_context.modules.remove(container.name()) {
                 Some(data) => data,
@@ -119,17 +159,59 @@ impl State<ContainerState> for Waiting {
             (
                 module_data,
                 container_volumes,
+                // Kept for when the container is replaced with an updated manifest
                 run_context
                     .env_vars
-                    .remove(container.name())
+                    .get(container.name())
+                    .cloned()
                     .unwrap_or_default(),
             )
         };
 
-        let mut env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
+        // The pod's status may not have caught up with the IP it was just assigned, and the
+        // downward API should still hand it to the module
+        let mut pod = state.pod.clone();
+        if let Some(ip) = pod_ip {
+            pod.set_pod_ip(&ip.to_string());
+        }
+        let mut env =
+            kubelet::provider::env_vars_with_tracker(&container, &pod, &client, Some(&tracker))
+                .await;
         env.extend(container_envs);
         let args = container.args().clone();
 
+        // Give the container its own /tmp, unless it mounts a volume there
+        let scratch_path = PathBuf::from(SCRATCH_GUEST_PATH);
+        let scratch = if container_volumes
+            .iter()
+            .any(|(host, guest)| guest.as_ref().unwrap_or(host) == &scratch_path)
+        {
+            None
+        } else {
+            match scratch_space.create(&state.pod, &container).await {
+                Ok(scratch) => {
+                    container_volumes.insert(scratch.path().to_owned(), Some(scratch_path));
+                    env.entry("TMPDIR".to_owned())
+                        .or_insert_with(|| SCRATCH_GUEST_PATH.to_owned());
+                    Some(scratch)
+                }
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Pod {} container {} failed to create scratch space: {:?}",
+                                state.pod.name(),
+                                container.name(),
+                                e
+                            ),
+                            true,
+                        ),
+                    )
+                }
+            }
+        };
+
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
 
@@ -185,16 +267,252 @@ impl State<ContainerState> for Waiting {
             }
         }
 
+        // Parse the stdout log format from annotation key
+        let log_format = match annotations.get(LOG_FORMAT_ANNOTATION_KEY) {
+            Some(annotation) => {
+                let metadata = LogMetadata {
+                    namespace: state.pod.namespace().to_owned(),
+                    pod: state.pod.name().to_owned(),
+                    container: container.name().to_owned(),
+                };
+                match LogFormat::from_annotation(annotation, metadata) {
+                    Ok(format) => format,
+                    Err(parse_err) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Error parsing annotation from key {:?}: {}",
+                                    LOG_FORMAT_ANNOTATION_KEY, parse_err,
+                                ),
+                                true,
+                            ),
+                        );
+                    }
+                }
+            }
+            None => LogFormat::default(),
+        };
+
+        // Parse the capabilities to strip from annotation key
+        let mut capabilities = match annotations.get(STRIP_CAPABILITIES_ANNOTATION_KEY) {
+            Some(annotation) => {
+                match StrippedCapabilities::from_annotation(annotation, &allowed_pod_overrides) {
+                    Ok(capabilities) => capabilities,
+                    Err(parse_err) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Error parsing annotation from key {:?}: {}",
+                                    STRIP_CAPABILITIES_ANNOTATION_KEY, parse_err,
+                                ),
+                                true,
+                            ),
+                        );
+                    }
+                }
+            }
+            None => StrippedCapabilities::default(),
+        };
+
+        // Parse the seed for deterministic randomness from annotation key
+        if let Some(annotation) = annotations.get(RANDOM_SEED_ANNOTATION_KEY) {
+            match annotation.parse() {
+                Ok(seed) => {
+                    capabilities = capabilities.with_random_seed(seed);
+                }
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                RANDOM_SEED_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Parse the wasmtime engine options from annotation key
+        let engine_options = match annotations.get(ENGINE_OPTIONS_ANNOTATION_KEY) {
+            Some(annotation) => {
+                match EngineOptions::from_annotation(annotation, &allowed_pod_overrides) {
+                    Ok(options) => options,
+                    Err(parse_err) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Error parsing annotation from key {:?}: {}",
+                                    ENGINE_OPTIONS_ANNOTATION_KEY, parse_err,
+                                ),
+                                true,
+                            ),
+                        );
+                    }
+                }
+            }
+            None => EngineOptions::default(),
+        };
+
+        // Parse the exported function to run from annotation key
+        let entrypoint = match annotations.get(ENTRYPOINT_ANNOTATION_KEY) {
+            Some(annotation) => match Entrypoint::from_annotation(annotation, &container) {
+                Ok(entrypoint) => entrypoint,
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                ENTRYPOINT_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            },
+            None => Entrypoint::default(),
+        };
+
+        // Resolve the source of stdin from annotation key
+        let stdin = match annotations.get(STDIN_ANNOTATION_KEY) {
+            Some(annotation) => {
+                let source = match StdinSource::from_annotation(annotation, &container) {
+                    Ok(source) => source,
+                    Err(parse_err) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Error parsing annotation from key {:?}: {}",
+                                    STDIN_ANNOTATION_KEY, parse_err,
+                                ),
+                                true,
+                            ),
+                        );
+                    }
+                };
+                match source {
+                    Some(source) => match source
+                        .resolve(&tracker, &state.pod, &container_volumes)
+                        .await
+                    {
+                        Ok(stdin) => Some(stdin),
+                        Err(e) => {
+                            return Transition::next(
+                                self,
+                                Terminated::new(
+                                    format!(
+                                        "Pod {} container {} failed to read stdin: {:?}",
+                                        state.pod.name(),
+                                        container.name(),
+                                        e
+                                    ),
+                                    true,
+                                ),
+                            );
+                        }
+                    },
+                    None => Stdin::attachable(&container),
+                }
+            }
+            // Without a source, attached clients write to stdin
+            None => Stdin::attachable(&container),
+        };
+
+        // Resolve the requested host extensions from annotation key
+        let mut host_extensions = match annotations.get(HOST_EXTENSIONS_ANNOTATION_KEY) {
+            Some(annotation) => match extension_registry.resolve(annotation) {
+                Ok(extensions) => extensions,
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                HOST_EXTENSIONS_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            },
+            None => Vec::new(),
+        };
+        // Modules that don't import the resolver aren't affected by it, so it's linked into all
+        // of them when the Kubelet knows the cluster DNS servers
+        if let Some(cluster_dns) = cluster_dns {
+            host_extensions.push(Arc::new(cluster_dns.resolver(state.pod.namespace())));
+        }
+
+        // The cgroup only adds kernel enforcement of the CPU limits, so the container can still
+        // run without one
+        let cgroup = cgroups.and_then(|cgroups| {
+            cgroups
+                .create_container(&state.pod, &container)
+                .map_err(|e| warn!(error = %e, "Unable to create container cgroup"))
+                .ok()
+        });
+
+        let pod_log = match pod_dirs {
+            Ok(dirs) => dirs.create_container_log(container.name()).await,
+            Err(e) => Err(e),
+        };
+        let pod_log = match pod_log {
+            Ok(path) => path,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to create log file: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
+
         // TODO: decide how/what it means to propagate annotations (from run_context) into WASM modules.
+        // The reference may be a tag, so the digest identifies the module that actually runs
+        let module_digest = match container.image() {
+            Ok(Some(image)) => store.digest(&image).await.unwrap_or_else(|e| {
+                warn!(error = %e, "Unable to look up the digest of the container's module");
+                None
+            }),
+            _ => None,
+        };
         let runtime = match WasiRuntime::new(
             name,
             module_data,
+            module_digest,
             env,
             args,
             container_volumes,
+            stdin,
             log_path,
             tx,
             wasi_http_config,
+            log_format,
+            pod_log,
+            log_file_format,
+            scratch,
+            capabilities,
+            engine_options,
+            entrypoint,
+            host_extensions,
+            cgroup,
+            kubelet::stats::container_memory_limit(&container),
+            instance_pool,
+            clock,
         )
         .await
         {
@@ -215,7 +533,17 @@ impl State<ContainerState> for Waiting {
             }
         };
         debug!("Starting container on thread");
-        let container_handle = match runtime.start().await {
+        let target = format!(
+            "startContainer {}/{}/{}",
+            state.pod.namespace(),
+            state.pod.name(),
+            container.name()
+        );
+        let started = match fault::inject(FaultPoint::Provider, &target).await {
+            Ok(()) => runtime.start().await,
+            Err(fault) => Err(fault.into()),
+        };
+        let container_handle = match started {
             Ok(handle) => handle,
             Err(e) => {
                 return Transition::next(
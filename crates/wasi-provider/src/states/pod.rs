@@ -5,7 +5,9 @@ use async_trait::async_trait;
 use krator::{ObjectState, SharedState};
 use kubelet::backoff::BackoffStrategy;
 use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::environment::Clock;
 use kubelet::pod::Pod;
+use kubelet::pod::PodDirs;
 use kubelet::pod::PodKey;
 use kubelet::pod::Status;
 use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
@@ -16,6 +18,7 @@ use crate::ModuleRunContext;
 use crate::ProviderState;
 
 pub(crate) mod completed;
+pub(crate) mod evicted;
 pub(crate) mod initializing;
 pub(crate) mod running;
 pub(crate) mod starting;
@@ -25,8 +28,11 @@ pub struct PodState {
     key: PodKey,
     run_context: SharedState<ModuleRunContext>,
     errors: usize,
+    /// The pod's volume and log directories
+    dirs: PodDirs,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
     pub(crate) crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    clock: Arc<dyn Clock>,
 }
 
 #[async_trait]
@@ -48,24 +54,48 @@ impl ObjectState for PodState {
             }
             let mut handles = provider_state.handles.write().await;
             handles.remove(&self.key);
+            provider_state.run_contexts.write().await.remove(&self.key);
+            provider_state.ipam.release(&self.key);
+            provider_state.dependency_tracker.release(&self.key);
+            if let Some(cgroups) = &provider_state.cgroups {
+                if let Err(e) = cgroups.remove_pod(&self.key).await {
+                    error!(error = %e, "Unable to remove pod cgroups");
+                }
+            }
+            if let Err(e) = provider_state.scratch.remove_pod(&self.key).await {
+                error!(error = %e, "Unable to remove pod scratch space");
+            }
+            provider_state.disk_usage.remove(&self.key);
+            if let Err(e) = self.dirs.remove().await {
+                error!(error = %e, "Unable to remove pod directories");
+            }
         }
     }
 }
 
 impl PodState {
-    pub fn new(pod: &Pod) -> Self {
+    pub(crate) fn run_context(&self) -> SharedState<ModuleRunContext> {
+        self.run_context.clone()
+    }
+
+    pub fn new(pod: &Pod, dirs: PodDirs, clock: Arc<dyn Clock>) -> Self {
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
             env_vars: Default::default(),
+            replacing: Default::default(),
         };
         let key = PodKey::from(pod);
         PodState {
             key,
             run_context: Arc::new(RwLock::new(run_context)),
             errors: 0,
-            image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
-            crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            dirs,
+            image_pull_backoff_strategy: ExponentialBackoffStrategy::default()
+                .with_clock(clock.clone()),
+            crash_loop_backoff_strategy: ExponentialBackoffStrategy::default()
+                .with_clock(clock.clone()),
+            clock,
         }
     }
 }
@@ -107,4 +137,7 @@ impl GenericPodState for PodState {
             ThresholdTrigger::Untriggered
         }
     }
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
 }
@@ -1,5 +1,6 @@
 use crate::{PodState, ProviderState};
 use kubelet::pod::state::prelude::*;
+use kubelet::state::common::GenericPodState;
 
 /// Pod was deleted.
 #[derive(Default, Debug)]
@@ -16,7 +17,11 @@ impl State<PodState> for Completed {
         Transition::Complete(Ok(()))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Succeeded, "Completed"))
+    async fn status(&self, pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Succeeded,
+            "Completed",
+            make_conditions(pod, Readiness::Completed, &*pod_state.clock()),
+        ))
     }
 }
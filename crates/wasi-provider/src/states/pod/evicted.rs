@@ -0,0 +1,41 @@
+use crate::{PodState, ProviderState};
+use kubelet::pod::state::prelude::*;
+use kubelet::state::common::GenericPodState;
+use kubelet::stats::EPHEMERAL_STORAGE_EXCEEDED;
+
+/// Pod was stopped for using more disk than its limit.
+#[derive(Debug)]
+pub struct Evicted {
+    message: String,
+}
+
+impl Evicted {
+    pub fn new(message: String) -> Self {
+        Evicted { message }
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Evicted {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<ProviderState>,
+        _pod_state: &mut PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<PodState> {
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(EPHEMERAL_STORAGE_EXCEEDED)
+            .message(&self.message)
+            .conditions(make_conditions(
+                pod,
+                Readiness::Completed,
+                &*pod_state.clock(),
+            ))
+            .build())
+    }
+}
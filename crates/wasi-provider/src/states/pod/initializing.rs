@@ -1,13 +1,16 @@
 use std::sync::Arc;
 
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
 use tracing::{error, info, instrument};
 
 use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{patch_status, PodKey};
 use kubelet::state::common::error::Error;
-use kubelet::state::common::GenericProviderState;
+use kubelet::state::common::{GenericPodState, GenericProviderState};
 
 use crate::states::container::waiting::Waiting;
 use crate::states::container::ContainerState;
@@ -37,11 +40,26 @@ impl State<PodState> for Initializing {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
-        let client = {
+        let (client, ipam) = {
             let provider_state = provider_state.read().await;
-            provider_state.client()
+            (provider_state.client(), provider_state.ipam.clone())
         };
 
+        if let Some(ipam) = ipam.resolve().await {
+            let preferred = pod.pod_ip().and_then(|ip| ip.parse().ok());
+            match ipam.assign(&PodKey::from(&pod), preferred) {
+                Ok(ip) => {
+                    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+                    let status = StatusBuilder::new().pod_ip(&ip.to_string()).build();
+                    patch_status(&api, pod.name(), status).await;
+                }
+                Err(e) => {
+                    error!(error = %e, "Unable to assign pod IP");
+                    return Transition::Complete(Err(e));
+                }
+            }
+        }
+
         for init_container in pod.init_containers() {
             info!(
                 container_name = init_container.name(),
@@ -86,7 +104,11 @@ impl State<PodState> for Initializing {
         Transition::next(self, Starting)
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Initializing"))
+    async fn status(&self, pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Running,
+            "Initializing",
+            make_conditions(pod, Readiness::NotInitialized, &*pod_state.clock()),
+        ))
     }
 }
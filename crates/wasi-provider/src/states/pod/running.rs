@@ -1,16 +1,28 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
 use tokio::sync::mpsc::Receiver;
+use tracing::{info, warn};
 
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{has_readiness_gates, patch_status, updated_ready_condition};
 use kubelet::state::common::error::Error;
-use kubelet::state::common::GenericProviderState;
+use kubelet::state::common::{GenericPodState, GenericProviderState};
+use kubelet::state::replay;
 
 use super::completed::Completed;
+use super::evicted::Evicted;
 use crate::fail_fatal;
 use crate::{PodState, ProviderState};
 
+/// How often the disk usage of a running pod is measured
+const DISK_USAGE_INTERVAL: Duration = Duration::from_secs(10);
+
 /// The Kubelet is running the Pod.
 #[derive(Debug, TransitionTo)]
-#[transition_to(Completed, Error<crate::WasiProvider>)]
+#[transition_to(Completed, Evicted, Error<crate::WasiProvider>)]
 pub struct Running {
     rx: Receiver<anyhow::Result<()>>,
 }
@@ -26,29 +38,66 @@ impl State<PodState> for Running {
     async fn next(
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
-        pod: Manifest<Pod>,
+        pod_state: &mut PodState,
+        mut manifest: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod = pod.latest();
+        let pod = manifest.latest();
 
         let mut completed = 0;
         let total_containers = pod.containers().len();
 
-        while let Some(result) = self.rx.recv().await {
-            match result {
-                Ok(()) => {
-                    completed += 1;
-                    if completed == total_containers {
-                        return Transition::next(self, Completed);
+        // Readiness gates are set by external controllers, so watch for changes to the pod's
+        // conditions and update the Ready condition to match
+        let watch_readiness_gates = has_readiness_gates(&pod);
+        let api: Api<KubePod> = {
+            let provider_state = provider_state.read().await;
+            Api::namespaced(provider_state.client(), pod.namespace())
+        };
+
+        let mut disk_usage_interval = tokio::time::interval(DISK_USAGE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                // Containers run outside the state machine, so their results are recorded for replays
+                result = replay::provider_result(&pod, "containerResult", async {
+                    self.rx.recv().await.transpose()
+                }) => match result.transpose() {
+                    Some(Ok(())) => {
+                        completed += 1;
+                        if completed == total_containers {
+                            return Transition::next(self, Completed);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        // Stop remaining containers;
+                        {
+                            let provider = provider_state.write().await;
+                            provider.stop(&pod).await.ok();
+                        }
+                        fail_fatal!(e);
+                    }
+                    None => break,
+                },
+                _ = disk_usage_interval.tick() => {
+                    // Walking the pod's directories can take a while, so it is done on a copy
+                    // of the provider state rather than while holding the lock on it
+                    let provider = provider_state.read().await.clone();
+                    match provider.measure_disk_usage(&pod).await {
+                        Ok(usage) => {
+                            if let Some(message) = usage.exceeded_limit(&pod) {
+                                info!(%message, "Evicting pod");
+                                provider.stop(&pod).await.ok();
+                                return Transition::next(self, Evicted::new(message));
+                            }
+                        }
+                        Err(e) => warn!(error = %e, "Unable to measure pod disk usage"),
                     }
                 }
-                Err(e) => {
-                    // Stop remaining containers;
-                    {
-                        let provider = provider_state.write().await;
-                        provider.stop(&pod).await.ok();
+                Some(latest) = manifest.next(), if watch_readiness_gates => {
+                    if let Some(ready) = updated_ready_condition(&latest, Readiness::Ready, &*pod_state.clock()) {
+                        let status = StatusBuilder::new().conditions(vec![ready]).build();
+                        patch_status(&api, latest.name(), status).await;
                     }
-                    fail_fatal!(e);
                 }
             }
         }
@@ -61,7 +110,11 @@ impl State<PodState> for Running {
         )
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Running"))
+    async fn status(&self, pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Running,
+            "Running",
+            make_conditions(pod, Readiness::Ready, &*pod_state.clock()),
+        ))
     }
 }
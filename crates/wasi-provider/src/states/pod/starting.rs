@@ -5,7 +5,8 @@ use tracing::{info, instrument};
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
-use kubelet::state::common::GenericProviderState;
+use kubelet::state::common::{GenericPodState, GenericProviderState};
+use kubelet::state::replay;
 
 use crate::states::container::waiting::Waiting;
 use crate::states::container::ContainerState;
@@ -39,40 +40,59 @@ impl State<PodState> for Starting {
         info!("Starting containers for pod");
         let containers = pod.containers();
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
-        for container in containers {
-            let initial_state = Waiting;
-            let container_key = ContainerKey::App(container.name().to_string());
-            let container_state = ContainerState::new(
-                pod.clone(),
-                container_key.clone(),
-                Arc::clone(&pod_state.run_context),
-            );
-            let task_provider = Arc::clone(&provider_state);
-            let task_tx = tx.clone();
-            let task_pod = pod_rx.clone();
-            tokio::task::spawn(async move {
-                let client = {
-                    let provider_state = task_provider.read().await;
-                    provider_state.client()
-                };
+        // Replayed pods are handed their containers' recorded results, so no runtimes are started
+        let started = replay::provider_result(&pod, "runtimeStart", async {
+            for container in containers {
+                let initial_state = Waiting;
+                let container_key = ContainerKey::App(container.name().to_string());
+                let container_state = ContainerState::new(
+                    pod.clone(),
+                    container_key.clone(),
+                    Arc::clone(&pod_state.run_context),
+                );
+                let task_provider = Arc::clone(&provider_state);
+                let task_tx = tx.clone();
+                let task_pod = pod_rx.clone();
+                let container = tokio::task::spawn(async move {
+                    let client = {
+                        let provider_state = task_provider.read().await;
+                        provider_state.client()
+                    };
 
-                let result = run_to_completion(
-                    &client,
-                    initial_state,
-                    task_provider,
-                    container_state,
-                    task_pod,
-                    container_key,
-                )
-                .await;
-                task_tx.send(result).await
-            });
+                    run_to_completion(
+                        &client,
+                        initial_state,
+                        task_provider,
+                        container_state,
+                        task_pod,
+                        container_key,
+                    )
+                    .await
+                });
+                // Panics in the container's states fail it, but one anywhere else in its task
+                // would leave the pod waiting for a result that never comes
+                tokio::task::spawn(async move {
+                    let result = container
+                        .await
+                        .unwrap_or_else(|e| Err(anyhow::anyhow!("Container task failed: {}", e)));
+                    task_tx.send(result).await
+                });
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = started {
+            return Transition::Complete(Err(e));
         }
         info!("All containers started for pod");
         Transition::next(self, Running::new(rx))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "Starting"))
+    async fn status(&self, pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status_with_conditions(
+            Phase::Pending,
+            "Starting",
+            make_conditions(pod, Readiness::NotReady, &*pod_state.clock()),
+        ))
     }
 }
@@ -0,0 +1,256 @@
+//! Support for feeding the stdin of a module from a ConfigMap, Secret or file, so that pipe-style
+//! batch tools can run as pods.
+//!
+//! Only containers with `stdin: true` can be given a source. The whole source is read once, after
+//! which the module sees the end of stdin, so `stdinOnce` is always honored. Containers with
+//! `stdin: true` and no source read what attached clients write instead, until the container is
+//! stopped or, with `stdinOnce`, the first client detaches.
+//!
+//! Files are opened beneath the root of their volume with `cap-std`, so a symlink that the module
+//! or another pod left in a writable volume can't point stdin at a file outside of it.
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use kubelet::container::Container;
+use kubelet::dependency::DependencyTracker;
+use kubelet::pod::{Pod, PodKey};
+use serde_derive::Deserialize;
+use tracing::warn;
+
+/// Where the stdin of a container is read from.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum StdinSource {
+    /// A key of a ConfigMap in the pod's namespace
+    ConfigMapKeyRef {
+        /// The name of the ConfigMap
+        name: String,
+        /// The key to read
+        key: String,
+    },
+    /// A key of a Secret in the pod's namespace
+    SecretKeyRef {
+        /// The name of the Secret
+        name: String,
+        /// The key to read
+        key: String,
+    },
+    /// A file inside one of the container's volume mounts, given as the path the module sees
+    File(PathBuf),
+}
+
+/// The resolved stdin of a container.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stdin {
+    /// Data read from a ConfigMap or Secret
+    Data(Vec<u8>),
+    /// A file inside the host directory of a volume
+    File {
+        /// The host directory of the volume
+        volume: PathBuf,
+        /// The path of the file relative to the volume
+        path: PathBuf,
+    },
+    /// What attached clients write
+    Attach {
+        /// Whether stdin closes when the first client detaches
+        once: bool,
+    },
+}
+
+impl Stdin {
+    /// The stdin of a container that has no source, which clients can attach to if the container
+    /// sets `stdin: true`.
+    pub fn attachable(container: &Container) -> Option<Self> {
+        if container.stdin() == Some(true) {
+            Some(Stdin::Attach {
+                once: container.stdin_once() == Some(true),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Opens a file within a volume without following symlinks out of it.
+pub(crate) fn open_in_volume(volume: &Path, path: &Path) -> anyhow::Result<cap_std::fs::File> {
+    // The volume itself is trusted, it is what the Kubelet mounted for the pod
+    let dir = unsafe { cap_std::fs::Dir::open_ambient_dir(volume) }?;
+    dir.open(path).map_err(|e| {
+        warn!(error = %e, volume = %volume.display(), path = %path.display(), "Unable to open stdin file");
+        anyhow::anyhow!("unable to open stdin file {}: {}", path.display(), e)
+    })
+}
+
+impl StdinSource {
+    /// Parses the source for the given container from an annotation holding a JSON object of
+    /// container names to sources, for example
+    /// `{"convert": {"configMapKeyRef": {"name": "input", "key": "data.csv"}}}`. Returns `None`
+    /// if the container has no source.
+    pub fn from_annotation(value: &str, container: &Container) -> anyhow::Result<Option<Self>> {
+        let mut sources: HashMap<String, StdinSource> = serde_json::from_str(value)?;
+        let source = match sources.remove(container.name()) {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        if container.stdin() != Some(true) {
+            return Err(anyhow::anyhow!(
+                "container {} has a stdin source but does not set stdin: true",
+                container.name()
+            ));
+        }
+        Ok(Some(source))
+    }
+
+    /// Reads the source. Files are resolved against the container's volumes, which map host paths
+    /// to the paths the module sees them at.
+    pub async fn resolve(
+        &self,
+        tracker: &DependencyTracker,
+        pod: &Pod,
+        volumes: &HashMap<PathBuf, Option<PathBuf>>,
+    ) -> anyhow::Result<Stdin> {
+        match self {
+            StdinSource::ConfigMapKeyRef { name, key } => {
+                let config_map = tracker.config_map(&PodKey::from(pod), name).await?;
+                config_map
+                    .data
+                    .get(key)
+                    .map(|v| v.as_bytes().to_vec())
+                    .or_else(|| config_map.binary_data.get(key).map(|v| v.0.clone()))
+                    .map(Stdin::Data)
+                    .ok_or_else(|| anyhow::anyhow!("key {} not found in ConfigMap {}", key, name))
+            }
+            StdinSource::SecretKeyRef { name, key } => {
+                let secret = tracker.secret(&PodKey::from(pod), name).await?;
+                secret
+                    .data
+                    .get(key)
+                    .map(|v| Stdin::Data(v.0.clone()))
+                    .ok_or_else(|| anyhow::anyhow!("key {} not found in Secret {}", key, name))
+            }
+            StdinSource::File(guest_path) => {
+                host_path(guest_path, volumes).map(|(volume, path)| Stdin::File { volume, path })
+            }
+        }
+    }
+}
+
+/// Finds the host directory of the volume a file the module would see at `guest_path` is in,
+/// along with the path of the file relative to it, refusing to leave the volume
+fn host_path(
+    guest_path: &Path,
+    volumes: &HashMap<PathBuf, Option<PathBuf>>,
+) -> anyhow::Result<(PathBuf, PathBuf)> {
+    if guest_path
+        .components()
+        .any(|c| matches!(c, Component::ParentDir))
+    {
+        return Err(anyhow::anyhow!(
+            "stdin file {} must not contain '..'",
+            guest_path.display()
+        ));
+    }
+    volumes
+        .iter()
+        .filter_map(|(host, guest)| {
+            let guest = guest.as_deref().unwrap_or(host);
+            guest_path
+                .strip_prefix(guest)
+                .ok()
+                .map(|rest| (guest.components().count(), host.clone(), rest.to_owned()))
+        })
+        // The most specific mount wins when mounts are nested
+        .max_by_key(|(depth, _, _)| *depth)
+        .map(|(_, host, path)| (host, path))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "stdin file {} is not inside any of the container's volume mounts",
+                guest_path.display()
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+    fn container(stdin: bool) -> Container {
+        Container::new(&KubeContainer {
+            name: "convert".into(),
+            stdin: Some(stdin),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_stdin_source() {
+        let annotation =
+            r#"{"convert": {"configMapKeyRef": {"name": "input", "key": "data.csv"}}}"#;
+        assert_eq!(
+            StdinSource::from_annotation(annotation, &container(true)).unwrap(),
+            Some(StdinSource::ConfigMapKeyRef {
+                name: "input".into(),
+                key: "data.csv".into()
+            })
+        );
+        assert!(StdinSource::from_annotation(annotation, &container(false)).is_err());
+        assert_eq!(
+            StdinSource::from_annotation(r#"{"other": {"file": "/data"}}"#, &container(true))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_stdin_file_host_path() {
+        let mut volumes = HashMap::new();
+        volumes.insert(PathBuf::from("/host/data"), Some(PathBuf::from("/data")));
+        volumes.insert(
+            PathBuf::from("/host/input"),
+            Some(PathBuf::from("/data/input")),
+        );
+        assert_eq!(
+            host_path(Path::new("/data/input/batch.txt"), &volumes).unwrap(),
+            (PathBuf::from("/host/input"), PathBuf::from("batch.txt"))
+        );
+        assert_eq!(
+            host_path(Path::new("/data/other.txt"), &volumes).unwrap(),
+            (PathBuf::from("/host/data"), PathBuf::from("other.txt"))
+        );
+        assert!(host_path(Path::new("/data/../etc/passwd"), &volumes).is_err());
+        assert!(host_path(Path::new("/elsewhere"), &volumes).is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_stdin_file_does_not_follow_symlinks_out_of_its_volume() {
+        use std::io::Read;
+
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), "secret").unwrap();
+        let volume = tempfile::tempdir().unwrap();
+        std::fs::write(volume.path().join("input"), "input").unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), volume.path().join("escape"))
+            .unwrap();
+        std::os::unix::fs::symlink("input", volume.path().join("alias")).unwrap();
+
+        let mut read = String::new();
+        open_in_volume(volume.path(), Path::new("alias"))
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "input");
+        assert!(open_in_volume(volume.path(), Path::new("escape")).is_err());
+    }
+
+    #[test]
+    fn test_attachable_stdin() {
+        assert_eq!(
+            Stdin::attachable(&container(true)),
+            Some(Stdin::Attach { once: false })
+        );
+        assert_eq!(Stdin::attachable(&container(false)), None);
+    }
+}
@@ -1,35 +1,75 @@
 use std::collections::HashMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
 use tracing::{debug, error, info, instrument, trace, warn};
 
 use tempfile::NamedTempFile;
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
 use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::WasiFile;
 use wasmtime::{InterruptHandle, Linker};
 
+use kubelet::cgroup::Cgroup;
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
-use kubelet::handle::StopHandler;
+use kubelet::environment::Clock;
+use kubelet::handle::{
+    attached_stdin, AttachedStdin, Preopen, RuntimeInfo, StdinReader, StopHandler,
+};
+use kubelet::log::broadcast::{BroadcastWriter, LogBroadcast, Subscription};
+use kubelet::log::cri::{CriWriter, Stream};
+use kubelet::log::LogFileFormat;
+use kubelet::scratch::ScratchDir;
+
+use crate::capabilities::{Capability, StrippedCapabilities};
+use crate::engine_options::EngineOptions;
+use crate::entrypoint::{Entrypoint, REACTOR_INITIALIZE_EXPORT};
+use crate::extensions::{HostExtension, ModuleState};
+use crate::log_format::{JsonLinesWriter, LogFormat, TeeWriter};
+use crate::pool::InstancePool;
+use crate::stdin::{open_in_volume, Stdin};
 
 use wasi_experimental_http_wasmtime::HttpCtx as WasiHttpCtx;
 
 pub struct Runtime {
     handle: JoinHandle<anyhow::Result<()>>,
     interrupt_handle: InterruptHandle,
+    info: Arc<Mutex<RuntimeInfo>>,
+    stdin: Option<Arc<AttachedStdin>>,
 }
 
 #[async_trait::async_trait]
 impl StopHandler for Runtime {
     async fn stop(&mut self) -> anyhow::Result<()> {
         self.interrupt_handle.interrupt();
+        // A module blocked reading stdin can't be interrupted until it reads the end of it
+        if let Some(stdin) = &self.stdin {
+            stdin.close();
+        }
         Ok(())
     }
 
     async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
-        Ok(())
+        match (&mut self.handle).await {
+            Err(e) if e.is_panic() => {
+                // The panic only fails this pod, unless the watchdog finds the runtime broken
+                warn!(error = %e, "The wasmtime runtime panicked while running a module");
+                crate::health::probe_soon();
+                Err(e.into())
+            }
+            res => res?,
+        }
+    }
+
+    fn runtime_info(&self) -> Option<RuntimeInfo> {
+        Some(self.info.lock().unwrap().clone())
+    }
+
+    fn stdin(&self) -> Option<Arc<AttachedStdin>> {
+        self.stdin.clone()
     }
 }
 
@@ -46,6 +86,77 @@ pub struct WasiRuntime {
     status_sender: Sender<Status>,
     /// Configuration for the WASI http
     http_config: WasiHttpConfig,
+    /// The format in which stdout is written to the log file
+    log_format: LogFormat,
+    /// A file output is also written to in the CRI log format, if any
+    pod_log: Option<PathBuf>,
+    /// The format the log file is written in
+    log_file_format: LogFileFormat,
+    /// The container's scratch space, if it has one
+    scratch: Option<ScratchDir>,
+    /// Default WASI capabilities that should be removed from the module
+    capabilities: StrippedCapabilities,
+    /// Engine options requested by the pod
+    engine_options: EngineOptions,
+    /// The exported function that runs the module
+    entrypoint: Entrypoint,
+    /// Additional host functions to link into the module
+    host_extensions: Vec<Arc<dyn HostExtension>>,
+    /// The cgroup the thread running the module is moved into, if any
+    cgroup: Option<Cgroup>,
+    /// The most bytes of linear memory the module may use, if the container is limited
+    memory_limit: Option<u64>,
+    /// The pool the module is instantiated from, if the node has one
+    instance_pool: Option<Arc<InstancePool>>,
+    /// The clock status updates are timestamped with
+    clock: Arc<dyn Clock>,
+}
+
+/// The bytes in a page of WebAssembly linear memory
+const WASM_PAGE_SIZE: u64 = 0x10000;
+
+/// Keeps the memory size in a module's [`RuntimeInfo`] current while the module runs, and keeps
+/// the module's memory within its container's limit. The store can't be looked at while a call
+/// into the module is in progress, but it consults its limiter whenever a memory is created or
+/// grown.
+pub(crate) struct MemoryMeter {
+    info: Arc<Mutex<RuntimeInfo>>,
+    /// The most pages a memory may have, if the container has a memory limit
+    limit_pages: Option<u64>,
+}
+
+impl MemoryMeter {
+    /// A meter that keeps the memory size in `info` current.
+    pub(crate) fn new(info: Arc<Mutex<RuntimeInfo>>) -> Self {
+        MemoryMeter {
+            info,
+            limit_pages: None,
+        }
+    }
+
+    /// Makes the meter refuse to let a memory grow past `bytes`.
+    pub(crate) fn with_limit(mut self, bytes: u64) -> Self {
+        self.limit_pages = Some(bytes / WASM_PAGE_SIZE);
+        self
+    }
+}
+
+impl wasmtime::ResourceLimiter for MemoryMeter {
+    fn memory_growing(&mut self, _current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        // Refusing fails the growth, or the instantiation for a memory's initial size
+        if matches!(self.limit_pages, Some(limit) if u64::from(desired) > limit) {
+            return false;
+        }
+        // Growing past the memory's maximum fails, so the memory stays the size it was
+        if maximum.map_or(true, |maximum| desired <= maximum) {
+            self.info.lock().unwrap().memory_bytes = Some(desired as u64 * WASM_PAGE_SIZE);
+        }
+        true
+    }
+
+    fn table_growing(&mut self, _current: u32, _desired: u32, _maximum: Option<u32>) -> bool {
+        true
+    }
 }
 
 // Configuration for WASI http.
@@ -58,6 +169,8 @@ pub struct WasiHttpConfig {
 struct Data {
     /// binary module data to be run as a wasm module
     module_data: Vec<u8>,
+    /// the digest the registry gave the module, if it is known
+    module_digest: Option<String>,
     /// key/value environment variables made available to the wasm process
     env: HashMap<String, String>,
     /// the arguments passed as the command-line arguments list
@@ -66,11 +179,15 @@ struct Data {
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     /// the same path will be allowed in the runtime
     dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// what the module reads from stdin, if anything
+    stdin: Option<Stdin>,
 }
 
 /// Holds our tempfile handle.
 pub struct HandleFactory {
     temp: Arc<NamedTempFile>,
+    format: LogFileFormat,
+    broadcast: Weak<LogBroadcast>,
 }
 
 impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
@@ -78,6 +195,20 @@ impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
     fn new_handle(&self) -> tokio::fs::File {
         tokio::fs::File::from_std(self.temp.reopen().unwrap())
     }
+
+    fn format(&self) -> LogFileFormat {
+        self.format
+    }
+
+    fn subscribe(&self) -> Option<Subscription> {
+        self.broadcast
+            .upgrade()
+            .map(|broadcast| broadcast.subscribe())
+    }
+
+    fn log_size(&self) -> Option<u64> {
+        std::fs::metadata(self.temp.path()).map(|m| m.len()).ok()
+    }
 }
 
 impl WasiRuntime {
@@ -86,22 +217,50 @@ impl WasiRuntime {
     /// # Arguments
     ///
     /// * `module_path` - the path to the WebAssembly binary
+    /// * `module_digest` - the digest the registry gave the module, if it is known
     /// * `env` - a collection of key/value pairs containing the environment variables
     /// * `args` - the arguments passed as the command-line arguments list
     /// * `dirs` - a map of local file system paths to optional path names in the runtime
     ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     ///     the same path will be allowed in the runtime
+    /// * `stdin` - what the module reads from stdin, if anything
     /// * `log_dir` - location for storing logs
+    /// * `log_format` - how the module's stdout should be written to the log file
+    /// * `pod_log` - a file to also write output to in the CRI log format
+    /// * `log_file_format` - the format the log file is written in
+    /// * `scratch` - the container's scratch space, whose quota is enforced while the module runs
+    /// * `capabilities` - default WASI capabilities to remove from the module
+    /// * `engine_options` - wasmtime engine options requested by the pod
+    /// * `entrypoint` - the exported function that runs the module
+    /// * `host_extensions` - additional host functions to link into the module
+    /// * `cgroup` - the cgroup to run the module in
+    /// * `memory_limit` - the container's memory limit, which the module's memory can't grow past
+    /// * `instance_pool` - the pool to instantiate the module from, if any
+    /// * `clock` - the clock that timestamps status updates and paces the scratch space checks
     #[allow(clippy::too_many_arguments)]
     pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
         name: String,
         module_data: Vec<u8>,
+        module_digest: Option<String>,
         env: HashMap<String, String>,
         args: Vec<String>,
         dirs: HashMap<PathBuf, Option<PathBuf>>,
+        stdin: Option<Stdin>,
         log_dir: L,
         status_sender: Sender<Status>,
         http_config: WasiHttpConfig,
+        log_format: LogFormat,
+        pod_log: Option<PathBuf>,
+        log_file_format: LogFileFormat,
+        scratch: Option<ScratchDir>,
+        capabilities: StrippedCapabilities,
+        engine_options: EngineOptions,
+        entrypoint: Entrypoint,
+        host_extensions: Vec<Arc<dyn HostExtension>>,
+        cgroup: Option<Cgroup>,
+        memory_limit: Option<u64>,
+        instance_pool: Option<Arc<InstancePool>>,
+        clock: Arc<dyn Clock>,
     ) -> anyhow::Result<Self> {
         let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
             Ok(NamedTempFile::new_in(log_dir)?)
@@ -118,13 +277,27 @@ impl WasiRuntime {
             name,
             data: Arc::new(Data {
                 module_data,
+                module_digest,
                 env,
                 args,
                 dirs,
+                stdin,
             }),
             output: Arc::new(temp),
             status_sender,
             http_config,
+            log_format,
+            pod_log,
+            log_file_format,
+            scratch,
+            capabilities,
+            engine_options,
+            entrypoint,
+            host_extensions,
+            cgroup,
+            memory_limit,
+            instance_pool,
+            clock,
         })
     }
 
@@ -137,29 +310,86 @@ impl WasiRuntime {
         })
         .await??;
 
+        // Only the module's output holds on to the broadcast, so that log followers stop when
+        // the module exits
+        let broadcast = Arc::new(LogBroadcast::default());
+        let info = Arc::new(Mutex::new(RuntimeInfo {
+            module_digest: self.data.module_digest.clone(),
+            preopens: self
+                .data
+                .dirs
+                .iter()
+                .map(|(host, guest)| Preopen {
+                    host_path: host.clone(),
+                    guest_path: guest.clone().unwrap_or_else(|| host.clone()),
+                })
+                .collect(),
+            ..Default::default()
+        }));
+        // Clients attach to the stdin of this run of the module only
+        let (stdin, stdin_reader) = match &self.data.stdin {
+            Some(Stdin::Attach { once }) => {
+                let (stdin, reader) = attached_stdin(*once);
+                (Some(Arc::new(stdin)), Some(reader))
+            }
+            _ => (None, None),
+        };
         let (interrupt_handle, handle) = self
-            .spawn_wasmtime(tokio::fs::File::from_std(output_write))
+            .spawn_wasmtime(
+                tokio::fs::File::from_std(output_write),
+                &broadcast,
+                info.clone(),
+                stdin_reader,
+            )
             .await?;
 
         let log_handle_factory = HandleFactory {
             temp: self.output.clone(),
+            format: self.log_file_format,
+            broadcast: Arc::downgrade(&broadcast),
         };
 
         Ok(ContainerHandle::new(
             Runtime {
                 handle,
                 interrupt_handle,
+                info,
+                stdin,
             },
             log_handle_factory,
         ))
     }
 
+    /// Wraps the writer of the container log file that one stream of output is written to, and
+    /// the pod log file if there is one, in the writers that encode the output for them.
+    fn output_writer(
+        &self,
+        file: BroadcastWriter<std::fs::File>,
+        pod_log: Option<std::fs::File>,
+        stream: Stream,
+    ) -> Box<dyn Write + Send + Sync> {
+        match (pod_log, self.log_file_format) {
+            (None, LogFileFormat::Plain) => Box::new(file),
+            (None, LogFileFormat::Cri) => Box::new(CriWriter::new(file, stream)),
+            (Some(pod_log), LogFileFormat::Plain) => {
+                Box::new(TeeWriter::new(file, CriWriter::new(pod_log, stream)))
+            }
+            // Encode once, so both files have the same entries
+            (Some(pod_log), LogFileFormat::Cri) => {
+                Box::new(CriWriter::new(TeeWriter::new(file, pod_log), stream))
+            }
+        }
+    }
+
     // Spawns a running wasmtime instance with the given context and status
     // channel.
-    #[instrument(level = "info", skip(self, output_write), fields(name = %self.name))]
+    #[instrument(level = "info", skip(self, output_write, broadcast, info, stdin_reader), fields(name = %self.name))]
     async fn spawn_wasmtime(
         &self,
         output_write: tokio::fs::File,
+        broadcast: &Arc<LogBroadcast>,
+        info: Arc<Mutex<RuntimeInfo>>,
+        stdin_reader: Option<StdinReader>,
     ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
@@ -167,25 +397,65 @@ impl WasiRuntime {
 
         // Log this info here so it isn't on _every_ log line
         trace!(env = ?data.env, args = ?data.args, dirs = ?data.dirs, "Starting setup of wasmtime module");
-        let env: Vec<(String, String)> = data
-            .env
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
-        let stdout = wasi_cap_std_sync::file::File::from_cap_std(unsafe {
-            cap_std::fs::File::from_std(output_write.try_clone().await?.into_std().await)
-        });
-        let stderr = wasi_cap_std_sync::file::File::from_cap_std(unsafe {
-            cap_std::fs::File::from_std(output_write.try_clone().await?.into_std().await)
-        });
+        let env: Vec<(String, String)> = if self.capabilities.contains(Capability::Env) {
+            Vec::new()
+        } else {
+            data.env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let stdout_file = output_write.try_clone().await?.into_std().await;
+        let stderr_file = output_write.try_clone().await?.into_std().await;
+        let open_pod_log = || -> std::io::Result<Option<std::fs::File>> {
+            match &self.pod_log {
+                Some(pod_log) => std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(pod_log)
+                    .map(Some),
+                None => Ok(None),
+            }
+        };
+        let stdout = self.output_writer(
+            broadcast.writer(stdout_file),
+            open_pod_log()?,
+            Stream::Stdout,
+        );
+        let stderr = self.output_writer(
+            broadcast.writer(stderr_file),
+            open_pod_log()?,
+            Stream::Stderr,
+        );
+        let stdout: Box<dyn WasiFile> = match &self.log_format {
+            LogFormat::Raw => Box::new(WritePipe::new(stdout)),
+            LogFormat::JsonLines(metadata) => Box::new(WritePipe::new(JsonLinesWriter::new(
+                stdout,
+                metadata.clone(),
+            ))),
+        };
+        let stderr = WritePipe::new(stderr);
 
         // Create the WASI context builder and pass arguments, environment,
         // and standard output and error.
         let mut builder = WasiCtxBuilder::new()
             .args(&data.args)?
             .envs(&env)?
-            .stdout(Box::new(stdout))
+            .stdout(stdout)
             .stderr(Box::new(stderr));
+        if let Some(stdin) = &data.stdin {
+            let stdin: Box<dyn WasiFile> = match (stdin, stdin_reader) {
+                (Stdin::Data(bytes), _) => Box::new(ReadPipe::from(bytes.clone())),
+                (Stdin::File { volume, path }, _) => Box::new(
+                    wasi_cap_std_sync::file::File::from_cap_std(open_in_volume(volume, path)?),
+                ),
+                (Stdin::Attach { .. }, Some(reader)) => Box::new(ReadPipe::new(reader)),
+                (Stdin::Attach { .. }, None) => {
+                    return Err(anyhow::anyhow!("attached stdin has no reader"))
+                }
+            };
+            builder = builder.stdin(stdin);
+        }
 
         // Add preopen dirs.
         for (key, value) in data.dirs.iter() {
@@ -200,17 +470,41 @@ impl WasiRuntime {
             builder = builder.preopened_dir(preopen_dir, guest_dir)?;
         }
 
-        let ctx = builder.build();
-
-        let mut config = wasmtime::Config::new();
-        config.interruptable(true);
-        let engine = wasmtime::Engine::new(&config)?;
-        let mut store = wasmtime::Store::new(&engine, ctx);
+        let mut ctx = builder.build();
+        self.capabilities.apply(&mut ctx);
+
+        // A pooled module is already compiled for an engine with an instance slot reserved for it.
+        // The store has to be dropped before the slot it was given is freed
+        let pooled = self
+            .instance_pool
+            .as_ref()
+            .and_then(|pool| pool.checkout(&self.engine_options, &data.module_data));
+        let engine = match &pooled {
+            Some(pooled) => pooled.engine.clone(),
+            None => {
+                let mut config = wasmtime::Config::new();
+                config.interruptable(true);
+                self.engine_options.apply(&mut config)?;
+                wasmtime::Engine::new(&config)?
+            }
+        };
+        // The threads of a pod share the Kubelet's memory cgroup, so a container's memory limit
+        // is enforced by the meter instead
+        let meter = match self.memory_limit {
+            Some(limit) => MemoryMeter::new(info.clone()).with_limit(limit),
+            None => MemoryMeter::new(info.clone()),
+        };
+        let mut store = wasmtime::Store::new(&engine, ModuleState { wasi: ctx, meter });
+        store.limiter(|state| &mut state.meter);
         let interrupt = store.interrupt_handle()?;
 
         let mut linker = Linker::new(&engine);
 
-        let module = match wasmtime::Module::new(&engine, &data.module_data) {
+        let module = match &pooled {
+            Some(pooled) => Ok(pooled.module().clone()),
+            None => wasmtime::Module::new(&engine, &data.module_data),
+        };
+        let module = match module {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
             Ok(m) => m,
@@ -221,7 +515,7 @@ impl WasiRuntime {
                     .send(Status::Terminated {
                         failed: true,
                         message: message.into(),
-                        timestamp: chrono::Utc::now(),
+                        timestamp: self.clock.now(),
                     })
                     .await?;
 
@@ -229,7 +523,7 @@ impl WasiRuntime {
             }
         };
 
-        wasmtime_wasi::add_to_linker(&mut linker, |cx| cx)?;
+        wasmtime_wasi::add_to_linker(&mut linker, |state| state.wasi())?;
 
         // Link WASI HTTP
         let WasiHttpConfig {
@@ -239,7 +533,16 @@ impl WasiRuntime {
         let wasi_http = WasiHttpCtx::new(allowed_domains, max_concurrent_requests)?;
         wasi_http.add_to_linker(&mut linker)?;
 
-        let instance = match linker.instantiate(&mut store, &module) {
+        // Link any host extensions requested by the pod
+        for extension in self.host_extensions.iter() {
+            extension.add_to_linker(&mut linker)?;
+        }
+
+        let instance = match &pooled {
+            Some(pooled) => pooled.instantiate(&mut store, &linker),
+            None => linker.instantiate(&mut store, &module),
+        };
+        let instance = match instance {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
             Ok(i) => i,
@@ -250,7 +553,7 @@ impl WasiRuntime {
                     .send(Status::Terminated {
                         failed: true,
                         message: message.into(),
-                        timestamp: chrono::Utc::now(),
+                        timestamp: self.clock.now(),
                     })
                     .await?;
                 // Converting from anyhow
@@ -258,18 +561,27 @@ impl WasiRuntime {
             }
         };
 
+        // The meter keeps this current as the module grows its memory
+        let memory = instance.get_memory(&mut store, "memory");
+        {
+            let mut info = info.lock().unwrap();
+            info.started_at = Some(self.clock.now());
+            info.memory_bytes = memory.map(|m| m.data_size(&store) as u64);
+        }
+
         info!("starting run of module");
         status_sender
             .send(Status::Running {
-                timestamp: chrono::Utc::now(),
+                timestamp: self.clock.now(),
             })
             .await?;
 
-        // NOTE(thomastaylor312): In the future, if we want to pass args directly, we'll
-        // need to do a bit more to pass them in here.
+        let export_name = self.entrypoint.export();
         let export = instance
-            .get_export(&mut store, "_start")
-            .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
+            .get_export(&mut store, export_name)
+            .ok_or_else(|| {
+                anyhow::anyhow!("{} export doesn't exist in wasm module", export_name)
+            })?;
 
         // NOTE(thomastaylor312): In the future (pun intended) we might be able to use something
         // like `func.call(...).await`. We should check every once and a while when upgraing
@@ -277,40 +589,113 @@ impl WasiRuntime {
         let func = match export {
             wasmtime::Extern::Func(f) => f,
             _ => {
-                let message =
-                    "_start import was not a function. This is likely a problem with the module";
-                error!(error = message);
+                let message = format!(
+                    "{} export was not a function. This is likely a problem with the module",
+                    export_name
+                );
+                error!(error = %message);
                 status_sender
                     .send(Status::Terminated {
                         failed: true,
-                        message: message.into(),
-                        timestamp: chrono::Utc::now(),
+                        message: message.clone(),
+                        timestamp: self.clock.now(),
+                    })
+                    .await?;
+
+                return Err(anyhow::anyhow!(message));
+            }
+        };
+        let params = match self.entrypoint.params(&func.ty(&store), &data.args) {
+            Ok(p) => p,
+            Err(e) => {
+                let message = format!("unable to pass args to {}: {}", export_name, e);
+                error!(error = %message);
+                status_sender
+                    .send(Status::Terminated {
+                        failed: true,
+                        message: message.clone(),
+                        timestamp: self.clock.now(),
                     })
                     .await?;
 
                 return Err(anyhow::anyhow!(message));
             }
         };
+        // Reactors that need initializing export `_initialize`, which must run before anything else
+        let initialize = if self.entrypoint.is_reactor() {
+            instance.get_func(&mut store, REACTOR_INITIALIZE_EXPORT)
+        } else {
+            None
+        };
+        let export_name = export_name.to_owned();
+
+        // Interrupt the module if it writes more to its scratch space than its quota allows
+        let over_quota: Arc<Mutex<Option<String>>> = Default::default();
+        let quota_watch = match &self.scratch {
+            Some(scratch) => {
+                let interrupt = store.interrupt_handle()?;
+                let over_quota = over_quota.clone();
+                let quota = scratch.quota().unwrap_or_default();
+                scratch.watch_quota(self.clock.clone(), move |used| {
+                    *over_quota.lock().unwrap() = Some(format!(
+                        "Container used {} bytes of scratch space, over its quota of {} bytes",
+                        used, quota
+                    ));
+                    interrupt.interrupt();
+                })
+            }
+            None => None,
+        };
 
         let name = self.name.clone();
+        let cgroup = self.cgroup.clone();
+        let clock = self.clock.clone();
         let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+            // Locals are dropped in reverse order, so the store goes before its pool slot
+            let _pooled = pooled;
+            let mut store = store;
+            let _quota_watch = quota_watch;
             let span = tracing::info_span!("wasmtime_module_run", %name);
             let _enter = span.enter();
-
-            match func.call(&mut store, &[]) {
+            // Blocking threads are reused, so the guard moves this one back out of the cgroup
+            // once the module exits
+            let _cgroup = cgroup.as_ref().and_then(|cgroup| {
+                cgroup
+                    .enter()
+                    .map_err(|e| warn!(error = %e, "Unable to move module into its cgroup"))
+                    .ok()
+            });
+
+            let result = match initialize {
+                Some(initialize) => initialize
+                    .call(&mut store, &[])
+                    .and_then(|_| func.call(&mut store, &params)),
+                None => func.call(&mut store, &params),
+            };
+            // Fuel can only be read from the store, which the call holds until it returns
+            info.lock().unwrap().fuel_consumed = store.fuel_consumed();
+            match result {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
-                Ok(_) => {}
+                Ok(results) => {
+                    if !results.is_empty() {
+                        info!(export = %export_name, ?results, "module export returned");
+                    }
+                }
                 Err(e) => {
-                    let message = "unable to run module";
+                    let message = over_quota
+                        .lock()
+                        .unwrap()
+                        .take()
+                        .unwrap_or_else(|| "unable to run module".to_owned());
                     error!(error = %e, "{}", message);
                     send(
                         &status_sender,
                         &name,
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
+                            message: message.clone(),
+                            timestamp: clock.now(),
                         },
                     );
 
@@ -325,7 +710,7 @@ impl WasiRuntime {
                 Status::Terminated {
                     failed: false,
                     message: "Module run completed".into(),
-                    timestamp: chrono::Utc::now(),
+                    timestamp: clock.now(),
                 },
             );
             Ok(())
@@ -342,3 +727,71 @@ fn send(sender: &Sender<Status>, name: &str, status: Status) {
         Ok(_) => debug!("send completed"),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_is_measured_while_module_runs() {
+        let info: Arc<Mutex<RuntimeInfo>> = Default::default();
+        let engine = wasmtime::Engine::default();
+        let mut store = wasmtime::Store::new(
+            &engine,
+            ModuleState {
+                wasi: WasiCtxBuilder::new().build(),
+                meter: MemoryMeter::new(info.clone()),
+            },
+        );
+        store.limiter(|state| &mut state.meter);
+        let module = wasmtime::Module::new(
+            &engine,
+            r#"(module
+                (import "test" "observe" (func $observe))
+                (memory 1)
+                (func (export "run")
+                    (drop (memory.grow (i32.const 2)))
+                    (call $observe)))"#,
+        )
+        .unwrap();
+
+        let observed: Arc<Mutex<Option<u64>>> = Default::default();
+        let mut linker = Linker::new(&engine);
+        let (seen, during) = (info.clone(), observed.clone());
+        linker
+            .func_wrap("test", "observe", move || {
+                *during.lock().unwrap() = seen.lock().unwrap().memory_bytes;
+            })
+            .unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        instance
+            .get_typed_func::<(), (), _>(&mut store, "run")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+
+        assert_eq!(Some(3 * WASM_PAGE_SIZE), *observed.lock().unwrap());
+    }
+
+    #[test]
+    fn test_memory_limit_is_enforced() {
+        let info: Arc<Mutex<RuntimeInfo>> = Default::default();
+        let engine = wasmtime::Engine::default();
+        let mut store = wasmtime::Store::new(
+            &engine,
+            MemoryMeter::new(info.clone()).with_limit(2 * WASM_PAGE_SIZE),
+        );
+        store.limiter(|meter| meter);
+
+        let too_large = wasmtime::Module::new(&engine, "(module (memory 3))").unwrap();
+        assert!(wasmtime::Instance::new(&mut store, &too_large, &[]).is_err());
+
+        let module =
+            wasmtime::Module::new(&engine, r#"(module (memory (export "memory") 1))"#).unwrap();
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "memory").unwrap();
+        assert!(memory.grow(&mut store, 1).is_ok());
+        assert!(memory.grow(&mut store, 1).is_err());
+        assert_eq!(Some(2 * WASM_PAGE_SIZE), info.lock().unwrap().memory_bytes);
+    }
+}
@@ -1,8 +1,9 @@
-use kubelet::config::Config;
+use kubelet::config::{Command, Config};
 use kubelet::plugin_watcher::PluginRegistry;
 use kubelet::resources::DeviceManager;
 use kubelet::store::composite::ComposableStore;
 use kubelet::store::oci::FileStore;
+use kubelet::support_bundle::{self, BundleOptions};
 use kubelet::Kubelet;
 use std::convert::TryFrom;
 use std::sync::Arc;
@@ -20,6 +21,20 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
+    if let Some(Command::SupportBundle {
+        output,
+        log_files,
+        log_lines,
+    }) = Config::command_from_flags(env!("CARGO_PKG_VERSION"))
+    {
+        let options = BundleOptions {
+            output,
+            log_files,
+            log_lines,
+        };
+        return support_bundle::create(&config, Some(make_store(&config)), &options).await;
+    }
+
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
     let store = make_store(&config);
@@ -46,7 +61,13 @@ fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
     let client = oci_distribution::Client::from_source(config);
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
+    let file_store = Arc::new(
+        FileStore::new(client, &store_path)
+            .with_background_client(oci_distribution::Client::from_source(config))
+            .with_scope(config.store_scope)
+            .with_pull_deadline(config.image_pull_deadline)
+            .with_net(config.environment.net.clone()),
+    );
 
     if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))